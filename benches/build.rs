@@ -0,0 +1,105 @@
+//! Startup cost of building a machine with 5k transitions
+//!
+//! Compares a builder without sizing hints to one using `reserve_transitions`,
+//! `reserve_states` and `reserve_history`:
+//!
+//! ```text
+//! cargo bench --bench build
+//! ```
+
+use rs_statemachine::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+struct Node(u32);
+
+impl State for Node {}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+struct Step(u8);
+
+impl Event for Step {}
+
+#[derive(Debug, Clone)]
+struct BenchContext;
+
+impl Context for BenchContext {}
+
+const STATES: u32 = 1_000;
+const EVENTS: u8 = 5;
+const TRANSITIONS: usize = STATES as usize * EVENTS as usize;
+const ROUNDS: u32 = 20;
+
+fn build(hinted: bool) -> StateMachine<Node, Step, BenchContext> {
+    let mut builder = StateMachineBuilderFactory::create::<Node, Step, BenchContext>();
+    if hinted {
+        builder
+            .reserve_transitions(TRANSITIONS)
+            .reserve_states(STATES as usize)
+            .reserve_history(1_024);
+    }
+    for state in 0..STATES {
+        for event in 0..EVENTS {
+            builder
+                .external_transition()
+                .from(Node(state))
+                .to(Node((state + event as u32 + 1) % STATES))
+                .on(Step(event))
+                .perform(|_s, _e, _c| {});
+        }
+    }
+    builder.build()
+}
+
+fn measure(hinted: bool) -> (Duration, usize) {
+    // Warm up
+    black_box(build(hinted));
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(build(hinted));
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    (elapsed / ROUNDS, allocations / ROUNDS as usize)
+}
+
+fn main() {
+    for hinted in [false, true] {
+        let (elapsed, allocations) = measure(hinted);
+        println!(
+            "build {} transitions ({}): {:?}/build, {} allocations/build",
+            TRANSITIONS,
+            if hinted { "with hints" } else { "no hints" },
+            elapsed,
+            allocations
+        );
+    }
+}
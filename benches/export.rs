@@ -0,0 +1,137 @@
+//! Peak memory of exporting a machine with 20k transitions
+//!
+//! Compares building the DOT and PlantUML documents as a `String` and
+//! writing them to a file with streaming them through `write_dot` and
+//! `write_plantuml`:
+//!
+//! ```text
+//! cargo bench --bench export --features visualization
+//! ```
+//!
+//! Streaming saves the size of the document. Both ways still sort the
+//! transitions first, which sets the peak when the document is smaller than
+//! the sort keys, as it is for PlantUML with these short names.
+
+use rs_statemachine::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+struct PeakAlloc;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grow(size: usize) {
+    let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(live, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        grow(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        grow(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAlloc = PeakAlloc;
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+struct Node(u32);
+
+impl State for Node {}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+struct Step(u8);
+
+impl Event for Step {}
+
+#[derive(Debug, Clone)]
+struct BenchContext;
+
+impl Context for BenchContext {}
+
+const STATES: u32 = 4_000;
+const EVENTS: u8 = 5;
+const TRANSITIONS: usize = STATES as usize * EVENTS as usize;
+
+fn build() -> StateMachine<Node, Step, BenchContext> {
+    let mut builder = StateMachineBuilderFactory::create::<Node, Step, BenchContext>();
+    builder.reserve_transitions(TRANSITIONS);
+    for state in 0..STATES {
+        for event in 0..EVENTS {
+            builder
+                .external_transition()
+                .from(Node(state))
+                .to(Node((state + event as u32 + 1) % STATES))
+                .on(Step(event))
+                .add();
+        }
+    }
+    builder.build()
+}
+
+// Time taken and peak memory allocated on top of what was live before
+fn measure(export: impl FnOnce(BufWriter<File>)) -> (Duration, usize) {
+    let path = std::env::temp_dir().join("rs_statemachine_export_bench");
+    let file = BufWriter::new(File::create(&path).unwrap());
+    let live = LIVE.load(Ordering::Relaxed);
+    PEAK.store(live, Ordering::Relaxed);
+    let start = Instant::now();
+    export(file);
+    let elapsed = start.elapsed();
+    let peak = PEAK.load(Ordering::Relaxed) - live;
+    std::fs::remove_file(path).unwrap();
+    (elapsed, peak)
+}
+
+fn report(format: &str, method: &str, (elapsed, peak): (Duration, usize)) {
+    println!(
+        "{} {} transitions ({}): {:?}, peak {} KiB",
+        format,
+        TRANSITIONS,
+        method,
+        elapsed,
+        peak / 1024
+    );
+}
+
+fn main() {
+    let machine = build();
+    let options = ExportOptions::new();
+
+    report(
+        "dot",
+        "to_dot",
+        measure(|mut file| file.write_all(machine.to_dot().as_bytes()).unwrap()),
+    );
+    report(
+        "dot",
+        "write_dot",
+        measure(|file| machine.write_dot(file, &options).unwrap()),
+    );
+    report(
+        "plantuml",
+        "to_plantuml",
+        measure(|mut file| file.write_all(machine.to_plantuml().as_bytes()).unwrap()),
+    );
+    report(
+        "plantuml",
+        "write_plantuml",
+        measure(|file| machine.write_plantuml(file, &options).unwrap()),
+    );
+}
@@ -0,0 +1,71 @@
+//! Fire cost of a single-candidate transition
+//!
+//! Run with and without the `guards` feature to compare:
+//!
+//! ```text
+//! cargo bench --bench fire_event
+//! cargo bench --bench fire_event --features guards
+//! ```
+
+use rs_statemachine::*;
+use std::hint::black_box;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum BenchState {
+    A,
+    B,
+}
+
+impl State for BenchState {}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum BenchEvent {
+    Go,
+}
+
+impl Event for BenchEvent {}
+
+#[derive(Debug, Clone)]
+struct BenchContext;
+
+impl Context for BenchContext {}
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn main() {
+    let mut builder = StateMachineBuilderFactory::create::<BenchState, BenchEvent, BenchContext>();
+    builder
+        .external_transition()
+        .from(BenchState::A)
+        .to(BenchState::B)
+        .on(BenchEvent::Go)
+        .when(|_s, _e, _c| true)
+        .perform(|_s, _e, _c| {});
+    let state_machine = builder.build();
+
+    // Warm up
+    for _ in 0..ITERATIONS / 10 {
+        let _ = black_box(state_machine.fire_event(BenchState::A, BenchEvent::Go, BenchContext));
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let _ = black_box(state_machine.fire_event(
+            black_box(BenchState::A),
+            BenchEvent::Go,
+            BenchContext,
+        ));
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "single-candidate fire_event (guards {}): {:?}/op",
+        if cfg!(feature = "guards") {
+            "on"
+        } else {
+            "off"
+        },
+        elapsed / ITERATIONS
+    );
+}
@@ -1,8 +1,8 @@
 //! Examples demonstrating different features of the state machine
 
 use rs_statemachine::*;
-use std::sync::Arc;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 enum OrderState {
     New,
@@ -17,6 +17,7 @@ enum OrderState {
 
 impl State for OrderState {}
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 enum OrderEvent {
     Pay,
@@ -30,6 +31,7 @@ enum OrderEvent {
 
 impl Event for OrderEvent {}
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct OrderContext {
     order_id: String,
@@ -125,13 +127,18 @@ fn history_example() {
     println!("Transition history:");
     for (i, record) in history.iter().enumerate() {
         println!(
-            "  {}. {:?} -> {:?} via {:?} (success: {})",
+            "  {}. {:?} -> {:?} via {:?} at {:?} in {:?} (success: {})",
             i + 1,
             record.from,
             record.to,
             record.event,
+            record.wall_time,
+            record.duration,
             record.success
         );
+        if let Some(error) = &record.error {
+            println!("     failed: {}", error);
+        }
     }
 }
 
@@ -457,25 +464,29 @@ fn parallel_example() {
 
     // Fire events in parallel regions
     println!("Firing Process event in parallel regions:");
-    let results = parallel_machine.fire_event(
-        vec![OrderState::New, OrderState::PaymentPending],
-        OrderEvent::Process,
-        context.clone(),
-    );
-
-    for (i, result) in results.iter().enumerate() {
-        println!("  Region {}: {:?}", i, result);
+    let outcome = parallel_machine
+        .fire_event(
+            vec![OrderState::New, OrderState::PaymentPending],
+            OrderEvent::Process,
+            context.clone(),
+        )
+        .unwrap();
+
+    for region in &outcome.regions {
+        println!("  Region {}: {:?}", region.region_name, region.result);
     }
 
     println!("Firing ConfirmPayment event in parallel regions:");
-    let results = parallel_machine.fire_event(
-        vec![OrderState::Processing, OrderState::PaymentPending],
-        OrderEvent::ConfirmPayment,
-        context,
-    );
-
-    for (i, result) in results.iter().enumerate() {
-        println!("  Region {}: {:?}", i, result);
+    let outcome = parallel_machine
+        .fire_event(
+            vec![OrderState::Processing, OrderState::PaymentPending],
+            OrderEvent::ConfirmPayment,
+            context,
+        )
+        .unwrap();
+
+    for region in &outcome.regions {
+        println!("  Region {}: {:?}", region.region_name, region.result);
     }
 }
 
@@ -531,7 +542,7 @@ fn complete_example() {
             println!("Order {} shipped", ctx.order_id);
         });
 
-    builder.set_fail_callback(Arc::new(|state, event, ctx| {
+    builder.set_fail_callback(std::sync::Arc::new(|state, event, ctx| {
         println!(
             "FAILED: Cannot handle {:?} in state {:?} for order {}",
             event, state, ctx.order_id
@@ -568,9 +579,12 @@ fn complete_example() {
     println!("\nHistory:");
     for record in state_machine.get_history() {
         println!(
-            "  {:?} -> {:?} (success: {})",
-            record.from, record.to, record.success
+            "  {:?} -> {:?} in {:?} (success: {})",
+            record.from, record.to, record.duration, record.success
         );
+        if let Some(error) = &record.error {
+            println!("    failed: {}", error);
+        }
     }
 
     println!("\nMetrics:");
@@ -17,6 +17,7 @@ enum TrafficLightState {
 
 impl State for TrafficLightState {}
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 enum TrafficLightEvent {
     Timer,
@@ -29,6 +30,7 @@ enum TrafficLightEvent {
 
 impl Event for TrafficLightEvent {}
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct TrafficContext {
     intersection_id: String,
@@ -41,8 +43,8 @@ struct TrafficContext {
 impl Context for TrafficContext {}
 
 /// Build a traffic light system with configurable features
-pub fn build_traffic_light_system(
-) -> StateMachine<TrafficLightState, TrafficLightEvent, TrafficContext> {
+fn build_traffic_light_system() -> StateMachine<TrafficLightState, TrafficLightEvent, TrafficContext>
+{
     let mut builder =
         StateMachineBuilderFactory::create::<TrafficLightState, TrafficLightEvent, TrafficContext>(
         );
@@ -69,9 +71,9 @@ pub fn build_traffic_light_system(
 }
 
 /// Configure basic state transitions
-fn configure_basic_transitions<'a>(
-    builder: &'a mut StateMachineBuilder<TrafficLightState, TrafficLightEvent, TrafficContext>,
-) -> &'a mut StateMachineBuilder<TrafficLightState, TrafficLightEvent, TrafficContext> {
+fn configure_basic_transitions(
+    builder: &mut StateMachineBuilder<TrafficLightState, TrafficLightEvent, TrafficContext>,
+) -> &mut StateMachineBuilder<TrafficLightState, TrafficLightEvent, TrafficContext> {
     // Normal traffic light cycle
     builder
         .external_transition()
@@ -369,13 +371,17 @@ fn demonstrate_history(
     let history = state_machine.get_history();
     for (i, record) in history.iter().enumerate() {
         println!(
-            "  {}. {:?} -> {:?} via {:?} ({})",
+            "  {}. {:?} -> {:?} via {:?} in {:?} ({})",
             i + 1,
             record.from,
             record.to,
             record.event,
+            record.duration,
             if record.success { "✓" } else { "✗" }
         );
+        if let Some(error) = &record.error {
+            println!("     {}", error);
+        }
     }
     println!();
 }
@@ -0,0 +1,331 @@
+//! Sharded actor serializing events per entity (requires `async` feature)
+//!
+//! `ShardedActor` owns a fixed pool of worker tasks. Every submitted event is
+//! routed to a worker by hashing its entity key, so events for one entity are
+//! processed strictly in submission order while different entities proceed in
+//! parallel on different workers. Fires are synchronous, so workers run them
+//! on tokio's blocking thread pool rather than on the async executor.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::{Context, Event, State, StateMachine, StateRepository, TransitionError};
+
+/// Capacity of each worker's queue before `send` waits
+const WORKER_QUEUE_CAPACITY: usize = 1024;
+
+struct Job<K, S, E, C>
+where
+    S: State,
+{
+    key: K,
+    event: E,
+    context: C,
+    reply: oneshot::Sender<Result<S, TransitionError<S, E>>>,
+}
+
+/// Snapshot of the actor's load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActorStats {
+    /// Events submitted but not yet completed
+    pub in_flight: usize,
+    /// Events completed since the actor was spawned
+    pub processed: u64,
+    /// Number of worker tasks
+    pub workers: usize,
+}
+
+/// Result of an event queued with `ShardedActor::enqueue`
+pub struct EventTicket<S, E>
+where
+    S: State,
+{
+    response: oneshot::Receiver<Result<S, TransitionError<S, E>>>,
+}
+
+impl<S, E> EventTicket<S, E>
+where
+    S: State,
+{
+    /// Wait for the event to be processed
+    pub async fn result(self) -> Result<S, TransitionError<S, E>> {
+        self.response.await.unwrap_or_else(|_| {
+            Err(TransitionError::AsyncError(
+                "actor worker stopped".to_string(),
+            ))
+        })
+    }
+}
+
+/// Pool of workers firing events against one shared machine definition
+pub struct ShardedActor<K, S, E, C>
+where
+    S: State,
+{
+    senders: Vec<mpsc::Sender<Job<K, S, E, C>>>,
+    workers: Vec<JoinHandle<()>>,
+    in_flight: Arc<AtomicUsize>,
+    processed: Arc<AtomicU64>,
+}
+
+impl<K, S, E, C> ShardedActor<K, S, E, C>
+where
+    K: Eq + Hash + Debug + Send + Sync + 'static,
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    C: Context + Send + Sync + 'static,
+{
+    /// Spawn `workers` worker tasks on the current tokio runtime
+    ///
+    /// Each event loads the entity's state from `repository`, fires against
+    /// `machine`, and saves the resulting state back.
+    pub fn spawn(
+        machine: Arc<StateMachine<S, E, C>>,
+        repository: Arc<dyn StateRepository<K, S>>,
+        workers: usize,
+    ) -> Self {
+        let workers = workers.max(1);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let processed = Arc::new(AtomicU64::new(0));
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+
+        for _ in 0..workers {
+            let (sender, mut receiver) = mpsc::channel::<Job<K, S, E, C>>(WORKER_QUEUE_CAPACITY);
+            let machine = machine.clone();
+            let repository = repository.clone();
+            let in_flight = in_flight.clone();
+            let processed = processed.clone();
+
+            handles.push(tokio::spawn(async move {
+                while let Some(job) = receiver.recv().await {
+                    let (machine, repository) = (machine.clone(), repository.clone());
+                    let (key, event, context) = (job.key, job.event, job.context);
+                    let fire = tokio::task::spawn_blocking(move || {
+                        machine.fire_event_inferred(repository.as_ref(), &key, event, context, None)
+                    });
+                    let result = fire.await.unwrap_or_else(|_| {
+                        Err(TransitionError::AsyncError(
+                            "actor fire panicked".to_string(),
+                        ))
+                    });
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    processed.fetch_add(1, Ordering::SeqCst);
+                    // The caller may have stopped waiting for the result
+                    let _ = job.reply.send(result);
+                }
+            }));
+            senders.push(sender);
+        }
+
+        ShardedActor {
+            senders,
+            workers: handles,
+            in_flight,
+            processed,
+        }
+    }
+
+    /// Submit an event for an entity and wait for its result
+    ///
+    /// Events for the same key are processed in the order they were sent.
+    pub async fn send(&self, key: K, event: E, context: C) -> Result<S, TransitionError<S, E>> {
+        self.enqueue(key, event, context).await?.result().await
+    }
+
+    /// Queue an event for an entity without waiting for it to be processed
+    ///
+    /// Only waits while the worker's queue is full. The returned ticket
+    /// gives the result once the event was processed.
+    pub async fn enqueue(
+        &self,
+        key: K,
+        event: E,
+        context: C,
+    ) -> Result<EventTicket<S, E>, TransitionError<S, E>> {
+        let (reply, response) = oneshot::channel();
+        let sender = &self.senders[self.shard_for(&key)];
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let job = Job {
+            key,
+            event,
+            context,
+            reply,
+        };
+        if sender.send(job).await.is_err() {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return Err(TransitionError::AsyncError(
+                "actor has shut down".to_string(),
+            ));
+        }
+
+        Ok(EventTicket { response })
+    }
+
+    /// Current load of the actor
+    pub fn in_flight(&self) -> ActorStats {
+        ActorStats {
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            processed: self.processed.load(Ordering::SeqCst),
+            workers: self.senders.len(),
+        }
+    }
+
+    /// Stop accepting events and wait until every queued event is processed
+    pub async fn shutdown(self) {
+        drop(self.senders);
+        for worker in self.workers {
+            let _ = worker.await;
+        }
+    }
+
+    fn shard_for(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+    use crate::{InMemoryStateRepository, StateMachineBuilderFactory, NEW_ENTITY};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug, Clone)]
+    struct OrderContext {
+        order_id: u32,
+    }
+
+    impl Context for OrderContext {}
+
+    type Spans = Arc<Mutex<Vec<(u32, Instant, Instant)>>>;
+
+    fn slow_pay_machine(spans: Spans) -> Arc<StateMachine<Order, OrderEvent, OrderContext>> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(move |_s, _e, c| {
+                let start = Instant::now();
+                std::thread::sleep(Duration::from_millis(50));
+                spans
+                    .lock()
+                    .unwrap()
+                    .push((c.order_id, start, Instant::now()));
+            });
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        Arc::new(builder.build())
+    }
+
+    fn keys_on_different_shards(
+        actor: &ShardedActor<u32, Order, OrderEvent, OrderContext>,
+    ) -> (u32, u32) {
+        let first = 1;
+        let second = (2..100)
+            .find(|key| actor.shard_for(key) != actor.shard_for(&first))
+            .unwrap();
+        (first, second)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_same_key_processed_in_order() {
+        let spans: Spans = Arc::new(Mutex::new(Vec::new()));
+        let repository = Arc::new(InMemoryStateRepository::new());
+        repository.save(&1, &Order::Placed, NEW_ENTITY).unwrap();
+        let actor = ShardedActor::spawn(slow_pay_machine(spans), repository.clone(), 4);
+
+        let context = OrderContext { order_id: 1 };
+        let (paid, shipped) = tokio::join!(
+            actor.send(1, OrderEvent::Pay, context.clone()),
+            actor.send(1, OrderEvent::Ship, context)
+        );
+        assert_eq!(paid.unwrap(), Order::Paid);
+        assert_eq!(shipped.unwrap(), Order::Shipped);
+        assert_eq!(repository.load(&1), Some(Order::Shipped));
+
+        let stats = actor.in_flight();
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.processed, 2);
+        actor.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_different_keys_processed_concurrently() {
+        let spans: Spans = Arc::new(Mutex::new(Vec::new()));
+        let repository = Arc::new(InMemoryStateRepository::new());
+        let actor = ShardedActor::spawn(slow_pay_machine(spans.clone()), repository.clone(), 4);
+        let (first, second) = keys_on_different_shards(&actor);
+        repository.save(&first, &Order::Placed, NEW_ENTITY).unwrap();
+        repository
+            .save(&second, &Order::Placed, NEW_ENTITY)
+            .unwrap();
+
+        let (a, b) = tokio::join!(
+            actor.send(first, OrderEvent::Pay, OrderContext { order_id: first }),
+            actor.send(second, OrderEvent::Pay, OrderContext { order_id: second })
+        );
+        assert!(a.is_ok() && b.is_ok());
+
+        let spans = spans.lock().unwrap().clone();
+        assert_eq!(spans.len(), 2);
+        let (_, start_a, end_a) = spans[0];
+        let (_, start_b, end_b) = spans[1];
+        assert!(
+            start_a < end_b && start_b < end_a,
+            "actions did not overlap"
+        );
+        actor.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queue() {
+        let spans: Spans = Arc::new(Mutex::new(Vec::new()));
+        let repository = Arc::new(InMemoryStateRepository::new());
+        let keys: Vec<u32> = (1..=6).collect();
+        for key in &keys {
+            repository.save(key, &Order::Placed, NEW_ENTITY).unwrap();
+        }
+        let actor = ShardedActor::spawn(slow_pay_machine(spans.clone()), repository.clone(), 2);
+
+        let mut tickets = Vec::new();
+        for key in &keys {
+            let context = OrderContext { order_id: *key };
+            tickets.push(actor.enqueue(*key, OrderEvent::Pay, context).await.unwrap());
+        }
+        tickets.push(
+            actor
+                .enqueue(1, OrderEvent::Ship, OrderContext { order_id: 1 })
+                .await
+                .unwrap(),
+        );
+        // Each action sleeps, so most events are still queued here
+        assert!(spans.lock().unwrap().len() < keys.len());
+        actor.shutdown().await;
+
+        assert_eq!(spans.lock().unwrap().len(), keys.len());
+        assert_eq!(repository.load(&1), Some(Order::Shipped));
+        for key in &keys[1..] {
+            assert_eq!(repository.load(key), Some(Order::Paid));
+        }
+        for ticket in tickets {
+            assert!(ticket.result().await.is_ok());
+        }
+    }
+}
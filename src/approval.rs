@@ -0,0 +1,281 @@
+//! Transitions that need an operator approval to fire
+//!
+//! A transition registered with `.requires_approval(checker)` is only taken
+//! through `StateMachine::fire_event_approved`, after `checker` accepted the
+//! supplied `Approval`. Through `fire_event` it fails with
+//! `TransitionError::ApprovalRequired`; a rejected approval fails with
+//! `TransitionError::ApprovalRejected`. Guards are evaluated first, so the
+//! checker only sees approvals for transitions that would otherwise fire.
+
+use std::sync::Arc;
+
+use crate::{Context, ContextMapper, Event, State, StateMachine, TransitionError};
+
+/// Approval attached to a fire by an operator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Approval {
+    pub id: String,
+    pub approver: String,
+    /// Credential the checker validates; never written to the history
+    pub token: String,
+}
+
+/// Approval details kept in the history
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ApprovalRecord {
+    pub id: String,
+    pub approver: String,
+}
+
+impl From<&Approval> for ApprovalRecord {
+    fn from(approval: &Approval) -> Self {
+        ApprovalRecord {
+            id: approval.id.clone(),
+            approver: approval.approver.clone(),
+        }
+    }
+}
+
+/// Validates approvals for the transitions it guards
+pub trait ApprovalChecker<C>: Send + Sync {
+    /// Accept `approval` for a fire with `context`, or return why not
+    fn check(&self, approval: &Approval, context: &C) -> Result<(), String>;
+}
+
+// Checker of a machine whose context was converted by `map_context`
+struct MappedChecker<C, C2> {
+    checker: Arc<dyn ApprovalChecker<C>>,
+    map: ContextMapper<C2, C>,
+}
+
+impl<C, C2> ApprovalChecker<C2> for MappedChecker<C, C2> {
+    fn check(&self, approval: &Approval, context: &C2) -> Result<(), String> {
+        self.checker.check(approval, &(self.map)(context))
+    }
+}
+
+pub(crate) fn map_checker<C, C2>(
+    checker: Arc<dyn ApprovalChecker<C>>,
+    map: &ContextMapper<C2, C>,
+) -> Arc<dyn ApprovalChecker<C2>>
+where
+    C: 'static,
+    C2: 'static,
+{
+    Arc::new(MappedChecker {
+        checker,
+        map: map.clone(),
+    })
+}
+
+// Check the approval of a selected transition, if it needs one
+pub(crate) fn check_approval<S, E, C>(
+    checker: Option<&Arc<dyn ApprovalChecker<C>>>,
+    approval: Option<&Approval>,
+    from: &S,
+    event: &E,
+    context: &C,
+) -> Result<(), TransitionError<S, E>>
+where
+    S: Clone,
+    E: Clone,
+{
+    let Some(checker) = checker else {
+        return Ok(());
+    };
+    let Some(approval) = approval else {
+        return Err(TransitionError::ApprovalRequired {
+            from: from.clone(),
+            event: event.clone(),
+        });
+    };
+    checker
+        .check(approval, context)
+        .map_err(|reason| TransitionError::ApprovalRejected { reason })
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Fire an event with an operator approval attached
+    ///
+    /// Transitions that don't require an approval ignore it. With the
+    /// `history` feature, the approval id and approver are recorded.
+    pub fn fire_event_approved(
+        &self,
+        from: S,
+        event: E,
+        mut context: C,
+        approval: &Approval,
+    ) -> Result<S, TransitionError<S, E>> {
+        self.fire_traced(from, event, &mut context, None, None, Some(approval))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Payment {
+        Captured,
+        Refunded,
+    }
+
+    impl State for Payment {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum PaymentEvent {
+        Refund,
+    }
+
+    impl Event for PaymentEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Refund {
+        amount: u64,
+    }
+
+    impl Context for Refund {}
+
+    // Accepts tokens of the form "ok:<approver>" for refunds up to 50k
+    struct TokenChecker;
+
+    impl ApprovalChecker<Refund> for TokenChecker {
+        fn check(&self, approval: &Approval, context: &Refund) -> Result<(), String> {
+            if approval.token != format!("ok:{}", approval.approver) {
+                return Err("invalid token".to_string());
+            }
+            if context.amount > 50_000 {
+                return Err(format!("{} exceeds the approval limit", context.amount));
+            }
+            Ok(())
+        }
+    }
+
+    fn refund_machine() -> StateMachine<Payment, PaymentEvent, Refund> {
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, Refund>();
+        builder
+            .external_transition()
+            .from(Payment::Captured)
+            .to(Payment::Refunded)
+            .on(PaymentEvent::Refund)
+            .requires_approval(Arc::new(TokenChecker))
+            .perform(|_s, _e, _c| {});
+        builder.build()
+    }
+
+    fn approval(token: &str) -> Approval {
+        Approval {
+            id: "apr-1".to_string(),
+            approver: "alice".to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_blocked_without_approval() {
+        let machine = refund_machine();
+        let result = machine.fire_event(
+            Payment::Captured,
+            PaymentEvent::Refund,
+            Refund { amount: 20_000 },
+        );
+        assert!(matches!(
+            result,
+            Err(TransitionError::ApprovalRequired { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rejected_approval_runs_fail_callback() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let counter = failures.clone();
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, Refund>();
+        builder
+            .external_transition()
+            .from(Payment::Captured)
+            .to(Payment::Refunded)
+            .on(PaymentEvent::Refund)
+            .requires_approval(Arc::new(TokenChecker))
+            .perform(|_s, _e, _c| {});
+        builder.set_fail_callback(Arc::new(move |_s, _e, _c| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        let machine = builder.build();
+
+        let refund = || Refund { amount: 20_000 };
+        assert!(machine
+            .fire_event(Payment::Captured, PaymentEvent::Refund, refund())
+            .is_err());
+        assert!(machine
+            .fire_event_approved(
+                Payment::Captured,
+                PaymentEvent::Refund,
+                refund(),
+                &approval("forged")
+            )
+            .is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_accepted_with_valid_token() {
+        let machine = refund_machine();
+        let to = machine
+            .fire_event_approved(
+                Payment::Captured,
+                PaymentEvent::Refund,
+                Refund { amount: 20_000 },
+                &approval("ok:alice"),
+            )
+            .unwrap();
+        assert_eq!(to, Payment::Refunded);
+
+        #[cfg(feature = "history")]
+        {
+            let history = machine.get_history();
+            assert_eq!(
+                history[0].approval,
+                Some(ApprovalRecord {
+                    id: "apr-1".to_string(),
+                    approver: "alice".to_string(),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejection_reason_surfaced() {
+        let machine = refund_machine();
+        let result = machine.fire_event_approved(
+            Payment::Captured,
+            PaymentEvent::Refund,
+            Refund { amount: 90_000 },
+            &approval("ok:alice"),
+        );
+        match result {
+            Err(TransitionError::ApprovalRejected { reason }) => {
+                assert_eq!(reason, "90000 exceeds the approval limit")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let result = machine.fire_event_approved(
+            Payment::Captured,
+            PaymentEvent::Refund,
+            Refund { amount: 100 },
+            &approval("forged"),
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Approval rejected: invalid token"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Working backwards from a state
+//!
+//! `StateMachine::transitions_into` lists the transitions that can leave a
+//! machine in a given state, from an index of targets built with the
+//! machine. With the `history` feature, `explain_arrival` finds the record
+//! that last entered a state together with the other ways the definition
+//! could have got there, e.g. to explain how an order ended up cancelled.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "history")]
+use crate::TransitionRecord;
+use crate::{Context, Event, State, StateMachine, Transition, TransitionMap, WildcardMap};
+
+// Position of a transition in the transition table or among the wildcards
+#[derive(Clone)]
+enum IncomingSlot<S, E> {
+    Keyed((S, E), usize),
+    Wildcard(E, usize),
+}
+
+/// Transitions of a machine by target state
+#[derive(Clone)]
+pub(crate) struct IncomingIndex<S, E>(HashMap<S, Box<[IncomingSlot<S, E>]>>);
+
+impl<S, E> IncomingIndex<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub(crate) fn new<C: Context>(
+        transitions: &TransitionMap<S, E, C>,
+        wildcards: &WildcardMap<S, E, C>,
+    ) -> Self {
+        let mut incoming: HashMap<S, Vec<IncomingSlot<S, E>>> = HashMap::new();
+        for (key, candidates) in transitions {
+            for (index, transition) in candidates.iter().enumerate() {
+                incoming
+                    .entry(transition.to.clone())
+                    .or_default()
+                    .push(IncomingSlot::Keyed(key.clone(), index));
+            }
+        }
+        for (event, candidates) in wildcards {
+            for (index, transition) in candidates.iter().enumerate() {
+                incoming
+                    .entry(transition.to.clone())
+                    .or_default()
+                    .push(IncomingSlot::Wildcard(event.clone(), index));
+            }
+        }
+        IncomingIndex(
+            incoming
+                .into_iter()
+                .map(|(state, slots)| (state, slots.into_boxed_slice()))
+                .collect(),
+        )
+    }
+
+    fn slots(&self, state: &S) -> &[IncomingSlot<S, E>] {
+        self.0.get(state).map_or(&[], |slots| &slots[..])
+    }
+}
+
+/// How an entity last entered a state, see `StateMachine::explain_arrival`
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+#[derive(Debug, Clone)]
+pub struct ArrivalExplanation<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// The last successful record ending in the state
+    pub record: TransitionRecord<S, E>,
+    /// Other `(from, event)` pairs of the definition ending in the state,
+    /// with `None` as source for `from_any` transitions
+    pub alternatives: Vec<(Option<S>, E)>,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Transitions ending in `state`, `from_any` ones included, in no
+    /// particular order
+    ///
+    /// Internal transitions within `state` are included.
+    pub fn transitions_into(&self, state: &S) -> Vec<&Transition<S, E, C>> {
+        self.incoming
+            .slots(state)
+            .iter()
+            .map(|slot| match slot {
+                IncomingSlot::Keyed(key, index) => &self.transitions[key][*index],
+                IncomingSlot::Wildcard(event, index) => &self.wildcard_transitions[event][*index],
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// How `history`, the records of one entity, last entered `state`
+    ///
+    /// `None` when no successful record ends in `state`. The transition the
+    /// record took is told apart from the alternatives the same way firing
+    /// does: a transition registered for its source state wins over
+    /// `from_any` ones.
+    pub fn explain_arrival(
+        &self,
+        history: &[TransitionRecord<S, E>],
+        state: &S,
+    ) -> Option<ArrivalExplanation<S, E>> {
+        let record = history
+            .iter()
+            .rev()
+            .find(|record| record.success && record.to == *state)?;
+        let keyed = self
+            .transitions
+            .contains_key(&(record.from.clone(), record.event.clone()));
+
+        let mut alternatives = Vec::new();
+        for slot in self.incoming.slots(state) {
+            let (taken, alternative) = match slot {
+                IncomingSlot::Keyed((from, event), _) => (
+                    keyed && *from == record.from && *event == record.event,
+                    (Some(from.clone()), event.clone()),
+                ),
+                IncomingSlot::Wildcard(event, _) => {
+                    (!keyed && *event == record.event, (None, event.clone()))
+                }
+            };
+            if !taken && !alternatives.contains(&alternative) {
+                alternatives.push(alternative);
+            }
+        }
+
+        Some(ArrivalExplanation {
+            record: record.clone(),
+            alternatives,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_builder, NoContext, Order, OrderEvent};
+    use std::collections::HashSet;
+
+    // Refunds cancel an order in any state
+    fn refundable_machine() -> StateMachine<Order, OrderEvent, NoContext> {
+        let mut builder = order_builder();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Order::Cancelled)
+            .on(OrderEvent::Refund)
+            .add();
+        builder
+            .internal_transition()
+            .within(Order::Paid)
+            .on(OrderEvent::Note)
+            .add();
+        builder.build()
+    }
+
+    #[test]
+    fn test_transitions_into_matches_scan() {
+        let machine = refundable_machine();
+        for state in machine.states() {
+            let indexed: HashSet<*const Transition<Order, OrderEvent, NoContext>> = machine
+                .transitions_into(state)
+                .into_iter()
+                .map(|t| t as *const _)
+                .collect();
+            let scanned: HashSet<*const Transition<Order, OrderEvent, NoContext>> = machine
+                .transitions()
+                .chain(machine.from_any_transitions())
+                .filter(|t| t.to() == state)
+                .map(|t| t as *const _)
+                .collect();
+            assert_eq!(indexed, scanned, "transitions into {:?}", state);
+        }
+        assert_eq!(machine.transitions_into(&Order::Cancelled).len(), 3);
+        assert_eq!(machine.transitions_into(&Order::Paid).len(), 2);
+        assert!(machine.transitions_into(&Order::Placed).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_explain_arrival() {
+        let machine = std::sync::Arc::new(refundable_machine());
+        let order = machine.start(Order::Placed);
+        order.process(OrderEvent::Pay, NoContext).unwrap();
+        order.process(OrderEvent::Cancel, NoContext).unwrap();
+
+        let explanation = order.explain_arrival(&Order::Cancelled).unwrap();
+        assert_eq!(explanation.record.from, Order::Paid);
+        assert_eq!(explanation.record.event, OrderEvent::Cancel);
+        let alternatives: HashSet<_> = explanation.alternatives.into_iter().collect();
+        assert_eq!(
+            alternatives,
+            HashSet::from([
+                (Some(Order::Placed), OrderEvent::Cancel),
+                (None, OrderEvent::Refund),
+            ])
+        );
+        assert!(order.explain_arrival(&Order::Shipped).is_none());
+
+        // Arriving through the wildcard leaves both keyed pairs as alternatives
+        let order = machine.start(Order::Shipped);
+        assert!(order.process(OrderEvent::Pay, NoContext).is_err());
+        order.process(OrderEvent::Refund, NoContext).unwrap();
+        let explanation = order.explain_arrival(&Order::Cancelled).unwrap();
+        assert_eq!(explanation.record.from, Order::Shipped);
+        assert_eq!(explanation.alternatives.len(), 2);
+        assert!(explanation
+            .alternatives
+            .iter()
+            .all(|(from, event)| from.is_some() && *event == OrderEvent::Cancel));
+    }
+}
@@ -0,0 +1,721 @@
+//! All-or-nothing application of a sequence of events
+//!
+//! `fire_atomic` runs the events against a staging copy of the machine and
+//! only publishes the resulting history and metrics when every event
+//! succeeds. Actions that already ran cannot be undone, so every transition
+//! taking part must have its action declared side-effect free with
+//! `.pure_action()`. Under the `extended` feature, an external transition
+//! also runs the exit action of its source and the entry actions of its
+//! target and of every state its completion transitions may reach; those
+//! states must be declared with `StateMachineBuilder::pure_state_actions`.
+//! An active override taking part must not carry an action of its own, and
+//! the machine must not have a fail callback, which any failing event runs.
+//! Each event sees the context as the previous ones left it.
+//!
+//! Listeners don't see the staged fires as they run. Once the batch
+//! commits, they are notified of every step in order; a batch that fails
+//! notifies them of nothing.
+
+use std::fmt;
+
+use crate::instance::InstanceFire;
+use crate::outcome::TransitionOutcome;
+use crate::recording::RecordingState;
+use crate::{Context, Event, State, StateMachine, TransitionError};
+#[cfg(feature = "extended")]
+use crate::{StateActions, StateMachineBuilder, TransitionType};
+
+/// Why `fire_atomic` applied none of the events
+#[derive(Debug, Clone)]
+pub enum AtomicError<S, E> {
+    /// The event at `index` would run an action not declared pure
+    ImpureAction { index: usize, from: S, event: E },
+    /// The machine has a fail callback, which a failing event would run
+    ImpureFailCallback,
+    /// The event at `index` would run the entry or exit action of `state`,
+    /// which was not declared pure
+    #[cfg(feature = "extended")]
+    ImpureStateAction {
+        index: usize,
+        from: S,
+        event: E,
+        state: S,
+    },
+    /// The event at `index` failed
+    Failed {
+        index: usize,
+        from: S,
+        event: E,
+        error: TransitionError<S, E>,
+    },
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> fmt::Display for AtomicError<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AtomicError::ImpureAction { index, from, event } => write!(
+                f,
+                "Event #{} ({:?} from {:?}) has an action not declared pure",
+                index, event, from
+            ),
+            AtomicError::ImpureFailCallback => {
+                write!(f, "The machine has a fail callback, which is not pure")
+            }
+            #[cfg(feature = "extended")]
+            AtomicError::ImpureStateAction {
+                index,
+                from,
+                event,
+                state,
+            } => write!(
+                f,
+                "Event #{} ({:?} from {:?}) runs state actions of {:?} not declared pure",
+                index, event, from, state
+            ),
+            AtomicError::Failed {
+                index,
+                from,
+                event,
+                error,
+            } => write!(
+                f,
+                "Event #{} ({:?} from {:?}) failed: {}",
+                index, event, from, error
+            ),
+        }
+    }
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> std::error::Error for AtomicError<S, E> {}
+
+// A staged step, replayed to the listeners on commit
+struct StagedStep<S, E, C> {
+    from: S,
+    event: E,
+    context_before: C,
+    context_after: C,
+    outcome: TransitionOutcome<S, E>,
+}
+
+#[cfg(feature = "extended")]
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Declare that the entry and exit actions of `state` have no side
+    /// effects outside the machine, allowing transitions into and out of it
+    /// in `StateMachine::fire_atomic`
+    pub fn pure_state_actions(&mut self, state: S) -> &mut Self {
+        let actions = self.state_actions.entry(state).or_insert(StateActions {
+            on_entry: None,
+            on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
+            _phantom: Default::default(),
+        });
+        actions.pure = true;
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Apply `events` in order starting from `from`, all or nothing
+    ///
+    /// Returns the final state if every event succeeds; history records and
+    /// metrics of all steps are then published together and the listeners
+    /// notified. On failure nothing is recorded or notified and the error
+    /// names the failing event.
+    pub fn fire_atomic(&self, from: S, events: &[E], context: &C) -> Result<S, AtomicError<S, E>> {
+        self.fire_atomic_in(from, events, context, None, |_| Ok(()))
+    }
+
+    // `fire_atomic` for `instance`, which admits each staged transition and
+    // whose recording gets the batch published too; `charge` is asked
+    // before staging the event at each index
+    pub(crate) fn fire_atomic_in(
+        &self,
+        from: S,
+        events: &[E],
+        context: &C,
+        instance: Option<&InstanceFire<'_, S, E>>,
+        mut charge: impl FnMut(usize) -> Result<(), TransitionError<S, E>>,
+    ) -> Result<S, AtomicError<S, E>> {
+        if self.fail_callback.is_some() {
+            return Err(AtomicError::ImpureFailCallback);
+        }
+        let staging = self.fork();
+        let observed = !self.listeners.is_empty();
+        let mut steps = Vec::new();
+        let mut state = from;
+        let mut staged_context = context.clone();
+        // Staged fires are admitted like any other, but recorded on publish
+        let staged_instance = instance.map(|instance| InstanceFire {
+            recording: None,
+            admit: instance.admit,
+        });
+
+        for (index, event) in events.iter().enumerate() {
+            charge(index).map_err(|error| AtomicError::Failed {
+                index,
+                from: state.clone(),
+                event: event.clone(),
+                error,
+            })?;
+            self.check_pure(index, &state, event)?;
+
+            let context_before = observed.then(|| staged_context.clone());
+            let (outcome, _) = staging
+                .fire_unobserved(
+                    state.clone(),
+                    event.clone(),
+                    &mut staged_context,
+                    None,
+                    staged_instance.as_ref(),
+                    None,
+                )
+                .map_err(|error| AtomicError::Failed {
+                    index,
+                    from: state.clone(),
+                    event: event.clone(),
+                    error,
+                })?;
+            let to = outcome.to.clone();
+            if let Some(context_before) = context_before {
+                steps.push(StagedStep {
+                    from: state,
+                    event: event.clone(),
+                    context_before,
+                    context_after: staged_context.clone(),
+                    outcome,
+                });
+            }
+            state = to;
+        }
+
+        self.publish(&staging, &self.recording, false);
+        if let Some(recording) = instance.and_then(|instance| instance.recording) {
+            self.publish(&staging, recording, true);
+        }
+        for step in steps {
+            self.notify_before(&step.from, &step.event, &step.context_before);
+            self.notify_outcome(
+                &step.from,
+                &step.event,
+                &step.context_after,
+                &Ok((step.outcome, ())),
+            );
+        }
+
+        Ok(state)
+    }
+
+    // Reject the event at `index` if it could run a callback not declared
+    // pure
+    #[cfg_attr(not(feature = "extended"), allow(unused_variables))]
+    fn check_pure(&self, index: usize, from: &S, event: &E) -> Result<(), AtomicError<S, E>> {
+        // An active override takes precedence over the candidates, as on
+        // the staging fork sharing it
+        if let Some((target, action)) = self.find_override(from, event) {
+            if action.is_some() {
+                return Err(AtomicError::ImpureAction {
+                    index,
+                    from: from.clone(),
+                    event: event.clone(),
+                });
+            }
+            #[cfg(feature = "extended")]
+            if let Some(state) = self.impure_state_along(from, &target, false) {
+                return Err(AtomicError::ImpureStateAction {
+                    index,
+                    from: from.clone(),
+                    event: event.clone(),
+                    state,
+                });
+            }
+            return Ok(());
+        }
+        let Some(candidates) = self.candidates(&(from.clone(), event.clone())) else {
+            return Ok(());
+        };
+        if candidates
+            .iter()
+            .any(|transition| transition.has_action() && !transition.pure_action)
+        {
+            return Err(AtomicError::ImpureAction {
+                index,
+                from: from.clone(),
+                event: event.clone(),
+            });
+        }
+        #[cfg(feature = "extended")]
+        if let Some(state) = candidates
+            .iter()
+            .filter(|transition| transition.transition_type == TransitionType::External)
+            .find_map(|transition| self.impure_state_along(from, &transition.to, true))
+        {
+            return Err(AtomicError::ImpureStateAction {
+                index,
+                from: from.clone(),
+                event: event.clone(),
+                state,
+            });
+        }
+        Ok(())
+    }
+
+    // The first state, among `from`, `to` and, if `settle`, the states
+    // completion transitions may enter from there, with state actions not
+    // declared pure
+    #[cfg(feature = "extended")]
+    fn impure_state_along(&self, from: &S, to: &S, settle: bool) -> Option<S> {
+        let impure = |state: &S| {
+            self.state_actions
+                .get(state)
+                .is_some_and(|actions| !actions.pure)
+        };
+        if impure(from) {
+            return Some(from.clone());
+        }
+        let mut reached = vec![to.clone()];
+        let mut next = 0;
+        while let Some(state) = reached.get(next) {
+            if impure(state) {
+                return Some(state.clone());
+            }
+            let completions = self.completion_transitions.get(state).filter(|_| settle);
+            for completion in completions.into_iter().flatten() {
+                if !reached.contains(&completion.to) {
+                    reached.push(completion.to.clone());
+                }
+            }
+            next += 1;
+        }
+        None
+    }
+
+    // Copy the history and metrics of `staging` into `recording`; an
+    // instance recording takes the metrics in its current epoch
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn publish(&self, staging: &Self, recording: &RecordingState<S, E>, instance: bool) {
+        #[cfg(feature = "history")]
+        recording.record_history(staging.get_history());
+
+        #[cfg(feature = "metrics")]
+        {
+            let staged = staging.get_metrics();
+            let epoch = if instance {
+                u64::MAX
+            } else {
+                staging.metrics_epoch()
+            };
+            recording.update_metrics_in(epoch, |metrics| {
+                metrics.total_transitions += staged.total_transitions;
+                metrics.successful_transitions += staged.successful_transitions;
+                metrics.failed_transitions += staged.failed_transitions;
+                metrics
+                    .transition_durations
+                    .merge(&staged.transition_durations);
+                for (state, count) in staged.state_visit_counts {
+                    *metrics.state_visit_counts.entry(state).or_insert(0) += count;
+                }
+                for (callback, count) in staged.slow_callbacks {
+                    *metrics.slow_callbacks.entry(callback).or_insert(0) += count;
+                }
+                for (code, count) in staged.failures_by_code {
+                    *metrics.failures_by_code.entry(code).or_insert(0) += count;
+                }
+                metrics.failure_rate.merge(&staged.failure_rate);
+                for (key, stats) in staged.transitions {
+                    metrics.transitions.entry(key).or_default().merge(&stats);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StateMachineBuilderFactory, TransitionListener};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Cart {
+        Open,
+        Discounted,
+        Priced,
+    }
+
+    impl State for Cart {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum CartEvent {
+        ApplyDiscount,
+        Reprice,
+        Checkout,
+    }
+
+    impl Event for CartEvent {}
+
+    #[derive(Debug, Clone)]
+    struct CartContext {
+        total: u32,
+    }
+
+    impl Context for CartContext {}
+
+    fn cart_machine() -> StateMachine<Cart, CartEvent, CartContext> {
+        cart_builder().build()
+    }
+
+    fn cart_builder() -> crate::StateMachineBuilder<Cart, CartEvent, CartContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Cart, CartEvent, CartContext>();
+        builder
+            .external_transition()
+            .from(Cart::Open)
+            .to(Cart::Discounted)
+            .on(CartEvent::ApplyDiscount)
+            .pure_action()
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Cart::Discounted)
+            .to(Cart::Priced)
+            .on(CartEvent::Reprice)
+            .when(|_s, _e, c| c.total > 0)
+            .pure_action()
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Cart::Priced)
+            .to(Cart::Open)
+            .on(CartEvent::Checkout)
+            .perform(|_s, _e, _c| {});
+        builder
+    }
+
+    #[test]
+    fn test_atomic_commits_all_steps() {
+        let machine = cart_machine();
+        let result = machine.fire_atomic(
+            Cart::Open,
+            &[CartEvent::ApplyDiscount, CartEvent::Reprice],
+            &CartContext { total: 10 },
+        );
+        assert_eq!(result.unwrap(), Cart::Priced);
+
+        #[cfg(feature = "history")]
+        assert_eq!(machine.get_history().len(), 2);
+        #[cfg(feature = "metrics")]
+        assert_eq!(machine.get_metrics().successful_transitions, 2);
+    }
+
+    #[test]
+    fn test_atomic_failure_records_nothing() {
+        let machine = cart_machine();
+        let result = machine.fire_atomic(
+            Cart::Open,
+            &[CartEvent::ApplyDiscount, CartEvent::Reprice],
+            &CartContext { total: 0 },
+        );
+        match result {
+            Err(AtomicError::Failed { index, from, .. }) => {
+                assert_eq!(index, 1);
+                assert_eq!(from, Cart::Discounted);
+            }
+            other => panic!("expected Failed, got {:?}", other),
+        }
+
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+        #[cfg(feature = "metrics")]
+        assert_eq!(machine.get_metrics().total_transitions, 0);
+    }
+
+    #[test]
+    fn test_atomic_rejects_impure_actions() {
+        let machine = cart_machine();
+        let result = machine.fire_atomic(
+            Cart::Open,
+            &[
+                CartEvent::ApplyDiscount,
+                CartEvent::Reprice,
+                CartEvent::Checkout,
+            ],
+            &CartContext { total: 10 },
+        );
+        assert!(matches!(
+            result,
+            Err(AtomicError::ImpureAction { index: 2, .. })
+        ));
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+    }
+
+    #[test]
+    fn test_atomic_checks_overrides() {
+        let machine = cart_machine();
+        let events = [
+            CartEvent::ApplyDiscount,
+            CartEvent::Reprice,
+            CartEvent::Checkout,
+        ];
+        let context = CartContext { total: 10 };
+
+        // The override skips the impure action of Checkout
+        let skip = machine
+            .override_transition(Cart::Priced, CartEvent::Checkout, Cart::Open, None)
+            .unwrap();
+        assert_eq!(
+            machine.fire_atomic(Cart::Open, &events, &context).unwrap(),
+            Cart::Open
+        );
+        let replace = machine
+            .override_transition_with(
+                Cart::Open,
+                CartEvent::ApplyDiscount,
+                Cart::Discounted,
+                None,
+                |_s, _e, _c| {},
+            )
+            .unwrap();
+        assert!(matches!(
+            machine.fire_atomic(Cart::Open, &events, &context),
+            Err(AtomicError::ImpureAction { index: 0, .. })
+        ));
+        drop((skip, replace));
+    }
+
+    #[test]
+    fn test_atomic_rejects_fail_callback() {
+        let mut builder = cart_builder();
+        builder.set_fail_callback(Arc::new(|_s, _e, _c| {}));
+        let machine = builder.build();
+
+        let result = machine.fire_atomic(
+            Cart::Open,
+            &[CartEvent::ApplyDiscount],
+            &CartContext { total: 10 },
+        );
+        assert!(matches!(result, Err(AtomicError::ImpureFailCallback)));
+    }
+
+    #[test]
+    fn test_atomic_chains_context() {
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = StateMachineBuilderFactory::create::<Cart, CartEvent, CartContext>();
+        builder
+            .external_transition()
+            .from(Cart::Open)
+            .to(Cart::Discounted)
+            .on(CartEvent::ApplyDiscount)
+            .pure_action()
+            .perform_mut(|_s, _e, c| c.total += 10);
+        builder
+            .external_transition()
+            .from(Cart::Discounted)
+            .to(Cart::Priced)
+            .on(CartEvent::Reprice)
+            .when(|_s, _e, c| c.total > 0)
+            .add();
+        builder.add_listener(Box::new(TotalLog(totals.clone())));
+        let machine = builder.build();
+
+        // Reprice sees the total ApplyDiscount raised
+        let result = machine.fire_atomic(
+            Cart::Open,
+            &[CartEvent::ApplyDiscount, CartEvent::Reprice],
+            &CartContext { total: 0 },
+        );
+        assert_eq!(result.unwrap(), Cart::Priced);
+        assert_eq!(*totals.lock().unwrap(), [(0, 10), (10, 10)]);
+    }
+
+    #[cfg(feature = "extended")]
+    #[test]
+    fn test_atomic_checks_state_actions() {
+        let machine = |pure: &[Cart]| {
+            let mut builder = cart_builder();
+            builder
+                .completion_transition()
+                .from(Cart::Discounted)
+                .to(Cart::Priced)
+                .when(|_s, _e, c| c.total > 100);
+            builder.with_exit_action(Cart::Open, |_s, _c| {});
+            builder.with_entry_action(Cart::Priced, |_s, _c| {});
+            for state in pure {
+                builder.pure_state_actions(state.clone());
+            }
+            builder.build()
+        };
+        let context = CartContext { total: 10 };
+        let discount = [CartEvent::ApplyDiscount];
+
+        let result = machine(&[Cart::Priced]).fire_atomic(Cart::Open, &discount, &context);
+        assert!(matches!(
+            result,
+            Err(AtomicError::ImpureStateAction {
+                index: 0,
+                state: Cart::Open,
+                ..
+            })
+        ));
+        // Priced may be entered through the completion transition, even if
+        // it doesn't apply this time
+        let result = machine(&[Cart::Open]).fire_atomic(Cart::Open, &discount, &context);
+        assert!(matches!(
+            result,
+            Err(AtomicError::ImpureStateAction {
+                index: 0,
+                state: Cart::Priced,
+                ..
+            })
+        ));
+        let pure = machine(&[Cart::Open, Cart::Priced]);
+        assert_eq!(
+            pure.fire_atomic(Cart::Open, &discount, &context).unwrap(),
+            Cart::Discounted
+        );
+    }
+
+    struct StepLog(Arc<Mutex<Vec<String>>>);
+
+    impl TransitionListener<Cart, CartEvent, CartContext> for StepLog {
+        fn before_transition(&self, from: &Cart, event: &CartEvent, _context: &CartContext) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("before {:?} {:?}", from, event));
+        }
+
+        fn after_transition(&self, from: &Cart, to: &Cart, _event: &CartEvent, _c: &CartContext) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("after {:?} -> {:?}", from, to));
+        }
+
+        fn on_failure(
+            &self,
+            from: &Cart,
+            _event: &CartEvent,
+            _context: &CartContext,
+            _error: &TransitionError<Cart, CartEvent>,
+        ) {
+            self.0.lock().unwrap().push(format!("failure {:?}", from));
+        }
+    }
+
+    // Records the total before and after each transition
+    struct TotalLog(Arc<Mutex<Vec<(u32, u32)>>>);
+
+    impl TransitionListener<Cart, CartEvent, CartContext> for TotalLog {
+        fn before_transition(&self, _from: &Cart, _event: &CartEvent, context: &CartContext) {
+            self.0.lock().unwrap().push((context.total, 0));
+        }
+
+        fn after_transition(&self, _from: &Cart, _to: &Cart, _e: &CartEvent, c: &CartContext) {
+            if let Some(last) = self.0.lock().unwrap().last_mut() {
+                last.1 = c.total;
+            }
+        }
+    }
+
+    #[test]
+    fn test_listeners_notified_on_commit_only() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = cart_builder();
+        builder.add_listener(Box::new(StepLog(log.clone())));
+        let machine = builder.build();
+        let events = [CartEvent::ApplyDiscount, CartEvent::Reprice];
+
+        assert!(machine
+            .fire_atomic(Cart::Open, &events, &CartContext { total: 0 })
+            .is_err());
+        assert!(log.lock().unwrap().is_empty());
+
+        machine
+            .fire_atomic(Cart::Open, &events, &CartContext { total: 10 })
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "before Open ApplyDiscount",
+                "after Open -> Discounted",
+                "before Discounted Reprice",
+                "after Discounted -> Priced",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_instance_moves_only_when_batch_commits() {
+        let machine = Arc::new(cart_machine());
+        let instance = machine.start(Cart::Open);
+        let events = [CartEvent::ApplyDiscount, CartEvent::Reprice];
+
+        assert!(instance
+            .fire_atomic(&events, &CartContext { total: 0 })
+            .is_err());
+        assert!(instance.is_in(&Cart::Open));
+
+        let to = instance.fire_atomic(&events, &CartContext { total: 10 });
+        assert_eq!(to.unwrap(), Cart::Priced);
+        assert!(instance.is_in(&Cart::Priced));
+        #[cfg(feature = "history")]
+        {
+            assert_eq!(instance.get_history().len(), 2);
+            assert_eq!(machine.get_history().len(), 2);
+        }
+        #[cfg(feature = "metrics")]
+        assert_eq!(instance.get_metrics().successful_transitions, 2);
+    }
+
+    #[test]
+    fn test_instance_batch_charged_per_event() {
+        let batch = [CartEvent::ApplyDiscount, CartEvent::Reprice];
+        let context = CartContext { total: 10 };
+
+        // Two events exceed a budget of one, even as a single batch
+        let mut builder = cart_builder();
+        builder.max_transitions_per_instance(Some(1));
+        let instance = Arc::new(builder.build()).start(Cart::Open);
+        match instance.fire_atomic(&batch, &context) {
+            Err(AtomicError::Failed {
+                index,
+                from,
+                error: TransitionError::TransitionBudgetExhausted { limit: 1 },
+                ..
+            }) => {
+                assert_eq!(index, 1);
+                assert_eq!(from, Cart::Discounted);
+            }
+            other => panic!("expected TransitionBudgetExhausted, got {:?}", other),
+        }
+        assert!(instance.is_in(&Cart::Open));
+        #[cfg(feature = "history")]
+        assert!(instance.get_history().is_empty());
+
+        // A budget of two fits the batch exactly, and nothing after it
+        let mut builder = cart_builder();
+        builder.max_transitions_per_instance(Some(2));
+        let instance = Arc::new(builder.build()).start(Cart::Open);
+        assert_eq!(
+            instance.fire_atomic(&batch, &context).unwrap(),
+            Cart::Priced
+        );
+        assert!(matches!(
+            instance.process(CartEvent::Checkout, context),
+            Err(TransitionError::TransitionBudgetExhausted { limit: 2 })
+        ));
+    }
+}
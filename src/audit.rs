@@ -0,0 +1,347 @@
+//! History kept outside the process (requires the `history` feature)
+//!
+//! A machine built with `with_sync_history_sink` hands every history record
+//! to a `HistorySink` as part of the fire, after the in-memory history has
+//! been written and its lock released, so a slow sink delays the fire that
+//! wrote the record but never blocks other fires on the history. Combined
+//! with `with_history_capacity(0)` the sink replaces the in-memory history.
+//!
+//! `StateMachine::persisted_history` reads the records back, e.g. from a new
+//! process after a restart. `JsonLinesHistorySink` (requires `serde`) stores
+//! them in a file, one JSON object per line, or with `with_codec` one
+//! encoded line per record. Sinks that must not delay fires at all are
+//! better served by the async `HistorySinkHandle`.
+
+#[cfg(feature = "serde")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "serde")]
+use std::io::{self, Write};
+#[cfg(feature = "serde")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "serde")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "serde")]
+use std::sync::{Arc, Mutex};
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder, TransitionRecord};
+
+/// Durable storage of history records, written as each fire completes
+pub trait HistorySink<S, E>: Send + Sync
+where
+    S: State,
+    E: Event,
+{
+    fn record(&self, record: &TransitionRecord<S, E>);
+
+    /// Every record stored so far, oldest first
+    fn load(&self) -> Vec<TransitionRecord<S, E>>;
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Also write every history record to `sink`, see the module
+    /// documentation
+    pub fn with_sync_history_sink(&mut self, sink: Box<dyn HistorySink<S, E>>) -> &mut Self {
+        self.sync_history_sink = Some(sink);
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Records stored by the sink given to `with_sync_history_sink`, empty
+    /// without one
+    pub fn persisted_history(&self) -> Vec<TransitionRecord<S, E>> {
+        self.recording.persisted_history()
+    }
+}
+
+/// `HistorySink` appending one JSON object per record to a file (requires
+/// the `serde` feature)
+///
+/// Lines are written with the serialized form of `TransitionRecord`.
+/// Loading gives each record a monotonic timestamp as far in the past as its
+/// wall-clock time, and skips lines that don't parse or decode, such as one
+/// cut short by a crash.
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub struct JsonLinesHistorySink {
+    path: PathBuf,
+    file: Mutex<File>,
+    codec: Option<Arc<dyn crate::SnapshotCodec>>,
+    failed: AtomicU64,
+}
+
+#[cfg(feature = "serde")]
+impl JsonLinesHistorySink {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(JsonLinesHistorySink {
+            path,
+            file: Mutex::new(file),
+            codec: None,
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    /// Pass every line through `codec`, e.g. to encrypt the records
+    ///
+    /// A file must be read with the codec it was written with.
+    pub fn with_codec(mut self, codec: Arc<dyn crate::SnapshotCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Records that could not be written
+    pub fn failed_records(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+// A line of the file as read back
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct StoredRecord<S, E> {
+    from: S,
+    to: S,
+    event: E,
+    wall_time: String,
+    duration: std::time::Duration,
+    success: bool,
+    error: Option<String>,
+    error_code: Option<String>,
+    approval: Option<crate::ApprovalRecord>,
+    #[serde(default)]
+    epoch: u64,
+    #[serde(default)]
+    context_changes: Option<Vec<crate::FieldChange>>,
+    #[serde(default)]
+    transition_type: Option<crate::TransitionType>,
+    #[serde(default)]
+    irreversible: bool,
+}
+
+#[cfg(feature = "serde")]
+impl<S, E> StoredRecord<S, E>
+where
+    S: State,
+    E: Event,
+{
+    fn into_record(self) -> Option<TransitionRecord<S, E>> {
+        let wall_time = crate::parse_rfc3339(&self.wall_time)?;
+        let age = std::time::SystemTime::now()
+            .duration_since(wall_time)
+            .unwrap_or_default();
+        let now = std::time::Instant::now();
+        Some(TransitionRecord {
+            from: self.from,
+            to: self.to,
+            event: self.event,
+            timestamp: now.checked_sub(age).unwrap_or(now),
+            wall_time,
+            duration: self.duration,
+            success: self.success,
+            error: self.error,
+            error_code: self.error_code.and_then(|code| {
+                crate::erased::ERROR_CODES
+                    .iter()
+                    .find(|known| **known == code)
+                    .copied()
+            }),
+            approval: self.approval,
+            epoch: self.epoch,
+            context_changes: self.context_changes,
+            transition_type: self.transition_type,
+            irreversible: self.irreversible,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<S, E> HistorySink<S, E> for JsonLinesHistorySink
+where
+    S: State + serde::Serialize + serde::de::DeserializeOwned,
+    E: Event + serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn record(&self, record: &TransitionRecord<S, E>) {
+        let written = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .and_then(|json| {
+                let mut line = match &self.codec {
+                    Some(codec) => crate::snapshot::encode_line(codec.as_ref(), json.as_bytes()),
+                    None => json,
+                };
+                line.push('\n');
+                self.file.lock().unwrap().write_all(line.as_bytes())
+            });
+        if written.is_err() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn load(&self) -> Vec<TransitionRecord<S, E>> {
+        let Ok(text) = std::fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+        text.lines()
+            .filter_map(|line| match &self.codec {
+                Some(codec) => crate::snapshot::decode_line(codec.as_ref(), line)
+                    .ok()
+                    .and_then(|json| serde_json::from_slice::<StoredRecord<S, E>>(&json).ok()),
+                None => serde_json::from_str::<StoredRecord<S, E>>(line).ok(),
+            })
+            .filter_map(StoredRecord::into_record)
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Invoice {
+        Draft,
+        Sent,
+        Paid,
+    }
+
+    impl State for Invoice {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum InvoiceEvent {
+        Send,
+        Pay,
+    }
+
+    impl Event for InvoiceEvent {}
+
+    fn invoice_machine(path: &Path) -> StateMachine<Invoice, InvoiceEvent, NoContext> {
+        invoice_machine_with(JsonLinesHistorySink::open(path).unwrap())
+    }
+
+    fn invoice_machine_with(
+        sink: JsonLinesHistorySink,
+    ) -> StateMachine<Invoice, InvoiceEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Invoice, InvoiceEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Invoice::Draft)
+            .to(Invoice::Sent)
+            .on(InvoiceEvent::Send)
+            .add();
+        builder
+            .external_transition()
+            .from(Invoice::Sent)
+            .to(Invoice::Paid)
+            .on(InvoiceEvent::Pay)
+            .add();
+        builder.with_sync_history_sink(Box::new(sink));
+        builder.build()
+    }
+
+    #[test]
+    fn test_records_survive_reload() {
+        let path = std::env::temp_dir().join(format!(
+            "rs-statemachine-audit-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let machine = invoice_machine(&path);
+        machine
+            .fire_event(Invoice::Draft, InvoiceEvent::Send, NoContext)
+            .unwrap();
+        assert!(machine
+            .fire_event(Invoice::Draft, InvoiceEvent::Pay, NoContext)
+            .is_err());
+        let written = machine.get_history();
+        drop(machine);
+
+        let machine = invoice_machine(&path);
+        machine
+            .fire_event(Invoice::Sent, InvoiceEvent::Pay, NoContext)
+            .unwrap();
+        let loaded = machine.persisted_history();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.len(), 3);
+        for (loaded, written) in loaded.iter().zip(&written) {
+            assert_eq!(
+                (&loaded.from, &loaded.to, &loaded.event),
+                (&written.from, &written.to, &written.event)
+            );
+            assert_eq!(loaded.success, written.success);
+            assert_eq!(loaded.error, written.error);
+            assert_eq!(loaded.error_code, written.error_code);
+            assert_eq!(loaded.duration, written.duration);
+        }
+        assert_eq!(loaded[1].error_code, Some("no_valid_transition"));
+        assert_eq!(loaded[2].to, Invoice::Paid);
+        // The in-memory history of the new machine only has its own fire
+        assert_eq!(machine.history_len(), 1);
+    }
+
+    #[test]
+    fn test_torn_line_skipped() {
+        let path = std::env::temp_dir().join(format!(
+            "rs-statemachine-audit-torn-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let machine = invoice_machine(&path);
+        machine
+            .fire_event(Invoice::Draft, InvoiceEvent::Send, NoContext)
+            .unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"from\":\"Sent\",\"to\"")
+            .unwrap();
+        assert_eq!(machine.persisted_history().len(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encrypted_records_not_plaintext() {
+        let path = std::env::temp_dir().join(format!(
+            "rs-statemachine-audit-encrypted-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let encrypted = |key: u8| {
+            JsonLinesHistorySink::open(&path)
+                .unwrap()
+                .with_codec(Arc::new(crate::AesGcmCodec::new(&[key; 32])))
+        };
+
+        let machine = invoice_machine_with(encrypted(7));
+        machine
+            .fire_event(Invoice::Draft, InvoiceEvent::Send, NoContext)
+            .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 1);
+        assert!(!written.contains("Draft"));
+
+        assert_eq!(machine.persisted_history()[0].to, Invoice::Sent);
+        // Under another key no record decodes
+        assert!(invoice_machine_with(encrypted(8))
+            .persisted_history()
+            .is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
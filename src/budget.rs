@@ -0,0 +1,197 @@
+//! Async fires sharing one time budget (requires the `async` feature)
+//!
+//! `StateMachine::fire_event_async_with_deadline` fires an event and then
+//! its follow-up events, like `fire_event_to_completion`, with the async
+//! action of each hop run as in `fire_event_async`. All hops share one
+//! deadline on the tokio clock: each async action only gets the time left
+//! by the hops before it, and no hop starts once the deadline has passed.
+//!
+//! The hop that runs out of time fails with `TransitionError::Timeout`,
+//! naming the hop and the state it fired from, and gets a failed history
+//! entry. Hops committed before it stay committed, so the entity remains in
+//! the state the last of them reached.
+
+use std::collections::VecDeque;
+
+use tokio::time::{self, Instant};
+
+use crate::{Completion, Context, Event, State, StateMachine, TransitionError};
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State + Send + Sync,
+    E: Event + Send + Sync,
+    C: Context + Send + Sync,
+{
+    /// `fire_event_to_completion` with async actions, all hops finishing
+    /// by `deadline`
+    pub async fn fire_event_async_with_deadline(
+        &self,
+        from: S,
+        event: E,
+        mut context: C,
+        deadline: Instant,
+    ) -> Result<Completion<S>, TransitionError<S, E>> {
+        let mut queue = VecDeque::from([event]);
+        let mut path = vec![from];
+        while let Some(event) = queue.pop_front() {
+            let hop = path.len();
+            if hop > self.max_chain_depth + 1 {
+                return Err(TransitionError::MaxChainDepthExceeded {
+                    depth: self.max_chain_depth,
+                });
+            }
+            let from = path[hop - 1].clone();
+            let started = std::time::Instant::now();
+            self.notify_before(&from, &event, &context);
+
+            let mut in_time = Instant::now() < deadline;
+            if let Some(async_action) = self.async_actions.get(&(from.clone(), event.clone())) {
+                in_time = in_time
+                    && time::timeout_at(deadline, async_action.execute(&from, &event, &context))
+                        .await
+                        .is_ok();
+            }
+            let result = if in_time {
+                self.fire_unobserved(from.clone(), event.clone(), &mut context, None, None, None)
+            } else {
+                let error = TransitionError::Timeout {
+                    hop,
+                    from: from.clone(),
+                    event: event.clone(),
+                };
+                self.record_aborted(&from, &event, &context, &error, started);
+                Err(error)
+            };
+            self.notify_outcome(&from, &event, &context, &result);
+            let (outcome, followups) = result?;
+            path.push(outcome.to);
+            queue.extend(followups);
+        }
+        Ok(Completion {
+            state: path[path.len() - 1].clone(),
+            path,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{AsyncAction, StateMachineBuilderFactory};
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Booking {
+        Requested,
+        Reserved,
+        Charged,
+        Confirmed,
+    }
+
+    impl State for Booking {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum BookingEvent {
+        Reserve,
+        Charge,
+        Confirm,
+    }
+
+    impl Event for BookingEvent {}
+
+    // A partner call taking `latency`
+    struct PartnerCall(Duration);
+
+    #[async_trait]
+    impl AsyncAction<Booking, BookingEvent, NoContext> for PartnerCall {
+        async fn execute(&self, _from: &Booking, _event: &BookingEvent, _context: &NoContext) {
+            time::sleep(self.0).await;
+        }
+    }
+
+    // Reserve, then Charge and Confirm as follow-ups, each hop calling a
+    // partner with the given latency in milliseconds
+    fn booking_machine(latencies: [u64; 3]) -> StateMachine<Booking, BookingEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Booking, BookingEvent, NoContext>();
+        let hops = [
+            (Booking::Requested, BookingEvent::Reserve, Booking::Reserved),
+            (Booking::Reserved, BookingEvent::Charge, Booking::Charged),
+            (Booking::Charged, BookingEvent::Confirm, Booking::Confirmed),
+        ];
+        for ((from, event, to), latency) in hops.into_iter().zip(latencies) {
+            let next = match event {
+                BookingEvent::Reserve => vec![BookingEvent::Charge],
+                BookingEvent::Charge => vec![BookingEvent::Confirm],
+                BookingEvent::Confirm => Vec::new(),
+            };
+            builder
+                .with_async_action(
+                    from.clone(),
+                    event.clone(),
+                    Arc::new(PartnerCall(Duration::from_millis(latency))),
+                )
+                .external_transition()
+                .from(from)
+                .to(to)
+                .on(event)
+                .perform_with_followups(move |_s, _e, _c| next.clone());
+        }
+        builder.build()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cascade_within_budget_completes() {
+        let machine = booking_machine([200, 200, 200]);
+        let deadline = Instant::now() + Duration::from_millis(800);
+        let completion = machine
+            .fire_event_async_with_deadline(
+                Booking::Requested,
+                BookingEvent::Reserve,
+                NoContext,
+                deadline,
+            )
+            .await
+            .unwrap();
+        assert_eq!(completion.state, Booking::Confirmed);
+        assert_eq!(completion.path.len(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_budget_exhausted_on_hop_two() {
+        // Each hop fits in 800ms alone, the first two together don't
+        let machine = booking_machine([300, 600, 100]);
+        let start = Instant::now();
+        let result = machine
+            .fire_event_async_with_deadline(
+                Booking::Requested,
+                BookingEvent::Reserve,
+                NoContext,
+                start + Duration::from_millis(800),
+            )
+            .await;
+        match result {
+            Err(TransitionError::Timeout { hop, from, event }) => {
+                assert_eq!(hop, 2);
+                assert_eq!(from, Booking::Reserved);
+                assert_eq!(event, BookingEvent::Charge);
+            }
+            other => panic!("expected Timeout, got {:?}", other),
+        }
+        // Hop two was cut off at the deadline rather than left to finish
+        assert_eq!(start.elapsed(), Duration::from_millis(800));
+
+        #[cfg(feature = "history")]
+        {
+            let history = machine.get_history();
+            assert_eq!(history.len(), 2);
+            assert!(history[0].success);
+            assert_eq!(history[0].to, Booking::Reserved);
+            assert!(!history[1].success);
+            assert_eq!(history[1].error_code, Some("timeout"));
+        }
+    }
+}
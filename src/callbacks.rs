@@ -0,0 +1,196 @@
+//! The guard and the action of a transition
+//!
+//! Builders offer several flavors of each, like `perform_mut` or
+//! `perform_with_info`; a transition holds one action and at most a plain
+//! guard together with an info guard.
+
+#[cfg(any(feature = "visualization", test))]
+use std::sync::Arc;
+
+use crate::derived::DerivedValues;
+use crate::info::{InfoAction, InfoCondition};
+use crate::{
+    ActionError, ActionMut, CheckedCondition, ConditionOutcome, Context, Event, FallibleAction,
+    FollowupAction, State, TransitionInfo,
+};
+
+// The plain and the info guard, each optional
+type GuardParts<S, E, C> = (
+    Option<CheckedCondition<S, E, C>>,
+    Option<InfoCondition<S, E, C>>,
+);
+
+/// Decides whether a transition applies
+#[derive(Clone)]
+pub(crate) enum Guard<S, E, C> {
+    Unguarded,
+    /// Set with `when`, `when_all` or `when_any`
+    Plain(CheckedCondition<S, E, C>),
+    /// Set with `when_with_info` and the guards built on it
+    Info(InfoCondition<S, E, C>),
+    /// Both kinds, which must both pass
+    Both(CheckedCondition<S, E, C>, InfoCondition<S, E, C>),
+}
+
+impl<S, E, C> Guard<S, E, C> {
+    pub(crate) fn from_parts(
+        plain: Option<CheckedCondition<S, E, C>>,
+        info: Option<InfoCondition<S, E, C>>,
+    ) -> Self {
+        match (plain, info) {
+            (None, None) => Guard::Unguarded,
+            (Some(plain), None) => Guard::Plain(plain),
+            (None, Some(info)) => Guard::Info(info),
+            (Some(plain), Some(info)) => Guard::Both(plain, info),
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> GuardParts<S, E, C> {
+        match self {
+            Guard::Unguarded => (None, None),
+            Guard::Plain(plain) => (Some(plain), None),
+            Guard::Info(info) => (None, Some(info)),
+            Guard::Both(plain, info) => (Some(plain), Some(info)),
+        }
+    }
+
+    pub(crate) fn plain(&self) -> Option<&CheckedCondition<S, E, C>> {
+        match self {
+            Guard::Plain(plain) | Guard::Both(plain, _) => Some(plain),
+            Guard::Unguarded | Guard::Info(_) => None,
+        }
+    }
+
+    pub(crate) fn info(&self) -> Option<&InfoCondition<S, E, C>> {
+        match self {
+            Guard::Info(info) | Guard::Both(_, info) => Some(info),
+            Guard::Unguarded | Guard::Plain(_) => None,
+        }
+    }
+
+    /// Replace the plain guard, keeping the info guard
+    pub(crate) fn with_plain(self, plain: CheckedCondition<S, E, C>) -> Self {
+        Guard::from_parts(Some(plain), self.into_parts().1)
+    }
+
+    /// Replace the info guard, keeping the plain guard
+    pub(crate) fn with_info(self, info: InfoCondition<S, E, C>) -> Self {
+        Guard::from_parts(self.into_parts().0, Some(info))
+    }
+
+    pub(crate) fn is_guarded(&self) -> bool {
+        !matches!(self, Guard::Unguarded)
+    }
+
+    #[cfg(any(feature = "visualization", test))]
+    /// Whether both guards are the same callbacks
+    pub(crate) fn ptr_eq(&self, other: &Self) -> bool {
+        same(self.plain(), other.plain()) && same(self.info(), other.info())
+    }
+}
+
+impl<S, E, C> Guard<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // Result of the guards, `None` without any
+    pub(crate) fn check(
+        &self,
+        info: &TransitionInfo<'_, S, E>,
+        from: &S,
+        event: &E,
+        context: &C,
+        derived: &mut DerivedValues<'_, C>,
+    ) -> Option<ConditionOutcome> {
+        let outcome = match self {
+            Guard::Unguarded => return None,
+            Guard::Plain(plain) => plain(from, event, context),
+            Guard::Info(guard) => guard(info, context, derived).then_some(()).ok_or(None),
+            Guard::Both(plain, guard) => plain(from, event, context)
+                .and_then(|()| guard(info, context, derived).then_some(()).ok_or(None)),
+        };
+        Some(outcome)
+    }
+}
+
+/// What taking a transition runs
+#[derive(Clone)]
+pub(crate) enum Action<S, E, C> {
+    Nothing,
+    /// Set with `perform`
+    Plain(crate::Action<S, E, C>),
+    /// Set with `perform_mut`
+    Mut(ActionMut<S, E, C>),
+    /// Set with `perform_fallible`
+    Fallible(FallibleAction<S, E, C>),
+    /// Set with `perform_with_followups`
+    Followups(FollowupAction<S, E, C>),
+    /// Set with `perform_with_info` and the actions built on it
+    Info(InfoAction<S, E, C>),
+}
+
+impl<S, E, C> Action<S, E, C> {
+    pub(crate) fn is_set(&self) -> bool {
+        !matches!(self, Action::Nothing)
+    }
+
+    pub(crate) fn is_fallible(&self) -> bool {
+        matches!(self, Action::Fallible(_))
+    }
+
+    #[cfg(any(feature = "visualization", test))]
+    /// Whether both are the same callback
+    pub(crate) fn ptr_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Action::Nothing, Action::Nothing) => true,
+            (Action::Plain(a), Action::Plain(b)) => Arc::ptr_eq(a, b),
+            (Action::Mut(a), Action::Mut(b)) => Arc::ptr_eq(a, b),
+            (Action::Fallible(a), Action::Fallible(b)) => Arc::ptr_eq(a, b),
+            (Action::Followups(a), Action::Followups(b)) => Arc::ptr_eq(a, b),
+            (Action::Info(a), Action::Info(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl<S, E, C> Action<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // Run the action, returning its outcome, `None` if there is none
+    pub(crate) fn run(
+        &self,
+        info: &TransitionInfo<'_, S, E>,
+        from: &S,
+        event: &E,
+        context: &mut C,
+        derived: &mut DerivedValues<'_, C>,
+    ) -> Option<Result<Vec<E>, ActionError>> {
+        match self {
+            Action::Nothing => return None,
+            Action::Plain(action) => action(from, event, context),
+            Action::Mut(action) => action(from, event, context),
+            Action::Fallible(action) => {
+                return Some(action(from, event, context).map(|()| Vec::new()))
+            }
+            Action::Followups(action) => return Some(Ok(action(from, event, context))),
+            Action::Info(action) => {
+                return Some(action(info, context, derived).map(|()| Vec::new()))
+            }
+        }
+        Some(Ok(Vec::new()))
+    }
+}
+
+#[cfg(any(feature = "visualization", test))]
+fn same<T: ?Sized>(a: Option<&Arc<T>>, b: Option<&Arc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
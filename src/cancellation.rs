@@ -0,0 +1,320 @@
+//! Cancelling async actions in flight (requires the `async` feature)
+//!
+//! `StateMachine::fire_event_async_cancellable` selects the transition, then
+//! runs its async action while watching a `CancellationToken`. A fire that
+//! would fail, say without a transition or with every guard rejecting it,
+//! fails as `fire_event` does without starting the action.
+//!
+//! A token cancelled before the action completes drops the action, runs the
+//! cleanup registered with `StateMachineBuilder::on_cancel`, records the
+//! failure in the history and metrics and returns
+//! `TransitionError::Cancelled`; the state does not change. Once the action
+//! has completed the transition commits as usual, however late the token is
+//! cancelled.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+pub use tokio_util::sync::CancellationToken;
+
+use crate::{
+    AsyncAction, Context, Event, State, StateMachine, StateMachineBuilder, TransitionError,
+    TransitionOutcome,
+};
+
+/// Cleanup run when the async action of a transition is cancelled
+pub type CancelCallback<S, E, C> = Arc<dyn Fn(&S, &E, &C) + Send + Sync>;
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Run `action` before the transition on `event` out of `from` fires
+    /// asynchronously
+    pub fn with_async_action(
+        &mut self,
+        from: S,
+        event: E,
+        action: Arc<dyn AsyncAction<S, E, C>>,
+    ) -> &mut Self {
+        self.async_actions.insert((from, event), action);
+        self
+    }
+
+    /// Run `cleanup` when the async action of the transition on `event` out
+    /// of `from` is cancelled
+    pub fn on_cancel<F>(&mut self, from: S, event: E, cleanup: F) -> &mut Self
+    where
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
+    {
+        self.cancel_callbacks
+            .insert((from, event), Arc::new(cleanup));
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State + Send + Sync,
+    E: Event + Send + Sync,
+    C: Context + Send + Sync,
+{
+    /// `fire_event_async`, giving up on the async action once `token` is
+    /// cancelled
+    pub async fn fire_event_async_cancellable(
+        &self,
+        from: S,
+        event: E,
+        mut context: C,
+        token: CancellationToken,
+    ) -> Result<S, TransitionError<S, E>> {
+        let key = (from.clone(), event.clone());
+        let started = Instant::now();
+        self.notify_before(&from, &event, &context);
+
+        // Only start the action for a fire that takes a transition; any other
+        // fails below the way `fire_event` does
+        let resolved = self.peek(&from, &event, &context).is_ok();
+        if let Some(async_action) = self.async_actions.get(&key).filter(|_| resolved) {
+            let completed = tokio::select! {
+                // An action finishing as the token is cancelled still commits
+                biased;
+                _ = async_action.execute(&from, &event, &context) => true,
+                _ = token.cancelled() => false,
+            };
+            if !completed {
+                if let Some(cleanup) = self.cancel_callbacks.get(&key) {
+                    cleanup(&from, &event, &context);
+                }
+                let error = TransitionError::Cancelled {
+                    from: from.clone(),
+                    event: event.clone(),
+                };
+                self.record_aborted(&from, &event, &context, &error, started);
+                let result: Result<(TransitionOutcome<S, E>, ()), _> = Err(error);
+                self.notify_outcome(&from, &event, &context, &result);
+                return result.map(|(outcome, _)| outcome.to);
+            }
+        }
+
+        let result =
+            self.fire_unobserved(from.clone(), event.clone(), &mut context, None, None, None);
+        self.notify_outcome(&from, &event, &context, &result);
+        result.map(|(outcome, _)| outcome.to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Payment {
+        Pending,
+        Captured,
+    }
+
+    impl State for Payment {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum PaymentEvent {
+        Capture,
+    }
+
+    impl Event for PaymentEvent {}
+
+    // Calls a slow partner API, cancelling `token` itself when given one
+    struct PartnerCall {
+        latency: Duration,
+        cancel_on_completion: Option<CancellationToken>,
+    }
+
+    #[async_trait]
+    impl AsyncAction<Payment, PaymentEvent, NoContext> for PartnerCall {
+        async fn execute(&self, _from: &Payment, _event: &PaymentEvent, _context: &NoContext) {
+            tokio::time::sleep(self.latency).await;
+            if let Some(token) = &self.cancel_on_completion {
+                token.cancel();
+            }
+        }
+    }
+
+    fn payment_machine(
+        action: PartnerCall,
+        cleanups: &Arc<AtomicUsize>,
+    ) -> StateMachine<Payment, PaymentEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Payment::Pending)
+            .to(Payment::Captured)
+            .on(PaymentEvent::Capture)
+            .add();
+        let cleanups = cleanups.clone();
+        builder
+            .with_async_action(Payment::Pending, PaymentEvent::Capture, Arc::new(action))
+            .on_cancel(
+                Payment::Pending,
+                PaymentEvent::Capture,
+                move |_s, _e, _c| {
+                    cleanups.fetch_add(1, Ordering::SeqCst);
+                },
+            );
+        builder.build()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_mid_action_keeps_state() {
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let machine = payment_machine(
+            PartnerCall {
+                latency: Duration::from_secs(5),
+                cancel_on_completion: None,
+            },
+            &cleanups,
+        );
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+
+        let result = machine
+            .fire_event_async_cancellable(Payment::Pending, PaymentEvent::Capture, NoContext, token)
+            .await;
+        match result {
+            Err(TransitionError::Cancelled { from, .. }) => assert_eq!(from, Payment::Pending),
+            other => panic!("expected Cancelled, got {:?}", other),
+        }
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+
+        #[cfg(feature = "history")]
+        {
+            let history = machine.get_history();
+            assert_eq!(history.len(), 1);
+            assert!(!history[0].success);
+            assert_eq!(history[0].to, Payment::Pending);
+            assert_eq!(history[0].error_code, Some("cancelled"));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_after_action_still_commits() {
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let token = CancellationToken::new();
+        let machine = payment_machine(
+            PartnerCall {
+                latency: Duration::from_secs(1),
+                cancel_on_completion: Some(token.clone()),
+            },
+            &cleanups,
+        );
+        let result = machine
+            .fire_event_async_cancellable(Payment::Pending, PaymentEvent::Capture, NoContext, token)
+            .await;
+        assert_eq!(result.unwrap(), Payment::Captured);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 0);
+    }
+
+    struct CountingCall(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl AsyncAction<Payment, PaymentEvent, NoContext> for CountingCall {
+        async fn execute(&self, _from: &Payment, _event: &PaymentEvent, _context: &NoContext) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_action_not_started_without_a_transition() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Payment::Pending)
+            .to(Payment::Captured)
+            .on(PaymentEvent::Capture)
+            .when(|_s, _e, _c| false)
+            .add();
+        builder
+            .with_async_action(
+                Payment::Pending,
+                PaymentEvent::Capture,
+                Arc::new(CountingCall(calls.clone())),
+            )
+            .with_async_action(
+                Payment::Captured,
+                PaymentEvent::Capture,
+                Arc::new(CountingCall(calls.clone())),
+            );
+        let machine = builder.build();
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = machine
+            .fire_event_async_cancellable(
+                Payment::Captured,
+                PaymentEvent::Capture,
+                NoContext,
+                token.clone(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(TransitionError::NoValidTransition { .. })
+        ));
+        let result = machine
+            .fire_event_async_cancellable(Payment::Pending, PaymentEvent::Capture, NoContext, token)
+            .await;
+        assert!(matches!(
+            result,
+            Err(TransitionError::ConditionFailed { .. })
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // Failed as fires, not as cancellations
+        #[cfg(feature = "history")]
+        assert!(machine
+            .get_history()
+            .iter()
+            .all(|record| record.error_code != Some("cancelled")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unused_token_fires_normally() {
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let machine = payment_machine(
+            PartnerCall {
+                latency: Duration::from_secs(1),
+                cancel_on_completion: None,
+            },
+            &cleanups,
+        );
+        let result = machine
+            .fire_event_async_cancellable(
+                Payment::Pending,
+                PaymentEvent::Capture,
+                NoContext,
+                CancellationToken::new(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), Payment::Captured);
+        assert_eq!(
+            machine
+                .fire_event_async(Payment::Pending, PaymentEvent::Capture, NoContext)
+                .await
+                .unwrap(),
+            Payment::Captured
+        );
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().iter().all(|record| record.success));
+    }
+}
@@ -0,0 +1,244 @@
+//! Runtime report of the optional subsystems available on a machine
+
+use crate::{features, Context, Event, State, StateMachine};
+
+/// Optional subsystems compiled into the crate and configured on a machine
+///
+/// The `bool` fields mirror the constants in [`features`]. The `*_configured`
+/// fields report whether the machine actually uses a subsystem, e.g. whether
+/// any entry/exit action was registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    pub history: bool,
+    pub extended: bool,
+    pub metrics: bool,
+    pub hierarchical: bool,
+    pub guards: bool,
+    pub timeout: bool,
+    pub parallel: bool,
+    pub visualization: bool,
+    pub serde: bool,
+    pub async_actions: bool,
+    pub axum: bool,
+    pub binary_snapshots: bool,
+    pub encryption: bool,
+    pub test_util: bool,
+    pub feature_flags_configured: bool,
+    pub state_actions_configured: bool,
+    pub timeouts_configured: bool,
+    pub async_actions_configured: bool,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Report which optional subsystems are compiled in and configured
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            history: features::HISTORY,
+            extended: features::EXTENDED,
+            metrics: features::METRICS,
+            hierarchical: features::HIERARCHICAL,
+            guards: features::GUARDS,
+            timeout: features::TIMEOUT,
+            parallel: features::PARALLEL,
+            visualization: features::VISUALIZATION,
+            serde: features::SERDE,
+            async_actions: features::ASYNC,
+            axum: features::AXUM,
+            binary_snapshots: features::BINARY_SNAPSHOTS,
+            encryption: features::ENCRYPTION,
+            test_util: features::TEST_UTIL,
+            feature_flags_configured: self.feature_flags.is_some(),
+            #[cfg(feature = "extended")]
+            state_actions_configured: !self.state_actions.is_empty(),
+            #[cfg(not(feature = "extended"))]
+            state_actions_configured: false,
+            #[cfg(feature = "timeout")]
+            timeouts_configured: !self.state_timeouts.is_empty(),
+            #[cfg(not(feature = "timeout"))]
+            timeouts_configured: false,
+            #[cfg(feature = "async")]
+            async_actions_configured: !self.async_actions.is_empty(),
+            #[cfg(not(feature = "async"))]
+            async_actions_configured: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Light {
+        On,
+        Off,
+    }
+
+    impl State for Light {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Switch {
+        Toggle,
+    }
+
+    impl Event for Switch {}
+
+    #[derive(Debug, Clone)]
+    struct Room;
+
+    impl Context for Room {}
+
+    fn light_capabilities() -> Capabilities {
+        let mut builder = StateMachineBuilderFactory::create::<Light, Switch, Room>();
+        builder
+            .external_transition()
+            .from(Light::Off)
+            .to(Light::On)
+            .on(Switch::Toggle)
+            .perform(|_s, _e, _c| {});
+        builder.build().capabilities()
+    }
+
+    // Unused by feature sets without a written-out report below
+    #[allow(dead_code)]
+    fn nothing_compiled() -> Capabilities {
+        Capabilities {
+            history: false,
+            extended: false,
+            metrics: false,
+            hierarchical: false,
+            guards: false,
+            timeout: false,
+            parallel: false,
+            visualization: false,
+            serde: false,
+            async_actions: false,
+            axum: false,
+            binary_snapshots: false,
+            encryption: false,
+            test_util: false,
+            feature_flags_configured: false,
+            state_actions_configured: false,
+            timeouts_configured: false,
+            async_actions_configured: false,
+        }
+    }
+
+    #[test]
+    fn test_nothing_configured() {
+        let capabilities = light_capabilities();
+        assert!(!capabilities.feature_flags_configured);
+        assert!(!capabilities.state_actions_configured);
+        assert!(!capabilities.timeouts_configured);
+        assert!(!capabilities.async_actions_configured);
+    }
+
+    // The reports below are written out for the feature sets CI builds
+    // rather than derived from `cfg!`, which is what the code does
+
+    #[test]
+    #[cfg(all(
+        feature = "history",
+        feature = "extended",
+        feature = "metrics",
+        not(any(
+            feature = "hierarchical",
+            feature = "guards",
+            feature = "timeout",
+            feature = "parallel",
+            feature = "visualization",
+            feature = "serde",
+            feature = "async",
+            feature = "test-util"
+        ))
+    ))]
+    fn test_default_features_report() {
+        let expected = Capabilities {
+            history: true,
+            extended: true,
+            metrics: true,
+            ..nothing_compiled()
+        };
+        assert_eq!(light_capabilities(), expected);
+    }
+
+    #[test]
+    #[cfg(not(any(
+        feature = "history",
+        feature = "extended",
+        feature = "metrics",
+        feature = "hierarchical",
+        feature = "guards",
+        feature = "timeout",
+        feature = "parallel",
+        feature = "visualization",
+        feature = "serde",
+        feature = "async",
+        feature = "test-util"
+    )))]
+    fn test_no_default_features_report() {
+        assert_eq!(light_capabilities(), nothing_compiled());
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "history",
+        feature = "extended",
+        feature = "metrics",
+        feature = "hierarchical",
+        feature = "guards",
+        feature = "timeout",
+        feature = "parallel",
+        feature = "visualization",
+        feature = "serde",
+        feature = "async",
+        feature = "axum",
+        feature = "binary-snapshots",
+        feature = "encryption",
+        feature = "test-util"
+    ))]
+    fn test_all_features_report() {
+        let expected = Capabilities {
+            history: true,
+            extended: true,
+            metrics: true,
+            hierarchical: true,
+            guards: true,
+            timeout: true,
+            parallel: true,
+            visualization: true,
+            serde: true,
+            async_actions: true,
+            axum: true,
+            binary_snapshots: true,
+            encryption: true,
+            test_util: true,
+            ..nothing_compiled()
+        };
+        assert_eq!(light_capabilities(), expected);
+    }
+
+    #[test]
+    #[cfg(all(feature = "extended", feature = "timeout"))]
+    fn test_capabilities_report_configuration() {
+        let mut builder = StateMachineBuilderFactory::create::<Light, Switch, Room>();
+        builder
+            .with_entry_action(Light::On, |_s, _c| {})
+            .with_state_timeout(
+                Light::On,
+                std::time::Duration::from_secs(60),
+                Light::Off,
+                Switch::Toggle,
+            );
+        let capabilities = builder.build().capabilities();
+        assert!(capabilities.state_actions_configured);
+        assert!(capabilities.timeouts_configured);
+    }
+}
@@ -0,0 +1,88 @@
+//! Source of time used by a machine
+//!
+//! Machines read the time through a `Clock` so tests can control it. The
+//! monotonic reading orders events and measures durations; the wall-clock
+//! reading is only used for display, export and filtering.
+
+use std::time::{Instant, SystemTime};
+
+#[cfg(feature = "test-util")]
+use std::sync::Mutex;
+#[cfg(feature = "test-util")]
+use std::time::Duration;
+
+/// Source of monotonic and wall-clock time
+pub trait Clock: Send + Sync {
+    /// Monotonic time, never goes backwards
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, may jump in either direction
+    fn wall_time(&self) -> SystemTime;
+}
+
+/// `Clock` reading the operating system clocks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Manually driven `Clock` for tests
+///
+/// Both readings start at the real current time and only move when told to.
+/// `advance` moves both; `set_wall_time` moves the wall clock alone, like an
+/// NTP correction would.
+#[cfg(feature = "test-util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    state: Mutex<(Duration, SystemTime)>,
+}
+
+#[cfg(feature = "test-util")]
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            state: Mutex::new((Duration::ZERO, SystemTime::now())),
+        }
+    }
+
+    /// Move both the monotonic and the wall clock forward
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += duration;
+        state.1 += duration;
+    }
+
+    /// Set the wall clock without touching the monotonic clock
+    pub fn set_wall_time(&self, time: SystemTime) {
+        self.state.lock().unwrap().1 = time;
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.state.lock().unwrap().0
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.state.lock().unwrap().1
+    }
+}
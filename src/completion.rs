@@ -0,0 +1,190 @@
+//! Run-to-completion firing of follow-up events
+//!
+//! Actions registered with `perform_with_followups` return events to fire
+//! next. `StateMachine::fire_event_to_completion` fires the first event,
+//! then drains the follow-ups in the order they were raised, each from the
+//! state the previous hop ended in. Every hop is an ordinary fire: it gets
+//! its own history record and metrics update. The chain stops at the first
+//! failing hop; hops before it stay applied.
+
+use std::collections::VecDeque;
+
+use crate::{Context, Event, State, StateMachine, TransitionError};
+
+/// Follow-up events fired after the first one unless the builder sets
+/// another limit with `with_max_chain_depth`
+pub const DEFAULT_MAX_CHAIN_DEPTH: usize = 32;
+
+/// Where a chain of events ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion<S> {
+    /// State after the last hop
+    pub state: S,
+    /// The state fired from, then the state each hop ended in
+    pub path: Vec<S>,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Fire `event`, then every follow-up event raised along the way
+    ///
+    /// `context` is shared by all hops, so changes made by `perform_mut`
+    /// actions are seen by the following ones. Once more follow-ups than the
+    /// configured chain depth would fire, the chain stops with
+    /// `TransitionError::MaxChainDepthExceeded`.
+    pub fn fire_event_to_completion(
+        &self,
+        from: S,
+        event: E,
+        mut context: C,
+    ) -> Result<Completion<S>, TransitionError<S, E>> {
+        let mut queue = VecDeque::from([event]);
+        let mut path = vec![from];
+        let mut depth = 0;
+        while let Some(event) = queue.pop_front() {
+            if depth > self.max_chain_depth {
+                return Err(TransitionError::MaxChainDepthExceeded {
+                    depth: self.max_chain_depth,
+                });
+            }
+            let from = path[path.len() - 1].clone();
+            let (outcome, followups) =
+                self.fire_step(from, event, &mut context, None, None, None)?;
+            path.push(outcome.to);
+            queue.extend(followups);
+            depth += 1;
+        }
+        Ok(Completion {
+            state: path[path.len() - 1].clone(),
+            path,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Payment {
+        Pending,
+        Received,
+        Processing,
+        Settled,
+    }
+
+    impl State for Payment {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum PaymentEvent {
+        PaymentReceived,
+        Process,
+        Settle,
+        Retry,
+    }
+
+    impl Event for PaymentEvent {}
+
+    fn payment_machine(max_depth: Option<usize>) -> StateMachine<Payment, PaymentEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, NoContext>();
+        if let Some(depth) = max_depth {
+            builder.with_max_chain_depth(depth);
+        }
+        builder
+            .external_transition()
+            .from(Payment::Pending)
+            .to(Payment::Received)
+            .on(PaymentEvent::PaymentReceived)
+            .perform_with_followups(|_s, _e, _c| vec![PaymentEvent::Process]);
+        builder
+            .external_transition()
+            .from(Payment::Received)
+            .to(Payment::Processing)
+            .on(PaymentEvent::Process)
+            .perform_with_followups(|_s, _e, _c| vec![PaymentEvent::Settle]);
+        builder
+            .external_transition()
+            .from(Payment::Processing)
+            .to(Payment::Settled)
+            .on(PaymentEvent::Settle)
+            .perform(|_s, _e, _c| {});
+        // Retrying never settles: it raises itself again
+        builder
+            .internal_transition()
+            .within(Payment::Processing)
+            .on(PaymentEvent::Retry)
+            .perform_with_followups(|_s, _e, _c| vec![PaymentEvent::Retry]);
+        builder.build()
+    }
+
+    #[test]
+    fn test_followups_run_to_completion() {
+        let machine = payment_machine(None);
+        let completion = machine
+            .fire_event_to_completion(Payment::Pending, PaymentEvent::PaymentReceived, NoContext)
+            .unwrap();
+        assert_eq!(completion.state, Payment::Settled);
+        assert_eq!(
+            completion.path,
+            vec![
+                Payment::Pending,
+                Payment::Received,
+                Payment::Processing,
+                Payment::Settled
+            ]
+        );
+
+        #[cfg(feature = "history")]
+        {
+            let events: Vec<_> = machine
+                .get_history()
+                .into_iter()
+                .map(|record| record.event)
+                .collect();
+            assert_eq!(
+                events,
+                vec![
+                    PaymentEvent::PaymentReceived,
+                    PaymentEvent::Process,
+                    PaymentEvent::Settle
+                ]
+            );
+        }
+        #[cfg(feature = "metrics")]
+        assert_eq!(machine.get_metrics().successful_transitions, 3);
+
+        // A plain fire ignores the follow-ups
+        assert_eq!(
+            machine
+                .fire_event(Payment::Pending, PaymentEvent::PaymentReceived, NoContext)
+                .unwrap(),
+            Payment::Received
+        );
+    }
+
+    #[test]
+    fn test_max_chain_depth_stops_loops() {
+        let machine = payment_machine(Some(5));
+        let result =
+            machine.fire_event_to_completion(Payment::Processing, PaymentEvent::Retry, NoContext);
+        assert!(matches!(
+            result,
+            Err(TransitionError::MaxChainDepthExceeded { depth: 5 })
+        ));
+        #[cfg(feature = "history")]
+        assert_eq!(machine.get_history().len(), 6);
+
+        let result = payment_machine(Some(1)).fire_event_to_completion(
+            Payment::Pending,
+            PaymentEvent::PaymentReceived,
+            NoContext,
+        );
+        assert_eq!(result.unwrap_err().code(), "max_chain_depth_exceeded");
+    }
+}
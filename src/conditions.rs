@@ -0,0 +1,258 @@
+//! Combinators for composing guards
+//!
+//! `Conditions` builds a `CheckedCondition` out of other conditions.
+//! Combined conditions stay `Send + Sync` and short-circuit: `all_of` stops
+//! at the first condition that fails, `any_of` at the first that passes.
+//!
+//! A condition wrapped in `Conditions::named` reports its name when it
+//! fails, and `TransitionError::ConditionFailed` carries the name reported
+//! last while the candidates were evaluated. Names of conditions whose
+//! failure didn't decide the outcome, inside a passing `any_of` or under
+//! `not`, are not reported.
+
+use std::sync::Arc;
+
+use crate::Condition;
+
+/// Outcome of a `CheckedCondition`, failing with the name of the named
+/// condition that decided the failure, if any
+pub type ConditionOutcome = Result<(), Option<&'static str>>;
+
+/// A condition built with `Conditions`
+pub type CheckedCondition<S, E, C> = Arc<dyn Fn(&S, &E, &C) -> ConditionOutcome + Send + Sync>;
+
+// `condition` failing without a name
+pub(crate) fn checked<S, E, C>(condition: Condition<S, E, C>) -> CheckedCondition<S, E, C>
+where
+    S: 'static,
+    E: 'static,
+    C: 'static,
+{
+    Conditions::of(move |s, e, c| condition(s, e, c))
+}
+
+/// Constructors of combined conditions
+pub struct Conditions;
+
+impl Conditions {
+    /// Box a closure as a `CheckedCondition` failing without a name
+    pub fn of<S, E, C, F>(condition: F) -> CheckedCondition<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
+    {
+        Arc::new(move |s, e, c| condition(s, e, c).then_some(()).ok_or(None))
+    }
+
+    /// Passes when every condition passes; an empty list passes
+    pub fn all_of<S, E, C>(conditions: Vec<CheckedCondition<S, E, C>>) -> CheckedCondition<S, E, C>
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        Arc::new(move |s, e, c| {
+            conditions
+                .iter()
+                .try_for_each(|condition| condition(s, e, c))
+        })
+    }
+
+    /// Passes when at least one condition passes; an empty list fails
+    ///
+    /// Failing, it reports the last name its conditions reported.
+    pub fn any_of<S, E, C>(conditions: Vec<CheckedCondition<S, E, C>>) -> CheckedCondition<S, E, C>
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        Arc::new(move |s, e, c| {
+            let mut failed = None;
+            for condition in &conditions {
+                match condition(s, e, c) {
+                    Ok(()) => return Ok(()),
+                    Err(name) => failed = name.or(failed),
+                }
+            }
+            Err(failed)
+        })
+    }
+
+    /// Passes when `condition` fails
+    pub fn not<S, E, C>(condition: CheckedCondition<S, E, C>) -> CheckedCondition<S, E, C>
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        Arc::new(move |s, e, c| match condition(s, e, c) {
+            Ok(()) => Err(None),
+            Err(_) => Ok(()),
+        })
+    }
+
+    /// `condition`, reporting `name` in `ConditionFailed` when it fails
+    pub fn named<S, E, C, F>(name: &'static str, condition: F) -> CheckedCondition<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
+    {
+        Arc::new(move |s, e, c| condition(s, e, c).then_some(()).ok_or(Some(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, Event, State, StateMachine, StateMachineBuilderFactory, TransitionError};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Transfer {
+        Pending,
+        Sent,
+        Flagged,
+    }
+
+    impl State for Transfer {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum TransferEvent {
+        Send,
+        Review,
+    }
+
+    impl Event for TransferEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Payment {
+        amount: i64,
+        verified: bool,
+        sanctioned: bool,
+    }
+
+    impl Context for Payment {}
+
+    fn payment(amount: i64, verified: bool, sanctioned: bool) -> Payment {
+        Payment {
+            amount,
+            verified,
+            sanctioned,
+        }
+    }
+
+    fn transfer_machine(
+        evaluated: Arc<AtomicUsize>,
+    ) -> StateMachine<Transfer, TransferEvent, Payment> {
+        let mut builder = StateMachineBuilderFactory::create::<Transfer, TransferEvent, Payment>();
+        builder
+            .external_transition()
+            .from(Transfer::Pending)
+            .to(Transfer::Sent)
+            .on(TransferEvent::Send)
+            .when_all(vec![
+                Conditions::named("amount_positive", |_s, _e, c: &Payment| c.amount > 0),
+                Conditions::named("verified", |_s, _e, c: &Payment| c.verified),
+                Conditions::not(Conditions::named("sanctioned", |_s, _e, c: &Payment| {
+                    c.sanctioned
+                })),
+                Conditions::of(move |_s, _e, _c| {
+                    evaluated.fetch_add(1, Ordering::SeqCst);
+                    true
+                }),
+            ])
+            .add();
+        builder
+            .external_transition()
+            .from(Transfer::Pending)
+            .to(Transfer::Flagged)
+            .on(TransferEvent::Review)
+            .when_any(vec![
+                Conditions::named("large", |_s, _e, c: &Payment| c.amount > 10_000),
+                Conditions::named("sanctioned", |_s, _e, c: &Payment| c.sanctioned),
+            ])
+            .add();
+        builder.build()
+    }
+
+    fn failed_guard(result: Result<Transfer, TransitionError<Transfer, TransferEvent>>) -> String {
+        match result {
+            Err(TransitionError::ConditionFailed {
+                guard: Some(guard), ..
+            }) => guard,
+            other => panic!("expected a named ConditionFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_all_of_short_circuits_and_names_failing_guard() {
+        let evaluated = Arc::new(AtomicUsize::new(0));
+        let machine = transfer_machine(evaluated.clone());
+        let send = |payment| machine.fire_event(Transfer::Pending, TransferEvent::Send, payment);
+
+        assert_eq!(send(payment(100, true, false)).unwrap(), Transfer::Sent);
+        assert_eq!(evaluated.load(Ordering::SeqCst), 1);
+
+        let error = send(payment(0, true, false)).unwrap_err();
+        assert!(error.to_string().ends_with("(guard amount_positive)"));
+        assert_eq!(failed_guard(Err(error)), "amount_positive");
+        assert_eq!(failed_guard(send(payment(100, false, false))), "verified");
+        // `not` fails because its inner condition passed; nothing inside it
+        // failed, so no name is reported
+        assert!(matches!(
+            send(payment(100, true, true)),
+            Err(TransitionError::ConditionFailed { guard: None, .. })
+        ));
+        assert_eq!(evaluated.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_any_of_passes_on_first_match() {
+        let machine = transfer_machine(Arc::default());
+        let review =
+            |payment| machine.fire_event(Transfer::Pending, TransferEvent::Review, payment);
+
+        assert_eq!(
+            review(payment(20_000, true, false)).unwrap(),
+            Transfer::Flagged
+        );
+        assert_eq!(review(payment(5, true, true)).unwrap(), Transfer::Flagged);
+        assert_eq!(failed_guard(review(payment(5, true, false))), "sanctioned");
+    }
+
+    #[test]
+    fn test_name_survives_guards_firing_other_machines() {
+        let inner = Arc::new(transfer_machine(Arc::default()));
+        let mut builder = StateMachineBuilderFactory::create::<Transfer, TransferEvent, Payment>();
+        builder
+            .external_transition()
+            .from(Transfer::Pending)
+            .to(Transfer::Sent)
+            .on(TransferEvent::Send)
+            .when_all(vec![Conditions::named(
+                "verified",
+                move |_s, _e, c: &Payment| {
+                    // A failing fire of its own while this guard decides
+                    let _ = inner.fire_event(Transfer::Pending, TransferEvent::Review, c.clone());
+                    c.verified
+                },
+            )])
+            .add();
+        let machine = builder.build();
+
+        let unverified = payment(5, false, false);
+        assert_eq!(
+            failed_guard(machine.fire_event(
+                Transfer::Pending,
+                TransferEvent::Send,
+                unverified.clone()
+            )),
+            "verified"
+        );
+        match machine.peek(&Transfer::Pending, &TransferEvent::Send, &unverified) {
+            Err(TransitionError::ConditionFailed { guard, .. }) => {
+                assert_eq!(guard.as_deref(), Some("verified"))
+            }
+            other => panic!("expected ConditionFailed, got {:?}", other),
+        }
+    }
+}
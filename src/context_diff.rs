@@ -0,0 +1,321 @@
+//! What a fire changed in the context, for audit logs
+//!
+//! A machine built with `StateMachineBuilder::with_context_differ` keeps a
+//! copy of the context when a fire starts and, once the fire succeeded,
+//! compares it with the context `perform_mut` and the mutable entry/exit
+//! actions left behind. The changes go to `TransitionOutcome::context_changes`,
+//! to `TransitionRecord::context_changes` and to the
+//! `after_context_changes` hook of the listeners.
+//!
+//! `json_differ` compares the serialized form of contexts implementing
+//! `serde::Serialize`.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{Context, Event, State, StateMachineBuilder};
+
+/// A field of the context changed by a fire
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldChange {
+    /// Path of the field, e.g. `"payment.id"` or `"items[2]"`
+    pub path: String,
+    /// Value before the fire, `None` when the field was added
+    pub before: Option<String>,
+    /// Value after the fire, `None` when the field was removed
+    pub after: Option<String>,
+}
+
+impl FieldChange {
+    /// A field changed from `before` to `after`
+    pub fn new(
+        path: impl Into<String>,
+        before: impl fmt::Display,
+        after: impl fmt::Display,
+    ) -> Self {
+        FieldChange {
+            path: path.into(),
+            before: Some(before.to_string()),
+            after: Some(after.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "<none>".to_string());
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            show(&self.before),
+            show(&self.after)
+        )
+    }
+}
+
+pub(crate) type ContextDiffer<C> = Arc<dyn Fn(&C, &C) -> Vec<FieldChange> + Send + Sync>;
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Compare the context before and after every successful fire with
+    /// `differ`, see the module documentation
+    pub fn with_context_differ<F>(&mut self, differ: F) -> &mut Self
+    where
+        F: Fn(&C, &C) -> Vec<FieldChange> + Send + Sync + 'static,
+    {
+        self.context_differ = Some(Arc::new(differ));
+        self
+    }
+}
+
+/// Differ comparing the JSON form of two contexts field by field
+///
+/// Objects are compared by key and arrays by index, down to the scalar
+/// values, which are reported as JSON text. A context that fails to
+/// serialize is reported as a single change at the root path `""`.
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub fn json_differ<C>(before: &C, after: &C) -> Vec<FieldChange>
+where
+    C: serde::Serialize,
+{
+    let mut changes = Vec::new();
+    match (serde_json::to_value(before), serde_json::to_value(after)) {
+        (Ok(before), Ok(after)) => {
+            diff_values(String::new(), Some(&before), Some(&after), &mut changes)
+        }
+        (before, after) => changes.push(FieldChange {
+            path: String::new(),
+            before: before.err().map(|error| error.to_string()),
+            after: after.err().map(|error| error.to_string()),
+        }),
+    }
+    changes
+}
+
+#[cfg(feature = "serde")]
+fn diff_values(
+    path: String,
+    before: Option<&serde_json::Value>,
+    after: Option<&serde_json::Value>,
+    changes: &mut Vec<FieldChange>,
+) {
+    use serde_json::Value;
+
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let field = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_values(field, before.get(key), after.get(key), changes);
+            }
+        }
+        (Some(Value::Array(before)), Some(Value::Array(after))) => {
+            for index in 0..before.len().max(after.len()) {
+                let item = format!("{}[{}]", path, index);
+                diff_values(item, before.get(index), after.get(index), changes);
+            }
+        }
+        (before, after) if before != after => changes.push(FieldChange {
+            path,
+            before: before.map(Value::to_string),
+            after: after.map(Value::to_string),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+    use crate::{StateMachine, StateMachineBuilderFactory, TransitionListener};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    struct Customer {
+        name: String,
+        city: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    struct OrderContext {
+        payment_id: Option<String>,
+        retries: u32,
+        customer: Customer,
+    }
+
+    impl Context for OrderContext {}
+
+    fn order_context() -> OrderContext {
+        OrderContext {
+            payment_id: None,
+            retries: 0,
+            customer: Customer {
+                name: "Ada".to_string(),
+                city: "London".to_string(),
+            },
+        }
+    }
+
+    fn payment_id_differ(before: &OrderContext, after: &OrderContext) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        if before.payment_id != after.payment_id {
+            changes.push(FieldChange::new(
+                "payment_id",
+                format!("{:?}", before.payment_id),
+                format!("{:?}", after.payment_id),
+            ));
+        }
+        changes
+    }
+
+    #[derive(Default)]
+    struct ChangeLog {
+        seen: Mutex<Vec<Vec<FieldChange>>>,
+    }
+
+    impl TransitionListener<Order, OrderEvent, OrderContext> for Arc<ChangeLog> {
+        fn after_context_changes(
+            &self,
+            _from: &Order,
+            _to: &Order,
+            _event: &OrderEvent,
+            changes: &[FieldChange],
+            _context: &OrderContext,
+        ) {
+            self.seen.lock().unwrap().push(changes.to_vec());
+        }
+    }
+
+    fn diffed_machine(
+        differ: fn(&OrderContext, &OrderContext) -> Vec<FieldChange>,
+        log: &Arc<ChangeLog>,
+    ) -> StateMachine<Order, OrderEvent, OrderContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform_mut(|_s, _e, c| {
+                c.payment_id = Some("pay_1".to_string());
+                c.customer.city = "Paris".to_string();
+            });
+        builder
+            .internal_transition()
+            .within(Order::Placed)
+            .on(OrderEvent::Note)
+            .add();
+        builder
+            .with_context_differ(differ)
+            .add_listener(Box::new(log.clone()));
+        builder.build()
+    }
+
+    #[test]
+    fn test_mutated_field_in_diff() {
+        let log = Arc::new(ChangeLog::default());
+        let machine = diffed_machine(payment_id_differ, &log);
+
+        let outcome = machine
+            .fire_event_detailed(Order::Placed, OrderEvent::Pay, order_context())
+            .unwrap();
+        let expected = vec![FieldChange::new("payment_id", "None", "Some(\"pay_1\")")];
+        assert_eq!(outcome.context_changes, expected);
+        assert_eq!(*log.seen.lock().unwrap(), vec![expected.clone()]);
+
+        #[cfg(feature = "history")]
+        {
+            let history = machine.get_history();
+            assert_eq!(history[0].context_changes, Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_unchanged_context_gives_empty_diff() {
+        let log = Arc::new(ChangeLog::default());
+        let machine = diffed_machine(payment_id_differ, &log);
+
+        let outcome = machine
+            .fire_event_detailed(Order::Placed, OrderEvent::Note, order_context())
+            .unwrap();
+        assert!(outcome.context_changes.is_empty());
+        assert_eq!(*log.seen.lock().unwrap(), vec![Vec::new()]);
+
+        // Without a differ the record carries no changes at all
+        #[cfg(feature = "history")]
+        {
+            let mut builder =
+                StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+            builder
+                .internal_transition()
+                .within(Order::Placed)
+                .on(OrderEvent::Note)
+                .add();
+            let plain = builder.build();
+            plain
+                .fire_event(Order::Placed, OrderEvent::Note, order_context())
+                .unwrap();
+            assert_eq!(plain.get_history()[0].context_changes, None);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_differ_walks_nested_structs() {
+        let log = Arc::new(ChangeLog::default());
+        let machine = diffed_machine(json_differ, &log);
+
+        let mut context = order_context();
+        let outcome = machine
+            .fire_event_mut(Order::Placed, OrderEvent::Pay, &mut context)
+            .unwrap();
+        assert_eq!(outcome, Order::Paid);
+        assert_eq!(
+            log.seen.lock().unwrap()[0],
+            vec![
+                FieldChange::new("customer.city", "\"London\"", "\"Paris\""),
+                FieldChange::new("payment_id", "null", "\"pay_1\""),
+            ]
+        );
+
+        let before = serde_json::json!({ "items": [1, 2], "note": "a" });
+        let after = serde_json::json!({ "items": [1, 3, 4] });
+        let changes = {
+            let mut changes = Vec::new();
+            diff_values(String::new(), Some(&before), Some(&after), &mut changes);
+            changes
+        };
+        assert_eq!(
+            changes,
+            vec![
+                FieldChange::new("items[1]", "2", "3"),
+                FieldChange {
+                    path: "items[2]".to_string(),
+                    before: None,
+                    after: Some("4".to_string()),
+                },
+                FieldChange {
+                    path: "note".to_string(),
+                    before: Some("\"a\"".to_string()),
+                    after: None,
+                },
+            ]
+        );
+    }
+}
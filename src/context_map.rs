@@ -0,0 +1,478 @@
+//! Driving a machine built for one context type with another
+//!
+//! `StateMachine::map_context` wraps every stored callback so it converts
+//! the new context into the original one on each invocation. Callbacks
+//! receiving values from `with_derived` get them derived from the converted
+//! context; such values are then shared within one callback rather than
+//! across the whole fire. Data a `when_providing` guard hands to its action
+//! is shared across the fire as usual. Mutable actions update the converted
+//! copy, so their changes do not reach the `C2` context.
+
+use std::sync::{Arc, OnceLock};
+
+use crate::approval::map_checker;
+use crate::callbacks::{Action, Guard};
+use crate::context_diff::ContextDiffer;
+use crate::derived::DerivationMap;
+use crate::eventless::CompletionTransition;
+use crate::info::{InfoAction, InfoCondition};
+use crate::listeners::map_listeners;
+use crate::{Context, Event, FeatureFlags, State, StateMachine, Transition};
+
+/// Conversion from the context a machine is driven with to the one it was
+/// built for
+pub type ContextMapper<C2, C> = Arc<dyn Fn(&C2) -> C + Send + Sync>;
+
+type Callback<S, E, C, R> = Arc<dyn Fn(&S, &E, &C) -> R + Send + Sync>;
+
+fn map_callback<S, E, C, C2, R>(
+    callback: Callback<S, E, C, R>,
+    map: &ContextMapper<C2, C>,
+) -> Callback<S, E, C2, R>
+where
+    S: 'static,
+    E: 'static,
+    C: 'static,
+    C2: 'static,
+    R: 'static,
+{
+    let map = map.clone();
+    Arc::new(move |s, e, c| callback(s, e, &map(c)))
+}
+
+fn map_callback_mut<S, E, C, C2>(
+    action: crate::ActionMut<S, E, C>,
+    map: &ContextMapper<C2, C>,
+) -> crate::ActionMut<S, E, C2>
+where
+    S: 'static,
+    E: 'static,
+    C: 'static,
+    C2: 'static,
+{
+    let map = map.clone();
+    Arc::new(move |s, e, c| action(s, e, &mut map(c)))
+}
+
+fn map_info_condition<S, E, C, C2>(
+    condition: InfoCondition<S, E, C>,
+    derivations: &Arc<DerivationMap<C>>,
+    map: &ContextMapper<C2, C>,
+) -> InfoCondition<S, E, C2>
+where
+    S: 'static,
+    E: 'static,
+    C: 'static,
+    C2: 'static,
+{
+    let (derivations, map) = (derivations.clone(), map.clone());
+    Arc::new(move |info, c, values| {
+        values.with_derivations(&derivations, |values| condition(info, &map(c), values))
+    })
+}
+
+fn map_info_action<S, E, C, C2>(
+    action: InfoAction<S, E, C>,
+    derivations: &Arc<DerivationMap<C>>,
+    map: &ContextMapper<C2, C>,
+) -> InfoAction<S, E, C2>
+where
+    S: 'static,
+    E: 'static,
+    C: 'static,
+    C2: 'static,
+{
+    let (derivations, map) = (derivations.clone(), map.clone());
+    Arc::new(move |info, c, values| {
+        values.with_derivations(&derivations, |values| action(info, &map(c), values))
+    })
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State + 'static,
+    E: Event + 'static,
+    C: Context + 'static,
+{
+    /// Drive this machine with `C2` contexts, converted with `map`
+    ///
+    /// History, metrics and the archived flag are kept; runtime overrides
+    /// are not carried over.
+    #[cfg(not(feature = "async"))]
+    pub fn map_context<C2>(self, map: ContextMapper<C2, C>) -> StateMachine<S, E, C2>
+    where
+        C2: Context + 'static,
+    {
+        self.map_definition(map)
+    }
+
+    /// Drive this machine with `C2` contexts, converted with `map`
+    ///
+    /// History, metrics and the archived flag are kept; runtime overrides
+    /// are not carried over.
+    #[cfg(feature = "async")]
+    pub fn map_context<C2>(mut self, map: ContextMapper<C2, C>) -> StateMachine<S, E, C2>
+    where
+        S: Send + Sync,
+        E: Send + Sync,
+        C: Send + Sync,
+        C2: Context + Send + Sync + 'static,
+    {
+        let async_actions = std::mem::take(&mut self.async_actions);
+        let mut machine = self.map_definition(map.clone());
+        machine.async_actions = async_actions
+            .into_iter()
+            .map(|(key, inner)| {
+                let action: Arc<dyn crate::AsyncAction<S, E, C2>> =
+                    Arc::new(async_map::MappedAsyncAction {
+                        inner,
+                        map: map.clone(),
+                    });
+                (key, action)
+            })
+            .collect();
+        machine
+    }
+
+    // Everything but the async actions
+    fn map_definition<C2>(self, map: ContextMapper<C2, C>) -> StateMachine<S, E, C2>
+    where
+        C2: Context + 'static,
+    {
+        let derivations = Arc::new(self.derivations);
+        let map_transition = |t: Transition<S, E, C>| Transition {
+            from: t.from,
+            to: t.to,
+            event: t.event,
+            guard: {
+                let (plain, info) = t.guard.into_parts();
+                Guard::from_parts(
+                    plain.map(|c| map_callback(c, &map)),
+                    info.map(|c| map_info_condition(c, &derivations, &map)),
+                )
+            },
+            action: match t.action {
+                Action::Nothing => Action::Nothing,
+                Action::Plain(a) => Action::Plain(map_callback(a, &map)),
+                Action::Mut(a) => Action::Mut(map_callback_mut(a, &map)),
+                Action::Fallible(a) => Action::Fallible(map_callback(a, &map)),
+                Action::Followups(a) => Action::Followups(map_callback(a, &map)),
+                Action::Info(a) => Action::Info(map_info_action(a, &derivations, &map)),
+            },
+            transition_type: t.transition_type,
+            required_flag: t.required_flag,
+            approval: t.approval.map(|checker| map_checker(checker, &map)),
+            pure_action: t.pure_action,
+            irreversible: t.irreversible,
+            tag: t.tag,
+            names: t.names,
+            #[cfg(feature = "guards")]
+            priority: t.priority,
+        };
+        let transitions = self
+            .transitions
+            .into_iter()
+            .map(|(key, candidates)| {
+                let candidates: Vec<Transition<S, E, C2>> = candidates
+                    .into_vec()
+                    .into_iter()
+                    .map(map_transition)
+                    .collect();
+                (key, candidates.into_boxed_slice())
+            })
+            .collect();
+        let wildcard_transitions = self
+            .wildcard_transitions
+            .into_iter()
+            .map(|(event, candidates)| {
+                let candidates: Vec<Transition<S, E, C2>> = candidates
+                    .into_vec()
+                    .into_iter()
+                    .map(map_transition)
+                    .collect();
+                (event, candidates.into_boxed_slice())
+            })
+            .collect();
+
+        let mapped_derivations: DerivationMap<C2> = derivations
+            .iter()
+            .map(|(type_id, derive)| {
+                let (derive, map) = (derive.clone(), map.clone());
+                let mapped: crate::derived::Derivation<C2> =
+                    Arc::new(move |c: &C2| derive(&map(c)));
+                (*type_id, mapped)
+            })
+            .collect();
+
+        StateMachine {
+            id: self.id,
+            transitions,
+            wildcard_transitions,
+            incoming: self.incoming,
+            accepted: OnceLock::new(),
+            completion_transitions: self
+                .completion_transitions
+                .into_iter()
+                .map(|(from, candidates)| {
+                    let candidates: Vec<CompletionTransition<S, E, C2>> = candidates
+                        .into_vec()
+                        .into_iter()
+                        .map(|t| CompletionTransition {
+                            from: t.from,
+                            to: t.to,
+                            condition: t.condition.map(|c| map_callback(c, &map)),
+                            #[cfg(feature = "guards")]
+                            priority: t.priority,
+                        })
+                        .collect();
+                    (from, candidates.into_boxed_slice())
+                })
+                .collect(),
+            fail_callback: self.fail_callback.map(|f| map_callback(f, &map)),
+            listeners: map_listeners(self.listeners, &map),
+            context_differ: self.context_differ.map(|differ| {
+                let map = map.clone();
+                let mapped: ContextDiffer<C2> =
+                    Arc::new(move |before: &C2, after: &C2| differ(&map(before), &map(after)));
+                mapped
+            }),
+            feature_flags: self.feature_flags.map(|flags| {
+                let (key, map) = (flags.key, map.clone());
+                FeatureFlags {
+                    provider: flags.provider,
+                    key: Arc::new(move |c: &C2| key(&map(c))),
+                }
+            }),
+            derivations: mapped_derivations,
+            services: self.services,
+            guard_resolution: self.guard_resolution,
+            max_chain_depth: self.max_chain_depth,
+            sequence_window: self.sequence_window,
+            instance_limits: self.instance_limits,
+            initial_state: self.initial_state,
+            terminal_states: self.terminal_states,
+            locked_events: self.locked_events,
+            clock: self.clock,
+            determinism: self.determinism,
+            deadline_check: self.deadline_check.map(|d| d.map_context(&map)),
+            slow_callbacks: self.slow_callbacks,
+            #[cfg(feature = "metrics")]
+            failure_rate: self.failure_rate,
+            overrides: Arc::default(),
+            archived: self.archived,
+            descriptions: self.descriptions,
+            names: self.names,
+            recording: self.recording,
+            #[cfg(feature = "extended")]
+            state_actions: self
+                .state_actions
+                .into_iter()
+                .map(|(state, actions)| {
+                    let wrap = |action: crate::StateAction<S, C>| {
+                        let map = map.clone();
+                        let mapped: crate::StateAction<S, C2> =
+                            Arc::new(move |s: &S, c: &C2| action(s, &map(c)));
+                        mapped
+                    };
+                    let wrap_mut = |action: crate::StateActionMut<S, C>| {
+                        let map = map.clone();
+                        let mapped: crate::StateActionMut<S, C2> =
+                            Arc::new(move |s: &S, c: &mut C2| action(s, &mut map(c)));
+                        mapped
+                    };
+                    let actions = crate::StateActions {
+                        on_entry: actions.on_entry.map(wrap),
+                        on_exit: actions.on_exit.map(wrap),
+                        on_entry_mut: actions.on_entry_mut.map(wrap_mut),
+                        on_exit_mut: actions.on_exit_mut.map(wrap_mut),
+                        on_entry_fallible: actions.on_entry_fallible.map(|action| {
+                            let map = map.clone();
+                            let mapped: crate::FallibleStateAction<S, C2> =
+                                Arc::new(move |s: &S, c: &C2| action(s, &map(c)));
+                            mapped
+                        }),
+                        pure: actions.pure,
+                        _phantom: Default::default(),
+                    };
+                    (state, actions)
+                })
+                .collect(),
+            #[cfg(feature = "extended")]
+            state_requirements: self
+                .state_requirements
+                .into_iter()
+                .map(|(state, requirements)| {
+                    let requirements = requirements
+                        .into_iter()
+                        .map(|requirement| {
+                            let (check, map) = (requirement.check, map.clone());
+                            crate::StateRequirement {
+                                name: requirement.name,
+                                check: Arc::new(move |c: &C2| check(&map(c))),
+                            }
+                        })
+                        .collect();
+                    (state, requirements)
+                })
+                .collect(),
+            #[cfg(feature = "timeout")]
+            state_timeouts: self.state_timeouts,
+            #[cfg(feature = "timeout")]
+            timeout_transitions: self.timeout_transitions,
+            #[cfg(feature = "async")]
+            async_actions: Default::default(),
+            #[cfg(feature = "async")]
+            cancel_callbacks: self
+                .cancel_callbacks
+                .into_iter()
+                .map(|(key, cleanup)| (key, map_callback(cleanup, &map)))
+                .collect(),
+            #[cfg(feature = "serde")]
+            snapshot_codec: self.snapshot_codec,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+mod async_map {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use super::ContextMapper;
+    use crate::{AsyncAction, Context, Event, State};
+
+    pub(super) struct MappedAsyncAction<S, E, C, C2> {
+        pub(super) inner: Arc<dyn AsyncAction<S, E, C>>,
+        pub(super) map: ContextMapper<C2, C>,
+    }
+
+    #[async_trait]
+    impl<S, E, C, C2> AsyncAction<S, E, C2> for MappedAsyncAction<S, E, C, C2>
+    where
+        S: State + Send + Sync,
+        E: Event + Send + Sync,
+        C: Context + Send + Sync,
+        C2: Context + Send + Sync,
+    {
+        async fn execute(&self, from: &S, event: &E, context: &C2) {
+            let context = (self.map)(context);
+            self.inner.execute(from, event, &context).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+    use crate::StateMachineBuilderFactory;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone)]
+    struct OrderContext {
+        order_id: String,
+        amount_cents: u64,
+    }
+
+    impl Context for OrderContext {}
+
+    // Older service storing the amount as a decimal string
+    #[derive(Debug, Clone)]
+    struct LegacyOrderContext {
+        id: u32,
+        amount: String,
+    }
+
+    impl Context for LegacyOrderContext {}
+
+    #[test]
+    fn test_machine_driven_with_mapped_context() {
+        let approved = Arc::new(Mutex::new(Vec::new()));
+        let log = approved.clone();
+
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .when(|_s, _e, c| c.amount_cents < 10_000)
+            .perform(move |_s, _e, c| log.lock().unwrap().push(c.order_id.clone()));
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::OnHold)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        let machine = builder
+            .build()
+            .map_context(Arc::new(|c: &LegacyOrderContext| {
+                let (units, cents) = c.amount.split_once('.').unwrap_or((&c.amount, "0"));
+                OrderContext {
+                    order_id: format!("ORD-{}", c.id),
+                    amount_cents: units.parse::<u64>().unwrap() * 100
+                        + cents.parse::<u64>().unwrap(),
+                }
+            }));
+
+        let small = LegacyOrderContext {
+            id: 7,
+            amount: "99.99".to_string(),
+        };
+        let large = LegacyOrderContext {
+            id: 8,
+            amount: "100.00".to_string(),
+        };
+        assert_eq!(
+            machine
+                .fire_event(Order::Placed, OrderEvent::Pay, small)
+                .unwrap(),
+            Order::Paid
+        );
+        assert_eq!(
+            machine
+                .fire_event(Order::Placed, OrderEvent::Pay, large)
+                .unwrap(),
+            Order::OnHold
+        );
+        assert_eq!(*approved.lock().unwrap(), vec!["ORD-7"]);
+    }
+
+    #[test]
+    fn test_mapped_machine_hands_guard_data_to_action() {
+        let approved = Arc::new(Mutex::new(Vec::new()));
+        let log = approved.clone();
+
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .when_providing(|_s, _e, c: &OrderContext| {
+                (c.amount_cents < 10_000).then(|| c.order_id.clone())
+            })
+            .perform_with(move |_s, _e, _c, order_id: &String| {
+                log.lock().unwrap().push(order_id.clone())
+            });
+        let machine = builder
+            .build()
+            .map_context(Arc::new(|c: &LegacyOrderContext| OrderContext {
+                order_id: format!("ORD-{}", c.id),
+                amount_cents: c.amount.parse::<u64>().unwrap() * 100,
+            }));
+
+        let small = LegacyOrderContext {
+            id: 7,
+            amount: "99".to_string(),
+        };
+        assert_eq!(
+            machine
+                .fire_event(Order::Placed, OrderEvent::Pay, small)
+                .unwrap(),
+            Order::Paid
+        );
+        assert_eq!(*approved.lock().unwrap(), vec!["ORD-7"]);
+    }
+}
@@ -0,0 +1,913 @@
+//! Contexts rebuilt from storage for entities fired by key
+//!
+//! `EntityManager::fire` fires an event for an entity of a `StateRepository`
+//! without the caller supplying a context. It loads the current state from
+//! the repository and the context from a `ContextLoader` and fires as
+//! `fire_event_mut` does. The new state is saved first, with the version
+//! check of the repository, and only then is the context, with whatever
+//! `perform_mut` and the mutable entry/exit actions changed, handed to the
+//! `ContextSaver` if one is set. A fire losing the race for the entity thus
+//! never overwrites the context of the one that won.
+//!
+//! `InMemoryContextStore` keeps contexts in memory. `FileContextStore`
+//! (requires `serde`) writes each one to a file through a `SnapshotCodec`,
+//! so contexts can be encrypted at rest with the machine's codec.
+//!
+//! A failed load rejects the event with `TransitionError::ContextLoadFailed`
+//! before any guard runs. A failed save fails the fire with
+//! `TransitionError::ContextSaveFailed` after putting the old state back in
+//! the repository; the error carries the conflict if someone else saved the
+//! entity in the meantime, leaving their state in place. History, metrics
+//! and listeners see a fire whose state or context was not saved as failed
+//! with that error. Actions that ran are not undone.
+//!
+//! With `EntityManager::with_retention`, the manager keeps track of the
+//! entities its fires left in a terminal state and evicts them once the
+//! retention has passed on the machine's clock. Every `fire` evicts those
+//! due, `sweep` does so without firing and `evict_now` evicts one of them
+//! right away. `with_max_pending` also caps how many are tracked, evicting
+//! the longest ended first. Eviction hands the entity to the `on_evict`
+//! callback, e.g. to archive it, and drops the tracking; the repository
+//! keeps whatever it stores.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::repository::save_fired;
+use crate::{Context, Event, State, StateMachine, StateRepository, TransitionError};
+
+/// Why the context of an entity could not be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// Storage holds no context for the entity
+    NotFound,
+    /// Storage failed, e.g. on I/O or decoding
+    Storage { reason: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "no stored context"),
+            LoadError::Storage { reason } => write!(f, "context storage failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Why the context of an entity could not be saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveError {
+    pub reason: String,
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "context could not be saved: {}", self.reason)
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+/// Rebuilds the context of an entity from storage
+pub trait ContextLoader<K, C>: Send + Sync {
+    fn load(&self, key: &K) -> Result<C, LoadError>;
+}
+
+/// Persists the context of an entity after a successful fire
+pub trait ContextSaver<K, C>: Send + Sync {
+    fn save(&self, key: &K, context: &C) -> Result<(), SaveError>;
+}
+
+/// `ContextLoader` and `ContextSaver` keeping contexts in a `HashMap`
+pub struct InMemoryContextStore<K, C> {
+    contexts: Mutex<HashMap<K, C>>,
+}
+
+impl<K, C> InMemoryContextStore<K, C>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        InMemoryContextStore {
+            contexts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Store `context` for `key`, replacing the one stored before
+    pub fn insert(&self, key: K, context: C) {
+        self.contexts.lock().unwrap().insert(key, context);
+    }
+
+    /// The context stored for `key`
+    pub fn get(&self, key: &K) -> Option<C>
+    where
+        C: Clone,
+    {
+        self.contexts.lock().unwrap().get(key).cloned()
+    }
+}
+
+impl<K, C> Default for InMemoryContextStore<K, C>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, C> ContextLoader<K, C> for InMemoryContextStore<K, C>
+where
+    K: Eq + Hash + Send,
+    C: Clone + Send,
+{
+    fn load(&self, key: &K) -> Result<C, LoadError> {
+        self.get(key).ok_or(LoadError::NotFound)
+    }
+}
+
+impl<K, C> ContextSaver<K, C> for InMemoryContextStore<K, C>
+where
+    K: Eq + Hash + Clone + Send,
+    C: Clone + Send,
+{
+    fn save(&self, key: &K, context: &C) -> Result<(), SaveError> {
+        self.insert(key.clone(), context.clone());
+        Ok(())
+    }
+}
+
+/// `ContextLoader` and `ContextSaver` keeping each context as JSON in a file
+/// of its own, passed through a `SnapshotCodec` (requires the `serde`
+/// feature)
+///
+/// Files are named after the hex digits of the key's `Debug` form and are
+/// replaced in one rename, so a crash never leaves half a context behind.
+/// A context that does not decode, e.g. under another key, fails to load
+/// with `LoadError::Storage`.
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub struct FileContextStore<K, C> {
+    dir: std::path::PathBuf,
+    codec: Arc<dyn crate::SnapshotCodec>,
+    _entries: std::marker::PhantomData<fn(&K) -> C>,
+}
+
+#[cfg(feature = "serde")]
+impl<K, C> FileContextStore<K, C>
+where
+    K: Debug,
+{
+    /// Store contexts under `dir`, creating it if needed, without encoding
+    pub fn open(dir: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        Ok(FileContextStore {
+            dir: dir.as_ref().to_path_buf(),
+            codec: Arc::new(crate::PassThroughCodec),
+            _entries: std::marker::PhantomData,
+        })
+    }
+
+    /// Pass every context through `codec`, e.g. to encrypt it
+    pub fn with_codec(mut self, codec: Arc<dyn crate::SnapshotCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// The file holding the context of `key`
+    pub fn path(&self, key: &K) -> std::path::PathBuf {
+        let name: String = format!("{:?}", key)
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.dir.join(name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, C> ContextLoader<K, C> for FileContextStore<K, C>
+where
+    K: Debug,
+    C: serde::de::DeserializeOwned,
+{
+    fn load(&self, key: &K) -> Result<C, LoadError> {
+        let storage = |reason: String| LoadError::Storage { reason };
+        let bytes = match std::fs::read(self.path(key)) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Err(LoadError::NotFound)
+            }
+            Err(error) => return Err(storage(error.to_string())),
+        };
+        let json = self
+            .codec
+            .decode(&bytes)
+            .map_err(|error| storage(error.reason))?;
+        serde_json::from_slice(&json).map_err(|error| storage(error.to_string()))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, C> ContextSaver<K, C> for FileContextStore<K, C>
+where
+    K: Debug,
+    C: serde::Serialize,
+{
+    fn save(&self, key: &K, context: &C) -> Result<(), SaveError> {
+        let failed = |reason: String| SaveError { reason };
+        let json = serde_json::to_vec(context).map_err(|error| failed(error.to_string()))?;
+        let path = self.path(key);
+        let staged = path.with_extension("tmp");
+        std::fs::write(&staged, self.codec.encode(&json))
+            .and_then(|_| std::fs::rename(&staged, &path))
+            .map_err(|error| failed(error.to_string()))
+    }
+}
+
+type EvictCallback<K, S> = Arc<dyn Fn(&K, &S) + Send + Sync>;
+
+// Entities left in a terminal state, ordered by the time they got there so
+// the due ones are found without looking at the others
+struct Ended<K, S> {
+    entries: HashMap<K, (S, Slot)>,
+    order: BTreeMap<Slot, K>,
+    inserted: u64,
+}
+
+// Time an entity ended, tied by insertion order
+type Slot = (Instant, u64);
+
+impl<K: Eq + Hash + Clone, S> Ended<K, S> {
+    fn new() -> Self {
+        Ended {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            inserted: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn insert(&mut self, key: K, state: S, at: Instant) {
+        self.inserted += 1;
+        let slot = (at, self.inserted);
+        if let Some((_, previous)) = self.entries.insert(key.clone(), (state, slot)) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(slot, key);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<S> {
+        let (state, slot) = self.entries.remove(key)?;
+        self.order.remove(&slot);
+        Some(state)
+    }
+
+    // Remove the longest ended entity if `due` says so of the time it ended
+    fn pop_oldest_if(&mut self, due: impl FnOnce(Instant) -> bool) -> Option<(K, S)> {
+        let oldest = self
+            .order
+            .first_entry()
+            .filter(|oldest| due(oldest.key().0))?;
+        let key = oldest.remove();
+        let (state, _) = self.entries.remove(&key)?;
+        Some((key, state))
+    }
+}
+
+/// Fires events for entities by key, loading their state and context from
+/// storage, see the module documentation
+pub struct EntityManager<K, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    machine: Arc<StateMachine<S, E, C>>,
+    repository: Arc<dyn StateRepository<K, S>>,
+    loader: Arc<dyn ContextLoader<K, C>>,
+    saver: Option<Arc<dyn ContextSaver<K, C>>>,
+    retention: Option<Duration>,
+    max_pending: Option<usize>,
+    on_evict: Option<EvictCallback<K, S>>,
+    ended: Mutex<Ended<K, S>>,
+}
+
+impl<K, S, E, C> EntityManager<K, S, E, C>
+where
+    K: Debug + Eq + Hash + Clone,
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn new(
+        machine: Arc<StateMachine<S, E, C>>,
+        repository: Arc<dyn StateRepository<K, S>>,
+        loader: Arc<dyn ContextLoader<K, C>>,
+    ) -> Self {
+        EntityManager {
+            machine,
+            repository,
+            loader,
+            saver: None,
+            retention: None,
+            max_pending: None,
+            on_evict: None,
+            ended: Mutex::new(Ended::new()),
+        }
+    }
+
+    /// Save the context with `saver` after every successful fire
+    pub fn with_saver(mut self, saver: Arc<dyn ContextSaver<K, C>>) -> Self {
+        self.saver = Some(saver);
+        self
+    }
+
+    /// Evict entities `retention` after a fire left them in a terminal
+    /// state, see the module documentation
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Track at most `max` entities in a terminal state, evicting the
+    /// longest ended ones before their retention has passed
+    pub fn with_max_pending(mut self, max: usize) -> Self {
+        self.max_pending = Some(max);
+        self
+    }
+
+    /// Call `callback` with the key and last state of every entity evicted
+    pub fn on_evict<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&K, &S) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Arc::new(callback));
+        self
+    }
+
+    /// Evict the entities whose retention has passed, or that exceed the
+    /// limit set with `with_max_pending`, returning how many
+    pub fn sweep(&self) -> usize {
+        let Some(retention) = self.retention else {
+            return 0;
+        };
+        let now = self.machine.clock.now();
+        let mut evicted = Vec::new();
+        let mut ended = self.ended.lock().unwrap();
+        while let Some(entity) =
+            ended.pop_oldest_if(|ended_at| now.saturating_duration_since(ended_at) >= retention)
+        {
+            evicted.push(entity);
+        }
+        let excess = self
+            .max_pending
+            .map_or(0, |max| ended.len().saturating_sub(max));
+        for _ in 0..excess {
+            evicted.extend(ended.pop_oldest_if(|_| true));
+        }
+        drop(ended);
+        for (key, state) in &evicted {
+            self.evicted(key, state);
+        }
+        evicted.len()
+    }
+
+    /// Evict the entity `key` without waiting for its retention to pass
+    ///
+    /// Returns `false` if the manager doesn't track it, i.e. it is not in a
+    /// terminal state or was evicted already.
+    pub fn evict_now(&self, key: &K) -> bool {
+        let removed = self.ended.lock().unwrap().remove(key);
+        match removed {
+            Some(state) => {
+                self.evicted(key, &state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Entities in a terminal state waiting for eviction
+    pub fn pending_evictions(&self) -> usize {
+        self.ended.lock().unwrap().len()
+    }
+
+    fn evicted(&self, key: &K, state: &S) {
+        if let Some(on_evict) = &self.on_evict {
+            on_evict(key, state);
+        }
+    }
+
+    pub fn machine(&self) -> &StateMachine<S, E, C> {
+        &self.machine
+    }
+
+    // Save the state `to` reached from `from`, then the context, putting
+    // `from` back if the context cannot be saved
+    fn save(
+        &self,
+        key: &K,
+        from: &S,
+        version: u64,
+        to: &S,
+        context: &C,
+    ) -> Result<(), TransitionError<S, E>> {
+        let saved = save_fired(self.repository.as_ref(), key, to, version)?;
+        let Some(saver) = &self.saver else {
+            return Ok(());
+        };
+        saver
+            .save(key, context)
+            .map_err(|error| TransitionError::ContextSaveFailed {
+                key: format!("{:?}", key),
+                error,
+                // Fails when the entity was saved again meanwhile, which then
+                // moved on from the new state
+                rollback: self.repository.save(key, from, saved).err(),
+            })
+    }
+
+    /// Fire `event` for the entity `key`, returning its new state
+    ///
+    /// Fails with `TransitionError::EntityNotFound` when the repository has
+    /// no state for the entity, and with `TransitionError::VersionConflict`
+    /// when it was saved by someone else during the fire. The context is not
+    /// saved in the latter case. Evicts the entities due, as `sweep` does.
+    ///
+    /// The fire is recorded once its state and context are saved; see the
+    /// module documentation for a save that fails.
+    pub fn fire(&self, key: &K, event: E) -> Result<S, TransitionError<S, E>> {
+        let (from, version) =
+            self.repository
+                .load_versioned(key)
+                .ok_or_else(|| TransitionError::EntityNotFound {
+                    key: format!("{:?}", key),
+                })?;
+        let mut context =
+            self.loader
+                .load(key)
+                .map_err(|error| TransitionError::ContextLoadFailed {
+                    key: format!("{:?}", key),
+                    error,
+                })?;
+
+        // Fired on a fork, whose history and metrics are published only
+        // once the outcome is saved
+        let machine = self.machine.as_ref();
+        let started = Instant::now();
+        let staging = machine.fork();
+        machine.notify_before(&from, &event, &context);
+        let result =
+            staging.fire_unobserved(from.clone(), event.clone(), &mut context, None, None, None);
+        let result = match result {
+            Ok((outcome, followups)) => {
+                match self.save(key, &from, version, &outcome.to, &context) {
+                    Ok(()) => {
+                        machine.publish(&staging, &machine.recording, false);
+                        Ok((outcome, followups))
+                    }
+                    Err(error) => {
+                        machine.record_aborted(&from, &event, &context, &error, started);
+                        Err(error)
+                    }
+                }
+            }
+            Err(error) => {
+                machine.publish(&staging, &machine.recording, false);
+                Err(error)
+            }
+        };
+        machine.notify_outcome(&from, &event, &context, &result);
+        let to = result?.0.to;
+
+        if self.retention.is_some() && self.machine.is_terminal(&to) {
+            let now = self.machine.clock.now();
+            self.ended
+                .lock()
+                .unwrap()
+                .insert(key.clone(), to.clone(), now);
+        }
+        self.sweep();
+        Ok(to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryStateRepository, StateMachineBuilderFactory, NEW_ENTITY};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Cart {
+        Open,
+        CheckedOut,
+    }
+
+    impl State for Cart {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum CartEvent {
+        AddItem,
+        CheckOut,
+    }
+
+    impl Event for CartEvent {}
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    struct CartContext {
+        items: u32,
+    }
+
+    impl Context for CartContext {}
+
+    fn cart_machine(guards: Arc<AtomicUsize>) -> Arc<StateMachine<Cart, CartEvent, CartContext>> {
+        let mut builder = StateMachineBuilderFactory::create::<Cart, CartEvent, CartContext>();
+        builder
+            .internal_transition()
+            .within(Cart::Open)
+            .on(CartEvent::AddItem)
+            .perform_mut(|_s, _e, c| c.items += 1);
+        builder
+            .external_transition()
+            .from(Cart::Open)
+            .to(Cart::CheckedOut)
+            .on(CartEvent::CheckOut)
+            .when(move |_s, _e, c| {
+                guards.fetch_add(1, Ordering::SeqCst);
+                c.items > 0
+            })
+            .add();
+        Arc::new(builder.build())
+    }
+
+    struct FailingSaver;
+
+    impl ContextSaver<u32, CartContext> for FailingSaver {
+        fn save(&self, _key: &u32, _context: &CartContext) -> Result<(), SaveError> {
+            Err(SaveError {
+                reason: "disk full".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_context_round_trip() {
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let store = Arc::new(InMemoryContextStore::new());
+        store.insert(1, CartContext { items: 0 });
+        let manager = EntityManager::new(
+            cart_machine(Arc::new(AtomicUsize::new(0))),
+            repo.clone(),
+            store.clone(),
+        )
+        .with_saver(store.clone());
+
+        assert_eq!(manager.fire(&1, CartEvent::AddItem).unwrap(), Cart::Open);
+        assert_eq!(manager.fire(&1, CartEvent::AddItem).unwrap(), Cart::Open);
+        assert_eq!(store.get(&1), Some(CartContext { items: 2 }));
+
+        // The guard sees the context saved by the fires before
+        assert_eq!(
+            manager.fire(&1, CartEvent::CheckOut).unwrap(),
+            Cart::CheckedOut
+        );
+        assert_eq!(repo.load(&1), Some(Cart::CheckedOut));
+    }
+
+    #[test]
+    fn test_load_failure_rejected_before_guards() {
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let guards = Arc::new(AtomicUsize::new(0));
+        let manager = EntityManager::new(
+            cart_machine(guards.clone()),
+            repo.clone(),
+            Arc::new(InMemoryContextStore::new()),
+        );
+
+        match manager.fire(&1, CartEvent::CheckOut) {
+            Err(TransitionError::ContextLoadFailed { key, error }) => {
+                assert_eq!(key, "1");
+                assert_eq!(error, LoadError::NotFound);
+            }
+            other => panic!("expected ContextLoadFailed, got {:?}", other),
+        }
+        assert_eq!(guards.load(Ordering::SeqCst), 0);
+        assert_eq!(repo.load(&1), Some(Cart::Open));
+    }
+
+    // Carts that end when checked out
+    #[cfg(feature = "test-util")]
+    fn ending_cart_machine(
+        clock: &Arc<crate::MockClock>,
+    ) -> Arc<StateMachine<Cart, CartEvent, CartContext>> {
+        let mut builder = StateMachineBuilderFactory::create::<Cart, CartEvent, CartContext>();
+        builder
+            .external_transition()
+            .from(Cart::Open)
+            .to(Cart::CheckedOut)
+            .on(CartEvent::CheckOut)
+            .add();
+        builder
+            .internal_transition()
+            .within(Cart::Open)
+            .on(CartEvent::AddItem)
+            .add();
+        builder
+            .terminal_states(vec![Cart::CheckedOut])
+            .with_clock(clock.clone());
+        Arc::new(builder.build())
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_retention_evicts_ended_entities() {
+        let clock = Arc::new(crate::MockClock::new());
+        let repo = Arc::new(InMemoryStateRepository::new());
+        let store = Arc::new(InMemoryContextStore::new());
+        for key in 1..=3 {
+            repo.save(&key, &Cart::Open, NEW_ENTITY).unwrap();
+            store.insert(key, CartContext { items: 0 });
+        }
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let archive = evicted.clone();
+        let manager = EntityManager::new(ending_cart_machine(&clock), repo.clone(), store)
+            .with_retention(Duration::from_secs(60))
+            .on_evict(move |key, state| archive.lock().unwrap().push((*key, state.clone())));
+
+        manager.fire(&1, CartEvent::CheckOut).unwrap();
+        manager.fire(&2, CartEvent::AddItem).unwrap();
+        assert_eq!(manager.pending_evictions(), 1);
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(manager.sweep(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(manager.sweep(), 1);
+        assert_eq!(*evicted.lock().unwrap(), [(1, Cart::CheckedOut)]);
+        // The repository still has the last state
+        assert_eq!(repo.load(&1), Some(Cart::CheckedOut));
+
+        // The open cart is left alone
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(manager.sweep(), 0);
+        assert!(!manager.evict_now(&2));
+        assert_eq!(manager.fire(&2, CartEvent::AddItem).unwrap(), Cart::Open);
+
+        manager.fire(&3, CartEvent::CheckOut).unwrap();
+        assert!(manager.evict_now(&3));
+        assert!(!manager.evict_now(&3));
+        assert_eq!(evicted.lock().unwrap().len(), 2);
+        assert_eq!(manager.pending_evictions(), 0);
+    }
+
+    #[test]
+    fn test_version_conflict_saves_no_context() {
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let store = Arc::new(InMemoryContextStore::new());
+        store.insert(1, CartContext { items: 0 });
+        let concurrent = repo.clone();
+        let mut builder = StateMachineBuilderFactory::create::<Cart, CartEvent, CartContext>();
+        builder
+            .internal_transition()
+            .within(Cart::Open)
+            .on(CartEvent::AddItem)
+            .perform_mut(move |_s, _e, c| {
+                c.items += 1;
+                // Another fire saves the entity first
+                let (state, version) = concurrent.load_versioned(&1).unwrap();
+                concurrent.save(&1, &state, version).unwrap();
+            });
+        let manager = EntityManager::new(Arc::new(builder.build()), repo, store.clone())
+            .with_saver(store.clone());
+
+        assert!(matches!(
+            manager.fire(&1, CartEvent::AddItem),
+            Err(TransitionError::VersionConflict { .. })
+        ));
+        assert_eq!(store.get(&1), Some(CartContext { items: 0 }));
+    }
+
+    #[test]
+    fn test_save_failure_keeps_state() {
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let store = Arc::new(InMemoryContextStore::new());
+        store.insert(1, CartContext { items: 1 });
+        let manager = EntityManager::new(
+            cart_machine(Arc::new(AtomicUsize::new(0))),
+            repo.clone(),
+            store.clone(),
+        )
+        .with_saver(Arc::new(FailingSaver));
+
+        assert!(matches!(
+            manager.fire(&1, CartEvent::CheckOut),
+            Err(TransitionError::ContextSaveFailed { rollback: None, .. })
+        ));
+        assert_eq!(repo.load(&1), Some(Cart::Open));
+
+        // Recorded as the failure it was, not as the transition
+        #[cfg(feature = "history")]
+        {
+            let history = manager.machine().get_history();
+            assert_eq!(history.len(), 1);
+            assert!(!history[0].success);
+            assert_eq!(history[0].to, Cart::Open);
+            assert_eq!(history[0].error_code, Some("context_save_failed"));
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = manager.machine().get_metrics();
+            assert_eq!(metrics.successful_transitions, 0);
+            assert_eq!(metrics.failed_transitions, 1);
+        }
+
+        // Rolled back to a version the next fire can save over
+        let retry = EntityManager::new(manager.machine.clone(), repo.clone(), store.clone());
+        assert_eq!(
+            retry.fire(&1, CartEvent::CheckOut).unwrap(),
+            Cart::CheckedOut
+        );
+        assert_eq!(repo.load(&1), Some(Cart::CheckedOut));
+    }
+
+    // Saves the entity as another fire would, then fails
+    struct RacingSaver(Arc<InMemoryStateRepository<u32, Cart>>);
+
+    impl ContextSaver<u32, CartContext> for RacingSaver {
+        fn save(&self, key: &u32, _context: &CartContext) -> Result<(), SaveError> {
+            let (state, version) = self.0.load_versioned(key).unwrap();
+            self.0.save(key, &state, version).unwrap();
+            Err(SaveError {
+                reason: "disk full".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_failed_rollback_reported() {
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let store = Arc::new(InMemoryContextStore::new());
+        store.insert(1, CartContext { items: 1 });
+        let manager = EntityManager::new(
+            cart_machine(Arc::new(AtomicUsize::new(0))),
+            repo.clone(),
+            store,
+        )
+        .with_saver(Arc::new(RacingSaver(repo.clone())));
+
+        let error = manager.fire(&1, CartEvent::CheckOut).unwrap_err();
+        let TransitionError::ContextSaveFailed {
+            rollback: Some(conflict),
+            ..
+        } = &error
+        else {
+            panic!("expected a failed rollback, got {:?}", error);
+        };
+        assert_eq!(conflict.actual, conflict.expected + 1);
+        assert!(error.to_string().contains("not rolled back"));
+        // The other save is kept
+        assert_eq!(repo.load(&1), Some(Cart::CheckedOut));
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_fires_evict_expired_entities() {
+        let clock = Arc::new(crate::MockClock::new());
+        let repo = Arc::new(InMemoryStateRepository::new());
+        let store = Arc::new(InMemoryContextStore::new());
+        for key in 1..=5 {
+            repo.save(&key, &Cart::Open, NEW_ENTITY).unwrap();
+            store.insert(key, CartContext { items: 0 });
+        }
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let archive = evicted.clone();
+        let manager = EntityManager::new(ending_cart_machine(&clock), repo.clone(), store)
+            .with_retention(Duration::from_secs(60))
+            .with_max_pending(2)
+            .on_evict(move |key, _state| archive.lock().unwrap().push(*key));
+
+        manager.fire(&1, CartEvent::CheckOut).unwrap();
+        clock.advance(Duration::from_secs(30));
+        manager.fire(&2, CartEvent::CheckOut).unwrap();
+        clock.advance(Duration::from_secs(30));
+
+        // Firing for any entity evicts the expired ones, without a sweep
+        manager.fire(&5, CartEvent::AddItem).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), [1]);
+        assert_eq!(manager.pending_evictions(), 1);
+
+        // Past the limit the longest ended goes first, however recent
+        manager.fire(&3, CartEvent::CheckOut).unwrap();
+        manager.fire(&4, CartEvent::CheckOut).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), [1, 2]);
+        assert_eq!(manager.pending_evictions(), 2);
+        assert!(!manager.evict_now(&2));
+        assert!(manager.evict_now(&3));
+        assert_eq!(repo.load(&2), Some(Cart::CheckedOut));
+    }
+
+    #[test]
+    fn test_ended_in_time_order() {
+        let start = Instant::now();
+        let mut ended = Ended::new();
+        ended.insert(1, Cart::CheckedOut, start + Duration::from_secs(2));
+        ended.insert(2, Cart::CheckedOut, start);
+        ended.insert(3, Cart::CheckedOut, start);
+        ended.insert(4, Cart::CheckedOut, start);
+        // Ending again replaces the earlier time
+        ended.insert(2, Cart::CheckedOut, start + Duration::from_secs(1));
+        assert_eq!(ended.remove(&4), Some(Cart::CheckedOut));
+        assert_eq!(ended.len(), 3);
+
+        assert!(ended.pop_oldest_if(|at| at > start).is_none());
+        let mut order = Vec::new();
+        while let Some((key, _)) = ended.pop_oldest_if(|_| true) {
+            order.push(key);
+        }
+        assert_eq!(order, [3, 2, 1]);
+        assert!(ended.order.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    fn context_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rs-statemachine-contexts-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_file_store_round_trip() {
+        let dir = context_dir("plain");
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let store = Arc::new(FileContextStore::open(&dir).unwrap());
+        assert_eq!(
+            ContextLoader::<u32, CartContext>::load(store.as_ref(), &1),
+            Err(LoadError::NotFound)
+        );
+        store.save(&1, &CartContext { items: 0 }).unwrap();
+        let manager = EntityManager::new(
+            cart_machine(Arc::new(AtomicUsize::new(0))),
+            repo,
+            store.clone(),
+        )
+        .with_saver(store.clone());
+
+        manager.fire(&1, CartEvent::AddItem).unwrap();
+        assert_eq!(store.load(&1), Ok(CartContext { items: 1 }));
+        // Without a codec the file is the JSON form
+        assert_eq!(
+            std::fs::read_to_string(store.path(&1)).unwrap(),
+            r#"{"items":1}"#
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encrypted_contexts_not_plaintext_on_disk() {
+        let dir = context_dir("encrypted");
+        let codec = Arc::new(crate::AesGcmCodec::new(&[7; 32]));
+        let repo = Arc::new(InMemoryStateRepository::new());
+        repo.save(&1, &Cart::Open, NEW_ENTITY).unwrap();
+        let store = Arc::new(FileContextStore::open(&dir).unwrap().with_codec(codec));
+        store.save(&1, &CartContext { items: 0 }).unwrap();
+        let manager = EntityManager::new(
+            cart_machine(Arc::new(AtomicUsize::new(0))),
+            repo,
+            store.clone(),
+        )
+        .with_saver(store.clone());
+
+        manager.fire(&1, CartEvent::AddItem).unwrap();
+        let bytes = std::fs::read(store.path(&1)).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("items"));
+        assert_eq!(store.load(&1), Ok(CartContext { items: 1 }));
+
+        // Another key cannot read it
+        let wrong: FileContextStore<u32, CartContext> = FileContextStore::open(&dir)
+            .unwrap()
+            .with_codec(Arc::new(crate::AesGcmCodec::new(&[8; 32])));
+        assert!(matches!(wrong.load(&1), Err(LoadError::Storage { .. })));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,129 @@
+//! Rejection of events arriving after a deadline carried by the context
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{Context, ContextMapper, Event, State, StateMachine, StateMachineBuilder};
+
+/// Context carrying a business deadline, e.g. a payment due date
+pub trait Deadline {
+    /// Wall-clock time after which deadline-bound events are rejected
+    fn deadline(&self) -> Option<SystemTime>;
+}
+
+type DeadlineFn<C> = Arc<dyn Fn(&C) -> Option<SystemTime> + Send + Sync>;
+
+// Events bound to the context deadline
+pub(crate) struct DeadlineCheck<E, C> {
+    events: HashSet<E>,
+    deadline: DeadlineFn<C>,
+}
+
+impl<E: Clone, C> Clone for DeadlineCheck<E, C> {
+    fn clone(&self) -> Self {
+        DeadlineCheck {
+            events: self.events.clone(),
+            deadline: self.deadline.clone(),
+        }
+    }
+}
+
+impl<E, C: 'static> DeadlineCheck<E, C> {
+    // Same check for a machine driven with `C2` contexts
+    pub(crate) fn map_context<C2>(self, map: &ContextMapper<C2, C>) -> DeadlineCheck<E, C2>
+    where
+        C2: 'static,
+    {
+        let (deadline, map) = (self.deadline, map.clone());
+        DeadlineCheck {
+            events: self.events,
+            deadline: Arc::new(move |context: &C2| deadline(&map(context))),
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context + Deadline,
+{
+    /// Reject `events` with `TransitionError::DeadlineExpired` once the
+    /// context's deadline has passed
+    ///
+    /// The check uses the machine's clock and happens before any guard is
+    /// evaluated. Other events ignore the deadline.
+    pub fn enforce_context_deadline(&mut self, events: Vec<E>) -> &mut Self {
+        self.deadline_check = Some(DeadlineCheck {
+            events: events.into_iter().collect(),
+            deadline: Arc::new(|context: &C| context.deadline()),
+        });
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Deadline of `context` if it has passed and `event` is bound to it
+    pub(crate) fn expired_deadline(&self, event: &E, context: &C) -> Option<SystemTime> {
+        let check = self.deadline_check.as_ref()?;
+        if !check.events.contains(event) {
+            return None;
+        }
+        let deadline = (check.deadline)(context)?;
+        (self.clock.wall_time() > deadline).then_some(deadline)
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_builder, Order, OrderEvent};
+    use crate::{Clock, MockClock, TransitionError};
+    use std::time::Duration;
+
+    #[derive(Debug, Clone)]
+    struct OrderContext {
+        pay_by: SystemTime,
+    }
+
+    impl Context for OrderContext {}
+
+    impl Deadline for OrderContext {
+        fn deadline(&self) -> Option<SystemTime> {
+            Some(self.pay_by)
+        }
+    }
+
+    #[test]
+    fn test_context_deadline() {
+        let clock = Arc::new(MockClock::new());
+        let mut builder = order_builder();
+        builder
+            .with_clock(clock.clone())
+            .enforce_context_deadline(vec![OrderEvent::Pay]);
+        let machine = builder.build();
+
+        let context = OrderContext {
+            pay_by: clock.wall_time() + Duration::from_secs(3600),
+        };
+        let result = machine.fire_event(Order::Placed, OrderEvent::Pay, context.clone());
+        assert_eq!(result.unwrap(), Order::Paid);
+
+        clock.advance(Duration::from_secs(7200));
+        match machine.fire_event(Order::Placed, OrderEvent::Pay, context.clone()) {
+            Err(TransitionError::DeadlineExpired { deadline }) => {
+                assert_eq!(deadline, context.pay_by);
+            }
+            other => panic!("expected DeadlineExpired, got {:?}", other),
+        }
+
+        let result = machine.fire_event(Order::Placed, OrderEvent::Cancel, context);
+        assert_eq!(result.unwrap(), Order::Cancelled);
+    }
+}
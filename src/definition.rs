@@ -0,0 +1,783 @@
+//! Machines loaded from data, with guards and actions bound by name
+//!
+//! A `MachineDefinition` lists the transitions of a machine with the names
+//! of their guards and actions, e.g. as deserialized from a config file
+//! (with the `serde` feature). `ActionBindings` registers the callbacks those
+//! names stand for, and `StateMachineBuilder::from_definition` checks the two
+//! against each other before registering anything: every name the
+//! definition refers to must be bound, and bound names no transition refers
+//! to are reported as warnings.
+//!
+//! Transitions registered with `when_named` and `perform_named` keep the
+//! names of their callbacks, so `StateMachine::to_definition` exports them
+//! and `from_definition` rebinds them. Closures registered inline have no
+//! name: the export marks them anonymous, and importing a definition with
+//! anonymous callbacks fails unless `ActionBindings::allow_anonymous` lets
+//! them be dropped with a warning. `require_named_callbacks` rejects them
+//! when they are registered instead.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::callbacks::{self, Guard};
+use crate::{
+    Action, BuildError, CheckedCondition, Conditions, Context, Event, ExternalTransitionBuilder,
+    ExternalTransitionsBuilder, InternalTransitionBuilder, State, StateMachine,
+    StateMachineBuilder, Transition, TransitionType,
+};
+
+/// One transition of a `MachineDefinition`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransitionDefinition<S, E> {
+    pub from: S,
+    pub event: E,
+    /// Same as `from` for internal transitions
+    pub to: S,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub internal: bool,
+    /// Name of the guard in the `ActionBindings`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub guard: Option<String>,
+    /// Name of the action in the `ActionBindings`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub action: Option<String>,
+    /// Callbacks the exported transition had as closures without a name,
+    /// which cannot be bound again
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub anonymous: Vec<BindingKind>,
+}
+
+/// Transitions of a machine with their guards and actions by name
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineDefinition<S, E> {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub initial_state: Option<S>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub terminal_states: Vec<S>,
+    pub transitions: Vec<TransitionDefinition<S, E>>,
+}
+
+impl<S, E> MachineDefinition<S, E> {
+    /// Names of the guards and actions the transitions refer to
+    pub fn required_bindings(&self) -> BindingManifest {
+        let mut manifest = BindingManifest::default();
+        for transition in &self.transitions {
+            manifest.guards.extend(transition.guard.iter().cloned());
+            manifest.actions.extend(transition.action.iter().cloned());
+        }
+        manifest
+    }
+}
+
+/// Kind of a named callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BindingKind {
+    Guard,
+    Action,
+}
+
+impl fmt::Display for BindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingKind::Guard => f.write_str("guard"),
+            BindingKind::Action => f.write_str("action"),
+        }
+    }
+}
+
+/// Names of guards and actions, bound or required
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BindingManifest {
+    pub guards: BTreeSet<String>,
+    pub actions: BTreeSet<String>,
+}
+
+impl BindingManifest {
+    pub fn contains(&self, kind: BindingKind, name: &str) -> bool {
+        match kind {
+            BindingKind::Guard => self.guards.contains(name),
+            BindingKind::Action => self.actions.contains(name),
+        }
+    }
+}
+
+/// Mismatch between a `MachineDefinition` and its `ActionBindings`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingError {
+    /// Names the definition refers to that are not bound, sorted
+    Missing {
+        guards: Vec<String>,
+        actions: Vec<String>,
+    },
+    /// A bound callback no transition refers to; only a warning
+    Unused { kind: BindingKind, name: String },
+    /// The transition from `from` on `event` had an anonymous callback when
+    /// exported; a warning with `ActionBindings::allow_anonymous`
+    Anonymous {
+        kind: BindingKind,
+        from: String,
+        event: String,
+    },
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingError::Missing { guards, actions } => {
+                write!(f, "unbound guards {:?} and actions {:?}", guards, actions)
+            }
+            BindingError::Unused { kind, name } => {
+                write!(f, "{} {} is bound but never used", kind, name)
+            }
+            BindingError::Anonymous { kind, from, event } => write!(
+                f,
+                "transition from {} on {} had an anonymous {} that cannot be bound",
+                from, event, kind
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BindingError {}
+
+/// Guards and actions by name, for `StateMachineBuilder::from_definition`
+pub struct ActionBindings<S, E, C> {
+    guards: HashMap<String, CheckedCondition<S, E, C>>,
+    actions: HashMap<String, Action<S, E, C>>,
+    allow_anonymous: bool,
+}
+
+impl<S, E, C> ActionBindings<S, E, C> {
+    pub fn new() -> Self {
+        ActionBindings {
+            guards: HashMap::new(),
+            actions: HashMap::new(),
+            allow_anonymous: false,
+        }
+    }
+
+    /// Load definitions with anonymous callbacks without them, reporting
+    /// each as a `BindingError::Anonymous` warning instead of failing
+    pub fn allow_anonymous(mut self, allow: bool) -> Self {
+        self.allow_anonymous = allow;
+        self
+    }
+
+    /// Bind `condition` to the guard name `name`
+    pub fn guard<F>(mut self, name: impl Into<String>, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
+    {
+        self.guards.insert(name.into(), Conditions::of(condition));
+        self
+    }
+
+    /// Bind `action` to the action name `name`
+    pub fn action<F>(mut self, name: impl Into<String>, action: F) -> Self
+    where
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
+    {
+        self.actions.insert(name.into(), Arc::new(action));
+        self
+    }
+
+    /// Names of the bound guards and actions
+    pub fn manifest(&self) -> BindingManifest {
+        BindingManifest {
+            guards: self.guards.keys().cloned().collect(),
+            actions: self.actions.keys().cloned().collect(),
+        }
+    }
+}
+
+impl<S, E, C> Default for ActionBindings<S, E, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, E, C> Clone for ActionBindings<S, E, C> {
+    fn clone(&self) -> Self {
+        ActionBindings {
+            guards: self.guards.clone(),
+            actions: self.actions.clone(),
+            allow_anonymous: self.allow_anonymous,
+        }
+    }
+}
+
+// Name and condition a transition builder's guard was set to by `when_named`
+pub(crate) type NamedGuard<S, E, C> = (String, CheckedCondition<S, E, C>);
+
+// Binding names of the guard and action of a registered transition, `None`
+// for a closure or no callback
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallbackNames {
+    guard: Option<String>,
+    action: Option<String>,
+}
+
+impl CallbackNames {
+    // Names a transition builder is finalized with; the guard keeps its name
+    // only while it is the very condition `when_named` set
+    pub(crate) fn of<S, E, C>(
+        guard: &Guard<S, E, C>,
+        named_guard: &Option<NamedGuard<S, E, C>>,
+        named_action: &Option<String>,
+    ) -> Self {
+        let guard = match (guard, named_guard) {
+            (Guard::Plain(plain), Some((name, bound))) if Arc::ptr_eq(plain, bound) => {
+                Some(name.clone())
+            }
+            _ => None,
+        };
+        CallbackNames {
+            guard,
+            action: named_action.clone(),
+        }
+    }
+}
+
+impl<S, E, C> Transition<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // Name identifying the transition among its candidates: the binding
+    // name of its guard, else its template tag
+    pub(crate) fn label(&self) -> Option<String> {
+        self.names.guard.clone().or_else(|| self.tag.clone())
+    }
+
+    // Callbacks of the transition registered as closures without a name
+    pub(crate) fn anonymous_callbacks(&self) -> Vec<BindingKind> {
+        let mut kinds = Vec::new();
+        if self.guard.is_guarded() && self.names.guard.is_none() {
+            kinds.push(BindingKind::Guard);
+        }
+        if self.action.is_set() && self.names.action.is_none() {
+            kinds.push(BindingKind::Action);
+        }
+        kinds
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Builder with the transitions of `definition` and the callbacks of
+    /// `bindings` they name
+    ///
+    /// Fails with `BindingError::Missing` listing every unbound name before
+    /// registering anything, and with `BindingError::Anonymous` for a
+    /// callback the definition was exported with but couldn't name.
+    /// Bindings no transition refers to are returned as
+    /// `BindingError::Unused` warnings next to the builder.
+    pub fn from_definition(
+        definition: &MachineDefinition<S, E>,
+        bindings: &ActionBindings<S, E, C>,
+    ) -> Result<(Self, Vec<BindingError>), BindingError> {
+        let required = definition.required_bindings();
+        let bound = bindings.manifest();
+        let missing = |required: &BTreeSet<String>, bound: &BTreeSet<String>| {
+            required.difference(bound).cloned().collect::<Vec<_>>()
+        };
+        let (guards, actions) = (
+            missing(&required.guards, &bound.guards),
+            missing(&required.actions, &bound.actions),
+        );
+        if !guards.is_empty() || !actions.is_empty() {
+            return Err(BindingError::Missing { guards, actions });
+        }
+        let mut warnings = Vec::new();
+        for transition in &definition.transitions {
+            for &kind in &transition.anonymous {
+                let anonymous = BindingError::Anonymous {
+                    kind,
+                    from: format!("{:?}", transition.from),
+                    event: format!("{:?}", transition.event),
+                };
+                if !bindings.allow_anonymous {
+                    return Err(anonymous);
+                }
+                warnings.push(anonymous);
+            }
+        }
+        let unused = |kind, bound: &BTreeSet<String>, required: &BTreeSet<String>| {
+            bound
+                .difference(required)
+                .map(|name| BindingError::Unused {
+                    kind,
+                    name: name.clone(),
+                })
+                .collect::<Vec<_>>()
+        };
+        warnings.extend(unused(BindingKind::Guard, &bound.guards, &required.guards));
+        warnings.extend(unused(
+            BindingKind::Action,
+            &bound.actions,
+            &required.actions,
+        ));
+
+        let mut builder = StateMachineBuilder::new();
+        builder.with_bindings(bindings.clone());
+        if let Some(initial) = &definition.initial_state {
+            builder.initial_state(initial.clone());
+        }
+        builder.terminal_states(definition.terminal_states.clone());
+        for transition in &definition.transitions {
+            if transition.internal {
+                let mut registered = builder
+                    .internal_transition()
+                    .within(transition.from.clone())
+                    .on(transition.event.clone());
+                if let Some(guard) = &transition.guard {
+                    registered = registered.when_named(guard.as_str());
+                }
+                match &transition.action {
+                    Some(action) => registered.perform_named(action.as_str()),
+                    None => registered.add(),
+                };
+            } else {
+                let mut registered = builder
+                    .external_transition()
+                    .from(transition.from.clone())
+                    .to(transition.to.clone())
+                    .on(transition.event.clone());
+                if let Some(guard) = &transition.guard {
+                    registered = registered.when_named(guard.as_str());
+                }
+                match &transition.action {
+                    Some(action) => registered.perform_named(action.as_str()),
+                    None => registered.add(),
+                };
+            }
+        }
+        Ok((builder, warnings))
+    }
+
+    /// Callbacks `when_named` and `perform_named` refer to, replacing any
+    /// bound before
+    pub fn with_bindings(&mut self, bindings: ActionBindings<S, E, C>) -> &mut Self {
+        self.bindings = bindings;
+        self
+    }
+
+    /// Reject transitions registered with a guard or action closure instead
+    /// of `when_named` / `perform_named`, keeping every transition exported
+    /// by `StateMachine::to_definition` loadable again
+    ///
+    /// Rejected transitions are reported by `try_build` as
+    /// `BuildError::AnonymousCallback`; `build` panics on them. `from_any`
+    /// and completion transitions are not part of a definition and are not
+    /// checked.
+    pub fn require_named_callbacks(&mut self, require: bool) -> &mut Self {
+        self.require_named_callbacks = require;
+        self
+    }
+
+    // Guard bound to `name`, recording an error when there is none
+    fn bound_guard(&mut self, name: String) -> Option<NamedGuard<S, E, C>> {
+        match self.bindings.guards.get(&name) {
+            Some(condition) => Some((name, condition.clone())),
+            None => {
+                self.unbound_callback(BindingKind::Guard, name);
+                None
+            }
+        }
+    }
+
+    // Action bound to `name`, recording an error when there is none
+    fn bound_action(&mut self, name: &str) -> Option<Action<S, E, C>> {
+        let action = self.bindings.actions.get(name).cloned();
+        if action.is_none() {
+            self.unbound_callback(BindingKind::Action, name.to_string());
+        }
+        action
+    }
+
+    fn unbound_callback(&mut self, kind: BindingKind, name: String) {
+        self.registration_errors
+            .push(BuildError::UnboundCallback { kind, name });
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Like `when`, with the condition bound to `name` with `with_bindings`
+    /// and the name kept by `StateMachine::to_definition`
+    pub fn when_named(mut self, name: impl Into<String>) -> Self {
+        if let Some((name, condition)) = self.builder.bound_guard(name.into()) {
+            self.guard = self.guard.with_plain(condition.clone());
+            self.named_guard = Some((name, condition));
+        }
+        self
+    }
+
+    /// Like `perform`, with the action bound to `name` with `with_bindings`
+    /// and the name kept by `StateMachine::to_definition`
+    pub fn perform_named(
+        mut self,
+        name: impl Into<String>,
+    ) -> &'a mut StateMachineBuilder<S, E, C> {
+        let name = name.into();
+        if let Some(action) = self.builder.bound_action(&name) {
+            self.action = callbacks::Action::Plain(action);
+            self.named_action = Some(name);
+        }
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> InternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Like `when`, with the condition bound to `name` with `with_bindings`
+    /// and the name kept by `StateMachine::to_definition`
+    pub fn when_named(mut self, name: impl Into<String>) -> Self {
+        if let Some((name, condition)) = self.builder.bound_guard(name.into()) {
+            self.guard = self.guard.with_plain(condition.clone());
+            self.named_guard = Some((name, condition));
+        }
+        self
+    }
+
+    /// Like `perform`, with the action bound to `name` with `with_bindings`
+    /// and the name kept by `StateMachine::to_definition`
+    pub fn perform_named(
+        mut self,
+        name: impl Into<String>,
+    ) -> &'a mut StateMachineBuilder<S, E, C> {
+        let name = name.into();
+        if let Some(action) = self.builder.bound_action(&name) {
+            self.action = callbacks::Action::Plain(action);
+            self.named_action = Some(name);
+        }
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionsBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Like `when`, with the condition bound to `name` with `with_bindings`
+    /// and the name kept by `StateMachine::to_definition`
+    pub fn when_named(mut self, name: impl Into<String>) -> Self {
+        if let Some((name, condition)) = self.builder.bound_guard(name.into()) {
+            self.guard = self.guard.with_plain(condition.clone());
+            self.named_guard = Some((name, condition));
+        }
+        self
+    }
+
+    /// Like `perform`, with the action bound to `name` with `with_bindings`
+    /// and the name kept by `StateMachine::to_definition`
+    pub fn perform_named(
+        mut self,
+        name: impl Into<String>,
+    ) -> &'a mut StateMachineBuilder<S, E, C> {
+        let name = name.into();
+        if let Some(action) = self.builder.bound_action(&name) {
+            self.action = callbacks::Action::Plain(action);
+            self.named_action = Some(name);
+        }
+        self.add()
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Definition of the machine with the binding names of its callbacks,
+    /// for `StateMachineBuilder::from_definition`
+    ///
+    /// Guards and actions registered as closures rather than with
+    /// `when_named` / `perform_named` are listed as `anonymous`. `from_any`
+    /// and completion transitions are not part of a definition.
+    pub fn to_definition(&self) -> MachineDefinition<S, E> {
+        let mut pairs: Vec<_> = self.transitions.iter().collect();
+        pairs.sort_by_cached_key(|((from, event), _)| format!("{:?}\u{0}{:?}", from, event));
+        let mut terminal_states: Vec<S> = self.terminal_states.iter().cloned().collect();
+        terminal_states.sort_by_cached_key(|state| format!("{:?}", state));
+        MachineDefinition {
+            initial_state: self.initial_state.clone(),
+            terminal_states,
+            transitions: pairs
+                .into_iter()
+                .flat_map(|(_, candidates)| candidates.iter())
+                .map(|t| TransitionDefinition {
+                    from: t.from.clone(),
+                    event: t.event.clone(),
+                    to: t.to.clone(),
+                    internal: t.transition_type == TransitionType::Internal,
+                    guard: t.names.guard.clone(),
+                    action: t.names.action.clone(),
+                    anonymous: t.anonymous_callbacks(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Door {
+        Closed,
+        Open,
+        Locked,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Open,
+        Close,
+        Lock,
+    }
+
+    impl Event for DoorEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Key {
+        fits: bool,
+    }
+
+    impl Context for Key {}
+
+    fn transition(
+        from: Door,
+        event: DoorEvent,
+        to: Door,
+        guard: Option<&str>,
+        action: Option<&str>,
+    ) -> TransitionDefinition<Door, DoorEvent> {
+        TransitionDefinition {
+            from,
+            event,
+            to,
+            internal: false,
+            guard: guard.map(str::to_string),
+            action: action.map(str::to_string),
+            anonymous: Vec::new(),
+        }
+    }
+
+    fn door() -> MachineDefinition<Door, DoorEvent> {
+        MachineDefinition {
+            initial_state: Some(Door::Closed),
+            terminal_states: Vec::new(),
+            transitions: vec![
+                transition(
+                    Door::Closed,
+                    DoorEvent::Open,
+                    Door::Open,
+                    None,
+                    Some("creak"),
+                ),
+                transition(
+                    Door::Open,
+                    DoorEvent::Close,
+                    Door::Closed,
+                    None,
+                    Some("creak"),
+                ),
+                transition(
+                    Door::Closed,
+                    DoorEvent::Lock,
+                    Door::Locked,
+                    Some("key_fits"),
+                    Some("click"),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_missing_bindings_are_listed() {
+        let definition = door();
+        let required = definition.required_bindings();
+        assert!(required.contains(BindingKind::Guard, "key_fits"));
+        assert!(required.contains(BindingKind::Action, "creak"));
+
+        let bindings = ActionBindings::new().action("creak", |_: &Door, _: &DoorEvent, _: &Key| {});
+        let Err(error) = StateMachineBuilder::from_definition(&definition, &bindings) else {
+            panic!("definition with unbound names loaded");
+        };
+        assert_eq!(
+            error,
+            BindingError::Missing {
+                guards: vec!["key_fits".to_string()],
+                actions: vec!["click".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_unused_bindings_are_warnings() {
+        let bindings = ActionBindings::new()
+            .guard("key_fits", |_: &Door, _: &DoorEvent, key: &Key| key.fits)
+            .guard("always", |_, _, _| true)
+            .action("creak", |_, _, _| {})
+            .action("click", |_, _, _| {})
+            .action("slam", |_, _, _| {});
+        let (_, warnings) = StateMachineBuilder::from_definition(&door(), &bindings).unwrap();
+        assert_eq!(
+            warnings,
+            [
+                BindingError::Unused {
+                    kind: BindingKind::Guard,
+                    name: "always".to_string(),
+                },
+                BindingError::Unused {
+                    kind: BindingKind::Action,
+                    name: "slam".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_definition_bound_by_name() {
+        let clicks = Arc::new(AtomicUsize::new(0));
+        let counter = clicks.clone();
+        let bindings = ActionBindings::new()
+            .guard("key_fits", |_: &Door, _: &DoorEvent, key: &Key| key.fits)
+            .action("creak", |_, _, _| {})
+            .action("click", move |_, _, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        let (builder, warnings) = StateMachineBuilder::from_definition(&door(), &bindings).unwrap();
+        assert!(warnings.is_empty());
+        let machine = builder.build();
+
+        let wrong_key = Key { fits: false };
+        assert!(machine
+            .fire_event(Door::Closed, DoorEvent::Lock, wrong_key)
+            .is_err());
+        let key = Key { fits: true };
+        assert_eq!(
+            machine
+                .fire_event(Door::Closed, DoorEvent::Lock, key)
+                .unwrap(),
+            Door::Locked
+        );
+        assert_eq!(clicks.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            machine.to_definition().required_bindings(),
+            door().required_bindings()
+        );
+    }
+
+    #[test]
+    fn test_export_marks_anonymous_callbacks() {
+        let bindings = ActionBindings::new()
+            .guard("key_fits", |_: &Door, _: &DoorEvent, key: &Key| key.fits)
+            .action("creak", |_, _, _| {});
+        let mut builder = StateMachineBuilder::new();
+        builder.with_bindings(bindings.clone());
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Open)
+            .perform_named("creak");
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Locked)
+            .on(DoorEvent::Lock)
+            .when_named("key_fits")
+            .perform(|_, _, _| {});
+        let definition = builder.build().to_definition();
+        let lock = &definition.transitions[0];
+        assert_eq!(lock.guard.as_deref(), Some("key_fits"));
+        assert_eq!(lock.action, None);
+        assert_eq!(lock.anonymous, [BindingKind::Action]);
+
+        let anonymous = BindingError::Anonymous {
+            kind: BindingKind::Action,
+            from: "Closed".to_string(),
+            event: "Lock".to_string(),
+        };
+        let Err(error) = StateMachineBuilder::from_definition(&definition, &bindings) else {
+            panic!("anonymous action dropped silently");
+        };
+        assert_eq!(error, anonymous);
+
+        let (builder, warnings) =
+            StateMachineBuilder::from_definition(&definition, &bindings.allow_anonymous(true))
+                .unwrap();
+        assert_eq!(warnings, [anonymous]);
+        let machine = builder.build();
+        let wrong_key = Key { fits: false };
+        assert!(machine
+            .fire_event(Door::Closed, DoorEvent::Lock, wrong_key)
+            .is_err());
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_inline_closure() {
+        let mut builder = StateMachineBuilder::<Door, DoorEvent, Key>::new();
+        builder
+            .with_bindings(ActionBindings::new().action("creak", |_, _, _| {}))
+            .require_named_callbacks(true);
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Open)
+            .perform_named("creak");
+        builder
+            .external_transition()
+            .from(Door::Open)
+            .to(Door::Closed)
+            .on(DoorEvent::Close)
+            .when(|_, _, key| key.fits)
+            .perform_named("creak");
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Locked)
+            .on(DoorEvent::Lock)
+            .perform_named("click");
+        let errors = builder.try_build().err().unwrap();
+        assert!(errors.contains(&BuildError::AnonymousCallback {
+            kind: BindingKind::Guard,
+            from: "Open".to_string(),
+            event: "Close".to_string(),
+        }));
+        assert!(errors.contains(&BuildError::UnboundCallback {
+            kind: BindingKind::Action,
+            name: "click".to_string(),
+        }));
+    }
+}
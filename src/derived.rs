@@ -0,0 +1,415 @@
+//! Values derived from the context once per fire and shared by callbacks
+//!
+//! A derivation registered with `StateMachineBuilder::with_derived` turns the
+//! context into a value of some type `D`. Guards and actions registered with
+//! `when_derived` / `perform_derived` receive `&D` in addition to the usual
+//! arguments. The derivation runs lazily the first time a callback asks for
+//! `D` during a fire and the result is reused by every later callback of that
+//! fire; fires that never reach such a callback don't run it at all.
+//!
+//! Each transition records the types its derived callbacks need. `try_build`
+//! fails, and `build` panics, when one of them has no derivation, so firing
+//! never finds a derivation missing.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::info::{InfoAction, InfoCondition};
+use crate::{
+    BuildError, Context, Event, ExternalTransitionBuilder, ExternalTransitionsBuilder,
+    InternalTransitionBuilder, Services, State, StateMachineBuilder,
+};
+
+pub(crate) type Derivation<C> = Arc<dyn Fn(&C) -> Box<dyn Any + Send + Sync> + Send + Sync>;
+pub(crate) type DerivationMap<C> = HashMap<TypeId, Derivation<C>>;
+
+// Type of a derived value with its name for build errors
+type DerivedInput = (TypeId, &'static str);
+
+/// Derived values the guard and action of a transition builder need
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DerivedInputs {
+    pub(crate) guard: Option<DerivedInput>,
+    pub(crate) action: Option<DerivedInput>,
+}
+
+fn derived_input<D: 'static>() -> Option<DerivedInput> {
+    Some((TypeId::of::<D>(), std::any::type_name::<D>()))
+}
+
+/// Derived values computed so far during one fire
+pub(crate) struct DerivedValues<'a, C> {
+    derivations: &'a DerivationMap<C>,
+    services: &'a Services,
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    // Data stashed by `when_providing` guards, by scratchpad key
+    scratch: HashMap<usize, Box<dyn Any + Send + Sync>>,
+}
+
+impl<'a, C> DerivedValues<'a, C> {
+    pub(crate) fn new(derivations: &'a DerivationMap<C>, services: &'a Services) -> Self {
+        DerivedValues {
+            derivations,
+            services,
+            values: HashMap::new(),
+            scratch: HashMap::new(),
+        }
+    }
+
+    /// Services provided to the machine
+    pub(crate) fn services(&self) -> &'a Services {
+        self.services
+    }
+
+    pub(crate) fn stash<D: Send + Sync + 'static>(&mut self, key: usize, data: D) {
+        self.scratch.insert(key, Box::new(data));
+    }
+
+    /// Data stashed under `key` during this fire, `None` if the guard
+    /// provided nothing
+    pub(crate) fn stashed<D: 'static>(&self, key: usize) -> Option<&D> {
+        self.scratch
+            .get(&key)
+            .and_then(|data| data.downcast_ref::<D>())
+    }
+
+    /// Run `f` with the values of another set of derivations, sharing the
+    /// data stashed during this fire
+    pub(crate) fn with_derivations<C2, R>(
+        &mut self,
+        derivations: &DerivationMap<C2>,
+        f: impl FnOnce(&mut DerivedValues<'_, C2>) -> R,
+    ) -> R {
+        let mut values = DerivedValues::new(derivations, self.services);
+        std::mem::swap(&mut values.scratch, &mut self.scratch);
+        let result = f(&mut values);
+        std::mem::swap(&mut values.scratch, &mut self.scratch);
+        result
+    }
+
+    /// Value of type `D` for `context`, deriving it on first use
+    ///
+    /// `build` checked that every type a callback asks for has a derivation.
+    fn get<D: 'static>(&mut self, context: &C) -> &D {
+        let derivations = self.derivations;
+        self.values
+            .entry(TypeId::of::<D>())
+            .or_insert_with(|| derivations[&TypeId::of::<D>()](context))
+            .downcast_ref::<D>()
+            .expect("derived value has the registered type")
+    }
+}
+
+fn derived_condition<S, E, C, D, F>(condition: F) -> InfoCondition<S, E, C>
+where
+    D: 'static,
+    F: Fn(&S, &E, &C, &D) -> bool + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, values| condition(info.from, info.event, c, values.get::<D>(c)))
+}
+
+fn derived_action<S, E, C, D, F>(action: F) -> InfoAction<S, E, C>
+where
+    D: 'static,
+    F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, values| {
+        action(info.from, info.event, c, values.get::<D>(c));
+        Ok(())
+    })
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Register how to derive a `D` from the context
+    ///
+    /// Registering a second derivation for the same type replaces the first.
+    pub fn with_derived<D, F>(&mut self, derive: F) -> &mut Self
+    where
+        D: Send + Sync + 'static,
+        F: Fn(&C) -> D + Send + Sync + 'static,
+    {
+        self.derivations.insert(
+            TypeId::of::<D>(),
+            Arc::new(move |context| Box::new(derive(context))),
+        );
+        self
+    }
+
+    // Record the derived values a transition from `from` on `event` needs
+    pub(crate) fn require_derived(&mut self, inputs: DerivedInputs, from: Option<&S>, event: &E) {
+        let mut needed = vec![inputs.guard, inputs.action];
+        needed.dedup();
+        for (type_id, value) in needed.into_iter().flatten() {
+            self.required_derivations.push((
+                type_id,
+                BuildError::MissingDerivation {
+                    value,
+                    from: from.map(|from| format!("{:?}", from)),
+                    event: format!("{:?}", event),
+                },
+            ));
+        }
+    }
+
+    // Transitions needing a derived value without a derivation
+    pub(crate) fn missing_derivations(&self) -> Vec<BuildError> {
+        self.required_derivations
+            .iter()
+            .filter(|(type_id, _)| !self.derivations.contains_key(type_id))
+            .map(|(_, error)| error.clone())
+            .collect()
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the value registered with `with_derived`
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info`.
+    pub fn when_derived<D, F>(mut self, condition: F) -> Self
+    where
+        D: 'static,
+        F: Fn(&S, &E, &C, &D) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(derived_condition(condition));
+        self.derived.guard = derived_input::<D>();
+        self
+    }
+
+    /// Like `perform`, with the value registered with `with_derived`
+    pub fn perform_derived<D, F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        D: 'static,
+        F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(derived_action(action));
+        self.derived.action = derived_input::<D>();
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> InternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the value registered with `with_derived`
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info`.
+    pub fn when_derived<D, F>(mut self, condition: F) -> Self
+    where
+        D: 'static,
+        F: Fn(&S, &E, &C, &D) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(derived_condition(condition));
+        self.derived.guard = derived_input::<D>();
+        self
+    }
+
+    /// Like `perform`, with the value registered with `with_derived`
+    pub fn perform_derived<D, F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        D: 'static,
+        F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(derived_action(action));
+        self.derived.action = derived_input::<D>();
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionsBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the value registered with `with_derived`
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info`.
+    pub fn when_derived<D, F>(mut self, condition: F) -> Self
+    where
+        D: 'static,
+        F: Fn(&S, &E, &C, &D) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(derived_condition(condition));
+        self.derived.guard = derived_input::<D>();
+        self
+    }
+
+    /// Like `perform`, with the value registered with `with_derived`
+    pub fn perform_derived<D, F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        D: 'static,
+        F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(derived_action(action));
+        self.derived.action = derived_input::<D>();
+        self.add()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BuildError, Context, Event, State, StateMachine, StateMachineBuilder,
+        StateMachineBuilderFactory,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Loan {
+        Applied,
+        Rejected,
+        Review,
+        Approved,
+    }
+
+    impl State for Loan {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum LoanEvent {
+        Assess,
+        Withdraw,
+    }
+
+    impl Event for LoanEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Application {
+        income: u32,
+        debt: u32,
+    }
+
+    impl Context for Application {}
+
+    struct RiskScore(u32);
+
+    fn loan_machine(derivations: Arc<AtomicUsize>) -> StateMachine<Loan, LoanEvent, Application> {
+        let mut builder = StateMachineBuilderFactory::create::<Loan, LoanEvent, Application>();
+        builder.with_derived(move |c: &Application| {
+            derivations.fetch_add(1, Ordering::SeqCst);
+            RiskScore(c.debt * 100 / c.income.max(1))
+        });
+        builder
+            .external_transition()
+            .from(Loan::Applied)
+            .to(Loan::Rejected)
+            .on(LoanEvent::Assess)
+            .when_derived(|_s, _e, _c, risk: &RiskScore| risk.0 > 80)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Loan::Applied)
+            .to(Loan::Review)
+            .on(LoanEvent::Assess)
+            .when_derived(|_s, _e, _c, risk: &RiskScore| risk.0 > 40)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Loan::Applied)
+            .to(Loan::Approved)
+            .on(LoanEvent::Assess)
+            .when_derived(|_s, _e, _c, risk: &RiskScore| risk.0 <= 40)
+            .perform_derived(|_s, _e, _c, risk: &RiskScore| assert!(risk.0 <= 40));
+        builder
+            .internal_transition()
+            .within(Loan::Applied)
+            .on(LoanEvent::Withdraw)
+            .perform(|_s, _e, _c| {});
+        builder.build()
+    }
+
+    #[test]
+    fn test_derivation_runs_once_per_fire() {
+        let derivations = Arc::new(AtomicUsize::new(0));
+        let machine = loan_machine(derivations.clone());
+
+        let context = Application {
+            income: 100,
+            debt: 10,
+        };
+        let result = machine.fire_event(Loan::Applied, LoanEvent::Assess, context.clone());
+        assert_eq!(result.unwrap(), Loan::Approved);
+        assert_eq!(derivations.load(Ordering::SeqCst), 1);
+
+        let result = machine.fire_event(Loan::Applied, LoanEvent::Assess, context);
+        assert_eq!(result.unwrap(), Loan::Approved);
+        assert_eq!(derivations.load(Ordering::SeqCst), 2);
+    }
+
+    fn underwriting_builder() -> StateMachineBuilder<Loan, LoanEvent, Application> {
+        let mut builder = StateMachineBuilderFactory::create::<Loan, LoanEvent, Application>();
+        builder
+            .external_transitions()
+            .from_among(vec![Loan::Applied, Loan::Review])
+            .to(Loan::Rejected)
+            .on(LoanEvent::Assess)
+            .when_derived(|_s, _e, _c, risk: &RiskScore| risk.0 > 80)
+            .perform_derived(|_s, _e, _c, _risk: &RiskScore| {});
+        // The derived guard is replaced, so the transition needs no derivation
+        builder
+            .external_transition()
+            .from(Loan::Review)
+            .to(Loan::Approved)
+            .on(LoanEvent::Assess)
+            .when_derived(|_s, _e, _c, risk: &RiskScore| risk.0 <= 40)
+            .when_with_info(|_info, _c| true)
+            .add();
+        builder.terminal_states(vec![Loan::Rejected, Loan::Approved]);
+        builder
+    }
+
+    #[test]
+    fn test_missing_derivation_fails_build() {
+        let errors = match underwriting_builder().try_build() {
+            Err(errors) => errors,
+            Ok(_) => panic!("expected MissingDerivation"),
+        };
+        let missing = |from: &str| BuildError::MissingDerivation {
+            value: std::any::type_name::<RiskScore>(),
+            from: Some(from.to_string()),
+            event: "Assess".to_string(),
+        };
+        assert_eq!(errors, vec![missing("Applied"), missing("Review")]);
+
+        let mut builder = underwriting_builder();
+        builder.with_derived(|c: &Application| RiskScore(c.debt));
+        assert!(builder.try_build().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "Transition from Applied on Assess needs derived")]
+    fn test_build_panics_on_missing_derivation() {
+        underwriting_builder().build();
+    }
+
+    #[test]
+    fn test_derivation_skipped_without_derived_callbacks() {
+        let derivations = Arc::new(AtomicUsize::new(0));
+        let machine = loan_machine(derivations.clone());
+
+        let context = Application {
+            income: 100,
+            debt: 10,
+        };
+        let result = machine.fire_event(Loan::Applied, LoanEvent::Withdraw, context);
+        assert_eq!(result.unwrap(), Loan::Applied);
+        assert_eq!(derivations.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,57 @@
+//! Human-written descriptions of states and events
+//!
+//! Descriptions are documentation only: they show up in exports and
+//! introspection and never affect firing.
+
+use std::collections::HashMap;
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder};
+
+#[derive(Clone)]
+pub(crate) struct Descriptions<S, E> {
+    pub(crate) states: HashMap<S, String>,
+    pub(crate) events: HashMap<E, String>,
+}
+
+impl<S, E> Default for Descriptions<S, E> {
+    fn default() -> Self {
+        Descriptions {
+            states: HashMap::new(),
+            events: HashMap::new(),
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Attach a description to `state`, replacing any previous one
+    pub fn describe_state(&mut self, state: S, text: impl Into<String>) -> &mut Self {
+        self.descriptions.states.insert(state, text.into());
+        self
+    }
+
+    /// Attach a description to `event`, replacing any previous one
+    pub fn describe_event(&mut self, event: E, text: impl Into<String>) -> &mut Self {
+        self.descriptions.events.insert(event, text.into());
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn state_description(&self, state: &S) -> Option<&str> {
+        self.descriptions.states.get(state).map(String::as_str)
+    }
+
+    pub fn event_description(&self, event: &E) -> Option<&str> {
+        self.descriptions.events.get(event).map(String::as_str)
+    }
+}
@@ -0,0 +1,494 @@
+//! Recording and replaying the nondeterministic inputs of fires
+//!
+//! A machine built with `StateMachineBuilder::with_determinism_log` writes
+//! the inputs that may differ between two runs of the same events to a
+//! `DeterminismLog`: every reading of its `Clock`, and the candidate each
+//! fire selected among the transitions of its `(from, event)` pair. A log in
+//! `ReplayMode::Replay` feeds the recorded inputs back instead: the clock
+//! returns the recorded readings, and fires take the recorded candidate
+//! without evaluating guards or feature flags. A fire that selected no
+//! candidate, or several, replays as `TransitionError::ConditionFailed`.
+//!
+//! Inputs are consumed in order, so the replayed fires must be the recorded
+//! ones, made one at a time on a machine with the same features and clock
+//! consumers. A fire whose candidate doesn't match the next input fails with
+//! `TransitionError::ReplayDiverged`. A mismatched clock reading returns the
+//! live time instead, and the divergence fails the next fire that selects a
+//! candidate. Decisions serialize with the `serde` feature, so a log
+//! recorded in production can be replayed locally.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    Clock, Context, Event, State, StateMachine, StateMachineBuilder, Transition, TransitionError,
+};
+
+/// Whether a `DeterminismLog` writes inputs or feeds them back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayMode {
+    Record,
+    Replay,
+}
+
+/// A nondeterministic input consumed by a fire
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Decision {
+    /// `Clock::now`, as the time since the first reading of the log
+    Monotonic { since_start: Duration },
+    /// `Clock::wall_time`
+    WallTime { time: SystemTime },
+    /// Position, in evaluation order, of the candidate taken among the
+    /// transitions of the pair; `None` if no single one was taken
+    Candidate {
+        from: String,
+        event: String,
+        index: Option<usize>,
+    },
+}
+
+impl Decision {
+    fn describe(&self) -> String {
+        match self {
+            Decision::Monotonic { .. } => MONOTONIC.to_string(),
+            Decision::WallTime { .. } => WALL_TIME.to_string(),
+            Decision::Candidate { from, event, .. } => candidate(from, event),
+        }
+    }
+}
+
+const MONOTONIC: &str = "a monotonic clock reading";
+const WALL_TIME: &str = "a wall clock reading";
+
+fn candidate(from: &str, event: &str) -> String {
+    format!("the candidate of {} on {}", from, event)
+}
+
+/// Why a replay could not go on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeterminismError {
+    /// Input `position` of the log is not what the replayed run asked for;
+    /// `found` is `None` past the end of the log
+    LogDivergence {
+        position: usize,
+        expected: String,
+        found: Option<String>,
+    },
+}
+
+impl fmt::Display for DeterminismError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeterminismError::LogDivergence {
+                position,
+                expected,
+                found: Some(found),
+            } => write!(
+                f,
+                "Replay diverged at input {}: expected {}, logged {}",
+                position, expected, found
+            ),
+            DeterminismError::LogDivergence {
+                position,
+                expected,
+                found: None,
+            } => write!(
+                f,
+                "Replay diverged at input {}: expected {}, the log ended",
+                position, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeterminismError {}
+
+/// Nondeterministic inputs of a machine's fires, see the module
+/// documentation
+pub struct DeterminismLog {
+    mode: ReplayMode,
+    state: Mutex<LogState>,
+}
+
+#[derive(Default)]
+struct LogState {
+    decisions: Vec<Decision>,
+    // Next decision to replay
+    cursor: usize,
+    // Monotonic time of the first reading, recorded or replayed
+    origin: Option<Instant>,
+    divergence: Option<DeterminismError>,
+}
+
+impl DeterminismLog {
+    /// Empty log writing the inputs of live fires
+    pub fn recording() -> Self {
+        DeterminismLog {
+            mode: ReplayMode::Record,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Log feeding `decisions`, as recorded, back to the fires
+    pub fn replaying(decisions: Vec<Decision>) -> Self {
+        DeterminismLog {
+            mode: ReplayMode::Replay,
+            state: Mutex::new(LogState {
+                decisions,
+                ..LogState::default()
+            }),
+        }
+    }
+
+    pub fn mode(&self) -> ReplayMode {
+        self.mode
+    }
+
+    /// The inputs recorded so far, or being replayed
+    pub fn decisions(&self) -> Vec<Decision> {
+        self.state.lock().unwrap().decisions.clone()
+    }
+
+    /// Recorded inputs not replayed yet
+    pub fn remaining(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.decisions.len() - state.cursor.min(state.decisions.len())
+    }
+
+    /// The first divergence met while replaying
+    pub fn divergence(&self) -> Option<DeterminismError> {
+        self.state.lock().unwrap().divergence.clone()
+    }
+
+    fn now(&self, live: Instant) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let origin = *state.origin.get_or_insert(live);
+        match (self.mode, state.next()) {
+            (ReplayMode::Record, _) => {
+                let since_start = live.saturating_duration_since(origin);
+                state.decisions.push(Decision::Monotonic { since_start });
+                live
+            }
+            (ReplayMode::Replay, Some(Decision::Monotonic { since_start })) => {
+                state.cursor += 1;
+                origin + since_start
+            }
+            (ReplayMode::Replay, logged) => {
+                state.diverge(MONOTONIC.to_string(), logged);
+                live
+            }
+        }
+    }
+
+    fn wall_time(&self, live: SystemTime) -> SystemTime {
+        let mut state = self.state.lock().unwrap();
+        match (self.mode, state.next()) {
+            (ReplayMode::Record, _) => {
+                state.decisions.push(Decision::WallTime { time: live });
+                live
+            }
+            (ReplayMode::Replay, Some(Decision::WallTime { time })) => {
+                state.cursor += 1;
+                time
+            }
+            (ReplayMode::Replay, logged) => {
+                state.diverge(WALL_TIME.to_string(), logged);
+                live
+            }
+        }
+    }
+
+    // Record the candidate a live selection took
+    fn record_candidate(&self, from: String, event: String, index: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .decisions
+            .push(Decision::Candidate { from, event, index });
+    }
+
+    // The recorded candidate of the pair, among `count`
+    fn replay_candidate(
+        &self,
+        from: String,
+        event: String,
+        count: usize,
+    ) -> Result<Option<usize>, DeterminismError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(divergence) = &state.divergence {
+            return Err(divergence.clone());
+        }
+        match state.next() {
+            Some(Decision::Candidate {
+                from: logged_from,
+                event: logged_event,
+                index,
+            }) if logged_from == from
+                && logged_event == event
+                && index.is_none_or(|index| index < count) =>
+            {
+                state.cursor += 1;
+                Ok(index)
+            }
+            logged => Err(state.diverge(candidate(&from, &event), logged)),
+        }
+    }
+}
+
+impl LogState {
+    // The next decision to replay
+    fn next(&self) -> Option<Decision> {
+        self.decisions.get(self.cursor).cloned()
+    }
+
+    // Remember the first divergence, at the next decision to replay
+    fn diverge(&mut self, expected: String, logged: Option<Decision>) -> DeterminismError {
+        let error = DeterminismError::LogDivergence {
+            position: self.cursor,
+            expected,
+            found: logged.as_ref().map(Decision::describe),
+        };
+        self.divergence.get_or_insert_with(|| error.clone());
+        error
+    }
+}
+
+// The candidate a fire takes, if any
+type Selection<'m, S, E, C> = Result<Option<&'m Transition<S, E, C>>, TransitionError<S, E>>;
+
+// Clock of a machine with a determinism log, passing every reading through it
+struct LoggedClock {
+    clock: Arc<dyn Clock>,
+    log: Arc<DeterminismLog>,
+}
+
+impl Clock for LoggedClock {
+    fn now(&self) -> Instant {
+        self.log.now(self.clock.now())
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.log.wall_time(self.clock.wall_time())
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Record the nondeterministic inputs of fires to `log`, or replay them
+    /// from it, depending on its mode
+    ///
+    /// Applies to the clock set with `with_clock` before or after this call.
+    pub fn with_determinism_log(&mut self, log: Arc<DeterminismLog>) -> &mut Self {
+        self.determinism = Some(log);
+        self
+    }
+}
+
+// `clock`, reading through `log` if there is one
+pub(crate) fn logged_clock(
+    clock: Arc<dyn Clock>,
+    log: Option<&Arc<DeterminismLog>>,
+) -> Arc<dyn Clock> {
+    match log {
+        Some(log) => Arc::new(LoggedClock {
+            clock,
+            log: log.clone(),
+        }),
+        None => clock,
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// The log set with `StateMachineBuilder::with_determinism_log`
+    pub fn determinism_log(&self) -> Option<&Arc<DeterminismLog>> {
+        self.determinism.as_ref()
+    }
+
+    // Run the live candidate `selection` of the pair and record its choice,
+    // or take the recorded choice instead when replaying
+    pub(crate) fn logged_selection<'m>(
+        &self,
+        from: &S,
+        event: &E,
+        transitions: &'m [Transition<S, E, C>],
+        selection: impl FnOnce() -> Selection<'m, S, E, C>,
+    ) -> Selection<'m, S, E, C> {
+        let Some(log) = &self.determinism else {
+            return selection();
+        };
+        let (from, event) = (format!("{:?}", from), format!("{:?}", event));
+        match log.mode() {
+            ReplayMode::Record => {
+                let selected = selection();
+                let index = match &selected {
+                    Ok(Some(taken)) => transitions
+                        .iter()
+                        .position(|transition| std::ptr::eq(transition, *taken)),
+                    _ => None,
+                };
+                log.record_candidate(from, event, index);
+                selected
+            }
+            ReplayMode::Replay => log
+                .replay_candidate(from, event, transitions.len())
+                .map(|index| index.map(|index| &transitions[index]))
+                .map_err(|error| TransitionError::ReplayDiverged { error }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Ticket {
+        Open,
+        Escalated,
+        Resolved,
+    }
+
+    impl State for Ticket {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum TicketEvent {
+        Triage,
+    }
+
+    impl Event for TicketEvent {}
+
+    // Triage escalates while `busy` is set, which stands for a guard
+    // reading the outside world
+    fn ticket_machine(
+        busy: Arc<AtomicBool>,
+        log: Arc<DeterminismLog>,
+    ) -> StateMachine<Ticket, TicketEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Ticket, TicketEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Escalated)
+            .on(TicketEvent::Triage)
+            .when(move |_s, _e, _c| busy.load(Ordering::SeqCst))
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Resolved)
+            .on(TicketEvent::Triage)
+            .perform(|_s, _e, _c| {});
+        builder.with_determinism_log(log);
+        builder.build()
+    }
+
+    fn triage(machine: &StateMachine<Ticket, TicketEvent, NoContext>) -> Ticket {
+        machine
+            .fire_event(Ticket::Open, TicketEvent::Triage, NoContext)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_replay_reproduces_choices_and_clock() {
+        let busy = Arc::new(AtomicBool::new(true));
+        let recording = Arc::new(DeterminismLog::recording());
+        let live = ticket_machine(busy.clone(), recording.clone());
+        let mut outcomes = vec![triage(&live)];
+        busy.store(false, Ordering::SeqCst);
+        outcomes.push(triage(&live));
+        assert_eq!(outcomes, vec![Ticket::Escalated, Ticket::Resolved]);
+
+        let decisions = recording.decisions();
+        let candidates: Vec<_> = decisions
+            .iter()
+            .filter_map(|decision| match decision {
+                Decision::Candidate { index, .. } => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(candidates, vec![Some(0), Some(1)]);
+
+        // The guard now passes, yet replay takes the recorded candidates
+        busy.store(true, Ordering::SeqCst);
+        let replaying = Arc::new(DeterminismLog::replaying(decisions));
+        let replay = ticket_machine(busy, replaying.clone());
+        assert_eq!(vec![triage(&replay), triage(&replay)], outcomes);
+        assert_eq!(replaying.remaining(), 0);
+        assert_eq!(replaying.divergence(), None);
+
+        #[cfg(feature = "history")]
+        {
+            let gaps = |history: Vec<crate::TransitionRecord<Ticket, TicketEvent>>| {
+                history[1].timestamp - history[0].timestamp
+            };
+            assert_eq!(gaps(replay.get_history()), gaps(live.get_history()));
+        }
+    }
+
+    #[test]
+    fn test_tampered_log_detected() {
+        let busy = Arc::new(AtomicBool::new(true));
+        let recording = Arc::new(DeterminismLog::recording());
+        triage(&ticket_machine(busy.clone(), recording.clone()));
+
+        let tampered = recording
+            .decisions()
+            .into_iter()
+            .map(|decision| match decision {
+                Decision::Candidate { from, index, .. } => Decision::Candidate {
+                    from,
+                    event: "Close".to_string(),
+                    index,
+                },
+                other => other,
+            })
+            .collect();
+        let replaying = Arc::new(DeterminismLog::replaying(tampered));
+        let replay = ticket_machine(busy, replaying.clone());
+        let result = replay.fire_event(Ticket::Open, TicketEvent::Triage, NoContext);
+        assert!(matches!(
+            result,
+            Err(TransitionError::ReplayDiverged {
+                error: DeterminismError::LogDivergence { position: 0, .. }
+            })
+        ));
+        assert!(replaying.divergence().is_some());
+
+        let exhausted = ticket_machine(
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(DeterminismLog::replaying(Vec::new())),
+        );
+        let result = exhausted.fire_event(Ticket::Open, TicketEvent::Triage, NoContext);
+        assert!(matches!(
+            result,
+            Err(TransitionError::ReplayDiverged {
+                error: DeterminismError::LogDivergence { found: None, .. }
+            })
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_log_round_trips_through_json() {
+        let recording = Arc::new(DeterminismLog::recording());
+        triage(&ticket_machine(
+            Arc::new(AtomicBool::new(false)),
+            recording.clone(),
+        ));
+        let json = serde_json::to_string(&recording.decisions()).unwrap();
+        let decisions: Vec<Decision> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decisions, recording.decisions());
+    }
+}
@@ -0,0 +1,293 @@
+//! Copies of a machine with replaced callbacks, for use as test doubles
+
+use std::sync::Arc;
+
+use crate::callbacks::{self, Guard};
+use crate::conditions;
+use crate::info::InfoCondition;
+use crate::{Action, Condition, Context, Event, State, StateMachine, TransitionType};
+
+#[cfg(feature = "extended")]
+use crate::StateAction;
+
+/// Identifies the transition whose guard or action is being replaced
+///
+/// For `from_any` transitions `from` is the same as `to`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionSlot<S, E> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+    pub transition_type: TransitionType,
+}
+
+/// Which state callback is being replaced
+#[cfg(feature = "extended")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateActionKind {
+    Entry,
+    Exit,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Copy this machine, replacing transition actions
+    ///
+    /// `f` is called once per transition; returning `Some` installs the
+    /// replacement, `None` keeps the original action. The copy shares no
+    /// mutable state with this machine: history and metrics start empty.
+    pub fn with_actions_replaced<F>(&self, f: F) -> StateMachine<S, E, C>
+    where
+        F: Fn(ActionSlot<S, E>) -> Option<Action<S, E, C>>,
+    {
+        let mut machine = self.fork();
+        for transition in machine.transitions_mut() {
+            if let Some(action) = f(Self::slot(transition)) {
+                transition.action = callbacks::Action::Plain(action);
+            }
+        }
+        machine
+    }
+
+    /// Copy this machine, replacing transition guards
+    ///
+    /// Works like `with_actions_replaced`; returning `Some` also installs a
+    /// guard on transitions that had none. A `when_providing` guard keeps
+    /// running after the replacement passed, so its `perform_with` action
+    /// gets the data, but no longer decides. When it provides nothing, the
+    /// fire fails with `TransitionError::ActionFailed`.
+    pub fn with_guards_replaced<F>(&self, f: F) -> StateMachine<S, E, C>
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+        F: Fn(ActionSlot<S, E>) -> Option<Condition<S, E, C>>,
+    {
+        let mut machine = self.fork();
+        for transition in machine.transitions_mut() {
+            if let Some(condition) = f(Self::slot(transition)) {
+                let (_, info) =
+                    std::mem::replace(&mut transition.guard, Guard::Unguarded).into_parts();
+                let provider = info.map(|original| {
+                    let provider: InfoCondition<S, E, C> = Arc::new(move |info, c, values| {
+                        original(info, c, values);
+                        true
+                    });
+                    provider
+                });
+                transition.guard =
+                    Guard::from_parts(Some(conditions::checked(condition)), provider);
+            }
+        }
+        machine
+    }
+
+    #[cfg(feature = "extended")]
+    /// Copy this machine, replacing entry and exit actions
+    ///
+    /// `f` is called for every registered entry and exit action; returning
+    /// `Some` installs the replacement, `None` keeps the original.
+    pub fn with_state_actions_replaced<F>(&self, f: F) -> StateMachine<S, E, C>
+    where
+        F: Fn(&S, StateActionKind) -> Option<StateAction<S, C>>,
+    {
+        let mut machine = self.fork();
+        for (state, actions) in machine.state_actions.iter_mut() {
+            if actions.on_entry.is_some()
+                || actions.on_entry_mut.is_some()
+                || actions.on_entry_fallible.is_some()
+            {
+                if let Some(action) = f(state, StateActionKind::Entry) {
+                    actions.on_entry = Some(action);
+                    actions.on_entry_mut = None;
+                    actions.on_entry_fallible = None;
+                }
+            }
+            if actions.on_exit.is_some() || actions.on_exit_mut.is_some() {
+                if let Some(action) = f(state, StateActionKind::Exit) {
+                    actions.on_exit = Some(action);
+                    actions.on_exit_mut = None;
+                }
+            }
+        }
+        machine
+    }
+
+    pub(crate) fn transitions_mut(
+        &mut self,
+    ) -> impl Iterator<Item = &mut crate::Transition<S, E, C>> {
+        self.transitions
+            .values_mut()
+            .chain(self.wildcard_transitions.values_mut())
+            .flat_map(|candidates| candidates.iter_mut())
+    }
+
+    fn slot(transition: &crate::Transition<S, E, C>) -> ActionSlot<S, E> {
+        ActionSlot {
+            from: transition.from.clone(),
+            event: transition.event.clone(),
+            to: transition.to.clone(),
+            transition_type: transition.transition_type.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StateMachineBuilderFactory, TransitionError};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Mail {
+        Draft,
+        Sent,
+    }
+
+    impl State for Mail {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum MailEvent {
+        Send,
+    }
+
+    impl Event for MailEvent {}
+
+    #[derive(Debug, Clone)]
+    struct MailContext {
+        approved: bool,
+    }
+
+    impl Context for MailContext {}
+
+    #[test]
+    fn test_actions_and_guards_replaced() {
+        let production_sends = Arc::new(AtomicUsize::new(0));
+        let sends = production_sends.clone();
+
+        let mut builder = StateMachineBuilderFactory::create::<Mail, MailEvent, MailContext>();
+        builder
+            .external_transition()
+            .from(Mail::Draft)
+            .to(Mail::Sent)
+            .on(MailEvent::Send)
+            .when(|_s, _e, c| c.approved)
+            .perform(move |_s, _e, _c| {
+                sends.fetch_add(1, Ordering::SeqCst);
+            });
+        let production = builder.build();
+
+        let spy_calls = Arc::new(AtomicUsize::new(0));
+        let spy = spy_calls.clone();
+        let double = production
+            .with_actions_replaced(|slot| {
+                assert_eq!(slot.to, Mail::Sent);
+                let spy = spy.clone();
+                let action: Action<Mail, MailEvent, MailContext> = Arc::new(move |_s, _e, _c| {
+                    spy.fetch_add(1, Ordering::SeqCst);
+                });
+                Some(action)
+            })
+            .with_guards_replaced(|_slot| Some(Arc::new(|_s, _e, _c| true)));
+
+        let context = MailContext { approved: false };
+        assert_eq!(
+            double
+                .fire_event(Mail::Draft, MailEvent::Send, context.clone())
+                .unwrap(),
+            Mail::Sent
+        );
+        assert_eq!(spy_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(production_sends.load(Ordering::SeqCst), 0);
+
+        // The original machine keeps its guard and action
+        assert!(production
+            .fire_event(Mail::Draft, MailEvent::Send, context)
+            .is_err());
+        assert!(production
+            .fire_event(Mail::Draft, MailEvent::Send, MailContext { approved: true })
+            .is_ok());
+        assert_eq!(production_sends.load(Ordering::SeqCst), 1);
+        assert_eq!(spy_calls.load(Ordering::SeqCst), 1);
+
+        #[cfg(feature = "history")]
+        {
+            assert_eq!(double.get_history().len(), 1);
+            assert_eq!(production.get_history().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_guard_replaced_keeps_provided_data() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+
+        let mut builder = StateMachineBuilderFactory::create::<Mail, MailEvent, MailContext>();
+        builder
+            .external_transition()
+            .from(Mail::Draft)
+            .to(Mail::Sent)
+            .on(MailEvent::Send)
+            .when_providing(|_s, _e, c| c.approved.then(|| "outbox".to_string()))
+            .perform_with(move |_s, _e, _c, queue: &String| {
+                sink.lock().unwrap().push(queue.clone());
+            });
+        let double = builder
+            .build()
+            .with_guards_replaced(|_slot| Some(Arc::new(|_s, _e, _c| true)));
+
+        let approved = MailContext { approved: true };
+        assert_eq!(
+            double
+                .fire_event(Mail::Draft, MailEvent::Send, approved)
+                .unwrap(),
+            Mail::Sent
+        );
+        assert_eq!(*received.lock().unwrap(), vec!["outbox".to_string()]);
+
+        // Forced through without data, the action fails rather than being
+        // dropped from a committed transition
+        let rejected = MailContext { approved: false };
+        assert!(matches!(
+            double.fire_event(Mail::Draft, MailEvent::Send, rejected),
+            Err(TransitionError::ActionFailed { .. })
+        ));
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_entry_action_replaced() {
+        let entries = Arc::new(AtomicUsize::new(0));
+        let mut builder = StateMachineBuilderFactory::create::<Mail, MailEvent, MailContext>();
+        builder
+            .with_entry_action(Mail::Sent, |_s, _c| panic!("production entry action"))
+            .external_transition()
+            .from(Mail::Draft)
+            .to(Mail::Sent)
+            .on(MailEvent::Send)
+            .perform(|_s, _e, _c| {});
+        let production = builder.build();
+
+        let counter = entries.clone();
+        let double = production.with_state_actions_replaced(move |state, kind| {
+            assert_eq!(state, &Mail::Sent);
+            assert_eq!(kind, StateActionKind::Entry);
+            let counter = counter.clone();
+            let action: StateAction<Mail, MailContext> = Arc::new(move |_s, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            Some(action)
+        });
+
+        let result =
+            double.fire_event(Mail::Draft, MailEvent::Send, MailContext { approved: true });
+        assert!(result.is_ok());
+        assert_eq!(entries.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,323 @@
+//! Bounded statistics of transition durations (requires the `metrics`
+//! feature)
+//!
+//! `StateMachineMetrics::transition_durations` counts the fires, sums and
+//! bounds their durations and sorts them into a log-linear histogram: 16
+//! buckets for every power of two of nanoseconds, so percentiles are off by
+//! at most 1/16 of the value while the memory taken only grows with the
+//! longest duration, never with the number of fires. Machines built with
+//! `StateMachineBuilder::with_duration_samples` also keep every duration.
+
+use std::time::Duration;
+
+use crate::{Context, Event, State, StateMachineBuilder};
+
+// Buckets per power of two are `1 << SUB_BITS`
+const SUB_BITS: u32 = 4;
+const SUB: u64 = 1 << SUB_BITS;
+
+fn bucket_of(nanos: u64) -> usize {
+    if nanos < SUB {
+        return nanos as usize;
+    }
+    let shift = 63 - nanos.leading_zeros() - SUB_BITS;
+    ((u64::from(shift) + 1) * SUB + (nanos >> shift) - SUB) as usize
+}
+
+// Largest duration in nanoseconds sorted into bucket `index`
+fn bucket_upper(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB {
+        return index;
+    }
+    let shift = index / SUB - 1;
+    let upper = (u128::from(SUB + index % SUB + 1) << shift) - 1;
+    upper.min(u128::from(u64::MAX)) as u64
+}
+
+/// Count, sum, bounds and histogram of durations, see the module
+/// documentation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DurationStats {
+    count: u64,
+    total: Duration,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    buckets: Vec<u64>,
+    samples: Option<Vec<Duration>>,
+}
+
+impl DurationStats {
+    /// Empty statistics that also keep every duration recorded
+    pub(crate) fn with_samples(capacity: usize) -> Self {
+        DurationStats {
+            samples: Some(Vec::with_capacity(capacity)),
+            ..Self::default()
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(self.total.div_f64(count as f64)),
+        }
+    }
+
+    /// Duration below or at which a fraction `quantile` of the durations
+    /// fall, `None` when empty
+    ///
+    /// `quantile` is clamped to `0.0..=1.0`.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let (min, max) = (self.min?, self.max?);
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_nanos(bucket_upper(index)).clamp(min, max));
+            }
+        }
+        Some(max)
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(0.5)
+    }
+
+    pub fn p95(&self) -> Option<Duration> {
+        self.percentile(0.95)
+    }
+
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(0.99)
+    }
+
+    /// Every duration recorded, oldest first, when the machine was built
+    /// with `with_duration_samples`
+    pub fn samples(&self) -> Option<&[Duration]> {
+        self.samples.as_deref()
+    }
+
+    pub(crate) fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.min = Some(self.min.map_or(duration, |min| min.min(duration)));
+        self.max = self.max.max(Some(duration));
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let index = bucket_of(nanos);
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        if let Some(samples) = &mut self.samples {
+            samples.push(duration);
+        }
+    }
+
+    /// Add the durations recorded by `other`
+    ///
+    /// The result keeps samples only if both sides kept all of theirs.
+    pub(crate) fn merge(&mut self, other: &DurationStats) {
+        self.samples = match (self.samples.take(), &other.samples) {
+            (Some(mut own), Some(theirs)) => {
+                own.extend_from_slice(theirs);
+                Some(own)
+            }
+            (Some(own), None) if other.count == 0 => Some(own),
+            (None, Some(theirs)) if self.count == 0 => Some(theirs.clone()),
+            _ => None,
+        };
+        self.count += other.count;
+        self.total += other.total;
+        self.min = match (self.min, other.min) {
+            (Some(own), Some(other)) => Some(own.min(other)),
+            (own, other) => own.or(other),
+        };
+        self.max = self.max.max(other.max);
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (own, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *own += count;
+        }
+    }
+
+    /// Bytes held on the heap
+    pub(crate) fn heap_size(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<u64>()
+            + self.samples.as_ref().map_or(0, |samples| {
+                samples.capacity() * std::mem::size_of::<Duration>()
+            })
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Keep the duration of every fire in `DurationStats::samples` on top
+    /// of the bounded statistics
+    ///
+    /// The samples grow with every fire until the metrics are reset.
+    pub fn with_duration_samples(&mut self) -> &mut Self {
+        self.duration_samples = true;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{StateMachine, StateMachineBuilderFactory};
+
+    #[test]
+    fn test_buckets_cover_every_value() {
+        for nanos in (0..4096).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let index = bucket_of(nanos);
+            assert!(
+                bucket_upper(index) >= nanos,
+                "{} in bucket {}",
+                nanos,
+                index
+            );
+            if index > 0 {
+                assert!(
+                    bucket_upper(index - 1) < nanos,
+                    "{} in bucket {}",
+                    nanos,
+                    index
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentiles_within_bucket_error() {
+        let mut stats = DurationStats::default();
+        assert_eq!(stats.p50(), None);
+        for millis in (1..=1000).rev() {
+            stats.record(Duration::from_millis(millis));
+        }
+
+        assert_eq!(stats.count(), 1000);
+        assert_eq!(stats.mean(), Some(Duration::from_micros(500_500)));
+        assert_eq!(stats.min(), Some(Duration::from_millis(1)));
+        assert_eq!(stats.percentile(1.0), Some(Duration::from_millis(1000)));
+        for (actual, exact) in [
+            (stats.percentile(0.0), 1.0),
+            (stats.p50(), 500.0),
+            (stats.p95(), 950.0),
+            (stats.p99(), 990.0),
+        ] {
+            let actual = actual.unwrap().as_secs_f64() * 1000.0;
+            assert!(
+                actual >= exact && actual <= exact * (1.0 + 1.0 / SUB as f64),
+                "{} for {}",
+                actual,
+                exact
+            );
+        }
+        assert_eq!(stats.samples(), None);
+    }
+
+    #[test]
+    fn test_merge_matches_single_recording() {
+        let durations: Vec<Duration> = (0..200).map(|i| Duration::from_micros(i * 37)).collect();
+        let mut whole = DurationStats::default();
+        let mut first = DurationStats::default();
+        let mut second = DurationStats::default();
+        for (i, duration) in durations.iter().enumerate() {
+            whole.record(*duration);
+            if i % 3 == 0 { &mut first } else { &mut second }.record(*duration);
+        }
+        first.merge(&second);
+        assert_eq!(first, whole);
+
+        // A side without samples drops them
+        let mut sampled = DurationStats::with_samples(0);
+        sampled.record(Duration::from_millis(1));
+        let mut merged = sampled.clone();
+        merged.merge(&DurationStats::default());
+        assert_eq!(merged.samples().map(<[_]>::len), Some(1));
+        merged.merge(&whole);
+        assert_eq!(merged.samples(), None);
+        let mut empty = DurationStats::default();
+        empty.merge(&sampled);
+        assert_eq!(empty.samples(), sampled.samples());
+    }
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Light {
+        Red,
+        Green,
+    }
+
+    impl State for Light {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum LightEvent {
+        Switch,
+    }
+
+    impl Event for LightEvent {}
+
+    fn light_machine(samples: bool) -> StateMachine<Light, LightEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Light, LightEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Light::Red)
+            .to(Light::Green)
+            .on(LightEvent::Switch)
+            .add();
+        if samples {
+            builder.with_duration_samples();
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_samples_kept_on_request() {
+        for samples in [false, true] {
+            let machine = light_machine(samples);
+            for _ in 0..3 {
+                machine
+                    .fire_event(Light::Red, LightEvent::Switch, NoContext)
+                    .unwrap();
+            }
+            let durations = machine.get_metrics().transition_durations;
+            assert_eq!(durations.count(), 3);
+            assert_eq!(durations.samples().map(<[_]>::len), samples.then_some(3));
+
+            // Resetting starts over with the same representation
+            machine.reset_metrics();
+            machine
+                .fire_event(Light::Red, LightEvent::Switch, NoContext)
+                .unwrap();
+            let durations = machine.get_metrics().transition_durations;
+            assert_eq!(durations.samples().map(<[_]>::len), samples.then_some(1));
+        }
+    }
+}
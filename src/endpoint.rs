@@ -0,0 +1,322 @@
+//! Firing events from axum handlers (requires the `axum` feature)
+//!
+//! `EventEndpoint` is the glue most web services write by hand: it reads the
+//! entity key, event and context from the request, fires the event through
+//! `StateMachine::fire_event_inferred` so the state comes from and goes back
+//! to a `StateRepository`, and answers with the new state as JSON. Errors
+//! answer with the `ErasedTransitionError` as JSON and a status picked by
+//! `TransitionError::code`:
+//!
+//! | Code | Status |
+//! |------|--------|
+//! | `entity_not_found` | 404 |
+//! | `no_valid_transition`, `feature_disabled`, `stale_state`, `out_of_order`, `terminal_state`, `event_not_allowed_in_state` | 409 |
+//! | `machine_archived` | 410 |
+//! | `condition_failed`, `state_requirement_failed`, `deadline_expired` | 422 |
+//! | `approval_required`, `approval_rejected` | 403 |
+//! | anything else | 500 |
+//!
+//! ```ignore
+//! let endpoint = EventEndpoint::new(machine, repository)
+//!     .entity_key(|parts| order_id(parts))
+//!     .event(|parts| order_event(parts))
+//!     .context(|parts| Ok(OrderContext::from_headers(&parts.headers)))
+//!     .status_for("condition_failed", StatusCode::BAD_REQUEST);
+//! let app = Router::new().route("/orders/{id}/{event}", endpoint.route());
+//! ```
+//!
+//! Transitions run synchronously on the request task, so long actions hold
+//! up the executor thread like any other blocking call.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use axum::extract::Request;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{post, MethodRouter};
+use axum::Json;
+use serde::Serialize;
+
+use crate::{Context, Event, State, StateMachine, StateRepository};
+
+/// Reads one input of the transition from the request
+///
+/// Returning `Err` answers the request with that response without firing.
+pub type PartsParser<T> = Arc<dyn Fn(&Parts) -> Result<T, Response> + Send + Sync>;
+
+/// Handler firing the event a request names for the entity it names
+pub struct EventEndpoint<K, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    machine: Arc<StateMachine<S, E, C>>,
+    repository: Arc<dyn StateRepository<K, S>>,
+    entity_key: Option<PartsParser<K>>,
+    event: Option<PartsParser<E>>,
+    context: Option<PartsParser<C>>,
+    statuses: HashMap<&'static str, StatusCode>,
+}
+
+impl<K, S, E, C> EventEndpoint<K, S, E, C>
+where
+    K: Debug + Send + Sync + 'static,
+    S: State + Serialize + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    C: Context + Send + Sync + 'static,
+{
+    pub fn new(
+        machine: Arc<StateMachine<S, E, C>>,
+        repository: Arc<dyn StateRepository<K, S>>,
+    ) -> Self {
+        let statuses = [
+            ("entity_not_found", StatusCode::NOT_FOUND),
+            ("no_valid_transition", StatusCode::CONFLICT),
+            ("feature_disabled", StatusCode::CONFLICT),
+            ("stale_state", StatusCode::CONFLICT),
+            ("version_conflict", StatusCode::CONFLICT),
+            ("out_of_order", StatusCode::CONFLICT),
+            ("terminal_state", StatusCode::CONFLICT),
+            ("event_not_allowed_in_state", StatusCode::CONFLICT),
+            ("machine_archived", StatusCode::GONE),
+            ("condition_failed", StatusCode::UNPROCESSABLE_ENTITY),
+            ("state_requirement_failed", StatusCode::UNPROCESSABLE_ENTITY),
+            ("deadline_expired", StatusCode::UNPROCESSABLE_ENTITY),
+            ("approval_required", StatusCode::FORBIDDEN),
+            ("approval_rejected", StatusCode::FORBIDDEN),
+        ];
+        EventEndpoint {
+            machine,
+            repository,
+            entity_key: None,
+            event: None,
+            context: None,
+            statuses: statuses.into_iter().collect(),
+        }
+    }
+
+    /// Read the key of the entity to fire for
+    pub fn entity_key<F>(mut self, parser: F) -> Self
+    where
+        F: Fn(&Parts) -> Result<K, Response> + Send + Sync + 'static,
+    {
+        self.entity_key = Some(Arc::new(parser));
+        self
+    }
+
+    /// Read the event to fire
+    pub fn event<F>(mut self, parser: F) -> Self
+    where
+        F: Fn(&Parts) -> Result<E, Response> + Send + Sync + 'static,
+    {
+        self.event = Some(Arc::new(parser));
+        self
+    }
+
+    /// Build the context passed to the transition
+    pub fn context<F>(mut self, builder: F) -> Self
+    where
+        F: Fn(&Parts) -> Result<C, Response> + Send + Sync + 'static,
+    {
+        self.context = Some(Arc::new(builder));
+        self
+    }
+
+    /// Answer errors with `code` (see `TransitionError::code`) with `status`
+    pub fn status_for(mut self, code: &'static str, status: StatusCode) -> Self {
+        self.statuses.insert(code, status);
+        self
+    }
+
+    /// Answer one request
+    ///
+    /// Only the request head is read; the body is ignored.
+    pub fn respond(&self, parts: &Parts) -> Response {
+        let (key, event, context) = match (
+            parse(&self.entity_key, "entity key", parts),
+            parse(&self.event, "event", parts),
+            parse(&self.context, "context", parts),
+        ) {
+            (Ok(key), Ok(event), Ok(context)) => (key, event, context),
+            (Err(response), _, _) | (_, Err(response), _) | (_, _, Err(response)) => {
+                return response
+            }
+        };
+
+        match self
+            .machine
+            .fire_event_inferred(self.repository.as_ref(), &key, event, context, None)
+        {
+            Ok(to) => Json(to).into_response(),
+            Err(error) => {
+                let status = self
+                    .statuses
+                    .get(error.code())
+                    .copied()
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                (status, Json(error.into_legacy())).into_response()
+            }
+        }
+    }
+
+    /// A `POST` route answering with `respond`
+    ///
+    /// # Panics
+    ///
+    /// If the entity key, event or context parser was not set.
+    pub fn route<T>(self) -> MethodRouter<T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        assert!(
+            self.entity_key.is_some() && self.event.is_some() && self.context.is_some(),
+            "EventEndpoint needs entity key, event and context parsers"
+        );
+        let endpoint = Arc::new(self);
+        post(move |request: Request| async move {
+            let (parts, _body) = request.into_parts();
+            endpoint.respond(&parts)
+        })
+    }
+}
+
+// `Response` is what parsers answer with, however large
+#[allow(clippy::result_large_err)]
+fn parse<T>(parser: &Option<PartsParser<T>>, what: &str, parts: &Parts) -> Result<T, Response> {
+    let parser = parser
+        .as_ref()
+        .unwrap_or_else(|| panic!("EventEndpoint has no {} parser", what));
+    parser(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryStateRepository, StateMachineBuilderFactory, NEW_ENTITY};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize)]
+    enum Door {
+        Open,
+        Closed,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Open,
+        Close,
+    }
+
+    impl Event for DoorEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Caller {
+        admin: bool,
+    }
+
+    impl Context for Caller {}
+
+    // Requests look like `POST /doors/{id}/{event}`
+    #[allow(clippy::result_large_err)]
+    fn endpoint() -> EventEndpoint<String, Door, DoorEvent, Caller> {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, Caller>();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Open)
+            .when(|_s, _e, c| c.admin)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Door::Open)
+            .to(Door::Closed)
+            .on(DoorEvent::Close)
+            .perform(|_s, _e, _c| {});
+        let repository = InMemoryStateRepository::new();
+        repository
+            .save(&"front".to_string(), &Door::Closed, NEW_ENTITY)
+            .unwrap();
+
+        EventEndpoint::new(Arc::new(builder.build()), Arc::new(repository))
+            .entity_key(|parts| {
+                let id = parts.uri.path().split('/').nth(2).unwrap_or_default();
+                Ok(id.to_string())
+            })
+            .event(|parts| match parts.uri.path().split('/').nth(3) {
+                Some("open") => Ok(DoorEvent::Open),
+                Some("close") => Ok(DoorEvent::Close),
+                _ => Err((StatusCode::BAD_REQUEST, "unknown event").into_response()),
+            })
+            .context(|parts| {
+                Ok(Caller {
+                    admin: parts.headers.contains_key("x-admin"),
+                })
+            })
+    }
+
+    fn request(uri: &str, admin: bool) -> Parts {
+        let mut request = axum::http::Request::post(uri);
+        if admin {
+            request = request.header("x-admin", "1");
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_success_returns_new_state() {
+        let endpoint = endpoint();
+        let response = endpoint.respond(&request("/doors/front/open", true));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, serde_json::json!("Open"));
+        assert_eq!(
+            endpoint.repository.load(&"front".to_string()),
+            Some(Door::Open)
+        );
+    }
+
+    #[test]
+    fn test_route_mounts_on_router() {
+        let _app: axum::Router =
+            axum::Router::new().route("/doors/{id}/{event}", endpoint().route());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_event_rejected_by_parser() {
+        let response = endpoint().respond(&request("/doors/front/slam", true));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_errors_map_to_statuses() {
+        let endpoint = endpoint();
+        let response = endpoint.respond(&request("/doors/front/close", true));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            body_json(response).await["code"],
+            serde_json::json!("no_valid_transition")
+        );
+
+        let response = endpoint.respond(&request("/doors/front/open", false));
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = endpoint.respond(&request("/doors/back/open", true));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let endpoint = endpoint.status_for("condition_failed", StatusCode::FORBIDDEN);
+        let response = endpoint.respond(&request("/doors/front/open", false));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,309 @@
+//! Stable error type for application error enums, logs and storage
+//!
+//! `TransitionError` is generic over the machine's state and event types and
+//! grows variants as features are added. `ErasedTransitionError` keeps the
+//! same shape regardless: a stable code, the message and the names involved,
+//! with the error of a failed action available as `source()`.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::{Context, Event, State, StateMachine, TransitionError};
+
+// Every value `TransitionError::code` returns, whatever the features
+#[cfg(all(feature = "history", feature = "serde"))]
+pub(crate) const ERROR_CODES: &[&str] = &[
+    "no_valid_transition",
+    "condition_failed",
+    "feature_disabled",
+    "ambiguous_transition",
+    "stale_state",
+    "entity_not_found",
+    "version_conflict",
+    "context_load_failed",
+    "context_save_failed",
+    "out_of_order",
+    "deadline_expired",
+    "machine_archived",
+    "approval_required",
+    "approval_rejected",
+    "max_chain_depth_exceeded",
+    "terminal_state",
+    "event_not_allowed_in_state",
+    "transition_budget_exhausted",
+    "loop_detected",
+    "completion_loop",
+    "action_failed",
+    "replay_diverged",
+    "idempotency_key_reused",
+    "idempotency_key_in_use",
+    "intent_log_failed",
+    "state_requirement_failed",
+    "timeout",
+    "async_error",
+    "cancelled",
+];
+
+impl<S, E> TransitionError<S, E> {
+    /// Stable identifier of the variant, e.g. `"no_valid_transition"`
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransitionError::NoValidTransition { .. } => "no_valid_transition",
+            TransitionError::ConditionFailed { .. } => "condition_failed",
+            TransitionError::FeatureDisabled { .. } => "feature_disabled",
+            TransitionError::AmbiguousTransition { .. } => "ambiguous_transition",
+            TransitionError::StaleState { .. } => "stale_state",
+            TransitionError::EntityNotFound { .. } => "entity_not_found",
+            TransitionError::VersionConflict { .. } => "version_conflict",
+            TransitionError::ContextLoadFailed { .. } => "context_load_failed",
+            TransitionError::ContextSaveFailed { .. } => "context_save_failed",
+            TransitionError::OutOfOrder { .. } => "out_of_order",
+            TransitionError::DeadlineExpired { .. } => "deadline_expired",
+            TransitionError::MachineArchived { .. } => "machine_archived",
+            TransitionError::ApprovalRequired { .. } => "approval_required",
+            TransitionError::ApprovalRejected { .. } => "approval_rejected",
+            TransitionError::MaxChainDepthExceeded { .. } => "max_chain_depth_exceeded",
+            TransitionError::TerminalState { .. } => "terminal_state",
+            TransitionError::EventNotAllowedInState { .. } => "event_not_allowed_in_state",
+            TransitionError::TransitionBudgetExhausted { .. } => "transition_budget_exhausted",
+            TransitionError::LoopDetected { .. } => "loop_detected",
+            TransitionError::CompletionLoop { .. } => "completion_loop",
+            TransitionError::ActionFailed { .. } => "action_failed",
+            TransitionError::ReplayDiverged { .. } => "replay_diverged",
+            TransitionError::IdempotencyKeyReused { .. } => "idempotency_key_reused",
+            TransitionError::IdempotencyKeyInUse { .. } => "idempotency_key_in_use",
+            TransitionError::IntentLogFailed { .. } => "intent_log_failed",
+            #[cfg(feature = "extended")]
+            TransitionError::StateRequirementFailed { .. } => "state_requirement_failed",
+            #[cfg(any(feature = "timeout", feature = "async"))]
+            TransitionError::Timeout { .. } => "timeout",
+            #[cfg(feature = "async")]
+            TransitionError::AsyncError(_) => "async_error",
+            #[cfg(feature = "async")]
+            TransitionError::Cancelled { .. } => "cancelled",
+        }
+    }
+
+    /// String-only form of this error, for logging and storage
+    pub fn into_legacy(self) -> ErasedTransitionError
+    where
+        S: fmt::Debug,
+        E: fmt::Debug,
+    {
+        ErasedTransitionError::from(self)
+    }
+}
+
+/// Non-generic transition error meant to be embedded in application errors
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ErasedTransitionError {
+    /// Stable code, see `TransitionError::code`
+    pub code: &'static str,
+    pub message: String,
+    pub from_name: Option<String>,
+    pub event_name: Option<String>,
+    pub machine_id: Option<String>,
+    // Boxed so the fat pointer doesn't push the error past `result_large_err`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    source: Option<Box<Arc<dyn Error + Send + Sync>>>,
+}
+
+impl ErasedTransitionError {
+    /// Fill in the machine and names the error itself doesn't carry
+    pub fn with_origin<S: fmt::Debug, E: fmt::Debug>(
+        mut self,
+        machine_id: &str,
+        from: &S,
+        event: &E,
+    ) -> Self {
+        self.machine_id = Some(machine_id.to_string());
+        self.from_name.get_or_insert_with(|| format!("{:?}", from));
+        self.event_name
+            .get_or_insert_with(|| format!("{:?}", event));
+        self
+    }
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> From<TransitionError<S, E>> for ErasedTransitionError {
+    fn from(error: TransitionError<S, E>) -> Self {
+        let (from_name, event_name) = match &error {
+            TransitionError::NoValidTransition { from, event, .. }
+            | TransitionError::ConditionFailed { from, event, .. }
+            | TransitionError::FeatureDisabled { from, event, .. }
+            | TransitionError::AmbiguousTransition { from, event, .. }
+            | TransitionError::ApprovalRequired { from, event }
+            | TransitionError::LoopDetected { from, event, .. }
+            | TransitionError::EventNotAllowedInState { state: from, event } => {
+                (Some(format!("{:?}", from)), Some(format!("{:?}", event)))
+            }
+            _ => (None, None),
+        };
+        let source = match &error {
+            TransitionError::ActionFailed { source } => Some(Box::new(source.clone())),
+            _ => None,
+        };
+        ErasedTransitionError {
+            code: error.code(),
+            message: error.to_string(),
+            from_name,
+            event_name,
+            machine_id: None,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for ErasedTransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.machine_id {
+            Some(id) => write!(f, "[{}] {}", id, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for ErasedTransitionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|error| &**error as &(dyn Error + 'static))
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// `fire_event` returning the erased error, with the machine id and the
+    /// names of `from` and `event` filled in
+    pub fn fire_event_erased_err(
+        &self,
+        from: S,
+        event: E,
+        context: C,
+    ) -> Result<S, ErasedTransitionError> {
+        let origin = (from.clone(), event.clone());
+        self.fire_event(from, event, context).map_err(|error| {
+            ErasedTransitionError::from(error).with_origin(&self.id, &origin.0, &origin.1)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Door {
+        Open,
+        Closed,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Close,
+        Lock,
+    }
+
+    impl Event for DoorEvent {}
+
+    fn door_machine() -> StateMachine<Door, DoorEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Door::Open)
+            .to(Door::Closed)
+            .on(DoorEvent::Close)
+            .perform(|_s, _e, _c| {});
+        builder.id("door").build()
+    }
+
+    // Application error embedding the erased error, as `#[from]` would
+    #[derive(Debug)]
+    enum AppError {
+        Transition(ErasedTransitionError),
+    }
+
+    impl From<ErasedTransitionError> for AppError {
+        fn from(error: ErasedTransitionError) -> Self {
+            AppError::Transition(error)
+        }
+    }
+
+    fn lock(machine: &StateMachine<Door, DoorEvent, NoContext>) -> Result<Door, AppError> {
+        Ok(machine.fire_event_erased_err(Door::Open, DoorEvent::Lock, NoContext)?)
+    }
+
+    #[test]
+    fn test_conversion_keeps_code_and_names() {
+        let error = TransitionError::NoValidTransition {
+            from: Door::Open,
+            event: DoorEvent::Lock,
+            accepted: vec![DoorEvent::Close],
+        }
+        .into_legacy();
+        assert_eq!(error.code, "no_valid_transition");
+        assert_eq!(error.from_name.as_deref(), Some("Open"));
+        assert_eq!(error.event_name.as_deref(), Some("Lock"));
+        assert!(error.machine_id.is_none());
+        assert!(error.source().is_none());
+
+        let error = ErasedTransitionError::from(TransitionError::<Door, DoorEvent>::ActionFailed {
+            source: Arc::new(std::io::Error::other("disk full")),
+        });
+        assert_eq!(error.code, "action_failed");
+        assert_eq!(error.source().unwrap().to_string(), "disk full");
+
+        let error =
+            ErasedTransitionError::from(TransitionError::<Door, DoorEvent>::DeadlineExpired {
+                deadline: std::time::UNIX_EPOCH,
+            })
+            .with_origin("door", &Door::Closed, &DoorEvent::Lock);
+        assert_eq!(error.code, "deadline_expired");
+        assert_eq!(error.from_name.as_deref(), Some("Closed"));
+        assert!(error.to_string().starts_with("[door] Deadline"));
+    }
+
+    #[test]
+    fn test_embedding_in_application_error() {
+        let machine = door_machine();
+        match lock(&machine) {
+            Err(AppError::Transition(error)) => {
+                assert_eq!(error.code, "no_valid_transition");
+                assert_eq!(error.machine_id.as_deref(), Some("door"));
+            }
+            other => panic!("expected a transition error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialized_shape() {
+        let machine = door_machine();
+        let error = machine
+            .fire_event_erased_err(Door::Open, DoorEvent::Lock, NoContext)
+            .unwrap_err();
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "no_valid_transition",
+                "message": error.message,
+                "from_name": "Open",
+                "event_name": "Lock",
+                "machine_id": "door",
+            })
+        );
+    }
+}
@@ -0,0 +1,420 @@
+//! Completion transitions, taken without an event
+//!
+//! A completion transition leaves its source state as soon as its guard
+//! holds. After an external transition succeeds, the machine looks for
+//! completion transitions out of the state it entered, takes the first whose
+//! guard passes (highest priority first under the `guards` feature) and
+//! repeats from there until none applies. The fire returns that stable
+//! state.
+//!
+//! Guards see the state being left, the event that started the chain and
+//! the context as the event's transition left it. Under the `extended`
+//! feature, a completion transition into a state whose requirements the
+//! context doesn't meet doesn't apply either.
+//!
+//! The whole chain is resolved before any of its hops runs. Completion
+//! transitions have no action of their own; exit and entry actions then run
+//! for every hop. A chain revisiting a state fails with
+//! `TransitionError::CompletionLoop`, a chain of more hops than the chain
+//! depth (see `with_max_chain_depth`) with `MaxChainDepthExceeded`. Either
+//! way no hop runs, while the event's own transition stays applied, like
+//! after a failing entry action.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::trace::{self, ExecutionTrace, TraceStep};
+use crate::{Condition, Context, Event, State, StateMachine, StateMachineBuilder, TransitionError};
+
+#[derive(Clone)]
+pub(crate) struct CompletionTransition<S, E, C> {
+    pub(crate) from: S,
+    pub(crate) to: S,
+    pub(crate) condition: Option<Condition<S, E, C>>,
+    #[cfg(feature = "guards")]
+    pub(crate) priority: u32,
+}
+
+// Completion transitions by source state, in evaluation order
+pub(crate) type CompletionMap<S, E, C> = HashMap<S, Box<[CompletionTransition<S, E, C>]>>;
+
+pub(crate) fn group_completions<S, E, C>(
+    transitions: Vec<CompletionTransition<S, E, C>>,
+) -> CompletionMap<S, E, C>
+where
+    S: State,
+{
+    let mut grouped: HashMap<S, Vec<_>> = HashMap::new();
+    for transition in transitions {
+        grouped
+            .entry(transition.from.clone())
+            .or_default()
+            .push(transition);
+    }
+    grouped
+        .into_iter()
+        .map(|(from, candidates)| (from, order_completions(candidates)))
+        .collect()
+}
+
+#[allow(unused_mut)]
+fn order_completions<S, E, C>(
+    mut candidates: Vec<CompletionTransition<S, E, C>>,
+) -> Box<[CompletionTransition<S, E, C>]> {
+    #[cfg(feature = "guards")]
+    {
+        // Higher priority first, registration order among equals
+        if candidates.len() > 1 {
+            candidates.sort_by_key(|t| std::cmp::Reverse(t.priority));
+        }
+    }
+    candidates.into_boxed_slice()
+}
+
+/// Builder for completion transitions
+pub struct CompletionTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    builder: &'a mut StateMachineBuilder<S, E, C>,
+    from: Option<S>,
+    to: Option<S>,
+    #[cfg(feature = "guards")]
+    priority: u32,
+}
+
+impl<'a, S, E, C> CompletionTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn from(mut self, state: S) -> Self {
+        self.from = Some(state);
+        self
+    }
+
+    pub fn to(mut self, state: S) -> Self {
+        self.to = Some(state);
+        self
+    }
+
+    #[cfg(feature = "guards")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Take the transition once `condition` holds after entering `from`
+    pub fn when<F>(self, condition: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
+    {
+        self.register(Some(Arc::new(condition)))
+    }
+
+    /// Take the transition whenever `from` is entered
+    pub fn always(self) -> &'a mut StateMachineBuilder<S, E, C> {
+        self.register(None)
+    }
+
+    fn register(
+        self,
+        condition: Option<Condition<S, E, C>>,
+    ) -> &'a mut StateMachineBuilder<S, E, C> {
+        let transition = CompletionTransition {
+            from: self.from.expect("from state is required"),
+            to: self.to.expect("to state is required"),
+            condition,
+            #[cfg(feature = "guards")]
+            priority: self.priority,
+        };
+        self.builder.completion_transitions.push(transition);
+        self.builder
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Start building a completion transition
+    pub fn completion_transition(&mut self) -> CompletionTransitionBuilder<'_, S, E, C> {
+        CompletionTransitionBuilder {
+            builder: self,
+            from: None,
+            to: None,
+            #[cfg(feature = "guards")]
+            priority: 0,
+        }
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // Follow completion transitions from the entered `state` until none
+    // applies
+    pub(crate) fn settle(
+        &self,
+        state: S,
+        event: &E,
+        context: &mut C,
+        trace: &mut Option<&mut ExecutionTrace>,
+    ) -> Result<S, TransitionError<S, E>> {
+        let mut path = self.completion_path(state, event, context, trace)?;
+        for hop in path.windows(2) {
+            let (from, to) = (&hop[0], &hop[1]);
+            trace::record(trace, || TraceStep::CompletionTransition {
+                from: self.names.state(from),
+                to: self.names.state(to),
+            });
+            #[cfg(feature = "extended")]
+            {
+                self.run_exit_action(from, context, trace);
+                self.run_entry_action(to, context, trace)
+                    .map_err(|source| TransitionError::ActionFailed {
+                        source: source.into(),
+                    })?;
+            }
+        }
+        Ok(path.pop().unwrap())
+    }
+
+    // The states the completion transitions lead through from `state`,
+    // resolved before any of their hops runs
+    fn completion_path(
+        &self,
+        state: S,
+        event: &E,
+        context: &C,
+        trace: &mut Option<&mut ExecutionTrace>,
+    ) -> Result<Vec<S>, TransitionError<S, E>> {
+        let mut path = vec![state];
+        loop {
+            let current = &path[path.len() - 1];
+            let Some(next) = self
+                .completion_transitions
+                .get(current)
+                .and_then(|candidates| {
+                    candidates.iter().find(|transition| {
+                        transition
+                            .condition
+                            .as_ref()
+                            .is_none_or(|condition| condition(current, event, context))
+                            && self.meets_requirements(&transition.to, context, trace)
+                    })
+                })
+            else {
+                return Ok(path);
+            };
+            if path.contains(&next.to) {
+                path.push(next.to.clone());
+                return Err(TransitionError::CompletionLoop { path });
+            }
+            if path.len() > self.max_chain_depth {
+                return Err(TransitionError::MaxChainDepthExceeded {
+                    depth: self.max_chain_depth,
+                });
+            }
+            path.push(next.to.clone());
+        }
+    }
+
+    // Whether `context` meets the requirements of `state`, tracing the first
+    // one it doesn't
+    #[cfg_attr(not(feature = "extended"), allow(unused_variables))]
+    fn meets_requirements(
+        &self,
+        state: &S,
+        context: &C,
+        trace: &mut Option<&mut ExecutionTrace>,
+    ) -> bool {
+        #[cfg(feature = "extended")]
+        if let Some(requirement) = self.failed_requirement(state, context) {
+            trace::record(trace, || TraceStep::RequirementFailed {
+                state: self.names.state(state),
+                requirement: requirement.to_string(),
+            });
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Application {
+        Submitted,
+        Validating,
+        Scoring,
+        Approved,
+        Rejected,
+    }
+
+    impl State for Application {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum ApplicationEvent {
+        Validate,
+    }
+
+    impl Event for ApplicationEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Applicant {
+        score: u32,
+    }
+
+    impl Context for Applicant {}
+
+    fn application_machine() -> StateMachineBuilder<Application, ApplicationEvent, Applicant> {
+        let mut builder =
+            StateMachineBuilderFactory::create::<Application, ApplicationEvent, Applicant>();
+        builder
+            .external_transition()
+            .from(Application::Submitted)
+            .to(Application::Validating)
+            .on(ApplicationEvent::Validate)
+            .perform(|_s, _e, _c| {});
+        builder
+            .completion_transition()
+            .from(Application::Validating)
+            .to(Application::Scoring)
+            .always();
+        builder
+            .completion_transition()
+            .from(Application::Scoring)
+            .to(Application::Approved)
+            .when(|_s, _e, c| c.score > 700);
+        builder
+            .completion_transition()
+            .from(Application::Scoring)
+            .to(Application::Rejected)
+            .when(|_s, _e, c| c.score < 300);
+        builder
+    }
+
+    #[test]
+    fn test_chases_completion_transitions() {
+        let machine = application_machine().build();
+        let fire = |score| {
+            machine.fire_event(
+                Application::Submitted,
+                ApplicationEvent::Validate,
+                Applicant { score },
+            )
+        };
+        assert_eq!(fire(750).unwrap(), Application::Approved);
+        assert_eq!(fire(200).unwrap(), Application::Rejected);
+        // Neither guard holds: the chain stops in Scoring
+        assert_eq!(fire(500).unwrap(), Application::Scoring);
+
+        #[cfg(feature = "history")]
+        assert_eq!(machine.get_history()[0].to, Application::Approved);
+    }
+
+    #[test]
+    fn test_completion_loop_detected() {
+        let mut builder = application_machine();
+        builder
+            .completion_transition()
+            .from(Application::Rejected)
+            .to(Application::Validating)
+            .always();
+        let machine = builder.build();
+        let result = machine.fire_event(
+            Application::Submitted,
+            ApplicationEvent::Validate,
+            Applicant { score: 100 },
+        );
+        match result {
+            Err(TransitionError::CompletionLoop { path }) => assert_eq!(
+                path,
+                vec![
+                    Application::Validating,
+                    Application::Scoring,
+                    Application::Rejected,
+                    Application::Validating
+                ]
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "extended")]
+    #[test]
+    fn test_loop_runs_no_hop() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let entered = Arc::new(AtomicUsize::new(0));
+        let mut builder = application_machine();
+        builder
+            .completion_transition()
+            .from(Application::Rejected)
+            .to(Application::Validating)
+            .always();
+        let counter = entered.clone();
+        builder.with_entry_action(Application::Scoring, move |_s, _c| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let machine = builder.build();
+
+        let result = machine.fire_event(
+            Application::Submitted,
+            ApplicationEvent::Validate,
+            Applicant { score: 100 },
+        );
+        assert!(matches!(
+            result,
+            Err(TransitionError::CompletionLoop { .. })
+        ));
+        assert_eq!(entered.load(Ordering::SeqCst), 0);
+    }
+
+    #[cfg(feature = "extended")]
+    #[test]
+    fn test_completion_checks_requirements() {
+        let mut builder = application_machine();
+        builder.state_requires(Application::Approved, "verified score", |c| c.score < 900);
+        let machine = builder.build();
+        let fire = |score| {
+            machine.fire_event(
+                Application::Submitted,
+                ApplicationEvent::Validate,
+                Applicant { score },
+            )
+        };
+        assert_eq!(fire(750).unwrap(), Application::Approved);
+        // Approved can't be entered: the chain stops in Scoring
+        assert_eq!(fire(950).unwrap(), Application::Scoring);
+    }
+
+    #[test]
+    fn test_chain_depth_bounds_completion() {
+        let mut builder = application_machine();
+        builder.with_max_chain_depth(1);
+        let machine = builder.build();
+        let result = machine.fire_event(
+            Application::Submitted,
+            ApplicationEvent::Validate,
+            Applicant { score: 750 },
+        );
+        assert!(matches!(
+            result,
+            Err(TransitionError::MaxChainDepthExceeded { depth: 1 })
+        ));
+    }
+}
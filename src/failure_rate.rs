@@ -0,0 +1,355 @@
+//! Moving average of the failure rate (requires the `metrics` feature)
+//!
+//! Every fire updates `StateMachineMetrics::failure_rate`, an exponentially
+//! weighted moving average of the failed fires per minute, read with the
+//! machine's clock. A burst of failures raises it at once; without failures
+//! it halves every half-life, set with
+//! `StateMachineBuilder::with_failure_rate_half_life` (5 minutes by default).
+//! A second average with a four times longer half-life serves as baseline
+//! for `StateMachineMetrics::failure_rate_trend`.
+//!
+//! `StateMachineBuilder::on_failure_rate_above` registers a callback run by
+//! the fire that finds the short-term average above a threshold, at most
+//! once per cooldown.
+
+use std::f64::consts::LN_2;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder, StateMachineMetrics};
+
+/// Callback given the failures per minute that exceeded the threshold
+pub type FailureRateCallback = Arc<dyn Fn(f64) + Send + Sync>;
+
+const DEFAULT_HALF_LIFE: Duration = Duration::from_secs(5 * 60);
+
+// Half-life of the baseline average, relative to the short-term one
+const BASELINE_FACTOR: f64 = 4.0;
+
+// Relative gap between the two averages below which the rate is steady
+const TREND_MARGIN: f64 = 0.2;
+
+/// Direction of the failure rate, see `StateMachineMetrics::failure_rate_trend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureRateTrend {
+    Rising,
+    Falling,
+    Steady,
+}
+
+/// Failed fires per minute, averaged over two horizons
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailureRate {
+    short_term: f64,
+    baseline: f64,
+    half_life: Duration,
+    updated: Option<Instant>,
+}
+
+impl Default for FailureRate {
+    fn default() -> Self {
+        FailureRate {
+            short_term: 0.0,
+            baseline: 0.0,
+            half_life: DEFAULT_HALF_LIFE,
+            updated: None,
+        }
+    }
+}
+
+impl FailureRate {
+    /// Average over the configured half-life
+    pub fn short_term(&self) -> f64 {
+        self.short_term
+    }
+
+    /// Average over four times the configured half-life
+    pub fn baseline(&self) -> f64 {
+        self.baseline
+    }
+
+    /// Decay both averages to `now`
+    pub(crate) fn decay(&mut self, now: Instant, half_life: Duration) {
+        self.half_life = half_life;
+        if let Some(updated) = self.updated {
+            let half_lives =
+                now.saturating_duration_since(updated).as_secs_f64() / half_life.as_secs_f64();
+            self.short_term *= 0.5f64.powf(half_lives);
+            self.baseline *= 0.5f64.powf(half_lives / BASELINE_FACTOR);
+        }
+        self.updated = Some(self.updated.map_or(now, |updated| updated.max(now)));
+    }
+
+    /// Account for a fire completed at `now`
+    pub(crate) fn record(&mut self, now: Instant, failed: bool, half_life: Duration) {
+        self.decay(now, half_life);
+        if failed {
+            // A steady `r` failures per minute converges to `r`
+            let per_minute = 60.0 / half_life.as_secs_f64();
+            self.short_term += LN_2 * per_minute;
+            self.baseline += LN_2 * per_minute / BASELINE_FACTOR;
+        }
+    }
+
+    /// Add the failures accounted for by `other`
+    pub(crate) fn merge(&mut self, other: &FailureRate) {
+        let Some(updated) = other.updated else {
+            return;
+        };
+        let now = self.updated.map_or(updated, |own| own.max(updated));
+        let mut other = other.clone();
+        other.decay(now, other.half_life);
+        self.decay(now, self.half_life);
+        self.short_term += other.short_term;
+        self.baseline += other.baseline;
+    }
+
+    fn trend(&self) -> FailureRateTrend {
+        if self.short_term > self.baseline * (1.0 + TREND_MARGIN) {
+            FailureRateTrend::Rising
+        } else if self.short_term < self.baseline * (1.0 - TREND_MARGIN) {
+            FailureRateTrend::Falling
+        } else {
+            FailureRateTrend::Steady
+        }
+    }
+}
+
+impl<S, E> StateMachineMetrics<S, E> {
+    /// Short-term moving average of the failed fires per minute
+    pub fn failure_rate_ewma(&self) -> f64 {
+        self.failure_rate.short_term()
+    }
+
+    /// Whether the short-term failure rate is above or below its baseline
+    /// by more than a fifth
+    pub fn failure_rate_trend(&self) -> FailureRateTrend {
+        self.failure_rate.trend()
+    }
+}
+
+pub(crate) struct FailureRateAlert {
+    threshold: f64,
+    cooldown: Duration,
+    callback: FailureRateCallback,
+    last: Mutex<Option<Instant>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct FailureRateSettings {
+    pub(crate) half_life: Duration,
+    alert: Option<Arc<FailureRateAlert>>,
+}
+
+impl Default for FailureRateSettings {
+    fn default() -> Self {
+        FailureRateSettings {
+            half_life: DEFAULT_HALF_LIFE,
+            alert: None,
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Half-life of the short-term failure rate average
+    ///
+    /// # Panics
+    ///
+    /// If `half_life` is zero.
+    pub fn with_failure_rate_half_life(&mut self, half_life: Duration) -> &mut Self {
+        assert!(
+            !half_life.is_zero(),
+            "failure rate half-life must not be zero"
+        );
+        self.failure_rate.half_life = half_life;
+        self
+    }
+
+    /// Run `callback` when a fire finds the short-term failure rate above
+    /// `threshold` failures per minute, at most once per `cooldown`
+    pub fn on_failure_rate_above<F>(
+        &mut self,
+        threshold: f64,
+        cooldown: Duration,
+        callback: F,
+    ) -> &mut Self
+    where
+        F: Fn(f64) + Send + Sync + 'static,
+    {
+        self.failure_rate.alert = Some(Arc::new(FailureRateAlert {
+            threshold,
+            cooldown,
+            callback: Arc::new(callback),
+            last: Mutex::new(None),
+        }));
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Run the failure rate callback if the rate is above its threshold
+    pub(crate) fn check_failure_rate(&self, now: Instant) {
+        let Some(alert) = &self.failure_rate.alert else {
+            return;
+        };
+        let rate = self.recording.with_metrics(|metrics| {
+            let mut failure_rate = metrics.failure_rate.clone();
+            failure_rate.decay(now, self.failure_rate.half_life);
+            failure_rate.short_term()
+        });
+        if rate <= alert.threshold {
+            return;
+        }
+        {
+            let mut last = alert.last.lock().unwrap();
+            if last.is_some_and(|last| now.saturating_duration_since(last) < alert.cooldown) {
+                return;
+            }
+            *last = Some(now);
+        }
+        (alert.callback)(rate);
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::{MockClock, StateMachineBuilderFactory};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Job {
+        Queued,
+        Done,
+    }
+
+    impl State for Job {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum JobEvent {
+        Run,
+    }
+
+    impl Event for JobEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Attempt {
+        ok: bool,
+    }
+
+    impl Context for Attempt {}
+
+    fn job_machine(
+        clock: &Arc<MockClock>,
+        configure: impl FnOnce(&mut StateMachineBuilder<Job, JobEvent, Attempt>),
+    ) -> StateMachine<Job, JobEvent, Attempt> {
+        let mut builder = StateMachineBuilderFactory::create::<Job, JobEvent, Attempt>();
+        builder
+            .external_transition()
+            .from(Job::Queued)
+            .to(Job::Done)
+            .on(JobEvent::Run)
+            .when(|_s, _e, attempt| attempt.ok)
+            .perform(|_s, _e, _c| {});
+        builder
+            .with_clock(clock.clone())
+            .with_failure_rate_half_life(Duration::from_secs(60));
+        configure(&mut builder);
+        builder.build()
+    }
+
+    fn fail(machine: &StateMachine<Job, JobEvent, Attempt>, times: usize) {
+        for _ in 0..times {
+            let _ = machine.fire_event(Job::Queued, JobEvent::Run, Attempt { ok: false });
+        }
+    }
+
+    #[test]
+    fn test_rate_decays_with_time() {
+        let clock = Arc::new(MockClock::new());
+        let machine = job_machine(&clock, |_| {});
+        fail(&machine, 10);
+        let burst = machine.get_metrics().failure_rate_ewma();
+        assert!((burst - 10.0 * LN_2).abs() < 1e-9);
+
+        clock.advance(Duration::from_secs(60));
+        let halved = machine.get_metrics().failure_rate_ewma();
+        assert!((halved - burst / 2.0).abs() < 1e-9);
+        clock.advance(Duration::from_secs(120));
+        assert!((machine.get_metrics().failure_rate_ewma() - burst / 8.0).abs() < 1e-9);
+
+        // Successes don't add to the rate
+        machine
+            .fire_event(Job::Queued, JobEvent::Run, Attempt { ok: true })
+            .unwrap();
+        assert!((machine.get_metrics().failure_rate_ewma() - burst / 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_threshold_callback_respects_cooldown() {
+        let clock = Arc::new(MockClock::new());
+        let alerts = Arc::new(AtomicUsize::new(0));
+        let counter = alerts.clone();
+        let machine = job_machine(&clock, |builder| {
+            builder.on_failure_rate_above(5.0, Duration::from_secs(300), move |rate| {
+                assert!(rate > 5.0);
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+
+        fail(&machine, 7);
+        assert_eq!(alerts.load(Ordering::SeqCst), 0);
+        fail(&machine, 5);
+        assert_eq!(alerts.load(Ordering::SeqCst), 1);
+
+        // Still above the threshold, but within the cooldown
+        clock.advance(Duration::from_secs(120));
+        fail(&machine, 20);
+        assert_eq!(alerts.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(200));
+        fail(&machine, 10);
+        assert_eq!(alerts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_trend_follows_burst() {
+        let clock = Arc::new(MockClock::new());
+        let machine = job_machine(&clock, |_| {});
+        assert_eq!(
+            machine.get_metrics().failure_rate_trend(),
+            FailureRateTrend::Steady
+        );
+
+        // One failure every 10 seconds settles both averages at 6 a minute
+        for _ in 0..600 {
+            fail(&machine, 1);
+            clock.advance(Duration::from_secs(10));
+        }
+        let metrics = machine.get_metrics();
+        assert!((metrics.failure_rate_ewma() - 6.0).abs() < 0.5);
+        assert_eq!(metrics.failure_rate_trend(), FailureRateTrend::Steady);
+
+        fail(&machine, 30);
+        assert_eq!(
+            machine.get_metrics().failure_rate_trend(),
+            FailureRateTrend::Rising
+        );
+
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(
+            machine.get_metrics().failure_rate_trend(),
+            FailureRateTrend::Falling
+        );
+    }
+}
@@ -0,0 +1,20 @@
+//! Compile-time feature information
+//!
+//! Each constant reports whether the corresponding Cargo feature was enabled
+//! when the crate was compiled, so code can check for optional subsystems
+//! without its own `cfg` attributes.
+
+pub const HISTORY: bool = cfg!(feature = "history");
+pub const EXTENDED: bool = cfg!(feature = "extended");
+pub const METRICS: bool = cfg!(feature = "metrics");
+pub const HIERARCHICAL: bool = cfg!(feature = "hierarchical");
+pub const GUARDS: bool = cfg!(feature = "guards");
+pub const TIMEOUT: bool = cfg!(feature = "timeout");
+pub const PARALLEL: bool = cfg!(feature = "parallel");
+pub const VISUALIZATION: bool = cfg!(feature = "visualization");
+pub const SERDE: bool = cfg!(feature = "serde");
+pub const ASYNC: bool = cfg!(feature = "async");
+pub const AXUM: bool = cfg!(feature = "axum");
+pub const BINARY_SNAPSHOTS: bool = cfg!(feature = "binary-snapshots");
+pub const ENCRYPTION: bool = cfg!(feature = "encryption");
+pub const TEST_UTIL: bool = cfg!(feature = "test-util");
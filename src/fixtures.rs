@@ -0,0 +1,64 @@
+//! Order lifecycle shared by the unit tests
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder, StateMachineBuilderFactory};
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub(crate) enum Order {
+    Placed,
+    Paid,
+    Shipped,
+    Delivered,
+    Cancelled,
+    Refunded,
+    OnHold,
+}
+
+impl State for Order {}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub(crate) enum OrderEvent {
+    Pay,
+    Ship,
+    Deliver,
+    Cancel,
+    Refund,
+    Note,
+}
+
+impl Event for OrderEvent {}
+
+#[derive(Debug, Clone)]
+pub(crate) struct NoContext;
+
+impl Context for NoContext {}
+
+/// An order paid then shipped, cancellable until it ships
+pub(crate) fn order_builder<C: Context>() -> StateMachineBuilder<Order, OrderEvent, C> {
+    let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, C>();
+    builder
+        .external_transition()
+        .from(Order::Placed)
+        .to(Order::Paid)
+        .on(OrderEvent::Pay)
+        .add();
+    builder
+        .external_transition()
+        .from(Order::Paid)
+        .to(Order::Shipped)
+        .on(OrderEvent::Ship)
+        .add();
+    builder
+        .external_transitions()
+        .from_among(vec![Order::Placed, Order::Paid])
+        .to(Order::Cancelled)
+        .on(OrderEvent::Cancel)
+        .add();
+    builder
+}
+
+#[allow(dead_code)]
+pub(crate) fn order_machine() -> StateMachine<Order, OrderEvent, NoContext> {
+    order_builder().build()
+}
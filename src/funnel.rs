@@ -0,0 +1,220 @@
+//! Conversion between two states across entities (requires the `history`
+//! feature)
+//!
+//! `StateMachine::funnel_report` answers questions like "what fraction of
+//! the orders that reached PaymentPending went on to Delivered, and how long
+//! did it take?" from the histories of the entities, one slice of records
+//! per entity such as `StateMachineInstance::get_history` returns. An entity
+//! reaches a state with the first successful record entering it; states
+//! passed through by completion transitions are not recorded and don't
+//! count.
+
+use std::time::Duration;
+
+use crate::{Context, Event, State, StateMachine, TransitionRecord};
+
+/// Conversion from one state to another, see `StateMachine::funnel_report`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunnelReport {
+    /// Entities that reached the first state
+    pub entered: usize,
+    /// Entities that reached the second state after the first
+    pub converted: usize,
+    /// Entities that reached the first state and neither converted nor
+    /// ended in a terminal state
+    pub in_progress: usize,
+    // Time from the first state to the second of the converted entities,
+    // shortest first
+    durations: Vec<Duration>,
+}
+
+impl FunnelReport {
+    /// Fraction of the entities that reached the first state and converted,
+    /// `None` when none did
+    pub fn conversion_rate(&self) -> Option<f64> {
+        match self.entered {
+            0 => None,
+            entered => Some(self.converted as f64 / entered as f64),
+        }
+    }
+
+    /// Time to conversion of every converted entity, shortest first
+    pub fn durations(&self) -> &[Duration] {
+        &self.durations
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.durations.first().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.durations.last().copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.durations.len() {
+            0 => None,
+            count => Some(
+                self.durations
+                    .iter()
+                    .sum::<Duration>()
+                    .div_f64(count as f64),
+            ),
+        }
+    }
+
+    /// Time to conversion below or at which a fraction `quantile` of the
+    /// converted entities fall, `None` when none converted
+    ///
+    /// `quantile` is clamped to `0.0..=1.0`.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.durations.len();
+        let rank = (quantile.clamp(0.0, 1.0) * count as f64).ceil() as usize;
+        self.durations.get(rank.clamp(1, count.max(1)) - 1).copied()
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Conversion from `from` to `to` over `histories`, the records of one
+    /// entity each
+    ///
+    /// An entity converts when a successful record enters `to` after the
+    /// first one entering `from`; the time between the two records is its
+    /// time to conversion. An entity that did not convert is in progress
+    /// unless its last successful record entered a terminal state.
+    pub fn funnel_report<H>(
+        &self,
+        histories: impl IntoIterator<Item = H>,
+        from: &S,
+        to: &S,
+    ) -> FunnelReport
+    where
+        H: AsRef<[TransitionRecord<S, E>]>,
+    {
+        let mut report = FunnelReport::default();
+        for history in histories {
+            let records = history.as_ref();
+            let Some(start) = records
+                .iter()
+                .position(|record| record.success && record.to == *from)
+            else {
+                continue;
+            };
+            report.entered += 1;
+            let entered_at = records[start].timestamp;
+            let converted = records[start + 1..]
+                .iter()
+                .find(|record| record.success && record.to == *to);
+            match converted {
+                Some(record) => {
+                    report.converted += 1;
+                    report
+                        .durations
+                        .push(record.timestamp.saturating_duration_since(entered_at));
+                }
+                None => {
+                    let ended = records
+                        .iter()
+                        .rev()
+                        .find(|record| record.success)
+                        .is_some_and(|record| self.is_terminal(&record.to));
+                    if !ended {
+                        report.in_progress += 1;
+                    }
+                }
+            }
+        }
+        report.durations.sort();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_builder, NoContext, Order, OrderEvent};
+
+    fn delivery_builder() -> crate::StateMachineBuilder<Order, OrderEvent, NoContext> {
+        let mut builder = order_builder();
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Delivered)
+            .on(OrderEvent::Deliver)
+            .add();
+        builder.terminal_states(vec![Order::Delivered, Order::Cancelled]);
+        builder
+    }
+
+    #[test]
+    fn test_no_entity_entered() {
+        let machine = delivery_builder().build();
+        let histories: Vec<Vec<TransitionRecord<Order, OrderEvent>>> = vec![Vec::new()];
+        let report = machine.funnel_report(histories, &Order::Paid, &Order::Delivered);
+        assert_eq!(report.entered, 0);
+        assert_eq!(report.conversion_rate(), None);
+        assert_eq!(report.percentile(0.5), None);
+        assert_eq!(report.mean(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_funnel_over_instances() {
+        use std::sync::Arc;
+
+        let clock = Arc::new(crate::MockClock::new());
+        let mut builder = delivery_builder();
+        builder.with_clock(clock.clone());
+        let machine = Arc::new(builder.build());
+        let secs = Duration::from_secs;
+        let [fast, slow, cancelled, waiting, browsing] =
+            std::array::from_fn(|_| machine.start(Order::Placed));
+
+        fast.process(OrderEvent::Pay, NoContext).unwrap();
+        clock.advance(secs(10));
+        fast.process(OrderEvent::Ship, NoContext).unwrap();
+        clock.advance(secs(20));
+        fast.process(OrderEvent::Deliver, NoContext).unwrap();
+
+        slow.process(OrderEvent::Pay, NoContext).unwrap();
+        clock.advance(secs(50));
+        slow.process(OrderEvent::Ship, NoContext).unwrap();
+        clock.advance(secs(40));
+        slow.process(OrderEvent::Deliver, NoContext).unwrap();
+
+        cancelled.process(OrderEvent::Pay, NoContext).unwrap();
+        cancelled.process(OrderEvent::Cancel, NoContext).unwrap();
+
+        waiting.process(OrderEvent::Pay, NoContext).unwrap();
+        // A failed fire doesn't reach Delivered
+        assert!(waiting.process(OrderEvent::Deliver, NoContext).is_err());
+        clock.advance(secs(5));
+        waiting.process(OrderEvent::Ship, NoContext).unwrap();
+
+        assert!(browsing.process(OrderEvent::Ship, NoContext).is_err());
+
+        let histories = [fast, slow, cancelled, waiting, browsing].map(|i| i.get_history());
+        let report = machine.funnel_report(&histories, &Order::Paid, &Order::Delivered);
+        assert_eq!(report.entered, 4);
+        assert_eq!(report.converted, 2);
+        assert_eq!(report.in_progress, 1);
+        assert_eq!(report.conversion_rate(), Some(0.5));
+        assert_eq!(report.durations(), [secs(30), secs(90)]);
+        assert_eq!(report.min(), Some(secs(30)));
+        assert_eq!(report.max(), Some(secs(90)));
+        assert_eq!(report.mean(), Some(secs(60)));
+        assert_eq!(report.percentile(0.5), Some(secs(30)));
+        assert_eq!(report.percentile(0.95), Some(secs(90)));
+
+        // Shipped to Delivered: the waiting order is still in progress
+        let report = machine.funnel_report(&histories, &Order::Shipped, &Order::Delivered);
+        assert_eq!((report.entered, report.converted), (3, 2));
+        assert_eq!(report.in_progress, 1);
+        assert_eq!(report.durations(), [secs(20), secs(40)]);
+    }
+}
@@ -0,0 +1,258 @@
+//! Searching the history without copying all of it (requires the `history`
+//! feature)
+//!
+//! A `HistoryQuery` combines predicates on the records, and
+//! `StateMachine::find_history` applies them while holding the history lock,
+//! cloning only the records that match. The `history_*` shortcuts cover the
+//! common single-predicate searches.
+
+use std::time::SystemTime;
+
+use crate::{Context, Event, State, StateMachine, TransitionRecord};
+
+/// Predicates on history records, all of which must hold
+///
+/// Matching records are returned oldest first.
+#[derive(Debug, Clone)]
+pub struct HistoryQuery<S, E> {
+    since: Option<SystemTime>,
+    state: Option<S>,
+    event: Option<E>,
+    success: Option<bool>,
+    last: Option<usize>,
+}
+
+impl<S, E> HistoryQuery<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Query matching every record
+    pub fn new() -> Self {
+        HistoryQuery {
+            since: None,
+            state: None,
+            event: None,
+            success: None,
+            last: None,
+        }
+    }
+
+    /// Records at or after `time` on the wall clock
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(time);
+        self
+    }
+
+    /// Records entering or leaving `state`
+    pub fn for_state(mut self, state: S) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Records of fires of `event`
+    pub fn for_event(mut self, event: E) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    /// Records of failed fires
+    pub fn failed(mut self) -> Self {
+        self.success = Some(false);
+        self
+    }
+
+    /// Records of successful fires
+    pub fn succeeded(mut self) -> Self {
+        self.success = Some(true);
+        self
+    }
+
+    /// Only the `n` most recent of the matching records
+    pub fn last(mut self, n: usize) -> Self {
+        self.last = Some(n);
+        self
+    }
+
+    /// Whether `record` satisfies the predicates; `last` is not one of them
+    pub fn matches(&self, record: &TransitionRecord<S, E>) -> bool {
+        self.since.is_none_or(|since| record.wall_time >= since)
+            && self
+                .state
+                .as_ref()
+                .is_none_or(|state| record.from == *state || record.to == *state)
+            && self
+                .event
+                .as_ref()
+                .is_none_or(|event| record.event == *event)
+            && self.success.is_none_or(|success| record.success == success)
+    }
+
+    fn apply(&self, records: &[TransitionRecord<S, E>]) -> Vec<TransitionRecord<S, E>> {
+        let matching = records.iter().filter(|record| self.matches(record));
+        match self.last {
+            Some(n) => {
+                let mut recent: Vec<_> = matching.rev().take(n).cloned().collect();
+                recent.reverse();
+                recent
+            }
+            None => matching.cloned().collect(),
+        }
+    }
+}
+
+impl<S, E> Default for HistoryQuery<S, E>
+where
+    S: State,
+    E: Event,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// History records matching `query`, oldest first
+    pub fn find_history(&self, query: &HistoryQuery<S, E>) -> Vec<TransitionRecord<S, E>> {
+        self.recording.with_history(|records| query.apply(records))
+    }
+
+    /// The `n` most recent history records
+    pub fn history_last(&self, n: usize) -> Vec<TransitionRecord<S, E>> {
+        self.find_history(&HistoryQuery::new().last(n))
+    }
+
+    /// History records at or after `time` on the wall clock
+    pub fn history_since(&self, time: SystemTime) -> Vec<TransitionRecord<S, E>> {
+        self.find_history(&HistoryQuery::new().since(time))
+    }
+
+    /// History records entering or leaving `state`
+    pub fn history_for_state(&self, state: &S) -> Vec<TransitionRecord<S, E>> {
+        self.find_history(&HistoryQuery::new().for_state(state.clone()))
+    }
+
+    /// History records of fires of `event`
+    pub fn history_for_event(&self, event: &E) -> Vec<TransitionRecord<S, E>> {
+        self.find_history(&HistoryQuery::new().for_event(event.clone()))
+    }
+
+    /// History records of failed fires
+    pub fn failed_transitions(&self) -> Vec<TransitionRecord<S, E>> {
+        self.find_history(&HistoryQuery::new().failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Door {
+        Open,
+        Closed,
+        Locked,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Open,
+        Close,
+        Lock,
+    }
+
+    impl Event for DoorEvent {}
+
+    // Closed -> Open -> Closed -> Locked, with two failed fires in between
+    fn used_door() -> StateMachine<Door, DoorEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, NoContext>();
+        for (from, event, to) in [
+            (Door::Closed, DoorEvent::Open, Door::Open),
+            (Door::Open, DoorEvent::Close, Door::Closed),
+            (Door::Closed, DoorEvent::Lock, Door::Locked),
+        ] {
+            builder
+                .external_transition()
+                .from(from)
+                .to(to)
+                .on(event)
+                .add();
+        }
+        let machine = builder.build();
+        for (from, event) in [
+            (Door::Closed, DoorEvent::Open),
+            (Door::Open, DoorEvent::Lock),
+            (Door::Open, DoorEvent::Close),
+            (Door::Locked, DoorEvent::Open),
+            (Door::Closed, DoorEvent::Lock),
+        ] {
+            let _ = machine.fire_event(from, event, NoContext);
+        }
+        machine
+    }
+
+    fn path(records: &[TransitionRecord<Door, DoorEvent>]) -> Vec<(Door, Door)> {
+        records
+            .iter()
+            .map(|record| (record.from.clone(), record.to.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_shortcuts() {
+        let machine = used_door();
+        assert_eq!(
+            path(&machine.history_last(2)),
+            vec![(Door::Locked, Door::Locked), (Door::Closed, Door::Locked)]
+        );
+        assert_eq!(
+            path(&machine.history_for_state(&Door::Open)),
+            vec![
+                (Door::Closed, Door::Open),
+                (Door::Open, Door::Open),
+                (Door::Open, Door::Closed),
+            ]
+        );
+        assert_eq!(machine.history_for_event(&DoorEvent::Lock).len(), 2);
+        assert_eq!(
+            path(&machine.failed_transitions()),
+            vec![(Door::Open, Door::Open), (Door::Locked, Door::Locked)]
+        );
+        assert_eq!(machine.history_since(SystemTime::UNIX_EPOCH).len(), 5);
+        assert!(machine
+            .history_since(SystemTime::now() + std::time::Duration::from_secs(60))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_query_combines_predicates() {
+        let machine = used_door();
+        let query = HistoryQuery::new()
+            .for_event(DoorEvent::Lock)
+            .succeeded()
+            .last(5);
+        assert_eq!(
+            path(&machine.find_history(&query)),
+            vec![(Door::Closed, Door::Locked)]
+        );
+
+        let query = HistoryQuery::new().for_state(Door::Closed).last(1);
+        assert_eq!(
+            path(&machine.find_history(&query)),
+            vec![(Door::Closed, Door::Locked)]
+        );
+        assert_eq!(
+            machine.find_history(&HistoryQuery::new()).len(),
+            machine.history_len()
+        );
+    }
+}
@@ -0,0 +1,526 @@
+//! Batched delivery of history records to async storage (requires `async`
+//! and `history` features)
+//!
+//! A machine built with `with_history_sink` pushes each record into a bounded
+//! in-memory buffer without waiting. A background task hands the buffered
+//! records to an `AsyncHistorySink` in batches, as soon as `batch_size` are
+//! waiting or when `flush_interval` has passed. When storage falls behind and
+//! the buffer is full, records are dropped according to the `OverflowPolicy`
+//! and counted.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::{Event, State, TransitionRecord};
+
+/// Storage receiving history records in batches
+#[async_trait]
+pub trait AsyncHistorySink<S, E>: Send + Sync
+where
+    S: State,
+    E: Event,
+{
+    async fn record_batch(&self, records: Vec<TransitionRecord<S, E>>) -> io::Result<()>;
+}
+
+/// Which record is given up when the buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Keep the buffered records and drop the incoming one
+    DropNewest,
+    /// Make room by dropping the oldest buffered record
+    DropOldest,
+}
+
+/// Buffering and flushing settings of a `HistorySinkHandle`
+#[derive(Debug, Clone)]
+pub struct HistorySinkConfig {
+    /// Records held before the overflow policy applies
+    pub capacity: usize,
+    /// Records written per `record_batch` call
+    pub batch_size: usize,
+    /// Longest time a record waits when fewer than `batch_size` are buffered
+    pub flush_interval: Duration,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for HistorySinkConfig {
+    fn default() -> Self {
+        HistorySinkConfig {
+            capacity: 10_000,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(1),
+            overflow: OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+enum Command {
+    Flush(oneshot::Sender<()>),
+    Close(oneshot::Sender<()>),
+}
+
+struct Buffer<S, E>
+where
+    S: State,
+    E: Event,
+{
+    records: Mutex<VecDeque<TransitionRecord<S, E>>>,
+    capacity: usize,
+    batch_size: usize,
+    overflow: OverflowPolicy,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    wake: Notify,
+}
+
+impl<S, E> Buffer<S, E>
+where
+    S: State,
+    E: Event,
+{
+    // Next batch, if at least `min` records are buffered
+    fn take_batch(&self, min: usize) -> Option<Vec<TransitionRecord<S, E>>> {
+        let mut records = self.records.lock().unwrap();
+        if records.is_empty() || records.len() < min {
+            return None;
+        }
+        let len = records.len().min(self.batch_size);
+        Some(records.drain(..len).collect())
+    }
+
+    async fn write(&self, sink: &dyn AsyncHistorySink<S, E>, min: usize) {
+        while let Some(batch) = self.take_batch(min) {
+            let len = batch.len() as u64;
+            if sink.record_batch(batch).await.is_err() {
+                self.failed.fetch_add(len, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Buffer in front of an `AsyncHistorySink`, with its flushing task
+pub struct HistorySinkHandle<S, E>
+where
+    S: State,
+    E: Event,
+{
+    buffer: Arc<Buffer<S, E>>,
+    commands: mpsc::UnboundedSender<Command>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<S, E> HistorySinkHandle<S, E>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    /// Spawn the flushing task for `sink` on the current tokio runtime
+    pub fn spawn(sink: Arc<dyn AsyncHistorySink<S, E>>, config: HistorySinkConfig) -> Self {
+        let buffer = Arc::new(Buffer {
+            records: Mutex::new(VecDeque::new()),
+            capacity: config.capacity.max(1),
+            batch_size: config.batch_size.max(1),
+            overflow: config.overflow,
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            wake: Notify::new(),
+        });
+        let (commands, mut receiver) = mpsc::unbounded_channel();
+
+        let task_buffer = buffer.clone();
+        let task = tokio::spawn(async move {
+            let buffer = task_buffer;
+            let period = config.flush_interval.max(Duration::from_millis(1));
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    _ = buffer.wake.notified() => buffer.write(&*sink, buffer.batch_size).await,
+                    _ = interval.tick() => buffer.write(&*sink, 1).await,
+                    command = receiver.recv() => {
+                        buffer.write(&*sink, 1).await;
+                        match command {
+                            Some(Command::Flush(done)) => {
+                                let _ = done.send(());
+                            }
+                            Some(Command::Close(done)) => {
+                                let _ = done.send(());
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+            }
+        });
+
+        HistorySinkHandle {
+            buffer,
+            commands,
+            task: Mutex::new(Some(task)),
+        }
+    }
+
+    /// Write every buffered record, returning once the sink has them
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.commands.send(Command::Flush(done)).is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    /// Stop accepting records, write the remaining ones and stop the task
+    pub async fn close(&self) {
+        self.buffer.closed.store(true, Ordering::Release);
+        let (done, wait) = oneshot::channel();
+        if self.commands.send(Command::Close(done)).is_ok() {
+            let _ = wait.await;
+        }
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+}
+
+impl<S, E> HistorySinkHandle<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Buffer `record` without waiting, applying the overflow policy
+    pub(crate) fn enqueue(&self, record: TransitionRecord<S, E>) {
+        let buffer = &self.buffer;
+        if buffer.closed.load(Ordering::Acquire) {
+            buffer.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut records = buffer.records.lock().unwrap();
+        if records.len() >= buffer.capacity {
+            buffer.dropped.fetch_add(1, Ordering::Relaxed);
+            match buffer.overflow {
+                OverflowPolicy::DropNewest => return,
+                OverflowPolicy::DropOldest => {
+                    records.pop_front();
+                }
+            }
+        }
+        records.push_back(record);
+        if records.len() >= buffer.batch_size {
+            buffer.wake.notify_one();
+        }
+    }
+
+    /// Records given up because the buffer was full or the handle closed
+    pub fn dropped_records(&self) -> u64 {
+        self.buffer.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Records in batches the sink returned an error for
+    pub fn failed_records(&self) -> u64 {
+        self.buffer.failed.load(Ordering::Relaxed)
+    }
+
+    /// Records waiting to be written
+    pub fn pending(&self) -> usize {
+        self.buffer.records.lock().unwrap().len()
+    }
+}
+
+/// Sink appending one JSON object per record to a file (requires `serde`
+/// feature)
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub struct JsonLinesFileSink {
+    file: tokio::sync::Mutex<tokio::fs::File>,
+    codec: Option<Arc<dyn crate::SnapshotCodec>>,
+}
+
+#[cfg(feature = "serde")]
+impl JsonLinesFileSink {
+    /// Open `path` for appending, creating it if needed
+    pub async fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(JsonLinesFileSink {
+            file: tokio::sync::Mutex::new(file),
+            codec: None,
+        })
+    }
+
+    /// Pass every line through `codec`, e.g. to encrypt the records
+    pub fn with_codec(mut self, codec: Arc<dyn crate::SnapshotCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+}
+
+#[cfg(feature = "serde")]
+#[async_trait]
+impl<S, E> AsyncHistorySink<S, E> for JsonLinesFileSink
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    async fn record_batch(&self, records: Vec<TransitionRecord<S, E>>) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut lines = String::new();
+        for record in &records {
+            let wall_time_ms = record
+                .wall_time
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or(0);
+            let line = serde_json::json!({
+                "from": format!("{:?}", record.from),
+                "to": format!("{:?}", record.to),
+                "event": format!("{:?}", record.event),
+                "wall_time_ms": wall_time_ms,
+                "duration_us": record.duration.as_micros() as u64,
+                "success": record.success,
+                "error": record.error,
+                "error_code": record.error_code,
+            });
+            match &self.codec {
+                Some(codec) => lines.push_str(&crate::snapshot::encode_line(
+                    codec.as_ref(),
+                    line.to_string().as_bytes(),
+                )),
+                None => lines.push_str(&line.to_string()),
+            }
+            lines.push('\n');
+        }
+
+        let mut file = self.file.lock().await;
+        file.write_all(lines.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{StateMachine, StateMachineBuilderFactory};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Job {
+        Queued,
+        Running,
+    }
+
+    impl State for Job {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum JobEvent {
+        Start,
+    }
+
+    impl Event for JobEvent {}
+
+    // Sink keeping the size of every batch it receives
+    #[derive(Default)]
+    struct RecordingSink {
+        batches: Mutex<Vec<usize>>,
+    }
+
+    #[async_trait]
+    impl AsyncHistorySink<Job, JobEvent> for RecordingSink {
+        async fn record_batch(
+            &self,
+            records: Vec<TransitionRecord<Job, JobEvent>>,
+        ) -> io::Result<()> {
+            self.batches.lock().unwrap().push(records.len());
+            Ok(())
+        }
+    }
+
+    // Sink that never completes, standing in for stalled storage
+    struct StalledSink;
+
+    #[async_trait]
+    impl AsyncHistorySink<Job, JobEvent> for StalledSink {
+        async fn record_batch(
+            &self,
+            _records: Vec<TransitionRecord<Job, JobEvent>>,
+        ) -> io::Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    fn job_machine(
+        sink: Arc<HistorySinkHandle<Job, JobEvent>>,
+    ) -> StateMachine<Job, JobEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Job, JobEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Job::Queued)
+            .to(Job::Running)
+            .on(JobEvent::Start)
+            .perform(|_s, _e, _c| {});
+        builder.with_history_sink(sink);
+        builder.build()
+    }
+
+    fn start(machine: &StateMachine<Job, JobEvent, NoContext>, times: usize) {
+        for _ in 0..times {
+            machine
+                .fire_event(Job::Queued, JobEvent::Start, NoContext)
+                .unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batches_by_size() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = HistorySinkConfig {
+            batch_size: 3,
+            flush_interval: Duration::from_secs(3600),
+            ..HistorySinkConfig::default()
+        };
+        let handle = Arc::new(HistorySinkHandle::spawn(sink.clone(), config));
+        let machine = job_machine(handle.clone());
+
+        start(&machine, 7);
+        tokio::task::yield_now().await;
+        assert_eq!(*sink.batches.lock().unwrap(), vec![3, 3]);
+        assert_eq!(handle.pending(), 1);
+
+        handle.flush().await;
+        assert_eq!(*sink.batches.lock().unwrap(), vec![3, 3, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flushes_on_interval() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = HistorySinkConfig {
+            batch_size: 100,
+            flush_interval: Duration::from_secs(5),
+            ..HistorySinkConfig::default()
+        };
+        let handle = Arc::new(HistorySinkHandle::spawn(sink.clone(), config));
+        let machine = job_machine(handle.clone());
+
+        start(&machine, 2);
+        time::sleep(Duration::from_secs(4)).await;
+        assert!(sink.batches.lock().unwrap().is_empty());
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(*sink.batches.lock().unwrap(), vec![2]);
+        assert_eq!(handle.pending(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_overflow_is_counted() {
+        let config = HistorySinkConfig {
+            capacity: 4,
+            batch_size: 2,
+            flush_interval: Duration::from_secs(3600),
+            overflow: OverflowPolicy::DropOldest,
+        };
+        let handle = Arc::new(HistorySinkHandle::spawn(Arc::new(StalledSink), config));
+        let machine = job_machine(handle.clone());
+
+        // The first batch is taken and stalls; the buffer then fills up
+        start(&machine, 2);
+        tokio::task::yield_now().await;
+        start(&machine, 7);
+        assert_eq!(handle.pending(), 4);
+        assert_eq!(handle.dropped_records(), 3);
+        assert_eq!(machine.get_history().len(), 9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_flushes_remainder() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = HistorySinkConfig {
+            batch_size: 10,
+            flush_interval: Duration::from_secs(3600),
+            ..HistorySinkConfig::default()
+        };
+        let handle = Arc::new(HistorySinkHandle::spawn(sink.clone(), config));
+        let machine = job_machine(handle.clone());
+
+        start(&machine, 4);
+        handle.close().await;
+        assert_eq!(*sink.batches.lock().unwrap(), vec![4]);
+
+        start(&machine, 1);
+        assert_eq!(handle.dropped_records(), 1);
+        assert_eq!(handle.pending(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_json_lines_file() {
+        let path = std::env::temp_dir().join(format!(
+            "rs-statemachine-history-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let file = Arc::new(JsonLinesFileSink::open(&path).await.unwrap());
+        let handle = Arc::new(HistorySinkHandle::spawn(file, HistorySinkConfig::default()));
+        let machine = job_machine(handle.clone());
+
+        start(&machine, 2);
+        handle.close().await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["from"], "Queued");
+        assert_eq!(lines[0]["to"], "Running");
+        assert_eq!(lines[0]["success"], true);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "rs-statemachine-history-encrypted-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let codec = Arc::new(crate::AesGcmCodec::new(&[7; 32]));
+        let file = JsonLinesFileSink::open(&path)
+            .await
+            .unwrap()
+            .with_codec(codec.clone());
+        let handle = Arc::new(HistorySinkHandle::spawn(
+            Arc::new(file),
+            HistorySinkConfig::default(),
+        ));
+        let machine = job_machine(handle.clone());
+
+        start(&machine, 1);
+        handle.close().await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(!written.contains("Queued"));
+        let json = crate::snapshot::decode_line(codec.as_ref(), written.trim_end()).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(line["from"], "Queued");
+
+        let other = crate::AesGcmCodec::new(&[8; 32]);
+        assert!(crate::snapshot::decode_line(&other, written.trim_end()).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+//! Guards and actions that know which machine and transition invoked them
+
+use std::sync::Arc;
+
+use crate::derived::DerivedValues;
+use crate::{
+    ActionError, Context, Event, ExternalTransitionBuilder, ExternalTransitionsBuilder,
+    InternalTransitionBuilder, State, StateMachineBuilder, Transition, TransitionType,
+};
+
+pub(crate) type InfoCondition<S, E, C> =
+    Arc<dyn Fn(&TransitionInfo<'_, S, E>, &C, &mut DerivedValues<'_, C>) -> bool + Send + Sync>;
+pub(crate) type InfoAction<S, E, C> = Arc<
+    dyn Fn(&TransitionInfo<'_, S, E>, &C, &mut DerivedValues<'_, C>) -> Result<(), ActionError>
+        + Send
+        + Sync,
+>;
+
+/// Description of a transition being evaluated or taken
+///
+/// Borrowed from the machine's definition, so building one costs nothing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionInfo<'a, S, E> {
+    pub machine_id: &'a str,
+    /// The state fired from, also for transitions registered with
+    /// `from_any`
+    pub from: &'a S,
+    pub to: &'a S,
+    pub event: &'a E,
+    pub transition_type: &'a TransitionType,
+    /// Always 0 without the `guards` feature
+    pub priority: u32,
+    /// `"<template>/<instance>"` for transitions stamped out by
+    /// `StateMachineBuilder::apply_template`
+    pub tag: Option<&'a str>,
+}
+
+impl<S, E, C> Transition<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // `from` is the state fired from, which differs from `self.from` for
+    // `from_any` transitions
+    pub(crate) fn info<'a>(&'a self, machine_id: &'a str, from: &'a S) -> TransitionInfo<'a, S, E> {
+        TransitionInfo {
+            machine_id,
+            from,
+            to: &self.to,
+            event: &self.event,
+            transition_type: &self.transition_type,
+            #[cfg(feature = "guards")]
+            priority: self.priority,
+            #[cfg(not(feature = "guards"))]
+            priority: 0,
+            tag: self.tag.as_deref(),
+        }
+    }
+}
+
+fn info_condition<S, E, C, F>(condition: F) -> InfoCondition<S, E, C>
+where
+    F: Fn(&TransitionInfo<'_, S, E>, &C) -> bool + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, _| condition(info, c))
+}
+
+fn info_action<S, E, C, F>(action: F) -> InfoAction<S, E, C>
+where
+    F: Fn(&TransitionInfo<'_, S, E>, &C) + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, _| {
+        action(info, c);
+        Ok(())
+    })
+}
+
+impl<'a, S, E, C> ExternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the machine id and transition metadata
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_derived`.
+    pub fn when_with_info<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&TransitionInfo<'_, S, E>, &C) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(info_condition(condition));
+        self.derived.guard = None;
+        self
+    }
+
+    /// Like `perform`, with the machine id and transition metadata
+    pub fn perform_with_info<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&TransitionInfo<'_, S, E>, &C) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(info_action(action));
+        self.derived.action = None;
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> InternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the machine id and transition metadata
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_derived`.
+    pub fn when_with_info<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&TransitionInfo<'_, S, E>, &C) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(info_condition(condition));
+        self.derived.guard = None;
+        self
+    }
+
+    /// Like `perform`, with the machine id and transition metadata
+    pub fn perform_with_info<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&TransitionInfo<'_, S, E>, &C) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(info_action(action));
+        self.derived.action = None;
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionsBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the machine id and transition metadata
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_derived`.
+    pub fn when_with_info<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&TransitionInfo<'_, S, E>, &C) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(info_condition(condition));
+        self.derived.guard = None;
+        self
+    }
+
+    /// Like `perform`, with the machine id and transition metadata
+    pub fn perform_with_info<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&TransitionInfo<'_, S, E>, &C) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(info_action(action));
+        self.derived.action = None;
+        self.add()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StateMachine, StateMachineBuilderFactory};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Doc {
+        Draft,
+        Published,
+    }
+
+    impl State for Doc {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DocEvent {
+        Publish,
+        Edit,
+    }
+
+    impl Event for DocEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Editor {
+        name: &'static str,
+    }
+
+    impl Context for Editor {}
+
+    type AuditLog = Arc<Mutex<Vec<String>>>;
+
+    fn audited_machine(id: &str, log: AuditLog) -> StateMachine<Doc, DocEvent, Editor> {
+        let audit = move |info: &TransitionInfo<'_, Doc, DocEvent>, c: &Editor| {
+            log.lock().unwrap().push(format!(
+                "{}: {:?} -{:?}-> {:?} ({:?}) by {}",
+                info.machine_id, info.from, info.event, info.to, info.transition_type, c.name
+            ));
+        };
+
+        let mut builder = StateMachineBuilderFactory::create::<Doc, DocEvent, Editor>();
+        builder
+            .external_transition()
+            .from(Doc::Draft)
+            .to(Doc::Published)
+            .on(DocEvent::Publish)
+            .when_with_info(|info, _c| info.machine_id != "readonly")
+            .perform_with_info(audit.clone());
+        builder
+            .internal_transition()
+            .within(Doc::Draft)
+            .on(DocEvent::Edit)
+            .perform_with_info(audit);
+        builder.id(id).build()
+    }
+
+    #[test]
+    fn test_shared_action_sees_machine_and_transition() {
+        let log: AuditLog = Arc::new(Mutex::new(Vec::new()));
+        let blog = audited_machine("blog", log.clone());
+        let wiki = audited_machine("wiki", log.clone());
+
+        let editor = Editor { name: "ann" };
+        assert!(blog
+            .fire_event(Doc::Draft, DocEvent::Publish, editor.clone())
+            .is_ok());
+        assert!(wiki.fire_event(Doc::Draft, DocEvent::Edit, editor).is_ok());
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "blog: Draft -Publish-> Published (External) by ann",
+                "wiki: Draft -Edit-> Draft (Internal) by ann",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_any_transition_sees_actual_source() {
+        let log: AuditLog = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let mut builder = StateMachineBuilderFactory::create::<Doc, DocEvent, Editor>();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Doc::Draft)
+            .on(DocEvent::Edit)
+            .when_with_info(|info, _c| info.from != info.to)
+            .perform_with_info(move |info, _c| {
+                sink.lock()
+                    .unwrap()
+                    .push(format!("{:?} -> {:?}", info.from, info.to));
+            });
+        let machine = builder.build();
+
+        let editor = Editor { name: "ann" };
+        let result = machine.fire_event(Doc::Published, DocEvent::Edit, editor.clone());
+        assert_eq!(result.unwrap(), Doc::Draft);
+        assert!(machine
+            .fire_event(Doc::Draft, DocEvent::Edit, editor)
+            .is_err());
+        assert_eq!(*log.lock().unwrap(), vec!["Published -> Draft"]);
+    }
+
+    #[test]
+    fn test_guard_sees_machine_id() {
+        let log: AuditLog = Arc::new(Mutex::new(Vec::new()));
+        let readonly = audited_machine("readonly", log.clone());
+
+        let result = readonly.fire_event(Doc::Draft, DocEvent::Publish, Editor { name: "bob" });
+        assert!(result.is_err());
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,556 @@
+//! A running machine that keeps its own current state
+//!
+//! `StateMachine` is a stateless definition: every `fire_event` call names
+//! the state to fire from. `StateMachine::start` returns a
+//! `StateMachineInstance` that stores the current state instead, so callers
+//! only pass events. Instances share the definition through an `Arc`; each
+//! one keeps the history and metrics of its own transitions, which are also
+//! recorded on the shared machine as usual.
+//!
+//! With the `history` feature, `StateMachineInstance::undo` reverts the last
+//! successful transition of an instance, e.g. for the "back" button of a
+//! wizard. Transitions built with `.irreversible()` refuse to be undone.
+
+use std::cell::RefCell;
+#[cfg(feature = "history")]
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::recording::RecordingState;
+use crate::safety::InstanceUsage;
+#[cfg(feature = "metrics")]
+use crate::StateMachineMetrics;
+#[cfg(feature = "history")]
+use crate::{ArrivalExplanation, TransitionRecord};
+use crate::{AtomicError, Context, Event, SequenceMark, State, StateMachine, TransitionError};
+
+/// Why `StateMachineInstance::undo` left the instance where it was
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+#[derive(Debug, Clone)]
+pub enum UndoError<S, E> {
+    /// The history of the instance has no successful transition left
+    NothingToUndo,
+    /// The last successful transition was built with `.irreversible()`
+    Irreversible { from: S, event: E, to: S },
+    /// The entry action of the state to restore failed
+    #[cfg(feature = "extended")]
+    EntryActionFailed {
+        state: S,
+        source: Arc<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+#[cfg(feature = "history")]
+impl<S: fmt::Debug, E: fmt::Debug> fmt::Display for UndoError<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndoError::NothingToUndo => write!(f, "No transition to undo"),
+            UndoError::Irreversible { from, event, to } => write!(
+                f,
+                "Transition from {:?} to {:?} on {:?} is irreversible",
+                from, to, event
+            ),
+            #[cfg(feature = "extended")]
+            UndoError::EntryActionFailed { state, source } => {
+                write!(f, "Entry action of {:?} failed: {}", state, source)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "history")]
+impl<S: fmt::Debug, E: fmt::Debug> std::error::Error for UndoError<S, E> {}
+
+/// A `StateMachine` definition together with a current state
+pub struct StateMachineInstance<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    machine: Arc<StateMachine<S, E, C>>,
+    current: RwLock<S>,
+    recording: RecordingState<S, E>,
+    usage: Mutex<InstanceUsage<S, E>>,
+    sequence: Mutex<SequenceMark>,
+}
+
+// Asked with `(from, event, to)` of the transition selected, before it runs
+type Admit<'a, S, E> = dyn Fn(&S, &E, &S) -> Result<(), TransitionError<S, E>> + 'a;
+
+// What firing for an instance adds to the machine's pipeline
+pub(crate) struct InstanceFire<'a, S, E>
+where
+    S: State,
+    E: Event,
+{
+    // Also gets the history and metrics of the fire, unless it is staged
+    pub(crate) recording: Option<&'a RecordingState<S, E>>,
+    pub(crate) admit: &'a Admit<'a, S, E>,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Start an instance of this machine in `initial`
+    pub fn start(self: &Arc<Self>, initial: S) -> StateMachineInstance<S, E, C> {
+        self.start_sequenced(initial, SequenceMark::default())
+    }
+
+    // Start an instance that already applied the sequences in `sequence`
+    pub(crate) fn start_sequenced(
+        self: &Arc<Self>,
+        initial: S,
+        sequence: SequenceMark,
+    ) -> StateMachineInstance<S, E, C> {
+        StateMachineInstance {
+            machine: self.clone(),
+            current: RwLock::new(initial),
+            recording: self.recording.fresh(),
+            usage: Mutex::default(),
+            sequence: Mutex::new(sequence),
+        }
+    }
+}
+
+impl<S, E, C> StateMachineInstance<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Fire `event` from the current state, moving to the target on success
+    ///
+    /// Events processed from several threads are applied one at a time. The
+    /// current state is locked while the transition runs, so its callbacks
+    /// must not call back into this instance.
+    ///
+    /// Fails with `TransitionError::TransitionBudgetExhausted` without firing
+    /// once the instance has taken the limit set with
+    /// `StateMachineBuilder::max_transitions_per_instance`, and with
+    /// `TransitionError::LoopDetected`, staying in the current state, when
+    /// the transition would be flagged by
+    /// `StateMachineBuilder::loop_detection`.
+    pub fn process(&self, event: E, context: C) -> Result<S, TransitionError<S, E>> {
+        let mut current = self.current.write().unwrap();
+        self.process_locked(&mut current, event, context)
+    }
+
+    /// `process` for an event carrying a sequence number
+    ///
+    /// An event whose sequence is not above the highest one this instance
+    /// applied, beyond the window set with
+    /// `StateMachineBuilder::with_sequence_window`, is rejected with
+    /// `TransitionError::OutOfOrder` before any guard or action runs. The
+    /// check, the transition and recording the sequence happen under the
+    /// lock of the current state, so concurrent deliveries of the same event
+    /// apply it once. The sequences applied are part of the snapshot.
+    pub fn fire_event_sequenced(
+        &self,
+        event: E,
+        context: C,
+        sequence: u64,
+    ) -> Result<S, TransitionError<S, E>> {
+        let mut current = self.current.write().unwrap();
+        let mut mark = self.sequence.lock().unwrap();
+        mark.check(sequence, self.machine.sequence_window)
+            .map_err(|last| TransitionError::OutOfOrder {
+                last,
+                attempted: sequence,
+            })?;
+        let result = self.process_locked(&mut current, event, context);
+        if result.is_ok() {
+            mark.record(sequence);
+        }
+        result
+    }
+
+    /// `StateMachine::fire_atomic` from the current state, moving to the
+    /// final state only when every event succeeds
+    ///
+    /// The current state is locked for the whole batch. Each event counts
+    /// against the limits set with
+    /// `StateMachineBuilder::max_transitions_per_instance` and
+    /// `StateMachineBuilder::loop_detection` as if processed on its own, and
+    /// a batch that would exceed them is rejected at the first event over.
+    pub fn fire_atomic(&self, events: &[E], context: &C) -> Result<S, AtomicError<S, E>> {
+        let mut current = self.current.write().unwrap();
+        let mut usage = self.usage.lock().unwrap();
+        let limits = &self.machine.instance_limits;
+        let staged = RefCell::new(Vec::new());
+        let admit = |from: &S, event: &E, to: &S| {
+            if limits.watches_loops() {
+                limits.check_staged_loop(&usage, &staged.borrow(), from, event, to)?;
+                staged
+                    .borrow_mut()
+                    .push((from.clone(), event.clone(), to.clone()));
+            }
+            Ok(())
+        };
+        let to = self.machine.fire_atomic_in(
+            current.clone(),
+            events,
+            context,
+            Some(&InstanceFire {
+                recording: Some(&self.recording),
+                admit: &admit,
+            }),
+            |index| limits.check_staged_budget(&usage, index as u64),
+        )?;
+        let mut staged = staged.into_inner().into_iter();
+        for _ in events {
+            limits.record(&mut usage, staged.next());
+        }
+        *current = to.clone();
+        Ok(to)
+    }
+
+    fn process_locked(
+        &self,
+        current: &mut S,
+        event: E,
+        mut context: C,
+    ) -> Result<S, TransitionError<S, E>> {
+        let mut usage = self.usage.lock().unwrap();
+        let limits = &self.machine.instance_limits;
+        limits.check_budget(&usage)?;
+        let fired = RefCell::new(None);
+        let admit = |from: &S, event: &E, to: &S| {
+            if limits.watches_loops() {
+                limits.check_loop(&usage, from, event, to)?;
+                *fired.borrow_mut() = Some((from.clone(), event.clone(), to.clone()));
+            }
+            Ok(())
+        };
+        let to = self.machine.fire_traced(
+            current.clone(),
+            event,
+            &mut context,
+            None,
+            Some(&InstanceFire {
+                recording: Some(&self.recording),
+                admit: &admit,
+            }),
+            None,
+        )?;
+        *current = to.clone();
+        limits.record(&mut usage, fired.into_inner());
+        Ok(to)
+    }
+
+    pub fn current_state(&self) -> S {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn is_in(&self, state: &S) -> bool {
+        &*self.current.read().unwrap() == state
+    }
+
+    /// Sequences applied by `fire_event_sequenced`
+    pub fn sequence(&self) -> SequenceMark {
+        *self.sequence.lock().unwrap()
+    }
+
+    // The current state with the sequences that led to it
+    #[cfg(feature = "serde")]
+    pub(crate) fn sequenced_state(&self) -> (S, SequenceMark) {
+        let current = self.current.read().unwrap();
+        (current.clone(), *self.sequence.lock().unwrap())
+    }
+
+    /// The shared definition this instance runs on
+    pub fn machine(&self) -> &Arc<StateMachine<S, E, C>> {
+        &self.machine
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Transitions attempted by this instance
+    pub fn get_history(&self) -> Vec<TransitionRecord<S, E>> {
+        self.recording.with_history(|records| records.to_vec())
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// `StateMachine::explain_arrival` over the history of this instance
+    pub fn explain_arrival(&self, state: &S) -> Option<ArrivalExplanation<S, E>> {
+        self.recording
+            .with_history(|records| self.machine.explain_arrival(records, state))
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Move back to the source of the last successful transition of this
+    /// instance, removing its record from the history of the instance
+    ///
+    /// Repeated calls walk back one successful transition at a time, skipping
+    /// failed attempts. Under the `extended` feature the exit action of the
+    /// current state and the entry action of the restored state run, unless
+    /// the transition was internal. Guards, transition actions, listeners and
+    /// the history of the shared machine are not involved.
+    #[cfg_attr(not(feature = "extended"), allow(unused_mut, unused_variables))]
+    pub fn undo(&self, mut context: C) -> Result<S, UndoError<S, E>> {
+        let mut current = self.current.write().unwrap();
+        let record = self
+            .recording
+            .with_history(|records| records.iter().rev().find(|r| r.success).cloned())
+            .ok_or(UndoError::NothingToUndo)?;
+
+        if record.irreversible {
+            return Err(UndoError::Irreversible {
+                from: record.from,
+                event: record.event,
+                to: record.to,
+            });
+        }
+
+        #[cfg(feature = "extended")]
+        if record.transition_type != Some(crate::TransitionType::Internal) {
+            self.machine
+                .run_exit_action(&current, &mut context, &mut None);
+            self.machine
+                .run_entry_action(&record.from, &mut context, &mut None)
+                .map_err(|source| UndoError::EntryActionFailed {
+                    state: record.from.clone(),
+                    source: source.into(),
+                })?;
+        }
+
+        self.recording.remove_last_success();
+        *current = record.from.clone();
+        Ok(record.from)
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Metrics of the transitions attempted by this instance
+    pub fn get_metrics(&self) -> StateMachineMetrics<S, E> {
+        let mut metrics = self.recording.with_metrics(StateMachineMetrics::clone);
+        metrics.failure_rate.decay(
+            self.machine.clock.now(),
+            self.machine.failure_rate.half_life,
+        );
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+    use std::thread;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Counter {
+        Idle,
+        Running,
+        Stopped,
+    }
+
+    impl State for Counter {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum CounterEvent {
+        Start,
+        Tick,
+        Stop,
+    }
+
+    impl Event for CounterEvent {}
+
+    fn counter_machine() -> Arc<StateMachine<Counter, CounterEvent, NoContext>> {
+        let mut builder = StateMachineBuilderFactory::create::<Counter, CounterEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Counter::Idle)
+            .to(Counter::Running)
+            .on(CounterEvent::Start)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(Counter::Running)
+            .on(CounterEvent::Tick)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Counter::Running)
+            .to(Counter::Stopped)
+            .on(CounterEvent::Stop)
+            .perform(|_s, _e, _c| {});
+        Arc::new(builder.build())
+    }
+
+    #[test]
+    fn test_instance_tracks_current_state() {
+        let machine = counter_machine();
+        let instance = machine.start(Counter::Idle);
+        assert!(instance.is_in(&Counter::Idle));
+
+        assert_eq!(
+            instance.process(CounterEvent::Start, NoContext).unwrap(),
+            Counter::Running
+        );
+        assert!(instance.process(CounterEvent::Start, NoContext).is_err());
+        assert_eq!(instance.current_state(), Counter::Running);
+
+        instance.process(CounterEvent::Stop, NoContext).unwrap();
+        assert!(instance.is_in(&Counter::Stopped));
+    }
+
+    #[test]
+    #[cfg(any(feature = "history", feature = "metrics"))]
+    fn test_records_attributed_per_instance() {
+        let machine = counter_machine();
+        let first = machine.start(Counter::Idle);
+        let second = machine.start(Counter::Idle);
+
+        first.process(CounterEvent::Start, NoContext).unwrap();
+        first.process(CounterEvent::Stop, NoContext).unwrap();
+        second.process(CounterEvent::Start, NoContext).unwrap();
+
+        #[cfg(feature = "history")]
+        {
+            assert_eq!(first.get_history().len(), 2);
+            assert_eq!(second.get_history().len(), 1);
+            assert_eq!(machine.get_history().len(), 3);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            assert_eq!(first.get_metrics().successful_transitions, 2);
+            assert_eq!(second.get_metrics().successful_transitions, 1);
+            assert_eq!(machine.get_metrics().successful_transitions, 3);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_undo_walks_back() {
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+        let mut builder = StateMachineBuilderFactory::create::<Counter, CounterEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Counter::Idle)
+            .to(Counter::Running)
+            .on(CounterEvent::Start)
+            .add();
+        builder
+            .internal_transition()
+            .within(Counter::Running)
+            .on(CounterEvent::Tick)
+            .add();
+        builder
+            .external_transition()
+            .from(Counter::Running)
+            .to(Counter::Stopped)
+            .on(CounterEvent::Stop)
+            .irreversible()
+            .add();
+        #[cfg(feature = "extended")]
+        for state in [Counter::Idle, Counter::Running] {
+            let (entries, exits) = (log.clone(), log.clone());
+            builder
+                .with_entry_action(state.clone(), move |s, _c| {
+                    entries.lock().unwrap().push(format!("enter {:?}", s))
+                })
+                .with_exit_action(state, move |s, _c| {
+                    exits.lock().unwrap().push(format!("exit {:?}", s))
+                });
+        }
+        let machine = Arc::new(builder.build());
+
+        let instance = machine.start(Counter::Idle);
+        instance.process(CounterEvent::Start, NoContext).unwrap();
+        instance.process(CounterEvent::Tick, NoContext).unwrap();
+        assert!(instance.process(CounterEvent::Start, NoContext).is_err());
+        log.lock().unwrap().clear();
+
+        // The internal transition is undone without state actions
+        assert_eq!(instance.undo(NoContext).unwrap(), Counter::Running);
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(instance.undo(NoContext).unwrap(), Counter::Idle);
+        #[cfg(feature = "extended")]
+        assert_eq!(*log.lock().unwrap(), ["exit Running", "enter Idle"]);
+        match instance.undo(NoContext) {
+            Err(UndoError::NothingToUndo) => {}
+            other => panic!("expected NothingToUndo, got {:?}", other),
+        }
+        assert!(instance.is_in(&Counter::Idle));
+        // The failed attempt stays in the history of the instance
+        assert_eq!(instance.get_history().len(), 1);
+        // The shared machine keeps every record
+        assert_eq!(machine.last_successful().unwrap().event, CounterEvent::Tick);
+
+        instance.process(CounterEvent::Start, NoContext).unwrap();
+        instance.process(CounterEvent::Stop, NoContext).unwrap();
+        match instance.undo(NoContext) {
+            Err(UndoError::Irreversible { from, to, .. }) => {
+                assert_eq!((from, to), (Counter::Running, Counter::Stopped))
+            }
+            other => panic!("expected Irreversible, got {:?}", other),
+        }
+        assert!(instance.is_in(&Counter::Stopped));
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_undo_after_completion_chain() {
+        let mut builder = StateMachineBuilderFactory::create::<Counter, CounterEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Counter::Idle)
+            .to(Counter::Running)
+            .on(CounterEvent::Start)
+            .irreversible()
+            .add();
+        builder
+            .completion_transition()
+            .from(Counter::Running)
+            .to(Counter::Stopped)
+            .always();
+        let machine = Arc::new(builder.build());
+
+        let instance = machine.start(Counter::Idle);
+        assert_eq!(
+            instance.process(CounterEvent::Start, NoContext).unwrap(),
+            Counter::Stopped
+        );
+        // The record names the settled state, the flag the transition taken
+        match instance.undo(NoContext) {
+            Err(UndoError::Irreversible { from, to, .. }) => {
+                assert_eq!((from, to), (Counter::Idle, Counter::Stopped))
+            }
+            other => panic!("expected Irreversible, got {:?}", other),
+        }
+        assert!(instance.is_in(&Counter::Stopped));
+    }
+
+    #[test]
+    fn test_concurrent_processing() {
+        let machine = counter_machine();
+        let instance = Arc::new(machine.start(Counter::Idle));
+        instance.process(CounterEvent::Start, NoContext).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let instance = instance.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        instance.process(CounterEvent::Tick, NoContext).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(instance.is_in(&Counter::Running));
+        #[cfg(feature = "metrics")]
+        assert_eq!(instance.get_metrics().successful_transitions, 801);
+    }
+}
@@ -0,0 +1,533 @@
+//! Write-ahead intents for actions with external side effects
+//!
+//! A process dying after an action charged a card but before the new state
+//! was saved leaves no trace of whether the charge happened.
+//! `StateMachine::fire_event_persisted` fires an event for an entity of a
+//! `StateRepository` like `fire_event_inferred`, but first writes an
+//! `Intent` to an `IntentLog`, and marks it completed once the fire has an
+//! outcome and a new state has been saved. The idempotency key of the intent
+//! is meant to be passed on to the external system by the actions.
+//!
+//! On startup `recover_incomplete` lists the intents that were never
+//! completed, with the state the repository holds now, so the application
+//! can reconcile each one with the external system and then complete it.
+//! `StateMachine::recheck_intent` tells whether the event would still apply.
+
+use std::fmt::Debug;
+#[cfg(feature = "serde")]
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(feature = "serde")]
+use std::io::Write;
+#[cfg(feature = "serde")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "serde")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::repository::save_fired;
+use crate::{
+    Context, Event, ResolvedTransition, State, StateMachine, StateRepository, TransitionError,
+};
+
+/// A fire about to run the actions of a transition
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Intent<K, S, E> {
+    /// Entity the event is fired for
+    pub key: K,
+    pub from: S,
+    pub event: E,
+    pub idempotency_key: String,
+}
+
+/// Durable record of intents, see the module documentation
+pub trait IntentLog<K, S, E>: Send + Sync {
+    /// Record `intent` before any of its actions runs
+    ///
+    /// The event is not fired if the intent could not be recorded.
+    fn begin(&self, intent: &Intent<K, S, E>) -> io::Result<()>;
+
+    /// Record that the intent with `idempotency_key` needs no recovery
+    fn complete(&self, idempotency_key: &str) -> io::Result<()>;
+
+    /// Intents begun and not completed, oldest first
+    fn incomplete(&self) -> Vec<Intent<K, S, E>>;
+}
+
+/// `IntentLog` kept in memory, for tests and single-process setups
+pub struct InMemoryIntentLog<K, S, E> {
+    pending: Mutex<Vec<Intent<K, S, E>>>,
+}
+
+impl<K, S, E> InMemoryIntentLog<K, S, E> {
+    pub fn new() -> Self {
+        InMemoryIntentLog {
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<K, S, E> Default for InMemoryIntentLog<K, S, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, S, E> IntentLog<K, S, E> for InMemoryIntentLog<K, S, E>
+where
+    K: Clone + Send,
+    S: State + Send,
+    E: Event + Send,
+{
+    fn begin(&self, intent: &Intent<K, S, E>) -> io::Result<()> {
+        self.pending.lock().unwrap().push(intent.clone());
+        Ok(())
+    }
+
+    fn complete(&self, idempotency_key: &str) -> io::Result<()> {
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|intent| intent.idempotency_key != idempotency_key);
+        Ok(())
+    }
+
+    fn incomplete(&self) -> Vec<Intent<K, S, E>> {
+        self.pending.lock().unwrap().clone()
+    }
+}
+
+/// `IntentLog` appending to a file, one JSON object per line (requires the
+/// `serde` feature)
+///
+/// Every write is synced to disk before the fire goes on. Lines that don't
+/// parse, such as one cut short by a crash, are skipped when reading back.
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub struct JsonLinesIntentLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    failed: AtomicU64,
+}
+
+#[cfg(feature = "serde")]
+impl JsonLinesIntentLog {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(JsonLinesIntentLog {
+            path,
+            file: Mutex::new(file),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    /// Lines that could not be written
+    pub fn failed_writes(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    fn append(&self, entry: &impl serde::Serialize) -> io::Result<()> {
+        let written = serde_json::to_string(entry)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                let mut file = self.file.lock().unwrap();
+                file.write_all(line.as_bytes())?;
+                file.sync_data()
+            });
+        if written.is_err() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        written
+    }
+}
+
+// A line of the file
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum IntentEntry<K, S, E> {
+    Begin(Intent<K, S, E>),
+    Complete(String),
+}
+
+#[cfg(feature = "serde")]
+impl<K, S, E> IntentLog<K, S, E> for JsonLinesIntentLog
+where
+    K: Clone + serde::Serialize + serde::de::DeserializeOwned,
+    S: State + serde::Serialize + serde::de::DeserializeOwned,
+    E: Event + serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn begin(&self, intent: &Intent<K, S, E>) -> io::Result<()> {
+        self.append(&IntentEntry::Begin(intent.clone()))
+    }
+
+    fn complete(&self, idempotency_key: &str) -> io::Result<()> {
+        self.append(&IntentEntry::<K, S, E>::Complete(
+            idempotency_key.to_string(),
+        ))
+    }
+
+    fn incomplete(&self) -> Vec<Intent<K, S, E>> {
+        let Ok(text) = std::fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+        let mut pending = Vec::new();
+        for entry in text
+            .lines()
+            .filter_map(|line| serde_json::from_str::<IntentEntry<K, S, E>>(line).ok())
+        {
+            match entry {
+                IntentEntry::Begin(intent) => pending.push(intent),
+                IntentEntry::Complete(key) => {
+                    pending.retain(|intent: &Intent<K, S, E>| intent.idempotency_key != key)
+                }
+            }
+        }
+        pending
+    }
+}
+
+/// An intent left incomplete, as listed by `recover_incomplete`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryItem<K, S, E> {
+    pub intent: Intent<K, S, E>,
+    /// State the repository holds for the entity now
+    pub current: Option<S>,
+}
+
+impl<K, S, E> RecoveryItem<K, S, E>
+where
+    S: State,
+{
+    /// Whether the repository moved on from the state the intent fired from,
+    /// i.e. the process died after saving the new state
+    ///
+    /// Always `false` for self-transitions.
+    pub fn saved(&self) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|current| *current != self.intent.from)
+    }
+}
+
+/// Intents of `log` without completion, with the current state of their
+/// entity in `repo`
+pub fn recover_incomplete<K, S, E>(
+    log: &dyn IntentLog<K, S, E>,
+    repo: &dyn StateRepository<K, S>,
+) -> Vec<RecoveryItem<K, S, E>>
+where
+    S: State,
+{
+    log.incomplete()
+        .into_iter()
+        .map(|intent| RecoveryItem {
+            current: repo.load(&intent.key),
+            intent,
+        })
+        .collect()
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// `fire_event_inferred` writing an intent to `log` before any action
+    /// runs, completed once the fire has an outcome
+    ///
+    /// A fire failing after an action ran (`ActionFailed`, `CompletionLoop`
+    /// or `MaxChainDepthExceeded`), or whose save fails with
+    /// `TransitionError::VersionConflict`, may have had side effects without
+    /// recording the new state, so its intent is left incomplete for
+    /// `recover_incomplete` to report.
+    ///
+    /// Fails with `TransitionError::IntentLogFailed`, without firing, when
+    /// the intent cannot be written. A completion that cannot be written
+    /// only leaves the intent to be reported, as saved, on recovery.
+    pub fn fire_event_persisted<K>(
+        &self,
+        repo: &dyn StateRepository<K, S>,
+        log: &dyn IntentLog<K, S, E>,
+        key: &K,
+        event: E,
+        context: C,
+        idempotency_key: impl Into<String>,
+    ) -> Result<S, TransitionError<S, E>>
+    where
+        K: Clone + Debug,
+    {
+        let (from, version) =
+            repo.load_versioned(key)
+                .ok_or_else(|| TransitionError::EntityNotFound {
+                    key: format!("{:?}", key),
+                })?;
+        let intent = Intent {
+            key: key.clone(),
+            from: from.clone(),
+            event: event.clone(),
+            idempotency_key: idempotency_key.into(),
+        };
+        log.begin(&intent)
+            .map_err(|error| TransitionError::IntentLogFailed {
+                idempotency_key: intent.idempotency_key.clone(),
+                reason: error.to_string(),
+            })?;
+
+        let to = match self.fire_event(from, event, context) {
+            Ok(to) => to,
+            Err(error) => {
+                if !error.after_actions() {
+                    let _ = log.complete(&intent.idempotency_key);
+                }
+                return Err(error);
+            }
+        };
+        save_fired(repo, key, &to, version)?;
+        let _ = log.complete(&intent.idempotency_key);
+        Ok(to)
+    }
+
+    /// Resolve the event of `item` from the current state of its entity,
+    /// without firing it
+    ///
+    /// Fails with `TransitionError::EntityNotFound` when the repository has
+    /// no state for the entity.
+    pub fn recheck_intent<K>(
+        &self,
+        item: &RecoveryItem<K, S, E>,
+        context: &C,
+    ) -> Result<ResolvedTransition<S>, TransitionError<S, E>>
+    where
+        K: Debug,
+    {
+        let current = item
+            .current
+            .as_ref()
+            .ok_or_else(|| TransitionError::EntityNotFound {
+                key: format!("{:?}", item.intent.key),
+            })?;
+        self.peek(current, &item.intent.event, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{InMemoryStateRepository, StateMachineBuilderFactory, NEW_ENTITY};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum Payment {
+        Pending,
+        Charged,
+    }
+
+    impl State for Payment {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum PaymentEvent {
+        Charge,
+    }
+
+    impl Event for PaymentEvent {}
+
+    fn payment_machine() -> StateMachine<Payment, PaymentEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Payment::Pending)
+            .to(Payment::Charged)
+            .on(PaymentEvent::Charge)
+            .perform(|_s, _e, _c| {});
+        builder.build()
+    }
+
+    // Dies before completing any intent
+    struct CrashingLog(InMemoryIntentLog<u32, Payment, PaymentEvent>);
+
+    impl IntentLog<u32, Payment, PaymentEvent> for CrashingLog {
+        fn begin(&self, intent: &Intent<u32, Payment, PaymentEvent>) -> io::Result<()> {
+            self.0.begin(intent)
+        }
+
+        fn complete(&self, _idempotency_key: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn incomplete(&self) -> Vec<Intent<u32, Payment, PaymentEvent>> {
+            self.0.incomplete()
+        }
+    }
+
+    // Can't write anything
+    struct FullLog;
+
+    impl IntentLog<u32, Payment, PaymentEvent> for FullLog {
+        fn begin(&self, _intent: &Intent<u32, Payment, PaymentEvent>) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn complete(&self, _idempotency_key: &str) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn incomplete(&self) -> Vec<Intent<u32, Payment, PaymentEvent>> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_unlogged_intent_is_not_fired() {
+        let machine = payment_machine();
+        let repo = InMemoryStateRepository::new();
+        repo.save(&1, &Payment::Pending, NEW_ENTITY).unwrap();
+
+        match machine.fire_event_persisted(
+            &repo,
+            &FullLog,
+            &1,
+            PaymentEvent::Charge,
+            NoContext,
+            "c-1",
+        ) {
+            Err(TransitionError::IntentLogFailed {
+                idempotency_key,
+                reason,
+            }) => assert_eq!(
+                (idempotency_key.as_str(), reason.as_str()),
+                ("c-1", "disk full")
+            ),
+            other => panic!("expected IntentLogFailed, got {:?}", other),
+        }
+        assert_eq!(repo.load(&1), Some(Payment::Pending));
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+    }
+
+    #[test]
+    fn test_completed_intents_need_no_recovery() {
+        let machine = payment_machine();
+        let repo = InMemoryStateRepository::new();
+        let log = InMemoryIntentLog::new();
+        repo.save(&1, &Payment::Pending, NEW_ENTITY).unwrap();
+
+        let charged =
+            machine.fire_event_persisted(&repo, &log, &1, PaymentEvent::Charge, NoContext, "c-1");
+        assert_eq!(charged.unwrap(), Payment::Charged);
+        // A failed fire has an outcome too
+        assert!(machine
+            .fire_event_persisted(&repo, &log, &1, PaymentEvent::Charge, NoContext, "c-2")
+            .is_err());
+        assert!(recover_incomplete(&log, &repo).is_empty());
+    }
+
+    #[test]
+    fn test_failed_action_leaves_intent_incomplete() {
+        let mut builder = StateMachineBuilderFactory::create::<Payment, PaymentEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Payment::Pending)
+            .to(Payment::Charged)
+            .on(PaymentEvent::Charge)
+            .perform_fallible(|_s, _e, _c| Err("gateway timed out".into()));
+        let machine = builder.build();
+        let repo = InMemoryStateRepository::new();
+        let log = InMemoryIntentLog::new();
+        repo.save(&1, &Payment::Pending, NEW_ENTITY).unwrap();
+
+        assert!(matches!(
+            machine.fire_event_persisted(&repo, &log, &1, PaymentEvent::Charge, NoContext, "c-1"),
+            Err(TransitionError::ActionFailed { .. })
+        ));
+        // The charge may have gone through before the gateway timed out
+        let items = recover_incomplete(&log, &repo);
+        assert_eq!(items.len(), 1);
+        assert!(!items[0].saved());
+    }
+
+    #[test]
+    fn test_crash_surfaces_intent() {
+        let machine = payment_machine();
+        let repo = InMemoryStateRepository::new();
+        let log = CrashingLog(InMemoryIntentLog::new());
+        repo.save(&1, &Payment::Pending, NEW_ENTITY).unwrap();
+        repo.save(&2, &Payment::Pending, NEW_ENTITY).unwrap();
+
+        machine
+            .fire_event_persisted(&repo, &log, &1, PaymentEvent::Charge, NoContext, "c-1")
+            .unwrap();
+        // Entity 2 died before its state was saved
+        log.begin(&Intent {
+            key: 2,
+            from: Payment::Pending,
+            event: PaymentEvent::Charge,
+            idempotency_key: "c-2".to_string(),
+        })
+        .unwrap();
+
+        let items = recover_incomplete(&log, &repo);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].intent.idempotency_key, "c-1");
+        assert!(items[0].saved());
+        assert!(!items[1].saved());
+
+        // The saved charge no longer applies, the unsaved one still does
+        assert!(machine.recheck_intent(&items[0], &NoContext).is_err());
+        assert_eq!(
+            machine.recheck_intent(&items[1], &NoContext).unwrap().to,
+            Payment::Charged
+        );
+
+        let unknown = RecoveryItem {
+            current: None,
+            ..items[1].clone()
+        };
+        assert!(matches!(
+            machine.recheck_intent(&unknown, &NoContext),
+            Err(TransitionError::EntityNotFound { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_file_log_survives_reopen() {
+        let path = std::env::temp_dir().join(format!(
+            "rs-statemachine-intents-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let log = JsonLinesIntentLog::open(&path).unwrap();
+        let intent = |key: u32, idempotency_key: &str| Intent {
+            key,
+            from: Payment::Pending,
+            event: PaymentEvent::Charge,
+            idempotency_key: idempotency_key.to_string(),
+        };
+        log.begin(&intent(1, "c-1")).unwrap();
+        log.begin(&intent(2, "c-2")).unwrap();
+        IntentLog::<u32, Payment, PaymentEvent>::complete(&log, "c-1").unwrap();
+        drop(log);
+
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"begin\":{\"key\":3")
+            .unwrap();
+        let log = JsonLinesIntentLog::open(&path).unwrap();
+        let pending: Vec<Intent<u32, Payment, PaymentEvent>> = log.incomplete();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(pending, vec![intent(2, "c-2")]);
+        assert_eq!(log.failed_writes(), 0);
+    }
+}
@@ -0,0 +1,323 @@
+//! Serializable description of a machine definition (requires `serde` feature)
+//!
+//! `MachineIntrospection` is meant to be returned as-is from admin endpoints.
+//! It contains no closures, only whether a guard or action is present, and
+//! all lists are sorted so the same definition always serializes to the same
+//! bytes regardless of `HashMap` iteration order.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Capabilities, Context, Event, State, StateMachine, TransitionType};
+
+/// One transition of the machine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransitionIntrospection {
+    /// `"*"` for `from_any` transitions
+    pub from: String,
+    pub to: String,
+    pub event: String,
+    /// `"external"` or `"internal"`
+    pub kind: String,
+    pub guarded: bool,
+    pub has_action: bool,
+    pub required_flag: Option<String>,
+    /// Template instance the transition was stamped out by
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Always 0 without the `guards` feature
+    pub priority: u32,
+}
+
+/// Timeout configured on a state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeoutIntrospection {
+    pub state: String,
+    pub after_ms: u64,
+    pub target: String,
+    pub event: String,
+}
+
+/// Complete description of a machine definition
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineIntrospection {
+    pub id: String,
+    /// Every state appearing in a transition or declared initial or
+    /// terminal, sorted
+    pub states: Vec<String>,
+    /// Declared with `StateMachineBuilder::initial_state`
+    #[serde(default)]
+    pub initial_state: Option<String>,
+    /// Declared with `StateMachineBuilder::terminal_states`, sorted
+    #[serde(default)]
+    pub terminal_states: Vec<String>,
+    pub transitions: Vec<TransitionIntrospection>,
+    pub timeouts: Vec<TimeoutIntrospection>,
+    pub capabilities: Capabilities,
+    /// Descriptions keyed by state name
+    #[serde(default)]
+    pub state_descriptions: BTreeMap<String, String>,
+    /// Descriptions keyed by event name
+    #[serde(default)]
+    pub event_descriptions: BTreeMap<String, String>,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Describe the definition of this machine as serializable data
+    pub fn introspect(&self) -> MachineIntrospection {
+        let wildcards = self
+            .wildcard_transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+            .map(|transition| ("*".to_string(), transition));
+        let mut transitions: Vec<TransitionIntrospection> = self
+            .transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+            .map(|transition| (format!("{:?}", transition.from), transition))
+            .chain(wildcards)
+            .map(|(from, transition)| TransitionIntrospection {
+                from,
+                to: format!("{:?}", transition.to),
+                event: format!("{:?}", transition.event),
+                kind: match transition.transition_type {
+                    TransitionType::External => "external".to_string(),
+                    TransitionType::Internal => "internal".to_string(),
+                },
+                guarded: transition.is_guarded(),
+                has_action: transition.has_action(),
+                required_flag: transition.required_flag.clone(),
+                tag: transition.tag.clone(),
+                #[cfg(feature = "guards")]
+                priority: transition.priority,
+                #[cfg(not(feature = "guards"))]
+                priority: 0,
+            })
+            .collect();
+        // The candidates of a pair are stored in evaluation order, which the
+        // stable sort keeps
+        transitions.sort_by(|a, b| (&a.from, &a.event).cmp(&(&b.from, &b.event)));
+
+        let initial_state = self
+            .initial_state
+            .as_ref()
+            .map(|state| format!("{:?}", state));
+        let mut terminal_states: Vec<String> = self
+            .terminal_states
+            .iter()
+            .map(|state| format!("{:?}", state))
+            .collect();
+        terminal_states.sort();
+
+        let mut states: Vec<String> = transitions
+            .iter()
+            .flat_map(|t| [t.from.clone(), t.to.clone()])
+            .filter(|state| state != "*")
+            .chain(initial_state.clone())
+            .chain(terminal_states.iter().cloned())
+            .collect();
+        states.sort();
+        states.dedup();
+
+        #[cfg(feature = "timeout")]
+        let mut timeouts: Vec<TimeoutIntrospection> = self
+            .state_timeouts
+            .iter()
+            .filter_map(|(state, duration)| {
+                let (target, event) = self.timeout_transitions.get(state)?;
+                Some(TimeoutIntrospection {
+                    state: format!("{:?}", state),
+                    after_ms: duration.as_millis() as u64,
+                    target: format!("{:?}", target),
+                    event: format!("{:?}", event),
+                })
+            })
+            .collect();
+        #[cfg(not(feature = "timeout"))]
+        let mut timeouts: Vec<TimeoutIntrospection> = Vec::new();
+        timeouts.sort_by(|a, b| a.state.cmp(&b.state));
+
+        MachineIntrospection {
+            id: self.id.clone(),
+            states,
+            initial_state,
+            terminal_states,
+            transitions,
+            timeouts,
+            capabilities: self.capabilities(),
+            state_descriptions: self
+                .descriptions
+                .states
+                .iter()
+                .map(|(state, text)| (format!("{:?}", state), text.clone()))
+                .collect(),
+            event_descriptions: self
+                .descriptions
+                .events
+                .iter()
+                .map(|(event, text)| (format!("{:?}", event), text.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone)]
+    struct OrderContext {
+        amount: u32,
+    }
+
+    impl Context for OrderContext {}
+
+    fn described_machine() -> StateMachine<Order, OrderEvent, OrderContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .when(|_s, _e, c| c.amount > 0)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transitions()
+            .from_among(vec![Order::Placed, Order::Paid])
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(Order::Placed)
+            .on(OrderEvent::Note)
+            .perform(|_s, _e, _c| {});
+        builder
+            .describe_state(Order::Paid, "Payment captured")
+            .describe_event(OrderEvent::Note, "Nudge the customer");
+        builder
+            .initial_state(Order::Placed)
+            .terminal_states(vec![Order::Shipped, Order::Cancelled]);
+        builder.id("order").build()
+    }
+
+    #[test]
+    fn test_introspection_snapshot() {
+        let introspection = described_machine().introspect();
+        assert_eq!(introspection.id, "order");
+        assert_eq!(
+            introspection.states,
+            vec!["Cancelled", "Paid", "Placed", "Shipped"]
+        );
+        assert_eq!(introspection.initial_state.as_deref(), Some("Placed"));
+        assert_eq!(introspection.terminal_states, vec!["Cancelled", "Shipped"]);
+        let edges: Vec<String> = introspection
+            .transitions
+            .iter()
+            .map(|t| format!("{} -{}-> {} ({})", t.from, t.event, t.to, t.kind))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                "Paid -Cancel-> Cancelled (external)",
+                "Paid -Ship-> Shipped (external)",
+                "Placed -Cancel-> Cancelled (external)",
+                "Placed -Note-> Placed (internal)",
+                "Placed -Pay-> Paid (external)",
+            ]
+        );
+        let pay = &introspection.transitions[4];
+        assert!(pay.guarded && pay.has_action);
+        assert_eq!(introspection.state_descriptions["Paid"], "Payment captured");
+        assert_eq!(
+            introspection.event_descriptions["Note"],
+            "Nudge the customer"
+        );
+
+        let json = serde_json::to_string(&introspection).unwrap();
+        let parsed: MachineIntrospection = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, introspection);
+    }
+
+    #[test]
+    fn test_candidates_listed_in_evaluation_order() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .when(|_s, _e, c| c.amount > 100)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        let machine = builder.build();
+        let targets: Vec<String> = machine
+            .introspect()
+            .transitions
+            .into_iter()
+            .map(|t| t.to)
+            .collect();
+        // Registration order, not the order of the target names
+        assert_eq!(targets, vec!["Shipped", "Cancelled"]);
+
+        #[cfg(feature = "guards")]
+        {
+            let mut builder =
+                StateMachineBuilderFactory::create::<Order, OrderEvent, OrderContext>();
+            builder
+                .external_transition()
+                .from(Order::Paid)
+                .to(Order::Cancelled)
+                .on(OrderEvent::Ship)
+                .with_priority(1)
+                .perform(|_s, _e, _c| {});
+            builder
+                .external_transition()
+                .from(Order::Paid)
+                .to(Order::Shipped)
+                .on(OrderEvent::Ship)
+                .with_priority(5)
+                .when(|_s, _e, c| c.amount > 100)
+                .perform(|_s, _e, _c| {});
+            let priorities: Vec<(String, u32)> = builder
+                .build()
+                .introspect()
+                .transitions
+                .into_iter()
+                .map(|t| (t.to, t.priority))
+                .collect();
+            assert_eq!(
+                priorities,
+                vec![("Shipped".to_string(), 5), ("Cancelled".to_string(), 1)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_introspection_is_deterministic() {
+        let first = serde_json::to_string(&described_machine().introspect()).unwrap();
+        for _ in 0..5 {
+            let again = serde_json::to_string(&described_machine().introspect()).unwrap();
+            assert_eq!(first, again);
+        }
+    }
+}
@@ -12,6 +12,12 @@
 //! - `visualization` - Export to DOT/PlantUML
 //! - `serde` - Serialization support
 //! - `async` - Async action support
+//! - `axum` - `EventEndpoint` for firing events from axum handlers
+//! - `binary-snapshots` - Compact binary encoding of `InstanceSnapshot`
+//! - `encryption` - AES-GCM `SnapshotCodec` for encrypting persisted data
+//! - `http-bridge` - `WebhookListener` posting transitions to HTTP endpoints
+//! - `test-util` - Testing helpers such as the virtual-time
+//!   `SimulatedScheduler`, the `Scenario` runner and `minimize_trace`
 //!
 //! # How to use rs-statemachine
 //!
@@ -51,6 +57,13 @@
 //!     .perform(|_s, _e, ctx| {
 //!         println!("Starting task {}", ctx.task_id);
 //!     });
+//! // Transitions with nothing to do need no action
+//! builder
+//!     .external_transition()
+//!     .from(MyState::Working)
+//!     .to(MyState::Done)
+//!     .on(MyEvent::Complete)
+//!     .add();
 //! let state_machine = builder.build();
 //!
 //! let context = MyContext {
@@ -60,15 +73,239 @@
 //! ```
 //!
 
-use std::collections::HashMap;
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+pub mod features;
 
+#[cfg(feature = "async")]
+mod actor;
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub use actor::*;
+#[cfg(feature = "async")]
+mod budget;
+#[cfg(feature = "async")]
+mod cancellation;
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub use cancellation::*;
+#[cfg(feature = "history")]
+mod audit;
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+pub use audit::*;
+mod approval;
+pub use approval::*;
+mod arrival;
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+pub use arrival::ArrivalExplanation;
+use arrival::IncomingIndex;
+mod atomic;
+pub use atomic::*;
+mod callbacks;
+use callbacks::Guard;
+mod capabilities;
+pub use capabilities::*;
+mod clock;
+pub use clock::*;
+mod completion;
+pub use completion::*;
+mod conditions;
+pub use conditions::{CheckedCondition, ConditionOutcome, Conditions};
+mod context_diff;
+mod context_map;
+mod context_store;
+#[cfg(feature = "metrics")]
+mod duration_stats;
+mod eventless;
+#[cfg(feature = "metrics")]
+mod failure_rate;
+#[cfg(test)]
+mod fixtures;
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub use context_diff::json_differ;
+use context_diff::ContextDiffer;
+pub use context_diff::FieldChange;
+pub use context_map::ContextMapper;
+pub use context_store::*;
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub use duration_stats::DurationStats;
+pub use eventless::CompletionTransitionBuilder;
+use eventless::{group_completions, CompletionMap, CompletionTransition};
+#[cfg(feature = "metrics")]
+use failure_rate::FailureRateSettings;
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub use failure_rate::{FailureRate, FailureRateCallback, FailureRateTrend};
+mod deadline;
+pub use deadline::Deadline;
+#[cfg(feature = "axum")]
+mod endpoint;
+use deadline::DeadlineCheck;
+#[cfg(feature = "axum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "axum")))]
+pub use endpoint::*;
+mod derived;
+use derived::{DerivationMap, DerivedInputs, DerivedValues};
+mod erased;
+pub use erased::*;
+#[cfg(all(feature = "async", feature = "history"))]
+mod history_sink;
+#[cfg(all(feature = "async", feature = "history"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "async", feature = "history"))))]
+pub use history_sink::*;
+#[cfg(feature = "history")]
+mod history_query;
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+pub use history_query::HistoryQuery;
+#[cfg(feature = "history")]
+mod funnel;
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+pub use funnel::FunnelReport;
+mod info;
+pub use info::TransitionInfo;
+mod definition;
+pub use definition::*;
+use definition::{CallbackNames, NamedGuard};
+mod descriptions;
+use descriptions::Descriptions;
+mod determinism;
+pub use determinism::*;
+mod doubles;
+pub use doubles::*;
+mod instance;
+use instance::InstanceFire;
+pub use instance::*;
+mod intent;
+pub use intent::*;
+mod lifecycle;
+pub use lifecycle::TerminalViolation;
+#[cfg(feature = "parallel")]
+mod lift;
+#[cfg(feature = "parallel")]
+#[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
+pub use lift::{lift_machine, CombinedState};
+mod listeners;
+use listeners::Listeners;
+pub use listeners::TransitionListener;
+mod locks;
+mod memo;
+pub use memo::{InMemoryResultStore, MemoizedFire, ResultStore, StoredFire};
+mod names;
+use names::Names;
+pub use names::TransitionNames;
+mod memory;
+pub use memory::*;
+#[cfg(feature = "serde")]
+mod introspection;
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub use introspection::*;
+mod outcome;
+use outcome::FireResult;
+pub use outcome::TransitionOutcome;
+mod overrides;
+use overrides::Overrides;
+pub use overrides::{ActiveOverride, OverrideGuard};
+mod peek;
+pub use peek::ResolvedTransition;
 #[cfg(feature = "history")]
-use std::sync::Mutex;
+mod projection;
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+pub use projection::{Projection, ProjectionFn};
+mod recording;
+use recording::RecordingState;
+mod reload;
+pub use reload::*;
+mod replay;
+pub use replay::ReplayError;
+mod repository;
+pub use repository::*;
+mod sequence;
+pub use sequence::*;
+#[cfg(feature = "serde")]
+mod snapshot;
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub use snapshot::*;
+mod safety;
+use safety::InstanceLimits;
+pub use safety::LoopCallback;
+mod scratchpad;
+pub use scratchpad::ProvidingTransitionBuilder;
+mod services;
+pub use services::Services;
+mod skeleton;
+pub use skeleton::*;
+mod shared;
+pub use shared::{SharedContext, SharedRef};
+mod slow;
+use slow::SlowCallbacks;
+pub use slow::{CallbackInfo, CallbackKind};
+mod state_report;
+pub use state_report::*;
+mod template;
+pub use template::*;
+#[cfg(feature = "metrics")]
+mod transition_stats;
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub use transition_stats::TransitionStats;
+#[cfg(feature = "history")]
+mod usage;
+#[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+pub use usage::*;
+#[cfg(feature = "visualization")]
+mod visualization;
+#[cfg(feature = "visualization")]
+#[cfg_attr(docsrs, doc(cfg(feature = "visualization")))]
+pub use visualization::*;
+#[cfg(feature = "http-bridge")]
+mod webhook;
+#[cfg(feature = "http-bridge")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http-bridge")))]
+pub use webhook::*;
+#[cfg_attr(not(feature = "test-util"), allow(dead_code))]
+mod trace;
+#[cfg(feature = "test-util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub use trace::{ExecutionTrace, TraceStep};
+#[cfg(not(feature = "test-util"))]
+use trace::{ExecutionTrace, TraceStep};
+mod validation;
+pub use validation::*;
+
+#[cfg(all(feature = "timeout", feature = "test-util"))]
+mod simulation;
+#[cfg(all(feature = "timeout", feature = "test-util"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "timeout", feature = "test-util"))))]
+pub use simulation::*;
+#[cfg(feature = "test-util")]
+mod minimize;
+#[cfg(feature = "test-util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub use minimize::*;
+#[cfg(feature = "test-util")]
+mod scenario;
+#[cfg(feature = "test-util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub use scenario::*;
 #[cfg(any(feature = "history", feature = "timeout", feature = "metrics"))]
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use std::time::Instant;
 
 /// Trait for state machine states
 pub trait State: Debug + Clone + Hash + Eq + PartialEq {
@@ -107,11 +344,108 @@ pub trait Context: Debug + Clone {
 pub type Condition<S, E, C> = Arc<dyn Fn(&S, &E, &C) -> bool + Send + Sync>;
 
 /// Type alias for action functions
-pub type Action<S, E, C> = Arc<dyn Fn(&S, &E, &C) -> () + Send + Sync>;
+pub type Action<S, E, C> = Arc<dyn Fn(&S, &E, &C) + Send + Sync>;
+
+/// Type alias for action functions allowed to update the context
+pub type ActionMut<S, E, C> = Arc<dyn Fn(&S, &E, &mut C) + Send + Sync>;
+
+/// Error returned by a fallible action
+pub type ActionError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Type alias for action functions that can abort the transition
+pub type FallibleAction<S, E, C> = Arc<dyn Fn(&S, &E, &C) -> Result<(), ActionError> + Send + Sync>;
+
+/// Type alias for action functions raising follow-up events, see
+/// `StateMachine::fire_event_to_completion`
+pub type FollowupAction<S, E, C> = Arc<dyn Fn(&S, &E, &C) -> Vec<E> + Send + Sync>;
 
 /// Type alias for fail callback functions
 pub type FailCallback<S, E, C> = Arc<dyn Fn(&S, &E, &C) + Send + Sync>;
 
+/// Type alias for state entry/exit action functions
+pub type StateAction<S, C> = Arc<dyn Fn(&S, &C) + Send + Sync>;
+
+/// Type alias for state entry/exit actions allowed to update the context
+pub type StateActionMut<S, C> = Arc<dyn Fn(&S, &mut C) + Send + Sync>;
+
+/// Type alias for state entry actions that can abort the transition
+pub type FallibleStateAction<S, C> = Arc<dyn Fn(&S, &C) -> Result<(), ActionError> + Send + Sync>;
+
+/// Type alias for state entry requirement checks
+pub type Requirement<C> = Arc<dyn Fn(&C) -> bool + Send + Sync>;
+
+/// Transition table keyed by source state and event
+///
+/// Candidates for a key are stored in evaluation order (highest priority
+/// first under the `guards` feature), so firing never needs to sort.
+type TransitionMap<S, E, C> = HashMap<(S, E), Box<[Transition<S, E, C>]>>;
+
+// Candidates of `from_any` transitions by event. They apply from any state
+// without a candidate of its own for the event; the `from` of these
+// transitions is a placeholder equal to `to`.
+type WildcardMap<S, E, C> = HashMap<E, Box<[Transition<S, E, C>]>>;
+
+// Target and label of each candidate passing under `RequireUnique`
+type Matched<S> = Vec<(S, Option<String>)>;
+
+/// Type alias for functions deriving the feature flag key from a context
+pub type FlagKeyFn<C> = Arc<dyn Fn(&C) -> String + Send + Sync>;
+
+/// Source of feature flags gating individual transitions
+pub trait FeatureFlagProvider: Send + Sync {
+    /// Whether `flag` is enabled for the entity identified by `ctx_key`
+    fn is_enabled(&self, flag: &str, ctx_key: &str) -> bool;
+}
+
+/// Feature flag provider together with the context key extractor
+#[derive(Clone)]
+struct FeatureFlags<C> {
+    provider: Arc<dyn FeatureFlagProvider>,
+    key: FlagKeyFn<C>,
+}
+
+/// Flag lookups made during a single fire, so candidates sharing a flag
+/// only query the provider once
+struct FlagCache<'a, C> {
+    flags: Option<&'a FeatureFlags<C>>,
+    key: Option<String>,
+    resolved: Vec<(&'a str, bool)>,
+}
+
+impl<'a, C> FlagCache<'a, C> {
+    fn new(flags: Option<&'a FeatureFlags<C>>) -> Self {
+        FlagCache {
+            flags,
+            key: None,
+            resolved: Vec::new(),
+        }
+    }
+
+    fn is_enabled(&mut self, flag: &'a str, context: &C) -> bool {
+        if let Some((_, enabled)) = self.resolved.iter().find(|(name, _)| *name == flag) {
+            return *enabled;
+        }
+        // Without a provider every flag is considered disabled
+        let enabled = match self.flags {
+            Some(flags) => {
+                let key = self.key.get_or_insert_with(|| (flags.key)(context));
+                flags.provider.is_enabled(flag, key)
+            }
+            None => false,
+        };
+        self.resolved.push((flag, enabled));
+        enabled
+    }
+
+    fn disabled_flags(&self) -> Vec<String> {
+        self.resolved
+            .iter()
+            .filter(|(_, enabled)| !enabled)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+}
+
 /// Represents a transition in the state machine
 #[derive(Clone)]
 pub struct Transition<S, E, C>
@@ -123,58 +457,486 @@ where
     from: S,
     to: S,
     event: E,
-    condition: Option<Condition<S, E, C>>,
-    action: Option<Action<S, E, C>>,
+    guard: Guard<S, E, C>,
+    action: callbacks::Action<S, E, C>,
     transition_type: TransitionType,
+    required_flag: Option<String>,
+    approval: Option<Arc<dyn ApprovalChecker<C>>>,
+    pure_action: bool,
+    // Refused by `StateMachineInstance::undo`
+    irreversible: bool,
+    // Template instance the transition was stamped out by
+    tag: Option<String>,
+    // Binding names of the guard and action, see `MachineDefinition`
+    names: CallbackNames,
     #[cfg(feature = "guards")]
     priority: u32,
 }
 
+impl<S, E, C> Transition<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Source state; a placeholder for `from_any` transitions
+    pub fn from(&self) -> &S {
+        &self.from
+    }
+
+    pub fn to(&self) -> &S {
+        &self.to
+    }
+
+    pub fn event(&self) -> &E {
+        &self.event
+    }
+
+    pub fn transition_type(&self) -> &TransitionType {
+        &self.transition_type
+    }
+
+    /// Whether a guard decides if the transition applies
+    pub fn has_condition(&self) -> bool {
+        self.is_guarded()
+    }
+
+    #[cfg(feature = "guards")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    fn is_guarded(&self) -> bool {
+        self.guard.is_guarded()
+    }
+
+    pub fn has_action(&self) -> bool {
+        self.action.is_set()
+    }
+
+    // Result of the guards, `None` if the transition has no guard
+    fn check_guards(
+        &self,
+        machine_id: &str,
+        from: &S,
+        event: &E,
+        context: &C,
+        derived: &mut DerivedValues<'_, C>,
+    ) -> Option<ConditionOutcome> {
+        self.guard
+            .check(&self.info(machine_id, from), from, event, context, derived)
+    }
+
+    // Run the action, returning its outcome, `None` if there was none
+    fn run_action(
+        &self,
+        machine_id: &str,
+        from: &S,
+        event: &E,
+        context: &mut C,
+        derived: &mut DerivedValues<'_, C>,
+    ) -> Option<Result<Vec<E>, ActionError>> {
+        self.action
+            .run(&self.info(machine_id, from), from, event, context, derived)
+    }
+}
+
 /// Type of transition
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TransitionType {
     External,
     Internal,
 }
 
+/// How a transition is chosen when several candidates pass their guards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GuardResolution {
+    /// Take the first passing candidate in priority order
+    #[default]
+    FirstMatch,
+    /// Evaluate every candidate and fail with
+    /// `TransitionError::AmbiguousTransition` if more than one passes
+    RequireUnique,
+}
+
+// Events accepted from each state with transitions of its own, and from
+// every other state, where only the wildcard transitions apply
+struct AcceptedEvents<S, E> {
+    by_state: HashMap<S, Vec<E>>,
+    wildcards: Vec<E>,
+}
+
 /// Error types for state machine operations
+///
+/// States and events are carried as values; see
+/// `TransitionError::into_legacy` for a string-only form.
 #[derive(Debug, Clone)]
-pub enum TransitionError {
+pub enum TransitionError<S, E> {
     NoValidTransition {
-        from: String,
-        event: String,
+        from: S,
+        event: E,
+        /// Events registered from `from`, ignoring guards and flags
+        accepted: Vec<E>,
+    },
+    /// Transitions exist for the pair but every guard rejected them
+    ConditionFailed {
+        from: S,
+        event: E,
+        /// Name of the `Conditions::named` guard that failed last, if any
+        guard: Option<String>,
+    },
+    FeatureDisabled {
+        from: S,
+        event: E,
+        flags: Vec<String>,
+    },
+    /// Several candidates passed under `GuardResolution::RequireUnique`
+    AmbiguousTransition {
+        from: S,
+        event: E,
+        /// Target of each passing candidate in evaluation order, with the
+        /// binding name of its guard or else its template tag
+        matched: Vec<(S, Option<String>)>,
+    },
+    StaleState {
+        expected: S,
+        actual: S,
+    },
+    EntityNotFound {
+        key: String,
+    },
+    VersionConflict {
+        key: String,
+        expected: u64,
+        actual: u64,
+    },
+    ContextLoadFailed {
+        key: String,
+        error: LoadError,
+    },
+    /// The context could not be saved after the transition ran
+    ContextSaveFailed {
+        key: String,
+        error: SaveError,
+        /// Set when the previous state could not be put back because the
+        /// entity was saved again meanwhile
+        rollback: Option<VersionConflict>,
+    },
+    OutOfOrder {
+        last: u64,
+        attempted: u64,
+    },
+    DeadlineExpired {
+        deadline: std::time::SystemTime,
+    },
+    /// The machine was archived and no longer accepts events
+    MachineArchived {
+        machine_id: String,
+    },
+    /// The transition needs an approval; see `fire_event_approved`
+    ApprovalRequired {
+        from: S,
+        event: E,
+    },
+    /// The approval checker turned down the supplied approval
+    ApprovalRejected {
+        reason: String,
+    },
+    /// Follow-up events were still queued after `depth` of them were fired,
+    /// or completion transitions went on for more than `depth` hops
+    MaxChainDepthExceeded {
+        depth: usize,
+    },
+    /// The entity is in a terminal state, which accepts no events
+    TerminalState {
+        state: S,
+    },
+    /// `event` is not on the list `state` was locked to with
+    /// `StateMachineBuilder::lock_state_events`
+    EventNotAllowedInState {
+        state: S,
+        event: E,
+    },
+    /// The instance has taken its `limit` of transitions, see
+    /// `StateMachineBuilder::max_transitions_per_instance`
+    TransitionBudgetExhausted {
+        limit: u64,
+    },
+    /// Taking the transition `(from, event, to)` would make it `repeats`
+    /// times within the recent transitions of the instance, which stays in
+    /// `from`; see `StateMachineBuilder::loop_detection`
+    LoopDetected {
+        from: S,
+        event: E,
+        to: S,
+        repeats: usize,
+    },
+    /// Completion transitions led back to a state of `path`, which lists the
+    /// states entered in order
+    CompletionLoop {
+        path: Vec<S>,
+    },
+    /// A fallible transition or entry action returned an error
+    ActionFailed {
+        source: Arc<dyn std::error::Error + Send + Sync>,
+    },
+    /// The fire doesn't match the next input of the determinism log being
+    /// replayed, see `StateMachineBuilder::with_determinism_log`
+    ReplayDiverged {
+        error: DeterminismError,
+    },
+    /// `fire_event_idempotent` found a result stored under `key` for a fire
+    /// of `event` from `from`, not for the fire attempted
+    IdempotencyKeyReused {
+        key: String,
+        from: S,
+        event: E,
+    },
+    /// Another `fire_event_idempotent` with `key` has not finished yet
+    IdempotencyKeyInUse {
+        key: String,
+    },
+    /// The intent of `fire_event_persisted` could not be written, so the
+    /// event was not fired
+    IntentLogFailed {
+        idempotency_key: String,
+        reason: String,
+    },
+    #[cfg(feature = "extended")]
+    StateRequirementFailed {
+        state: S,
+        requirement: String,
+    },
+    /// The deadline of `fire_event_async_with_deadline` passed during hop
+    /// `hop`, counting from 1, firing `event` from `from`
+    #[cfg(any(feature = "timeout", feature = "async"))]
+    Timeout {
+        hop: usize,
+        from: S,
+        event: E,
     },
-    ConditionFailed,
-    #[cfg(feature = "timeout")]
-    Timeout,
     #[cfg(feature = "async")]
     AsyncError(String),
+    /// The token passed to `fire_event_async_cancellable` was cancelled
+    /// before the async action completed
+    #[cfg(feature = "async")]
+    Cancelled {
+        from: S,
+        event: E,
+    },
+}
+
+// Debug names of `values`, comma separated
+fn debug_list<T: Debug>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{:?}", value))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-impl std::fmt::Display for TransitionError {
+impl<S: Debug, E: Debug> std::fmt::Display for TransitionError<S, E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TransitionError::NoValidTransition { from, event } => {
+            TransitionError::NoValidTransition { from, event, .. } => {
                 write!(
                     f,
-                    "No valid transition from state {} with event {}",
+                    "No valid transition from state {:?} with event {:?}",
                     from, event
                 )
             }
-            TransitionError::ConditionFailed => write!(f, "Transition condition failed"),
-            #[cfg(feature = "timeout")]
-            TransitionError::Timeout => write!(f, "State timeout occurred"),
+            TransitionError::ConditionFailed { from, event, guard } => {
+                write!(
+                    f,
+                    "Transition condition failed from state {:?} with event {:?}",
+                    from, event
+                )?;
+                match guard {
+                    Some(guard) => write!(f, " (guard {})", guard),
+                    None => Ok(()),
+                }
+            }
+            TransitionError::FeatureDisabled { from, event, flags } => {
+                write!(
+                    f,
+                    "No enabled transition from state {:?} with event {:?} (disabled flags: {})",
+                    from,
+                    event,
+                    flags.join(", ")
+                )
+            }
+            TransitionError::AmbiguousTransition {
+                from,
+                event,
+                matched,
+            } => {
+                let matched: Vec<_> = matched
+                    .iter()
+                    .map(|(to, name)| match name {
+                        Some(name) => format!("{:?} ({})", to, name),
+                        None => format!("{:?}", to),
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "Ambiguous transition from {:?} on event {:?}: guards passed for {}",
+                    from,
+                    event,
+                    matched.join(", ")
+                )
+            }
+            TransitionError::StaleState { expected, actual } => {
+                write!(
+                    f,
+                    "Stale state: expected {:?} but entity is in {:?}",
+                    expected, actual
+                )
+            }
+            TransitionError::EntityNotFound { key } => write!(f, "Entity {} not found", key),
+            TransitionError::VersionConflict {
+                key,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Entity {} was saved concurrently: expected version {} but found {}",
+                key, expected, actual
+            ),
+            TransitionError::ContextLoadFailed { key, error } => {
+                write!(
+                    f,
+                    "Context of entity {} could not be loaded: {}",
+                    key, error
+                )
+            }
+            TransitionError::ContextSaveFailed {
+                key,
+                error,
+                rollback,
+            } => {
+                write!(f, "Context of entity {} could not be saved: {}", key, error)?;
+                match rollback {
+                    Some(conflict) => write!(f, "; its state was not rolled back, {}", conflict),
+                    None => Ok(()),
+                }
+            }
+            TransitionError::DeadlineExpired { deadline } => {
+                write!(f, "Deadline {:?} has expired", deadline)
+            }
+            TransitionError::MachineArchived { machine_id } => {
+                write!(f, "State machine {} is archived", machine_id)
+            }
+            TransitionError::ApprovalRequired { from, event } => write!(
+                f,
+                "Transition from state {:?} with event {:?} requires an approval",
+                from, event
+            ),
+            TransitionError::ApprovalRejected { reason } => {
+                write!(f, "Approval rejected: {}", reason)
+            }
+            TransitionError::MaxChainDepthExceeded { depth } => {
+                write!(f, "Chain of events still going after {} hops", depth)
+            }
+            TransitionError::TerminalState { state } => {
+                write!(f, "State {:?} is terminal and accepts no events", state)
+            }
+            TransitionError::EventNotAllowedInState { state, event } => {
+                write!(f, "Event {:?} is not allowed in state {:?}", event, state)
+            }
+            TransitionError::TransitionBudgetExhausted { limit } => {
+                write!(f, "Instance has taken its {} transitions", limit)
+            }
+            TransitionError::LoopDetected {
+                from,
+                event,
+                to,
+                repeats,
+            } => write!(
+                f,
+                "Loop detected: {:?} -> {:?} on {:?} taken {} times",
+                from, to, event, repeats
+            ),
+            TransitionError::CompletionLoop { path } => {
+                write!(f, "Completion transitions loop: {}", debug_list(path))
+            }
+            TransitionError::ActionFailed { source } => write!(f, "Action failed: {}", source),
+            TransitionError::ReplayDiverged { error } => write!(f, "{}", error),
+            TransitionError::IdempotencyKeyReused { key, from, event } => write!(
+                f,
+                "Idempotency key {} was used to fire {:?} from {:?}",
+                key, event, from
+            ),
+            TransitionError::IdempotencyKeyInUse { key } => {
+                write!(f, "Idempotency key {} is held by an unfinished fire", key)
+            }
+            TransitionError::IntentLogFailed {
+                idempotency_key,
+                reason,
+            } => write!(
+                f,
+                "Intent {} could not be logged: {}",
+                idempotency_key, reason
+            ),
+            TransitionError::OutOfOrder { last, attempted } => {
+                write!(
+                    f,
+                    "Event sequence {} is not newer than last applied sequence {}",
+                    attempted, last
+                )
+            }
+            #[cfg(feature = "extended")]
+            TransitionError::StateRequirementFailed { state, requirement } => {
+                write!(
+                    f,
+                    "Requirement {} for entering state {:?} failed",
+                    requirement, state
+                )
+            }
+            #[cfg(any(feature = "timeout", feature = "async"))]
+            TransitionError::Timeout { hop, from, event } => write!(
+                f,
+                "Deadline passed on hop {} firing {:?} from {:?}",
+                hop, event, from
+            ),
             #[cfg(feature = "async")]
             TransitionError::AsyncError(msg) => write!(f, "Async error: {}", msg),
+            #[cfg(feature = "async")]
+            TransitionError::Cancelled { from, event } => {
+                write!(f, "Transition from {:?} on {:?} was cancelled", from, event)
+            }
+        }
+    }
+}
+
+impl<S: Debug, E: Debug> std::error::Error for TransitionError<S, E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransitionError::ActionFailed { source } => Some(&**source),
+            TransitionError::ReplayDiverged { error } => Some(error),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for TransitionError {}
+impl<S, E> TransitionError<S, E> {
+    // Whether a fire failing with this error got to run an action
+    pub(crate) fn after_actions(&self) -> bool {
+        matches!(
+            self,
+            TransitionError::ActionFailed { .. }
+                | TransitionError::CompletionLoop { .. }
+                | TransitionError::MaxChainDepthExceeded { .. }
+        )
+    }
+}
 
 // History tracking feature
 #[cfg(feature = "history")]
+#[cfg_attr(docsrs, doc(cfg(feature = "history")))]
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TransitionRecord<S, E>
 where
     S: State,
@@ -183,40 +945,150 @@ where
     pub from: S,
     pub to: S,
     pub event: E,
+    /// Monotonic time of the transition, used for ordering
+    ///
+    /// Left out when serializing, `wall_time` stands in for it.
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub timestamp: Instant,
+    /// Wall-clock time of the transition, for display and export
+    ///
+    /// Serialized as an RFC 3339 string in UTC.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_rfc3339"))]
+    pub wall_time: std::time::SystemTime,
+    /// Time from the start of the fire to its outcome, actions included
+    pub duration: Duration,
     pub success: bool,
+    /// Message of the error a failed transition returned
+    pub error: Option<String>,
+    /// `TransitionError::code` of that error
+    pub error_code: Option<&'static str>,
+    /// Approval supplied with `fire_event_approved`
+    pub approval: Option<ApprovalRecord>,
+    /// `StateMachine::history_epoch` when the fire started
+    pub epoch: u64,
+    /// Changes to the context, for successful fires of a machine with a
+    /// context differ
+    pub context_changes: Option<Vec<FieldChange>>,
+    /// Type of the transition taken for the event, `None` for failed fires
+    pub transition_type: Option<TransitionType>,
+    /// Whether the transition taken for the event was built with
+    /// `.irreversible()`
+    pub irreversible: bool,
+}
+
+// `2024-05-01T12:30:00.250Z`, down to the millisecond
+#[cfg(all(feature = "history", feature = "serde"))]
+fn serialize_rfc3339<Z>(time: &std::time::SystemTime, serializer: Z) -> Result<Z::Ok, Z::Error>
+where
+    Z: serde::Serializer,
+{
+    serializer.serialize_str(&rfc3339(*time))
+}
+
+#[cfg(all(feature = "history", feature = "serde"))]
+pub(crate) fn rfc3339(time: std::time::SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Inverse of `rfc3339`, `None` for anything else
+#[cfg(all(feature = "history", feature = "serde"))]
+pub(crate) fn parse_rfc3339(text: &str) -> Option<std::time::SystemTime> {
+    let bytes = text.as_bytes();
+    if bytes.len() != 24 || text.get(19..20) != Some(".") || !text.ends_with('Z') {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| text.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    let millis = field(20..23)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since 1970-01-01 from the civil date (Howard Hinnant's algorithm)
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(
+        std::time::UNIX_EPOCH
+            + Duration::from_secs(u64::try_from(secs).ok()?)
+            + Duration::from_millis(millis as u64),
+    )
 }
 
 // Metrics feature
 #[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
 #[derive(Debug, Clone)]
-pub struct StateMachineMetrics {
+pub struct StateMachineMetrics<S, E> {
     pub total_transitions: u64,
     pub successful_transitions: u64,
     pub failed_transitions: u64,
-    pub transition_durations: Vec<Duration>,
+    /// Durations of all fires, see `DurationStats`
+    pub transition_durations: DurationStats,
     pub state_visit_counts: HashMap<String, u64>,
+    /// Callbacks exceeding the slow-callback threshold, by callback name
+    pub slow_callbacks: HashMap<String, u64>,
+    /// Failed transitions by `TransitionError::code`
+    pub failures_by_code: HashMap<String, u64>,
+    /// Moving average of the failed fires per minute
+    pub failure_rate: FailureRate,
+    /// Outcomes and durations by `(from, event, to)`, see `TransitionStats`
+    pub transitions: HashMap<(S, E, S), TransitionStats>,
+}
+
+#[cfg(feature = "metrics")]
+impl<S, E> Default for StateMachineMetrics<S, E> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(feature = "metrics")]
-impl StateMachineMetrics {
+impl<S, E> StateMachineMetrics<S, E> {
     pub fn new() -> Self {
         StateMachineMetrics {
             total_transitions: 0,
             successful_transitions: 0,
             failed_transitions: 0,
-            transition_durations: Vec::new(),
+            transition_durations: DurationStats::default(),
             state_visit_counts: HashMap::new(),
+            slow_callbacks: HashMap::new(),
+            failures_by_code: HashMap::new(),
+            failure_rate: FailureRate::default(),
+            transitions: HashMap::new(),
         }
     }
 
     pub fn average_transition_time(&self) -> Option<Duration> {
-        if self.transition_durations.is_empty() {
-            None
-        } else {
-            let total: Duration = self.transition_durations.iter().sum();
-            Some(total / self.transition_durations.len() as u32)
-        }
+        self.transition_durations.mean()
     }
 
     pub fn success_rate(&self) -> f64 {
@@ -228,21 +1100,63 @@ impl StateMachineMetrics {
     }
 }
 
+// Guard fan-out statistics
+#[cfg(feature = "guards")]
+#[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuardFanoutStats {
+    /// Number of distinct `(from, event)` pairs
+    pub pairs: usize,
+    /// Largest number of candidates sharing one pair
+    pub max_candidates: usize,
+    /// Number of pairs by candidate count
+    pub distribution: std::collections::BTreeMap<usize, usize>,
+}
+
+#[cfg(feature = "guards")]
+impl GuardFanoutStats {
+    /// Number of pairs with more than one candidate, i.e. where guards and
+    /// priorities decide the outcome
+    pub fn multi_candidate_pairs(&self) -> usize {
+        self.distribution
+            .iter()
+            .filter(|(candidates, _)| **candidates > 1)
+            .map(|(_, pairs)| pairs)
+            .sum()
+    }
+}
+
 // Extended state machine features
 #[cfg(feature = "extended")]
+#[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
+#[derive(Clone)]
 pub struct StateActions<S, E, C>
 where
     S: State,
     E: Event,
     C: Context,
 {
-    pub on_entry: Option<Arc<dyn Fn(&S, &C) + Send + Sync>>,
-    pub on_exit: Option<Arc<dyn Fn(&S, &C) + Send + Sync>>,
+    pub on_entry: Option<StateAction<S, C>>,
+    pub on_exit: Option<StateAction<S, C>>,
+    pub on_entry_mut: Option<StateActionMut<S, C>>,
+    pub on_exit_mut: Option<StateActionMut<S, C>>,
+    pub on_entry_fallible: Option<FallibleStateAction<S, C>>,
+    // Set by `StateMachineBuilder::pure_state_actions`
+    pub(crate) pure: bool,
     _phantom: std::marker::PhantomData<E>,
 }
 
+// Named precondition that must hold to enter a state
+#[cfg(feature = "extended")]
+#[derive(Clone)]
+struct StateRequirement<C> {
+    name: String,
+    check: Requirement<C>,
+}
+
 // Hierarchical state support
 #[cfg(feature = "hierarchical")]
+#[cfg_attr(docsrs, doc(cfg(feature = "hierarchical")))]
 pub trait HierarchicalState: State {
     fn parent(&self) -> Option<Self>;
     fn children(&self) -> Vec<Self>;
@@ -254,6 +1168,7 @@ pub trait HierarchicalState: State {
 use async_trait::async_trait;
 
 #[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
 #[async_trait]
 pub trait AsyncAction<S, E, C>: Send + Sync
 where
@@ -264,6 +1179,9 @@ where
     async fn execute(&self, from: &S, event: &E, context: &C);
 }
 
+#[cfg(feature = "async")]
+type AsyncActionMap<S, E, C> = HashMap<(S, E), Arc<dyn AsyncAction<S, E, C>>>;
+
 /// The main state machine struct
 pub struct StateMachine<S, E, C>
 where
@@ -272,17 +1190,41 @@ where
     C: Context,
 {
     id: String,
-    transitions: HashMap<(S, E), Vec<Transition<S, E, C>>>,
+    transitions: TransitionMap<S, E, C>,
+    wildcard_transitions: WildcardMap<S, E, C>,
+    incoming: IncomingIndex<S, E>,
+    // Built on the first fire finding no transition, see `accepted_events`
+    accepted: OnceLock<AcceptedEvents<S, E>>,
+    completion_transitions: CompletionMap<S, E, C>,
     fail_callback: Option<FailCallback<S, E, C>>,
-
-    #[cfg(feature = "history")]
-    history: Arc<Mutex<Vec<TransitionRecord<S, E>>>>,
-
+    listeners: Listeners<S, E, C>,
+    context_differ: Option<ContextDiffer<C>>,
+    feature_flags: Option<FeatureFlags<C>>,
+    derivations: DerivationMap<C>,
+    services: Services,
+    guard_resolution: GuardResolution,
+    max_chain_depth: usize,
+    sequence_window: u32,
+    instance_limits: InstanceLimits<S, E>,
+    initial_state: Option<S>,
+    terminal_states: HashSet<S>,
+    locked_events: HashMap<S, HashSet<E>>,
+    clock: Arc<dyn Clock>,
+    determinism: Option<Arc<DeterminismLog>>,
+    deadline_check: Option<DeadlineCheck<E, C>>,
+    slow_callbacks: Option<SlowCallbacks<S, E>>,
     #[cfg(feature = "metrics")]
-    metrics: Arc<Mutex<StateMachineMetrics>>,
+    failure_rate: FailureRateSettings,
+    overrides: Arc<Overrides<S, E, C>>,
+    archived: Arc<AtomicBool>,
+    descriptions: Descriptions<S, E>,
+    names: Arc<Names<S, E>>,
+    recording: Arc<RecordingState<S, E>>,
 
     #[cfg(feature = "extended")]
     state_actions: HashMap<S, StateActions<S, E, C>>,
+    #[cfg(feature = "extended")]
+    state_requirements: HashMap<S, Vec<StateRequirement<C>>>,
 
     #[cfg(feature = "timeout")]
     state_timeouts: HashMap<S, Duration>,
@@ -290,7 +1232,11 @@ where
     timeout_transitions: HashMap<S, (S, E)>,
 
     #[cfg(feature = "async")]
-    async_actions: HashMap<(S, E), Box<dyn AsyncAction<S, E, C>>>,
+    async_actions: AsyncActionMap<S, E, C>,
+    #[cfg(feature = "async")]
+    cancel_callbacks: HashMap<(S, E), CancelCallback<S, E, C>>,
+    #[cfg(feature = "serde")]
+    snapshot_codec: Arc<dyn SnapshotCodec>,
 }
 
 impl<S, E, C> StateMachine<S, E, C>
@@ -300,129 +1246,801 @@ where
     C: Context,
 {
     /// Fire an event and perform state transition
-    pub fn fire_event(&self, from: S, event: E, context: C) -> Result<S, TransitionError> {
-        #[cfg(feature = "metrics")]
-        let start_time = Instant::now();
+    pub fn fire_event(
+        &self,
+        from: S,
+        event: E,
+        mut context: C,
+    ) -> Result<S, TransitionError<S, E>> {
+        self.fire_traced(from, event, &mut context, None, None, None)
+    }
 
-        #[cfg(feature = "extended")]
-        {
-            // Execute exit action for current state
-            if let Some(actions) = self.state_actions.get(&from) {
-                if let Some(on_exit) = &actions.on_exit {
-                    on_exit(&from, &context);
-                }
-            }
-        }
+    /// Fire an event, letting `perform_mut` and the mutable entry/exit
+    /// actions update `context`
+    ///
+    /// Guards still see the context read-only.
+    pub fn fire_event_mut(
+        &self,
+        from: S,
+        event: E,
+        context: &mut C,
+    ) -> Result<S, TransitionError<S, E>> {
+        self.fire_traced(from, event, context, None, None, None)
+    }
 
-        let key = (from.clone(), event.clone());
-        let result = if let Some(transitions) = self.transitions.get(&key) {
-            let mut valid_transitions = transitions.clone();
+    // Shared firing pipeline, recording each step into `trace` when given.
+    // `instance` is given when firing for a `StateMachineInstance`.
+    // `approval` is checked by transitions that require one.
+    fn fire_traced(
+        &self,
+        from: S,
+        event: E,
+        context: &mut C,
+        trace: Option<&mut ExecutionTrace>,
+        instance: Option<&InstanceFire<'_, S, E>>,
+        approval: Option<&Approval>,
+    ) -> Result<S, TransitionError<S, E>> {
+        self.fire_step(from, event, context, trace, instance, approval)
+            .map(|(outcome, _)| outcome.to)
+    }
 
-            #[cfg(feature = "guards")]
-            {
-                // Sort by priority if guards feature is enabled
-                valid_transitions.sort_by_key(|t| std::cmp::Reverse(t.priority));
-            }
+    // `fire_traced` describing the transition taken, also returning the
+    // follow-up events the action raised
+    fn fire_step(
+        &self,
+        from: S,
+        event: E,
+        context: &mut C,
+        trace: Option<&mut ExecutionTrace>,
+        instance: Option<&InstanceFire<'_, S, E>>,
+        approval: Option<&Approval>,
+    ) -> FireResult<S, E, Vec<E>> {
+        if self.listeners.is_empty() {
+            return self.fire_unobserved(from, event, context, trace, instance, approval);
+        }
+        self.notify_before(&from, &event, context);
+        let result = self.fire_unobserved(
+            from.clone(),
+            event.clone(),
+            context,
+            trace,
+            instance,
+            approval,
+        );
+        self.notify_outcome(&from, &event, context, &result);
+        result
+    }
 
-            let mut transition_result = None;
-            for transition in valid_transitions {
-                if let Some(condition) = &transition.condition {
-                    if !condition(&from, &event, &context) {
-                        continue;
-                    }
-                }
+    // `fire_step` without notifying listeners
+    #[cfg_attr(
+        not(any(feature = "history", feature = "metrics")),
+        allow(unused_variables)
+    )]
+    fn fire_unobserved(
+        &self,
+        from: S,
+        event: E,
+        context: &mut C,
+        mut trace: Option<&mut ExecutionTrace>,
+        instance: Option<&InstanceFire<'_, S, E>>,
+        approval: Option<&Approval>,
+    ) -> FireResult<S, E, Vec<E>> {
+        if self.is_archived() {
+            let error = TransitionError::MachineArchived {
+                machine_id: self.id.clone(),
+            };
+            self.run_fail_callback(&from, &event, context, &mut trace);
+            trace::record(&mut trace, || TraceStep::Failed {
+                error: error.to_string(),
+            });
+            return Err(error);
+        }
 
-                // Execute action if present
-                if let Some(action) = &transition.action {
-                    action(&from, &event, &context);
-                }
+        let start_time = Instant::now();
+        // A clear or reset while the fire runs leaves its record and metrics
+        // in the epoch it started in
+        #[cfg(feature = "history")]
+        let history_epoch = self.recording.history_epoch();
+        #[cfg(feature = "metrics")]
+        let metrics_epoch = self.recording.metrics_epoch();
 
-                transition_result = Some(Ok(transition.to.clone()));
-                break;
-            }
+        // Copy of the context to compare with once the fire succeeded
+        let context_before = self.context_differ.as_ref().map(|_| context.clone());
+
+        // The instance may refuse the transition chosen before it runs
+        let admit =
+            |to: &S| instance.map_or(Ok(()), |instance| (instance.admit)(&from, &event, to));
+
+        let key = (from.clone(), event.clone());
+        let result = if self.is_terminal(&from) {
+            Err(TransitionError::TerminalState {
+                state: from.clone(),
+            })
+        } else if !self.is_event_allowed(&from, &event) {
+            Err(TransitionError::EventNotAllowedInState {
+                state: from.clone(),
+                event: event.clone(),
+            })
+        } else if let Some(deadline) = self.expired_deadline(&event, context) {
+            Err(TransitionError::DeadlineExpired { deadline })
+        } else if let Some((target, action)) = self.find_override(&from, &event) {
+            trace::record(&mut trace, || TraceStep::Override {
+                to: self.names.state(&target),
+            });
+            // An override moves the entity like an external transition would
+            let action = |context: &mut C| {
+                action.map(|action| {
+                    action(&from, &event, context);
+                    Ok(Vec::new())
+                })
+            };
+            admit(&target)
+                .and_then(|()| {
+                    self.move_externally(
+                        &from,
+                        (&event, &target),
+                        context,
+                        &mut trace,
+                        false,
+                        action,
+                    )
+                })
+                .map(|followups| {
+                    (
+                        TransitionOutcome::overridden(&from, &event, target),
+                        followups,
+                    )
+                })
+        } else if let Some(transitions) = self.candidates(&key) {
+            let mut flags = FlagCache::new(self.feature_flags.as_ref());
+            let mut derived = DerivedValues::new(&self.derivations, &self.services);
+            let mut failed_guard = None;
+            let guard_context: &C = context;
+            let selection = self.logged_selection(&from, &event, transitions, || {
+                self.select(
+                    transitions,
+                    guard_context,
+                    &mut flags,
+                    &mut trace,
+                    &mut failed_guard,
+                    |transition| {
+                        let transition_key = Some((&transition.event, &transition.to));
+                        self.timed(CallbackKind::Guard, &from, transition_key, || {
+                            transition.check_guards(
+                                &self.id,
+                                &from,
+                                &event,
+                                guard_context,
+                                &mut derived,
+                            )
+                        })
+                    },
+                )
+                .map_err(|matched| TransitionError::AmbiguousTransition {
+                    from: from.clone(),
+                    event: event.clone(),
+                    matched,
+                })
+            });
+
+            let transition_result = match selection {
+                Err(error) => Some(Err(error)),
+                Ok(Some(transition)) => {
+                    let taken = check_approval(
+                        transition.approval.as_ref(),
+                        approval,
+                        &from,
+                        &event,
+                        context,
+                    )
+                    .and_then(|()| admit(&transition.to))
+                    .and_then(|()| {
+                        self.take_transition(
+                            transition,
+                            &from,
+                            &event,
+                            context,
+                            &mut derived,
+                            &mut trace,
+                        )
+                    })
+                    .and_then(|(to, followups)| {
+                        let to = if transition.transition_type == TransitionType::Internal {
+                            to
+                        } else {
+                            self.settle(to, &event, context, &mut trace)?
+                        };
+                        let wildcard = !self.transitions.contains_key(&key);
+                        let outcome =
+                            TransitionOutcome::taken(transition, &from, &event, to, wildcard);
+                        Ok((outcome, followups))
+                    });
+                    Some(taken)
+                }
+                Ok(None) => None,
+            };
 
             transition_result.unwrap_or_else(|| {
-                if let Some(fail_callback) = &self.fail_callback {
-                    fail_callback(&from, &event, &context);
+                let disabled = flags.disabled_flags();
+                if disabled.is_empty() {
+                    Err(TransitionError::ConditionFailed {
+                        from: from.clone(),
+                        event: event.clone(),
+                        guard: failed_guard.map(str::to_string),
+                    })
+                } else {
+                    Err(TransitionError::FeatureDisabled {
+                        from: from.clone(),
+                        event: event.clone(),
+                        flags: disabled,
+                    })
                 }
-                Err(TransitionError::NoValidTransition {
-                    from: format!("{:?}", from),
-                    event: format!("{:?}", event),
-                })
             })
         } else {
-            if let Some(fail_callback) = &self.fail_callback {
-                fail_callback(&from, &event, &context);
-            }
             Err(TransitionError::NoValidTransition {
-                from: format!("{:?}", from),
-                event: format!("{:?}", event),
+                from: from.clone(),
+                event: event.clone(),
+                accepted: self.accepted_events(&from),
             })
         };
+        if result.is_err() {
+            self.run_fail_callback(&from, &event, context, &mut trace);
+        }
 
-        #[cfg(feature = "extended")]
-        {
-            // Execute entry action for new state
-            if let Ok(new_state) = &result {
-                if let Some(actions) = self.state_actions.get(new_state) {
-                    if let Some(on_entry) = &actions.on_entry {
-                        on_entry(new_state, &context);
-                    }
-                }
+        let duration = start_time.elapsed();
+        let result = result.map(|(mut outcome, followups)| {
+            outcome.duration = duration;
+            outcome.names = self
+                .names
+                .transition(&outcome.from, Some(&outcome.to), &outcome.event);
+            if let (Some(differ), Some(before)) = (&self.context_differ, &context_before) {
+                outcome.context_changes = differ(before, context);
             }
-        }
+            (outcome, followups)
+        });
+
+        // Time of the outcome, for the history record and the failure rate
+        #[cfg(any(feature = "history", feature = "metrics"))]
+        let now = self.clock.now();
 
         #[cfg(feature = "history")]
         {
+            let timestamp = now;
+            let wall_time = self.clock.wall_time();
             let record = match &result {
-                Ok(to_state) => TransitionRecord {
-                    from: from.clone(),
-                    to: to_state.clone(),
-                    event: event.clone(),
-                    timestamp: Instant::now(),
+                Ok((outcome, _)) => TransitionRecord {
+                    from: outcome.from.clone(),
+                    to: outcome.to.clone(),
+                    event: outcome.event.clone(),
+                    timestamp,
+                    wall_time,
+                    duration,
                     success: true,
+                    error: None,
+                    error_code: None,
+                    approval: approval.map(ApprovalRecord::from),
+                    epoch: history_epoch,
+                    context_changes: self
+                        .context_differ
+                        .as_ref()
+                        .map(|_| outcome.context_changes.clone()),
+                    transition_type: Some(outcome.transition_type.clone()),
+                    irreversible: outcome.irreversible,
                 },
-                Err(_) => TransitionRecord {
+                Err(error) => TransitionRecord {
                     from: from.clone(),
                     to: from.clone(),
                     event: event.clone(),
-                    timestamp: Instant::now(),
+                    timestamp,
+                    wall_time,
+                    duration,
                     success: false,
+                    error: Some(error.to_string()),
+                    error_code: Some(error.code()),
+                    approval: approval.map(ApprovalRecord::from),
+                    epoch: history_epoch,
+                    context_changes: None,
+                    transition_type: None,
+                    irreversible: false,
                 },
             };
 
-            if let Ok(mut history) = self.history.lock() {
-                history.push(record);
+            if let Some(recording) = instance.and_then(|instance| instance.recording) {
+                recording.record_history([record.clone()]);
+            }
+            if self.recording.record_history([record]) {
+                trace::record(&mut trace, || TraceStep::HistoryWrite {
+                    success: result.is_ok(),
+                });
             }
         }
 
         #[cfg(feature = "metrics")]
         {
-            let duration = start_time.elapsed();
-            if let Ok(mut metrics) = self.metrics.lock() {
+            let duration = result
+                .as_ref()
+                .map_or_else(|_| start_time.elapsed(), |(outcome, _)| outcome.duration);
+            // Failed fires stay in `from`
+            let to = result.as_ref().map_or(&from, |(outcome, _)| &outcome.to);
+            let update = |metrics: &mut StateMachineMetrics<S, E>| {
                 metrics.total_transitions += 1;
-                metrics.transition_durations.push(duration);
+                metrics.transition_durations.record(duration);
+                metrics
+                    .failure_rate
+                    .record(now, result.is_err(), self.failure_rate.half_life);
+                metrics
+                    .transitions
+                    .entry((from.clone(), event.clone(), to.clone()))
+                    .or_default()
+                    .record(result.is_ok(), duration);
 
                 match &result {
-                    Ok(to_state) => {
+                    Ok((outcome, _)) => {
                         metrics.successful_transitions += 1;
-                        let state_name = format!("{:?}", to_state);
+                        let state_name = format!("{:?}", outcome.to);
                         *metrics.state_visit_counts.entry(state_name).or_insert(0) += 1;
                     }
-                    Err(_) => {
+                    Err(error) => {
                         metrics.failed_transitions += 1;
+                        *metrics
+                            .failures_by_code
+                            .entry(error.code().to_string())
+                            .or_insert(0) += 1;
                     }
                 }
+            };
+            if let Some(recording) = instance.and_then(|instance| instance.recording) {
+                recording.update_metrics(update);
             }
+            if self.recording.update_metrics_in(metrics_epoch, update) {
+                trace::record(&mut trace, || TraceStep::MetricsWrite {
+                    success: result.is_ok(),
+                });
+            }
+            self.check_failure_rate(now);
         }
 
+        trace::record(&mut trace, || match &result {
+            Ok((outcome, _)) => TraceStep::Completed {
+                to: self.names.state(&outcome.to),
+            },
+            Err(error) => TraceStep::Failed {
+                error: error.to_string(),
+            },
+        });
         result
     }
 
-    /// Verify if a transition is possible
-    pub fn verify(&self, from: S, event: E) -> bool {
-        let key = (from, event);
-        self.transitions.contains_key(&key)
+    // Fail callback, failed history entry and metrics for a fire given up
+    // outside the firing pipeline, before its transition ran or after it
+    // could not be persisted
+    #[cfg_attr(
+        not(any(feature = "history", feature = "metrics")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn record_aborted(
+        &self,
+        from: &S,
+        event: &E,
+        context: &C,
+        error: &TransitionError<S, E>,
+        started: Instant,
+    ) {
+        let duration = started.elapsed();
+        self.run_fail_callback(from, event, context, &mut None);
+
+        #[cfg(feature = "history")]
+        self.recording.record_history([TransitionRecord {
+            from: from.clone(),
+            to: from.clone(),
+            event: event.clone(),
+            timestamp: self.clock.now(),
+            wall_time: self.clock.wall_time(),
+            duration,
+            success: false,
+            error: Some(error.to_string()),
+            error_code: Some(error.code()),
+            approval: None,
+            epoch: self.recording.history_epoch(),
+            context_changes: None,
+            transition_type: None,
+            irreversible: false,
+        }]);
+
+        #[cfg(feature = "metrics")]
+        {
+            let now = self.clock.now();
+            self.recording.update_metrics(|metrics| {
+                metrics.total_transitions += 1;
+                metrics.failed_transitions += 1;
+                metrics.transition_durations.record(duration);
+                metrics
+                    .failure_rate
+                    .record(now, true, self.failure_rate.half_life);
+                metrics
+                    .transitions
+                    .entry((from.clone(), event.clone(), from.clone()))
+                    .or_default()
+                    .record(false, duration);
+                *metrics
+                    .failures_by_code
+                    .entry(error.code().to_string())
+                    .or_insert(0) += 1;
+            });
+            self.check_failure_rate(now);
+        }
+    }
+
+    // Pick the candidate to take, following the guard resolution. `check`
+    // evaluates the guards of one candidate, `None` if it has none, and the
+    // name the last failing one reported is left in `failed_guard`. Fails
+    // with the targets of every passing candidate when more than one passes
+    // under `GuardResolution::RequireUnique`.
+    fn select<'m>(
+        &'m self,
+        transitions: &'m [Transition<S, E, C>],
+        context: &C,
+        flags: &mut FlagCache<'m, C>,
+        trace: &mut Option<&mut ExecutionTrace>,
+        failed_guard: &mut Option<&'static str>,
+        mut check: impl FnMut(&'m Transition<S, E, C>) -> Option<ConditionOutcome>,
+    ) -> Result<Option<&'m Transition<S, E, C>>, Matched<S>> {
+        let mut selected: Option<&Transition<S, E, C>> = None;
+        let mut ambiguous = Vec::new();
+        for (candidate, transition) in transitions.iter().enumerate() {
+            if let Some(flag) = &transition.required_flag {
+                let enabled = flags.is_enabled(flag, context);
+                trace::record(trace, || TraceStep::FlagCheck {
+                    flag: flag.clone(),
+                    enabled,
+                });
+                if !enabled {
+                    continue;
+                }
+            }
+            if let Some(outcome) = check(transition) {
+                let passed = outcome
+                    .map_err(|name| *failed_guard = name.or(*failed_guard))
+                    .is_ok();
+                trace::record(trace, || TraceStep::Guard {
+                    candidate,
+                    to: self.names.state(&transition.to),
+                    passed,
+                });
+                if !passed {
+                    continue;
+                }
+            }
+
+            match selected {
+                None => selected = Some(transition),
+                Some(first) => {
+                    if ambiguous.is_empty() {
+                        ambiguous.push((first.to.clone(), first.label()));
+                    }
+                    ambiguous.push((transition.to.clone(), transition.label()));
+                }
+            }
+            if self.guard_resolution == GuardResolution::FirstMatch {
+                break;
+            }
+        }
+        if ambiguous.is_empty() {
+            Ok(selected)
+        } else {
+            Err(ambiguous)
+        }
+    }
+
+    // Run the selected transition, unless a requirement of its target fails
+    fn take_transition(
+        &self,
+        transition: &Transition<S, E, C>,
+        from: &S,
+        event: &E,
+        context: &mut C,
+        derived: &mut DerivedValues<'_, C>,
+        trace: &mut Option<&mut ExecutionTrace>,
+    ) -> Result<(S, Vec<E>), TransitionError<S, E>> {
+        let to = &transition.to;
+        let action =
+            |context: &mut C| transition.run_action(&self.id, from, event, context, derived);
+        // Internal transitions stay in the state: no requirements, no exit or
+        // entry actions
+        let followups = if transition.transition_type == TransitionType::External {
+            let fallible = transition.action.is_fallible();
+            self.move_externally(from, (event, to), context, trace, fallible, action)?
+        } else {
+            self.run_transition_action(from, event, to, context, trace, action)?
+        };
+        Ok((to.clone(), followups))
+    }
+
+    // Leave `from` for `to`: check the requirements of `to`, then run the
+    // exit action, `action` and the entry action. A `fallible` action runs
+    // before the exit action, so its failure leaves the source state as is.
+    fn move_externally(
+        &self,
+        from: &S,
+        (event, to): (&E, &S),
+        context: &mut C,
+        trace: &mut Option<&mut ExecutionTrace>,
+        fallible: bool,
+        action: impl FnOnce(&mut C) -> Option<Result<Vec<E>, ActionError>>,
+    ) -> Result<Vec<E>, TransitionError<S, E>> {
+        #[cfg(feature = "extended")]
+        {
+            if let Some(requirement) = self.failed_requirement(to, context) {
+                trace::record(trace, || TraceStep::RequirementFailed {
+                    state: self.names.state(to),
+                    requirement: requirement.to_string(),
+                });
+                return Err(TransitionError::StateRequirementFailed {
+                    state: to.clone(),
+                    requirement: requirement.to_string(),
+                });
+            }
+        }
+
+        let (before_exit, after_exit) = if fallible {
+            (Some(action), None)
+        } else {
+            (None, Some(action))
+        };
+        let mut followups = match before_exit {
+            Some(action) => self.run_transition_action(from, event, to, context, trace, action)?,
+            None => Vec::new(),
+        };
+
+        #[cfg(feature = "extended")]
+        self.run_exit_action(from, context, trace);
+
+        if let Some(action) = after_exit {
+            followups = self.run_transition_action(from, event, to, context, trace, action)?;
+        }
+
+        #[cfg(feature = "extended")]
+        self.run_entry_action(to, context, trace)
+            .map_err(|source| TransitionError::ActionFailed {
+                source: source.into(),
+            })?;
+
+        Ok(followups)
+    }
+
+    // Run the action of a transition from `from` to `to`, if it has one
+    fn run_transition_action(
+        &self,
+        from: &S,
+        event: &E,
+        to: &S,
+        context: &mut C,
+        trace: &mut Option<&mut ExecutionTrace>,
+        action: impl FnOnce(&mut C) -> Option<Result<Vec<E>, ActionError>>,
+    ) -> Result<Vec<E>, TransitionError<S, E>> {
+        let Some(outcome) = self.timed(CallbackKind::Action, from, Some((event, to)), || {
+            action(context)
+        }) else {
+            return Ok(Vec::new());
+        };
+        trace::record(trace, || TraceStep::Action {
+            from: self.names.state(from),
+            to: self.names.state(to),
+            event: self.names.event(event),
+        });
+        outcome.map_err(|source| TransitionError::ActionFailed {
+            source: source.into(),
+        })
+    }
+
+    #[cfg(feature = "extended")]
+    fn run_exit_action(&self, state: &S, context: &mut C, trace: &mut Option<&mut ExecutionTrace>) {
+        let Some(actions) = self.state_actions.get(state) else {
+            return;
+        };
+        if let Some(on_exit) = &actions.on_exit {
+            self.timed(CallbackKind::Exit, state, None, || on_exit(state, context));
+        } else if let Some(on_exit) = &actions.on_exit_mut {
+            self.timed(CallbackKind::Exit, state, None, || on_exit(state, context));
+        } else {
+            return;
+        }
+        trace::record(trace, || TraceStep::ExitAction {
+            state: self.names.state(state),
+        });
+    }
+
+    #[cfg(feature = "extended")]
+    fn run_entry_action(
+        &self,
+        state: &S,
+        context: &mut C,
+        trace: &mut Option<&mut ExecutionTrace>,
+    ) -> Result<(), ActionError> {
+        let Some(actions) = self.state_actions.get(state) else {
+            return Ok(());
+        };
+        let outcome = if let Some(on_entry) = &actions.on_entry {
+            self.timed(CallbackKind::Entry, state, None, || {
+                on_entry(state, context)
+            });
+            Ok(())
+        } else if let Some(on_entry) = &actions.on_entry_mut {
+            self.timed(CallbackKind::Entry, state, None, || {
+                on_entry(state, context)
+            });
+            Ok(())
+        } else if let Some(on_entry) = &actions.on_entry_fallible {
+            self.timed(CallbackKind::Entry, state, None, || {
+                on_entry(state, context)
+            })
+        } else {
+            return Ok(());
+        };
+        trace::record(trace, || TraceStep::EntryAction {
+            state: self.names.state(state),
+        });
+        outcome
+    }
+
+    fn run_fail_callback(
+        &self,
+        from: &S,
+        event: &E,
+        context: &C,
+        trace: &mut Option<&mut ExecutionTrace>,
+    ) {
+        if let Some(fail_callback) = &self.fail_callback {
+            fail_callback(from, event, context);
+            trace::record(trace, || TraceStep::FailCallback);
+        }
+    }
+
+    #[cfg(feature = "extended")]
+    /// Name of the first requirement of `state` that `context` does not meet
+    fn failed_requirement(&self, state: &S, context: &C) -> Option<&str> {
+        self.state_requirements
+            .get(state)?
+            .iter()
+            .find(|requirement| !(requirement.check)(context))
+            .map(|requirement| requirement.name.as_str())
+    }
+
+    /// Verify if a transition is registered for `event` out of `from`
+    ///
+    /// Guards are not evaluated; see `can_fire`.
+    pub fn verify(&self, from: &S, event: &E) -> bool {
+        self.candidates(&(from.clone(), event.clone())).is_some()
+    }
+
+    /// Whether firing `event` from `from` with `context` would take a
+    /// transition
+    ///
+    /// Whether `peek` resolves a transition: candidates are walked in the
+    /// same order and with the same guard resolution as `fire_event`,
+    /// checking feature flags, approvals and target state requirements too.
+    /// No action runs and nothing is recorded, though guards themselves are
+    /// called.
+    pub fn can_fire(&self, from: &S, event: &E, context: &C) -> bool {
+        self.peek(from, event, context).is_ok()
+    }
+
+    // Candidates for the pair, falling back to the `from_any` ones
+    fn candidates(&self, key: &(S, E)) -> Option<&[Transition<S, E, C>]> {
+        self.transitions
+            .get(key)
+            .or_else(|| self.wildcard_transitions.get(&key.1))
+            .map(|candidates| &candidates[..])
+    }
+
+    // `available_events`, from an index built on the first call
+    fn accepted_events(&self, from: &S) -> Vec<E> {
+        let accepted = self.accepted.get_or_init(|| {
+            let mut by_state = HashMap::new();
+            for (state, _) in self.transitions.keys() {
+                if !by_state.contains_key(state) {
+                    by_state.insert(state.clone(), self.available_events(state));
+                }
+            }
+            let mut wildcards: Vec<E> = self.wildcard_transitions.keys().cloned().collect();
+            wildcards.sort_by_cached_key(|event| format!("{:?}", event));
+            AcceptedEvents {
+                by_state,
+                wildcards,
+            }
+        });
+        accepted
+            .by_state
+            .get(from)
+            .unwrap_or(&accepted.wildcards)
+            .clone()
+    }
+
+    /// Events with at least one transition registered from `from`
+    ///
+    /// Guards and feature flags are not evaluated.
+    pub fn available_events(&self, from: &S) -> Vec<E> {
+        let mut events: Vec<E> = self
+            .transitions
+            .keys()
+            .filter(|(state, _)| state == from)
+            .map(|(_, event)| event.clone())
+            .collect();
+        events.extend(
+            self.wildcard_transitions
+                .keys()
+                .filter(|event| {
+                    !self
+                        .transitions
+                        .contains_key(&(from.clone(), (*event).clone()))
+                })
+                .cloned(),
+        );
+        events.sort_by_cached_key(|event| format!("{:?}", event));
+        events
+    }
+
+    /// Events that can be fired from `from`, sorted by name
+    ///
+    /// `available_events` without those rejected whatever the context: all
+    /// of them once the machine is archived or `from` is terminal, and those
+    /// a locked `from` doesn't accept. Guards are not evaluated; see
+    /// `allowed_events_with`.
+    pub fn allowed_events(&self, from: &S) -> Vec<E> {
+        if self.is_archived() || self.is_terminal(from) {
+            return Vec::new();
+        }
+        let mut events = self.available_events(from);
+        events.retain(|event| self.is_event_allowed(from, event));
+        events
+    }
+
+    /// `allowed_events` that `can_fire` with `context`, sorted by name
+    pub fn allowed_events_with(&self, from: &S, context: &C) -> Vec<E> {
+        let mut events = self.allowed_events(from);
+        events.retain(|event| self.can_fire(from, event, context));
+        events
+    }
+
+    /// Transitions registered out of a given state, in no particular order
+    ///
+    /// Candidates of one `(from, event)` pair come in evaluation order.
+    /// `from_any` transitions are listed by `from_any_transitions`.
+    pub fn transitions(&self) -> impl Iterator<Item = &Transition<S, E, C>> {
+        self.transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+    }
+
+    /// Transitions registered with `from_any`, in no particular order
+    pub fn from_any_transitions(&self) -> impl Iterator<Item = &Transition<S, E, C>> {
+        self.wildcard_transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+    }
+
+    /// States the definition mentions: sources and targets of transitions,
+    /// the initial state and terminal states
+    pub fn states(&self) -> HashSet<&S> {
+        let mut states: HashSet<&S> = self
+            .transitions()
+            .flat_map(|t| [&t.from, &t.to])
+            .chain(self.from_any_transitions().map(|t| &t.to))
+            .collect();
+        for candidates in self.completion_transitions.values() {
+            states.extend(candidates.iter().flat_map(|t| [&t.from, &t.to]));
+        }
+        states.extend(self.initial_state.as_ref());
+        states.extend(&self.terminal_states);
+        states
+    }
+
+    /// Events some transition is registered on
+    pub fn events(&self) -> HashSet<&E> {
+        self.transitions()
+            .chain(self.from_any_transitions())
+            .map(|t| &t.event)
+            .collect()
     }
 
     /// Get the ID of the state machine
@@ -430,25 +2048,218 @@ where
         &self.id
     }
 
+    /// Whether the machine was archived through its factory
+    ///
+    /// Archived machines reject every event with
+    /// `TransitionError::MachineArchived` without running any callback or
+    /// recording history; everything else keeps working.
+    pub fn is_archived(&self) -> bool {
+        self.archived.load(Ordering::Acquire)
+    }
+
+    /// Copy the definition of this machine with fresh history and metrics
+    /// and without listeners
+    fn fork(&self) -> Self {
+        StateMachine {
+            id: self.id.clone(),
+            transitions: self.transitions.clone(),
+            wildcard_transitions: self.wildcard_transitions.clone(),
+            incoming: self.incoming.clone(),
+            accepted: OnceLock::new(),
+            completion_transitions: self.completion_transitions.clone(),
+            fail_callback: self.fail_callback.clone(),
+            listeners: Vec::new(),
+            context_differ: self.context_differ.clone(),
+            feature_flags: self.feature_flags.clone(),
+            derivations: self.derivations.clone(),
+            services: self.services.clone(),
+            guard_resolution: self.guard_resolution,
+            max_chain_depth: self.max_chain_depth,
+            sequence_window: self.sequence_window,
+            instance_limits: self.instance_limits.clone(),
+            initial_state: self.initial_state.clone(),
+            terminal_states: self.terminal_states.clone(),
+            locked_events: self.locked_events.clone(),
+            clock: self.clock.clone(),
+            determinism: self.determinism.clone(),
+            deadline_check: self.deadline_check.clone(),
+            slow_callbacks: self.slow_callbacks.clone(),
+            #[cfg(feature = "metrics")]
+            failure_rate: self.failure_rate.clone(),
+            overrides: self.overrides.clone(),
+            archived: self.archived.clone(),
+            descriptions: self.descriptions.clone(),
+            names: self.names.clone(),
+            recording: Arc::new(self.recording.fresh()),
+            #[cfg(feature = "extended")]
+            state_actions: self.state_actions.clone(),
+            #[cfg(feature = "extended")]
+            state_requirements: self.state_requirements.clone(),
+            #[cfg(feature = "timeout")]
+            state_timeouts: self.state_timeouts.clone(),
+            #[cfg(feature = "timeout")]
+            timeout_transitions: self.timeout_transitions.clone(),
+            #[cfg(feature = "async")]
+            async_actions: self.async_actions.clone(),
+            #[cfg(feature = "async")]
+            cancel_callbacks: self.cancel_callbacks.clone(),
+            #[cfg(feature = "serde")]
+            snapshot_codec: self.snapshot_codec.clone(),
+        }
+    }
+
+    #[cfg(feature = "guards")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
+    /// Get the distribution of candidate transitions per `(from, event)` pair
+    pub fn guard_fanout_stats(&self) -> GuardFanoutStats {
+        let mut stats = GuardFanoutStats {
+            pairs: self.transitions.len(),
+            max_candidates: 0,
+            distribution: std::collections::BTreeMap::new(),
+        };
+        for candidates in self.transitions.values() {
+            stats.max_candidates = stats.max_candidates.max(candidates.len());
+            *stats.distribution.entry(candidates.len()).or_insert(0) += 1;
+        }
+        stats
+    }
+
     #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
     /// Get transition history
     pub fn get_history(&self) -> Vec<TransitionRecord<S, E>> {
-        self.history.lock().unwrap().clone()
+        self.recording.with_history(|records| records.to_vec())
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Visit the transition history without copying it
+    ///
+    /// Firing blocks on the history while `visitor` runs; read-only APIs
+    /// such as `verify` do not.
+    pub fn with_history<R>(&self, visitor: impl FnOnce(&[TransitionRecord<S, E>]) -> R) -> R {
+        self.recording.with_history(visitor)
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Get the history records whose wall-clock time falls in `range`
+    ///
+    /// Records are returned in monotonic order, even if the wall clock was
+    /// adjusted between them.
+    pub fn query_history<R>(&self, range: R) -> Vec<TransitionRecord<S, E>>
+    where
+        R: std::ops::RangeBounds<std::time::SystemTime>,
+    {
+        let mut records: Vec<TransitionRecord<S, E>> = self.recording.with_history(|records| {
+            records
+                .iter()
+                .filter(|record| range.contains(&record.wall_time))
+                .cloned()
+                .collect()
+        });
+        records.sort_by_key(|record| record.timestamp);
+        records
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Number of records in the history
+    pub fn history_len(&self) -> usize {
+        self.recording.with_history(|records| records.len())
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Most recent record of a transition that succeeded
+    pub fn last_successful(&self) -> Option<TransitionRecord<S, E>> {
+        self.recording
+            .with_history(|records| records.iter().rev().find(|record| record.success).cloned())
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Records the history keeps, `None` when unbounded; see
+    /// `StateMachineBuilder::with_history_capacity`
+    pub fn history_capacity(&self) -> Option<usize> {
+        self.recording.history_capacity()
     }
 
     #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
     /// Clear transition history
+    ///
+    /// Starts a new history epoch: `get_history` then only returns records of
+    /// fires started after the clear. With
+    /// `StateMachineBuilder::keep_cleared_epochs`, records of the previous
+    /// epochs, including those of fires still running during the clear, are
+    /// kept until `drain_previous_epochs` takes them; otherwise they are
+    /// dropped.
     pub fn clear_history(&self) {
-        self.history.lock().unwrap().clear();
+        self.recording.clear_history();
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Number of times the history was cleared
+    pub fn history_epoch(&self) -> u64 {
+        self.recording.history_epoch()
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Take the records cleared from the history, ordered by epoch
+    ///
+    /// Every record kept is returned by exactly one call. Empty unless the
+    /// machine was built with `StateMachineBuilder::keep_cleared_epochs`;
+    /// past the history capacity the oldest kept records are dropped.
+    pub fn drain_previous_epochs(&self) -> Vec<TransitionRecord<S, E>> {
+        self.recording.drain_previous_history()
     }
 
     #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
     /// Get metrics
-    pub fn get_metrics(&self) -> StateMachineMetrics {
-        self.metrics.lock().unwrap().clone()
+    ///
+    /// The failure rate average is decayed to the current time.
+    pub fn get_metrics(&self) -> StateMachineMetrics<S, E> {
+        let mut metrics = self.recording.with_metrics(StateMachineMetrics::clone);
+        metrics
+            .failure_rate
+            .decay(self.clock.now(), self.failure_rate.half_life);
+        metrics
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Number of times the metrics were reset
+    pub fn metrics_epoch(&self) -> u64 {
+        self.recording.metrics_epoch()
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Start a new metrics window
+    ///
+    /// `get_metrics` then only counts fires started after the reset. With
+    /// `StateMachineBuilder::keep_cleared_epochs`, the metrics of the window
+    /// that ended, completed by fires still running during the reset, are
+    /// kept until `drain_previous_metrics` takes them; otherwise they are
+    /// dropped.
+    pub fn reset_metrics(&self) {
+        self.recording.reset_metrics();
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Take the metrics of the windows ended by `reset_metrics`, with the
+    /// epoch of each, oldest first
+    pub fn drain_previous_metrics(&self) -> Vec<(u64, StateMachineMetrics<S, E>)> {
+        self.recording.drain_previous_metrics()
     }
 
     #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
     /// Add entry action for a state
     pub fn add_entry_action<F>(&mut self, state: S, action: F)
     where
@@ -457,12 +2268,19 @@ where
         let actions = self.state_actions.entry(state).or_insert(StateActions {
             on_entry: None,
             on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
             _phantom: Default::default(),
         });
         actions.on_entry = Some(Arc::new(action));
+        actions.on_entry_mut = None;
+        actions.on_entry_fallible = None;
     }
 
     #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
     /// Add exit action for a state
     pub fn add_exit_action<F>(&mut self, state: S, action: F)
     where
@@ -471,12 +2289,18 @@ where
         let actions = self.state_actions.entry(state).or_insert(StateActions {
             on_entry: None,
             on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
             _phantom: Default::default(),
         });
         actions.on_exit = Some(Arc::new(action));
+        actions.on_exit_mut = None;
     }
 
     #[cfg(feature = "timeout")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "timeout")))]
     /// Set timeout for a state
     pub fn set_state_timeout(
         &mut self,
@@ -491,41 +2315,17 @@ where
     }
 
     #[cfg(feature = "visualization")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "visualization")))]
     /// Export to DOT format
     pub fn to_dot(&self) -> String {
-        let mut dot = String::from("digraph StateMachine {\n");
-        dot.push_str("  rankdir=LR;\n");
-        dot.push_str("  node [shape=box];\n\n");
-
-        for ((from, event), transitions) in &self.transitions {
-            for transition in transitions {
-                dot.push_str(&format!(
-                    "  \"{:?}\" -> \"{:?}\" [label=\"{:?}\"];\n",
-                    from, transition.to, event
-                ));
-            }
-        }
-
-        dot.push_str("}\n");
-        dot
+        self.to_dot_with(&ExportOptions::new())
     }
 
     #[cfg(feature = "visualization")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "visualization")))]
     /// Export to PlantUML format
     pub fn to_plantuml(&self) -> String {
-        let mut uml = String::from("@startuml\n");
-
-        for ((from, event), transitions) in &self.transitions {
-            for transition in transitions {
-                uml.push_str(&format!(
-                    "{:?} --> {:?} : {:?}\n",
-                    from, transition.to, event
-                ));
-            }
-        }
-
-        uml.push_str("@enduml\n");
-        uml
+        self.to_plantuml_with(&ExportOptions::new())
     }
 }
 
@@ -537,19 +2337,25 @@ where
     C: Context + Send + Sync,
 {
     /// Fire an event asynchronously
+    ///
+    /// Listeners are notified before the async action runs.
     pub async fn fire_event_async(
         &self,
         from: S,
         event: E,
-        context: C,
-    ) -> Result<S, TransitionError> {
+        mut context: C,
+    ) -> Result<S, TransitionError<S, E>> {
         let key = (from.clone(), event.clone());
+        self.notify_before(&from, &event, &context);
 
         if let Some(async_action) = self.async_actions.get(&key) {
             async_action.execute(&from, &event, &context).await;
         }
 
-        self.fire_event(from, event, context)
+        let result =
+            self.fire_unobserved(from.clone(), event.clone(), &mut context, None, None, None);
+        self.notify_outcome(&from, &event, &context, &result);
+        result.map(|(outcome, _)| outcome.to)
     }
 }
 
@@ -562,15 +2368,64 @@ where
 {
     id: Option<String>,
     transitions: Vec<Transition<S, E, C>>,
+    wildcard_transitions: Vec<Transition<S, E, C>>,
+    completion_transitions: Vec<CompletionTransition<S, E, C>>,
     fail_callback: Option<FailCallback<S, E, C>>,
+    listeners: Listeners<S, E, C>,
+    context_differ: Option<ContextDiffer<C>>,
+    feature_flags: Option<FeatureFlags<C>>,
+    derivations: DerivationMap<C>,
+    services: Services,
+    required_services: Vec<(std::any::TypeId, &'static str)>,
+    required_derivations: Vec<(std::any::TypeId, BuildError)>,
+    guard_resolution: GuardResolution,
+    max_chain_depth: usize,
+    sequence_window: u32,
+    instance_limits: InstanceLimits<S, E>,
+    initial_state: Option<S>,
+    terminal_states: HashSet<S>,
+    locked_events: HashMap<S, HashSet<E>>,
+    clock: Arc<dyn Clock>,
+    determinism: Option<Arc<DeterminismLog>>,
+    deadline_check: Option<DeadlineCheck<E, C>>,
+    slow_callbacks: Option<SlowCallbacks<S, E>>,
+    #[cfg(feature = "metrics")]
+    failure_rate: FailureRateSettings,
+    #[cfg(feature = "metrics")]
+    duration_samples: bool,
+    fail_on_name_collision: bool,
+    // Transitions whose builder was finalized without a required part or
+    // with callbacks `require_named_callbacks` rejects
+    registration_errors: Vec<BuildError>,
+    bindings: ActionBindings<S, E, C>,
+    require_named_callbacks: bool,
+    descriptions: Descriptions<S, E>,
+    expected_states: usize,
+    history_reserve: usize,
+    #[cfg(feature = "history")]
+    history_limit: Option<usize>,
+    #[cfg(any(feature = "history", feature = "metrics"))]
+    keep_cleared_epochs: bool,
+    #[cfg(feature = "history")]
+    projections: projection::Projections<S, E>,
+    #[cfg(feature = "history")]
+    sync_history_sink: Option<Box<dyn HistorySink<S, E>>>,
     #[cfg(feature = "extended")]
     state_actions: HashMap<S, StateActions<S, E, C>>,
+    #[cfg(feature = "extended")]
+    state_requirements: HashMap<S, Vec<StateRequirement<C>>>,
     #[cfg(feature = "timeout")]
     state_timeouts: HashMap<S, Duration>,
     #[cfg(feature = "timeout")]
     timeout_transitions: HashMap<S, (S, E)>,
+    #[cfg(all(feature = "async", feature = "history"))]
+    history_sink: Option<Arc<HistorySinkHandle<S, E>>>,
+    #[cfg(feature = "async")]
+    async_actions: AsyncActionMap<S, E, C>,
     #[cfg(feature = "async")]
-    async_actions: HashMap<(S, E), Box<dyn AsyncAction<S, E, C>>>,
+    cancel_callbacks: HashMap<(S, E), CancelCallback<S, E, C>>,
+    #[cfg(feature = "serde")]
+    snapshot_codec: Arc<dyn SnapshotCodec>,
 }
 
 impl<S, E, C> StateMachineBuilder<S, E, C>
@@ -584,15 +2439,62 @@ where
         StateMachineBuilder {
             id: None,
             transitions: Vec::new(),
+            wildcard_transitions: Vec::new(),
+            completion_transitions: Vec::new(),
             fail_callback: None,
+            listeners: Vec::new(),
+            context_differ: None,
+            feature_flags: None,
+            derivations: HashMap::new(),
+            services: Services::default(),
+            required_services: Vec::new(),
+            required_derivations: Vec::new(),
+            guard_resolution: GuardResolution::FirstMatch,
+            max_chain_depth: DEFAULT_MAX_CHAIN_DEPTH,
+            sequence_window: 0,
+            instance_limits: InstanceLimits::default(),
+            initial_state: None,
+            terminal_states: HashSet::new(),
+            locked_events: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            determinism: None,
+            deadline_check: None,
+            slow_callbacks: None,
+            #[cfg(feature = "metrics")]
+            failure_rate: FailureRateSettings::default(),
+            #[cfg(feature = "metrics")]
+            duration_samples: false,
+            fail_on_name_collision: false,
+            registration_errors: Vec::new(),
+            bindings: ActionBindings::new(),
+            require_named_callbacks: false,
+            descriptions: Descriptions::default(),
+            expected_states: 0,
+            history_reserve: 0,
+            #[cfg(feature = "history")]
+            history_limit: None,
+            #[cfg(any(feature = "history", feature = "metrics"))]
+            keep_cleared_epochs: false,
+            #[cfg(feature = "history")]
+            projections: HashMap::new(),
+            #[cfg(feature = "history")]
+            sync_history_sink: None,
             #[cfg(feature = "extended")]
             state_actions: HashMap::new(),
+            #[cfg(feature = "extended")]
+            state_requirements: HashMap::new(),
             #[cfg(feature = "timeout")]
             state_timeouts: HashMap::new(),
             #[cfg(feature = "timeout")]
             timeout_transitions: HashMap::new(),
+            #[cfg(all(feature = "async", feature = "history"))]
+            history_sink: None,
             #[cfg(feature = "async")]
             async_actions: HashMap::new(),
+            #[cfg(feature = "async")]
+            cancel_callbacks: HashMap::new(),
+            #[cfg(feature = "serde")]
+            snapshot_codec: Arc::new(PassThroughCodec),
         }
     }
 
@@ -602,29 +2504,149 @@ where
         self
     }
 
-    /// Start building an external transition
-    pub fn external_transition(&mut self) -> ExternalTransitionBuilder<S, E, C> {
-        ExternalTransitionBuilder::new(self)
+    /// Reserve room for `additional` more transitions
+    ///
+    /// Only a sizing hint: building a large machine without it gives the
+    /// same result, with more reallocations along the way.
+    pub fn reserve_transitions(&mut self, additional: usize) -> &mut Self {
+        self.transitions.reserve(additional);
+        self
     }
 
-    /// Start building an internal transition
-    pub fn internal_transition(&mut self) -> InternalTransitionBuilder<S, E, C> {
-        InternalTransitionBuilder::new(self)
-    }
+    /// Size the per-state tables for `count` states
+    ///
+    /// Besides the builder's own tables, the built machine uses the hint to
+    /// preallocate its per-state visit metrics.
+    pub fn reserve_states(&mut self, count: usize) -> &mut Self {
+        self.expected_states = self.expected_states.max(count);
+        self.descriptions.states.reserve(count);
+        #[cfg(feature = "extended")]
+        {
+            self.state_actions.reserve(count);
+            self.state_requirements.reserve(count);
+        }
+        #[cfg(feature = "timeout")]
+        {
+            self.state_timeouts.reserve(count);
+            self.timeout_transitions.reserve(count);
+        }
+        self
+    }
+
+    /// Preallocate room for `capacity` history records, and transition
+    /// durations when kept with `with_duration_samples`, in the built machine
+    pub fn reserve_history(&mut self, capacity: usize) -> &mut Self {
+        self.history_reserve = capacity;
+        self
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Keep the latest `capacity` history records, dropping the oldest once
+    /// more are recorded
+    ///
+    /// The history is unbounded by default. Instances started from the
+    /// machine keep as many.
+    pub fn with_history_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.history_limit = Some(capacity);
+        self
+    }
+
+    #[cfg(any(feature = "history", feature = "metrics"))]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "history", feature = "metrics"))))]
+    /// Keep the records removed by `clear_history` and the metrics windows
+    /// ended by `reset_metrics` until `drain_previous_epochs` and
+    /// `drain_previous_metrics` take them
+    ///
+    /// Without it, clearing and resetting drop them, along with what fires
+    /// still running during the clear record for the old epoch. The kept
+    /// records are bounded by `with_history_capacity`; metrics windows are
+    /// kept until drained.
+    pub fn keep_cleared_epochs(&mut self) -> &mut Self {
+        self.keep_cleared_epochs = true;
+        self
+    }
+
+    /// Start building an external transition
+    pub fn external_transition(&mut self) -> ExternalTransitionBuilder<'_, S, E, C> {
+        ExternalTransitionBuilder::new(self)
+    }
+
+    /// Start building an internal transition
+    pub fn internal_transition(&mut self) -> InternalTransitionBuilder<'_, S, E, C> {
+        InternalTransitionBuilder::new(self)
+    }
 
     /// Start building external transitions from multiple states
-    pub fn external_transitions(&mut self) -> ExternalTransitionsBuilder<S, E, C> {
+    pub fn external_transitions(&mut self) -> ExternalTransitionsBuilder<'_, S, E, C> {
         ExternalTransitionsBuilder::new(self)
     }
 
-    /// Set fail callback
+    /// Run `callback` with the `from`, `event` and context of every fire
+    /// that fails, whatever the error
+    ///
+    /// Errors raised around a fire rather than by it don't run it: those
+    /// rejecting the event before the machine fires, such as
+    /// `TransitionError::EntityNotFound`, `StaleState`, `OutOfOrder` or the
+    /// idempotency key errors, and the `VersionConflict` of
+    /// `fire_event_inferred` and `fire_event_sequenced`, whose fire succeeded.
     pub fn set_fail_callback(&mut self, callback: FailCallback<S, E, C>) -> &mut Self {
         self.fail_callback = Some(callback);
         self
     }
 
+    /// Set the feature flag provider consulted for flag-gated transitions
+    ///
+    /// `key` derives the entity key passed to the provider from the context.
+    pub fn with_feature_flags<F>(
+        &mut self,
+        provider: Arc<dyn FeatureFlagProvider>,
+        key: F,
+    ) -> &mut Self
+    where
+        F: Fn(&C) -> String + Send + Sync + 'static,
+    {
+        self.feature_flags = Some(FeatureFlags {
+            provider,
+            key: Arc::new(key),
+        });
+        self
+    }
+
+    /// Read time from `clock` instead of the system clocks
+    pub fn with_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+
+    #[cfg(all(feature = "async", feature = "history"))]
+    #[cfg_attr(docsrs, doc(cfg(all(feature = "async", feature = "history"))))]
+    /// Also hand every history record to `sink`, without waiting for it
+    pub fn with_history_sink(&mut self, sink: Arc<HistorySinkHandle<S, E>>) -> &mut Self {
+        self.history_sink = Some(sink);
+        self
+    }
+
+    /// Choose how candidates passing their guards are resolved
+    pub fn with_guard_resolution(&mut self, resolution: GuardResolution) -> &mut Self {
+        self.guard_resolution = resolution;
+        self
+    }
+
+    /// Limit the follow-up events `StateMachine::fire_event_to_completion`
+    /// fires after the first one, and the completion transitions taken
+    /// after one event; `DEFAULT_MAX_CHAIN_DEPTH` by default
+    pub fn with_max_chain_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_chain_depth = depth;
+        self
+    }
+
     #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
     /// Add entry action for a state
+    ///
+    /// Runs after the action of an external transition into `state`.
+    /// Internal transitions and failed events don't run it.
     pub fn with_entry_action<F>(&mut self, state: S, action: F) -> &mut Self
     where
         F: Fn(&S, &C) + Send + Sync + 'static,
@@ -632,14 +2654,24 @@ where
         let actions = self.state_actions.entry(state).or_insert(StateActions {
             on_entry: None,
             on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
             _phantom: Default::default(),
         });
         actions.on_entry = Some(Arc::new(action));
+        actions.on_entry_mut = None;
+        actions.on_entry_fallible = None;
         self
     }
 
     #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
     /// Add exit action for a state
+    ///
+    /// Runs once an external transition out of `state` has been selected,
+    /// before its action. Internal transitions and failed events don't run it.
     pub fn with_exit_action<F>(&mut self, state: S, action: F) -> &mut Self
     where
         F: Fn(&S, &C) + Send + Sync + 'static,
@@ -647,13 +2679,110 @@ where
         let actions = self.state_actions.entry(state).or_insert(StateActions {
             on_entry: None,
             on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
             _phantom: Default::default(),
         });
         actions.on_exit = Some(Arc::new(action));
+        actions.on_exit_mut = None;
+        self
+    }
+
+    #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
+    /// Add an entry action for a state that may update the context
+    pub fn with_entry_action_mut<F>(&mut self, state: S, action: F) -> &mut Self
+    where
+        F: Fn(&S, &mut C) + Send + Sync + 'static,
+    {
+        let actions = self.state_actions.entry(state).or_insert(StateActions {
+            on_entry: None,
+            on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
+            _phantom: Default::default(),
+        });
+        actions.on_entry = None;
+        actions.on_entry_mut = Some(Arc::new(action));
+        actions.on_entry_fallible = None;
+        self
+    }
+
+    #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
+    /// Add an entry action for a state whose `Err` aborts the transition
+    ///
+    /// The exit and transition actions have run by then; the fire returns
+    /// `TransitionError::ActionFailed` and is recorded as failed.
+    pub fn with_entry_action_fallible<F>(&mut self, state: S, action: F) -> &mut Self
+    where
+        F: Fn(&S, &C) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        let actions = self.state_actions.entry(state).or_insert(StateActions {
+            on_entry: None,
+            on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
+            _phantom: Default::default(),
+        });
+        actions.on_entry = None;
+        actions.on_entry_mut = None;
+        actions.on_entry_fallible = Some(Arc::new(action));
+        self
+    }
+
+    #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
+    /// Add an exit action for a state that may update the context
+    pub fn with_exit_action_mut<F>(&mut self, state: S, action: F) -> &mut Self
+    where
+        F: Fn(&S, &mut C) + Send + Sync + 'static,
+    {
+        let actions = self.state_actions.entry(state).or_insert(StateActions {
+            on_entry: None,
+            on_exit: None,
+            on_entry_mut: None,
+            on_exit_mut: None,
+            on_entry_fallible: None,
+            pure: false,
+            _phantom: Default::default(),
+        });
+        actions.on_exit = None;
+        actions.on_exit_mut = Some(Arc::new(action));
+        self
+    }
+
+    #[cfg(feature = "extended")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "extended")))]
+    /// Add a named requirement that must hold to enter `state`
+    ///
+    /// Requirements are checked, in registration order, when an external
+    /// transition targeting `state` has been selected and before its action
+    /// and the entry action run. The first failing requirement aborts the
+    /// transition with `TransitionError::StateRequirementFailed`. A
+    /// completion transition into `state` doesn't apply unless they all hold.
+    pub fn state_requires<F>(&mut self, state: S, name: &str, requirement: F) -> &mut Self
+    where
+        F: Fn(&C) -> bool + Send + Sync + 'static,
+    {
+        self.state_requirements
+            .entry(state)
+            .or_default()
+            .push(StateRequirement {
+                name: name.to_string(),
+                check: Arc::new(requirement),
+            });
         self
     }
 
     #[cfg(feature = "timeout")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "timeout")))]
     /// Set timeout for a state
     pub fn with_state_timeout(
         &mut self,
@@ -669,40 +2798,172 @@ where
     }
 
     /// Build the state machine
+    ///
+    /// # Panics
+    ///
+    /// If a transition builder was finalized without its source state,
+    /// target state or event, a terminal state has a transition out of it,
+    /// a locked state a transition on an event it doesn't allow, a required
+    /// service was not provided, or a `when_derived` / `perform_derived`
+    /// callback needs a value no `with_derived` derivation produces.
+    /// `try_build` reports these, and the other problems it checks for, as
+    /// errors instead.
     pub fn build(self) -> StateMachine<S, E, C> {
+        match self.registration_errors.first() {
+            Some(BuildError::IncompleteTransition { missing, .. }) => {
+                panic!("{} is required", missing)
+            }
+            Some(error) => panic!("{}", error),
+            None => {}
+        }
+        if let Some((state, event)) = self.disallowed_transitions().first() {
+            panic!(
+                "event {:?} is not allowed in locked state {:?}",
+                event, state
+            );
+        }
+        match self.outgoing_from_terminal().first() {
+            Some((state, Some(event))) => {
+                panic!("terminal state {:?} has a transition on {:?}", state, event)
+            }
+            Some((state, None)) => {
+                panic!("terminal state {:?} has a completion transition", state)
+            }
+            None => {}
+        }
+        if let Some(BuildError::MissingService { service }) = self.missing_services().first() {
+            panic!("service {} is required but was not provided", service);
+        }
+        if let Some(error) = self.missing_derivations().first() {
+            panic!("{}", error);
+        }
         let id = self.id.unwrap_or_else(|| "StateMachine".to_string());
-        let mut transitions_map = HashMap::new();
+        let recording = RecordingState::with_capacity(self.history_reserve, self.expected_states);
+        #[cfg(any(feature = "history", feature = "metrics"))]
+        let recording = recording.with_cleared_epochs(self.keep_cleared_epochs);
+        #[cfg(feature = "history")]
+        let recording = recording
+            .with_history_capacity(self.history_limit)
+            .with_projections(self.projections)
+            .with_sync_sink(self.sync_history_sink);
+        #[cfg(all(feature = "async", feature = "history"))]
+        let recording = recording.with_sink(self.history_sink);
+        #[cfg(feature = "metrics")]
+        let recording =
+            recording.with_duration_samples(self.duration_samples, self.history_reserve);
 
+        // The transition count bounds the number of groups, so grouping never
+        // rehashes; the final map is then sized from the actual group count
+        let mut grouped = HashMap::with_capacity(self.transitions.len());
         for transition in self.transitions {
             let key = (transition.from.clone(), transition.event.clone());
-            transitions_map
+            grouped
                 .entry(key)
-                .or_insert_with(Vec::new)
+                .or_insert_with(|| Vec::with_capacity(1))
                 .push(transition);
         }
 
-        StateMachine {
+        let mut transitions_map = HashMap::with_capacity(grouped.len());
+        transitions_map.extend(
+            grouped
+                .into_iter()
+                .map(|(key, candidates)| (key, Self::order_candidates(candidates))),
+        );
+
+        let mut wildcards: HashMap<E, Vec<_>> = HashMap::new();
+        for transition in self.wildcard_transitions {
+            wildcards
+                .entry(transition.event.clone())
+                .or_default()
+                .push(transition);
+        }
+        let wildcard_transitions = wildcards
+            .into_iter()
+            .map(|(event, candidates)| (event, Self::order_candidates(candidates)))
+            .collect();
+        let incoming = IncomingIndex::new(&transitions_map, &wildcard_transitions);
+
+        let mut machine = StateMachine {
             id,
             transitions: transitions_map,
+            wildcard_transitions,
+            incoming,
+            accepted: OnceLock::new(),
+            completion_transitions: group_completions(self.completion_transitions),
             fail_callback: self.fail_callback,
-            #[cfg(feature = "history")]
-            history: Arc::new(Mutex::new(Vec::new())),
+            listeners: self.listeners,
+            context_differ: self.context_differ,
+            feature_flags: self.feature_flags,
+            derivations: self.derivations,
+            services: self.services,
+            guard_resolution: self.guard_resolution,
+            max_chain_depth: self.max_chain_depth,
+            sequence_window: self.sequence_window,
+            instance_limits: self.instance_limits,
+            initial_state: self.initial_state,
+            terminal_states: self.terminal_states,
+            locked_events: self.locked_events,
+            clock: determinism::logged_clock(self.clock, self.determinism.as_ref()),
+            determinism: self.determinism,
+            deadline_check: self.deadline_check,
+            slow_callbacks: self.slow_callbacks,
             #[cfg(feature = "metrics")]
-            metrics: Arc::new(Mutex::new(StateMachineMetrics::new())),
+            failure_rate: self.failure_rate,
+            overrides: Arc::default(),
+            archived: Arc::default(),
+            descriptions: self.descriptions,
+            names: Arc::default(),
+            recording: Arc::new(recording),
             #[cfg(feature = "extended")]
             state_actions: self.state_actions,
+            #[cfg(feature = "extended")]
+            state_requirements: self.state_requirements,
             #[cfg(feature = "timeout")]
             state_timeouts: self.state_timeouts,
             #[cfg(feature = "timeout")]
             timeout_transitions: self.timeout_transitions,
             #[cfg(feature = "async")]
             async_actions: self.async_actions,
-        }
+            #[cfg(feature = "async")]
+            cancel_callbacks: self.cancel_callbacks,
+            #[cfg(feature = "serde")]
+            snapshot_codec: self.snapshot_codec,
+        };
+        machine.names = Arc::new(Names::new(&machine));
+        machine
     }
 
     fn add_transition(&mut self, transition: Transition<S, E, C>) {
+        if self.require_named_callbacks {
+            let anonymous = transition.anonymous_callbacks();
+            if !anonymous.is_empty() {
+                for kind in anonymous {
+                    self.registration_errors
+                        .push(BuildError::AnonymousCallback {
+                            kind,
+                            from: format!("{:?}", transition.from),
+                            event: format!("{:?}", transition.event),
+                        });
+                }
+                return;
+            }
+        }
         self.transitions.push(transition);
     }
+
+    /// Put the candidates of one `(from, event)` pair in evaluation order
+    #[allow(unused_mut)]
+    fn order_candidates(mut candidates: Vec<Transition<S, E, C>>) -> Box<[Transition<S, E, C>]> {
+        #[cfg(feature = "guards")]
+        {
+            // Higher priority first; the stable sort keeps registration order
+            // for equal priorities. A single candidate needs no ordering.
+            if candidates.len() > 1 {
+                candidates.sort_by_key(|t| std::cmp::Reverse(t.priority));
+            }
+        }
+        candidates.into_boxed_slice()
+    }
 }
 
 impl<S, E, C> Default for StateMachineBuilder<S, E, C>
@@ -726,9 +2987,17 @@ where
     builder: &'a mut StateMachineBuilder<S, E, C>,
     from: Option<S>,
     to: Option<S>,
-    event: Option<E>,
-    condition: Option<Condition<S, E, C>>,
-    action: Option<Action<S, E, C>>,
+    events: Vec<E>,
+    guard: Guard<S, E, C>,
+    action: callbacks::Action<S, E, C>,
+    derived: DerivedInputs,
+    required_flag: Option<String>,
+    approval: Option<Arc<dyn ApprovalChecker<C>>>,
+    pure_action: bool,
+    // Set with `when_named` and `perform_named`
+    named_guard: Option<NamedGuard<S, E, C>>,
+    named_action: Option<String>,
+    irreversible: bool,
     #[cfg(feature = "guards")]
     priority: u32,
 }
@@ -744,9 +3013,16 @@ where
             builder,
             from: None,
             to: None,
-            event: None,
-            condition: None,
-            action: None,
+            events: Vec::new(),
+            guard: Guard::Unguarded,
+            action: callbacks::Action::Nothing,
+            derived: DerivedInputs::default(),
+            required_flag: None,
+            approval: None,
+            pure_action: false,
+            named_guard: None,
+            named_action: None,
+            irreversible: false,
             #[cfg(feature = "guards")]
             priority: 0,
         }
@@ -763,7 +3039,14 @@ where
     }
 
     pub fn on(mut self, event: E) -> Self {
-        self.event = Some(event);
+        self.events = vec![event];
+        self
+    }
+
+    /// Like `on`, registering one transition per event with the same guard,
+    /// action and priority
+    pub fn on_any_of(mut self, events: Vec<E>) -> Self {
+        self.events = events;
         self
     }
 
@@ -771,11 +3054,64 @@ where
     where
         F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
     {
-        self.condition = Some(Arc::new(condition));
+        self.guard = self.guard.with_plain(Conditions::of(condition));
+        self
+    }
+
+    /// Guard passing when every condition passes, see `Conditions::all_of`
+    ///
+    /// Replaces a guard set with `when`.
+    pub fn when_all(mut self, conditions: Vec<CheckedCondition<S, E, C>>) -> Self
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        self.guard = self.guard.with_plain(Conditions::all_of(conditions));
+        self
+    }
+
+    /// Guard passing when any condition passes, see `Conditions::any_of`
+    ///
+    /// Replaces a guard set with `when`.
+    pub fn when_any(mut self, conditions: Vec<CheckedCondition<S, E, C>>) -> Self
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        self.guard = self.guard.with_plain(Conditions::any_of(conditions));
+        self
+    }
+
+    /// Only consider this transition while `flag` is enabled
+    pub fn requires_flag(mut self, flag: impl Into<String>) -> Self {
+        self.required_flag = Some(flag.into());
+        self
+    }
+
+    /// Only take this transition with an approval `checker` accepts, see
+    /// `StateMachine::fire_event_approved`
+    pub fn requires_approval(mut self, checker: Arc<dyn ApprovalChecker<C>>) -> Self {
+        self.approval = Some(checker);
+        self
+    }
+
+    /// Declare that the action has no side effects outside the machine,
+    /// allowing the transition in `StateMachine::fire_atomic`
+    pub fn pure_action(mut self) -> Self {
+        self.pure_action = true;
+        self
+    }
+
+    /// Refuse to revert this transition with `StateMachineInstance::undo`
+    pub fn irreversible(mut self) -> Self {
+        self.irreversible = true;
         self
     }
 
     #[cfg(feature = "guards")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
     pub fn with_priority(mut self, priority: u32) -> Self {
         self.priority = priority;
         self
@@ -783,25 +3119,84 @@ where
 
     pub fn perform<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
     where
-        F: Fn(&S, &E, &C) -> () + Send + Sync + 'static,
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
     {
-        self.action = Some(Arc::new(action));
-        self.build()
-    }
-
-    fn build(self) -> &'a mut StateMachineBuilder<S, E, C> {
-        let transition = Transition {
-            from: self.from.expect("from state is required"),
-            to: self.to.expect("to state is required"),
-            event: self.event.expect("event is required"),
-            condition: self.condition,
-            action: self.action,
-            transition_type: TransitionType::External,
-            #[cfg(feature = "guards")]
-            priority: self.priority,
+        self.action = callbacks::Action::Plain(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with the action receiving the context mutably when
+    /// fired through `StateMachine::fire_event_mut`
+    pub fn perform_mut<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &mut C) + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Mut(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with an `Err` from the action aborting the transition
+    /// with `TransitionError::ActionFailed`
+    ///
+    /// The action runs before the exit action of the source state, so a
+    /// failure leaves the source state without having been exited.
+    pub fn perform_fallible<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Fallible(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with the returned events fired next by
+    /// `StateMachine::fire_event_to_completion`
+    pub fn perform_with_followups<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> Vec<E> + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Followups(Arc::new(action));
+        self.add()
+    }
+
+    /// Register the transition without an action
+    pub fn add(self) -> &'a mut StateMachineBuilder<S, E, C> {
+        let (Some(from), Some(to)) = (self.from.clone(), self.to.clone()) else {
+            let missing = if self.from.is_none() {
+                "from state"
+            } else {
+                "to state"
+            };
+            return self
+                .builder
+                .incomplete_transition(missing, self.from.as_ref(), &self.events);
         };
+        if self.events.is_empty() {
+            return self
+                .builder
+                .incomplete_transition("event", Some(&from), &self.events);
+        }
+        for event in self.events {
+            self.builder
+                .require_derived(self.derived, Some(&from), &event);
+            let transition = Transition {
+                from: from.clone(),
+                to: to.clone(),
+                event,
+                guard: self.guard.clone(),
+                action: self.action.clone(),
+                transition_type: TransitionType::External,
+                required_flag: self.required_flag.clone(),
+                approval: self.approval.clone(),
+                pure_action: self.pure_action,
+                irreversible: self.irreversible,
+                tag: None,
+                names: CallbackNames::of(&self.guard, &self.named_guard, &self.named_action),
+                #[cfg(feature = "guards")]
+                priority: self.priority,
+            };
 
-        self.builder.add_transition(transition);
+            self.builder.add_transition(transition);
+        }
         self.builder
     }
 }
@@ -815,9 +3210,17 @@ where
 {
     builder: &'a mut StateMachineBuilder<S, E, C>,
     within: Option<S>,
-    event: Option<E>,
-    condition: Option<Condition<S, E, C>>,
-    action: Option<Action<S, E, C>>,
+    events: Vec<E>,
+    guard: Guard<S, E, C>,
+    action: callbacks::Action<S, E, C>,
+    derived: DerivedInputs,
+    required_flag: Option<String>,
+    approval: Option<Arc<dyn ApprovalChecker<C>>>,
+    pure_action: bool,
+    // Set with `when_named` and `perform_named`
+    named_guard: Option<NamedGuard<S, E, C>>,
+    named_action: Option<String>,
+    irreversible: bool,
     #[cfg(feature = "guards")]
     priority: u32,
 }
@@ -832,9 +3235,16 @@ where
         InternalTransitionBuilder {
             builder,
             within: None,
-            event: None,
-            condition: None,
-            action: None,
+            events: Vec::new(),
+            guard: Guard::Unguarded,
+            action: callbacks::Action::Nothing,
+            derived: DerivedInputs::default(),
+            required_flag: None,
+            approval: None,
+            pure_action: false,
+            named_guard: None,
+            named_action: None,
+            irreversible: false,
             #[cfg(feature = "guards")]
             priority: 0,
         }
@@ -846,7 +3256,14 @@ where
     }
 
     pub fn on(mut self, event: E) -> Self {
-        self.event = Some(event);
+        self.events = vec![event];
+        self
+    }
+
+    /// Like `on`, registering one transition per event with the same guard,
+    /// action and priority
+    pub fn on_any_of(mut self, events: Vec<E>) -> Self {
+        self.events = events;
         self
     }
 
@@ -854,11 +3271,64 @@ where
     where
         F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
     {
-        self.condition = Some(Arc::new(condition));
+        self.guard = self.guard.with_plain(Conditions::of(condition));
+        self
+    }
+
+    /// Guard passing when every condition passes, see `Conditions::all_of`
+    ///
+    /// Replaces a guard set with `when`.
+    pub fn when_all(mut self, conditions: Vec<CheckedCondition<S, E, C>>) -> Self
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        self.guard = self.guard.with_plain(Conditions::all_of(conditions));
+        self
+    }
+
+    /// Guard passing when any condition passes, see `Conditions::any_of`
+    ///
+    /// Replaces a guard set with `when`.
+    pub fn when_any(mut self, conditions: Vec<CheckedCondition<S, E, C>>) -> Self
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        self.guard = self.guard.with_plain(Conditions::any_of(conditions));
+        self
+    }
+
+    /// Only consider this transition while `flag` is enabled
+    pub fn requires_flag(mut self, flag: impl Into<String>) -> Self {
+        self.required_flag = Some(flag.into());
+        self
+    }
+
+    /// Only take this transition with an approval `checker` accepts, see
+    /// `StateMachine::fire_event_approved`
+    pub fn requires_approval(mut self, checker: Arc<dyn ApprovalChecker<C>>) -> Self {
+        self.approval = Some(checker);
+        self
+    }
+
+    /// Declare that the action has no side effects outside the machine,
+    /// allowing the transition in `StateMachine::fire_atomic`
+    pub fn pure_action(mut self) -> Self {
+        self.pure_action = true;
+        self
+    }
+
+    /// Refuse to revert this transition with `StateMachineInstance::undo`
+    pub fn irreversible(mut self) -> Self {
+        self.irreversible = true;
         self
     }
 
     #[cfg(feature = "guards")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
     pub fn with_priority(mut self, priority: u32) -> Self {
         self.priority = priority;
         self
@@ -866,26 +3336,79 @@ where
 
     pub fn perform<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
     where
-        F: Fn(&S, &E, &C) -> () + Send + Sync + 'static,
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
     {
-        self.action = Some(Arc::new(action));
-        self.build()
-    }
-
-    fn build(self) -> &'a mut StateMachineBuilder<S, E, C> {
-        let state = self.within.expect("within state is required");
-        let transition = Transition {
-            from: state.clone(),
-            to: state,
-            event: self.event.expect("event is required"),
-            condition: self.condition,
-            action: self.action,
-            transition_type: TransitionType::Internal,
-            #[cfg(feature = "guards")]
-            priority: self.priority,
+        self.action = callbacks::Action::Plain(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with the action receiving the context mutably when
+    /// fired through `StateMachine::fire_event_mut`
+    pub fn perform_mut<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &mut C) + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Mut(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with an `Err` from the action aborting the transition
+    /// with `TransitionError::ActionFailed`
+    ///
+    /// The action runs before the exit action of the source state, so a
+    /// failure leaves the source state without having been exited.
+    pub fn perform_fallible<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Fallible(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with the returned events fired next by
+    /// `StateMachine::fire_event_to_completion`
+    pub fn perform_with_followups<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> Vec<E> + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Followups(Arc::new(action));
+        self.add()
+    }
+
+    /// Register the transition without an action
+    pub fn add(self) -> &'a mut StateMachineBuilder<S, E, C> {
+        let Some(state) = self.within.clone() else {
+            return self
+                .builder
+                .incomplete_transition("within state", None, &self.events);
         };
+        if self.events.is_empty() {
+            return self
+                .builder
+                .incomplete_transition("event", Some(&state), &self.events);
+        }
+        for event in self.events {
+            self.builder
+                .require_derived(self.derived, Some(&state), &event);
+            let transition = Transition {
+                from: state.clone(),
+                to: state.clone(),
+                event,
+                guard: self.guard.clone(),
+                action: self.action.clone(),
+                transition_type: TransitionType::Internal,
+                required_flag: self.required_flag.clone(),
+                approval: self.approval.clone(),
+                pure_action: self.pure_action,
+                irreversible: self.irreversible,
+                tag: None,
+                names: CallbackNames::of(&self.guard, &self.named_guard, &self.named_action),
+                #[cfg(feature = "guards")]
+                priority: self.priority,
+            };
 
-        self.builder.add_transition(transition);
+            self.builder.add_transition(transition);
+        }
         self.builder
     }
 }
@@ -899,10 +3422,19 @@ where
 {
     builder: &'a mut StateMachineBuilder<S, E, C>,
     from_states: Vec<S>,
+    from_any: bool,
     to: Option<S>,
-    event: Option<E>,
-    condition: Option<Condition<S, E, C>>,
-    action: Option<Action<S, E, C>>,
+    events: Vec<E>,
+    guard: Guard<S, E, C>,
+    action: callbacks::Action<S, E, C>,
+    derived: DerivedInputs,
+    required_flag: Option<String>,
+    approval: Option<Arc<dyn ApprovalChecker<C>>>,
+    pure_action: bool,
+    // Set with `when_named` and `perform_named`
+    named_guard: Option<NamedGuard<S, E, C>>,
+    named_action: Option<String>,
+    irreversible: bool,
     #[cfg(feature = "guards")]
     priority: u32,
 }
@@ -917,10 +3449,18 @@ where
         ExternalTransitionsBuilder {
             builder,
             from_states: Vec::new(),
+            from_any: false,
             to: None,
-            event: None,
-            condition: None,
-            action: None,
+            events: Vec::new(),
+            guard: Guard::Unguarded,
+            action: callbacks::Action::Nothing,
+            derived: DerivedInputs::default(),
+            required_flag: None,
+            approval: None,
+            pure_action: false,
+            named_guard: None,
+            named_action: None,
+            irreversible: false,
             #[cfg(feature = "guards")]
             priority: 0,
         }
@@ -931,13 +3471,30 @@ where
         self
     }
 
+    /// Apply the transition from every state that has no transition of its
+    /// own for the event
+    ///
+    /// Transitions registered for a specific source state always win over
+    /// this one, even when their guards reject the event.
+    pub fn from_any(mut self) -> Self {
+        self.from_any = true;
+        self
+    }
+
     pub fn to(mut self, state: S) -> Self {
         self.to = Some(state);
         self
     }
 
     pub fn on(mut self, event: E) -> Self {
-        self.event = Some(event);
+        self.events = vec![event];
+        self
+    }
+
+    /// Like `on`, registering one transition per event with the same guard,
+    /// action and priority
+    pub fn on_any_of(mut self, events: Vec<E>) -> Self {
+        self.events = events;
         self
     }
 
@@ -945,11 +3502,64 @@ where
     where
         F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
     {
-        self.condition = Some(Arc::new(condition));
+        self.guard = self.guard.with_plain(Conditions::of(condition));
+        self
+    }
+
+    /// Guard passing when every condition passes, see `Conditions::all_of`
+    ///
+    /// Replaces a guard set with `when`.
+    pub fn when_all(mut self, conditions: Vec<CheckedCondition<S, E, C>>) -> Self
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        self.guard = self.guard.with_plain(Conditions::all_of(conditions));
+        self
+    }
+
+    /// Guard passing when any condition passes, see `Conditions::any_of`
+    ///
+    /// Replaces a guard set with `when`.
+    pub fn when_any(mut self, conditions: Vec<CheckedCondition<S, E, C>>) -> Self
+    where
+        S: 'static,
+        E: 'static,
+        C: 'static,
+    {
+        self.guard = self.guard.with_plain(Conditions::any_of(conditions));
+        self
+    }
+
+    /// Only consider this transition while `flag` is enabled
+    pub fn requires_flag(mut self, flag: impl Into<String>) -> Self {
+        self.required_flag = Some(flag.into());
+        self
+    }
+
+    /// Only take this transition with an approval `checker` accepts, see
+    /// `StateMachine::fire_event_approved`
+    pub fn requires_approval(mut self, checker: Arc<dyn ApprovalChecker<C>>) -> Self {
+        self.approval = Some(checker);
+        self
+    }
+
+    /// Declare that the action has no side effects outside the machine,
+    /// allowing the transition in `StateMachine::fire_atomic`
+    pub fn pure_action(mut self) -> Self {
+        self.pure_action = true;
+        self
+    }
+
+    /// Refuse to revert this transition with `StateMachineInstance::undo`
+    pub fn irreversible(mut self) -> Self {
+        self.irreversible = true;
         self
     }
 
     #[cfg(feature = "guards")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "guards")))]
     pub fn with_priority(mut self, priority: u32) -> Self {
         self.priority = priority;
         self
@@ -957,32 +3567,96 @@ where
 
     pub fn perform<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
     where
-        F: Fn(&S, &E, &C) -> () + Send + Sync + 'static,
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Plain(Arc::new(action));
+        self.add()
+    }
+
+    /// Like `perform`, with the action receiving the context mutably when
+    /// fired through `StateMachine::fire_event_mut`
+    pub fn perform_mut<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &mut C) + Send + Sync + 'static,
     {
-        self.action = Some(Arc::new(action));
-        self.build()
+        self.action = callbacks::Action::Mut(Arc::new(action));
+        self.add()
     }
 
-    fn build(self) -> &'a mut StateMachineBuilder<S, E, C> {
-        let to = self.to.expect("to state is required");
-        let event = self.event.expect("event is required");
-        let condition = self.condition.clone();
-        let action = self.action.clone();
+    /// Like `perform`, with an `Err` from the action aborting the transition
+    /// with `TransitionError::ActionFailed`
+    ///
+    /// The action runs before the exit action of the source state, so a
+    /// failure leaves the source state without having been exited.
+    pub fn perform_fallible<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> Result<(), ActionError> + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Fallible(Arc::new(action));
+        self.add()
+    }
 
-        for from in self.from_states {
-            let transition = Transition {
+    /// Like `perform`, with the returned events fired next by
+    /// `StateMachine::fire_event_to_completion`
+    pub fn perform_with_followups<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C) -> Vec<E> + Send + Sync + 'static,
+    {
+        self.action = callbacks::Action::Followups(Arc::new(action));
+        self.add()
+    }
+
+    /// Register the transition without an action
+    pub fn add(self) -> &'a mut StateMachineBuilder<S, E, C> {
+        let from = self.from_states.first();
+        if !self.from_any && from.is_none() {
+            return self
+                .builder
+                .incomplete_transition("from state", None, &self.events);
+        }
+        let Some(to) = self.to.clone() else {
+            return self
+                .builder
+                .incomplete_transition("to state", from, &self.events);
+        };
+        if self.events.is_empty() {
+            return self
+                .builder
+                .incomplete_transition("event", from, &self.events);
+        }
+
+        for event in self.events {
+            let transition = |from: S| Transition {
                 from,
                 to: to.clone(),
                 event: event.clone(),
-                condition: condition.clone(),
-                action: action.clone(),
+                guard: self.guard.clone(),
+                action: self.action.clone(),
                 transition_type: TransitionType::External,
+                required_flag: self.required_flag.clone(),
+                approval: self.approval.clone(),
+                pure_action: self.pure_action,
+                irreversible: self.irreversible,
+                tag: None,
+                names: CallbackNames::of(&self.guard, &self.named_guard, &self.named_action),
                 #[cfg(feature = "guards")]
                 priority: self.priority,
             };
 
-            self.builder.add_transition(transition);
-        }
+            if self.from_any {
+                // The source of a wildcard is a placeholder, see `WildcardMap`
+                let wildcard = transition(to.clone());
+                self.builder.require_derived(self.derived, None, &event);
+                self.builder.wildcard_transitions.push(wildcard);
+            } else {
+                for from in &self.from_states {
+                    self.builder
+                        .require_derived(self.derived, Some(from), &event);
+                    let transition = transition(from.clone());
+                    self.builder.add_transition(transition);
+                }
+            }
+        }
 
         self.builder
     }
@@ -1003,17 +3677,33 @@ impl StateMachineBuilderFactory {
 }
 
 /// Factory for managing multiple state machines
-pub struct StateMachineFactory<S, E, C>
+///
+/// Machines are registered under a key of type `K`, typically a composite
+/// such as `(TenantId, WorkflowKind)`. [`StringFactory`] keys machines by
+/// their id.
+pub struct StateMachineFactory<K, S, E, C>
 where
+    K: Eq + Hash + Clone,
     S: State,
     E: Event,
     C: Context,
 {
-    machines: HashMap<String, StateMachine<S, E, C>>,
+    machines: HashMap<K, StateMachine<S, E, C>>,
+}
+
+/// Factory keyed by machine id
+pub type StringFactory<S, E, C> = StateMachineFactory<String, S, E, C>;
+
+/// Whether a factory machine accepts events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineStatus {
+    Active,
+    Archived,
 }
 
-impl<S, E, C> StateMachineFactory<S, E, C>
+impl<K, S, E, C> StateMachineFactory<K, S, E, C>
 where
+    K: Eq + Hash + Clone,
     S: State,
     E: Event,
     C: Context,
@@ -1024,20 +3714,109 @@ where
         }
     }
 
-    pub fn register(&mut self, machine: StateMachine<S, E, C>) {
-        self.machines.insert(machine.id.clone(), machine);
+    /// Register a machine under `key`, replacing any machine already there
+    pub fn register_keyed(&mut self, key: K, machine: StateMachine<S, E, C>) {
+        self.machines.insert(key, machine);
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&StateMachine<S, E, C>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.machines.get(key)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut StateMachine<S, E, C>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.machines.get_mut(key)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<StateMachine<S, E, C>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.machines.remove(key)
+    }
+
+    /// Keys of all registered machines, in no particular order
+    pub fn keys(&self) -> Vec<&K> {
+        self.machines.keys().collect()
+    }
+
+    /// Stop the machine under `key` from accepting events
+    ///
+    /// Applies to every handle on the machine. Returns `false` if there is
+    /// no such machine.
+    pub fn archive<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.set_archived(key, true)
+    }
+
+    /// Let an archived machine accept events again
+    pub fn unarchive<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.set_archived(key, false)
+    }
+
+    /// Keys of archived machines, in no particular order
+    pub fn list_archived(&self) -> Vec<&K> {
+        self.machines
+            .iter()
+            .filter(|(_, machine)| machine.is_archived())
+            .map(|(key, _)| key)
+            .collect()
     }
 
-    pub fn get(&self, id: &str) -> Option<&StateMachine<S, E, C>> {
-        self.machines.get(id)
+    /// Keys of all registered machines with their status, in no particular order
+    pub fn list(&self) -> Vec<(&K, MachineStatus)> {
+        self.machines
+            .iter()
+            .map(|(key, machine)| {
+                let status = if machine.is_archived() {
+                    MachineStatus::Archived
+                } else {
+                    MachineStatus::Active
+                };
+                (key, status)
+            })
+            .collect()
     }
 
-    pub fn get_mut(&mut self, id: &str) -> Option<&mut StateMachine<S, E, C>> {
-        self.machines.get_mut(id)
+    fn set_archived<Q>(&self, key: &Q, archived: bool) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.machines.get(key) {
+            Some(machine) => {
+                machine.archived.store(archived, Ordering::Release);
+                true
+            }
+            None => false,
+        }
     }
+}
 
-    pub fn remove(&mut self, id: &str) -> Option<StateMachine<S, E, C>> {
-        self.machines.remove(id)
+impl<S, E, C> StateMachineFactory<String, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Register a machine under its id
+    pub fn register(&mut self, machine: StateMachine<S, E, C>) {
+        self.machines.insert(machine.id.clone(), machine);
     }
 
     pub fn list_ids(&self) -> Vec<&str> {
@@ -1045,8 +3824,9 @@ where
     }
 }
 
-impl<S, E, C> Default for StateMachineFactory<S, E, C>
+impl<K, S, E, C> Default for StateMachineFactory<K, S, E, C>
 where
+    K: Eq + Hash + Clone,
     S: State,
     E: Event,
     C: Context,
@@ -1058,13 +3838,152 @@ where
 
 // Parallel state machine support (requires parallel feature)
 #[cfg(feature = "parallel")]
+struct Region<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    name: String,
+    machine: StateMachine<S, E, C>,
+}
+
+#[cfg(feature = "parallel")]
+#[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
 pub struct ParallelStateMachine<S, E, C>
 where
     S: State,
     E: Event,
     C: Context,
 {
-    regions: Vec<StateMachine<S, E, C>>,
+    regions: Vec<Region<S, E, C>>,
+    event_owners: HashMap<E, usize>,
+}
+
+/// Error registering a region or firing an event on the regions
+#[cfg(feature = "parallel")]
+#[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionError {
+    /// The event is already owned by another region
+    EventAlreadyOwned { event: String, owner: String },
+    /// Another region already has the name
+    DuplicateName { name: String },
+    /// `fire_event` was given a different number of states than there are
+    /// regions
+    StateCountMismatch { regions: usize, states: usize },
+}
+
+#[cfg(feature = "parallel")]
+impl std::fmt::Display for RegionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegionError::EventAlreadyOwned { event, owner } => {
+                write!(f, "Event {} is already owned by region {}", event, owner)
+            }
+            RegionError::DuplicateName { name } => {
+                write!(f, "A region named {} is already registered", name)
+            }
+            RegionError::StateCountMismatch { regions, states } => {
+                write!(f, "{} states were given for {} regions", states, regions)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl std::error::Error for RegionError {}
+
+// History record annotated with the region that produced it
+#[cfg(all(feature = "parallel", feature = "history"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "parallel", feature = "history"))))]
+#[derive(Debug, Clone)]
+pub struct RegionTransitionRecord<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub region: String,
+    pub region_index: usize,
+    pub record: TransitionRecord<S, E>,
+}
+
+/// Result of firing an event in one region
+#[cfg(feature = "parallel")]
+#[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
+#[derive(Debug, Clone)]
+pub struct RegionOutcome<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub region_name: String,
+    pub previous_state: S,
+    pub result: Result<S, TransitionError<S, E>>,
+}
+
+#[cfg(feature = "parallel")]
+impl<S, E> RegionOutcome<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// State of the region after the fire; failed regions keep their state
+    pub fn next_state(&self) -> &S {
+        self.result.as_ref().unwrap_or(&self.previous_state)
+    }
+}
+
+/// Results of firing an event in a `ParallelStateMachine`, in region order
+#[cfg(feature = "parallel")]
+#[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
+#[derive(Debug, Clone)]
+pub struct ParallelOutcome<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub regions: Vec<RegionOutcome<S, E>>,
+}
+
+#[cfg(feature = "parallel")]
+impl<S, E> ParallelOutcome<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub fn all_succeeded(&self) -> bool {
+        self.regions.iter().all(|region| region.result.is_ok())
+    }
+
+    /// Whether any region moved to a different state
+    pub fn any_changed(&self) -> bool {
+        self.regions
+            .iter()
+            .any(|region| region.next_state() != &region.previous_state)
+    }
+
+    pub fn failures(&self) -> Vec<&RegionOutcome<S, E>> {
+        self.regions
+            .iter()
+            .filter(|region| region.result.is_err())
+            .collect()
+    }
+
+    /// States to pass to the next fire
+    pub fn next_states(&self) -> Vec<S> {
+        self.regions
+            .iter()
+            .map(|region| region.next_state().clone())
+            .collect()
+    }
+
+    /// Outcome of the region called `name`
+    pub fn region(&self, name: &str) -> Option<&RegionOutcome<S, E>> {
+        self.regions
+            .iter()
+            .find(|region| region.region_name == name)
+    }
 }
 
 #[cfg(feature = "parallel")]
@@ -1077,41 +3996,235 @@ where
     pub fn new() -> Self {
         ParallelStateMachine {
             regions: Vec::new(),
+            event_owners: HashMap::new(),
         }
     }
 
+    /// Add a region named after the machine's id
+    ///
+    /// If another region already uses that name, the region index is appended
+    /// (`id#index`, counting up while that is taken too) so every region
+    /// stays addressable by name.
     pub fn add_region(&mut self, machine: StateMachine<S, E, C>) {
-        self.regions.push(machine);
+        let mut name = machine.id().to_string();
+        let mut index = self.regions.len();
+        while self.region_index(&name).is_some() {
+            name = format!("{}#{}", machine.id(), index);
+            index += 1;
+        }
+        self.push_region(name, machine);
+    }
+
+    /// Add a region under an explicit name
+    ///
+    /// Fails without adding the region if another region already has the
+    /// name.
+    pub fn add_named_region(
+        &mut self,
+        name: impl Into<String>,
+        machine: StateMachine<S, E, C>,
+    ) -> Result<(), RegionError> {
+        let name = name.into();
+        if self.region_index(&name).is_some() {
+            return Err(RegionError::DuplicateName { name });
+        }
+        self.push_region(name, machine);
+        Ok(())
+    }
+
+    /// Add a named region that exclusively handles `owned_events`
+    ///
+    /// Owned events are only delivered to this region. Fails without adding
+    /// the region if another region already has its name or owns one of the
+    /// events.
+    pub fn add_region_with_events(
+        &mut self,
+        name: impl Into<String>,
+        machine: StateMachine<S, E, C>,
+        owned_events: Vec<E>,
+    ) -> Result<(), RegionError> {
+        let name = name.into();
+        if self.region_index(&name).is_some() {
+            return Err(RegionError::DuplicateName { name });
+        }
+        for event in &owned_events {
+            if let Some(owner) = self.event_owners.get(event) {
+                return Err(RegionError::EventAlreadyOwned {
+                    event: format!("{:?}", event),
+                    owner: self.regions[*owner].name.clone(),
+                });
+            }
+        }
+
+        let index = self.regions.len();
+        self.push_region(name, machine);
+        for event in owned_events {
+            self.event_owners.insert(event, index);
+        }
+        Ok(())
     }
 
+    /// Fire an event on the regions, one state per region in registration order
+    ///
+    /// An event owned by a region is only fired there; the other regions
+    /// report their state unchanged. Other events are fired on every region.
+    /// Fails without firing anything unless there is exactly one state per
+    /// region.
     pub fn fire_event(
         &self,
         states: Vec<S>,
         event: E,
         context: C,
-    ) -> Vec<Result<S, TransitionError>> {
-        self.regions
+    ) -> Result<ParallelOutcome<S, E>, RegionError> {
+        if states.len() != self.regions.len() {
+            return Err(RegionError::StateCountMismatch {
+                regions: self.regions.len(),
+                states: states.len(),
+            });
+        }
+        let owner = self.event_owners.get(&event).copied();
+        let regions = self
+            .regions
             .iter()
-            .zip(states.iter())
-            .map(|(machine, state)| {
-                machine.fire_event(state.clone(), event.clone(), context.clone())
+            .zip(states)
+            .enumerate()
+            .map(|(index, (region, state))| {
+                let result = match owner {
+                    Some(owner) if owner != index => Ok(state.clone()),
+                    _ => region
+                        .machine
+                        .fire_event(state.clone(), event.clone(), context.clone()),
+                };
+                RegionOutcome {
+                    region_name: region.name.clone(),
+                    previous_state: state,
+                    result,
+                }
             })
-            .collect()
+            .collect();
+        Ok(ParallelOutcome { regions })
     }
 
     pub fn get_region(&self, index: usize) -> Option<&StateMachine<S, E, C>> {
-        self.regions.get(index)
+        self.regions.get(index).map(|region| &region.machine)
+    }
+
+    /// Get a region by name
+    pub fn region(&self, name: &str) -> Option<&StateMachine<S, E, C>> {
+        self.region_index(name)
+            .map(|index| &self.regions[index].machine)
+    }
+
+    /// Names of all regions in registration order
+    pub fn region_names(&self) -> Vec<&str> {
+        self.regions
+            .iter()
+            .map(|region| region.name.as_str())
+            .collect()
     }
 
     pub fn region_count(&self) -> usize {
         self.regions.len()
     }
+
+    fn region_index(&self, name: &str) -> Option<usize> {
+        self.regions.iter().position(|region| region.name == name)
+    }
+
+    fn push_region(&mut self, name: String, machine: StateMachine<S, E, C>) {
+        self.regions.push(Region { name, machine });
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Get the transition history of a single region
+    pub fn region_history(&self, name: &str) -> Option<Vec<TransitionRecord<S, E>>> {
+        self.region(name).map(|machine| machine.get_history())
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Get the history of all regions merged into one chronological list
+    ///
+    /// Records with identical timestamps are ordered by region index.
+    pub fn combined_history(&self) -> Vec<RegionTransitionRecord<S, E>> {
+        let mut combined: Vec<RegionTransitionRecord<S, E>> =
+            self.regions
+                .iter()
+                .enumerate()
+                .flat_map(|(index, region)| {
+                    region.machine.get_history().into_iter().map(move |record| {
+                        RegionTransitionRecord {
+                            region: region.name.clone(),
+                            region_index: index,
+                            record,
+                        }
+                    })
+                })
+                .collect();
+        // Stable sort keeps each region's own order for equal timestamps
+        combined.sort_by_key(|entry| (entry.record.timestamp, entry.region_index));
+        combined
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Get metrics summed across all regions
+    ///
+    /// State visit counts are keyed by `region/state` so regions sharing
+    /// state names stay distinguishable; statistics of a `(from, event)`
+    /// pair several regions fire are summed.
+    pub fn combined_metrics(&self) -> StateMachineMetrics<S, E> {
+        let mut combined = StateMachineMetrics::new();
+        for region in &self.regions {
+            let metrics = region.machine.get_metrics();
+            combined.total_transitions += metrics.total_transitions;
+            combined.successful_transitions += metrics.successful_transitions;
+            combined.failed_transitions += metrics.failed_transitions;
+            combined
+                .transition_durations
+                .merge(&metrics.transition_durations);
+            for (state, count) in metrics.state_visit_counts {
+                *combined
+                    .state_visit_counts
+                    .entry(format!("{}/{}", region.name, state))
+                    .or_insert(0) += count;
+            }
+            for (callback, count) in metrics.slow_callbacks {
+                *combined
+                    .slow_callbacks
+                    .entry(format!("{}/{}", region.name, callback))
+                    .or_insert(0) += count;
+            }
+            for (code, count) in metrics.failures_by_code {
+                *combined.failures_by_code.entry(code).or_insert(0) += count;
+            }
+            combined.failure_rate.merge(&metrics.failure_rate);
+            for (key, stats) in metrics.transitions {
+                combined.transitions.entry(key).or_default().merge(&stats);
+            }
+        }
+        combined
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl<S, E, C> Default for ParallelStateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[allow(dead_code)]
     #[derive(Debug, Clone, Hash, Eq, PartialEq)]
     enum States {
         State1,
@@ -1122,6 +4235,7 @@ mod tests {
 
     impl State for States {}
 
+    #[allow(dead_code)]
     #[derive(Debug, Clone, Hash, Eq, PartialEq)]
     enum Events {
         Event1,
@@ -1133,6 +4247,7 @@ mod tests {
 
     impl Event for Events {}
 
+    #[allow(dead_code)]
     #[derive(Debug, Clone)]
     struct TestContext {
         operator: String,
@@ -1167,42 +4282,75 @@ mod tests {
         assert_eq!(result.unwrap(), States::State2);
     }
 
+    struct StubFlags {
+        enabled: std::sync::atomic::AtomicBool,
+        lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FeatureFlagProvider for StubFlags {
+        fn is_enabled(&self, flag: &str, ctx_key: &str) -> bool {
+            use std::sync::atomic::Ordering;
+            assert_eq!(flag, "express-shipping");
+            assert_eq!(ctx_key, "123456");
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.enabled.load(Ordering::SeqCst)
+        }
+    }
+
     #[test]
-    #[cfg(feature = "history")]
-    fn test_history_tracking() {
-        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+    fn test_feature_flag_gating() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+        let flags = Arc::new(StubFlags {
+            enabled: AtomicBool::new(false),
+            lookups: AtomicUsize::new(0),
+        });
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder.with_feature_flags(flags.clone(), |c| c.entity_id.clone());
         builder
             .external_transition()
             .from(States::State1)
             .to(States::State2)
             .on(Events::Event1)
+            .when(|_s, _e, c| c.operator == "frank")
+            .requires_flag("express-shipping")
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State3)
+            .on(Events::Event1)
+            .requires_flag("express-shipping")
             .perform(|_s, _e, _c| {});
 
         let state_machine = builder.build();
         let context = TestContext {
-            operator: "test".to_string(),
-            entity_id: "789".to_string(),
+            operator: "frank".to_string(),
+            entity_id: "123456".to_string(),
         };
 
-        let _ = state_machine.fire_event(States::State1, Events::Event1, context);
-        let history = state_machine.get_history();
-        assert_eq!(history.len(), 1);
-        assert!(history[0].success);
+        let result = state_machine.fire_event(States::State1, Events::Event1, context.clone());
+        match result {
+            Err(TransitionError::FeatureDisabled { flags, .. }) => {
+                assert_eq!(flags, vec!["express-shipping".to_string()]);
+            }
+            other => panic!("expected FeatureDisabled, got {:?}", other),
+        }
+        // Both candidates share the flag, so the provider is asked once per fire
+        assert_eq!(flags.lookups.load(Ordering::SeqCst), 1);
+
+        flags.enabled.store(true, Ordering::SeqCst);
+        let result = state_machine.fire_event(States::State1, Events::Event1, context);
+        assert!(result.is_ok());
+        assert_eq!(flags.lookups.load(Ordering::SeqCst), 2);
     }
 
     #[test]
-    #[cfg(feature = "extended")]
-    fn test_entry_exit_actions() {
+    #[cfg(feature = "history")]
+    fn test_history_tracking() {
         let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
 
         builder
-            .with_entry_action(States::State2, |_s, _c| {
-                println!("Entering State2");
-            })
-            .with_exit_action(States::State1, |_s, _c| {
-                println!("Exiting State1");
-            })
             .external_transition()
             .from(States::State1)
             .to(States::State2)
@@ -1215,42 +4363,1164 @@ mod tests {
             entity_id: "789".to_string(),
         };
 
-        let result = state_machine.fire_event(States::State1, Events::Event1, context);
-        assert!(result.is_ok());
+        let _ = state_machine.fire_event(States::State1, Events::Event1, context);
+        let history = state_machine.get_history();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].success);
     }
 
     #[test]
-    #[cfg(feature = "metrics")]
-    fn test_metrics_collection() {
+    #[cfg(feature = "history")]
+    fn test_history_records_failure_details() {
         let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
-
         builder
             .external_transition()
             .from(States::State1)
             .to(States::State2)
             .on(Events::Event1)
-            .perform(|_s, _e, _c| {});
-
+            .perform(|_s, _e, _c| std::thread::sleep(Duration::from_millis(5)));
         let state_machine = builder.build();
         let context = TestContext {
             operator: "test".to_string(),
             entity_id: "789".to_string(),
         };
 
-        let _ = state_machine.fire_event(States::State1, Events::Event1, context.clone());
-        let _ = state_machine.fire_event(States::State1, Events::Event2, context); // Should fail
+        let before = std::time::SystemTime::now();
+        state_machine
+            .fire_event(States::State1, Events::Event1, context.clone())
+            .unwrap();
+        assert!(state_machine
+            .fire_event(States::State2, Events::Event1, context)
+            .is_err());
 
-        let metrics = state_machine.get_metrics();
-        assert_eq!(metrics.total_transitions, 2);
-        assert_eq!(metrics.successful_transitions, 1);
-        assert_eq!(metrics.failed_transitions, 1);
-        assert_eq!(metrics.success_rate(), 0.5);
+        let history = state_machine.get_history();
+        assert!(history[0].wall_time >= before);
+        assert!(history[0].duration >= Duration::from_millis(5));
+        assert_eq!(history[0].error, None);
+        assert!(!history[1].success);
+        assert_eq!(history[1].error_code, Some("no_valid_transition"));
+        assert!(history[1]
+            .error
+            .as_deref()
+            .is_some_and(|error| error.contains("State2")));
     }
 
     #[test]
-    #[cfg(feature = "visualization")]
-    fn test_visualization() {
-        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+    #[cfg(all(feature = "history", feature = "serde"))]
+    fn test_history_record_serializes() {
+        #[derive(Debug, Clone, Hash, Eq, PartialEq, serde::Serialize)]
+        enum Light {
+            Red,
+        }
+        impl State for Light {}
+        #[derive(Debug, Clone, Hash, Eq, PartialEq, serde::Serialize)]
+        enum LightEvent {
+            Switch,
+        }
+        impl Event for LightEvent {}
+
+        let record = TransitionRecord {
+            from: Light::Red,
+            to: Light::Red,
+            event: LightEvent::Switch,
+            timestamp: Instant::now(),
+            wall_time: std::time::UNIX_EPOCH + Duration::from_millis(1_714_566_600_250),
+            duration: Duration::from_millis(3),
+            success: false,
+            error: Some("guard failed".to_string()),
+            error_code: Some("condition_not_met"),
+            approval: None,
+            epoch: 0,
+            context_changes: None,
+            transition_type: None,
+            irreversible: false,
+        };
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["wall_time"], "2024-05-01T12:30:00.250Z");
+        assert_eq!(json["duration"]["nanos"], 3_000_000);
+        assert_eq!(json["error"], "guard failed");
+        assert!(json.get("timestamp").is_none());
+
+        assert_eq!(rfc3339(std::time::UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            parse_rfc3339("2024-05-01T12:30:00.250Z"),
+            Some(record.wall_time)
+        );
+        assert_eq!(parse_rfc3339("2024-05-01 12:30:00Z"), None);
+        assert_eq!(
+            rfc3339(std::time::UNIX_EPOCH + Duration::from_secs(951_868_800)),
+            "2000-03-01T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_history_capacity_drops_oldest() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder.with_history_capacity(3);
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State2)
+            .to(States::State1)
+            .on(Events::Event2)
+            .perform(|_s, _e, _c| {});
+        let state_machine = Arc::new(builder.build());
+        assert_eq!(state_machine.history_capacity(), Some(3));
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        let mut state = States::State1;
+        for event in [Events::Event1, Events::Event2, Events::Event1] {
+            state = state_machine
+                .fire_event(state, event, context.clone())
+                .unwrap();
+        }
+        assert_eq!(state_machine.history_len(), 3);
+        // Wraps twice: a successful fire, then a failed one
+        state_machine
+            .fire_event(state, Events::Event2, context.clone())
+            .unwrap();
+        assert!(state_machine
+            .fire_event(States::State3, Events::Event1, context.clone())
+            .is_err());
+
+        let history = state_machine.get_history();
+        let steps: Vec<_> = history
+            .iter()
+            .map(|record| (record.from.clone(), record.success))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (States::State1, true),
+                (States::State2, true),
+                (States::State3, false),
+            ]
+        );
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert_eq!(state_machine.with_history(|records| records.len()), 3);
+
+        // Instances keep as many records as the machine
+        let instance = state_machine.start(States::State1);
+        for event in [
+            Events::Event1,
+            Events::Event2,
+            Events::Event1,
+            Events::Event2,
+        ] {
+            instance.process(event, context.clone()).unwrap();
+        }
+        assert_eq!(instance.get_history().len(), 3);
+        assert_eq!(
+            StateMachineBuilderFactory::create::<States, Events, TestContext>()
+                .build()
+                .history_capacity(),
+            None
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_entry_exit_actions() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+
+        builder
+            .with_entry_action(States::State2, |_s, _c| {
+                println!("Entering State2");
+            })
+            .with_exit_action(States::State1, |_s, _c| {
+                println!("Exiting State1");
+            })
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        let result = state_machine.fire_event(States::State1, Events::Event1, context);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_state_actions_skip_failed_and_internal_transitions() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        let exits = calls.clone();
+        let entries = calls.clone();
+        builder
+            .with_exit_action(States::State1, move |s, _c| {
+                exits.lock().unwrap().push(format!("exit {:?}", s))
+            })
+            .with_entry_action(States::State1, move |s, _c| {
+                entries.lock().unwrap().push(format!("enter {:?}", s))
+            });
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .when(|_s, _e, c| c.operator == "admin")
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(States::State1)
+            .on(Events::InternalEvent)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "guest".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        // Unknown event and rejected guard
+        assert!(state_machine
+            .fire_event(States::State1, Events::Event2, context.clone())
+            .is_err());
+        assert!(state_machine
+            .fire_event(States::State1, Events::Event1, context.clone())
+            .is_err());
+        assert_eq!(
+            state_machine
+                .fire_event(States::State1, Events::InternalEvent, context)
+                .unwrap(),
+            States::State1
+        );
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fallible_action_aborts_transition() {
+        let failures = Arc::new(std::sync::Mutex::new(0));
+        let counter = failures.clone();
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .set_fail_callback(Arc::new(move |_s, _e, _c| *counter.lock().unwrap() += 1))
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform_fallible(|_s, _e, c| {
+                if c.entity_id.is_empty() {
+                    Err("entity id is required".into())
+                } else {
+                    Ok(())
+                }
+            });
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: String::new(),
+        };
+
+        let error = state_machine
+            .fire_event(States::State1, Events::Event1, context.clone())
+            .unwrap_err();
+        assert!(matches!(error, TransitionError::ActionFailed { .. }));
+        assert_eq!(error.to_string(), "Action failed: entity id is required");
+        assert!(std::error::Error::source(&error).is_some());
+        assert_eq!(*failures.lock().unwrap(), 1);
+
+        #[cfg(feature = "history")]
+        {
+            let history = state_machine.get_history();
+            assert!(!history[0].success);
+            assert_eq!(history[0].to, States::State1);
+            assert_eq!(
+                history[0].error.as_deref(),
+                Some("Action failed: entity id is required")
+            );
+        }
+
+        let context = TestContext {
+            entity_id: "789".to_string(),
+            ..context
+        };
+        let result = state_machine.fire_event(States::State1, Events::Event1, context);
+        assert_eq!(result.unwrap(), States::State2);
+        assert_eq!(*failures.lock().unwrap(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_failed_action_leaves_source_state_unexited() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .with_exit_action_mut(States::State1, |_s, c| c.operator.push_str("-left"))
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform_fallible(|_s, _e, c| {
+                if c.entity_id.is_empty() {
+                    Err("entity id is required".into())
+                } else if c.operator.ends_with("-left") {
+                    Err("ran after the exit action".into())
+                } else {
+                    Ok(())
+                }
+            });
+
+        let state_machine = builder.build();
+        let mut context = TestContext {
+            operator: "test".to_string(),
+            entity_id: String::new(),
+        };
+        let result = state_machine.fire_event_mut(States::State1, Events::Event1, &mut context);
+        assert!(matches!(result, Err(TransitionError::ActionFailed { .. })));
+        assert_eq!(context.operator, "test");
+
+        context.entity_id = "789".to_string();
+        let result = state_machine.fire_event_mut(States::State1, Events::Event1, &mut context);
+        assert_eq!(result.unwrap(), States::State2);
+        assert_eq!(context.operator, "test-left");
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_fallible_entry_action_aborts_transition() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .with_entry_action_fallible(States::State2, |_s, c| {
+                if c.operator == "guest" {
+                    Err("guests cannot enter State2".into())
+                } else {
+                    Ok(())
+                }
+            })
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "guest".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        let result = state_machine.fire_event(States::State1, Events::Event1, context);
+        assert!(matches!(result, Err(TransitionError::ActionFailed { .. })));
+        #[cfg(feature = "history")]
+        assert!(!state_machine.get_history()[0].success);
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_read_paths_do_not_wait_for_history_lock() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        let machine = Arc::new(builder.build());
+
+        let (held_tx, held_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let holder = {
+            let machine = machine.clone();
+            std::thread::spawn(move || {
+                machine.with_history(|_| {
+                    held_tx.send(()).unwrap();
+                    // Keep the lock until the reader is done, or give up
+                    let _ = done_rx.recv_timeout(Duration::from_secs(5));
+                });
+            })
+        };
+        held_rx.recv().unwrap();
+
+        let (read_tx, read_rx) = mpsc::channel();
+        let reader = {
+            let machine = machine.clone();
+            std::thread::spawn(move || {
+                assert!(machine.verify(&States::State1, &Events::Event1));
+                assert_eq!(
+                    machine.available_events(&States::State1),
+                    vec![Events::Event1]
+                );
+                #[cfg(feature = "visualization")]
+                assert!(machine.to_dot().contains("State1"));
+                read_tx.send(()).unwrap();
+            })
+        };
+
+        let finished = read_rx.recv_timeout(Duration::from_secs(1));
+        done_tx.send(()).unwrap();
+        holder.join().unwrap();
+        reader.join().unwrap();
+        assert!(
+            finished.is_ok(),
+            "read-only APIs blocked on the history lock"
+        );
+    }
+
+    #[test]
+    fn test_on_any_of_expands_per_event() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State4)
+            .on_any_of(vec![Events::Event2, Events::Event3])
+            .when(|_s, _e, c| c.operator == "admin")
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(States::State2)
+            .on_any_of(vec![Events::Event1, Events::InternalEvent])
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transitions()
+            .from_among(vec![States::State2, States::State3])
+            .to(States::State1)
+            .on_any_of(vec![Events::Event2, Events::Event3])
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        assert_eq!(state_machine.transitions.len(), 8);
+        let first = &state_machine.transitions[&(States::State1, Events::Event2)][0];
+        let second = &state_machine.transitions[&(States::State1, Events::Event3)][0];
+        assert!(first.guard.ptr_eq(&second.guard));
+        assert!(first.action.ptr_eq(&second.action));
+
+        let admin = TestContext {
+            operator: "admin".to_string(),
+            entity_id: "789".to_string(),
+        };
+        for event in [Events::Event2, Events::Event3] {
+            assert_eq!(
+                state_machine
+                    .fire_event(States::State1, event.clone(), admin.clone())
+                    .unwrap(),
+                States::State4
+            );
+            assert_eq!(
+                state_machine
+                    .fire_event(States::State3, event, admin.clone())
+                    .unwrap(),
+                States::State1
+            );
+        }
+        assert_eq!(
+            state_machine
+                .fire_event(States::State2, Events::InternalEvent, admin)
+                .unwrap(),
+            States::State2
+        );
+    }
+
+    #[test]
+    fn test_transitions_without_action() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .add();
+        builder
+            .internal_transition()
+            .within(States::State2)
+            .on(Events::InternalEvent)
+            .add();
+        builder
+            .external_transitions()
+            .from_among(vec![States::State1, States::State2])
+            .to(States::State3)
+            .on(Events::Event2)
+            .add();
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "user".to_string(),
+            entity_id: "42".to_string(),
+        };
+        let fire = |from, event| state_machine.fire_event(from, event, context.clone());
+        assert_eq!(
+            fire(States::State1, Events::Event1).unwrap(),
+            States::State2
+        );
+        assert_eq!(
+            fire(States::State2, Events::InternalEvent).unwrap(),
+            States::State2
+        );
+        assert_eq!(
+            fire(States::State1, Events::Event2).unwrap(),
+            States::State3
+        );
+        assert_eq!(
+            fire(States::State2, Events::Event2).unwrap(),
+            States::State3
+        );
+    }
+
+    #[test]
+    fn test_can_fire_evaluates_guards_without_side_effects() {
+        let actions = Arc::new(std::sync::Mutex::new(0));
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        let counter = actions.clone();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .when(|_s, _e, c| c.operator == "admin")
+            .perform(move |_s, _e, _c| *counter.lock().unwrap() += 1);
+        builder
+            .external_transition()
+            .from(States::State2)
+            .to(States::State3)
+            .on(Events::Event2)
+            .add();
+        let machine = builder.build();
+        let context = |operator: &str| TestContext {
+            operator: operator.to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        // Registered, but the guard rejects the context
+        assert!(machine.verify(&States::State1, &Events::Event1));
+        assert!(!machine.can_fire(&States::State1, &Events::Event1, &context("user")));
+        assert!(machine.can_fire(&States::State1, &Events::Event1, &context("admin")));
+        assert!(machine.can_fire(&States::State2, &Events::Event2, &context("user")));
+        assert!(!machine.can_fire(&States::State3, &Events::Event2, &context("admin")));
+
+        assert_eq!(*actions.lock().unwrap(), 0);
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+        #[cfg(feature = "metrics")]
+        assert_eq!(machine.get_metrics().total_transitions, 0);
+    }
+
+    #[test]
+    fn test_transition_table_accessors() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .when(|_s, _e, _c| true)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(States::State2)
+            .on(Events::InternalEvent)
+            .add();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(States::State4)
+            .on(Events::Event3)
+            .add();
+        let state_machine = builder.build();
+
+        let mut table: Vec<_> = state_machine
+            .transitions()
+            .map(|t| {
+                (
+                    t.from().clone(),
+                    t.event().clone(),
+                    t.to().clone(),
+                    t.transition_type().clone(),
+                    t.has_condition(),
+                    t.has_action(),
+                )
+            })
+            .collect();
+        table.sort_by_key(|row| format!("{:?}", row));
+        assert_eq!(
+            table,
+            vec![
+                (
+                    States::State1,
+                    Events::Event1,
+                    States::State2,
+                    TransitionType::External,
+                    true,
+                    true
+                ),
+                (
+                    States::State2,
+                    Events::InternalEvent,
+                    States::State2,
+                    TransitionType::Internal,
+                    false,
+                    false
+                ),
+            ]
+        );
+        let wildcards: Vec<_> = state_machine
+            .from_any_transitions()
+            .map(|t| (t.event(), t.to()))
+            .collect();
+        assert_eq!(wildcards, vec![(&Events::Event3, &States::State4)]);
+        assert_eq!(
+            state_machine.states(),
+            HashSet::from([&States::State1, &States::State2, &States::State4])
+        );
+        assert_eq!(
+            state_machine.events(),
+            HashSet::from([&Events::Event1, &Events::InternalEvent, &Events::Event3])
+        );
+    }
+
+    #[test]
+    fn test_from_any_transition() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(States::State4)
+            .on(Events::Event3)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State2)
+            .to(States::State3)
+            .on(Events::Event3)
+            .when(|_s, _e, c| c.operator == "admin")
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "guest".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        assert_eq!(
+            state_machine
+                .fire_event(States::State1, Events::Event3, context.clone())
+                .unwrap(),
+            States::State4
+        );
+        // The wildcard also covers states no transition mentions
+        assert!(state_machine.verify(&States::State4, &Events::Event3));
+        assert_eq!(
+            state_machine.available_events(&States::State1),
+            vec![Events::Event1, Events::Event3]
+        );
+
+        // The explicit transition wins, even when its guard rejects
+        assert!(matches!(
+            state_machine.fire_event(States::State2, Events::Event3, context),
+            Err(TransitionError::ConditionFailed { .. })
+        ));
+        let admin = TestContext {
+            operator: "admin".to_string(),
+            entity_id: "789".to_string(),
+        };
+        assert_eq!(
+            state_machine
+                .fire_event(States::State2, Events::Event3, admin)
+                .unwrap(),
+            States::State3
+        );
+    }
+
+    #[test]
+    fn test_allowed_events() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .add();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State3)
+            .on(Events::Event2)
+            .when(|_s, _e, c| c.operator == "admin")
+            .add();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(States::State4)
+            .on(Events::Event3)
+            .add();
+        builder
+            .lock_state_events(States::State2, vec![Events::Event1])
+            .terminal_states(vec![States::State4]);
+        let machine = builder.build();
+        let guest = TestContext {
+            operator: "guest".to_string(),
+            entity_id: "1".to_string(),
+        };
+        let admin = TestContext {
+            operator: "admin".to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        assert_eq!(
+            machine.allowed_events(&States::State1),
+            vec![Events::Event1, Events::Event2, Events::Event3]
+        );
+        assert_eq!(
+            machine.allowed_events_with(&States::State1, &guest),
+            vec![Events::Event1, Events::Event3]
+        );
+        assert_eq!(
+            machine.allowed_events_with(&States::State1, &admin),
+            machine.allowed_events(&States::State1)
+        );
+        // The lock rules out the wildcard
+        assert!(machine.allowed_events(&States::State2).is_empty());
+        assert!(machine.allowed_events(&States::State4).is_empty());
+    }
+
+    #[test]
+    fn test_missing_transition_lists_accepted_events() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        for (event, to) in [
+            (Events::Event2, States::State2),
+            (Events::Event1, States::State3),
+        ] {
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(to)
+                .on(event)
+                .add();
+        }
+        builder
+            .external_transitions()
+            .from_any()
+            .to(States::State4)
+            .on(Events::Event3)
+            .add();
+        let machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        // Asked twice, the second time from the index built by the first
+        for _ in 0..2 {
+            for from in [States::State1, States::State2] {
+                match machine.fire_event(from.clone(), Events::Event4, context.clone()) {
+                    Err(TransitionError::NoValidTransition { accepted, .. }) => {
+                        assert_eq!(accepted, machine.available_events(&from))
+                    }
+                    other => panic!("expected NoValidTransition, got {:?}", other),
+                }
+            }
+        }
+        assert_eq!(
+            machine.available_events(&States::State1),
+            vec![Events::Event1, Events::Event2, Events::Event3]
+        );
+        assert_eq!(
+            machine.available_events(&States::State2),
+            vec![Events::Event3]
+        );
+    }
+
+    #[test]
+    fn test_guard_rejection_distinct_from_missing_transition() {
+        let failures = Arc::new(std::sync::Mutex::new(0));
+        let counter = failures.clone();
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .set_fail_callback(Arc::new(move |_s, _e, _c| *counter.lock().unwrap() += 1))
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .when(|_s, _e, c| c.operator == "admin")
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        let rejected = state_machine.fire_event(States::State1, Events::Event1, context.clone());
+        assert!(matches!(
+            rejected,
+            Err(TransitionError::ConditionFailed { ref from, ref event, .. })
+                if *from == States::State1 && *event == Events::Event1
+        ));
+        let missing = state_machine.fire_event(States::State1, Events::Event2, context);
+        assert!(matches!(
+            missing,
+            Err(TransitionError::NoValidTransition { ref accepted, .. })
+                if *accepted == vec![Events::Event1]
+        ));
+        assert_eq!(*failures.lock().unwrap(), 2);
+
+        #[cfg(feature = "history")]
+        {
+            let history = state_machine.get_history();
+            assert_eq!(history[0].error_code, Some("condition_failed"));
+            assert_eq!(history[1].error_code, Some("no_valid_transition"));
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = state_machine.get_metrics();
+            assert_eq!(metrics.failures_by_code["condition_failed"], 1);
+            assert_eq!(metrics.failures_by_code["no_valid_transition"], 1);
+        }
+    }
+
+    #[test]
+    fn test_actions_mutate_context() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .when(|_s, _e, c| c.operator == "test")
+            .perform_mut(|_s, _e, c| c.entity_id.push_str("-approved"));
+
+        let state_machine = builder.build();
+        let mut context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        let result = state_machine.fire_event_mut(States::State1, Events::Event1, &mut context);
+        assert_eq!(result.unwrap(), States::State2);
+        assert_eq!(context.entity_id, "789-approved");
+
+        // The read-only entry point runs the action on its own copy
+        let result = state_machine.fire_event(States::State1, Events::Event1, context.clone());
+        assert!(result.is_ok());
+        assert_eq!(context.entity_id, "789-approved");
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_entry_exit_actions_mutate_context() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .with_exit_action_mut(States::State1, |_s, c| c.operator.push_str("-left"))
+            .with_entry_action_mut(States::State2, |s, c| {
+                c.operator.push_str(&format!("-entered-{:?}", s))
+            })
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        assert!(state_machine.has_entry_action(&States::State2));
+        let mut context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        state_machine
+            .fire_event_mut(States::State1, Events::Event1, &mut context)
+            .unwrap();
+        assert_eq!(context.operator, "test-left-entered-State2");
+    }
+
+    #[test]
+    fn test_sizing_hints_do_not_change_behavior() {
+        fn configure(builder: &mut StateMachineBuilder<States, Events, TestContext>) {
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State2)
+                .on(Events::Event1)
+                .perform(|_s, _e, _c| {});
+            builder
+                .external_transition()
+                .from(States::State2)
+                .to(States::State3)
+                .on(Events::Event2)
+                .when(|_s, _e, c| c.operator == "admin")
+                .perform(|_s, _e, _c| {});
+            builder
+                .internal_transition()
+                .within(States::State3)
+                .on(Events::InternalEvent)
+                .perform(|_s, _e, _c| {});
+        }
+
+        let mut plain = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        configure(&mut plain);
+        let plain = plain.build();
+
+        let mut hinted = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        hinted
+            .reserve_transitions(3)
+            .reserve_states(4)
+            .reserve_history(16);
+        configure(&mut hinted);
+        let hinted = hinted.build();
+
+        let states = [
+            States::State1,
+            States::State2,
+            States::State3,
+            States::State4,
+        ];
+        let events = [
+            Events::Event1,
+            Events::Event2,
+            Events::Event3,
+            Events::InternalEvent,
+        ];
+        for operator in ["admin", "guest"] {
+            let context = TestContext {
+                operator: operator.to_string(),
+                entity_id: "1".to_string(),
+            };
+            for state in &states {
+                for event in &events {
+                    let expected = plain.fire_event(state.clone(), event.clone(), context.clone());
+                    let actual = hinted.fire_event(state.clone(), event.clone(), context.clone());
+                    assert_eq!(actual.ok(), expected.ok(), "{:?} on {:?}", event, state);
+                }
+            }
+        }
+        assert_eq!(hinted.transitions.len(), plain.transitions.len());
+        #[cfg(feature = "history")]
+        assert_eq!(hinted.get_history().len(), plain.get_history().len());
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_metrics_collection() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        let _ = state_machine.fire_event(States::State1, Events::Event1, context.clone());
+        let _ = state_machine.fire_event(States::State1, Events::Event2, context); // Should fail
+
+        let metrics = state_machine.get_metrics();
+        assert_eq!(metrics.total_transitions, 2);
+        assert_eq!(metrics.successful_transitions, 1);
+        assert_eq!(metrics.failed_transitions, 1);
+        assert_eq!(metrics.success_rate(), 0.5);
+    }
+
+    #[test]
+    #[cfg(all(feature = "history", feature = "metrics"))]
+    fn test_clear_and_reset_start_new_epochs() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .add();
+        builder.keep_cleared_epochs();
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "42".to_string(),
+        };
+
+        let _ = state_machine.fire_event(States::State1, Events::Event1, context.clone());
+        state_machine.clear_history();
+        state_machine.reset_metrics();
+        let _ = state_machine.fire_event(States::State2, Events::Event1, context);
+
+        assert_eq!(state_machine.history_epoch(), 1);
+        let history = state_machine.get_history();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].epoch, history[0].success), (1, false));
+        let previous = state_machine.drain_previous_epochs();
+        assert_eq!(previous.len(), 1);
+        assert_eq!((previous[0].epoch, previous[0].success), (0, true));
+        assert!(state_machine.drain_previous_epochs().is_empty());
+
+        assert_eq!(state_machine.metrics_epoch(), 1);
+        assert_eq!(state_machine.get_metrics().failed_transitions, 1);
+        let windows = state_machine.drain_previous_metrics();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].0, 0);
+        assert_eq!(windows[0].1.successful_transitions, 1);
+        assert!(state_machine.drain_previous_metrics().is_empty());
+    }
+
+    #[test]
+    #[cfg(all(feature = "history", feature = "metrics"))]
+    fn test_concurrent_clears_keep_every_record_once() {
+        use std::sync::atomic::AtomicUsize;
+
+        #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+        struct Id(u64);
+        impl State for Id {}
+        #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+        enum Tick {
+            Tick,
+        }
+        impl Event for Tick {}
+        #[derive(Debug, Clone)]
+        struct NoContext;
+        impl Context for NoContext {}
+
+        const THREADS: u64 = 4;
+        const FIRES: u64 = 2_000;
+
+        let mut builder = StateMachineBuilderFactory::create::<Id, Tick, NoContext>();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Id(0))
+            .on(Tick::Tick)
+            .add();
+        builder.keep_cleared_epochs();
+        let machine = Arc::new(builder.build());
+        let firing = Arc::new(AtomicUsize::new(THREADS as usize));
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let machine = machine.clone();
+                let firing = firing.clone();
+                std::thread::spawn(move || {
+                    // Each fire leaves from its own state, identifying its record
+                    for n in 0..FIRES {
+                        let id = thread * FIRES + n + 1;
+                        machine.fire_event(Id(id), Tick::Tick, NoContext).unwrap();
+                    }
+                    firing.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        let mut drained = Vec::new();
+        let mut windows = Vec::new();
+        while firing.load(Ordering::SeqCst) > 0 {
+            machine.clear_history();
+            machine.reset_metrics();
+            drained.extend(machine.drain_previous_epochs());
+            windows.extend(machine.drain_previous_metrics());
+            std::thread::yield_now();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        drained.extend(machine.drain_previous_epochs());
+        windows.extend(machine.drain_previous_metrics());
+
+        let current = machine.get_history();
+        let current_epoch = machine.history_epoch();
+        assert!(current.iter().all(|record| record.epoch == current_epoch));
+        assert!(drained.iter().all(|record| record.epoch < current_epoch));
+
+        let mut ids: Vec<u64> = drained
+            .iter()
+            .chain(&current)
+            .map(|record| record.from.0)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(drained.len() + current.len(), ids.len());
+        assert_eq!(ids, (1..=THREADS * FIRES).collect::<Vec<_>>());
+
+        let counted: u64 = windows
+            .iter()
+            .map(|(_, metrics)| metrics.total_transitions)
+            .sum::<u64>()
+            + machine.get_metrics().total_transitions;
+        assert_eq!(counted, THREADS * FIRES);
+    }
+
+    #[test]
+    #[cfg(all(feature = "history", feature = "metrics"))]
+    fn test_clearing_without_draining_stays_bounded() {
+        let build = |keep: bool| {
+            let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State2)
+                .on(Events::Event1)
+                .add();
+            builder.with_history_capacity(4);
+            if keep {
+                builder.keep_cleared_epochs();
+            }
+            builder.build()
+        };
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "42".to_string(),
+        };
+
+        for keep in [false, true] {
+            let state_machine = build(keep);
+            for _ in 0..100 {
+                for _ in 0..3 {
+                    let _ =
+                        state_machine.fire_event(States::State1, Events::Event1, context.clone());
+                }
+                state_machine.clear_history();
+            }
+            let previous = state_machine.drain_previous_epochs();
+            if keep {
+                // The history capacity bounds the kept records, newest kept
+                assert_eq!(previous.len(), 4);
+                assert!(previous.iter().all(|record| record.epoch >= 98));
+            } else {
+                assert!(previous.is_empty());
+            }
+        }
+
+        let state_machine = build(false);
+        for _ in 0..100 {
+            let _ = state_machine.fire_event(States::State1, Events::Event1, context.clone());
+            state_machine.reset_metrics();
+        }
+        assert!(state_machine.drain_previous_metrics().is_empty());
+        assert_eq!(state_machine.metrics_epoch(), 100);
+    }
+
+    #[test]
+    #[cfg(feature = "visualization")]
+    fn test_visualization() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
 
         builder
             .external_transition()
@@ -1272,6 +5542,240 @@ mod tests {
         assert!(plantuml.contains("State2"));
     }
 
+    #[test]
+    #[cfg(all(feature = "history", feature = "test-util"))]
+    fn test_history_survives_wall_clock_jump() {
+        use std::time::SystemTime;
+
+        let clock = Arc::new(MockClock::new());
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        builder.with_clock(clock.clone());
+        let machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        let before_jump = clock.wall_time();
+        let _ = machine.fire_event(States::State1, Events::Event1, context.clone());
+        clock.advance(Duration::from_secs(1));
+        // NTP correction moves the wall clock an hour back
+        clock.set_wall_time(before_jump - Duration::from_secs(3600));
+        let _ = machine.fire_event(States::State2, Events::Event1, context);
+
+        let history = machine.query_history(..);
+        assert_eq!(history.len(), 2);
+        assert!(history[0].success && !history[1].success);
+        assert!(history[0].timestamp < history[1].timestamp);
+        assert!(history[0].wall_time > history[1].wall_time);
+
+        let recent =
+            machine.query_history(before_jump..SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(recent.len(), 1);
+        assert!(recent[0].success);
+    }
+
+    #[test]
+    fn test_factory_composite_keys() {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        struct WorkflowKey {
+            tenant: u32,
+            kind: &'static str,
+        }
+
+        let machine = |id: &str| {
+            let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State2)
+                .on(Events::Event1)
+                .perform(|_s, _e, _c| {});
+            builder.id(id).build()
+        };
+        let acme_orders = WorkflowKey {
+            tenant: 1,
+            kind: "orders",
+        };
+        let acme_refunds = WorkflowKey {
+            tenant: 1,
+            kind: "refunds",
+        };
+        let globex_orders = WorkflowKey {
+            tenant: 2,
+            kind: "orders",
+        };
+
+        let mut factory = StateMachineFactory::new();
+        factory.register_keyed(acme_orders.clone(), machine("acme-orders"));
+        factory.register_keyed(acme_refunds.clone(), machine("acme-refunds"));
+        factory.register_keyed(globex_orders.clone(), machine("globex-orders"));
+
+        assert_eq!(factory.get(&acme_orders).unwrap().id(), "acme-orders");
+        assert_eq!(factory.get(&globex_orders).unwrap().id(), "globex-orders");
+        let mut keys = factory.keys();
+        keys.sort();
+        assert_eq!(keys, vec![&acme_orders, &acme_refunds, &globex_orders]);
+
+        assert!(factory.remove(&acme_refunds).is_some());
+        assert!(factory.get(&acme_refunds).is_none());
+
+        let mut by_id: StringFactory<States, Events, TestContext> = StateMachineFactory::new();
+        by_id.register(machine("legacy"));
+        assert!(by_id.get("legacy").is_some());
+        assert_eq!(by_id.list_ids(), vec!["legacy"]);
+    }
+
+    #[test]
+    fn test_factory_archive() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        let mut factory: StringFactory<States, Events, TestContext> = StateMachineFactory::new();
+        factory.register(builder.id("orders-v1").build());
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        assert!(factory.archive("orders-v1"));
+        assert!(!factory.archive("orders-v2"));
+        assert_eq!(factory.list_archived(), vec!["orders-v1"]);
+        assert_eq!(
+            factory.list(),
+            vec![(&"orders-v1".to_string(), MachineStatus::Archived)]
+        );
+
+        let machine = factory.get("orders-v1").unwrap();
+        match machine.fire_event(States::State1, Events::Event1, context.clone()) {
+            Err(TransitionError::MachineArchived { machine_id }) => {
+                assert_eq!(machine_id, "orders-v1")
+            }
+            other => panic!("expected MachineArchived, got {:?}", other),
+        }
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+        assert!(machine.verify(&States::State1, &Events::Event1));
+        #[cfg(feature = "visualization")]
+        assert!(machine.to_dot().contains("\"State1\" -> \"State2\""));
+
+        assert!(factory.unarchive("orders-v1"));
+        let machine = factory.get("orders-v1").unwrap();
+        assert_eq!(
+            machine
+                .fire_event(States::State1, Events::Event1, context)
+                .unwrap(),
+            States::State2
+        );
+        assert!(factory.list_archived().is_empty());
+    }
+
+    #[test]
+    fn test_guard_resolution() {
+        let failures = Arc::new(std::sync::Mutex::new(0));
+        let machine = |resolution: GuardResolution| {
+            let counter = failures.clone();
+            let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State2)
+                .on(Events::Event1)
+                .when(|_s, _e, c| c.operator == "admin")
+                .perform(|_s, _e, _c| {});
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State3)
+                .on(Events::Event1)
+                .perform(|_s, _e, _c| {});
+            builder
+                .with_guard_resolution(resolution)
+                .set_fail_callback(Arc::new(move |_s, _e, _c| *counter.lock().unwrap() += 1));
+            builder.build()
+        };
+        let admin = TestContext {
+            operator: "admin".to_string(),
+            entity_id: "1".to_string(),
+        };
+        let guest = TestContext {
+            operator: "guest".to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        let first_match = machine(GuardResolution::default());
+        let result = first_match.fire_event(States::State1, Events::Event1, admin.clone());
+        assert_eq!(result.unwrap(), States::State2);
+
+        let strict = machine(GuardResolution::RequireUnique);
+        match strict.fire_event(States::State1, Events::Event1, admin) {
+            Err(TransitionError::AmbiguousTransition { matched, .. }) => {
+                assert_eq!(
+                    matched,
+                    vec![(States::State2, None), (States::State3, None)]
+                );
+            }
+            other => panic!("expected AmbiguousTransition, got {:?}", other),
+        }
+        assert_eq!(*failures.lock().unwrap(), 1);
+        let result = strict.fire_event(States::State1, Events::Event1, guest);
+        assert_eq!(result.unwrap(), States::State3);
+    }
+
+    #[test]
+    fn test_ambiguous_transitions_to_one_target_are_named() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder.with_bindings(
+            ActionBindings::new()
+                .guard("is_admin", |_s, _e, c: &TestContext| c.operator == "admin")
+                .guard("has_entity", |_s, _e, c: &TestContext| {
+                    !c.entity_id.is_empty()
+                }),
+        );
+        for guard in ["is_admin", "has_entity"] {
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State2)
+                .on(Events::Event1)
+                .when_named(guard)
+                .add();
+        }
+        builder.with_guard_resolution(GuardResolution::RequireUnique);
+        let machine = builder.build();
+        let admin = TestContext {
+            operator: "admin".to_string(),
+            entity_id: "1".to_string(),
+        };
+
+        let error = machine
+            .fire_event(States::State1, Events::Event1, admin)
+            .unwrap_err();
+        match &error {
+            TransitionError::AmbiguousTransition { matched, .. } => assert_eq!(
+                *matched,
+                vec![
+                    (States::State2, Some("is_admin".to_string())),
+                    (States::State2, Some("has_entity".to_string())),
+                ]
+            ),
+            other => panic!("expected AmbiguousTransition, got {:?}", other),
+        }
+        assert!(error
+            .to_string()
+            .ends_with("State2 (is_admin), State2 (has_entity)"));
+    }
+
     #[test]
     #[cfg(feature = "parallel")]
     fn test_parallel_regions() {
@@ -1300,16 +5804,374 @@ mod tests {
             entity_id: "789".to_string(),
         };
 
-        let results = parallel_machine.fire_event(
-            vec![States::State1, States::State3],
-            Events::Event1,
-            context,
+        let outcome = parallel_machine
+            .fire_event(
+                vec![States::State1, States::State3],
+                Events::Event1,
+                context,
+            )
+            .unwrap();
+
+        assert_eq!(outcome.regions.len(), 2);
+        assert!(outcome.all_succeeded());
+        assert_eq!(outcome.next_states(), vec![States::State2, States::State4]);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_outcome_helpers() {
+        let mut builder1 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder1
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        let mut builder2 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder2
+            .external_transition()
+            .from(States::State4)
+            .to(States::State1)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+
+        let mut parallel_machine = ParallelStateMachine::new();
+        parallel_machine
+            .add_named_region("payment", builder1.build())
+            .unwrap();
+        parallel_machine
+            .add_named_region("shipping", builder2.build())
+            .unwrap();
+
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+        let outcome = parallel_machine
+            .fire_event(
+                vec![States::State1, States::State3],
+                Events::Event1,
+                context.clone(),
+            )
+            .unwrap();
+
+        assert!(!outcome.all_succeeded());
+        assert!(outcome.any_changed());
+        let failures = outcome.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].region_name, "shipping");
+        assert_eq!(
+            outcome.region("payment").unwrap().previous_state,
+            States::State1
+        );
+        // The failed region keeps its state for the next fire
+        assert_eq!(outcome.next_states(), vec![States::State2, States::State3]);
+
+        let outcome = parallel_machine
+            .fire_event(outcome.next_states(), Events::Event1, context)
+            .unwrap();
+        assert!(!outcome.any_changed());
+        assert_eq!(outcome.failures().len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_region_owned_events() {
+        let mut builder1 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder1
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        builder1
+            .external_transition()
+            .from(States::State1)
+            .to(States::State3)
+            .on(Events::Event3)
+            .perform(|_s, _e, _c| {});
+        let mut builder2 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder2
+            .external_transition()
+            .from(States::State3)
+            .to(States::State4)
+            .on(Events::Event2)
+            .perform(|_s, _e, _c| {});
+        builder2
+            .external_transition()
+            .from(States::State3)
+            .to(States::State1)
+            .on(Events::Event3)
+            .perform(|_s, _e, _c| {});
+
+        let mut parallel_machine = ParallelStateMachine::new();
+        parallel_machine
+            .add_region_with_events("payment", builder1.build(), vec![Events::Event1])
+            .unwrap();
+        parallel_machine
+            .add_region_with_events("shipping", builder2.build(), vec![Events::Event2])
+            .unwrap();
+
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+
+        // Owned events only reach their region
+        let outcome = parallel_machine
+            .fire_event(
+                vec![States::State1, States::State3],
+                Events::Event2,
+                context.clone(),
+            )
+            .unwrap();
+        assert_eq!(outcome.regions[0].result.as_ref().unwrap(), &States::State1);
+        assert_eq!(outcome.regions[1].result.as_ref().unwrap(), &States::State4);
+
+        // Unowned events are broadcast
+        let outcome = parallel_machine
+            .fire_event(
+                vec![States::State1, States::State3],
+                Events::Event3,
+                context,
+            )
+            .unwrap();
+        assert_eq!(outcome.regions[0].result.as_ref().unwrap(), &States::State3);
+        assert_eq!(outcome.regions[1].result.as_ref().unwrap(), &States::State1);
+
+        let mut builder3 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder3
+            .internal_transition()
+            .within(States::State1)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        let conflict = parallel_machine.add_region_with_events(
+            "audit",
+            builder3.build(),
+            vec![Events::Event4, Events::Event1],
+        );
+        assert_eq!(
+            conflict,
+            Err(RegionError::EventAlreadyOwned {
+                event: "Event1".to_string(),
+                owner: "payment".to_string(),
+            })
+        );
+        let duplicate = parallel_machine.add_region_with_events(
+            "shipping",
+            StateMachineBuilderFactory::create::<States, Events, TestContext>().build(),
+            vec![Events::Event4],
+        );
+        assert_eq!(
+            duplicate,
+            Err(RegionError::DuplicateName {
+                name: "shipping".to_string(),
+            })
+        );
+        assert_eq!(parallel_machine.region_count(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_rejects_duplicate_names_and_missing_states() {
+        let region = || {
+            let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+            builder
+                .external_transition()
+                .from(States::State1)
+                .to(States::State2)
+                .on(Events::Event1)
+                .perform(|_s, _e, _c| {});
+            builder.id("payment").build()
+        };
+        let mut parallel_machine = ParallelStateMachine::new();
+        parallel_machine
+            .add_named_region("payment", region())
+            .unwrap();
+        assert_eq!(
+            parallel_machine.add_named_region("payment", region()),
+            Err(RegionError::DuplicateName {
+                name: "payment".to_string(),
+            })
+        );
+        // Unnamed regions are renamed instead
+        parallel_machine.add_region(region());
+        parallel_machine
+            .add_named_region("payment#2", region())
+            .unwrap();
+        parallel_machine.add_region(region());
+        assert_eq!(
+            parallel_machine.region_names(),
+            vec!["payment", "payment#1", "payment#2", "payment#3"]
+        );
+
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+        let error = parallel_machine
+            .fire_event(vec![States::State1], Events::Event1, context)
+            .unwrap_err();
+        assert_eq!(
+            error,
+            RegionError::StateCountMismatch {
+                regions: 4,
+                states: 1,
+            }
         );
+        #[cfg(feature = "history")]
+        assert!(parallel_machine
+            .region_history("payment")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    #[cfg(all(feature = "parallel", feature = "history", feature = "metrics"))]
+    fn test_parallel_combined_history_and_metrics() {
+        let mut builder1 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder1
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        let mut builder2 = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder2
+            .external_transition()
+            .from(States::State3)
+            .to(States::State4)
+            .on(Events::Event2)
+            .perform(|_s, _e, _c| {});
+
+        let mut parallel_machine = ParallelStateMachine::new();
+        parallel_machine.add_region(builder1.id("payment").build());
+        parallel_machine.add_region(builder2.id("shipping").build());
+
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+        let payment = parallel_machine.region("payment").unwrap();
+        let shipping = parallel_machine.region("shipping").unwrap();
+        let _ = shipping.fire_event(States::State3, Events::Event2, context.clone());
+        let _ = payment.fire_event(States::State1, Events::Event1, context.clone());
+        let _ = shipping.fire_event(States::State3, Events::Event1, context.clone());
+        let _ = payment.fire_event(States::State1, Events::Event1, context);
+
+        let combined = parallel_machine.combined_history();
+        let regions: Vec<&str> = combined.iter().map(|r| r.region.as_str()).collect();
+        assert_eq!(regions, vec!["shipping", "payment", "shipping", "payment"]);
+        assert!(combined
+            .windows(2)
+            .all(|w| w[0].record.timestamp <= w[1].record.timestamp));
+        assert_eq!(parallel_machine.region_history("payment").unwrap().len(), 2);
+        assert!(parallel_machine.region_history("billing").is_none());
+
+        let metrics = parallel_machine.combined_metrics();
+        assert_eq!(metrics.total_transitions, 4);
+        assert_eq!(metrics.successful_transitions, 3);
+        assert_eq!(metrics.failed_transitions, 1);
+        assert_eq!(metrics.state_visit_counts.get("payment/State2"), Some(&2));
+        assert_eq!(metrics.state_visit_counts.get("shipping/State4"), Some(&1));
+    }
+
+    #[test]
+    #[cfg(feature = "guards")]
+    fn test_guard_priority_and_fanout() {
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .with_priority(1)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State3)
+            .on(Events::Event1)
+            .with_priority(10)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State1)
+            .to(States::State4)
+            .on(Events::Event1)
+            .when(|_s, _e, c| c.operator == "admin")
+            .with_priority(20)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(States::State2)
+            .to(States::State3)
+            .on(Events::Event2)
+            .perform(|_s, _e, _c| {});
+
+        let state_machine = builder.build();
+        let context = TestContext {
+            operator: "test".to_string(),
+            entity_id: "789".to_string(),
+        };
+        let result = state_machine.fire_event(States::State1, Events::Event1, context);
+        assert_eq!(result.unwrap(), States::State3);
+
+        let stats = state_machine.guard_fanout_stats();
+        assert_eq!(stats.pairs, 2);
+        assert_eq!(stats.max_candidates, 3);
+        assert_eq!(stats.distribution.get(&1), Some(&1));
+        assert_eq!(stats.distribution.get(&3), Some(&1));
+        assert_eq!(stats.multi_candidate_pairs(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "extended")]
+    fn test_state_requirements() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let entries = Arc::new(AtomicUsize::new(0));
+        let counter = entries.clone();
+        let mut builder = StateMachineBuilderFactory::create::<States, Events, TestContext>();
+        builder
+            .state_requires(States::State2, "has_operator", |c| !c.operator.is_empty())
+            .state_requires(States::State2, "has_entity", |c| !c.entity_id.is_empty())
+            .with_entry_action(States::State2, move |_s, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .external_transition()
+            .from(States::State1)
+            .to(States::State2)
+            .on(Events::Event1)
+            .perform(|_s, _e, _c| {});
+        let state_machine = builder.build();
+
+        let missing_entity = TestContext {
+            operator: "frank".to_string(),
+            entity_id: String::new(),
+        };
+        match state_machine.fire_event(States::State1, Events::Event1, missing_entity) {
+            Err(TransitionError::StateRequirementFailed { state, requirement }) => {
+                assert_eq!(state, States::State2);
+                assert_eq!(requirement, "has_entity");
+            }
+            other => panic!("expected StateRequirementFailed, got {:?}", other),
+        }
+        assert_eq!(entries.load(Ordering::SeqCst), 0);
 
-        assert_eq!(results.len(), 2);
-        assert!(results[0].is_ok());
-        assert!(results[1].is_ok());
-        assert_eq!(results[0].as_ref().unwrap(), &States::State2);
-        assert_eq!(results[1].as_ref().unwrap(), &States::State4);
+        let complete = TestContext {
+            operator: "frank".to_string(),
+            entity_id: "123456".to_string(),
+        };
+        let result = state_machine.fire_event(States::State1, Events::Event1, complete);
+        assert_eq!(result.unwrap(), States::State2);
+        assert_eq!(entries.load(Ordering::SeqCst), 1);
+
+        #[cfg(feature = "history")]
+        {
+            let history = state_machine.get_history();
+            assert!(!history[0].success);
+            assert!(history[1].success);
+        }
     }
 }
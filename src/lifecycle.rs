@@ -0,0 +1,316 @@
+//! Where a machine starts and where it ends
+//!
+//! The initial state is informational: exports draw an entry arrow to it.
+//! Terminal states are enforced. Firing from one fails with
+//! `TransitionError::TerminalState` before any transition is looked up,
+//! and a definition with transitions out of a terminal state does not
+//! build. `from_any` transitions don't apply to terminal states.
+//!
+//! `StateMachine::assert_terminal_integrity` checks the built machine for
+//! transitions out of terminal states, including state timeouts, which the
+//! build doesn't reject, so tests can assert the invariant directly.
+
+use std::fmt;
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder};
+
+/// A transition out of a terminal state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerminalViolation<S, E> {
+    pub from: S,
+    /// `None` for completion transitions
+    pub event: Option<E>,
+    pub to: S,
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> fmt::Display for TerminalViolation<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.event {
+            Some(event) => write!(
+                f,
+                "terminal state {:?} has a transition on {:?} to {:?}",
+                self.from, event, self.to
+            ),
+            None => write!(
+                f,
+                "terminal state {:?} has a completion transition to {:?}",
+                self.from, self.to
+            ),
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Declare the state new entities start in
+    pub fn initial_state(&mut self, state: S) -> &mut Self {
+        self.initial_state = Some(state);
+        self
+    }
+
+    /// Declare states that accept no events
+    ///
+    /// `build` panics, and `try_build` fails, if any of them has a transition
+    /// out of it.
+    pub fn terminal_states(&mut self, states: Vec<S>) -> &mut Self {
+        self.terminal_states.extend(states);
+        self
+    }
+
+    // Transitions registered out of terminal states, as (state, event);
+    // completion transitions have no event
+    pub(crate) fn outgoing_from_terminal(&self) -> Vec<(&S, Option<&E>)> {
+        let mut outgoing: Vec<_> = self
+            .transitions
+            .iter()
+            .filter(|t| self.terminal_states.contains(&t.from))
+            .map(|t| (&t.from, Some(&t.event)))
+            .chain(
+                self.completion_transitions
+                    .iter()
+                    .filter(|t| self.terminal_states.contains(&t.from))
+                    .map(|t| (&t.from, None)),
+            )
+            .collect();
+        outgoing.sort_by_cached_key(|entry| format!("{:?}", entry));
+        outgoing.dedup();
+        outgoing
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// The state declared with `StateMachineBuilder::initial_state`
+    pub fn initial_state(&self) -> Option<&S> {
+        self.initial_state.as_ref()
+    }
+
+    pub fn is_terminal(&self, state: &S) -> bool {
+        self.terminal_states.contains(state)
+    }
+
+    /// Check that no transition leaves a terminal state, except on the
+    /// events of `whitelist`
+    ///
+    /// Returns every violation, sorted by state, event and target name.
+    pub fn assert_terminal_integrity(
+        &self,
+        whitelist: &[E],
+    ) -> Result<(), Vec<TerminalViolation<S, E>>> {
+        let violations = self.terminal_violations(whitelist);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// `assert_terminal_integrity`, panicking with every violation
+    #[track_caller]
+    pub fn expect_terminal_integrity(&self, whitelist: &[E]) {
+        if let Err(violations) = self.assert_terminal_integrity(whitelist) {
+            let lines: Vec<String> = violations.iter().map(ToString::to_string).collect();
+            panic!("terminal integrity violated:\n{}", lines.join("\n"));
+        }
+    }
+
+    pub(crate) fn terminal_violations(&self, whitelist: &[E]) -> Vec<TerminalViolation<S, E>> {
+        let violation = |from: &S, event: Option<&E>, to: &S| TerminalViolation {
+            from: from.clone(),
+            event: event.cloned(),
+            to: to.clone(),
+        };
+        let mut violations: Vec<_> = self
+            .transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+            .filter(|t| self.is_terminal(&t.from))
+            .map(|t| violation(&t.from, Some(&t.event), &t.to))
+            .collect();
+        for (from, candidates) in &self.completion_transitions {
+            if self.is_terminal(from) {
+                violations.extend(candidates.iter().map(|t| violation(from, None, &t.to)));
+            }
+        }
+        #[cfg(feature = "timeout")]
+        violations.extend(
+            self.timeout_transitions
+                .iter()
+                .filter(|(from, _)| self.is_terminal(from))
+                .map(|(from, (to, event))| violation(from, Some(event), to)),
+        );
+        violations.retain(|v| {
+            v.event
+                .as_ref()
+                .is_none_or(|event| !whitelist.contains(event))
+        });
+        violations.sort_by_cached_key(|v| format!("{:?}\u{0}{:?}\u{0}{:?}", v.from, v.event, v.to));
+        violations
+    }
+
+    // Terminal states, sorted by name
+    #[cfg(feature = "visualization")]
+    pub(crate) fn sorted_terminal_states(&self) -> Vec<&S> {
+        let mut states: Vec<&S> = self.terminal_states.iter().collect();
+        states.sort_by_cached_key(|state| format!("{:?}", state));
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_builder, NoContext, Order, OrderEvent};
+    use crate::{BuildError, TransitionError};
+
+    // Orders end delivered or refunded
+    fn delivery_builder() -> StateMachineBuilder<Order, OrderEvent, NoContext> {
+        let mut builder = order_builder();
+        builder
+            .initial_state(Order::Placed)
+            .terminal_states(vec![Order::Delivered, Order::Refunded]);
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Delivered)
+            .on(OrderEvent::Deliver)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Order::Refunded)
+            .on(OrderEvent::Refund)
+            .perform(|_s, _e, _c| {});
+        builder
+    }
+
+    #[test]
+    fn test_terminal_state_rejects_events() {
+        let machine = delivery_builder().build();
+        assert_eq!(machine.initial_state(), Some(&Order::Placed));
+        assert!(machine.is_terminal(&Order::Refunded));
+        assert!(!machine.is_terminal(&Order::Paid));
+
+        assert_eq!(
+            machine
+                .fire_event(Order::Paid, OrderEvent::Refund, NoContext)
+                .unwrap(),
+            Order::Refunded
+        );
+        // The wildcard refund would apply, but Delivered is terminal
+        match machine.fire_event(Order::Delivered, OrderEvent::Refund, NoContext) {
+            Err(TransitionError::TerminalState { state }) => assert_eq!(state, Order::Delivered),
+            other => panic!("expected TerminalState, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_transition_out_of_terminal_state_rejected() {
+        let mut builder = delivery_builder();
+        builder
+            .external_transition()
+            .from(Order::Delivered)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        match builder.try_build() {
+            Err(errors) => assert_eq!(
+                errors,
+                vec![BuildError::TransitionFromTerminal {
+                    state: "Delivered".to_string(),
+                    event: Some("Pay".to_string()),
+                }]
+            ),
+            Ok(_) => panic!("expected the terminal state to fail the build"),
+        }
+    }
+
+    #[test]
+    fn test_clean_machine_passes_terminal_integrity() {
+        let machine = delivery_builder().build();
+        assert_eq!(machine.assert_terminal_integrity(&[]), Ok(()));
+        machine.expect_terminal_integrity(&[]);
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn test_terminal_integrity_violations() {
+        use std::time::Duration;
+
+        let mut builder = delivery_builder();
+        builder
+            .with_state_timeout(
+                Order::Delivered,
+                Duration::from_secs(60),
+                Order::Refunded,
+                OrderEvent::Refund,
+            )
+            .with_state_timeout(
+                Order::Refunded,
+                Duration::from_secs(60),
+                Order::Refunded,
+                OrderEvent::Deliver,
+            );
+        let machine = builder.build();
+        assert_eq!(
+            machine.assert_terminal_integrity(&[]),
+            Err(vec![
+                TerminalViolation {
+                    from: Order::Delivered,
+                    event: Some(OrderEvent::Refund),
+                    to: Order::Refunded,
+                },
+                TerminalViolation {
+                    from: Order::Refunded,
+                    event: Some(OrderEvent::Deliver),
+                    to: Order::Refunded,
+                },
+            ])
+        );
+        // Deliver is an audit event here
+        assert_eq!(
+            machine
+                .assert_terminal_integrity(&[OrderEvent::Deliver])
+                .unwrap_err()
+                .len(),
+            1
+        );
+        assert!(machine
+            .assert_terminal_integrity(&[OrderEvent::Deliver, OrderEvent::Refund])
+            .is_ok());
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    #[should_panic(expected = "terminal state Delivered has a transition on Refund to Refunded")]
+    fn test_expect_terminal_integrity_panics() {
+        let mut builder = delivery_builder();
+        builder.with_state_timeout(
+            Order::Delivered,
+            std::time::Duration::from_secs(60),
+            Order::Refunded,
+            OrderEvent::Refund,
+        );
+        builder.build().expect_terminal_integrity(&[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "terminal state Delivered has a transition on Pay")]
+    fn test_build_panics_on_transition_out_of_terminal_state() {
+        let mut builder = delivery_builder();
+        builder
+            .internal_transition()
+            .within(Order::Delivered)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        builder.build();
+    }
+}
@@ -0,0 +1,979 @@
+//! Regions over different state types (requires the `parallel` feature)
+//!
+//! A `ParallelStateMachine` drives regions sharing one state type. Machines
+//! built for state enums of their own are combined by declaring an enum
+//! with a variant per state type with `combine_states!`, then lifting each
+//! machine to it with `lift_machine`. Lifting wraps every state of the
+//! definition in the variant and every callback so it is handed the
+//! original state, the same way `map_context` wraps them for another
+//! context type.
+//!
+//! `from_any` transitions of a lifted machine only apply to states of its
+//! own variant. Callbacks reached with a state of another variant, such as
+//! the fail callback of a fire from a foreign state, are skipped. A lifted
+//! machine starts with empty history and metrics; runtime overrides,
+//! history projections and history sinks are not carried over.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use crate::callbacks::{Action, Guard};
+use crate::derived::DerivedValues;
+use crate::eventless::CompletionTransition;
+use crate::info::{InfoAction, InfoCondition};
+use crate::slow::SlowCallbackHandler;
+use crate::{
+    CallbackInfo, Context, Event, FieldChange, IncomingIndex, State, StateMachine, Transition,
+    TransitionError, TransitionInfo, TransitionListener,
+};
+
+/// State type with a variant holding the states of `P`, see
+/// `combine_states!`
+pub trait CombinedState<P>: State {
+    fn lift(state: P) -> Self;
+
+    /// The state of `P` held, `None` for other variants
+    fn project(&self) -> Option<&P>;
+}
+
+/// Declare an enum combining the states of several machines
+///
+/// ```
+/// # use rs_statemachine::*;
+/// #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+/// enum PaymentState { Pending, Paid }
+/// impl State for PaymentState {}
+///
+/// #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+/// enum ShippingState { Packing, Shipped }
+/// impl State for ShippingState {}
+///
+/// combine_states!(Combined {
+///     Payment(PaymentState),
+///     Shipping(ShippingState),
+/// });
+///
+/// assert_eq!(
+///     CombinedState::<PaymentState>::project(&Combined::Payment(PaymentState::Paid)),
+///     Some(&PaymentState::Paid)
+/// );
+/// ```
+///
+/// Each state type may appear in one variant only.
+#[macro_export]
+macro_rules! combine_states {
+    ($(#[$meta:meta])* $vis:vis $name:ident { $($variant:ident($inner:ty)),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+        $vis enum $name {
+            $($variant($inner)),+
+        }
+
+        impl $crate::State for $name {}
+
+        $(
+            impl $crate::CombinedState<$inner> for $name {
+                fn lift(state: $inner) -> Self {
+                    $name::$variant(state)
+                }
+
+                #[allow(unreachable_patterns)]
+                fn project(&self) -> Option<&$inner> {
+                    match self {
+                        $name::$variant(state) => Some(state),
+                        _ => None,
+                    }
+                }
+            }
+        )+
+    };
+}
+
+type Callback<S, E, C, R> = Arc<dyn Fn(&S, &E, &C) -> R + Send + Sync>;
+
+fn project<P, Q: CombinedState<P>>(state: &Q) -> Option<&P> {
+    CombinedState::<P>::project(state)
+}
+
+// `callback` over lifted states, returning `skipped()` for other variants
+fn lift_callback<P, Q, E, C, R>(
+    callback: Callback<P, E, C, R>,
+    skipped: fn() -> R,
+) -> Callback<Q, E, C, R>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    E: 'static,
+    C: 'static,
+    R: 'static,
+{
+    Arc::new(move |s, e, c| match project(s) {
+        Some(s) => callback(s, e, c),
+        None => skipped(),
+    })
+}
+
+fn lift_action_mut<P, Q, E, C>(action: crate::ActionMut<P, E, C>) -> crate::ActionMut<Q, E, C>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    E: 'static,
+    C: 'static,
+{
+    Arc::new(move |s, e, c| {
+        if let Some(s) = project(s) {
+            action(s, e, c)
+        }
+    })
+}
+
+fn project_info<'a, P, Q, E>(info: &TransitionInfo<'a, Q, E>) -> Option<TransitionInfo<'a, P, E>>
+where
+    Q: CombinedState<P>,
+{
+    Some(TransitionInfo {
+        machine_id: info.machine_id,
+        from: project(info.from)?,
+        to: project(info.to)?,
+        event: info.event,
+        transition_type: info.transition_type,
+        priority: info.priority,
+        tag: info.tag,
+    })
+}
+
+fn lift_info_condition<P, Q, E, C>(condition: InfoCondition<P, E, C>) -> InfoCondition<Q, E, C>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    E: 'static,
+    C: 'static,
+{
+    Arc::new(move |info, c, values: &mut DerivedValues<'_, C>| {
+        project_info(info).is_some_and(|info| condition(&info, c, values))
+    })
+}
+
+fn lift_info_action<P, Q, E, C>(action: InfoAction<P, E, C>) -> InfoAction<Q, E, C>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    E: 'static,
+    C: 'static,
+{
+    Arc::new(
+        move |info, c, values: &mut DerivedValues<'_, C>| match project_info(info) {
+            Some(info) => action(&info, c, values),
+            None => Ok(()),
+        },
+    )
+}
+
+#[cfg(feature = "extended")]
+fn lift_state_action<P, Q, C>(action: crate::StateAction<P, C>) -> crate::StateAction<Q, C>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    C: 'static,
+{
+    Arc::new(move |s, c| {
+        if let Some(s) = project(s) {
+            action(s, c)
+        }
+    })
+}
+
+#[cfg(feature = "extended")]
+fn lift_state_action_mut<P, Q, C>(
+    action: crate::StateActionMut<P, C>,
+) -> crate::StateActionMut<Q, C>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    C: 'static,
+{
+    Arc::new(move |s, c| {
+        if let Some(s) = project(s) {
+            action(s, c)
+        }
+    })
+}
+
+// `error` with its states projected, `None` if one is of another variant
+fn project_error<P, Q, E>(error: &TransitionError<Q, E>) -> Option<TransitionError<P, E>>
+where
+    P: Clone,
+    Q: CombinedState<P>,
+    E: Clone,
+{
+    let state = |state: &Q| project(state).cloned();
+    let states = |states: &[Q]| states.iter().map(state).collect::<Option<Vec<_>>>();
+    Some(match error {
+        TransitionError::NoValidTransition {
+            from,
+            event,
+            accepted,
+        } => TransitionError::NoValidTransition {
+            from: state(from)?,
+            event: event.clone(),
+            accepted: accepted.clone(),
+        },
+        TransitionError::ConditionFailed { from, event, guard } => {
+            TransitionError::ConditionFailed {
+                from: state(from)?,
+                event: event.clone(),
+                guard: guard.clone(),
+            }
+        }
+        TransitionError::FeatureDisabled { from, event, flags } => {
+            TransitionError::FeatureDisabled {
+                from: state(from)?,
+                event: event.clone(),
+                flags: flags.clone(),
+            }
+        }
+        TransitionError::AmbiguousTransition {
+            from,
+            event,
+            matched,
+        } => TransitionError::AmbiguousTransition {
+            from: state(from)?,
+            event: event.clone(),
+            matched: matched
+                .iter()
+                .map(|(to, name)| Some((state(to)?, name.clone())))
+                .collect::<Option<_>>()?,
+        },
+        TransitionError::StaleState { expected, actual } => TransitionError::StaleState {
+            expected: state(expected)?,
+            actual: state(actual)?,
+        },
+        TransitionError::EntityNotFound { key } => {
+            TransitionError::EntityNotFound { key: key.clone() }
+        }
+        TransitionError::VersionConflict {
+            key,
+            expected,
+            actual,
+        } => TransitionError::VersionConflict {
+            key: key.clone(),
+            expected: *expected,
+            actual: *actual,
+        },
+        TransitionError::ContextLoadFailed { key, error } => TransitionError::ContextLoadFailed {
+            key: key.clone(),
+            error: error.clone(),
+        },
+        TransitionError::ContextSaveFailed {
+            key,
+            error,
+            rollback,
+        } => TransitionError::ContextSaveFailed {
+            key: key.clone(),
+            error: error.clone(),
+            rollback: *rollback,
+        },
+        TransitionError::OutOfOrder { last, attempted } => TransitionError::OutOfOrder {
+            last: *last,
+            attempted: *attempted,
+        },
+        TransitionError::DeadlineExpired { deadline } => TransitionError::DeadlineExpired {
+            deadline: *deadline,
+        },
+        TransitionError::MachineArchived { machine_id } => TransitionError::MachineArchived {
+            machine_id: machine_id.clone(),
+        },
+        TransitionError::ApprovalRequired { from, event } => TransitionError::ApprovalRequired {
+            from: state(from)?,
+            event: event.clone(),
+        },
+        TransitionError::ApprovalRejected { reason } => TransitionError::ApprovalRejected {
+            reason: reason.clone(),
+        },
+        TransitionError::MaxChainDepthExceeded { depth } => {
+            TransitionError::MaxChainDepthExceeded { depth: *depth }
+        }
+        TransitionError::TerminalState { state: terminal } => TransitionError::TerminalState {
+            state: state(terminal)?,
+        },
+        TransitionError::EventNotAllowedInState {
+            state: locked,
+            event,
+        } => TransitionError::EventNotAllowedInState {
+            state: state(locked)?,
+            event: event.clone(),
+        },
+        TransitionError::TransitionBudgetExhausted { limit } => {
+            TransitionError::TransitionBudgetExhausted { limit: *limit }
+        }
+        TransitionError::LoopDetected {
+            from,
+            event,
+            to,
+            repeats,
+        } => TransitionError::LoopDetected {
+            from: state(from)?,
+            event: event.clone(),
+            to: state(to)?,
+            repeats: *repeats,
+        },
+        TransitionError::CompletionLoop { path } => TransitionError::CompletionLoop {
+            path: states(path)?,
+        },
+        TransitionError::ActionFailed { source } => TransitionError::ActionFailed {
+            source: source.clone(),
+        },
+        TransitionError::ReplayDiverged { error } => TransitionError::ReplayDiverged {
+            error: error.clone(),
+        },
+        TransitionError::IdempotencyKeyReused { key, from, event } => {
+            TransitionError::IdempotencyKeyReused {
+                key: key.clone(),
+                from: state(from)?,
+                event: event.clone(),
+            }
+        }
+        TransitionError::IdempotencyKeyInUse { key } => {
+            TransitionError::IdempotencyKeyInUse { key: key.clone() }
+        }
+        TransitionError::IntentLogFailed {
+            idempotency_key,
+            reason,
+        } => TransitionError::IntentLogFailed {
+            idempotency_key: idempotency_key.clone(),
+            reason: reason.clone(),
+        },
+        #[cfg(feature = "extended")]
+        TransitionError::StateRequirementFailed {
+            state: entered,
+            requirement,
+        } => TransitionError::StateRequirementFailed {
+            state: state(entered)?,
+            requirement: requirement.clone(),
+        },
+        #[cfg(any(feature = "timeout", feature = "async"))]
+        TransitionError::Timeout { hop, from, event } => TransitionError::Timeout {
+            hop: *hop,
+            from: state(from)?,
+            event: event.clone(),
+        },
+        #[cfg(feature = "async")]
+        TransitionError::AsyncError(message) => TransitionError::AsyncError(message.clone()),
+        #[cfg(feature = "async")]
+        TransitionError::Cancelled { from, event } => TransitionError::Cancelled {
+            from: state(from)?,
+            event: event.clone(),
+        },
+    })
+}
+
+// Listener of the original machine, told about fires from its own states
+//
+// The named hooks are not forwarded: they carry names of the combined
+// states, without the states to tell which region fired.
+struct LiftedListener<P, Q, E, C> {
+    inner: Arc<dyn TransitionListener<P, E, C>>,
+    _states: PhantomData<fn(&Q)>,
+}
+
+impl<P, Q, E, C> TransitionListener<Q, E, C> for LiftedListener<P, Q, E, C>
+where
+    P: State,
+    Q: CombinedState<P>,
+    E: Event,
+    C: Context,
+{
+    fn before_transition(&self, from: &Q, event: &E, context: &C) {
+        if let Some(from) = project(from) {
+            self.inner.before_transition(from, event, context);
+        }
+    }
+
+    fn after_transition(&self, from: &Q, to: &Q, event: &E, context: &C) {
+        if let (Some(from), Some(to)) = (project(from), project(to)) {
+            self.inner.after_transition(from, to, event, context);
+        }
+    }
+
+    fn on_failure(&self, from: &Q, event: &E, context: &C, error: &TransitionError<Q, E>) {
+        if let (Some(from), Some(error)) = (project(from), project_error(error)) {
+            self.inner.on_failure(from, event, context, &error);
+        }
+    }
+
+    fn after_context_changes(
+        &self,
+        from: &Q,
+        to: &Q,
+        event: &E,
+        changes: &[FieldChange],
+        context: &C,
+    ) {
+        if let (Some(from), Some(to)) = (project(from), project(to)) {
+            self.inner
+                .after_context_changes(from, to, event, changes, context);
+        }
+    }
+}
+
+fn lift_slow_handler<P, Q, E>(handler: SlowCallbackHandler<P, E>) -> SlowCallbackHandler<Q, E>
+where
+    P: 'static,
+    Q: CombinedState<P> + 'static,
+    E: 'static,
+{
+    Arc::new(move |info: &CallbackInfo<'_, Q, E>, elapsed| {
+        let transition = match info.transition {
+            Some((event, to)) => match project(to) {
+                Some(to) => Some((event, to)),
+                None => return,
+            },
+            None => None,
+        };
+        if let Some(state) = project(info.state) {
+            let info = CallbackInfo {
+                kind: info.kind,
+                machine_id: info.machine_id,
+                state,
+                transition,
+            };
+            handler(&info, elapsed)
+        }
+    })
+}
+
+/// Turn `machine` into a machine over the combined states `Q`, wrapping
+/// its states with `variant`, see the module documentation
+///
+/// ```ignore
+/// let payment = lift_machine(payment_machine, Combined::Payment);
+/// ```
+#[cfg(not(feature = "async"))]
+pub fn lift_machine<P, Q, E, C>(
+    machine: StateMachine<P, E, C>,
+    variant: fn(P) -> Q,
+) -> StateMachine<Q, E, C>
+where
+    P: State + 'static,
+    Q: CombinedState<P> + 'static,
+    E: Event + 'static,
+    C: Context + 'static,
+{
+    lift_definition(machine, variant)
+}
+
+/// Turn `machine` into a machine over the combined states `Q`, wrapping
+/// its states with `variant`, see the module documentation
+///
+/// ```ignore
+/// let payment = lift_machine(payment_machine, Combined::Payment);
+/// ```
+#[cfg(feature = "async")]
+pub fn lift_machine<P, Q, E, C>(
+    mut machine: StateMachine<P, E, C>,
+    variant: fn(P) -> Q,
+) -> StateMachine<Q, E, C>
+where
+    P: State + Send + Sync + 'static,
+    Q: CombinedState<P> + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    C: Context + Send + Sync + 'static,
+{
+    let async_actions = std::mem::take(&mut machine.async_actions);
+    let mut lifted = lift_definition(machine, variant);
+    lifted.async_actions = async_actions
+        .into_iter()
+        .map(|((from, event), inner)| {
+            let action: Arc<dyn crate::AsyncAction<Q, E, C>> =
+                Arc::new(async_lift::LiftedAsyncAction {
+                    inner,
+                    _states: PhantomData,
+                });
+            ((variant(from), event), action)
+        })
+        .collect();
+    lifted
+}
+
+// Everything but the async actions
+//
+// Every struct is destructured without `..`, so a field added to the
+// machine, its transitions or its state actions fails to compile here
+// until it is lifted or dropped on purpose.
+fn lift_definition<P, Q, E, C>(
+    machine: StateMachine<P, E, C>,
+    variant: fn(P) -> Q,
+) -> StateMachine<Q, E, C>
+where
+    P: State + 'static,
+    Q: CombinedState<P> + 'static,
+    E: Event + 'static,
+    C: Context + 'static,
+{
+    let StateMachine {
+        id,
+        transitions,
+        wildcard_transitions,
+        incoming: _,
+        accepted: _,
+        completion_transitions,
+        fail_callback,
+        listeners,
+        context_differ,
+        feature_flags,
+        derivations,
+        services,
+        guard_resolution,
+        max_chain_depth,
+        sequence_window,
+        instance_limits,
+        initial_state,
+        terminal_states,
+        locked_events,
+        clock,
+        determinism,
+        deadline_check,
+        slow_callbacks,
+        #[cfg(feature = "metrics")]
+        failure_rate,
+        overrides: _,
+        archived,
+        descriptions,
+        names: _,
+        recording,
+        #[cfg(feature = "extended")]
+        state_actions,
+        #[cfg(feature = "extended")]
+        state_requirements,
+        #[cfg(feature = "timeout")]
+        state_timeouts,
+        #[cfg(feature = "timeout")]
+        timeout_transitions,
+        #[cfg(feature = "async")]
+        async_actions,
+        #[cfg(feature = "async")]
+        cancel_callbacks,
+        #[cfg(feature = "serde")]
+        snapshot_codec,
+    } = machine;
+    // Taken out and lifted by the async `lift_machine`
+    #[cfg(feature = "async")]
+    debug_assert!(async_actions.is_empty());
+
+    let lift_transition = |t: Transition<P, E, C>, wildcard: bool| {
+        let Transition {
+            from,
+            to,
+            event,
+            guard,
+            action,
+            transition_type,
+            required_flag,
+            approval,
+            pure_action,
+            irreversible,
+            tag,
+            names,
+            #[cfg(feature = "guards")]
+            priority,
+        } = t;
+        let (condition, info_condition) = guard.into_parts();
+        let condition = condition.map(|c| lift_callback(c, || Err(None)));
+        Transition {
+            from: variant(from),
+            to: variant(to),
+            event,
+            // Keep `from_any` transitions to states of the variant
+            guard: Guard::from_parts(
+                match (wildcard, condition) {
+                    (true, condition) => {
+                        let guard: crate::CheckedCondition<Q, E, C> = Arc::new(move |s, e, c| {
+                            project::<P, Q>(s).ok_or(None)?;
+                            condition
+                                .as_ref()
+                                .map_or(Ok(()), |condition| condition(s, e, c))
+                        });
+                        Some(guard)
+                    }
+                    (false, condition) => condition,
+                },
+                info_condition.map(lift_info_condition),
+            ),
+            action: match action {
+                Action::Nothing => Action::Nothing,
+                Action::Plain(a) => Action::Plain(lift_callback(a, || ())),
+                Action::Mut(a) => Action::Mut(lift_action_mut(a)),
+                Action::Fallible(a) => Action::Fallible(lift_callback(a, || Ok(()))),
+                Action::Followups(a) => Action::Followups(lift_callback(a, Vec::new)),
+                Action::Info(a) => Action::Info(lift_info_action(a)),
+            },
+            transition_type,
+            required_flag,
+            approval,
+            pure_action,
+            irreversible,
+            tag,
+            names,
+            #[cfg(feature = "guards")]
+            priority,
+        }
+    };
+    let transitions = transitions
+        .into_iter()
+        .map(|((from, event), candidates)| {
+            let candidates: Vec<Transition<Q, E, C>> = candidates
+                .into_vec()
+                .into_iter()
+                .map(|t| lift_transition(t, false))
+                .collect();
+            ((variant(from), event), candidates.into_boxed_slice())
+        })
+        .collect();
+    let wildcard_transitions = wildcard_transitions
+        .into_iter()
+        .map(|(event, candidates)| {
+            let candidates: Vec<Transition<Q, E, C>> = candidates
+                .into_vec()
+                .into_iter()
+                .map(|t| lift_transition(t, true))
+                .collect();
+            (event, candidates.into_boxed_slice())
+        })
+        .collect();
+    let incoming = IncomingIndex::new(&transitions, &wildcard_transitions);
+    let crate::descriptions::Descriptions {
+        states: state_descriptions,
+        events: event_descriptions,
+    } = descriptions;
+
+    let mut lifted = StateMachine {
+        id,
+        transitions,
+        wildcard_transitions,
+        incoming,
+        accepted: OnceLock::new(),
+        completion_transitions: completion_transitions
+            .into_iter()
+            .map(|(from, candidates)| {
+                let candidates: Vec<CompletionTransition<Q, E, C>> = candidates
+                    .into_vec()
+                    .into_iter()
+                    .map(|t| {
+                        let CompletionTransition {
+                            from,
+                            to,
+                            condition,
+                            #[cfg(feature = "guards")]
+                            priority,
+                        } = t;
+                        CompletionTransition {
+                            from: variant(from),
+                            to: variant(to),
+                            condition: condition.map(|c| lift_callback(c, || false)),
+                            #[cfg(feature = "guards")]
+                            priority,
+                        }
+                    })
+                    .collect();
+                (variant(from), candidates.into_boxed_slice())
+            })
+            .collect(),
+        fail_callback: fail_callback.map(|f| lift_callback(f, || ())),
+        listeners: listeners
+            .into_iter()
+            .map(|inner| {
+                let listener: Arc<dyn TransitionListener<Q, E, C>> = Arc::new(LiftedListener {
+                    inner,
+                    _states: PhantomData,
+                });
+                listener
+            })
+            .collect(),
+        context_differ,
+        feature_flags,
+        derivations,
+        services,
+        guard_resolution,
+        max_chain_depth,
+        sequence_window,
+        instance_limits: instance_limits.map_loop_callback(|callback| {
+            Arc::new(move |from: &Q, event: &E, to: &Q, repeats| {
+                if let (Some(from), Some(to)) = (project(from), project(to)) {
+                    callback(from, event, to, repeats)
+                }
+            })
+        }),
+        initial_state: initial_state.map(variant),
+        terminal_states: terminal_states.into_iter().map(variant).collect(),
+        locked_events: locked_events
+            .into_iter()
+            .map(|(state, events)| (variant(state), events))
+            .collect(),
+        clock,
+        determinism,
+        deadline_check,
+        slow_callbacks: slow_callbacks.map(|slow| slow.map_handler(lift_slow_handler)),
+        #[cfg(feature = "metrics")]
+        failure_rate,
+        overrides: Arc::default(),
+        archived: Arc::new(AtomicBool::new(archived.load(Ordering::Acquire))),
+        descriptions: crate::descriptions::Descriptions {
+            states: state_descriptions
+                .into_iter()
+                .map(|(state, description)| (variant(state), description))
+                .collect(),
+            events: event_descriptions,
+        },
+        names: Arc::default(),
+        recording: Arc::new(recording.fresh_for()),
+        #[cfg(feature = "extended")]
+        state_actions: state_actions
+            .into_iter()
+            .map(|(state, actions)| {
+                let crate::StateActions {
+                    on_entry,
+                    on_exit,
+                    on_entry_mut,
+                    on_exit_mut,
+                    on_entry_fallible,
+                    pure,
+                    _phantom,
+                } = actions;
+                let actions = crate::StateActions {
+                    on_entry: on_entry.map(lift_state_action),
+                    on_exit: on_exit.map(lift_state_action),
+                    on_entry_mut: on_entry_mut.map(lift_state_action_mut),
+                    on_exit_mut: on_exit_mut.map(lift_state_action_mut),
+                    on_entry_fallible: on_entry_fallible.map(|action| {
+                        let lifted: crate::FallibleStateAction<Q, C> =
+                            Arc::new(move |s: &Q, c: &C| match project(s) {
+                                Some(s) => action(s, c),
+                                None => Ok(()),
+                            });
+                        lifted
+                    }),
+                    pure,
+                    _phantom,
+                };
+                (variant(state), actions)
+            })
+            .collect(),
+        #[cfg(feature = "extended")]
+        state_requirements: state_requirements
+            .into_iter()
+            .map(|(state, requirements)| (variant(state), requirements))
+            .collect(),
+        #[cfg(feature = "timeout")]
+        state_timeouts: state_timeouts
+            .into_iter()
+            .map(|(state, timeout)| (variant(state), timeout))
+            .collect(),
+        #[cfg(feature = "timeout")]
+        timeout_transitions: timeout_transitions
+            .into_iter()
+            .map(|(from, (to, event))| (variant(from), (variant(to), event)))
+            .collect(),
+        #[cfg(feature = "async")]
+        async_actions: Default::default(),
+        #[cfg(feature = "async")]
+        cancel_callbacks: cancel_callbacks
+            .into_iter()
+            .map(|((from, event), cleanup)| ((variant(from), event), lift_callback(cleanup, || ())))
+            .collect(),
+        #[cfg(feature = "serde")]
+        snapshot_codec,
+    };
+    lifted.names = Arc::new(crate::names::Names::new(&lifted));
+    lifted
+}
+
+#[cfg(feature = "async")]
+mod async_lift {
+    use std::marker::PhantomData;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use super::{project, CombinedState};
+    use crate::{AsyncAction, Context, Event, State};
+
+    pub(super) struct LiftedAsyncAction<P, Q, E, C> {
+        pub(super) inner: Arc<dyn AsyncAction<P, E, C>>,
+        pub(super) _states: PhantomData<fn(&Q)>,
+    }
+
+    #[async_trait]
+    impl<P, Q, E, C> AsyncAction<Q, E, C> for LiftedAsyncAction<P, Q, E, C>
+    where
+        P: State + Send + Sync,
+        Q: CombinedState<P> + Send + Sync,
+        E: Event + Send + Sync,
+        C: Context + Send + Sync,
+    {
+        async fn execute(&self, from: &Q, event: &E, context: &C) {
+            if let Some(from) = project(from) {
+                self.inner.execute(from, event, context).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{ParallelStateMachine, StateMachineBuilderFactory};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum PaymentState {
+        Pending,
+        Paid,
+    }
+
+    impl State for PaymentState {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum ShippingState {
+        Packing,
+        Shipped,
+    }
+
+    impl State for ShippingState {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum OrderEvent {
+        Pay,
+        Ship,
+        Refund,
+    }
+
+    impl Event for OrderEvent {}
+
+    combine_states!(Combined {
+        Payment(PaymentState),
+        Shipping(ShippingState),
+    });
+
+    fn payment_machine(
+        paid: &Arc<Mutex<Vec<PaymentState>>>,
+    ) -> StateMachine<PaymentState, OrderEvent, NoContext> {
+        let log = paid.clone();
+        let mut builder =
+            StateMachineBuilderFactory::create::<PaymentState, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(PaymentState::Pending)
+            .to(PaymentState::Paid)
+            .on(OrderEvent::Pay)
+            .when(|s, _e, _c| *s == PaymentState::Pending)
+            .perform(move |s, _e, _c| log.lock().unwrap().push(s.clone()));
+        builder
+            .external_transitions()
+            .from_any()
+            .to(PaymentState::Pending)
+            .on(OrderEvent::Refund)
+            .add();
+        builder.id("payment").build()
+    }
+
+    fn shipping_machine() -> StateMachine<ShippingState, OrderEvent, NoContext> {
+        let mut builder =
+            StateMachineBuilderFactory::create::<ShippingState, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(ShippingState::Packing)
+            .to(ShippingState::Shipped)
+            .on(OrderEvent::Ship)
+            .add();
+        builder.id("shipping").build()
+    }
+
+    #[test]
+    fn test_lifted_machines_keep_behavior() {
+        let paid = Arc::new(Mutex::new(Vec::new()));
+        let payment = lift_machine(payment_machine(&paid), Combined::Payment);
+        let shipping = lift_machine(shipping_machine(), Combined::Shipping);
+
+        assert_eq!(
+            payment
+                .fire_event(
+                    Combined::Payment(PaymentState::Pending),
+                    OrderEvent::Pay,
+                    NoContext
+                )
+                .unwrap(),
+            Combined::Payment(PaymentState::Paid)
+        );
+        assert_eq!(*paid.lock().unwrap(), vec![PaymentState::Pending]);
+        assert_eq!(
+            shipping
+                .fire_event(
+                    Combined::Shipping(ShippingState::Packing),
+                    OrderEvent::Ship,
+                    NoContext
+                )
+                .unwrap(),
+            Combined::Shipping(ShippingState::Shipped)
+        );
+        assert!(payment
+            .states()
+            .contains(&Combined::Payment(PaymentState::Paid)));
+
+        // `from_any` stays within the payment states
+        assert!(payment
+            .fire_event(
+                Combined::Payment(PaymentState::Paid),
+                OrderEvent::Refund,
+                NoContext
+            )
+            .is_ok());
+        assert!(payment
+            .fire_event(
+                Combined::Shipping(ShippingState::Shipped),
+                OrderEvent::Refund,
+                NoContext
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_parallel_regions_of_different_types() {
+        let paid = Arc::new(Mutex::new(Vec::new()));
+        let mut parallel = ParallelStateMachine::new();
+        parallel.add_region(lift_machine(payment_machine(&paid), Combined::Payment));
+        parallel.add_region(lift_machine(shipping_machine(), Combined::Shipping));
+        let states = vec![
+            Combined::Payment(PaymentState::Pending),
+            Combined::Shipping(ShippingState::Packing),
+        ];
+
+        // Only the payment region understands paying
+        let outcome = parallel
+            .fire_event(states, OrderEvent::Pay, NoContext)
+            .unwrap();
+        assert!(outcome.region("payment").unwrap().result.is_ok());
+        assert!(outcome.region("shipping").unwrap().result.is_err());
+        let states = outcome.next_states();
+        assert_eq!(
+            states,
+            vec![
+                Combined::Payment(PaymentState::Paid),
+                Combined::Shipping(ShippingState::Packing),
+            ]
+        );
+
+        let outcome = parallel
+            .fire_event(states, OrderEvent::Ship, NoContext)
+            .unwrap();
+        assert_eq!(
+            outcome.next_states(),
+            vec![
+                Combined::Payment(PaymentState::Paid),
+                Combined::Shipping(ShippingState::Shipped),
+            ]
+        );
+        assert_eq!(paid.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+//! Observers notified around every fire
+//!
+//! Listeners added with `StateMachineBuilder::add_listener` see each fire of
+//! the machine, sync or async: `before_transition` once the fire starts,
+//! then `after_transition` when it succeeded or `on_failure` when it did
+//! not. `after_transition_named` and `on_failure_named` follow them with
+//! the interned names of the states and event, for logging without
+//! formatting. On a machine with a context differ, `after_context_changes`
+//! then gets what the fire changed in the context. They run in registration
+//! order. A panicking listener is caught and skipped, so it cannot change
+//! the outcome of the fire or keep the listeners after it from running. The
+//! single `set_fail_callback` keeps working alongside them.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::context_map::ContextMapper;
+use crate::outcome::FireResult;
+use crate::{
+    Context, Event, FieldChange, State, StateMachine, StateMachineBuilder, TransitionError,
+    TransitionNames,
+};
+
+/// Cross-cutting behavior run around every fire, such as audit logging
+///
+/// All hooks default to doing nothing.
+pub trait TransitionListener<S, E, C>: Send + Sync
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    fn before_transition(&self, _from: &S, _event: &E, _context: &C) {}
+
+    fn after_transition(&self, _from: &S, _to: &S, _event: &E, _context: &C) {}
+
+    fn on_failure(&self, _from: &S, _event: &E, _context: &C, _error: &TransitionError<S, E>) {}
+
+    /// Run after `after_transition`, see `StateMachine::state_name`
+    fn after_transition_named(&self, _names: &TransitionNames, _context: &C) {}
+
+    /// Run after `on_failure`; `names.to` is `None`
+    fn on_failure_named(
+        &self,
+        _names: &TransitionNames,
+        _context: &C,
+        _error: &TransitionError<S, E>,
+    ) {
+    }
+
+    /// Run after `after_transition_named` when the machine has a context
+    /// differ, see `StateMachineBuilder::with_context_differ`
+    fn after_context_changes(
+        &self,
+        _from: &S,
+        _to: &S,
+        _event: &E,
+        _changes: &[FieldChange],
+        _context: &C,
+    ) {
+    }
+}
+
+pub(crate) type Listeners<S, E, C> = Vec<Arc<dyn TransitionListener<S, E, C>>>;
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Notify `listener` around every fire, after the listeners added before
+    pub fn add_listener(&mut self, listener: Box<dyn TransitionListener<S, E, C>>) -> &mut Self {
+        self.listeners.push(Arc::from(listener));
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub(crate) fn notify_before(&self, from: &S, event: &E, context: &C) {
+        for listener in &self.listeners {
+            isolate(|| listener.before_transition(from, event, context));
+        }
+    }
+
+    pub(crate) fn notify_outcome<T>(
+        &self,
+        from: &S,
+        event: &E,
+        context: &C,
+        result: &FireResult<S, E, T>,
+    ) {
+        match result {
+            Ok((outcome, _)) => {
+                for listener in &self.listeners {
+                    isolate(|| listener.after_transition(from, &outcome.to, event, context));
+                    isolate(|| listener.after_transition_named(&outcome.names, context));
+                    if self.context_differ.is_some() {
+                        isolate(|| {
+                            listener.after_context_changes(
+                                from,
+                                &outcome.to,
+                                event,
+                                &outcome.context_changes,
+                                context,
+                            )
+                        });
+                    }
+                }
+            }
+            Err(error) => {
+                let names = self.names.transition(from, None, event);
+                for listener in &self.listeners {
+                    isolate(|| listener.on_failure(from, event, context, error));
+                    isolate(|| listener.on_failure_named(&names, context, error));
+                }
+            }
+        }
+    }
+}
+
+// A listener panicking must not unwind into the fire
+fn isolate(hook: impl FnOnce()) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(hook));
+}
+
+// Listener of a machine driven through `map_context`
+struct MappedListener<S, E, C, C2>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    listener: Arc<dyn TransitionListener<S, E, C>>,
+    map: ContextMapper<C2, C>,
+}
+
+impl<S, E, C, C2> TransitionListener<S, E, C2> for MappedListener<S, E, C, C2>
+where
+    S: State,
+    E: Event,
+    C: Context,
+    C2: Context,
+{
+    fn before_transition(&self, from: &S, event: &E, context: &C2) {
+        self.listener
+            .before_transition(from, event, &(self.map)(context));
+    }
+
+    fn after_transition(&self, from: &S, to: &S, event: &E, context: &C2) {
+        self.listener
+            .after_transition(from, to, event, &(self.map)(context));
+    }
+
+    fn on_failure(&self, from: &S, event: &E, context: &C2, error: &TransitionError<S, E>) {
+        self.listener
+            .on_failure(from, event, &(self.map)(context), error);
+    }
+
+    fn after_transition_named(&self, names: &TransitionNames, context: &C2) {
+        self.listener
+            .after_transition_named(names, &(self.map)(context));
+    }
+
+    fn on_failure_named(
+        &self,
+        names: &TransitionNames,
+        context: &C2,
+        error: &TransitionError<S, E>,
+    ) {
+        self.listener
+            .on_failure_named(names, &(self.map)(context), error);
+    }
+
+    fn after_context_changes(
+        &self,
+        from: &S,
+        to: &S,
+        event: &E,
+        changes: &[FieldChange],
+        context: &C2,
+    ) {
+        self.listener
+            .after_context_changes(from, to, event, changes, &(self.map)(context));
+    }
+}
+
+pub(crate) fn map_listeners<S, E, C, C2>(
+    listeners: Listeners<S, E, C>,
+    map: &ContextMapper<C2, C>,
+) -> Listeners<S, E, C2>
+where
+    S: State + 'static,
+    E: Event + 'static,
+    C: Context + 'static,
+    C2: Context + 'static,
+{
+    listeners
+        .into_iter()
+        .map(|listener| {
+            let mapped: Arc<dyn TransitionListener<S, E, C2>> = Arc::new(MappedListener {
+                listener,
+                map: map.clone(),
+            });
+            mapped
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Door {
+        Open,
+        Closed,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Open,
+        Close,
+    }
+
+    impl Event for DoorEvent {}
+
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TransitionListener<Door, DoorEvent, NoContext> for Recorder {
+        fn before_transition(&self, from: &Door, event: &DoorEvent, _context: &NoContext) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} before {:?} {:?}", self.name, from, event));
+        }
+
+        fn after_transition(
+            &self,
+            from: &Door,
+            to: &Door,
+            _event: &DoorEvent,
+            _context: &NoContext,
+        ) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} after {:?} -> {:?}", self.name, from, to));
+        }
+
+        fn on_failure(
+            &self,
+            _from: &Door,
+            _event: &DoorEvent,
+            _context: &NoContext,
+            error: &TransitionError<Door, DoorEvent>,
+        ) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} failed {}", self.name, error.code()));
+        }
+    }
+
+    struct Panicking;
+
+    impl TransitionListener<Door, DoorEvent, NoContext> for Panicking {
+        fn before_transition(&self, _from: &Door, _event: &DoorEvent, _context: &NoContext) {
+            panic!("listener failure");
+        }
+    }
+
+    fn door_machine(log: &Arc<Mutex<Vec<String>>>) -> StateMachine<Door, DoorEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Open)
+            .perform(|_s, _e, _c| {});
+        builder
+            .add_listener(Box::new(Recorder {
+                name: "audit",
+                log: log.clone(),
+            }))
+            .add_listener(Box::new(Panicking))
+            .add_listener(Box::new(Recorder {
+                name: "cache",
+                log: log.clone(),
+            }));
+        builder.build()
+    }
+
+    #[test]
+    fn test_listeners_run_in_registration_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let machine = door_machine(&log);
+        assert_eq!(
+            machine
+                .fire_event(Door::Closed, DoorEvent::Open, NoContext)
+                .unwrap(),
+            Door::Open
+        );
+        assert!(machine
+            .fire_event(Door::Open, DoorEvent::Close, NoContext)
+            .is_err());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "audit before Closed Open",
+                "cache before Closed Open",
+                "audit after Closed -> Open",
+                "cache after Closed -> Open",
+                "audit before Open Close",
+                "cache before Open Close",
+                "audit failed no_valid_transition",
+                "cache failed no_valid_transition",
+            ]
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_listeners_notified_on_async_fire() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let machine = door_machine(&log);
+        assert_eq!(
+            machine
+                .fire_event_async(Door::Closed, DoorEvent::Open, NoContext)
+                .await
+                .unwrap(),
+            Door::Open
+        );
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "audit before Closed Open",
+                "cache before Closed Open",
+                "audit after Closed -> Open",
+                "cache after Closed -> Open",
+            ]
+        );
+    }
+}
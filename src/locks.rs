@@ -0,0 +1,186 @@
+//! Per-state event whitelists
+//!
+//! A state locked with `StateMachineBuilder::lock_state_events` accepts only
+//! the listed events. Firing anything else from it fails with
+//! `TransitionError::EventNotAllowedInState` before any transition, wildcard
+//! or override is looked up. Registering a transition from a locked state on
+//! an event it doesn't allow fails the build, and installing such an
+//! override panics.
+
+use std::collections::HashSet;
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder};
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Only accept `allowed` events in `state`
+    ///
+    /// Locking a state again adds to its list. `build` panics, and
+    /// `try_build` fails, if `state` has a transition on another event.
+    pub fn lock_state_events(&mut self, state: S, allowed: Vec<E>) -> &mut Self {
+        self.locked_events.entry(state).or_default().extend(allowed);
+        self
+    }
+
+    // Transitions registered on events their locked source state doesn't
+    // allow, as (state, event)
+    pub(crate) fn disallowed_transitions(&self) -> Vec<(&S, &E)> {
+        let mut disallowed: Vec<_> = self
+            .transitions
+            .iter()
+            .filter(|t| {
+                self.locked_events
+                    .get(&t.from)
+                    .is_some_and(|allowed| !allowed.contains(&t.event))
+            })
+            .map(|t| (&t.from, &t.event))
+            .collect();
+        disallowed.sort_by_cached_key(|entry| format!("{:?}", entry));
+        disallowed.dedup();
+        disallowed
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Whether `state` accepts `event`; true for states that aren't locked
+    pub fn is_event_allowed(&self, state: &S, event: &E) -> bool {
+        self.locked_events
+            .get(state)
+            .is_none_or(|allowed: &HashSet<E>| allowed.contains(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{BuildError, StateMachineBuilderFactory, TransitionError};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Loan {
+        Draft,
+        UnderReview,
+        Approved,
+    }
+
+    impl State for Loan {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum LoanEvent {
+        Submit,
+        Approve,
+        Decline,
+        Edit,
+    }
+
+    impl Event for LoanEvent {}
+
+    fn loan_builder() -> StateMachineBuilder<Loan, LoanEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Loan, LoanEvent, NoContext>();
+        builder.lock_state_events(
+            Loan::UnderReview,
+            vec![LoanEvent::Approve, LoanEvent::Decline],
+        );
+        builder
+            .external_transition()
+            .from(Loan::Draft)
+            .to(Loan::UnderReview)
+            .on(LoanEvent::Submit)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Loan::UnderReview)
+            .to(Loan::Approved)
+            .on(LoanEvent::Approve)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Loan::Draft)
+            .on(LoanEvent::Edit)
+            .perform(|_s, _e, _c| {});
+        builder
+    }
+
+    #[test]
+    fn test_locked_state_rejects_other_events() {
+        let machine = loan_builder().build();
+        assert!(!machine.is_event_allowed(&Loan::UnderReview, &LoanEvent::Edit));
+        assert!(machine.is_event_allowed(&Loan::Draft, &LoanEvent::Edit));
+
+        // The wildcard edit would apply, but UnderReview doesn't allow it
+        match machine.fire_event(Loan::UnderReview, LoanEvent::Edit, NoContext) {
+            Err(TransitionError::EventNotAllowedInState { state, event }) => {
+                assert_eq!(state, Loan::UnderReview);
+                assert_eq!(event, LoanEvent::Edit);
+            }
+            other => panic!("expected EventNotAllowedInState, got {:?}", other),
+        }
+        assert_eq!(
+            machine
+                .fire_event(Loan::Approved, LoanEvent::Edit, NoContext)
+                .unwrap(),
+            Loan::Draft
+        );
+    }
+
+    #[test]
+    fn test_whitelisted_event_fires_normally() {
+        let machine = loan_builder().build();
+        assert_eq!(
+            machine
+                .fire_event(Loan::UnderReview, LoanEvent::Approve, NoContext)
+                .unwrap(),
+            Loan::Approved
+        );
+        // Allowed, but nothing handles it
+        assert!(matches!(
+            machine.fire_event(Loan::UnderReview, LoanEvent::Decline, NoContext),
+            Err(TransitionError::NoValidTransition { .. })
+        ));
+    }
+
+    #[test]
+    fn test_disallowed_transition_fails_build() {
+        let mut builder = loan_builder();
+        builder
+            .internal_transition()
+            .within(Loan::UnderReview)
+            .on(LoanEvent::Submit)
+            .perform(|_s, _e, _c| {});
+        match builder.try_build() {
+            Err(errors) => assert_eq!(
+                errors,
+                vec![BuildError::EventNotAllowed {
+                    state: "UnderReview".to_string(),
+                    event: "Submit".to_string(),
+                }]
+            ),
+            Ok(_) => panic!("expected the locked state to fail the build"),
+        }
+    }
+
+    #[test]
+    fn test_disallowed_override_rejected() {
+        let machine = loan_builder().build();
+        let guard =
+            machine.override_transition(Loan::UnderReview, LoanEvent::Edit, Loan::Draft, None);
+        assert!(matches!(
+            guard,
+            Err(TransitionError::EventNotAllowedInState {
+                state: Loan::UnderReview,
+                event: LoanEvent::Edit,
+            })
+        ));
+        assert!(machine.active_overrides().is_empty());
+    }
+}
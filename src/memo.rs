@@ -0,0 +1,629 @@
+//! Exactly-once fires across retries of the same command
+//!
+//! A command handler retrying after a transient failure fires the same
+//! event again, running its actions a second time.
+//! `StateMachine::fire_event_idempotent` looks up the idempotency key of the
+//! command in a `ResultStore` first: a key seen before returns the memoized
+//! outcome and follow-up events without running anything, so the caller can
+//! still publish the events it lost. Otherwise the event is fired and its
+//! result stored. A fire failing after an action ran (see
+//! `TransitionError::ActionFailed`) stores its error, returned again on
+//! retry; other failed fires are not memoized and run again on retry.
+//!
+//! A stored result keeps the `from` and `event` of its fire. Reusing the
+//! key for another fire fails with `TransitionError::IdempotencyKeyReused`
+//! instead of replaying a result that belongs to a different command.
+//!
+//! While a first attempt runs, the store holds a claim on its key: a second
+//! attempt made meanwhile fails with `TransitionError::IdempotencyKeyInUse`
+//! and can be retried once the first one finished. Stores that don't
+//! implement `claim` let both attempts fire.
+//!
+//! Entries expire after the time to live of the store, read with the
+//! machine's clock; the key is then processed anew.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{Context, Event, State, StateMachine, TransitionError, TransitionOutcome};
+
+/// Result of a fire under an idempotency key
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoizedFire<S, E> {
+    pub outcome: TransitionOutcome<S, E>,
+    /// Follow-up events raised by the action, see `perform_with_followups`
+    pub emitted: Vec<E>,
+    /// Whether the result was taken from the store instead of firing
+    pub replayed: bool,
+}
+
+/// What a `ResultStore` keeps for an idempotency key
+#[derive(Debug, Clone)]
+pub enum StoredFire<S, E> {
+    /// The fire succeeded
+    Completed(MemoizedFire<S, E>),
+    /// The fire of `event` from `from` failed after an action ran
+    Failed {
+        from: S,
+        event: E,
+        error: TransitionError<S, E>,
+    },
+}
+
+/// Results of fires by idempotency key, see the module documentation
+pub trait ResultStore<S, E>: Send + Sync {
+    /// The result stored for `key`, unless it expired by `now`
+    fn get(&self, key: &str, now: Instant) -> Option<StoredFire<S, E>>;
+
+    /// Store `result` for `key`, fired at `now`, ending the claim on `key`
+    fn put(&self, key: &str, result: StoredFire<S, E>, now: Instant);
+
+    /// Reserve `key` for a fire starting at `now`, `false` if another fire
+    /// holds it
+    ///
+    /// The default reserves nothing.
+    fn claim(&self, _key: &str, _now: Instant) -> bool {
+        true
+    }
+
+    /// End the claim on `key` of a fire that stored no result
+    fn release(&self, _key: &str) {}
+}
+
+// Results by key, with the time they were stored
+type Entries<S, E> = HashMap<String, (Instant, StoredFire<S, E>)>;
+
+/// `ResultStore` kept in memory, dropping entries older than its time to
+/// live
+pub struct InMemoryResultStore<S, E> {
+    ttl: Duration,
+    entries: Mutex<Entries<S, E>>,
+    claimed: Mutex<HashSet<String>>,
+}
+
+impl<S, E> InMemoryResultStore<S, E> {
+    pub fn new(ttl: Duration) -> Self {
+        InMemoryResultStore {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            claimed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Entries stored, expired ones not dropped yet included
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn expired(&self, stored: Instant, now: Instant) -> bool {
+        now.saturating_duration_since(stored) >= self.ttl
+    }
+}
+
+impl<S, E> ResultStore<S, E> for InMemoryResultStore<S, E>
+where
+    S: State + Send,
+    E: Event + Send,
+{
+    fn get(&self, key: &str, now: Instant) -> Option<StoredFire<S, E>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((stored, _)) if self.expired(*stored, now) => {
+                entries.remove(key);
+                None
+            }
+            Some((_, result)) => Some(result.clone()),
+            None => None,
+        }
+    }
+
+    fn put(&self, key: &str, result: StoredFire<S, E>, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (stored, _)| !self.expired(*stored, now));
+        entries.insert(key.to_string(), (now, result));
+        self.release(key);
+    }
+
+    fn claim(&self, key: &str, _now: Instant) -> bool {
+        self.claimed.lock().unwrap().insert(key.to_string())
+    }
+
+    fn release(&self, key: &str) {
+        self.claimed.lock().unwrap().remove(key);
+    }
+}
+
+// Releases its claim unless the result was stored, also when the fire
+// panics
+struct Claim<'a, S, E> {
+    store: &'a dyn ResultStore<S, E>,
+    key: &'a str,
+    stored: bool,
+}
+
+impl<S, E> Drop for Claim<'_, S, E> {
+    fn drop(&mut self) {
+        if !self.stored {
+            self.store.release(self.key);
+        }
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Fire `event` at most once per `idempotency_key` while `store` keeps
+    /// its result, see the module documentation
+    ///
+    /// Like `fire_event_mut`, actions may update `context`. A memoized
+    /// result is returned as stored, with `replayed` set, when it was stored
+    /// for the same `from` and `event`; `context` is then left as is.
+    pub fn fire_event_idempotent(
+        &self,
+        store: &dyn ResultStore<S, E>,
+        from: S,
+        event: E,
+        context: &mut C,
+        idempotency_key: &str,
+    ) -> Result<MemoizedFire<S, E>, TransitionError<S, E>> {
+        let now = self.clock.now();
+        if let Some(result) = store.get(idempotency_key, now) {
+            return replay(result, &from, &event, idempotency_key);
+        }
+        if !store.claim(idempotency_key, now) {
+            return Err(TransitionError::IdempotencyKeyInUse {
+                key: idempotency_key.to_string(),
+            });
+        }
+        let mut claim = Claim {
+            store,
+            key: idempotency_key,
+            stored: false,
+        };
+        // The fire holding the claim before may have finished in between
+        if let Some(result) = store.get(idempotency_key, now) {
+            return replay(result, &from, &event, idempotency_key);
+        }
+        let (stored, result) =
+            match self.fire_step(from.clone(), event.clone(), context, None, None, None) {
+                Ok((outcome, emitted)) => {
+                    let result = MemoizedFire {
+                        outcome,
+                        emitted,
+                        replayed: false,
+                    };
+                    (StoredFire::Completed(result.clone()), Ok(result))
+                }
+                Err(error) if error.after_actions() => {
+                    let stored = StoredFire::Failed {
+                        from,
+                        event,
+                        error: error.clone(),
+                    };
+                    (stored, Err(error))
+                }
+                Err(error) => return Err(error),
+            };
+        store.put(idempotency_key, stored, self.clock.now());
+        claim.stored = true;
+        result
+    }
+}
+
+// The `stored` result, if it was stored for a fire of `event` from `from`
+fn replay<S, E>(
+    stored: StoredFire<S, E>,
+    from: &S,
+    event: &E,
+    key: &str,
+) -> Result<MemoizedFire<S, E>, TransitionError<S, E>>
+where
+    S: State,
+    E: Event,
+{
+    let (stored_from, stored_event) = match &stored {
+        StoredFire::Completed(result) => (&result.outcome.from, &result.outcome.event),
+        StoredFire::Failed { from, event, .. } => (from, event),
+    };
+    if stored_from != from || stored_event != event {
+        return Err(TransitionError::IdempotencyKeyReused {
+            key: key.to_string(),
+            from: stored_from.clone(),
+            event: stored_event.clone(),
+        });
+    }
+    match stored {
+        StoredFire::Completed(result) => Ok(MemoizedFire {
+            replayed: true,
+            ..result
+        }),
+        StoredFire::Failed { error, .. } => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StateMachineBuilder, StateMachineBuilderFactory};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Signup {
+        Pending,
+        Welcomed,
+    }
+
+    impl State for Signup {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum SignupEvent {
+        Welcome,
+        EmailSent,
+    }
+
+    impl Event for SignupEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Mailer {
+        up: bool,
+    }
+
+    impl Context for Mailer {}
+
+    fn signup_machine(
+        sent: &Arc<AtomicUsize>,
+        configure: impl FnOnce(&mut StateMachineBuilder<Signup, SignupEvent, Mailer>),
+    ) -> StateMachine<Signup, SignupEvent, Mailer> {
+        let mut builder = StateMachineBuilderFactory::create::<Signup, SignupEvent, Mailer>();
+        let counter = sent.clone();
+        builder
+            .external_transition()
+            .from(Signup::Pending)
+            .to(Signup::Welcomed)
+            .on(SignupEvent::Welcome)
+            .when(|_s, _e, mailer| mailer.up)
+            .perform_with_followups(move |_s, _e, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                vec![SignupEvent::EmailSent]
+            });
+        configure(&mut builder);
+        builder.build()
+    }
+
+    #[test]
+    fn test_retry_replays_result() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let machine = signup_machine(&sent, |_| {});
+        let store = InMemoryResultStore::new(Duration::from_secs(3600));
+
+        // A failed attempt leaves nothing to replay
+        assert!(machine
+            .fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: false },
+                "signup-1",
+            )
+            .is_err());
+        assert!(store.is_empty());
+
+        let first = machine
+            .fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-1",
+            )
+            .unwrap();
+        assert!(!first.replayed);
+        assert_eq!(first.outcome.to, Signup::Welcomed);
+        assert_eq!(first.emitted, vec![SignupEvent::EmailSent]);
+
+        let retry = machine
+            .fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-1",
+            )
+            .unwrap();
+        assert!(retry.replayed);
+        assert_eq!(retry.outcome, first.outcome);
+        assert_eq!(retry.emitted, first.emitted);
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // Another command fires on its own
+        machine
+            .fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-2",
+            )
+            .unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_key_reused_for_another_fire_is_rejected() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let machine = signup_machine(&sent, |builder| {
+            builder
+                .external_transition()
+                .from(Signup::Welcomed)
+                .to(Signup::Pending)
+                .on(SignupEvent::EmailSent)
+                .add();
+        });
+        let store = InMemoryResultStore::new(Duration::from_secs(3600));
+        machine
+            .fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-1",
+            )
+            .unwrap();
+
+        let result = machine.fire_event_idempotent(
+            &store,
+            Signup::Welcomed,
+            SignupEvent::EmailSent,
+            &mut Mailer { up: true },
+            "signup-1",
+        );
+        match result {
+            Err(TransitionError::IdempotencyKeyReused { key, from, event }) => {
+                assert_eq!(key, "signup-1");
+                assert_eq!(from, Signup::Pending);
+                assert_eq!(event, SignupEvent::Welcome);
+            }
+            other => panic!("expected IdempotencyKeyReused, got {:?}", other),
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_concurrent_attempt_rejected_while_first_runs() {
+        use std::sync::mpsc;
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (finish_tx, finish_rx) = mpsc::channel::<()>();
+        let (started_tx, finish_rx) = (Mutex::new(started_tx), Mutex::new(finish_rx));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let mut builder = StateMachineBuilderFactory::create::<Signup, SignupEvent, Mailer>();
+        builder
+            .external_transition()
+            .from(Signup::Pending)
+            .to(Signup::Welcomed)
+            .on(SignupEvent::Welcome)
+            .perform(move |_s, _e, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                started_tx.lock().unwrap().send(()).unwrap();
+                finish_rx.lock().unwrap().recv().unwrap();
+            });
+        let machine = Arc::new(builder.build());
+        let store = Arc::new(InMemoryResultStore::new(Duration::from_secs(3600)));
+        let fire = |machine: &StateMachine<Signup, SignupEvent, Mailer>,
+                    store: &InMemoryResultStore<Signup, SignupEvent>| {
+            machine.fire_event_idempotent(
+                store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-1",
+            )
+        };
+
+        let first = {
+            let (machine, store) = (machine.clone(), store.clone());
+            std::thread::spawn(move || fire(&machine, &store))
+        };
+        started_rx.recv().unwrap();
+
+        // The action of the first attempt is still running
+        assert!(matches!(
+            fire(&machine, &store),
+            Err(TransitionError::IdempotencyKeyInUse { .. })
+        ));
+
+        finish_tx.send(()).unwrap();
+        let first = first.join().unwrap().unwrap();
+        assert!(!first.replayed);
+        let retry = fire(&machine, &store).unwrap();
+        assert!(retry.replayed);
+        assert_eq!(retry.outcome, first.outcome);
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_failed_attempt_releases_key() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let machine = signup_machine(&sent, |_| {});
+        let store = InMemoryResultStore::new(Duration::from_secs(3600));
+        let fire = |up| {
+            machine.fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up },
+                "signup-1",
+            )
+        };
+
+        assert!(matches!(
+            fire(false),
+            Err(TransitionError::ConditionFailed { .. })
+        ));
+        assert!(!fire(true).unwrap().replayed);
+    }
+
+    #[test]
+    fn test_failure_after_action_is_memoized() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let mut builder = StateMachineBuilderFactory::create::<Signup, SignupEvent, Mailer>();
+        builder
+            .external_transition()
+            .from(Signup::Pending)
+            .to(Signup::Welcomed)
+            .on(SignupEvent::Welcome)
+            .perform_fallible(move |_s, _e, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Err("mailbox full".into())
+            });
+        let machine = builder.build();
+        let store = InMemoryResultStore::new(Duration::from_secs(3600));
+        let fire = || {
+            machine.fire_event_idempotent(
+                &store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-1",
+            )
+        };
+
+        // The email may have gone out: the retry gets the same error
+        for _ in 0..2 {
+            match fire() {
+                Err(TransitionError::ActionFailed { source }) => {
+                    assert_eq!(source.to_string(), "mailbox full")
+                }
+                other => panic!("expected ActionFailed, got {:?}", other),
+            }
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_actions_update_context() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let machine = signup_machine(&sent, |builder| {
+            builder
+                .external_transition()
+                .from(Signup::Welcomed)
+                .to(Signup::Welcomed)
+                .on(SignupEvent::EmailSent)
+                .perform_mut(|_s, _e, mailer| mailer.up = false);
+        });
+        let store = InMemoryResultStore::new(Duration::from_secs(3600));
+        let mut mailer = Mailer { up: true };
+
+        machine
+            .fire_event_idempotent(
+                &store,
+                Signup::Welcomed,
+                SignupEvent::EmailSent,
+                &mut mailer,
+                "sent-1",
+            )
+            .unwrap();
+        assert!(!mailer.up);
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_expired_entry_fires_again() {
+        let clock = Arc::new(crate::MockClock::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let machine = signup_machine(&sent, |builder| {
+            builder.with_clock(clock.clone());
+        });
+        let store = InMemoryResultStore::new(Duration::from_secs(60));
+        let fire = || {
+            machine
+                .fire_event_idempotent(
+                    &store,
+                    Signup::Pending,
+                    SignupEvent::Welcome,
+                    &mut Mailer { up: true },
+                    "signup-1",
+                )
+                .unwrap()
+        };
+
+        assert!(!fire().replayed);
+        clock.advance(Duration::from_secs(59));
+        assert!(fire().replayed);
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!fire().replayed);
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_entry_outlives_ttl_passing_while_first_runs() {
+        use std::sync::mpsc;
+
+        let clock = Arc::new(crate::MockClock::new());
+        let (started_tx, started_rx) = mpsc::channel();
+        let (finish_tx, finish_rx) = mpsc::channel::<()>();
+        let (started_tx, finish_rx) = (Mutex::new(started_tx), Mutex::new(finish_rx));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let mut builder = StateMachineBuilderFactory::create::<Signup, SignupEvent, Mailer>();
+        builder
+            .external_transition()
+            .from(Signup::Pending)
+            .to(Signup::Welcomed)
+            .on(SignupEvent::Welcome)
+            .perform(move |_s, _e, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                started_tx.lock().unwrap().send(()).unwrap();
+                finish_rx.lock().unwrap().recv().unwrap();
+            });
+        builder.with_clock(clock.clone());
+        let machine = Arc::new(builder.build());
+        let store = Arc::new(InMemoryResultStore::new(Duration::from_secs(60)));
+        let fire = |machine: &StateMachine<Signup, SignupEvent, Mailer>,
+                    store: &InMemoryResultStore<Signup, SignupEvent>| {
+            machine.fire_event_idempotent(
+                store,
+                Signup::Pending,
+                SignupEvent::Welcome,
+                &mut Mailer { up: true },
+                "signup-1",
+            )
+        };
+
+        let first = {
+            let (machine, store) = (machine.clone(), store.clone());
+            std::thread::spawn(move || fire(&machine, &store))
+        };
+        started_rx.recv().unwrap();
+
+        // The claim doesn't expire with the time to live
+        clock.advance(Duration::from_secs(120));
+        assert!(matches!(
+            fire(&machine, &store),
+            Err(TransitionError::IdempotencyKeyInUse { .. })
+        ));
+
+        // The result lives for the time to live from when it was stored
+        finish_tx.send(()).unwrap();
+        assert!(!first.join().unwrap().unwrap().replayed);
+        clock.advance(Duration::from_secs(59));
+        assert!(fire(&machine, &store).unwrap().replayed);
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+}
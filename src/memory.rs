@@ -0,0 +1,329 @@
+//! Approximate memory footprint of a machine's subsystems
+
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::{size_of, size_of_val};
+use std::sync::Arc;
+
+use crate::callbacks::Action;
+use crate::{Context, Event, State, StateMachine, Transition};
+
+#[cfg(feature = "history")]
+use crate::TransitionRecord;
+
+/// Estimated bytes used by each subsystem of a machine
+///
+/// Sizes count the inline size of stored values plus the heap data the crate
+/// itself allocates: history error messages, approvals and context changes,
+/// the buckets of the per-key metric maps with their string keys, vectors
+/// and closures. Heap data owned by user state, event or context types is
+/// not visible and is not counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    /// Transition table, including each shared closure once
+    pub transitions: usize,
+    /// Recorded transition history, with cleared records kept until drained
+    pub history: usize,
+    /// Counters, durations and state visit counts, with reset windows kept
+    /// until drained
+    pub metrics: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.transitions + self.history + self.metrics
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes (transitions: {}, history: {}, metrics: {})",
+            self.total(),
+            self.transitions,
+            self.history,
+            self.metrics
+        )
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Estimate the memory currently used by this machine
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            transitions: self.transitions_footprint(),
+            #[cfg(feature = "history")]
+            history: self
+                .recording
+                .with_history(|records| records.iter().map(record_footprint).sum::<usize>())
+                + self.recording.with_previous_history(|records| {
+                    records.iter().map(record_footprint).sum::<usize>()
+                }),
+            #[cfg(not(feature = "history"))]
+            history: 0,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics_footprint(),
+            #[cfg(not(feature = "metrics"))]
+            metrics: 0,
+        }
+    }
+
+    fn transitions_footprint(&self) -> usize {
+        // Closures shared between transitions (e.g. from `from_among`) are
+        // counted once, identified by the address of their data
+        let mut closures = HashSet::new();
+        let mut closure = |data: *const (), size: usize| {
+            if closures.insert(data) {
+                // Arc header: strong and weak counts
+                size + 2 * size_of::<usize>()
+            } else {
+                0
+            }
+        };
+
+        let mut bytes = self.transitions.capacity()
+            * size_of::<((S, E), Box<[Transition<S, E, C>]>)>()
+            + self.wildcard_transitions.capacity() * size_of::<(E, Box<[Transition<S, E, C>]>)>();
+        for transition in self
+            .transitions
+            .values()
+            .chain(self.wildcard_transitions.values())
+            .flat_map(|c| c.iter())
+        {
+            bytes += size_of::<Transition<S, E, C>>();
+            if let Some(flag) = &transition.required_flag {
+                bytes += flag.capacity();
+            }
+            if let Some(condition) = transition.guard.plain() {
+                bytes += closure(
+                    Arc::as_ptr(condition) as *const (),
+                    size_of_val(&**condition),
+                );
+            }
+            if let Some(condition) = transition.guard.info() {
+                bytes += closure(
+                    Arc::as_ptr(condition) as *const (),
+                    size_of_val(&**condition),
+                );
+            }
+            let action = match &transition.action {
+                Action::Nothing => None,
+                Action::Plain(a) => Some((Arc::as_ptr(a) as *const (), size_of_val(&**a))),
+                Action::Mut(a) => Some((Arc::as_ptr(a) as *const (), size_of_val(&**a))),
+                Action::Fallible(a) => Some((Arc::as_ptr(a) as *const (), size_of_val(&**a))),
+                Action::Followups(a) => Some((Arc::as_ptr(a) as *const (), size_of_val(&**a))),
+                Action::Info(a) => Some((Arc::as_ptr(a) as *const (), size_of_val(&**a))),
+            };
+            if let Some((ptr, size)) = action {
+                bytes += closure(ptr, size);
+            }
+        }
+        bytes
+    }
+
+    #[cfg(feature = "metrics")]
+    fn metrics_footprint(&self) -> usize {
+        self.recording.with_metrics(window_footprint)
+            + self.recording.with_previous_metrics(|windows| {
+                windows
+                    .values()
+                    // Each window sits in a B-tree node next to its epoch
+                    .map(|metrics| window_footprint(metrics) + size_of::<u64>())
+                    .sum::<usize>()
+            })
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn window_footprint<S: State, E: Event>(metrics: &crate::StateMachineMetrics<S, E>) -> usize {
+    let mut bytes = size_of::<crate::StateMachineMetrics<S, E>>()
+        + metrics.transition_durations.heap_size()
+        + map_footprint::<(S, E, S), crate::TransitionStats>(metrics.transitions.capacity());
+    for counts in [
+        &metrics.state_visit_counts,
+        &metrics.slow_callbacks,
+        &metrics.failures_by_code,
+    ] {
+        bytes += map_footprint::<String, u64>(counts.capacity());
+        bytes += counts.keys().map(String::capacity).sum::<usize>();
+    }
+    bytes
+}
+
+// Buckets of a `HashMap` with `capacity`, plus one control byte each
+#[cfg(feature = "metrics")]
+fn map_footprint<K, V>(capacity: usize) -> usize {
+    capacity * (size_of::<(K, V)>() + 1)
+}
+
+#[cfg(feature = "history")]
+fn record_footprint<S: State, E: Event>(record: &TransitionRecord<S, E>) -> usize {
+    let approval = record.approval.as_ref().map_or(0, |approval| {
+        approval.id.capacity() + approval.approver.capacity()
+    });
+    let changes = record.context_changes.as_ref().map_or(0, |changes| {
+        changes.capacity() * size_of::<crate::FieldChange>()
+            + changes
+                .iter()
+                .map(|change| {
+                    change.path.capacity()
+                        + change.before.as_ref().map_or(0, String::capacity)
+                        + change.after.as_ref().map_or(0, String::capacity)
+                })
+                .sum::<usize>()
+    });
+    size_of::<TransitionRecord<S, E>>()
+        + record.error.as_ref().map_or(0, String::capacity)
+        + approval
+        + changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Switch {
+        On,
+        Off,
+    }
+
+    impl State for Switch {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Flip {
+        Flip,
+    }
+
+    impl Event for Flip {}
+
+    fn switch_machine() -> StateMachine<Switch, Flip, NoContext> {
+        switch_machine_with(|_| {})
+    }
+
+    fn switch_machine_with(
+        configure: impl FnOnce(&mut crate::StateMachineBuilder<Switch, Flip, NoContext>),
+    ) -> StateMachine<Switch, Flip, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Switch, Flip, NoContext>();
+        configure(&mut builder);
+        builder
+            .external_transitions()
+            .from_among(vec![Switch::On, Switch::Off])
+            .to(Switch::Off)
+            .on(Flip::Flip)
+            .when(|s, _e, _c| *s == Switch::On)
+            .perform(|_s, _e, _c| {});
+        builder.build()
+    }
+
+    #[test]
+    fn test_memory_usage_display() {
+        let usage = switch_machine().memory_usage();
+        assert!(usage.transitions > 0);
+        assert_eq!(
+            usage.total(),
+            usage.transitions + usage.history + usage.metrics
+        );
+        assert!(usage
+            .to_string()
+            .starts_with(&format!("{} bytes", usage.total())));
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_history_estimate_scales_with_records() {
+        let machine = switch_machine();
+        assert_eq!(machine.memory_usage().history, 0);
+
+        for _ in 0..10_000 {
+            let _ = machine.fire_event(Switch::On, Flip::Flip, NoContext);
+        }
+        let first = machine.memory_usage().history;
+        for _ in 0..10_000 {
+            let _ = machine.fire_event(Switch::On, Flip::Flip, NoContext);
+        }
+        let second = machine.memory_usage().history;
+        assert!(first > 0);
+        assert_eq!(second, 2 * first);
+
+        // Without `keep_cleared_epochs` the cleared records are freed
+        machine.clear_history();
+        assert_eq!(machine.memory_usage().history, 0);
+        assert!(machine.drain_previous_epochs().is_empty());
+    }
+
+    #[test]
+    #[cfg(all(feature = "history", feature = "metrics"))]
+    fn test_estimate_counts_kept_epochs() {
+        let machine = switch_machine_with(|builder| {
+            builder.keep_cleared_epochs();
+        });
+        for _ in 0..1_000 {
+            let _ = machine.fire_event(Switch::On, Flip::Flip, NoContext);
+        }
+        let live = machine.memory_usage();
+
+        // Cleared records stay in memory until drained
+        machine.clear_history();
+        machine.reset_metrics();
+        let cleared = machine.memory_usage();
+        assert!(cleared.history >= live.history);
+        assert!(cleared.metrics > live.metrics);
+
+        let drained = machine.drain_previous_epochs();
+        assert_eq!(drained.len(), 1_000);
+        let _ = machine.drain_previous_metrics();
+        assert_eq!(machine.memory_usage().history, 0);
+        assert!(machine.memory_usage().metrics < cleared.metrics);
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_history_estimate_counts_error_messages() {
+        let machine = switch_machine();
+        for _ in 0..100 {
+            // The guard rejects firing from Off
+            assert!(machine
+                .fire_event(Switch::Off, Flip::Flip, NoContext)
+                .is_err());
+        }
+        let history = machine.get_history();
+        let messages: usize = history
+            .iter()
+            .map(|record| record.error.as_ref().unwrap().len())
+            .sum();
+        assert!(messages > 0);
+        assert!(
+            machine.memory_usage().history
+                >= history.len() * size_of::<TransitionRecord<Switch, Flip>>() + messages
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_metrics_estimate_counts_keyed_maps() {
+        let machine = switch_machine();
+        let empty = machine.memory_usage().metrics;
+        machine
+            .fire_event(Switch::On, Flip::Flip, NoContext)
+            .unwrap();
+        let _ = machine.fire_event(Switch::Off, Flip::Flip, NoContext);
+
+        let metrics = machine.get_metrics();
+        let keys: usize = metrics
+            .state_visit_counts
+            .keys()
+            .chain(metrics.failures_by_code.keys())
+            .map(String::len)
+            .sum();
+        assert!(machine.memory_usage().metrics >= empty + keys);
+    }
+}
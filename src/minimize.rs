@@ -0,0 +1,253 @@
+//! Shrinking failing event sequences (requires the `test-util` feature)
+//!
+//! `minimize_trace` takes a sequence of events that makes a machine
+//! misbehave, as found by a random walk or a property test, and removes
+//! events while the failure still reproduces. It is delta debugging: the
+//! sequence is split into chunks, and any chunk whose removal keeps the
+//! failure is dropped; when none can be, the chunks are halved, down to
+//! single events. The result is 1-minimal: removing any one of its events
+//! makes the failure go away.
+//!
+//! Every candidate is replayed on a copy of the machine with fresh history
+//! and metrics, without its fail callback and with every transition action
+//! not marked `pure_action` replaced by a no-op, so shrinking has no side
+//! effects. Failures raised by those actions therefore don't reproduce.
+
+use std::sync::Arc;
+
+use crate::callbacks::Action;
+use crate::{Context, Event, State, StateMachine, TransitionError};
+
+/// What replaying a sequence of events did
+#[derive(Debug, Clone)]
+pub struct TraceResult<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Outcome of each event, in order; failed events leave the state
+    /// unchanged
+    pub results: Vec<Result<S, TransitionError<S, E>>>,
+    /// State after the last event
+    pub final_state: S,
+}
+
+impl<S, E> TraceResult<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Index and error of the first event that failed
+    pub fn first_error(&self) -> Option<(usize, &TransitionError<S, E>)> {
+        self.results
+            .iter()
+            .enumerate()
+            .find_map(|(index, result)| result.as_ref().err().map(|error| (index, error)))
+    }
+}
+
+/// Shrink `trace` to a minimal subsequence for which `failure` still holds
+///
+/// Returns `trace` unchanged if it doesn't fail to begin with. The result
+/// depends only on the arguments.
+pub fn minimize_trace<S, E, C, F>(
+    machine: &StateMachine<S, E, C>,
+    initial: S,
+    trace: Vec<(E, C)>,
+    failure: F,
+) -> Vec<(E, C)>
+where
+    S: State,
+    E: Event,
+    C: Context,
+    F: Fn(&TraceResult<S, E>) -> bool,
+{
+    let replay_machine = machine.without_side_effects();
+    let fails = |kept: &[usize]| {
+        let replay = replay_machine.fork();
+        let mut state = initial.clone();
+        let mut results = Vec::with_capacity(kept.len());
+        for &index in kept {
+            let (event, context) = &trace[index];
+            let result = replay.fire_event(state.clone(), event.clone(), context.clone());
+            if let Ok(to) = &result {
+                state = to.clone();
+            }
+            results.push(result);
+        }
+        failure(&TraceResult {
+            results,
+            final_state: state,
+        })
+    };
+
+    let mut kept: Vec<usize> = (0..trace.len()).collect();
+    if !fails(&kept) {
+        return trace;
+    }
+    let mut chunks = 2;
+    while !kept.is_empty() {
+        let chunk_len = kept.len().div_ceil(chunks);
+        let reduced = (0..kept.len()).step_by(chunk_len).find_map(|start| {
+            let end = (start + chunk_len).min(kept.len());
+            let candidate: Vec<usize> = kept[..start].iter().chain(&kept[end..]).copied().collect();
+            fails(&candidate).then_some(candidate)
+        });
+        match reduced {
+            Some(candidate) => {
+                kept = candidate;
+                chunks = (chunks - 1).max(2);
+            }
+            None if chunk_len == 1 => break,
+            None => chunks = (chunks * 2).min(kept.len()),
+        }
+    }
+
+    let mut trace: Vec<Option<(E, C)>> = trace.into_iter().map(Some).collect();
+    kept.into_iter()
+        .filter_map(|index| trace[index].take())
+        .collect()
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // Copy for replaying: impure transition actions do nothing and failures
+    // are not reported
+    fn without_side_effects(&self) -> Self {
+        let mut machine = self.fork();
+        machine.fail_callback = None;
+        for transition in machine.transitions_mut() {
+            if transition.has_action() && !transition.pure_action {
+                transition.action = Action::Plain(Arc::new(|_s, _e, _c| {}));
+            }
+        }
+        machine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Valve {
+        Closed,
+        Open,
+        Burst,
+    }
+
+    impl State for Valve {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum ValveEvent {
+        Open,
+        Close,
+        Pressurize,
+        Inspect,
+        Log,
+    }
+
+    impl Event for ValveEvent {}
+
+    // Pressurizing an open valve bursts it
+    fn valve_machine(alarms: Arc<AtomicUsize>) -> StateMachine<Valve, ValveEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Valve, ValveEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Valve::Closed)
+            .to(Valve::Open)
+            .on(ValveEvent::Open)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Valve::Open)
+            .to(Valve::Closed)
+            .on(ValveEvent::Close)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Valve::Open)
+            .to(Valve::Burst)
+            .on(ValveEvent::Pressurize)
+            .perform(move |_s, _e, _c| {
+                alarms.fetch_add(1, Ordering::SeqCst);
+            });
+        builder
+            .internal_transition()
+            .within(Valve::Closed)
+            .on(ValveEvent::Pressurize)
+            .perform(|_s, _e, _c| {});
+        for state in [Valve::Closed, Valve::Open] {
+            builder
+                .internal_transition()
+                .within(state)
+                .on(ValveEvent::Inspect)
+                .perform(|_s, _e, _c| {});
+        }
+        builder.build()
+    }
+
+    // 400 events of noise, with the valve opened at 100 and pressurized at
+    // 300; nothing in between closes it
+    fn planted_trace() -> Vec<(ValveEvent, NoContext)> {
+        let mut seed: u64 = 42;
+        (0..400)
+            .map(|index| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                let noise = (seed >> 33) as usize;
+                let event = match index {
+                    100 => ValveEvent::Open,
+                    300 => ValveEvent::Pressurize,
+                    0..100 => [ValveEvent::Inspect, ValveEvent::Log, ValveEvent::Pressurize]
+                        [noise % 3]
+                        .clone(),
+                    _ => [ValveEvent::Inspect, ValveEvent::Log][noise % 2].clone(),
+                };
+                (event, NoContext)
+            })
+            .collect()
+    }
+
+    fn burst(result: &TraceResult<Valve, ValveEvent>) -> bool {
+        result.final_state == Valve::Burst
+    }
+
+    #[test]
+    fn test_minimizes_to_triggering_events() {
+        let alarms = Arc::new(AtomicUsize::new(0));
+        let machine = valve_machine(alarms.clone());
+
+        let minimal = minimize_trace(&machine, Valve::Closed, planted_trace(), burst);
+        let events: Vec<ValveEvent> = minimal.into_iter().map(|(event, _)| event).collect();
+        assert_eq!(events, vec![ValveEvent::Open, ValveEvent::Pressurize]);
+
+        let again = minimize_trace(&machine, Valve::Closed, planted_trace(), burst);
+        assert_eq!(again.len(), 2);
+        // Replays skip the alarm and leave the machine untouched
+        assert_eq!(alarms.load(Ordering::SeqCst), 0);
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+    }
+
+    #[test]
+    fn test_passing_trace_returned_unchanged() {
+        let machine = valve_machine(Arc::default());
+        let trace = vec![
+            (ValveEvent::Open, NoContext),
+            (ValveEvent::Log, NoContext),
+            (ValveEvent::Close, NoContext),
+        ];
+        let minimal = minimize_trace(&machine, Valve::Closed, trace, |result| {
+            assert_eq!(result.first_error().map(|(index, _)| index), Some(1));
+            burst(result)
+        });
+        assert_eq!(minimal.len(), 3);
+    }
+}
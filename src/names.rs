@@ -0,0 +1,180 @@
+//! Names of states and events without formatting them on every fire
+//!
+//! A machine formats the `Debug` name of every state and event its
+//! definition mentions once, when it is built. `StateMachine::state_name`
+//! and `event_name` hand them out as `&str`; `TransitionOutcome::names`,
+//! the steps of an `ExecutionTrace` and the `after_transition_named` and
+//! `on_failure_named` listener hooks carry them as shared strings, so
+//! logging a fire needs no allocation. Values the definition doesn't
+//! mention have no name; callers fall back to formatting them themselves.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{Context, Event, State, StateMachine};
+
+/// Names of the states and event of a fire, `None` for values the
+/// definition doesn't mention
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransitionNames {
+    pub from: Option<Arc<str>>,
+    /// `None` for failed fires
+    pub to: Option<Arc<str>>,
+    pub event: Option<Arc<str>>,
+}
+
+fn format_name(value: &dyn std::fmt::Debug) -> Arc<str> {
+    Arc::from(format!("{:?}", value))
+}
+
+/// Names of the states and events of a definition
+pub(crate) struct Names<S, E> {
+    states: HashMap<S, Arc<str>>,
+    events: HashMap<E, Arc<str>>,
+}
+
+impl<S, E> Default for Names<S, E> {
+    fn default() -> Self {
+        Names {
+            states: HashMap::new(),
+            events: HashMap::new(),
+        }
+    }
+}
+
+impl<S, E> Names<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub(crate) fn new<C: Context>(machine: &StateMachine<S, E, C>) -> Self {
+        Names {
+            states: machine
+                .states()
+                .into_iter()
+                .map(|state| (state.clone(), format_name(state)))
+                .collect(),
+            events: machine
+                .events()
+                .into_iter()
+                .map(|event| (event.clone(), format_name(event)))
+                .collect(),
+        }
+    }
+
+    /// Interned name of `state`, formatted on the spot for foreign states
+    pub(crate) fn state(&self, state: &S) -> Arc<str> {
+        self.states
+            .get(state)
+            .cloned()
+            .unwrap_or_else(|| format_name(state))
+    }
+
+    /// Interned name of `event`, formatted on the spot for foreign events
+    pub(crate) fn event(&self, event: &E) -> Arc<str> {
+        self.events
+            .get(event)
+            .cloned()
+            .unwrap_or_else(|| format_name(event))
+    }
+
+    pub(crate) fn transition(&self, from: &S, to: Option<&S>, event: &E) -> TransitionNames {
+        TransitionNames {
+            from: self.states.get(from).cloned(),
+            to: to.and_then(|to| self.states.get(to).cloned()),
+            event: self.events.get(event).cloned(),
+        }
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// `Debug` name of `state`, formatted when the machine was built
+    ///
+    /// `None` for states the definition doesn't mention.
+    pub fn state_name(&self, state: &S) -> Option<&str> {
+        self.names.states.get(state).map(|name| &**name)
+    }
+
+    /// `Debug` name of `event`, formatted when the machine was built
+    ///
+    /// `None` for events no transition is registered on.
+    pub fn event_name(&self, event: &E) -> Option<&str> {
+        self.names.events.get(event).map(|name| &**name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Ticket {
+        Open,
+        Closed,
+        Archived,
+    }
+
+    impl State for Ticket {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum TicketEvent {
+        Close,
+        Reopen,
+        Purge,
+    }
+
+    impl Event for TicketEvent {}
+
+    fn ticket_machine() -> StateMachine<Ticket, TicketEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Ticket, TicketEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Closed)
+            .on(TicketEvent::Close)
+            .add();
+        builder
+            .external_transition()
+            .from(Ticket::Closed)
+            .to(Ticket::Open)
+            .on(TicketEvent::Reopen)
+            .add();
+        builder.build()
+    }
+
+    #[test]
+    fn test_names_of_built_definition() {
+        let machine = ticket_machine();
+        for state in machine.states() {
+            assert_eq!(machine.state_name(state), Some(&*format!("{:?}", state)));
+        }
+        assert_eq!(machine.event_name(&TicketEvent::Close), Some("Close"));
+        assert_eq!(machine.state_name(&Ticket::Archived), None);
+        assert_eq!(machine.event_name(&TicketEvent::Purge), None);
+
+        let outcome = machine
+            .fire_event_detailed(Ticket::Open, TicketEvent::Close, NoContext)
+            .unwrap();
+        assert_eq!(outcome.names.from.as_deref(), Some("Open"));
+        assert_eq!(outcome.names.to.as_deref(), Some("Closed"));
+        assert_eq!(outcome.names.event.as_deref(), Some("Close"));
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_trace_steps_share_interned_names() {
+        let machine = ticket_machine();
+        let trace = machine.trace_fire(Ticket::Open, TicketEvent::Close, NoContext);
+        let Some(crate::TraceStep::Completed { to }) = trace.steps.last() else {
+            panic!("fire did not complete: {:?}", trace);
+        };
+        assert!(Arc::ptr_eq(to, &machine.names.states[&Ticket::Closed]));
+    }
+}
@@ -0,0 +1,204 @@
+//! What a successful fire did
+//!
+//! `StateMachine::fire_event_detailed` returns a `TransitionOutcome`
+//! describing the transition taken rather than only the state reached.
+//! Every fire builds one; `fire_event` returns its `to`, and history
+//! records of successful fires are made from it.
+
+use std::time::Duration;
+
+use crate::{
+    Context, Event, FieldChange, State, StateMachine, Transition, TransitionError, TransitionNames,
+    TransitionType,
+};
+
+// Outcome of a fire along with `T`, such as the follow-up events raised
+pub(crate) type FireResult<S, E, T> = Result<(TransitionOutcome<S, E>, T), TransitionError<S, E>>;
+
+/// Transition taken by a fire
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionOutcome<S, E> {
+    pub from: S,
+    /// State the fire settled in, after any completion transitions
+    pub to: S,
+    pub event: E,
+    pub transition_type: TransitionType,
+    /// Always 0 without the `guards` feature, and for overrides
+    pub priority: u32,
+    /// Whether a `from_any` transition was taken
+    pub wildcard: bool,
+    /// Whether a runtime override took the place of the transitions
+    pub overridden: bool,
+    /// Template instance tag of the transition
+    pub tag: Option<String>,
+    /// Whether the transition was built with `.irreversible()`
+    pub irreversible: bool,
+    /// How long the fire took, as recorded in the metrics
+    pub duration: Duration,
+    /// Interned names of the states and event, see `StateMachine::state_name`
+    pub names: TransitionNames,
+    /// What the fire changed in the context, empty without a context differ
+    pub context_changes: Vec<FieldChange>,
+}
+
+impl<S, E> TransitionOutcome<S, E>
+where
+    S: State,
+    E: Event,
+{
+    // Outcome of taking `transition`; the duration is set once the fire ends
+    pub(crate) fn taken<C: Context>(
+        transition: &Transition<S, E, C>,
+        from: &S,
+        event: &E,
+        to: S,
+        wildcard: bool,
+    ) -> Self {
+        TransitionOutcome {
+            from: from.clone(),
+            to,
+            event: event.clone(),
+            transition_type: transition.transition_type.clone(),
+            #[cfg(feature = "guards")]
+            priority: transition.priority,
+            #[cfg(not(feature = "guards"))]
+            priority: 0,
+            wildcard,
+            overridden: false,
+            tag: transition.tag.clone(),
+            irreversible: transition.irreversible,
+            duration: Duration::ZERO,
+            names: TransitionNames::default(),
+            context_changes: Vec::new(),
+        }
+    }
+
+    // Outcome of a runtime override moving the entity to `to`
+    pub(crate) fn overridden(from: &S, event: &E, to: S) -> Self {
+        TransitionOutcome {
+            from: from.clone(),
+            to,
+            event: event.clone(),
+            transition_type: TransitionType::External,
+            priority: 0,
+            wildcard: false,
+            overridden: true,
+            tag: None,
+            irreversible: false,
+            duration: Duration::ZERO,
+            names: TransitionNames::default(),
+            context_changes: Vec::new(),
+        }
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// `fire_event`, describing the transition taken
+    pub fn fire_event_detailed(
+        &self,
+        from: S,
+        event: E,
+        mut context: C,
+    ) -> Result<TransitionOutcome<S, E>, TransitionError<S, E>> {
+        self.fire_step(from, event, &mut context, None, None, None)
+            .map(|(outcome, _)| outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Light {
+        Off,
+        On,
+        Broken,
+    }
+
+    impl State for Light {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum LightEvent {
+        Toggle,
+        Dim,
+        Smash,
+    }
+
+    impl Event for LightEvent {}
+
+    fn light_machine() -> StateMachine<Light, LightEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Light, LightEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Light::Off)
+            .to(Light::On)
+            .on(LightEvent::Toggle)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(Light::On)
+            .on(LightEvent::Dim)
+            .add();
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Light::Broken)
+            .on(LightEvent::Smash)
+            .add();
+        builder.build()
+    }
+
+    #[test]
+    fn test_detailed_outcome_describes_transition() {
+        let machine = light_machine();
+        let outcome = machine
+            .fire_event_detailed(Light::Off, LightEvent::Toggle, NoContext)
+            .unwrap();
+        assert_eq!(outcome.from, Light::Off);
+        assert_eq!(outcome.to, Light::On);
+        assert_eq!(outcome.event, LightEvent::Toggle);
+        assert_eq!(outcome.transition_type, TransitionType::External);
+        assert!(!outcome.wildcard && !outcome.overridden);
+
+        let dimmed = machine
+            .fire_event_detailed(Light::On, LightEvent::Dim, NoContext)
+            .unwrap();
+        assert_eq!(dimmed.to, Light::On);
+        assert_eq!(dimmed.transition_type, TransitionType::Internal);
+
+        let smashed = machine
+            .fire_event_detailed(Light::On, LightEvent::Smash, NoContext)
+            .unwrap();
+        assert_eq!(smashed.to, Light::Broken);
+        assert!(smashed.wildcard);
+
+        match machine.fire_event_detailed(Light::Broken, LightEvent::Dim, NoContext) {
+            Err(TransitionError::NoValidTransition { from, .. }) => assert_eq!(from, Light::Broken),
+            other => panic!("expected NoValidTransition, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_duration_covers_action() {
+        let mut builder = StateMachineBuilderFactory::create::<Light, LightEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Light::Off)
+            .to(Light::On)
+            .on(LightEvent::Toggle)
+            .perform(|_s, _e, _c| std::thread::sleep(Duration::from_millis(20)));
+        let machine = builder.build();
+        let outcome = machine
+            .fire_event_detailed(Light::Off, LightEvent::Toggle, NoContext)
+            .unwrap();
+        assert!(outcome.duration >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,344 @@
+//! Temporary runtime rerouting of transitions
+//!
+//! An override installed with `StateMachine::override_transition` takes
+//! precedence over the registered candidates of its `(from, event)` pair:
+//! guards are not evaluated and the registered action is skipped, or
+//! replaced by the override's own action. The entity otherwise moves as on an
+//! external transition: the requirements of the target are checked, then
+//! the exit action, the override's action and the entry action run.
+//! Overrides stack, the last one installed wins, and each one is removed
+//! when its guard is dropped or its TTL has passed on the machine's clock.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use crate::{Context, Event, State, StateMachine, TransitionError};
+
+type OverrideAction<S, E, C> = Arc<dyn Fn(&S, &E, &C) + Send + Sync>;
+
+// Target and action of an override
+type ResolvedOverride<S, E, C> = (S, Option<OverrideAction<S, E, C>>);
+
+struct TransitionOverride<S, E, C> {
+    id: u64,
+    from: S,
+    event: E,
+    target: S,
+    expires_at: Option<Instant>,
+    action: Option<OverrideAction<S, E, C>>,
+}
+
+// Overrides shared by a machine and the guards it handed out
+pub(crate) struct Overrides<S, E, C> {
+    next_id: AtomicU64,
+    active: RwLock<Vec<TransitionOverride<S, E, C>>>,
+}
+
+impl<S, E, C> Default for Overrides<S, E, C> {
+    fn default() -> Self {
+        Overrides {
+            next_id: AtomicU64::new(0),
+            active: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+/// An installed override, as listed by `StateMachine::active_overrides`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveOverride<S, E> {
+    pub from: S,
+    pub event: E,
+    pub target: S,
+    pub expires_at: Option<Instant>,
+}
+
+/// Removes its override when dropped
+#[must_use = "the override is removed as soon as the guard is dropped"]
+pub struct OverrideGuard<S, E, C> {
+    overrides: Weak<Overrides<S, E, C>>,
+    id: u64,
+}
+
+impl<S, E, C> Drop for OverrideGuard<S, E, C> {
+    fn drop(&mut self) {
+        if let Some(overrides) = self.overrides.upgrade() {
+            if let Ok(mut active) = overrides.active.write() {
+                active.retain(|o| o.id != self.id);
+            }
+        }
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Route `event` from `from` to `new_target` until the guard is dropped
+    /// or `ttl` has passed
+    ///
+    /// The registered action of the pair is skipped; state requirements,
+    /// entry and exit actions, history and metrics behave as for a regular
+    /// external transition.
+    ///
+    /// Fails with `TransitionError::EventNotAllowedInState` if `from` is
+    /// locked to events other than `event` (see
+    /// `StateMachineBuilder::lock_state_events`).
+    pub fn override_transition(
+        &self,
+        from: S,
+        event: E,
+        new_target: S,
+        ttl: Option<Duration>,
+    ) -> Result<OverrideGuard<S, E, C>, TransitionError<S, E>> {
+        self.install_override(from, event, new_target, ttl, None)
+    }
+
+    /// Like `override_transition`, running `action` instead of the
+    /// registered action, between the exit and entry actions
+    pub fn override_transition_with<F>(
+        &self,
+        from: S,
+        event: E,
+        new_target: S,
+        ttl: Option<Duration>,
+        action: F,
+    ) -> Result<OverrideGuard<S, E, C>, TransitionError<S, E>>
+    where
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
+    {
+        self.install_override(from, event, new_target, ttl, Some(Arc::new(action)))
+    }
+
+    /// Overrides in effect, in installation order
+    pub fn active_overrides(&self) -> Vec<ActiveOverride<S, E>> {
+        let now = self.clock.now();
+        let active = self.overrides.active.read().unwrap();
+        active
+            .iter()
+            .filter(|o| o.expires_at.is_none_or(|expires_at| now < expires_at))
+            .map(|o| ActiveOverride {
+                from: o.from.clone(),
+                event: o.event.clone(),
+                target: o.target.clone(),
+                expires_at: o.expires_at,
+            })
+            .collect()
+    }
+
+    fn install_override(
+        &self,
+        from: S,
+        event: E,
+        target: S,
+        ttl: Option<Duration>,
+        action: Option<OverrideAction<S, E, C>>,
+    ) -> Result<OverrideGuard<S, E, C>, TransitionError<S, E>> {
+        if !self.is_event_allowed(&from, &event) {
+            return Err(TransitionError::EventNotAllowedInState { state: from, event });
+        }
+        let id = self.overrides.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = self.clock.now();
+        let expires_at = ttl.map(|ttl| now + ttl);
+        let mut active = self.overrides.active.write().unwrap();
+        active.retain(|o| o.expires_at.is_none_or(|expires_at| now < expires_at));
+        active.push(TransitionOverride {
+            id,
+            from,
+            event,
+            target,
+            expires_at,
+            action,
+        });
+
+        Ok(OverrideGuard {
+            overrides: Arc::downgrade(&self.overrides),
+            id,
+        })
+    }
+
+    // Target and action of the latest unexpired override for the pair
+    pub(crate) fn find_override(&self, from: &S, event: &E) -> Option<ResolvedOverride<S, E, C>> {
+        let active = self.overrides.active.read().ok()?;
+        if active.is_empty() {
+            return None;
+        }
+        let now = self.clock.now();
+        let found = active.iter().rev().find(|o| {
+            &o.from == from
+                && &o.event == event
+                && o.expires_at.is_none_or(|expires_at| now < expires_at)
+        })?;
+        Some((found.target.clone(), found.action.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{NoContext, Order, OrderEvent};
+    use crate::StateMachineBuilderFactory;
+    use std::sync::atomic::AtomicUsize;
+
+    fn counted_machine(shipped: Arc<AtomicUsize>) -> StateMachine<Order, OrderEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(move |_s, _e, _c| {
+                shipped.fetch_add(1, Ordering::SeqCst);
+            });
+        builder.build()
+    }
+
+    fn ship(machine: &StateMachine<Order, OrderEvent, NoContext>) -> Order {
+        machine
+            .fire_event(Order::Paid, OrderEvent::Ship, NoContext)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_override_applied_and_restored_on_drop() {
+        let shipped = Arc::new(AtomicUsize::new(0));
+        let machine = counted_machine(shipped.clone());
+
+        let hold = machine
+            .override_transition(Order::Paid, OrderEvent::Ship, Order::OnHold, None)
+            .unwrap();
+        assert_eq!(ship(&machine), Order::OnHold);
+        assert_eq!(shipped.load(Ordering::SeqCst), 0);
+
+        let cancel =
+            machine.override_transition(Order::Paid, OrderEvent::Ship, Order::Cancelled, None);
+        let cancel = cancel.unwrap();
+        assert_eq!(ship(&machine), Order::Cancelled);
+        assert_eq!(machine.active_overrides().len(), 2);
+
+        drop(cancel);
+        assert_eq!(ship(&machine), Order::OnHold);
+        drop(hold);
+        assert_eq!(ship(&machine), Order::Shipped);
+        assert_eq!(shipped.load(Ordering::SeqCst), 1);
+        assert!(machine.active_overrides().is_empty());
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_override_expires_after_ttl() {
+        let clock = Arc::new(crate::MockClock::new());
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        builder.with_clock(clock.clone());
+        let machine = builder.build();
+
+        let reviewed = Arc::new(AtomicUsize::new(0));
+        let counter = reviewed.clone();
+        let _guard = machine
+            .override_transition_with(
+                Order::Paid,
+                OrderEvent::Ship,
+                Order::OnHold,
+                Some(Duration::from_secs(60)),
+                move |_s, _e, _c| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                },
+            )
+            .unwrap();
+        assert_eq!(ship(&machine), Order::OnHold);
+        assert_eq!(reviewed.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(61));
+        assert!(machine.active_overrides().is_empty());
+        assert_eq!(ship(&machine), Order::Shipped);
+        assert_eq!(reviewed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_concurrent_fires_during_install() {
+        let machine = Arc::new(counted_machine(Arc::new(AtomicUsize::new(0))));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let machine = machine.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        let state = ship(&machine);
+                        assert!(state == Order::Shipped || state == Order::OnHold);
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..50 {
+            let _guard = machine
+                .override_transition(Order::Paid, OrderEvent::Ship, Order::OnHold, None)
+                .unwrap();
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(ship(&machine), Order::Shipped);
+    }
+
+    #[cfg(feature = "extended")]
+    #[test]
+    fn test_override_moves_like_external_transition() {
+        let steps = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = |step: &'static str| {
+            let steps = steps.clone();
+            move |_s: &Order, _c: &NoContext| steps.lock().unwrap().push(step)
+        };
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        builder.with_exit_action(Order::Paid, log("exit"));
+        builder.with_entry_action(Order::OnHold, log("entry"));
+        builder.state_requires(Order::Cancelled, "never", |_c| false);
+        let machine = builder.build();
+
+        let action_steps = steps.clone();
+        let hold = machine
+            .override_transition_with(
+                Order::Paid,
+                OrderEvent::Ship,
+                Order::OnHold,
+                None,
+                move |_s, _e, _c| action_steps.lock().unwrap().push("action"),
+            )
+            .unwrap();
+        assert_eq!(ship(&machine), Order::OnHold);
+        assert_eq!(*steps.lock().unwrap(), vec!["exit", "action", "entry"]);
+        drop(hold);
+
+        // Overrides don't bypass the requirements of their target
+        steps.lock().unwrap().clear();
+        let _cancel = machine
+            .override_transition(Order::Paid, OrderEvent::Ship, Order::Cancelled, None)
+            .unwrap();
+        let result = machine.fire_event(Order::Paid, OrderEvent::Ship, NoContext);
+        assert!(matches!(
+            result,
+            Err(TransitionError::StateRequirementFailed {
+                state: Order::Cancelled,
+                ..
+            })
+        ));
+        assert!(matches!(
+            machine.peek(&Order::Paid, &OrderEvent::Ship, &NoContext),
+            Err(TransitionError::StateRequirementFailed { .. })
+        ));
+        assert!(steps.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Resolving a fire without taking it
+//!
+//! `StateMachine::peek` answers which transition firing an event would take
+//! and where it leads. It goes through the same checks, in the same order,
+//! as `fire_event` and picks the candidate with the same selection, so the
+//! two can't disagree. Guards, feature flags and approval checkers are
+//! called; actions, the fail callback, listeners, history and metrics are
+//! not touched.
+
+use crate::derived::DerivedValues;
+use crate::{
+    check_approval, Context, Event, FlagCache, State, StateMachine, TransitionError, TransitionType,
+};
+
+/// Where firing an event would lead, as resolved by `StateMachine::peek`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedTransition<S> {
+    /// Target of the transition; completion transitions are not followed
+    pub to: S,
+    pub transition_type: TransitionType,
+    /// Always 0 without the `guards` feature, and for overrides
+    pub priority: u32,
+    /// Whether a runtime override takes the place of the transitions
+    pub overridden: bool,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Resolve firing `event` from `from` with `context` without firing it
+    ///
+    /// Fails with the error `fire_event` would return before running any
+    /// action.
+    pub fn peek(
+        &self,
+        from: &S,
+        event: &E,
+        context: &C,
+    ) -> Result<ResolvedTransition<S>, TransitionError<S, E>> {
+        if self.is_archived() {
+            return Err(TransitionError::MachineArchived {
+                machine_id: self.id.clone(),
+            });
+        }
+        if self.is_terminal(from) {
+            return Err(TransitionError::TerminalState {
+                state: from.clone(),
+            });
+        }
+        if !self.is_event_allowed(from, event) {
+            return Err(TransitionError::EventNotAllowedInState {
+                state: from.clone(),
+                event: event.clone(),
+            });
+        }
+        if let Some(deadline) = self.expired_deadline(event, context) {
+            return Err(TransitionError::DeadlineExpired { deadline });
+        }
+        if let Some((target, _)) = self.find_override(from, event) {
+            #[cfg(feature = "extended")]
+            if let Some(requirement) = self.failed_requirement(&target, context) {
+                return Err(TransitionError::StateRequirementFailed {
+                    state: target,
+                    requirement: requirement.to_string(),
+                });
+            }
+            return Ok(ResolvedTransition {
+                to: target,
+                transition_type: TransitionType::External,
+                priority: 0,
+                overridden: true,
+            });
+        }
+        let Some(transitions) = self.candidates(&(from.clone(), event.clone())) else {
+            return Err(TransitionError::NoValidTransition {
+                from: from.clone(),
+                event: event.clone(),
+                accepted: self.accepted_events(from),
+            });
+        };
+
+        let mut flags = FlagCache::new(self.feature_flags.as_ref());
+        let mut derived = DerivedValues::new(&self.derivations, &self.services);
+        let mut failed_guard = None;
+        let selection = self.select(
+            transitions,
+            context,
+            &mut flags,
+            &mut None,
+            &mut failed_guard,
+            |transition| transition.check_guards(&self.id, from, event, context, &mut derived),
+        );
+        let transition = match selection {
+            Ok(Some(transition)) => transition,
+            Ok(None) => {
+                let disabled = flags.disabled_flags();
+                return Err(if disabled.is_empty() {
+                    TransitionError::ConditionFailed {
+                        from: from.clone(),
+                        event: event.clone(),
+                        guard: failed_guard.map(str::to_string),
+                    }
+                } else {
+                    TransitionError::FeatureDisabled {
+                        from: from.clone(),
+                        event: event.clone(),
+                        flags: disabled,
+                    }
+                });
+            }
+            Err(ambiguous) => {
+                return Err(TransitionError::AmbiguousTransition {
+                    from: from.clone(),
+                    event: event.clone(),
+                    matched: ambiguous,
+                })
+            }
+        };
+
+        check_approval(transition.approval.as_ref(), None, from, event, context)?;
+        #[cfg(feature = "extended")]
+        if transition.transition_type == TransitionType::External {
+            if let Some(requirement) = self.failed_requirement(&transition.to, context) {
+                return Err(TransitionError::StateRequirementFailed {
+                    state: transition.to.clone(),
+                    requirement: requirement.to_string(),
+                });
+            }
+        }
+        let info = transition.info(&self.id, from);
+        Ok(ResolvedTransition {
+            to: transition.to.clone(),
+            transition_type: transition.transition_type.clone(),
+            priority: info.priority,
+            overridden: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+    use crate::StateMachineBuilderFactory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone)]
+    struct Parcel {
+        express: bool,
+    }
+
+    impl Context for Parcel {}
+
+    #[test]
+    fn test_peek_agrees_with_fire_and_runs_nothing() {
+        let actions = Arc::new(AtomicUsize::new(0));
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, Parcel>();
+        for (to, express) in [(Order::Delivered, true), (Order::Shipped, false)] {
+            let actions = actions.clone();
+            builder
+                .external_transition()
+                .from(Order::Paid)
+                .to(to)
+                .on(OrderEvent::Ship)
+                .when(move |_s, _e, c| c.express == express)
+                .perform(move |_s, _e, _c| {
+                    actions.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        builder
+            .internal_transition()
+            .within(Order::Paid)
+            .on(OrderEvent::Note)
+            .add();
+        let machine = builder.build();
+
+        for (fires, express) in [true, false].into_iter().enumerate() {
+            let parcel = Parcel { express };
+            let peeked = machine
+                .peek(&Order::Paid, &OrderEvent::Ship, &parcel)
+                .unwrap();
+            assert_eq!(actions.load(Ordering::SeqCst), fires);
+            assert_eq!(peeked.transition_type, TransitionType::External);
+            assert!(!peeked.overridden);
+            let fired = machine
+                .fire_event(Order::Paid, OrderEvent::Ship, parcel)
+                .unwrap();
+            assert_eq!(peeked.to, fired);
+        }
+        assert_eq!(actions.load(Ordering::SeqCst), 2);
+
+        let parcel = Parcel { express: false };
+        let internal = machine
+            .peek(&Order::Paid, &OrderEvent::Note, &parcel)
+            .unwrap();
+        assert_eq!(internal.to, Order::Paid);
+        assert_eq!(internal.transition_type, TransitionType::Internal);
+        match machine.peek(&Order::Shipped, &OrderEvent::Cancel, &parcel) {
+            Err(TransitionError::NoValidTransition { from, .. }) => {
+                assert_eq!(from, Order::Shipped)
+            }
+            other => panic!("expected NoValidTransition, got {:?}", other),
+        }
+        #[cfg(feature = "history")]
+        assert_eq!(machine.get_history().len(), 2);
+    }
+
+    #[cfg(feature = "guards")]
+    #[test]
+    fn test_peek_reports_winning_priority() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, Parcel>();
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .add();
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Delivered)
+            .on(OrderEvent::Ship)
+            .when(|_s, _e, c| c.express)
+            .with_priority(10)
+            .add();
+        let machine = builder.build();
+        let peeked = machine
+            .peek(&Order::Paid, &OrderEvent::Ship, &Parcel { express: true })
+            .unwrap();
+        assert_eq!(peeked.to, Order::Delivered);
+        assert_eq!(peeked.priority, 10);
+    }
+}
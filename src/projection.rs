@@ -0,0 +1,252 @@
+//! Read models folded from the history (requires the `history` feature)
+//!
+//! A `Projection` is a value kept up to date with every history record the
+//! machine writes, such as the number of orders that entered each state.
+//! Projections are attached by name with `StateMachineBuilder::with_projection`
+//! and read back with `StateMachine::projection`. Each record is applied
+//! right after it is committed to the history, in history order, so a
+//! projection always agrees with the records written so far.
+//!
+//! `StateMachine::rebuild_from_history` recomputes every projection from its
+//! initial value and the records the history still holds, e.g. after a
+//! projection panicked. Clearing the history leaves projections as they are;
+//! a bounded history only rebuilds from the records it kept.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder, TransitionRecord};
+
+/// Function applying one history record to a projected value
+pub type ProjectionFn<S, E, T> = Arc<dyn Fn(&mut T, &TransitionRecord<S, E>) + Send + Sync>;
+
+/// A value folded from history records, see the module documentation
+pub struct Projection<S, E, T>
+where
+    S: State,
+    E: Event,
+{
+    initial: T,
+    value: Mutex<T>,
+    apply: ProjectionFn<S, E, T>,
+}
+
+impl<S, E, T> Projection<S, E, T>
+where
+    S: State,
+    E: Event,
+    T: Clone + Send + Sync + 'static,
+{
+    pub fn new<F>(initial: T, apply: F) -> Self
+    where
+        F: Fn(&mut T, &TransitionRecord<S, E>) + Send + Sync + 'static,
+    {
+        Projection {
+            value: Mutex::new(initial.clone()),
+            initial,
+            apply: Arc::new(apply),
+        }
+    }
+}
+
+impl<S, E, T> fmt::Debug for Projection<S, E, T>
+where
+    S: State,
+    E: Event,
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Projection")
+            .field("value", &self.value)
+            .finish_non_exhaustive()
+    }
+}
+
+// A projection with its value type erased
+pub(crate) trait ProjectionSlot<S, E>: Send + Sync
+where
+    S: State,
+    E: Event,
+{
+    fn apply(&self, records: &mut dyn Iterator<Item = &TransitionRecord<S, E>>);
+
+    fn rebuild(&self, records: &[TransitionRecord<S, E>]);
+
+    // The same projection back at its initial value
+    fn fresh(&self) -> Box<dyn ProjectionSlot<S, E>>;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<S, E, T> ProjectionSlot<S, E> for Projection<S, E, T>
+where
+    S: State + 'static,
+    E: Event + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    fn apply(&self, records: &mut dyn Iterator<Item = &TransitionRecord<S, E>>) {
+        let mut value = self.value.lock().unwrap_or_else(|e| e.into_inner());
+        records.for_each(|record| (self.apply)(&mut value, record));
+    }
+
+    fn rebuild(&self, records: &[TransitionRecord<S, E>]) {
+        let mut value = self.initial.clone();
+        records
+            .iter()
+            .for_each(|record| (self.apply)(&mut value, record));
+        *self.value.lock().unwrap_or_else(|e| e.into_inner()) = value;
+    }
+
+    fn fresh(&self) -> Box<dyn ProjectionSlot<S, E>> {
+        Box::new(Projection {
+            initial: self.initial.clone(),
+            value: Mutex::new(self.initial.clone()),
+            apply: self.apply.clone(),
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub(crate) type Projections<S, E> = HashMap<String, Box<dyn ProjectionSlot<S, E>>>;
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State + 'static,
+    E: Event + 'static,
+    C: Context,
+{
+    /// Keep `projection` up to date with the history under `name`
+    ///
+    /// A second projection with the same name replaces the first.
+    pub fn with_projection<T>(
+        &mut self,
+        name: impl Into<String>,
+        projection: Projection<S, E, T>,
+    ) -> &mut Self
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.projections.insert(name.into(), Box::new(projection));
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State + 'static,
+    E: Event + 'static,
+    C: Context,
+{
+    /// Current value of the projection named `name`
+    ///
+    /// `None` when there is no such projection or its value is not a `T`.
+    pub fn projection<T>(&self, name: &str) -> Option<T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.recording.with_projection(name, |slot| {
+            let projection = slot.as_any().downcast_ref::<Projection<S, E, T>>()?;
+            let value = projection.value.lock().unwrap_or_else(|e| e.into_inner());
+            Some(value.clone())
+        })?
+    }
+
+    /// Recompute every projection from the records the history holds
+    pub fn rebuild_from_history(&self) {
+        self.recording.rebuild_projections();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_builder, NoContext, Order, OrderEvent};
+
+    fn state_counts() -> Projection<Order, OrderEvent, HashMap<Order, usize>> {
+        Projection::<Order, OrderEvent, _>::new(HashMap::new(), |counts, record| {
+            if record.success {
+                *counts.entry(record.to.clone()).or_insert(0) += 1;
+            }
+        })
+    }
+
+    fn projected_machine() -> StateMachine<Order, OrderEvent, NoContext> {
+        let mut builder = order_builder();
+        builder
+            .with_projection("counts", state_counts())
+            .with_projection(
+                "failures",
+                Projection::new(0usize, |failures, record| {
+                    *failures += usize::from(!record.success)
+                }),
+            );
+        builder.build()
+    }
+
+    fn counts_from_history(
+        machine: &StateMachine<Order, OrderEvent, NoContext>,
+    ) -> HashMap<Order, usize> {
+        let mut counts = HashMap::new();
+        for record in machine.get_history().iter().filter(|r| r.success) {
+            *counts.entry(record.to.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_projection_follows_history() {
+        let machine = projected_machine();
+        for _ in 0..3 {
+            machine
+                .fire_event(Order::Placed, OrderEvent::Pay, NoContext)
+                .unwrap();
+        }
+        machine
+            .fire_event(Order::Paid, OrderEvent::Ship, NoContext)
+            .unwrap();
+        assert!(machine
+            .fire_event(Order::Shipped, OrderEvent::Pay, NoContext)
+            .is_err());
+
+        let counts: HashMap<Order, usize> = machine.projection("counts").unwrap();
+        assert_eq!(counts, counts_from_history(&machine));
+        assert_eq!(counts[&Order::Paid], 3);
+        assert_eq!(counts[&Order::Shipped], 1);
+        assert_eq!(machine.projection::<usize>("failures"), Some(1));
+
+        // Unknown names and other value types are not found
+        assert_eq!(machine.projection::<usize>("counts"), None);
+        assert_eq!(machine.projection::<usize>("missing"), None);
+    }
+
+    #[test]
+    fn test_rebuild_matches_incremental() {
+        let machine = projected_machine();
+        machine
+            .fire_event(Order::Placed, OrderEvent::Pay, NoContext)
+            .unwrap();
+        let _ = machine.fire_event(Order::Placed, OrderEvent::Ship, NoContext);
+        machine
+            .fire_event(Order::Paid, OrderEvent::Ship, NoContext)
+            .unwrap();
+
+        let incremental: HashMap<Order, usize> = machine.projection("counts").unwrap();
+        machine.rebuild_from_history();
+        assert_eq!(machine.projection("counts"), Some(incremental));
+        assert_eq!(machine.projection::<usize>("failures"), Some(1));
+
+        // Rebuilding after clearing starts over from the initial values
+        machine.clear_history();
+        machine.rebuild_from_history();
+        assert_eq!(
+            machine.projection("counts"),
+            Some(HashMap::<Order, usize>::new())
+        );
+        assert_eq!(machine.projection::<usize>("failures"), Some(0));
+    }
+}
@@ -0,0 +1,466 @@
+//! History and metrics written by the firing pipeline
+//!
+//! Every lock `fire_event` writes through lives in `RecordingState`. Its
+//! fields are private to this module, so read-only APIs such as `verify`,
+//! `available_events` or the exports cannot take those locks by accident;
+//! they only reach them through the explicit methods below.
+
+#[cfg(feature = "history")]
+use std::collections::VecDeque;
+#[cfg(feature = "metrics")]
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+#[cfg(any(feature = "history", feature = "metrics"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(feature = "history", feature = "metrics"))]
+use std::sync::Mutex;
+
+#[cfg(feature = "history")]
+use crate::projection::{ProjectionSlot, Projections};
+#[cfg(feature = "metrics")]
+use crate::{DurationStats, StateMachineMetrics};
+use crate::{Event, State};
+#[cfg(feature = "history")]
+use crate::{HistorySink, TransitionRecord};
+
+pub(crate) struct RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    #[cfg(feature = "history")]
+    history: Mutex<VecDeque<TransitionRecord<S, E>>>,
+    // Bumped under the `history` lock by each clear
+    #[cfg(feature = "history")]
+    history_epoch: AtomicU64,
+    // Records of earlier epochs not drained yet, locked after `history`,
+    // only filled when `keep_cleared` is set
+    #[cfg(feature = "history")]
+    previous_history: Mutex<Vec<TransitionRecord<S, E>>>,
+    // Records kept before the oldest are dropped, unbounded if `None`
+    #[cfg(feature = "history")]
+    history_capacity: Option<usize>,
+    // Updated with each record once it is in `history`
+    #[cfg(feature = "history")]
+    projections: Projections<S, E>,
+    // Written after `history`, outside its lock
+    #[cfg(feature = "history")]
+    sync_sink: Option<Box<dyn HistorySink<S, E>>>,
+    #[cfg(all(feature = "async", feature = "history"))]
+    sink: Option<std::sync::Arc<crate::HistorySinkHandle<S, E>>>,
+    #[cfg(feature = "metrics")]
+    metrics: Mutex<StateMachineMetrics<S, E>>,
+    // Bumped under the `metrics` lock by each reset
+    #[cfg(feature = "metrics")]
+    metrics_epoch: AtomicU64,
+    // Metrics of earlier epochs not drained yet, locked after `metrics`
+    #[cfg(feature = "metrics")]
+    previous_metrics: Mutex<BTreeMap<u64, StateMachineMetrics<S, E>>>,
+    // Whether new metrics keep every transition duration
+    #[cfg(feature = "metrics")]
+    duration_samples: bool,
+    // Whether cleared records and reset metrics are kept until drained
+    #[cfg(any(feature = "history", feature = "metrics"))]
+    keep_cleared: bool,
+    _types: PhantomData<fn() -> (S, E)>,
+}
+
+impl<S, E> Default for RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    fn default() -> Self {
+        Self::with_capacity(0, 0)
+    }
+}
+
+impl<S, E> RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Preallocate `history` records, and visit counts for `states` states
+    #[allow(unused_variables)]
+    pub(crate) fn with_capacity(history: usize, states: usize) -> Self {
+        RecordingState {
+            #[cfg(feature = "history")]
+            history: Mutex::new(VecDeque::with_capacity(history)),
+            #[cfg(feature = "history")]
+            history_epoch: AtomicU64::new(0),
+            #[cfg(feature = "history")]
+            previous_history: Mutex::new(Vec::new()),
+            #[cfg(feature = "history")]
+            history_capacity: None,
+            #[cfg(feature = "history")]
+            projections: Projections::new(),
+            #[cfg(feature = "history")]
+            sync_sink: None,
+            #[cfg(all(feature = "async", feature = "history"))]
+            sink: None,
+            #[cfg(feature = "metrics")]
+            metrics: Mutex::new(StateMachineMetrics {
+                state_visit_counts: HashMap::with_capacity(states),
+                ..StateMachineMetrics::new()
+            }),
+            #[cfg(feature = "metrics")]
+            metrics_epoch: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            previous_metrics: Mutex::new(BTreeMap::new()),
+            #[cfg(feature = "metrics")]
+            duration_samples: false,
+            #[cfg(any(feature = "history", feature = "metrics"))]
+            keep_cleared: false,
+            _types: PhantomData,
+        }
+    }
+
+    /// `fresh` for a machine over another state type, without projections
+    /// since they fold records of this one
+    #[cfg(feature = "parallel")]
+    pub(crate) fn fresh_for<S2: State>(&self) -> RecordingState<S2, E> {
+        RecordingState {
+            #[cfg(feature = "history")]
+            history_epoch: AtomicU64::new(self.history_epoch()),
+            #[cfg(feature = "metrics")]
+            metrics_epoch: AtomicU64::new(self.metrics_epoch()),
+            #[cfg(feature = "metrics")]
+            metrics: Mutex::new(StateMachineMetrics {
+                transition_durations: self.empty_metrics(0).transition_durations,
+                ..StateMachineMetrics::new()
+            }),
+            #[cfg(feature = "metrics")]
+            duration_samples: self.duration_samples,
+            #[cfg(feature = "history")]
+            history_capacity: self.history_capacity,
+            #[cfg(any(feature = "history", feature = "metrics"))]
+            keep_cleared: self.keep_cleared,
+            ..RecordingState::default()
+        }
+    }
+
+    /// Empty recording in the same epochs, with the same history capacity
+    /// and projections at their initial values, without the sinks
+    pub(crate) fn fresh(&self) -> Self {
+        RecordingState {
+            #[cfg(feature = "history")]
+            history_epoch: AtomicU64::new(self.history_epoch()),
+            #[cfg(feature = "metrics")]
+            metrics_epoch: AtomicU64::new(self.metrics_epoch()),
+            #[cfg(feature = "metrics")]
+            metrics: Mutex::new(self.empty_metrics(0)),
+            #[cfg(feature = "metrics")]
+            duration_samples: self.duration_samples,
+            #[cfg(feature = "history")]
+            history_capacity: self.history_capacity,
+            #[cfg(any(feature = "history", feature = "metrics"))]
+            keep_cleared: self.keep_cleared,
+            #[cfg(feature = "history")]
+            projections: self
+                .projections
+                .iter()
+                .map(|(name, projection)| (name.clone(), projection.fresh()))
+                .collect(),
+            ..Self::default()
+        }
+    }
+}
+
+#[cfg(any(feature = "history", feature = "metrics"))]
+impl<S, E> RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Keep cleared history records and reset metrics windows until they
+    /// are drained, instead of dropping them
+    pub(crate) fn with_cleared_epochs(mut self, keep: bool) -> Self {
+        self.keep_cleared = keep;
+        self
+    }
+}
+
+#[cfg(all(feature = "async", feature = "history"))]
+impl<S, E> RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub(crate) fn with_sink(
+        mut self,
+        sink: Option<std::sync::Arc<crate::HistorySinkHandle<S, E>>>,
+    ) -> Self {
+        self.sink = sink;
+        self
+    }
+}
+
+#[cfg(feature = "history")]
+impl<S, E> RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Keep at most `capacity` records, dropping the oldest first
+    pub(crate) fn with_history_capacity(mut self, capacity: Option<usize>) -> Self {
+        if let (Some(capacity), Ok(history)) = (capacity, self.history.get_mut()) {
+            history.shrink_to(capacity);
+        }
+        self.history_capacity = capacity;
+        self
+    }
+
+    pub(crate) fn with_sync_sink(mut self, sink: Option<Box<dyn HistorySink<S, E>>>) -> Self {
+        self.sync_sink = sink;
+        self
+    }
+
+    pub(crate) fn with_projections(mut self, projections: Projections<S, E>) -> Self {
+        self.projections = projections;
+        self
+    }
+
+    pub(crate) fn history_capacity(&self) -> Option<usize> {
+        self.history_capacity
+    }
+
+    pub(crate) fn history_epoch(&self) -> u64 {
+        self.history_epoch.load(Ordering::Acquire)
+    }
+
+    /// Append `records`, returning whether the history could be written
+    ///
+    /// Records of fires started before the last clear are kept for
+    /// `drain_previous_history` instead, or dropped when cleared epochs
+    /// are not kept.
+    pub(crate) fn record_history(
+        &self,
+        records: impl IntoIterator<Item = TransitionRecord<S, E>>,
+    ) -> bool {
+        let records: Vec<_> = records.into_iter().collect();
+        let persisted = self.sync_sink.as_ref().map(|sink| (sink, records.clone()));
+        #[cfg(feature = "async")]
+        if let Some(sink) = &self.sink {
+            records
+                .iter()
+                .cloned()
+                .for_each(|record| sink.enqueue(record));
+        }
+        let written = match self.history.lock() {
+            Ok(mut history) => {
+                let epoch = self.history_epoch();
+                let records = if records.iter().all(|record| record.epoch >= epoch) {
+                    records
+                } else {
+                    let (current, stale): (Vec<_>, Vec<_>) = records
+                        .into_iter()
+                        .partition(|record| record.epoch >= epoch);
+                    for projection in self.projections.values() {
+                        isolate(|| projection.apply(&mut stale.iter()));
+                    }
+                    self.keep_previous(stale);
+                    current
+                };
+                let start = history.len();
+                history.extend(records);
+                // Still under the lock, so projections see history order
+                for projection in self.projections.values() {
+                    isolate(|| projection.apply(&mut history.range(start..)));
+                }
+                if let Some(capacity) = self.history_capacity {
+                    let excess = history.len().saturating_sub(capacity);
+                    history.drain(..excess);
+                }
+                true
+            }
+            Err(_) => false,
+        };
+        if let Some((sink, records)) = persisted {
+            records.iter().for_each(|record| sink.record(record));
+        }
+        written
+    }
+
+    pub(crate) fn persisted_history(&self) -> Vec<TransitionRecord<S, E>> {
+        self.sync_sink
+            .as_ref()
+            .map(|sink| sink.load())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn with_history<R>(&self, f: impl FnOnce(&[TransitionRecord<S, E>]) -> R) -> R {
+        f(self.history.lock().unwrap().make_contiguous())
+    }
+
+    /// Remove the most recent successful record
+    pub(crate) fn remove_last_success(&self) {
+        let mut history = self.history.lock().unwrap();
+        if let Some(position) = history.iter().rposition(|record| record.success) {
+            history.remove(position);
+        }
+    }
+
+    /// Start a new epoch, keeping the records of the last one for
+    /// `drain_previous_history` when cleared epochs are kept
+    pub(crate) fn clear_history(&self) {
+        let mut history = self.history.lock().unwrap();
+        self.history_epoch.fetch_add(1, Ordering::AcqRel);
+        if self.keep_cleared {
+            let cleared: Vec<_> = history.drain(..).collect();
+            self.keep_previous(cleared);
+        } else {
+            history.clear();
+        }
+    }
+
+    // Called under the `history` lock; the history capacity bounds the
+    // kept records too, dropping the oldest epochs first
+    fn keep_previous(&self, records: impl IntoIterator<Item = TransitionRecord<S, E>>) {
+        if !self.keep_cleared {
+            return;
+        }
+        let mut previous = self.previous_history.lock().unwrap();
+        previous.extend(records);
+        if let Some(capacity) = self.history_capacity {
+            let excess = previous.len().saturating_sub(capacity);
+            if excess > 0 {
+                previous.sort_by_key(|record| record.epoch);
+                previous.drain(..excess);
+            }
+        }
+    }
+
+    /// Records kept from earlier epochs and not drained yet
+    pub(crate) fn with_previous_history<R>(
+        &self,
+        f: impl FnOnce(&[TransitionRecord<S, E>]) -> R,
+    ) -> R {
+        f(&self.previous_history.lock().unwrap())
+    }
+
+    pub(crate) fn drain_previous_history(&self) -> Vec<TransitionRecord<S, E>> {
+        let mut drained = std::mem::take(&mut *self.previous_history.lock().unwrap());
+        drained.sort_by_key(|record| record.epoch);
+        drained
+    }
+
+    pub(crate) fn with_projection<R>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&dyn ProjectionSlot<S, E>) -> R,
+    ) -> Option<R> {
+        self.projections
+            .get(name)
+            .map(|projection| f(projection.as_ref()))
+    }
+
+    pub(crate) fn rebuild_projections(&self) {
+        let mut history = self.history.lock().unwrap();
+        let records = history.make_contiguous();
+        for projection in self.projections.values() {
+            isolate(|| projection.rebuild(records));
+        }
+    }
+}
+
+// A panicking projection must not poison the history
+#[cfg(feature = "history")]
+fn isolate(update: impl FnOnce()) {
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(update));
+}
+
+#[cfg(feature = "metrics")]
+impl<S, E> RecordingState<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub(crate) fn metrics_epoch(&self) -> u64 {
+        self.metrics_epoch.load(Ordering::Acquire)
+    }
+
+    /// Keep every transition duration in the metrics, with room for
+    /// `reserve` of them
+    pub(crate) fn with_duration_samples(mut self, keep: bool, reserve: usize) -> Self {
+        self.duration_samples = keep;
+        let durations = self.empty_metrics(reserve).transition_durations;
+        if let Ok(metrics) = self.metrics.get_mut() {
+            metrics.transition_durations = durations;
+        }
+        self
+    }
+
+    fn empty_metrics(&self, reserve: usize) -> StateMachineMetrics<S, E> {
+        let mut metrics = StateMachineMetrics::new();
+        if self.duration_samples {
+            metrics.transition_durations = DurationStats::with_samples(reserve);
+        }
+        metrics
+    }
+
+    /// Apply `f` to the metrics, returning whether they could be written
+    pub(crate) fn update_metrics(&self, f: impl FnOnce(&mut StateMachineMetrics<S, E>)) -> bool {
+        self.update_metrics_in(u64::MAX, f)
+    }
+
+    /// `update_metrics` for a fire started in `epoch`, applied to the
+    /// metrics of that epoch when it has been reset since
+    pub(crate) fn update_metrics_in(
+        &self,
+        epoch: u64,
+        f: impl FnOnce(&mut StateMachineMetrics<S, E>),
+    ) -> bool {
+        match self.metrics.lock() {
+            Ok(mut metrics) => {
+                if epoch < self.metrics_epoch() {
+                    if !self.keep_cleared {
+                        return true;
+                    }
+                    f(self
+                        .previous_metrics
+                        .lock()
+                        .unwrap()
+                        .entry(epoch)
+                        .or_default());
+                } else {
+                    f(&mut metrics);
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Start a new epoch, keeping the metrics of the last one for
+    /// `drain_previous_metrics` when cleared epochs are kept
+    pub(crate) fn reset_metrics(&self) {
+        let mut metrics = self.metrics.lock().unwrap();
+        let epoch = self.metrics_epoch.fetch_add(1, Ordering::AcqRel);
+        let finished = std::mem::replace(&mut *metrics, self.empty_metrics(0));
+        if !self.keep_cleared {
+            return;
+        }
+        self.previous_metrics
+            .lock()
+            .unwrap()
+            .insert(epoch, finished);
+    }
+
+    pub(crate) fn drain_previous_metrics(&self) -> Vec<(u64, StateMachineMetrics<S, E>)> {
+        std::mem::take(&mut *self.previous_metrics.lock().unwrap())
+            .into_iter()
+            .collect()
+    }
+
+    /// Metrics windows kept from earlier epochs and not drained yet
+    pub(crate) fn with_previous_metrics<R>(
+        &self,
+        f: impl FnOnce(&BTreeMap<u64, StateMachineMetrics<S, E>>) -> R,
+    ) -> R {
+        f(&self.previous_metrics.lock().unwrap())
+    }
+
+    pub(crate) fn with_metrics<R>(&self, f: impl FnOnce(&StateMachineMetrics<S, E>) -> R) -> R {
+        f(&self.metrics.lock().unwrap())
+    }
+}
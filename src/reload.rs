@@ -0,0 +1,356 @@
+//! Replacing a machine definition while it is in use
+//!
+//! `ReloadableStateMachine` holds the current definition in a slot that can
+//! be swapped at any time. Every call takes its own reference to the
+//! definition current when it starts, so a fire that overlaps a swap runs
+//! entirely against the old definition and the next one sees the new one.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "metrics")]
+use crate::StateMachineMetrics;
+#[cfg(feature = "history")]
+use crate::TransitionRecord;
+use crate::{
+    Context, Event, ResolvedTransition, State, StateMachine, TransitionError, TransitionOutcome,
+};
+
+/// Something instances persisted under the old definition may run into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompatibilityWarning<S, E> {
+    /// `state` has no transitions in the new definition; instances stored
+    /// in it are stuck
+    StateRemoved { state: S },
+    /// `event` is no longer handled from `from`
+    EventRemoved { from: S, event: E },
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> fmt::Display for CompatibilityWarning<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompatibilityWarning::StateRemoved { state } => {
+                write!(f, "State {:?} was removed", state)
+            }
+            CompatibilityWarning::EventRemoved { from, event } => {
+                write!(f, "Event {:?} is no longer handled from {:?}", event, from)
+            }
+        }
+    }
+}
+
+/// Check whether instances of `old` can keep running on `new`
+///
+/// Warnings are ordered by the `Debug` names of their states and events.
+pub fn validate_compatible<S, E, C>(
+    old: &StateMachine<S, E, C>,
+    new: &StateMachine<S, E, C>,
+) -> Vec<CompatibilityWarning<S, E>>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    let new_states = new.known_states();
+    let mut removed: Vec<&S> = old
+        .known_states()
+        .into_iter()
+        .filter(|state| !new_states.contains(state))
+        .collect();
+    removed.sort_by_cached_key(|state| format!("{:?}", state));
+
+    let mut dropped: Vec<&(S, E)> = old
+        .transitions
+        .keys()
+        .filter(|key| new_states.contains(&key.0) && new.candidates(key).is_none())
+        .collect();
+    dropped.sort_by_cached_key(|(from, event)| format!("{:?} {:?}", from, event));
+
+    removed
+        .into_iter()
+        .map(|state| CompatibilityWarning::StateRemoved {
+            state: state.clone(),
+        })
+        .chain(
+            dropped
+                .into_iter()
+                .map(|(from, event)| CompatibilityWarning::EventRemoved {
+                    from: from.clone(),
+                    event: event.clone(),
+                }),
+        )
+        .collect()
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // States appearing as source or target of any transition
+    fn known_states(&self) -> HashSet<&S> {
+        self.transitions
+            .iter()
+            .flat_map(|((from, _), candidates)| {
+                std::iter::once(from).chain(candidates.iter().map(|t| &t.to))
+            })
+            .chain(
+                self.wildcard_transitions
+                    .values()
+                    .flat_map(|candidates| candidates.iter().map(|t| &t.to)),
+            )
+            .collect()
+    }
+}
+
+/// A machine definition that can be replaced while events are fired
+pub struct ReloadableStateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    slot: RwLock<Arc<StateMachine<S, E, C>>>,
+}
+
+impl<S, E, C> ReloadableStateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn new(machine: StateMachine<S, E, C>) -> Self {
+        ReloadableStateMachine {
+            slot: RwLock::new(Arc::new(machine)),
+        }
+    }
+
+    /// The definition in use right now
+    pub fn current(&self) -> Arc<StateMachine<S, E, C>> {
+        self.slot.read().unwrap().clone()
+    }
+
+    /// Install `new`, returning the definition it replaces
+    ///
+    /// Fires already running finish on the old definition. History and
+    /// metrics are not carried over; read them from the returned machine.
+    pub fn swap(&self, new: StateMachine<S, E, C>) -> Arc<StateMachine<S, E, C>> {
+        std::mem::replace(&mut *self.slot.write().unwrap(), Arc::new(new))
+    }
+
+    pub fn fire_event(&self, from: S, event: E, context: C) -> Result<S, TransitionError<S, E>> {
+        self.current().fire_event(from, event, context)
+    }
+
+    pub fn fire_event_detailed(
+        &self,
+        from: S,
+        event: E,
+        context: C,
+    ) -> Result<TransitionOutcome<S, E>, TransitionError<S, E>> {
+        self.current().fire_event_detailed(from, event, context)
+    }
+
+    pub fn fire_event_mut(
+        &self,
+        from: S,
+        event: E,
+        context: &mut C,
+    ) -> Result<S, TransitionError<S, E>> {
+        self.current().fire_event_mut(from, event, context)
+    }
+
+    pub fn verify(&self, from: &S, event: &E) -> bool {
+        self.current().verify(from, event)
+    }
+
+    pub fn can_fire(&self, from: &S, event: &E, context: &C) -> bool {
+        self.current().can_fire(from, event, context)
+    }
+
+    pub fn peek(
+        &self,
+        from: &S,
+        event: &E,
+        context: &C,
+    ) -> Result<ResolvedTransition<S>, TransitionError<S, E>> {
+        self.current().peek(from, event, context)
+    }
+
+    pub fn available_events(&self, from: &S) -> Vec<E> {
+        self.current().available_events(from)
+    }
+
+    pub fn allowed_events(&self, from: &S) -> Vec<E> {
+        self.current().allowed_events(from)
+    }
+
+    pub fn allowed_events_with(&self, from: &S, context: &C) -> Vec<E> {
+        self.current().allowed_events_with(from, context)
+    }
+
+    pub fn id(&self) -> String {
+        self.current().id().to_string()
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// History of the current definition
+    pub fn get_history(&self) -> Vec<TransitionRecord<S, E>> {
+        self.current().get_history()
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Metrics of the current definition
+    pub fn get_metrics(&self) -> StateMachineMetrics<S, E> {
+        self.current().get_metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+    use std::thread;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Step {
+        Draft,
+        Review,
+        PublishedV1,
+        PublishedV2,
+    }
+
+    impl State for Step {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum StepEvent {
+        Submit,
+        Publish,
+    }
+
+    impl Event for StepEvent {}
+
+    #[derive(Debug, Clone, Default)]
+    struct Seen {
+        version: u8,
+    }
+
+    impl Context for Seen {}
+
+    fn definition(version: u8) -> StateMachine<Step, StepEvent, Seen> {
+        let mut builder = StateMachineBuilderFactory::create::<Step, StepEvent, Seen>();
+        let published = if version == 1 {
+            Step::PublishedV1
+        } else {
+            Step::PublishedV2
+        };
+        if version == 1 {
+            builder
+                .external_transition()
+                .from(Step::Draft)
+                .to(Step::Review)
+                .on(StepEvent::Submit)
+                .perform(|_s, _e, _c| {});
+            builder
+                .external_transition()
+                .from(Step::Review)
+                .to(published)
+                .on(StepEvent::Publish)
+                .perform_mut(move |_s, _e, c| c.version = version);
+        } else {
+            builder
+                .external_transition()
+                .from(Step::Draft)
+                .to(published)
+                .on(StepEvent::Publish)
+                .perform_mut(move |_s, _e, c| c.version = version);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_fires_never_see_torn_definition() {
+        let machine = Arc::new(ReloadableStateMachine::new(definition(1)));
+        let firing: Vec<_> = (0..4)
+            .map(|_| {
+                let machine = machine.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let from = if machine.verify(&Step::Review, &StepEvent::Publish) {
+                            Step::Review
+                        } else {
+                            Step::Draft
+                        };
+                        let mut seen = Seen::default();
+                        if let Ok(to) = machine.fire_event_mut(from, StepEvent::Publish, &mut seen)
+                        {
+                            let expected = if seen.version == 1 {
+                                Step::PublishedV1
+                            } else {
+                                Step::PublishedV2
+                            };
+                            assert_eq!(to, expected);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for version in [2, 1, 2, 1, 2] {
+            machine.swap(definition(version));
+            thread::yield_now();
+        }
+        for handle in firing {
+            handle.join().unwrap();
+        }
+
+        let mut seen = Seen::default();
+        assert_eq!(
+            machine
+                .fire_event_mut(Step::Draft, StepEvent::Publish, &mut seen)
+                .unwrap(),
+            Step::PublishedV2
+        );
+    }
+
+    #[test]
+    fn test_swap_returns_old_definition() {
+        let machine = ReloadableStateMachine::new(definition(1));
+        machine
+            .fire_event(Step::Draft, StepEvent::Submit, Seen::default())
+            .unwrap();
+        let old = machine.swap(definition(2));
+        assert!(old.verify(&Step::Draft, &StepEvent::Submit));
+        assert!(!machine.verify(&Step::Draft, &StepEvent::Submit));
+        #[cfg(feature = "history")]
+        {
+            assert_eq!(old.get_history().len(), 1);
+            assert!(machine.get_history().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_removed_state_is_reported() {
+        let warnings = validate_compatible(&definition(1), &definition(2));
+        assert_eq!(
+            warnings,
+            vec![
+                CompatibilityWarning::StateRemoved {
+                    state: Step::PublishedV1
+                },
+                CompatibilityWarning::StateRemoved {
+                    state: Step::Review
+                },
+                CompatibilityWarning::EventRemoved {
+                    from: Step::Draft,
+                    event: StepEvent::Submit
+                },
+            ]
+        );
+        assert_eq!(warnings[1].to_string(), "State Review was removed");
+        assert!(validate_compatible(&definition(2), &definition(2)).is_empty());
+    }
+}
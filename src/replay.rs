@@ -0,0 +1,244 @@
+//! Rebuilding a state from a log of events
+//!
+//! `StateMachine::replay` walks a log of events from an initial state,
+//! e.g. the events of an entity persisted before a crash, and returns the
+//! state it ends in. Each event is resolved with `peek`: guards are called,
+//! but no action, listener or fail callback runs and nothing is written to
+//! the history or metrics. `replay_with_actions` also runs the transition
+//! and state actions, for actions that maintain read models which must be
+//! rebuilt, still without recording anything on the machine.
+//!
+//! Both stop at the first event the definition no longer accepts.
+
+use std::fmt;
+
+use crate::{Context, Event, State, StateMachine, TransitionError};
+
+/// The event of a log that could not be replayed
+#[derive(Debug, Clone)]
+pub struct ReplayError<S, E> {
+    /// Position of the event in the log
+    pub index: usize,
+    /// State the log had reached
+    pub from: S,
+    pub event: E,
+    pub error: TransitionError<S, E>,
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> fmt::Display for ReplayError<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Replaying event #{} ({:?} from {:?}) failed: {}",
+            self.index, self.event, self.from, self.error
+        )
+    }
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> std::error::Error for ReplayError<S, E> {}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// State reached by firing `records` in order from `initial`, without
+    /// running any action
+    pub fn replay(
+        &self,
+        initial: S,
+        records: impl IntoIterator<Item = (E, C)>,
+    ) -> Result<S, ReplayError<S, E>> {
+        let mut state = initial;
+        for (index, (event, context)) in records.into_iter().enumerate() {
+            match self.peek(&state, &event, &context) {
+                Ok(resolved) => state = resolved.to,
+                Err(error) => {
+                    return Err(ReplayError {
+                        index,
+                        from: state,
+                        event,
+                        error,
+                    })
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// `replay`, running the actions of the transitions and states
+    ///
+    /// Listeners and the fail callback are not called, and the history and
+    /// metrics of the machine are left as they are.
+    pub fn replay_with_actions(
+        &self,
+        initial: S,
+        records: impl IntoIterator<Item = (E, C)>,
+    ) -> Result<S, ReplayError<S, E>> {
+        let mut machine = self.fork();
+        machine.fail_callback = None;
+
+        let mut state = initial;
+        for (index, (event, mut context)) in records.into_iter().enumerate() {
+            match machine.fire_unobserved(
+                state.clone(),
+                event.clone(),
+                &mut context,
+                None,
+                None,
+                None,
+            ) {
+                Ok((outcome, _)) => state = outcome.to,
+                Err(error) => {
+                    return Err(ReplayError {
+                        index,
+                        from: state,
+                        event,
+                        error,
+                    })
+                }
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Ticket {
+        Open,
+        Assigned,
+        Resolved,
+    }
+
+    impl State for Ticket {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum TicketEvent {
+        Assign,
+        Resolve,
+        Reopen,
+    }
+
+    impl Event for TicketEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Agent {
+        on_call: bool,
+    }
+
+    impl Context for Agent {}
+
+    fn ticket_machine(notified: &Arc<AtomicUsize>) -> StateMachine<Ticket, TicketEvent, Agent> {
+        let mut builder = StateMachineBuilderFactory::create::<Ticket, TicketEvent, Agent>();
+        let counter = notified.clone();
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Assigned)
+            .on(TicketEvent::Assign)
+            .when(|_s, _e, agent| agent.on_call)
+            .perform(move |_s, _e, _c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        builder
+            .external_transition()
+            .from(Ticket::Assigned)
+            .to(Ticket::Resolved)
+            .on(TicketEvent::Resolve)
+            .add();
+        builder
+            .external_transition()
+            .from(Ticket::Resolved)
+            .to(Ticket::Open)
+            .on(TicketEvent::Reopen)
+            .add();
+        builder.build()
+    }
+
+    fn log() -> Vec<(TicketEvent, Agent)> {
+        [
+            TicketEvent::Assign,
+            TicketEvent::Resolve,
+            TicketEvent::Reopen,
+            TicketEvent::Assign,
+        ]
+        .into_iter()
+        .map(|event| (event, Agent { on_call: true }))
+        .collect()
+    }
+
+    #[test]
+    fn test_replay_has_no_side_effects() {
+        let notified = Arc::new(AtomicUsize::new(0));
+        let machine = ticket_machine(&notified);
+
+        assert_eq!(
+            machine.replay(Ticket::Open, log()).unwrap(),
+            Ticket::Assigned
+        );
+        assert_eq!(notified.load(Ordering::SeqCst), 0);
+        #[cfg(feature = "history")]
+        assert_eq!(machine.history_len(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!(machine.get_metrics().total_transitions, 0);
+
+        assert_eq!(
+            machine.replay_with_actions(Ticket::Open, log()).unwrap(),
+            Ticket::Assigned
+        );
+        assert_eq!(notified.load(Ordering::SeqCst), 2);
+        #[cfg(feature = "history")]
+        assert_eq!(machine.history_len(), 0);
+    }
+
+    #[test]
+    fn test_replay_stops_at_mismatch() {
+        let machine = ticket_machine(&Arc::new(AtomicUsize::new(0)));
+        let mut events = log();
+        events[3].1.on_call = false;
+
+        for result in [
+            machine.replay(Ticket::Open, events.clone()),
+            machine.replay_with_actions(Ticket::Open, events),
+        ] {
+            let error = result.unwrap_err();
+            assert_eq!(error.index, 3);
+            assert_eq!(error.from, Ticket::Open);
+            assert_eq!(error.error.code(), "condition_failed");
+        }
+
+        let error = machine
+            .replay(
+                Ticket::Open,
+                [(TicketEvent::Resolve, Agent { on_call: true })],
+            )
+            .unwrap_err();
+        assert_eq!(error.index, 0);
+        assert_eq!(error.error.code(), "no_valid_transition");
+    }
+
+    #[test]
+    #[cfg(feature = "history")]
+    fn test_replay_recorded_history() {
+        let machine = ticket_machine(&Arc::new(AtomicUsize::new(0)));
+        let mut state = Ticket::Open;
+        for (event, agent) in log() {
+            state = machine.fire_event(state, event, agent).unwrap();
+        }
+
+        let events = machine
+            .get_history()
+            .into_iter()
+            .filter(|record| record.success)
+            .map(|record| (record.event, Agent { on_call: true }));
+        assert_eq!(machine.replay(Ticket::Open, events).unwrap(), state);
+    }
+}
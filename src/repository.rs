@@ -0,0 +1,547 @@
+//! Storage of the current state of entities driven by a state machine
+//!
+//! Every saved state carries a version. A save names the version it expects
+//! to replace, so two processes firing events for the same entity can't
+//! both load the same state and silently overwrite each other: the second
+//! save fails with a `VersionConflict` and its fire with
+//! `TransitionError::VersionConflict`.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use crate::{Context, Event, SequenceMark, State, StateMachine, TransitionError};
+
+/// Version of an entity that has never been saved
+pub const NEW_ENTITY: u64 = 0;
+
+/// A save expected a version other than the stored one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected version {} but the stored version is {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+/// Authoritative store of the current state of each entity
+pub trait StateRepository<K, S>: Send + Sync
+where
+    S: State,
+{
+    /// Load the current state of an entity with its version, `None` if it
+    /// is unknown
+    fn load_versioned(&self, key: &K) -> Option<(S, u64)>;
+
+    /// Load the current state of an entity, `None` if it is unknown
+    fn load(&self, key: &K) -> Option<S> {
+        self.load_versioned(key).map(|(state, _)| state)
+    }
+
+    /// Persist the state of an entity if its stored version is still
+    /// `expected_version`, returning the new version
+    ///
+    /// `NEW_ENTITY` expects no state to be stored yet. The check and the
+    /// write must be atomic.
+    fn save(&self, key: &K, state: &S, expected_version: u64) -> Result<u64, VersionConflict>;
+
+    /// Sequences applied to an entity by `fire_event_sequenced`
+    ///
+    /// Repositories that don't track sequences return `None`, which makes
+    /// every sequenced event acceptable.
+    fn load_sequence(&self, _key: &K) -> Option<SequenceMark> {
+        None
+    }
+
+    /// `save`, also persisting the sequences applied to the entity in the
+    /// same atomic write
+    ///
+    /// The default ignores `sequence` and only saves the state.
+    fn save_sequenced(
+        &self,
+        key: &K,
+        state: &S,
+        _sequence: &SequenceMark,
+        expected_version: u64,
+    ) -> Result<u64, VersionConflict> {
+        self.save(key, state, expected_version)
+    }
+}
+
+/// `StateRepository` keeping states in a `HashMap`
+pub struct InMemoryStateRepository<K, S> {
+    entities: Mutex<HashMap<K, StoredEntity<S>>>,
+}
+
+struct StoredEntity<S> {
+    state: S,
+    version: u64,
+    sequence: Option<SequenceMark>,
+}
+
+impl<K, S> InMemoryStateRepository<K, S>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        InMemoryStateRepository {
+            entities: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K, S> Default for InMemoryStateRepository<K, S>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, S> StateRepository<K, S> for InMemoryStateRepository<K, S>
+where
+    K: Eq + Hash + Clone + Send,
+    S: State + Send,
+{
+    fn load_versioned(&self, key: &K) -> Option<(S, u64)> {
+        let entities = self.entities.lock().unwrap();
+        let entity = entities.get(key)?;
+        Some((entity.state.clone(), entity.version))
+    }
+
+    fn save(&self, key: &K, state: &S, expected_version: u64) -> Result<u64, VersionConflict> {
+        self.store(key, state, None, expected_version)
+    }
+
+    fn load_sequence(&self, key: &K) -> Option<SequenceMark> {
+        let entities = self.entities.lock().unwrap();
+        entities.get(key).and_then(|entity| entity.sequence)
+    }
+
+    fn save_sequenced(
+        &self,
+        key: &K,
+        state: &S,
+        sequence: &SequenceMark,
+        expected_version: u64,
+    ) -> Result<u64, VersionConflict> {
+        self.store(key, state, Some(*sequence), expected_version)
+    }
+}
+
+impl<K, S> InMemoryStateRepository<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: Clone,
+{
+    // Replace the state, and the sequences when `sequence` is given
+    fn store(
+        &self,
+        key: &K,
+        state: &S,
+        sequence: Option<SequenceMark>,
+        expected_version: u64,
+    ) -> Result<u64, VersionConflict> {
+        let mut entities = self.entities.lock().unwrap();
+        let stored = entities.get(key);
+        let actual = stored.map_or(NEW_ENTITY, |entity| entity.version);
+        if actual != expected_version {
+            return Err(VersionConflict {
+                expected: expected_version,
+                actual,
+            });
+        }
+        let sequence = sequence.or_else(|| stored.and_then(|entity| entity.sequence));
+        entities.insert(
+            key.clone(),
+            StoredEntity {
+                state: state.clone(),
+                version: actual + 1,
+                sequence,
+            },
+        );
+        Ok(actual + 1)
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Fire an event for an entity whose current state is loaded from `repo`
+    ///
+    /// The loaded state is authoritative. If the caller passes the state it
+    /// believes the entity is in as `expected_from` and it disagrees with the
+    /// repository, the event is rejected with `TransitionError::StaleState`.
+    /// On success the new state is saved back to the repository, unless the
+    /// entity was saved by someone else since it was loaded, which fails with
+    /// `TransitionError::VersionConflict` after the actions ran.
+    pub fn fire_event_inferred<K>(
+        &self,
+        repo: &dyn StateRepository<K, S>,
+        key: &K,
+        event: E,
+        context: C,
+        expected_from: Option<&S>,
+    ) -> Result<S, TransitionError<S, E>>
+    where
+        K: Debug,
+    {
+        let (from, version) =
+            repo.load_versioned(key)
+                .ok_or_else(|| TransitionError::EntityNotFound {
+                    key: format!("{:?}", key),
+                })?;
+
+        if let Some(expected) = expected_from {
+            if expected != &from {
+                return Err(TransitionError::StaleState {
+                    expected: expected.clone(),
+                    actual: from,
+                });
+            }
+        }
+
+        let to = self.fire_event(from, event, context)?;
+        save_fired(repo, key, &to, version)?;
+        Ok(to)
+    }
+
+    /// Fire an event carrying a sequence number for an entity in `repo`
+    ///
+    /// The repository remembers the sequences applied to each entity. An
+    /// event whose sequence is not above the highest one applied, beyond the
+    /// window set with `StateMachineBuilder::with_sequence_window`, is a
+    /// redelivery or arrived out of order; it is rejected with
+    /// `TransitionError::OutOfOrder` before any guard or action runs.
+    ///
+    /// The sequence is saved together with the new state by
+    /// `StateRepository::save_sequenced`, only when the transition succeeds.
+    /// Two deliveries of the same event racing each other both pass the
+    /// check, but only the first save succeeds; the second fails with
+    /// `TransitionError::VersionConflict`.
+    pub fn fire_event_sequenced<K>(
+        &self,
+        repo: &dyn StateRepository<K, S>,
+        key: &K,
+        event: E,
+        context: C,
+        sequence: u64,
+    ) -> Result<S, TransitionError<S, E>>
+    where
+        K: Debug,
+    {
+        let (from, version) =
+            repo.load_versioned(key)
+                .ok_or_else(|| TransitionError::EntityNotFound {
+                    key: format!("{:?}", key),
+                })?;
+        let mut mark = repo.load_sequence(key).unwrap_or_default();
+        mark.check(sequence, self.sequence_window)
+            .map_err(|last| TransitionError::OutOfOrder {
+                last,
+                attempted: sequence,
+            })?;
+
+        let to = self.fire_event(from, event, context)?;
+        mark.record(sequence);
+        repo.save_sequenced(key, &to, &mark, version)
+            .map_err(|conflict| version_conflict(key, conflict))?;
+        Ok(to)
+    }
+}
+
+// Save the state a fire moved to, failing the fire on a version conflict
+pub(crate) fn save_fired<K, S, E>(
+    repo: &dyn StateRepository<K, S>,
+    key: &K,
+    to: &S,
+    version: u64,
+) -> Result<u64, TransitionError<S, E>>
+where
+    K: Debug,
+    S: State,
+{
+    repo.save(key, to, version)
+        .map_err(|conflict| version_conflict(key, conflict))
+}
+
+fn version_conflict<K, S, E>(key: &K, conflict: VersionConflict) -> TransitionError<S, E>
+where
+    K: Debug,
+{
+    TransitionError::VersionConflict {
+        key: format!("{:?}", key),
+        expected: conflict.expected,
+        actual: conflict.actual,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Ticket {
+        Open,
+        Closed,
+    }
+
+    impl State for Ticket {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum TicketEvent {
+        Close,
+        Reopen,
+    }
+
+    impl Event for TicketEvent {}
+
+    #[derive(Debug, Clone)]
+    struct TicketContext;
+
+    impl Context for TicketContext {}
+
+    fn ticket_machine() -> StateMachine<Ticket, TicketEvent, TicketContext> {
+        let mut builder =
+            StateMachineBuilderFactory::create::<Ticket, TicketEvent, TicketContext>();
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Closed)
+            .on(TicketEvent::Close)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Ticket::Closed)
+            .to(Ticket::Open)
+            .on(TicketEvent::Reopen)
+            .perform(|_s, _e, _c| {});
+        builder.build()
+    }
+
+    #[test]
+    fn test_stale_hint_rejected() {
+        let machine = ticket_machine();
+        let repo = InMemoryStateRepository::new();
+        repo.save(&7, &Ticket::Closed, NEW_ENTITY).unwrap();
+
+        let result = machine.fire_event_inferred(
+            &repo,
+            &7,
+            TicketEvent::Close,
+            TicketContext,
+            Some(&Ticket::Open),
+        );
+        match result {
+            Err(TransitionError::StaleState { expected, actual }) => {
+                assert_eq!(expected, Ticket::Open);
+                assert_eq!(actual, Ticket::Closed);
+            }
+            other => panic!("expected StaleState, got {:?}", other),
+        }
+        assert_eq!(repo.load(&7), Some(Ticket::Closed));
+    }
+
+    #[test]
+    fn test_inferred_without_hint() {
+        let machine = ticket_machine();
+        let repo = InMemoryStateRepository::new();
+        repo.save(&7, &Ticket::Open, NEW_ENTITY).unwrap();
+
+        let result =
+            machine.fire_event_inferred(&repo, &7, TicketEvent::Close, TicketContext, None);
+        assert_eq!(result.unwrap(), Ticket::Closed);
+        assert_eq!(repo.load(&7), Some(Ticket::Closed));
+
+        let missing =
+            machine.fire_event_inferred(&repo, &8, TicketEvent::Close, TicketContext, None);
+        assert!(matches!(
+            missing,
+            Err(TransitionError::EntityNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_save_conflicts() {
+        let repo = InMemoryStateRepository::new();
+        assert_eq!(repo.save(&7, &Ticket::Open, NEW_ENTITY), Ok(1));
+        assert_eq!(
+            repo.save(&7, &Ticket::Open, NEW_ENTITY),
+            Err(VersionConflict {
+                expected: NEW_ENTITY,
+                actual: 1
+            })
+        );
+
+        // Another process closes the ticket between our load and our save
+        let machine = ticket_machine();
+        let (loaded, version) = repo.load_versioned(&7).unwrap();
+        machine
+            .fire_event_inferred(&repo, &7, TicketEvent::Close, TicketContext, None)
+            .unwrap();
+        let ours = machine.fire_event(loaded, TicketEvent::Close, TicketContext);
+        match save_fired::<_, _, TicketEvent>(&repo, &7, &ours.unwrap(), version) {
+            Err(TransitionError::VersionConflict {
+                expected, actual, ..
+            }) => {
+                assert_eq!(expected, 1);
+                assert_eq!(actual, 2);
+            }
+            other => panic!("expected VersionConflict, got {:?}", other),
+        }
+        assert_eq!(repo.load_versioned(&7), Some((Ticket::Closed, 2)));
+    }
+
+    #[test]
+    fn test_sequenced_events() {
+        let machine = ticket_machine();
+        let repo = InMemoryStateRepository::new();
+        repo.save(&7, &Ticket::Open, NEW_ENTITY).unwrap();
+
+        let closed = machine.fire_event_sequenced(&repo, &7, TicketEvent::Close, TicketContext, 1);
+        assert_eq!(closed.unwrap(), Ticket::Closed);
+        let reopened =
+            machine.fire_event_sequenced(&repo, &7, TicketEvent::Reopen, TicketContext, 3);
+        assert_eq!(reopened.unwrap(), Ticket::Open);
+
+        // A late delivery of sequence 2 must not be applied after 3
+        let stale = machine.fire_event_sequenced(&repo, &7, TicketEvent::Close, TicketContext, 2);
+        match stale {
+            Err(TransitionError::OutOfOrder { last, attempted }) => {
+                assert_eq!(last, 3);
+                assert_eq!(attempted, 2);
+            }
+            other => panic!("expected OutOfOrder, got {:?}", other),
+        }
+        assert_eq!(repo.load(&7), Some(Ticket::Open));
+
+        // The high-water mark lives in the repository, so a freshly built
+        // machine keeps rejecting the duplicate
+        let restarted = ticket_machine();
+        let duplicate =
+            restarted.fire_event_sequenced(&repo, &7, TicketEvent::Reopen, TicketContext, 3);
+        assert!(matches!(duplicate, Err(TransitionError::OutOfOrder { .. })));
+        assert_eq!(repo.load_sequence(&7).unwrap().last(), Some(3));
+    }
+
+    #[test]
+    fn test_sequence_window_applies_late_event_once() {
+        let mut builder =
+            StateMachineBuilderFactory::create::<Ticket, TicketEvent, TicketContext>();
+        builder
+            .internal_transition()
+            .within(Ticket::Open)
+            .on(TicketEvent::Reopen)
+            .add();
+        builder.with_sequence_window(4);
+        let machine = builder.build();
+        let repo = InMemoryStateRepository::new();
+        repo.save(&7, &Ticket::Open, NEW_ENTITY).unwrap();
+
+        for sequence in [1, 3] {
+            machine
+                .fire_event_sequenced(&repo, &7, TicketEvent::Reopen, TicketContext, sequence)
+                .unwrap();
+        }
+        // 2 arrives late but within the window
+        let late = machine.fire_event_sequenced(&repo, &7, TicketEvent::Reopen, TicketContext, 2);
+        assert_eq!(late.unwrap(), Ticket::Open);
+        for duplicate in [2, 3] {
+            let result = machine.fire_event_sequenced(
+                &repo,
+                &7,
+                TicketEvent::Reopen,
+                TicketContext,
+                duplicate,
+            );
+            assert!(matches!(
+                result,
+                Err(TransitionError::OutOfOrder { last: 3, .. })
+            ));
+        }
+        assert_eq!(repo.load_versioned(&7).unwrap().1, 4);
+    }
+
+    // Redelivers the event it is fired for while the first delivery is
+    // between its check and its save
+    struct RedeliveringRepository {
+        inner: InMemoryStateRepository<u32, Ticket>,
+        machine: StateMachine<Ticket, TicketEvent, TicketContext>,
+        redelivered: Mutex<Option<Result<Ticket, TransitionError<Ticket, TicketEvent>>>>,
+    }
+
+    impl StateRepository<u32, Ticket> for RedeliveringRepository {
+        fn load_versioned(&self, key: &u32) -> Option<(Ticket, u64)> {
+            self.inner.load_versioned(key)
+        }
+
+        fn save(&self, key: &u32, state: &Ticket, version: u64) -> Result<u64, VersionConflict> {
+            self.inner.save(key, state, version)
+        }
+
+        fn load_sequence(&self, key: &u32) -> Option<SequenceMark> {
+            self.inner.load_sequence(key)
+        }
+
+        fn save_sequenced(
+            &self,
+            key: &u32,
+            state: &Ticket,
+            sequence: &SequenceMark,
+            version: u64,
+        ) -> Result<u64, VersionConflict> {
+            let mut redelivered = self.redelivered.lock().unwrap();
+            if redelivered.is_none() {
+                *redelivered = Some(self.machine.fire_event_sequenced(
+                    &self.inner,
+                    key,
+                    TicketEvent::Close,
+                    TicketContext,
+                    1,
+                ));
+            }
+            self.inner.save_sequenced(key, state, sequence, version)
+        }
+    }
+
+    #[test]
+    fn test_racing_redelivery_fails_version_check() {
+        let repo = RedeliveringRepository {
+            inner: InMemoryStateRepository::new(),
+            machine: ticket_machine(),
+            redelivered: Mutex::new(None),
+        };
+        repo.save(&7, &Ticket::Open, NEW_ENTITY).unwrap();
+
+        let first =
+            ticket_machine().fire_event_sequenced(&repo, &7, TicketEvent::Close, TicketContext, 1);
+        // The redelivery saved first; the original delivery loses the race
+        let redelivered = repo.redelivered.lock().unwrap().take().unwrap();
+        assert_eq!(redelivered.unwrap(), Ticket::Closed);
+        assert!(matches!(
+            first,
+            Err(TransitionError::VersionConflict {
+                expected: 1,
+                actual: 2,
+                ..
+            })
+        ));
+        assert_eq!(repo.load_sequence(&7).unwrap().last(), Some(1));
+    }
+}
@@ -0,0 +1,394 @@
+//! Per-instance limits against runaway machines
+//!
+//! A misconfigured machine driven by cascading transitions can bounce
+//! between states forever. Two limits, both off by default, stop a
+//! `StateMachineInstance` that does:
+//!
+//! - a budget on the transitions one instance may take, after which
+//!   `process` fails with `TransitionError::TransitionBudgetExhausted`
+//! - a loop detector flagging a `(from, event, to)` transition taken more
+//!   than `max_repeats` times within the last `window` transitions of the
+//!   instance, either by refusing it with `TransitionError::LoopDetected`
+//!   before it runs or by calling a callback once it was taken
+//!
+//! Only successful transitions count, including each event of
+//! `StateMachineInstance::fire_atomic`. The loop detector is asked about
+//! the transition a fire selects before it runs, so `to` is the target of
+//! that transition, before any completion transition; a refused fire is
+//! recorded as failed. Fires through `StateMachine` directly are not
+//! limited, since they belong to no instance.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::{Context, Event, State, StateMachineBuilder, TransitionError};
+
+/// Called with the repeated transition and its repeat count instead of
+/// failing, see `StateMachineBuilder::on_loop_detected`
+pub type LoopCallback<S, E> = Arc<dyn Fn(&S, &E, &S, usize) + Send + Sync>;
+
+// Limits applied to every instance of a machine
+#[derive(Clone)]
+pub(crate) struct InstanceLimits<S, E> {
+    max_transitions: Option<u64>,
+    // Window and most repeats allowed within it
+    loop_detection: Option<(usize, usize)>,
+    loop_callback: Option<LoopCallback<S, E>>,
+}
+
+impl<S, E> Default for InstanceLimits<S, E> {
+    fn default() -> Self {
+        InstanceLimits {
+            max_transitions: None,
+            loop_detection: None,
+            loop_callback: None,
+        }
+    }
+}
+
+impl<S, E> InstanceLimits<S, E> {
+    /// The same limits, with the loop callback replaced by `map` of it
+    #[cfg(feature = "parallel")]
+    pub(crate) fn map_loop_callback<S2>(
+        self,
+        map: impl FnOnce(LoopCallback<S, E>) -> LoopCallback<S2, E>,
+    ) -> InstanceLimits<S2, E> {
+        InstanceLimits {
+            max_transitions: self.max_transitions,
+            loop_detection: self.loop_detection,
+            loop_callback: self.loop_callback.map(map),
+        }
+    }
+}
+
+// What one instance has done so far
+pub(crate) struct InstanceUsage<S, E> {
+    taken: u64,
+    // Last transitions, oldest first, at most the detection window
+    recent: VecDeque<(S, E, S)>,
+}
+
+impl<S, E> Default for InstanceUsage<S, E> {
+    fn default() -> Self {
+        InstanceUsage {
+            taken: 0,
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl<S, E> InstanceLimits<S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub(crate) fn check_budget(
+        &self,
+        usage: &InstanceUsage<S, E>,
+    ) -> Result<(), TransitionError<S, E>> {
+        self.check_staged_budget(usage, 0)
+    }
+
+    /// `check_budget` once `staged` more transitions have been taken
+    pub(crate) fn check_staged_budget(
+        &self,
+        usage: &InstanceUsage<S, E>,
+        staged: u64,
+    ) -> Result<(), TransitionError<S, E>> {
+        match self.max_transitions {
+            Some(limit) if usage.taken.saturating_add(staged) >= limit => {
+                Err(TransitionError::TransitionBudgetExhausted { limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn watches_loops(&self) -> bool {
+        self.loop_detection.is_some()
+    }
+
+    /// Refuse `(from, event, to)` if taking it would flag a loop, unless a
+    /// callback is set to be told instead
+    pub(crate) fn check_loop(
+        &self,
+        usage: &InstanceUsage<S, E>,
+        from: &S,
+        event: &E,
+        to: &S,
+    ) -> Result<(), TransitionError<S, E>> {
+        self.check_staged_loop(usage, &[], from, event, to)
+    }
+
+    /// `check_loop` once the `staged` transitions have been taken
+    pub(crate) fn check_staged_loop(
+        &self,
+        usage: &InstanceUsage<S, E>,
+        staged: &[(S, E, S)],
+        from: &S,
+        event: &E,
+        to: &S,
+    ) -> Result<(), TransitionError<S, E>> {
+        let (Some((window, max_repeats)), None) = (self.loop_detection, &self.loop_callback) else {
+            return Ok(());
+        };
+        // The oldest transition leaves the window when this one enters it
+        let taken = usage.recent.len() + staged.len();
+        let kept = taken.min(window - 1);
+        let repeats = 1 + usage
+            .recent
+            .iter()
+            .chain(staged)
+            .skip(taken - kept)
+            .filter(|(f, e, t)| f == from && e == event && t == to)
+            .count();
+        if repeats <= max_repeats {
+            return Ok(());
+        }
+        Err(TransitionError::LoopDetected {
+            from: from.clone(),
+            event: event.clone(),
+            to: to.clone(),
+            repeats,
+        })
+    }
+
+    /// Count a transition taken; `fired` is the `(from, event, to)` checked
+    /// with `check_loop`, known when loops are watched
+    pub(crate) fn record(&self, usage: &mut InstanceUsage<S, E>, fired: Option<(S, E, S)>) {
+        usage.taken += 1;
+        let (Some((window, max_repeats)), Some(fired)) = (self.loop_detection, fired) else {
+            return;
+        };
+        if usage.recent.len() == window {
+            usage.recent.pop_front();
+        }
+        usage.recent.push_back(fired);
+
+        let (from, event, to) = usage.recent.back().unwrap();
+        let repeats = usage
+            .recent
+            .iter()
+            .filter(|(f, e, t)| f == from && e == event && t == to)
+            .count();
+        if let (true, Some(callback)) = (repeats > max_repeats, &self.loop_callback) {
+            callback(from, event, to, repeats);
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Limit the transitions each instance may take; `None`, the default,
+    /// for no limit
+    pub fn max_transitions_per_instance(&mut self, limit: Option<u64>) -> &mut Self {
+        self.instance_limits.max_transitions = limit;
+        self
+    }
+
+    /// Flag a transition an instance takes more than `max_repeats` times
+    /// within its last `window` transitions
+    ///
+    /// The instance refuses the transition with
+    /// `TransitionError::LoopDetected`, staying where it is, unless
+    /// `on_loop_detected` sets a callback.
+    ///
+    /// # Panics
+    ///
+    /// If `window` is 0.
+    pub fn loop_detection(&mut self, window: usize, max_repeats: usize) -> &mut Self {
+        assert!(window > 0, "loop detection window must not be empty");
+        self.instance_limits.loop_detection = Some((window, max_repeats));
+        self
+    }
+
+    /// Call `callback` when `loop_detection` flags a transition, instead of
+    /// failing
+    pub fn on_loop_detected<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&S, &E, &S, usize) + Send + Sync + 'static,
+    {
+        self.instance_limits.loop_callback = Some(Arc::new(callback));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::{AtomicError, StateMachine, StateMachineBuilderFactory};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Link {
+        Up,
+        Down,
+    }
+
+    impl State for Link {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum LinkEvent {
+        Flap,
+        Ping,
+    }
+
+    impl Event for LinkEvent {}
+
+    fn link_builder() -> StateMachineBuilder<Link, LinkEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Link, LinkEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Link::Up)
+            .to(Link::Down)
+            .on(LinkEvent::Flap)
+            .add();
+        builder
+            .external_transition()
+            .from(Link::Down)
+            .to(Link::Up)
+            .on(LinkEvent::Flap)
+            .add();
+        builder
+            .internal_transition()
+            .within(Link::Up)
+            .on(LinkEvent::Ping)
+            .add();
+        builder
+    }
+
+    fn machine(
+        builder: StateMachineBuilder<Link, LinkEvent, NoContext>,
+    ) -> Arc<StateMachine<Link, LinkEvent, NoContext>> {
+        Arc::new(builder.build())
+    }
+
+    #[test]
+    fn test_budget_exhausted() {
+        let mut builder = link_builder();
+        builder.max_transitions_per_instance(Some(3));
+        let instance = machine(builder).start(Link::Up);
+
+        // Failed fires don't use up the budget
+        assert!(instance.process(LinkEvent::Ping, NoContext).is_ok());
+        instance.process(LinkEvent::Flap, NoContext).unwrap();
+        assert!(instance.process(LinkEvent::Ping, NoContext).is_err());
+        instance.process(LinkEvent::Flap, NoContext).unwrap();
+        assert!(matches!(
+            instance.process(LinkEvent::Flap, NoContext),
+            Err(TransitionError::TransitionBudgetExhausted { limit: 3 })
+        ));
+        assert!(instance.is_in(&Link::Up));
+    }
+
+    #[test]
+    fn test_ping_pong_detected() {
+        let mut builder = link_builder();
+        builder.loop_detection(6, 2);
+        let instance = machine(builder).start(Link::Up);
+
+        for _ in 0..4 {
+            instance.process(LinkEvent::Flap, NoContext).unwrap();
+        }
+        // The third Up -> Down within six transitions
+        match instance.process(LinkEvent::Flap, NoContext) {
+            Err(TransitionError::LoopDetected {
+                from,
+                event,
+                to,
+                repeats,
+            }) => {
+                assert_eq!((from, event, to), (Link::Up, LinkEvent::Flap, Link::Down));
+                assert_eq!(repeats, 3);
+            }
+            other => panic!("expected LoopDetected, got {:?}", other),
+        }
+        // Refused before it ran, so the instance stays where it was
+        assert!(instance.is_in(&Link::Up));
+        #[cfg(feature = "history")]
+        {
+            let history = instance.get_history();
+            assert_eq!(history.len(), 5);
+            assert_eq!(history[4].error_code, Some("loop_detected"));
+        }
+
+        // Other transitions push the repeats out of the window again
+        instance.process(LinkEvent::Ping, NoContext).unwrap();
+        instance.process(LinkEvent::Ping, NoContext).unwrap();
+        assert_eq!(
+            instance.process(LinkEvent::Flap, NoContext).unwrap(),
+            Link::Down
+        );
+    }
+
+    #[test]
+    fn test_atomic_batch_checked_for_loops() {
+        let mut builder = link_builder();
+        builder.loop_detection(6, 2);
+        let instance = machine(builder).start(Link::Up);
+        instance.process(LinkEvent::Flap, NoContext).unwrap();
+
+        // Down -> Up, then the second and third Up -> Down
+        let events = [
+            LinkEvent::Flap,
+            LinkEvent::Flap,
+            LinkEvent::Flap,
+            LinkEvent::Flap,
+        ];
+        match instance.fire_atomic(&events, &NoContext) {
+            Err(AtomicError::Failed {
+                index,
+                error: TransitionError::LoopDetected { repeats, .. },
+                ..
+            }) => {
+                assert_eq!(index, 3);
+                assert_eq!(repeats, 3);
+            }
+            other => panic!("expected LoopDetected, got {:?}", other),
+        }
+        assert!(instance.is_in(&Link::Down));
+
+        // A committed batch counts towards the window of later fires
+        instance.fire_atomic(&events[..2], &NoContext).unwrap();
+        instance.process(LinkEvent::Flap, NoContext).unwrap();
+        assert!(matches!(
+            instance.process(LinkEvent::Flap, NoContext),
+            Err(TransitionError::LoopDetected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_repeats_under_threshold_or_reported_continue() {
+        let mut builder = link_builder();
+        builder.loop_detection(4, 3);
+        let instance = machine(builder).start(Link::Up);
+        // Three pings, then other transitions push the older ones out
+        for event in [LinkEvent::Ping, LinkEvent::Ping, LinkEvent::Ping] {
+            instance.process(event, NoContext).unwrap();
+        }
+        instance.process(LinkEvent::Flap, NoContext).unwrap();
+        instance.process(LinkEvent::Flap, NoContext).unwrap();
+        instance.process(LinkEvent::Ping, NoContext).unwrap();
+
+        let flagged = Arc::new(Mutex::new(Vec::new()));
+        let seen = flagged.clone();
+        let mut builder = link_builder();
+        builder
+            .loop_detection(4, 3)
+            .on_loop_detected(move |_from, event, _to, repeats| {
+                seen.lock().unwrap().push((event.clone(), repeats))
+            });
+        let instance = machine(builder).start(Link::Up);
+        for _ in 0..5 {
+            instance.process(LinkEvent::Ping, NoContext).unwrap();
+        }
+        assert_eq!(
+            *flagged.lock().unwrap(),
+            vec![(LinkEvent::Ping, 4), (LinkEvent::Ping, 4)]
+        );
+    }
+}
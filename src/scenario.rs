@@ -0,0 +1,319 @@
+//! Data-driven scenarios for documentation and integration tests (requires
+//! the `test-util` feature)
+//!
+//! A `Scenario` lists steps — a starting state, events to fire and
+//! expectations — and runs them against a copy of a machine with fresh
+//! history and metrics. The first expectation that does not hold stops the
+//! run with a `ScenarioFailure` naming the step:
+//!
+//! ```text
+//! step 3 (expect_state(Shipped)): expected state Shipped, found Paid
+//! ```
+//!
+//! Events that fail do not stop the run by themselves; the state is left
+//! unchanged and `expect_error` can check the error code. Entry and exit
+//! expectations are checked against the trace of the last fired event.
+
+use std::fmt;
+
+use crate::{Context, Event, ExecutionTrace, State, StateMachine, TraceStep, TransitionError};
+
+enum Step<S, E, C> {
+    Given(S),
+    When(E, C),
+    ExpectState(S),
+    ExpectError(&'static str),
+    ExpectEntry(S),
+    ExpectExit(S),
+    #[cfg(feature = "history")]
+    ExpectHistoryLen(usize),
+    #[cfg(feature = "metrics")]
+    ExpectMetric(&'static str, u64),
+}
+
+impl<S: fmt::Debug, E: fmt::Debug, C> fmt::Display for Step<S, E, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Given(state) => write!(f, "given({:?})", state),
+            Step::When(event, _) => write!(f, "when({:?})", event),
+            Step::ExpectState(state) => write!(f, "expect_state({:?})", state),
+            Step::ExpectError(code) => write!(f, "expect_error({})", code),
+            Step::ExpectEntry(state) => write!(f, "expect_entry({:?})", state),
+            Step::ExpectExit(state) => write!(f, "expect_exit({:?})", state),
+            #[cfg(feature = "history")]
+            Step::ExpectHistoryLen(len) => write!(f, "expect_history_len({})", len),
+            #[cfg(feature = "metrics")]
+            Step::ExpectMetric(name, value) => write!(f, "expect_metric({}, {})", name, value),
+        }
+    }
+}
+
+/// The step at which a scenario diverged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioFailure {
+    /// 1-based position of the step
+    pub step: usize,
+    /// The step as written, e.g. `expect_state(Shipped)`
+    pub description: String,
+    pub message: String,
+}
+
+impl fmt::Display for ScenarioFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {} ({}): {}",
+            self.step, self.description, self.message
+        )
+    }
+}
+
+impl std::error::Error for ScenarioFailure {}
+
+/// Steps run in order against a copy of a machine
+pub struct Scenario<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    machine: StateMachine<S, E, C>,
+    steps: Vec<Step<S, E, C>>,
+}
+
+// What the last `when` step did
+struct LastFire<S, E> {
+    result: Result<S, TransitionError<S, E>>,
+    trace: ExecutionTrace,
+}
+
+impl<S, E, C> Scenario<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn new(machine: &StateMachine<S, E, C>) -> Self {
+        Scenario {
+            machine: machine.fork(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Set the current state
+    pub fn given(mut self, state: S) -> Self {
+        self.steps.push(Step::Given(state));
+        self
+    }
+
+    /// Fire `event` from the current state
+    pub fn when(mut self, event: E, context: C) -> Self {
+        self.steps.push(Step::When(event, context));
+        self
+    }
+
+    pub fn expect_state(mut self, state: S) -> Self {
+        self.steps.push(Step::ExpectState(state));
+        self
+    }
+
+    /// Expect the last event to have failed with `code`, see
+    /// `TransitionError::code`
+    pub fn expect_error(mut self, code: &'static str) -> Self {
+        self.steps.push(Step::ExpectError(code));
+        self
+    }
+
+    /// Expect the last event to have run the entry action of `state`
+    pub fn expect_entry(mut self, state: S) -> Self {
+        self.steps.push(Step::ExpectEntry(state));
+        self
+    }
+
+    /// Expect the last event to have run the exit action of `state`
+    pub fn expect_exit(mut self, state: S) -> Self {
+        self.steps.push(Step::ExpectExit(state));
+        self
+    }
+
+    #[cfg(feature = "history")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "history")))]
+    /// Expect `len` history records, counted from the start of the scenario
+    pub fn expect_history_len(mut self, len: usize) -> Self {
+        self.steps.push(Step::ExpectHistoryLen(len));
+        self
+    }
+
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+    /// Expect a counter of `StateMachineMetrics` to equal `value`
+    ///
+    /// `name` is one of `total_transitions`, `successful_transitions` or
+    /// `failed_transitions`.
+    pub fn expect_metric(mut self, name: &'static str, value: u64) -> Self {
+        self.steps.push(Step::ExpectMetric(name, value));
+        self
+    }
+
+    /// Run the steps, stopping at the first one that does not hold
+    pub fn run(self) -> Result<(), ScenarioFailure> {
+        let mut state: Option<S> = None;
+        let mut last: Option<LastFire<S, E>> = None;
+
+        for (index, step) in self.steps.iter().enumerate() {
+            let fail = |message: String| ScenarioFailure {
+                step: index + 1,
+                description: step.to_string(),
+                message,
+            };
+            match step {
+                Step::Given(given) => state = Some(given.clone()),
+                Step::When(event, context) => {
+                    let from = state
+                        .clone()
+                        .ok_or_else(|| fail("no state given before the event".to_string()))?;
+                    let mut context = context.clone();
+                    let mut trace = ExecutionTrace::default();
+                    let result = self.machine.fire_traced(
+                        from,
+                        event.clone(),
+                        &mut context,
+                        Some(&mut trace),
+                        None,
+                        None,
+                    );
+                    if let Ok(to) = &result {
+                        state = Some(to.clone());
+                    }
+                    last = Some(LastFire { result, trace });
+                }
+                Step::ExpectState(expected) => match &state {
+                    Some(current) if current == expected => {}
+                    Some(current) => {
+                        return Err(fail(format!(
+                            "expected state {:?}, found {:?}",
+                            expected, current
+                        )))
+                    }
+                    None => return Err(fail("no state given".to_string())),
+                },
+                Step::ExpectError(code) => match last.as_ref().map(|fire| &fire.result) {
+                    Some(Err(error)) if error.code() == *code => {}
+                    Some(Err(error)) => {
+                        return Err(fail(format!(
+                            "expected error {}, got {}: {}",
+                            code,
+                            error.code(),
+                            error
+                        )))
+                    }
+                    Some(Ok(to)) => {
+                        return Err(fail(format!(
+                            "expected error {}, but the event moved to {:?}",
+                            code, to
+                        )))
+                    }
+                    None => return Err(fail("no event fired yet".to_string())),
+                },
+                Step::ExpectEntry(expected) => {
+                    let ran = Self::last_trace(&last, &fail)?.steps.iter().any(|step| {
+                        matches!(step, TraceStep::EntryAction { state } if **state == *format!("{:?}", expected))
+                    });
+                    if !ran {
+                        return Err(fail(format!("entry action of {:?} did not run", expected)));
+                    }
+                }
+                Step::ExpectExit(expected) => {
+                    let ran = Self::last_trace(&last, &fail)?.steps.iter().any(|step| {
+                        matches!(step, TraceStep::ExitAction { state } if **state == *format!("{:?}", expected))
+                    });
+                    if !ran {
+                        return Err(fail(format!("exit action of {:?} did not run", expected)));
+                    }
+                }
+                #[cfg(feature = "history")]
+                Step::ExpectHistoryLen(expected) => {
+                    let len = self.machine.recording.with_history(|records| records.len());
+                    if len != *expected {
+                        return Err(fail(format!(
+                            "expected {} history records, found {}",
+                            expected, len
+                        )));
+                    }
+                }
+                #[cfg(feature = "metrics")]
+                Step::ExpectMetric(name, expected) => {
+                    let metrics = self.machine.get_metrics();
+                    let value = match *name {
+                        "total_transitions" => metrics.total_transitions,
+                        "successful_transitions" => metrics.successful_transitions,
+                        "failed_transitions" => metrics.failed_transitions,
+                        _ => return Err(fail(format!("unknown metric {}", name))),
+                    };
+                    if value != *expected {
+                        return Err(fail(format!(
+                            "expected {} = {}, found {}",
+                            name, expected, value
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn last_trace<'a>(
+        last: &'a Option<LastFire<S, E>>,
+        fail: &impl Fn(String) -> ScenarioFailure,
+    ) -> Result<&'a ExecutionTrace, ScenarioFailure> {
+        last.as_ref()
+            .map(|fire| &fire.trace)
+            .ok_or_else(|| fail("no event fired yet".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_machine, NoContext, Order, OrderEvent};
+
+    #[test]
+    fn test_failure_names_diverging_step() {
+        let failure = Scenario::new(&order_machine())
+            .given(Order::Placed)
+            .when(OrderEvent::Ship, NoContext)
+            .expect_state(Order::Shipped)
+            .run()
+            .unwrap_err();
+        assert_eq!(failure.step, 3);
+        assert_eq!(
+            failure.to_string(),
+            "step 3 (expect_state(Shipped)): expected state Shipped, found Placed"
+        );
+
+        let failure = Scenario::new(&order_machine())
+            .given(Order::Placed)
+            .when(OrderEvent::Pay, NoContext)
+            .expect_error("no_valid_transition")
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            failure.message,
+            "expected error no_valid_transition, but the event moved to Paid"
+        );
+    }
+
+    #[test]
+    fn test_scenario_runs_on_fresh_copy() {
+        let machine = order_machine();
+        Scenario::new(&machine)
+            .given(Order::Placed)
+            .when(OrderEvent::Pay, NoContext)
+            .when(OrderEvent::Ship, NoContext)
+            .expect_state(Order::Shipped)
+            .run()
+            .unwrap();
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+//! Data handed from a guard to the action of the same transition
+//!
+//! A guard registered with `when_providing` returns `Some(data)` to pass,
+//! and the action registered with `perform_with` receives `&data`, so work
+//! done to decide whether the transition applies isn't repeated to carry it
+//! out. The data lives for one fire and is only seen by the transition whose
+//! guard produced it. `when_providing` returns a builder that only offers
+//! `perform_with`, so the guard and the action always agree on the type.
+//!
+//! A copy made with `StateMachine::with_guards_replaced` still runs the
+//! providing guard for its data, but the replacement decides whether the
+//! transition is taken. When it is taken without data, the action fails
+//! with `TransitionError::ActionFailed` and the transition is not taken.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::info::{InfoAction, InfoCondition};
+use crate::{
+    Context, Event, ExternalTransitionBuilder, ExternalTransitionsBuilder,
+    InternalTransitionBuilder, State, StateMachineBuilder,
+};
+
+// Keys telling the scratchpads of different transitions apart
+static NEXT_KEY: AtomicUsize = AtomicUsize::new(0);
+
+/// Transition builder whose guard provides a `D` to its action
+pub struct ProvidingTransitionBuilder<B, D> {
+    builder: B,
+    key: usize,
+    _data: PhantomData<fn() -> D>,
+}
+
+fn providing_condition<S, E, C, D, F>(key: usize, condition: F) -> InfoCondition<S, E, C>
+where
+    D: Send + Sync + 'static,
+    F: Fn(&S, &E, &C) -> Option<D> + Send + Sync + 'static,
+{
+    Arc::new(
+        move |info, c, values| match condition(info.from, info.event, c) {
+            Some(data) => {
+                values.stash(key, data);
+                true
+            }
+            None => false,
+        },
+    )
+}
+
+fn receiving_action<S, E, C, D, F>(key: usize, action: F) -> InfoAction<S, E, C>
+where
+    D: 'static,
+    F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, values| match values.stashed::<D>(key) {
+        Some(data) => {
+            action(info.from, info.event, c, data);
+            Ok(())
+        }
+        None => Err("transition taken without the data its guard provides".into()),
+    })
+}
+
+impl<'a, S, E, C> ExternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard passing when it returns `Some`, handing the data to the action
+    /// registered with `perform_with`
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info` or `when_derived`.
+    pub fn when_providing<D, F>(mut self, condition: F) -> ProvidingTransitionBuilder<Self, D>
+    where
+        D: Send + Sync + 'static,
+        F: Fn(&S, &E, &C) -> Option<D> + Send + Sync + 'static,
+    {
+        let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        self.guard = self.guard.with_info(providing_condition(key, condition));
+        self.derived.guard = None;
+        ProvidingTransitionBuilder {
+            builder: self,
+            key,
+            _data: PhantomData,
+        }
+    }
+}
+
+impl<'a, S, E, C> InternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard passing when it returns `Some`, handing the data to the action
+    /// registered with `perform_with`
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info` or `when_derived`.
+    pub fn when_providing<D, F>(mut self, condition: F) -> ProvidingTransitionBuilder<Self, D>
+    where
+        D: Send + Sync + 'static,
+        F: Fn(&S, &E, &C) -> Option<D> + Send + Sync + 'static,
+    {
+        let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        self.guard = self.guard.with_info(providing_condition(key, condition));
+        self.derived.guard = None;
+        ProvidingTransitionBuilder {
+            builder: self,
+            key,
+            _data: PhantomData,
+        }
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionsBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard passing when it returns `Some`, handing the data to the action
+    /// registered with `perform_with`
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info` or `when_derived`.
+    pub fn when_providing<D, F>(mut self, condition: F) -> ProvidingTransitionBuilder<Self, D>
+    where
+        D: Send + Sync + 'static,
+        F: Fn(&S, &E, &C) -> Option<D> + Send + Sync + 'static,
+    {
+        let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        self.guard = self.guard.with_info(providing_condition(key, condition));
+        self.derived.guard = None;
+        ProvidingTransitionBuilder {
+            builder: self,
+            key,
+            _data: PhantomData,
+        }
+    }
+}
+
+impl<'a, S, E, C, D> ProvidingTransitionBuilder<ExternalTransitionBuilder<'a, S, E, C>, D>
+where
+    S: State,
+    E: Event,
+    C: Context,
+    D: 'static,
+{
+    /// Like `perform`, with the data the guard provided
+    pub fn perform_with<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+    {
+        self.builder.action = crate::callbacks::Action::Info(receiving_action(self.key, action));
+        self.builder.derived.action = None;
+        self.builder.add()
+    }
+}
+
+impl<'a, S, E, C, D> ProvidingTransitionBuilder<InternalTransitionBuilder<'a, S, E, C>, D>
+where
+    S: State,
+    E: Event,
+    C: Context,
+    D: 'static,
+{
+    /// Like `perform`, with the data the guard provided
+    pub fn perform_with<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+    {
+        self.builder.action = crate::callbacks::Action::Info(receiving_action(self.key, action));
+        self.builder.derived.action = None;
+        self.builder.add()
+    }
+}
+
+impl<'a, S, E, C, D> ProvidingTransitionBuilder<ExternalTransitionsBuilder<'a, S, E, C>, D>
+where
+    S: State,
+    E: Event,
+    C: Context,
+    D: 'static,
+{
+    /// Like `perform`, with the data the guard provided
+    pub fn perform_with<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C, &D) + Send + Sync + 'static,
+    {
+        self.builder.action = crate::callbacks::Action::Info(receiving_action(self.key, action));
+        self.builder.derived.action = None;
+        self.builder.add()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Context, Event, State, StateMachine, StateMachineBuilderFactory};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Claim {
+        Filed,
+        Paid,
+        Denied,
+    }
+
+    impl State for Claim {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum ClaimEvent {
+        Decide,
+        Appeal,
+    }
+
+    impl Event for ClaimEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Policy {
+        covered: bool,
+    }
+
+    impl Context for Policy {}
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Eligibility {
+        reason: &'static str,
+    }
+
+    struct Recorder {
+        checks: AtomicUsize,
+        seen: Mutex<Vec<String>>,
+    }
+
+    fn claim_machine(recorder: Arc<Recorder>) -> StateMachine<Claim, ClaimEvent, Policy> {
+        let mut builder = StateMachineBuilderFactory::create::<Claim, ClaimEvent, Policy>();
+        let (check, pay) = (recorder.clone(), recorder.clone());
+        builder
+            .external_transition()
+            .from(Claim::Filed)
+            .to(Claim::Paid)
+            .on(ClaimEvent::Decide)
+            .when_providing(move |_s, _e, c: &Policy| {
+                check.checks.fetch_add(1, Ordering::SeqCst);
+                c.covered.then_some(Eligibility { reason: "covered" })
+            })
+            .perform_with(move |_s, _e, _c, eligibility: &Eligibility| {
+                pay.seen
+                    .lock()
+                    .unwrap()
+                    .push(eligibility.reason.to_string());
+            });
+        let deny = recorder.clone();
+        builder
+            .external_transition()
+            .from(Claim::Filed)
+            .to(Claim::Denied)
+            .on(ClaimEvent::Decide)
+            .perform(move |_s, _e, _c| deny.seen.lock().unwrap().push("denied".to_string()));
+        builder
+            .external_transitions()
+            .from_among(vec![Claim::Denied])
+            .to(Claim::Paid)
+            .on(ClaimEvent::Appeal)
+            .when_providing(|_s, _e, _c| Some(42u32))
+            .perform_with(move |_s, _e, _c, case: &u32| {
+                recorder
+                    .seen
+                    .lock()
+                    .unwrap()
+                    .push(format!("appeal {}", case));
+            });
+        builder.build()
+    }
+
+    #[test]
+    fn test_guard_data_reaches_action_once() {
+        let recorder = Arc::new(Recorder {
+            checks: AtomicUsize::new(0),
+            seen: Mutex::new(Vec::new()),
+        });
+        let machine = claim_machine(recorder.clone());
+
+        let paid = machine.fire_event(Claim::Filed, ClaimEvent::Decide, Policy { covered: true });
+        assert_eq!(paid.unwrap(), Claim::Paid);
+        assert_eq!(recorder.checks.load(Ordering::SeqCst), 1);
+
+        // The guard declines, so the next candidate runs without any data
+        let denied =
+            machine.fire_event(Claim::Filed, ClaimEvent::Decide, Policy { covered: false });
+        assert_eq!(denied.unwrap(), Claim::Denied);
+        assert_eq!(recorder.checks.load(Ordering::SeqCst), 2);
+
+        let appealed =
+            machine.fire_event(Claim::Denied, ClaimEvent::Appeal, Policy { covered: false });
+        assert_eq!(appealed.unwrap(), Claim::Paid);
+        assert_eq!(
+            *recorder.seen.lock().unwrap(),
+            vec!["covered", "denied", "appeal 42"]
+        );
+    }
+}
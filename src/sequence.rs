@@ -0,0 +1,159 @@
+//! Sequence numbers guarding against redelivered and reordered events
+//!
+//! Brokers may deliver an event twice or after a newer one. Events fired with
+//! `StateMachineInstance::fire_event_sequenced` or
+//! `StateMachine::fire_event_sequenced` carry a sequence number, and a
+//! `SequenceMark` remembers the highest one applied. An event at or below
+//! that mark is rejected with `TransitionError::OutOfOrder` before any guard
+//! or action runs.
+//!
+//! `StateMachineBuilder::with_sequence_window` tolerates events arriving
+//! slightly late: a sequence up to `window` below the mark is still applied
+//! once, while a second delivery of it, like any exact duplicate, is
+//! rejected. The mark is part of `InstanceSnapshot` and is stored by
+//! `StateRepository::save_sequenced` together with the state.
+
+use crate::{Context, Event, State, StateMachineBuilder};
+
+/// Widest tolerance window, the sequences a `SequenceMark` tracks below its
+/// highest one
+pub const MAX_SEQUENCE_WINDOW: u32 = 64;
+
+/// The sequences applied to an entity, see the module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequenceMark {
+    last: Option<u64>,
+    // Bit `n` is set once `last - 1 - n` was applied
+    applied_below: u64,
+}
+
+impl SequenceMark {
+    /// Highest sequence applied, `None` before the first
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Whether `sequence` may be applied, tolerating sequences up to
+    /// `window` below the highest one that were not applied yet
+    ///
+    /// Fails with the highest sequence applied.
+    pub fn check(&self, sequence: u64, window: u32) -> Result<(), u64> {
+        let Some(last) = self.last else {
+            return Ok(());
+        };
+        if sequence > last {
+            return Ok(());
+        }
+        let behind = last - sequence;
+        let window = u64::from(window.min(MAX_SEQUENCE_WINDOW));
+        if behind > 0 && behind <= window && self.applied_below & (1 << (behind - 1)) == 0 {
+            Ok(())
+        } else {
+            Err(last)
+        }
+    }
+
+    /// Remember that `sequence` was applied
+    pub fn record(&mut self, sequence: u64) {
+        match self.last {
+            None => self.last = Some(sequence),
+            Some(last) if sequence > last => {
+                let gap = sequence - last;
+                self.applied_below = if gap > u64::from(MAX_SEQUENCE_WINDOW) {
+                    0
+                } else {
+                    // `last` itself is now `gap - 1` below the mark
+                    let shift = gap as u32;
+                    self.applied_below.checked_shl(shift).unwrap_or(0) | 1 << (shift - 1)
+                };
+                self.last = Some(sequence);
+            }
+            Some(last) => {
+                let behind = last - sequence;
+                if (1..=u64::from(MAX_SEQUENCE_WINDOW)).contains(&behind) {
+                    self.applied_below |= 1 << (behind - 1);
+                }
+            }
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Still apply sequenced events up to `window` below the highest
+    /// sequence applied, once each; capped at `MAX_SEQUENCE_WINDOW`
+    ///
+    /// The default of 0 only accepts sequences above the highest.
+    pub fn with_sequence_window(&mut self, window: u32) -> &mut Self {
+        self.sequence_window = window.min(MAX_SEQUENCE_WINDOW);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_mark_rejects_everything_not_newer() {
+        let mut mark = SequenceMark::default();
+        assert_eq!(mark.check(0, 0), Ok(()));
+        mark.record(5);
+        assert_eq!(mark.check(6, 0), Ok(()));
+        assert_eq!(mark.check(5, 0), Err(5));
+        assert_eq!(mark.check(4, 0), Err(5));
+    }
+
+    #[test]
+    fn test_window_applies_late_sequences_once() {
+        let mut mark = SequenceMark::default();
+        for sequence in [1, 2, 5] {
+            mark.record(sequence);
+        }
+        // 3 and 4 are late but within the window
+        assert_eq!(mark.check(4, 3), Ok(()));
+        mark.record(4);
+        assert_eq!(mark.check(4, 3), Err(5));
+        assert_eq!(mark.check(3, 3), Ok(()));
+        assert_eq!(mark.check(2, 3), Err(5));
+        // 1 is beyond the window
+        assert_eq!(mark.check(1, 3), Err(5));
+
+        // Moving far ahead forgets what was applied below
+        mark.record(200);
+        assert_eq!(mark.last(), Some(200));
+        assert_eq!(mark.check(199, 64), Ok(()));
+        assert_eq!(mark.check(136, 64), Ok(()));
+        assert_eq!(mark.check(135, 64), Err(200));
+    }
+
+    #[test]
+    fn test_large_gaps_forget_everything_below() {
+        for gap in [65, 1 << 32, (1 << 32) + 1] {
+            let mut mark = SequenceMark::default();
+            for sequence in 1..=10 {
+                mark.record(sequence);
+            }
+            let last = 10 + gap;
+            mark.record(last);
+            assert_eq!(mark.last(), Some(last));
+            // Nothing within the window was applied, not even the old mark
+            for behind in 1..=u64::from(MAX_SEQUENCE_WINDOW) {
+                assert_eq!(mark.check(last - behind, MAX_SEQUENCE_WINDOW), Ok(()));
+            }
+            assert_eq!(mark.check(last, MAX_SEQUENCE_WINDOW), Err(last));
+        }
+
+        // A gap of exactly the window keeps only the old mark
+        let mut mark = SequenceMark::default();
+        mark.record(1);
+        mark.record(65);
+        assert_eq!(mark.check(1, 64), Err(65));
+        assert_eq!(mark.check(2, 64), Ok(()));
+    }
+}
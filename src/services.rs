@@ -0,0 +1,323 @@
+//! Services handed to guards and actions
+//!
+//! Handles to collaborators such as clients or repositories are provided
+//! once on the builder with `StateMachineBuilder::provide`, keyed by their
+//! type, instead of being captured by every closure. Guards and actions
+//! registered with `when_injected` / `perform_injected` receive the
+//! `Services` of the machine and look them up with `Services::get`, so a
+//! test builds the same definition and provides doubles instead.
+//!
+//! A definition declares what it can't run without with `requires_service`;
+//! `try_build` fails, and `build` panics, when such a service was not
+//! provided.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::info::{InfoAction, InfoCondition};
+use crate::{
+    BuildError, Context, Event, ExternalTransitionBuilder, ExternalTransitionsBuilder,
+    InternalTransitionBuilder, State, StateMachine, StateMachineBuilder,
+};
+
+/// Services provided to a machine, one per type
+#[derive(Debug, Clone, Default)]
+pub struct Services {
+    services: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Services {
+    /// The service of type `T`, if one was provided
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.services.get(&TypeId::of::<T>())?.downcast_ref::<T>()
+    }
+}
+
+fn injected_condition<S, E, C, F>(condition: F) -> InfoCondition<S, E, C>
+where
+    F: Fn(&S, &E, &C, &Services) -> bool + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, values| condition(info.from, info.event, c, values.services()))
+}
+
+fn injected_action<S, E, C, F>(action: F) -> InfoAction<S, E, C>
+where
+    F: Fn(&S, &E, &C, &Services) + Send + Sync + 'static,
+{
+    Arc::new(move |info, c, values| {
+        action(info.from, info.event, c, values.services());
+        Ok(())
+    })
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Provide `service` to injected guards and actions
+    ///
+    /// Providing a second service of the same type replaces the first.
+    pub fn provide<T: Send + Sync + 'static>(&mut self, service: T) -> &mut Self {
+        self.services
+            .services
+            .insert(TypeId::of::<T>(), Arc::new(service));
+        self
+    }
+
+    /// Declare that the machine needs a service of type `T` provided
+    pub fn requires_service<T: Send + Sync + 'static>(&mut self) -> &mut Self {
+        self.required_services
+            .push((TypeId::of::<T>(), std::any::type_name::<T>()));
+        self
+    }
+
+    // Type names of required services that were not provided
+    pub(crate) fn missing_services(&self) -> Vec<BuildError> {
+        let mut missing: Vec<&'static str> = self
+            .required_services
+            .iter()
+            .filter(|(type_id, _)| !self.services.services.contains_key(type_id))
+            .map(|(_, name)| *name)
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+            .into_iter()
+            .map(|service| BuildError::MissingService { service })
+            .collect()
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the services provided to the machine
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info` or `when_derived`.
+    pub fn when_injected<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &C, &Services) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(injected_condition(condition));
+        self.derived.guard = None;
+        self
+    }
+
+    /// Like `perform`, with the services provided to the machine
+    pub fn perform_injected<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C, &Services) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(injected_action(action));
+        self.derived.action = None;
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> InternalTransitionBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the services provided to the machine
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info` or `when_derived`.
+    pub fn when_injected<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &C, &Services) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(injected_condition(condition));
+        self.derived.guard = None;
+        self
+    }
+
+    /// Like `perform`, with the services provided to the machine
+    pub fn perform_injected<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C, &Services) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(injected_action(action));
+        self.derived.action = None;
+        self.add()
+    }
+}
+
+impl<'a, S, E, C> ExternalTransitionsBuilder<'a, S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Guard receiving the services provided to the machine
+    ///
+    /// If `when` is also set, both guards must pass. Replaces a guard set
+    /// with `when_with_info` or `when_derived`.
+    pub fn when_injected<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &C, &Services) -> bool + Send + Sync + 'static,
+    {
+        self.guard = self.guard.with_info(injected_condition(condition));
+        self.derived.guard = None;
+        self
+    }
+
+    /// Like `perform`, with the services provided to the machine
+    pub fn perform_injected<F>(mut self, action: F) -> &'a mut StateMachineBuilder<S, E, C>
+    where
+        F: Fn(&S, &E, &C, &Services) + Send + Sync + 'static,
+    {
+        self.action = crate::callbacks::Action::Info(injected_action(action));
+        self.derived.action = None;
+        self.add()
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Services provided to the machine
+    pub fn services(&self) -> &Services {
+        &self.services
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Signup {
+        Pending,
+        Confirmed,
+    }
+
+    impl State for Signup {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum SignupEvent {
+        Confirm,
+    }
+
+    impl Event for SignupEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Account {
+        email: String,
+    }
+
+    impl Context for Account {}
+
+    // Records the mails it is asked to send instead of sending them
+    #[derive(Default)]
+    struct EmailClient {
+        sender: &'static str,
+        sent: Mutex<Vec<String>>,
+    }
+
+    struct Blocklist(Vec<&'static str>);
+
+    fn signup_builder() -> StateMachineBuilder<Signup, SignupEvent, Account> {
+        let mut builder = StateMachineBuilderFactory::create::<Signup, SignupEvent, Account>();
+        builder
+            .terminal_states(vec![Signup::Confirmed])
+            .requires_service::<EmailClient>()
+            .external_transition()
+            .from(Signup::Pending)
+            .to(Signup::Confirmed)
+            .on(SignupEvent::Confirm)
+            .when_injected(|_s, _e, c, services| {
+                services
+                    .get::<Blocklist>()
+                    .is_none_or(|blocklist| !blocklist.0.contains(&c.email.as_str()))
+            })
+            .perform_injected(|_s, _e, c, services| {
+                let email = services.get::<EmailClient>().unwrap();
+                email
+                    .sent
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} -> {}", email.sender, c.email));
+            });
+        builder
+    }
+
+    fn confirm(machine: &StateMachine<Signup, SignupEvent, Account>, email: &str) -> bool {
+        let account = Account {
+            email: email.to_string(),
+        };
+        machine
+            .fire_event(Signup::Pending, SignupEvent::Confirm, account)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_services_resolved_in_callbacks() {
+        let mut builder = signup_builder();
+        builder
+            .provide(EmailClient {
+                sender: "noreply",
+                ..Default::default()
+            })
+            .provide(Blocklist(vec!["spam@example.com"]));
+        let machine = builder.try_build().unwrap();
+
+        assert!(confirm(&machine, "ada@example.com"));
+        assert!(!confirm(&machine, "spam@example.com"));
+        let email = machine.services().get::<EmailClient>().unwrap();
+        assert_eq!(
+            *email.sent.lock().unwrap(),
+            vec!["noreply -> ada@example.com"]
+        );
+    }
+
+    #[test]
+    fn test_missing_required_service_fails_build() {
+        let mut builder = signup_builder();
+        builder.provide(Blocklist(Vec::new()));
+        match builder.try_build() {
+            Err(errors) => assert_eq!(
+                errors,
+                vec![BuildError::MissingService {
+                    service: std::any::type_name::<EmailClient>(),
+                }]
+            ),
+            Ok(_) => panic!("expected MissingService"),
+        }
+    }
+
+    #[test]
+    fn test_double_replaces_provided_service() {
+        let mut builder = signup_builder();
+        builder
+            .provide(EmailClient {
+                sender: "noreply",
+                ..Default::default()
+            })
+            .provide(EmailClient {
+                sender: "test-double",
+                ..Default::default()
+            });
+        let machine = builder.build();
+
+        assert!(confirm(&machine, "ada@example.com"));
+        let email = machine.services().get::<EmailClient>().unwrap();
+        assert_eq!(
+            *email.sent.lock().unwrap(),
+            vec!["test-double -> ada@example.com"]
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! One context shared by several machines
+//!
+//! Machines operating on the same entity can be driven with clones of one
+//! `SharedContext` instead of each holding its own copy. Cloning the wrapper
+//! only clones an `Arc`, so firing never copies the data. Updates made
+//! through `update` are seen by every holder, bump the version and notify
+//! the receivers returned by `subscribe`. Guards and actions registered with
+//! `when_shared` and `perform_shared` read the data under the read lock.
+//!
+//! # Deadlocks
+//!
+//! The data sits behind an `RwLock`, so the usual rules apply:
+//!
+//! - Don't call `update` on a context while reading it, e.g. from inside a
+//!   `when_shared` guard or `perform_shared` action on that same context.
+//!   Plain `perform` actions hold no lock and may update it.
+//! - When locking several contexts at once, lock them in the order they
+//!   were created.
+//!
+//! Debug builds assert both rules: locking a context while the same thread
+//! holds it or one created later panics instead of risking a deadlock.
+
+use std::cell::RefCell;
+use std::fmt::{self, Debug};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+use crate::{
+    Context, Event, ExternalTransitionBuilder, ExternalTransitionsBuilder,
+    InternalTransitionBuilder, State, StateMachineBuilder,
+};
+
+// Creation order of contexts, which is also their lock order
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Contexts locked by this thread, in locking order
+    static HELD: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Shared<T> {
+    id: u64,
+    data: RwLock<T>,
+    version: AtomicU64,
+    subscribers: Mutex<Vec<Sender<u64>>>,
+}
+
+/// Context data shared between machines, see the module documentation
+pub struct SharedContext<T> {
+    inner: Arc<Shared<T>>,
+}
+
+impl<T> SharedContext<T> {
+    pub fn new(data: T) -> Self {
+        SharedContext {
+            inner: Arc::new(Shared {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                data: RwLock::new(data),
+                version: AtomicU64::new(0),
+                subscribers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Read the data until the returned guard is dropped
+    pub fn read(&self) -> SharedRef<'_, T> {
+        let held = Held::acquire(self.inner.id);
+        SharedRef {
+            guard: self.inner.data.read().unwrap(),
+            _held: held,
+        }
+    }
+
+    /// Change the data, then notify subscribers of the new version
+    pub fn update<R>(&self, change: impl FnOnce(&mut T) -> R) -> R {
+        let (result, version) = {
+            let _held = Held::acquire(self.inner.id);
+            let mut data = self.inner.data.write().unwrap();
+            let result = change(&mut data);
+            (
+                result,
+                self.inner.version.fetch_add(1, Ordering::AcqRel) + 1,
+            )
+        };
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(version).is_ok());
+        result
+    }
+
+    /// Number of updates made so far
+    pub fn version(&self) -> u64 {
+        self.inner.version.load(Ordering::Acquire)
+    }
+
+    /// Receive the version reached by every later update
+    pub fn subscribe(&self) -> Receiver<u64> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+impl<T> Clone for SharedContext<T> {
+    fn clone(&self) -> Self {
+        SharedContext {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+// Doesn't lock, so it is safe to log a context from inside its own guard
+impl<T> Debug for SharedContext<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedContext")
+            .field("version", &self.version())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Context for SharedContext<T> where T: Send + Sync {}
+
+/// Read access to the data of a `SharedContext`
+pub struct SharedRef<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+    _held: Held,
+}
+
+impl<T> Deref for SharedRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+// Marks a context as locked by this thread while alive
+struct Held(u64);
+
+impl Held {
+    fn acquire(id: u64) -> Self {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            debug_assert!(
+                held.iter().all(|&other| other < id),
+                "SharedContext #{} locked while holding {:?}: lock contexts once each, \
+                 in creation order",
+                id,
+                held
+            );
+            held.push(id);
+        });
+        Held(id)
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(position) = held.iter().rposition(|&id| id == self.0) {
+                held.remove(position);
+            }
+        });
+    }
+}
+
+impl<'a, S, E, T> ExternalTransitionBuilder<'a, S, E, SharedContext<T>>
+where
+    S: State,
+    E: Event,
+    T: Send + Sync + 'static,
+{
+    /// Guard reading the shared data in place
+    pub fn when_shared<F>(self, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &T) -> bool + Send + Sync + 'static,
+    {
+        self.when(move |s, e, c| condition(s, e, &c.read()))
+    }
+
+    /// Action reading the shared data in place
+    pub fn perform_shared<F>(self, action: F) -> &'a mut StateMachineBuilder<S, E, SharedContext<T>>
+    where
+        F: Fn(&S, &E, &T) + Send + Sync + 'static,
+    {
+        self.perform(move |s, e, c| action(s, e, &c.read()))
+    }
+}
+
+impl<'a, S, E, T> InternalTransitionBuilder<'a, S, E, SharedContext<T>>
+where
+    S: State,
+    E: Event,
+    T: Send + Sync + 'static,
+{
+    /// Guard reading the shared data in place
+    pub fn when_shared<F>(self, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &T) -> bool + Send + Sync + 'static,
+    {
+        self.when(move |s, e, c| condition(s, e, &c.read()))
+    }
+
+    /// Action reading the shared data in place
+    pub fn perform_shared<F>(self, action: F) -> &'a mut StateMachineBuilder<S, E, SharedContext<T>>
+    where
+        F: Fn(&S, &E, &T) + Send + Sync + 'static,
+    {
+        self.perform(move |s, e, c| action(s, e, &c.read()))
+    }
+}
+
+impl<'a, S, E, T> ExternalTransitionsBuilder<'a, S, E, SharedContext<T>>
+where
+    S: State,
+    E: Event,
+    T: Send + Sync + 'static,
+{
+    /// Guard reading the shared data in place
+    pub fn when_shared<F>(self, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &T) -> bool + Send + Sync + 'static,
+    {
+        self.when(move |s, e, c| condition(s, e, &c.read()))
+    }
+
+    /// Action reading the shared data in place
+    pub fn perform_shared<F>(self, action: F) -> &'a mut StateMachineBuilder<S, E, SharedContext<T>>
+    where
+        F: Fn(&S, &E, &T) + Send + Sync + 'static,
+    {
+        self.perform(move |s, e, c| action(s, e, &c.read()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+    use crate::{StateMachine, StateMachineBuilderFactory};
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Shipment {
+        Waiting,
+        Shipped,
+    }
+
+    impl State for Shipment {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum ShipmentEvent {
+        Ship,
+    }
+
+    impl Event for ShipmentEvent {}
+
+    static FULFILMENT_CLONES: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, Default)]
+    struct Fulfilment {
+        paid: bool,
+        shipped: bool,
+    }
+
+    impl Clone for Fulfilment {
+        fn clone(&self) -> Self {
+            FULFILMENT_CLONES.fetch_add(1, Ordering::SeqCst);
+            Fulfilment {
+                paid: self.paid,
+                shipped: self.shipped,
+            }
+        }
+    }
+
+    type Ctx = SharedContext<Fulfilment>;
+
+    fn delivery_machine() -> StateMachine<Order, OrderEvent, Ctx> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, Ctx>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, c| c.update(|f| f.paid = true));
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Delivered)
+            .on(OrderEvent::Deliver)
+            .when_shared(|_s, _e, f| f.shipped)
+            .add();
+        builder.build()
+    }
+
+    fn shipment_machine() -> StateMachine<Shipment, ShipmentEvent, Ctx> {
+        let mut builder = StateMachineBuilderFactory::create::<Shipment, ShipmentEvent, Ctx>();
+        builder
+            .external_transition()
+            .from(Shipment::Waiting)
+            .to(Shipment::Shipped)
+            .on(ShipmentEvent::Ship)
+            .when_shared(|_s, _e, f| f.paid)
+            .perform(|_s, _e, c| c.update(|f| f.shipped = true));
+        builder.build()
+    }
+
+    #[test]
+    fn test_machines_observe_each_others_updates() {
+        let context = SharedContext::new(Fulfilment::default());
+        let changes = context.subscribe();
+        let (orders, shipments) = (delivery_machine(), shipment_machine());
+
+        // Not paid yet
+        assert!(shipments
+            .fire_event(Shipment::Waiting, ShipmentEvent::Ship, context.clone())
+            .is_err());
+        assert_eq!(
+            orders
+                .fire_event(Order::Placed, OrderEvent::Pay, context.clone())
+                .unwrap(),
+            Order::Paid
+        );
+        assert_eq!(
+            shipments
+                .fire_event(Shipment::Waiting, ShipmentEvent::Ship, context.clone())
+                .unwrap(),
+            Shipment::Shipped
+        );
+        assert_eq!(
+            orders
+                .fire_event(Order::Paid, OrderEvent::Deliver, context.clone())
+                .unwrap(),
+            Order::Delivered
+        );
+
+        assert_eq!(context.version(), 2);
+        assert_eq!(changes.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+        assert!(context.read().shipped);
+    }
+
+    #[test]
+    fn test_fires_do_not_clone_shared_data() {
+        let context = SharedContext::new(Fulfilment::default());
+        let orders = delivery_machine();
+        let before = FULFILMENT_CLONES.load(Ordering::SeqCst);
+        for _ in 0..10 {
+            orders
+                .fire_event(Order::Placed, OrderEvent::Pay, context.clone())
+                .unwrap();
+            let _ = orders.fire_event(Order::Paid, OrderEvent::Deliver, context.clone());
+        }
+        assert_eq!(FULFILMENT_CLONES.load(Ordering::SeqCst), before);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "lock contexts once each, in creation order")]
+    fn test_lock_order_violation_asserted() {
+        let first = SharedContext::new(0);
+        let second = SharedContext::new(0);
+        let _second = second.read();
+        first.update(|value| *value += 1);
+    }
+}
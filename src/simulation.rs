@@ -0,0 +1,411 @@
+//! Virtual-time simulation of state timeouts (requires the `timeout` and
+//! `test-util` features)
+//!
+//! `SimulatedScheduler` drives the timeouts configured on a `StateMachine`
+//! against a `MockClock`, so models with many timeouts can be run over
+//! "virtual days" in a few milliseconds of test time. Pass the same clock to
+//! `StateMachineBuilder::with_clock` and the machine's history and metrics
+//! see the virtual time too.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Clock, Context, Event, MockClock, State, StateMachine, TransitionError};
+
+/// A timeout that was delivered by the simulator
+#[derive(Debug, Clone)]
+pub struct SimulatedFiring<K, S, E>
+where
+    S: State,
+    E: Event,
+{
+    pub key: K,
+    pub at: Instant,
+    pub from: S,
+    pub event: E,
+    pub result: Result<S, TransitionError<S, E>>,
+}
+
+struct SimulatedInstance<S, C> {
+    state: S,
+    context: C,
+    armed: Option<(Instant, u64)>,
+}
+
+struct PendingTimeout<K, S, E> {
+    key: K,
+    state: S,
+    event: E,
+}
+
+/// Deterministic scheduler delivering state timeouts against a virtual clock
+///
+/// Each tracked instance arms the timeout configured for its current state.
+/// When a timeout becomes due its event is fired through the machine, and the
+/// timeout of the resulting state is armed in turn. Due timeouts are processed
+/// in timestamp order; timeouts due at the same instant are processed in the
+/// order they were armed.
+pub struct SimulatedScheduler<'m, K, S, E, C>
+where
+    K: Eq + Hash + Clone,
+    S: State,
+    E: Event,
+    C: Context,
+{
+    machine: &'m StateMachine<S, E, C>,
+    clock: Arc<MockClock>,
+    start: Instant,
+    next_seq: u64,
+    pending: BTreeMap<(Instant, u64), PendingTimeout<K, S, E>>,
+    instances: HashMap<K, SimulatedInstance<S, C>>,
+    fired: Vec<SimulatedFiring<K, S, E>>,
+}
+
+impl<'m, K, S, E, C> SimulatedScheduler<'m, K, S, E, C>
+where
+    K: Eq + Hash + Clone,
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Create a scheduler for the given machine with a fresh `MockClock`
+    pub fn new(machine: &'m StateMachine<S, E, C>) -> Self {
+        Self::with_clock(machine, Arc::new(MockClock::new()))
+    }
+
+    /// Create a scheduler advancing `clock`, normally the clock the machine
+    /// was built with
+    pub fn with_clock(machine: &'m StateMachine<S, E, C>, clock: Arc<MockClock>) -> Self {
+        SimulatedScheduler {
+            machine,
+            start: clock.now(),
+            clock,
+            next_seq: 0,
+            pending: BTreeMap::new(),
+            instances: HashMap::new(),
+            fired: Vec::new(),
+        }
+    }
+
+    /// Current virtual time
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Virtual time elapsed since the scheduler was created
+    pub fn elapsed(&self) -> Duration {
+        self.now() - self.start
+    }
+
+    /// The clock the scheduler advances
+    pub fn clock(&self) -> &Arc<MockClock> {
+        &self.clock
+    }
+
+    /// Track an instance in the given state, arming that state's timeout
+    pub fn add_instance(&mut self, key: K, state: S, context: C) {
+        let previous = self.instances.insert(
+            key.clone(),
+            SimulatedInstance {
+                state,
+                context,
+                armed: None,
+            },
+        );
+        if let Some(armed) = previous.and_then(|instance| instance.armed) {
+            self.pending.remove(&armed);
+        }
+        self.arm(&key);
+    }
+
+    /// Current state of a tracked instance
+    pub fn current_state(&self, key: &K) -> Option<&S> {
+        self.instances.get(key).map(|instance| &instance.state)
+    }
+
+    /// Fire an event on a tracked instance at the current virtual time
+    ///
+    /// A successful transition cancels the pending timeout of the old state
+    /// and arms the timeout of the new one.
+    pub fn fire(&mut self, key: &K, event: E) -> Option<Result<S, TransitionError<S, E>>> {
+        let instance = self.instances.get(key)?;
+        let result =
+            self.machine
+                .fire_event(instance.state.clone(), event, instance.context.clone());
+        if let Ok(new_state) = &result {
+            self.enter(key, new_state.clone());
+        }
+        Some(result)
+    }
+
+    /// Virtual time of the next pending timeout
+    pub fn next_event_at(&self) -> Option<Instant> {
+        self.pending.keys().next().map(|(at, _)| *at)
+    }
+
+    /// Advance the clock to the next pending timeout and deliver it
+    ///
+    /// Returns the virtual time the timeout fired at, or `None` when nothing
+    /// is scheduled.
+    pub fn advance_to_next_event(&mut self) -> Option<Instant> {
+        let ((at, _), pending) = self.pending.pop_first()?;
+        self.advance_clock_to(at);
+        self.deliver(at, pending);
+        Some(at)
+    }
+
+    /// Advance the clock by `duration`, delivering every timeout that becomes
+    /// due on the way in order
+    ///
+    /// Returns the number of timeouts delivered.
+    pub fn advance_by(&mut self, duration: Duration) -> usize {
+        let deadline = self.now() + duration;
+        let mut delivered = 0;
+        while let Some(at) = self.next_event_at() {
+            if at > deadline {
+                break;
+            }
+            self.advance_to_next_event();
+            delivered += 1;
+        }
+        self.advance_clock_to(deadline);
+        delivered
+    }
+
+    /// All timeouts delivered so far, in delivery order
+    pub fn fired(&self) -> &[SimulatedFiring<K, S, E>] {
+        &self.fired
+    }
+
+    fn deliver(&mut self, at: Instant, pending: PendingTimeout<K, S, E>) {
+        let context = match self.instances.get_mut(&pending.key) {
+            Some(instance) => {
+                instance.armed = None;
+                instance.context.clone()
+            }
+            None => return,
+        };
+
+        let result = self
+            .machine
+            .fire_event(pending.state.clone(), pending.event.clone(), context);
+        if let Ok(new_state) = &result {
+            self.enter(&pending.key, new_state.clone());
+        }
+
+        self.fired.push(SimulatedFiring {
+            key: pending.key,
+            at,
+            from: pending.state,
+            event: pending.event,
+            result,
+        });
+    }
+
+    // Timeouts due in the past, e.g. after the clock was advanced by hand,
+    // are delivered without moving the clock back
+    fn advance_clock_to(&self, at: Instant) {
+        let now = self.clock.now();
+        if at > now {
+            self.clock.advance(at - now);
+        }
+    }
+
+    fn enter(&mut self, key: &K, state: S) {
+        let instance = match self.instances.get_mut(key) {
+            Some(instance) => instance,
+            None => return,
+        };
+        if instance.state == state {
+            // Staying in the state keeps its timeout running
+            if instance.armed.is_some() {
+                return;
+            }
+        } else {
+            instance.state = state;
+            // Leaving a state cancels its pending timeout
+            if let Some(armed) = instance.armed.take() {
+                self.pending.remove(&armed);
+            }
+        }
+        self.arm(key);
+    }
+
+    fn arm(&mut self, key: &K) {
+        let instance = match self.instances.get(key) {
+            Some(instance) => instance,
+            None => return,
+        };
+        let duration = match self.machine.state_timeouts.get(&instance.state) {
+            Some(duration) => *duration,
+            None => return,
+        };
+        let (_, event) = match self.machine.timeout_transitions.get(&instance.state) {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let slot = (self.clock.now() + duration, self.next_seq);
+        self.next_seq += 1;
+        self.pending.insert(
+            slot,
+            PendingTimeout {
+                key: key.clone(),
+                state: instance.state.clone(),
+                event: event.clone(),
+            },
+        );
+        if let Some(instance) = self.instances.get_mut(key) {
+            instance.armed = Some(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Job {
+        Queued,
+        Running,
+        Done,
+    }
+
+    impl State for Job {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum JobEvent {
+        Tick,
+        Progress,
+    }
+
+    impl Event for JobEvent {}
+
+    #[derive(Debug, Clone)]
+    struct JobContext;
+
+    impl Context for JobContext {}
+
+    fn job_machine() -> StateMachine<Job, JobEvent, JobContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Job, JobEvent, JobContext>();
+        builder
+            .external_transition()
+            .from(Job::Queued)
+            .to(Job::Running)
+            .on(JobEvent::Tick)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Job::Running)
+            .to(Job::Done)
+            .on(JobEvent::Tick)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(Job::Running)
+            .on(JobEvent::Progress)
+            .perform(|_s, _e, _c| {});
+        builder
+            .with_state_timeout(
+                Job::Queued,
+                Duration::from_secs(60),
+                Job::Running,
+                JobEvent::Tick,
+            )
+            .with_state_timeout(
+                Job::Running,
+                Duration::from_secs(3600),
+                Job::Done,
+                JobEvent::Tick,
+            );
+        builder.build()
+    }
+
+    #[test]
+    fn test_overlapping_timeouts_fire_in_order() {
+        let machine = job_machine();
+        let mut sim = SimulatedScheduler::new(&machine);
+        sim.add_instance("a", Job::Queued, JobContext);
+        sim.add_instance("b", Job::Running, JobContext);
+        sim.add_instance("c", Job::Queued, JobContext);
+
+        while sim.advance_to_next_event().is_some() {}
+
+        let order: Vec<(&str, Job)> = sim
+            .fired()
+            .iter()
+            .map(|f| (f.key, f.from.clone()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("a", Job::Queued),
+                ("c", Job::Queued),
+                ("b", Job::Running),
+                ("a", Job::Running),
+                ("c", Job::Running),
+            ]
+        );
+        assert_eq!(sim.current_state(&"a"), Some(&Job::Done));
+        assert_eq!(sim.elapsed(), Duration::from_secs(3660));
+    }
+
+    #[test]
+    fn test_advance_by_processes_all_due_events() {
+        let machine = job_machine();
+        let mut sim = SimulatedScheduler::new(&machine);
+        sim.add_instance(1, Job::Queued, JobContext);
+        sim.add_instance(2, Job::Queued, JobContext);
+
+        assert_eq!(sim.advance_by(Duration::from_secs(30)), 0);
+        assert_eq!(sim.advance_by(Duration::from_secs(24 * 3600)), 4);
+        assert!(sim.fired().windows(2).all(|w| w[0].at <= w[1].at));
+        assert_eq!(sim.current_state(&2), Some(&Job::Done));
+        assert_eq!(sim.next_event_at(), None);
+    }
+
+    #[test]
+    fn test_manual_fire_cancels_pending_timeout() {
+        let machine = job_machine();
+        let mut sim = SimulatedScheduler::new(&machine);
+        sim.add_instance("a", Job::Queued, JobContext);
+
+        assert!(sim.fire(&"a", JobEvent::Tick).unwrap().is_ok());
+        assert_eq!(sim.advance_by(Duration::from_secs(120)), 0);
+        assert_eq!(sim.advance_by(Duration::from_secs(3600)), 1);
+        assert_eq!(sim.current_state(&"a"), Some(&Job::Done));
+    }
+
+    #[test]
+    fn test_internal_transition_keeps_timeout() {
+        let machine = job_machine();
+        let mut sim = SimulatedScheduler::new(&machine);
+        sim.add_instance("a", Job::Running, JobContext);
+
+        sim.advance_by(Duration::from_secs(3000));
+        assert!(sim.fire(&"a", JobEvent::Progress).unwrap().is_ok());
+        // The timeout still fires an hour after entering Running
+        assert_eq!(sim.advance_by(Duration::from_secs(600)), 1);
+        assert_eq!(sim.fired()[0].at - sim.start, Duration::from_secs(3600));
+        assert_eq!(sim.current_state(&"a"), Some(&Job::Done));
+    }
+
+    #[test]
+    fn test_scheduler_drives_shared_clock() {
+        let machine = job_machine();
+        let clock = Arc::new(MockClock::new());
+        let started = clock.now();
+        let mut sim = SimulatedScheduler::with_clock(&machine, clock.clone());
+        sim.add_instance("a", Job::Queued, JobContext);
+
+        assert!(sim.advance_to_next_event().is_some());
+        assert_eq!(clock.now() - started, Duration::from_secs(60));
+        sim.advance_by(Duration::from_secs(30));
+        assert_eq!(clock.now() - started, Duration::from_secs(90));
+        assert_eq!(sim.now(), clock.now());
+    }
+}
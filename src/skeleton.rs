@@ -0,0 +1,649 @@
+//! Machines sketched with plain names before any types exist
+//!
+//! A `MachineSkeleton` has `String` states and events and no behavior. It is
+//! sketched with `SkeletonBuilder` at design time, checked and rendered with
+//! the same algorithms as a built machine, and compared with other skeletons,
+//! including the one `StateMachine::skeleton` extracts from the real
+//! definition. Once the state and event types exist, `MachineSkeleton::bind`
+//! maps every name to a value and attaches guards and actions, giving a
+//! `StateMachineBuilder` to finish the definition with.
+//!
+//! Checks and exports run on a machine built from the skeleton, with states
+//! and events named exactly as sketched.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::{
+    Action, BuildError, Condition, Context, Event, State, StateMachine, StateMachineBuilder,
+    TransitionType, ValidationReport,
+};
+
+/// One transition of a skeleton, by name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SkeletonTransition {
+    pub from: String,
+    pub event: String,
+    /// Same as `from` for internal transitions
+    pub to: String,
+    pub internal: bool,
+}
+
+/// States, events and transitions of a machine, by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MachineSkeleton {
+    initial_state: Option<String>,
+    terminal_states: BTreeSet<String>,
+    transitions: Vec<SkeletonTransition>,
+}
+
+/// Builder for a `MachineSkeleton`
+#[derive(Debug, Clone, Default)]
+pub struct SkeletonBuilder {
+    skeleton: MachineSkeleton,
+}
+
+impl SkeletonBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the state new entities start in
+    pub fn initial_state(&mut self, state: impl Into<String>) -> &mut Self {
+        self.skeleton.initial_state = Some(state.into());
+        self
+    }
+
+    /// Declare states that accept no events
+    pub fn terminal_states<T: Into<String>>(&mut self, states: Vec<T>) -> &mut Self {
+        self.skeleton
+            .terminal_states
+            .extend(states.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add an external transition from `from` to `to` on `event`
+    pub fn transition(
+        &mut self,
+        from: impl Into<String>,
+        event: impl Into<String>,
+        to: impl Into<String>,
+    ) -> &mut Self {
+        self.skeleton.transitions.push(SkeletonTransition {
+            from: from.into(),
+            event: event.into(),
+            to: to.into(),
+            internal: false,
+        });
+        self
+    }
+
+    /// Add an internal transition within `state` on `event`
+    pub fn internal_transition(
+        &mut self,
+        state: impl Into<String>,
+        event: impl Into<String>,
+    ) -> &mut Self {
+        let state = state.into();
+        self.skeleton.transitions.push(SkeletonTransition {
+            from: state.clone(),
+            event: event.into(),
+            to: state,
+            internal: true,
+        });
+        self
+    }
+
+    /// Build the skeleton, failing only on transitions out of terminal states
+    ///
+    /// Anything else `try_build` would reject is reported by
+    /// `MachineSkeleton::check`, so unfinished sketches can still be
+    /// rendered.
+    pub fn build(&self) -> Result<MachineSkeleton, Vec<BuildError>> {
+        let errors: Vec<BuildError> = self
+            .skeleton
+            .transitions
+            .iter()
+            .filter(|t| self.skeleton.terminal_states.contains(&t.from))
+            .map(|t| BuildError::TransitionFromTerminal {
+                state: t.from.clone(),
+                event: Some(t.event.clone()),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(self.skeleton.clone())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Differences between two skeletons, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SkeletonDiff {
+    pub added_states: Vec<String>,
+    pub removed_states: Vec<String>,
+    pub added_transitions: Vec<SkeletonTransition>,
+    pub removed_transitions: Vec<SkeletonTransition>,
+}
+
+impl SkeletonDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_states.is_empty()
+            && self.removed_states.is_empty()
+            && self.added_transitions.is_empty()
+            && self.removed_transitions.is_empty()
+    }
+}
+
+/// Reason `MachineSkeleton::bind` could not produce a builder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindError {
+    /// The state map has no value for a state of the skeleton
+    UnmappedState { state: String },
+    /// The event map has no value for an event of the skeleton
+    UnmappedEvent { event: String },
+    /// A binding was given for a transition the skeleton doesn't have
+    UnknownTransition {
+        from: String,
+        event: String,
+        to: String,
+    },
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindError::UnmappedState { state } => write!(f, "state {} is not mapped", state),
+            BindError::UnmappedEvent { event } => write!(f, "event {} is not mapped", event),
+            BindError::UnknownTransition { from, event, to } => write!(
+                f,
+                "no transition from {} on {} to {} to bind",
+                from, event, to
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
+// Transition of a skeleton as (from, event, to)
+type TransitionKey = (String, String, String);
+
+// Guard and action bound to one transition
+struct Binding<S, E, C> {
+    condition: Option<Condition<S, E, C>>,
+    action: Option<Action<S, E, C>>,
+}
+
+/// Guards and actions for the transitions of a skeleton, by name
+pub struct SkeletonBindings<S, E, C> {
+    bindings: HashMap<TransitionKey, Binding<S, E, C>>,
+}
+
+impl<S, E, C> SkeletonBindings<S, E, C> {
+    pub fn new() -> Self {
+        SkeletonBindings {
+            bindings: HashMap::new(),
+        }
+    }
+
+    /// Guard the transition from `from` to `to` on `event`
+    ///
+    /// Internal transitions are named with the same `from` and `to`.
+    pub fn when<F>(mut self, from: &str, event: &str, to: &str, condition: F) -> Self
+    where
+        F: Fn(&S, &E, &C) -> bool + Send + Sync + 'static,
+    {
+        self.binding(from, event, to).condition = Some(std::sync::Arc::new(condition));
+        self
+    }
+
+    /// Run `action` when the transition from `from` to `to` on `event` fires
+    pub fn perform<F>(mut self, from: &str, event: &str, to: &str, action: F) -> Self
+    where
+        F: Fn(&S, &E, &C) + Send + Sync + 'static,
+    {
+        self.binding(from, event, to).action = Some(std::sync::Arc::new(action));
+        self
+    }
+
+    fn binding(&mut self, from: &str, event: &str, to: &str) -> &mut Binding<S, E, C> {
+        self.bindings
+            .entry((from.to_string(), event.to_string(), to.to_string()))
+            .or_insert(Binding {
+                condition: None,
+                action: None,
+            })
+    }
+}
+
+impl<S, E, C> Default for SkeletonBindings<S, E, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// State or event of the machine a skeleton is checked and rendered with,
+// shown as the bare name
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Name(String);
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl State for Name {}
+impl Event for Name {}
+
+#[derive(Debug, Clone)]
+struct Sketch;
+
+impl Context for Sketch {}
+
+impl MachineSkeleton {
+    pub fn builder() -> SkeletonBuilder {
+        SkeletonBuilder::new()
+    }
+
+    pub fn initial_state(&self) -> Option<&str> {
+        self.initial_state.as_deref()
+    }
+
+    pub fn terminal_states(&self) -> impl Iterator<Item = &str> {
+        self.terminal_states.iter().map(String::as_str)
+    }
+
+    pub fn transitions(&self) -> &[SkeletonTransition] {
+        &self.transitions
+    }
+
+    /// Every state named by the skeleton, sorted
+    pub fn states(&self) -> Vec<&str> {
+        let mut states: BTreeSet<&str> = self.terminal_states().collect();
+        states.extend(self.initial_state());
+        for transition in &self.transitions {
+            states.insert(&transition.from);
+            states.insert(&transition.to);
+        }
+        states.into_iter().collect()
+    }
+
+    /// Every event named by the skeleton, sorted
+    pub fn events(&self) -> Vec<&str> {
+        let events: BTreeSet<&str> = self.transitions.iter().map(|t| t.event.as_str()).collect();
+        events.into_iter().collect()
+    }
+
+    /// Problems `try_build` would report for the skeleton
+    pub fn check(&self) -> Vec<BuildError> {
+        self.graph_builder().try_build().err().unwrap_or_default()
+    }
+
+    /// `StateMachine::validate` for the skeleton
+    pub fn validate(&self) -> ValidationReport {
+        self.graph().validate()
+    }
+
+    /// What changed from `self` to `other`
+    pub fn diff(&self, other: &MachineSkeleton) -> SkeletonDiff {
+        let (before, after) = (self.states(), other.states());
+        let transitions = |skeleton: &MachineSkeleton| -> BTreeSet<SkeletonTransition> {
+            skeleton.transitions.iter().cloned().collect()
+        };
+        let (old, new) = (transitions(self), transitions(other));
+        SkeletonDiff {
+            added_states: difference(&after, &before),
+            removed_states: difference(&before, &after),
+            added_transitions: new.difference(&old).cloned().collect(),
+            removed_transitions: old.difference(&new).cloned().collect(),
+        }
+    }
+
+    /// Start a builder of real types from the skeleton
+    ///
+    /// Every state and event must be mapped. Transitions without a binding
+    /// are registered without guard or action.
+    pub fn bind<S, E, C>(
+        &self,
+        states: &HashMap<String, S>,
+        events: &HashMap<String, E>,
+        bindings: SkeletonBindings<S, E, C>,
+    ) -> Result<StateMachineBuilder<S, E, C>, BindError>
+    where
+        S: State + 'static,
+        E: Event + 'static,
+        C: Context + 'static,
+    {
+        if let Some(state) = self.states().into_iter().find(|s| !states.contains_key(*s)) {
+            return Err(BindError::UnmappedState {
+                state: state.to_string(),
+            });
+        }
+        if let Some(event) = self.events().into_iter().find(|e| !events.contains_key(*e)) {
+            return Err(BindError::UnmappedEvent {
+                event: event.to_string(),
+            });
+        }
+        let mut bindings = bindings.bindings;
+        let mut unknown: Vec<&TransitionKey> = bindings
+            .keys()
+            .filter(|(from, event, to)| {
+                !self
+                    .transitions
+                    .iter()
+                    .any(|t| t.from == *from && t.event == *event && t.to == *to)
+            })
+            .collect();
+        unknown.sort();
+        if let Some((from, event, to)) = unknown.first() {
+            return Err(BindError::UnknownTransition {
+                from: from.clone(),
+                event: event.clone(),
+                to: to.clone(),
+            });
+        }
+
+        let mut builder = StateMachineBuilder::new();
+        if let Some(initial) = &self.initial_state {
+            builder.initial_state(states[initial].clone());
+        }
+        builder.terminal_states(
+            self.terminal_states
+                .iter()
+                .map(|state| states[state].clone())
+                .collect(),
+        );
+        for transition in &self.transitions {
+            let key = (
+                transition.from.clone(),
+                transition.event.clone(),
+                transition.to.clone(),
+            );
+            let Binding { condition, action } = bindings.remove(&key).unwrap_or(Binding {
+                condition: None,
+                action: None,
+            });
+            let (from, event) = (&states[&transition.from], &events[&transition.event]);
+            if transition.internal {
+                let mut registered = builder
+                    .internal_transition()
+                    .within(from.clone())
+                    .on(event.clone());
+                if let Some(condition) = condition {
+                    registered = registered.when(move |s, e, c| condition(s, e, c));
+                }
+                match action {
+                    Some(action) => registered.perform(move |s, e, c| action(s, e, c)),
+                    None => registered.add(),
+                };
+            } else {
+                let mut registered = builder
+                    .external_transition()
+                    .from(from.clone())
+                    .to(states[&transition.to].clone())
+                    .on(event.clone());
+                if let Some(condition) = condition {
+                    registered = registered.when(move |s, e, c| condition(s, e, c));
+                }
+                match action {
+                    Some(action) => registered.perform(move |s, e, c| action(s, e, c)),
+                    None => registered.add(),
+                };
+            }
+        }
+        Ok(builder)
+    }
+
+    // Definition of the skeleton with its names as states and events
+    fn graph_builder(&self) -> StateMachineBuilder<Name, Name, Sketch> {
+        let mut builder = StateMachineBuilder::new();
+        if let Some(initial) = &self.initial_state {
+            builder.initial_state(Name(initial.clone()));
+        }
+        builder.terminal_states(self.terminal_states.iter().cloned().map(Name).collect());
+        for transition in &self.transitions {
+            let event = Name(transition.event.clone());
+            if transition.internal {
+                builder
+                    .internal_transition()
+                    .within(Name(transition.from.clone()))
+                    .on(event)
+                    .add();
+            } else {
+                builder
+                    .external_transition()
+                    .from(Name(transition.from.clone()))
+                    .to(Name(transition.to.clone()))
+                    .on(event)
+                    .add();
+            }
+        }
+        builder
+    }
+
+    // `SkeletonBuilder::build` rejected what `build` panics on
+    fn graph(&self) -> StateMachine<Name, Name, Sketch> {
+        self.graph_builder().build()
+    }
+}
+
+#[cfg(feature = "visualization")]
+#[cfg_attr(docsrs, doc(cfg(feature = "visualization")))]
+impl MachineSkeleton {
+    /// `StateMachine::to_dot` for the skeleton
+    pub fn to_dot(&self) -> String {
+        self.graph().to_dot()
+    }
+
+    /// `StateMachine::to_plantuml` for the skeleton
+    pub fn to_plantuml(&self) -> String {
+        self.graph().to_plantuml()
+    }
+
+    /// `StateMachine::to_markdown` for the skeleton
+    pub fn to_markdown(&self) -> String {
+        self.graph().to_markdown()
+    }
+}
+
+fn difference(left: &[&str], right: &[&str]) -> Vec<String> {
+    left.iter()
+        .filter(|name| !right.contains(name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// The keyed transitions, initial and terminal states of the definition,
+    /// named by their `Debug` representation
+    ///
+    /// `from_any`, completion and timeout transitions are left out.
+    pub fn skeleton(&self) -> MachineSkeleton {
+        let name = |value: &dyn fmt::Debug| format!("{:?}", value);
+        let mut transitions: Vec<SkeletonTransition> = self
+            .transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+            .map(|t| SkeletonTransition {
+                from: name(&t.from),
+                event: name(&t.event),
+                to: name(&t.to),
+                internal: t.transition_type == TransitionType::Internal,
+            })
+            .collect();
+        transitions.sort();
+        MachineSkeleton {
+            initial_state: self.initial_state.as_ref().map(|state| name(state)),
+            terminal_states: self
+                .terminal_states
+                .iter()
+                .map(|state| name(state))
+                .collect(),
+            transitions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Order, OrderEvent};
+
+    #[derive(Debug, Clone)]
+    struct OrderContext {
+        paid_amount: u32,
+    }
+
+    impl Context for OrderContext {}
+
+    fn sketch() -> MachineSkeleton {
+        let mut builder = MachineSkeleton::builder();
+        builder
+            .initial_state("Placed")
+            .terminal_states(vec!["Shipped"])
+            .transition("Placed", "Pay", "Paid")
+            .transition("Paid", "Ship", "Shipped")
+            .internal_transition("Placed", "Note");
+        builder.build().unwrap()
+    }
+
+    fn names<T: Clone>(values: &[(&str, T)]) -> HashMap<String, T> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_skeleton_checked_and_bound() {
+        let skeleton = sketch();
+        assert_eq!(skeleton.states(), vec!["Paid", "Placed", "Shipped"]);
+        assert_eq!(skeleton.events(), vec!["Note", "Pay", "Ship"]);
+        assert!(skeleton.check().is_empty());
+        assert!(skeleton.validate().is_clean());
+        #[cfg(feature = "visualization")]
+        {
+            let dot = skeleton.to_dot();
+            assert!(dot.contains("\"Placed\" -> \"Paid\" [label=\"Pay\"];"));
+            assert!(dot.contains("\"Shipped\" [shape=doublecircle];"));
+        }
+
+        let states = names(&[
+            ("Placed", Order::Placed),
+            ("Paid", Order::Paid),
+            ("Shipped", Order::Shipped),
+        ]);
+        let events = names(&[
+            ("Pay", OrderEvent::Pay),
+            ("Ship", OrderEvent::Ship),
+            ("Note", OrderEvent::Note),
+        ]);
+        let bindings =
+            SkeletonBindings::new().when("Placed", "Pay", "Paid", |_s, _e, c: &OrderContext| {
+                c.paid_amount > 0
+            });
+        let machine = skeleton
+            .bind(&states, &events, bindings)
+            .unwrap()
+            .try_build()
+            .unwrap();
+
+        assert!(machine
+            .fire_event(
+                Order::Placed,
+                OrderEvent::Pay,
+                OrderContext { paid_amount: 0 }
+            )
+            .is_err());
+        let context = OrderContext { paid_amount: 30 };
+        assert_eq!(
+            machine
+                .fire_event(Order::Placed, OrderEvent::Pay, context.clone())
+                .unwrap(),
+            Order::Paid
+        );
+        assert_eq!(
+            machine
+                .fire_event(Order::Placed, OrderEvent::Note, context)
+                .unwrap(),
+            Order::Placed
+        );
+        assert!(skeleton.diff(&machine.skeleton()).is_empty());
+    }
+
+    #[test]
+    fn test_bind_rejects_unmapped_names() {
+        let skeleton = sketch();
+        let states = names(&[("Placed", Order::Placed), ("Paid", Order::Paid)]);
+        let events = names(&[("Pay", OrderEvent::Pay)]);
+        match skeleton.bind::<_, _, OrderContext>(&states, &events, SkeletonBindings::new()) {
+            Err(BindError::UnmappedState { state }) => assert_eq!(state, "Shipped"),
+            other => panic!("expected UnmappedState, got {:?}", other.err()),
+        }
+
+        let states = names(&[
+            ("Placed", Order::Placed),
+            ("Paid", Order::Paid),
+            ("Shipped", Order::Shipped),
+        ]);
+        match skeleton.bind::<_, _, OrderContext>(&states, &events, SkeletonBindings::new()) {
+            Err(BindError::UnmappedEvent { event }) => assert_eq!(event, "Note"),
+            other => panic!("expected UnmappedEvent, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_diff_and_check_of_unfinished_sketch() {
+        let mut builder = MachineSkeleton::builder();
+        builder
+            .initial_state("Placed")
+            .transition("Placed", "Pay", "Paid")
+            .transition("Placed", "Cancel", "Cancelled");
+        let draft = builder.build().unwrap();
+        assert_eq!(
+            draft.check(),
+            vec![
+                BuildError::DeadEndState {
+                    state: "Cancelled".to_string()
+                },
+                BuildError::DeadEndState {
+                    state: "Paid".to_string()
+                },
+            ]
+        );
+
+        let diff = draft.diff(&sketch());
+        assert_eq!(diff.added_states, vec!["Shipped"]);
+        assert_eq!(diff.removed_states, vec!["Cancelled"]);
+        assert_eq!(diff.added_transitions.len(), 2);
+        assert_eq!(
+            diff.removed_transitions,
+            vec![SkeletonTransition {
+                from: "Placed".to_string(),
+                event: "Cancel".to_string(),
+                to: "Cancelled".to_string(),
+                internal: false,
+            }]
+        );
+
+        let mut builder = MachineSkeleton::builder();
+        builder
+            .terminal_states(vec!["Paid"])
+            .transition("Paid", "Refund", "Placed");
+        assert_eq!(
+            builder.build().unwrap_err(),
+            vec![BuildError::TransitionFromTerminal {
+                state: "Paid".to_string(),
+                event: Some("Refund".to_string()),
+            }]
+        );
+    }
+}
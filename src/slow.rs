@@ -0,0 +1,256 @@
+//! Detection of guards and actions exceeding a time budget
+//!
+//! With a threshold configured through
+//! `StateMachineBuilder::with_slow_callback_threshold`, every guard, action,
+//! entry and exit callback is timed individually with the machine's clock.
+//! Callbacks taking longer than the threshold are reported to a handler
+//! and, with the `metrics` feature, counted in
+//! `StateMachineMetrics::slow_callbacks`. Outcomes are never changed.
+//! Without a threshold nothing is timed.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Context, Event, State, StateMachine, StateMachineBuilder};
+
+pub(crate) type SlowCallbackHandler<S, E> =
+    Arc<dyn Fn(&CallbackInfo<'_, S, E>, Duration) + Send + Sync>;
+
+/// Kind of callback that was timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackKind {
+    Guard,
+    Action,
+    Entry,
+    Exit,
+}
+
+/// Identifies a timed callback
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallbackInfo<'a, S, E> {
+    pub kind: CallbackKind,
+    pub machine_id: &'a str,
+    /// Source state for guards, actions and exit actions; the entered state
+    /// for entry actions
+    pub state: &'a S,
+    /// Event and target of the transition, for guards and actions
+    pub transition: Option<(&'a E, &'a S)>,
+}
+
+impl<S: Debug, E: Debug> CallbackInfo<'_, S, E> {
+    /// Label identifying the callback, e.g. `guard Idle --Start--> Running`
+    pub fn name(&self) -> String {
+        let kind = match self.kind {
+            CallbackKind::Guard => "guard",
+            CallbackKind::Action => "action",
+            CallbackKind::Entry => "entry",
+            CallbackKind::Exit => "exit",
+        };
+        match self.transition {
+            Some((event, to)) => format!("{} {:?} --{:?}--> {:?}", kind, self.state, event, to),
+            None => format!("{} {:?}", kind, self.state),
+        }
+    }
+}
+
+pub(crate) struct SlowCallbacks<S, E> {
+    threshold: Duration,
+    handler: SlowCallbackHandler<S, E>,
+}
+
+impl<S, E> Clone for SlowCallbacks<S, E> {
+    fn clone(&self) -> Self {
+        SlowCallbacks {
+            threshold: self.threshold,
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+impl<S, E> SlowCallbacks<S, E> {
+    /// The same threshold, with the handler replaced by `map` of it
+    #[cfg(feature = "parallel")]
+    pub(crate) fn map_handler<S2>(
+        self,
+        map: impl FnOnce(SlowCallbackHandler<S, E>) -> SlowCallbackHandler<S2, E>,
+    ) -> SlowCallbacks<S2, E> {
+        SlowCallbacks {
+            threshold: self.threshold,
+            handler: map(self.handler),
+        }
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Report every callback running longer than `threshold` to `handler`
+    pub fn with_slow_callback_threshold<F>(&mut self, threshold: Duration, handler: F) -> &mut Self
+    where
+        F: Fn(&CallbackInfo<'_, S, E>, Duration) + Send + Sync + 'static,
+    {
+        self.slow_callbacks = Some(SlowCallbacks {
+            threshold,
+            handler: Arc::new(handler),
+        });
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Run `callback`, timing it if a slow-callback threshold is set
+    pub(crate) fn timed<T>(
+        &self,
+        kind: CallbackKind,
+        state: &S,
+        transition: Option<(&E, &S)>,
+        callback: impl FnOnce() -> T,
+    ) -> T {
+        let slow = match &self.slow_callbacks {
+            Some(slow) => slow,
+            None => return callback(),
+        };
+
+        let start = self.clock.now();
+        let output = callback();
+        let elapsed = self.clock.now().saturating_duration_since(start);
+        if elapsed > slow.threshold {
+            let info = CallbackInfo {
+                kind,
+                machine_id: &self.id,
+                state,
+                transition,
+            };
+            (slow.handler)(&info, elapsed);
+
+            #[cfg(feature = "metrics")]
+            {
+                self.recording.update_metrics(|metrics| {
+                    *metrics.slow_callbacks.entry(info.name()).or_insert(0) += 1;
+                });
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, StateMachineBuilderFactory, SystemClock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Instant, SystemTime};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Job {
+        Idle,
+        Running,
+    }
+
+    impl State for Job {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum JobEvent {
+        Start,
+    }
+
+    impl Event for JobEvent {}
+
+    #[derive(Debug, Clone)]
+    struct JobContext {
+        slow: bool,
+    }
+
+    impl Context for JobContext {}
+
+    // Clock counting how often the monotonic time is read
+    #[derive(Default)]
+    struct CountingClock {
+        reads: AtomicUsize,
+    }
+
+    impl Clock for CountingClock {
+        fn now(&self) -> Instant {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            SystemClock.now()
+        }
+
+        fn wall_time(&self) -> SystemTime {
+            SystemClock.wall_time()
+        }
+    }
+
+    fn job_machine(
+        clock: Arc<CountingClock>,
+        slow: Option<Arc<Mutex<Vec<String>>>>,
+    ) -> StateMachine<Job, JobEvent, JobContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Job, JobEvent, JobContext>();
+        builder
+            .external_transition()
+            .from(Job::Idle)
+            .to(Job::Running)
+            .on(JobEvent::Start)
+            .when(|_s, _e, c| {
+                if c.slow {
+                    std::thread::sleep(Duration::from_millis(30));
+                }
+                true
+            })
+            .perform(|_s, _e, _c| {});
+        builder.with_clock(clock);
+        if let Some(reported) = slow {
+            builder.with_slow_callback_threshold(Duration::from_millis(10), move |info, _| {
+                reported.lock().unwrap().push(info.name());
+            });
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_slow_guard_reported() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let machine = job_machine(Arc::default(), Some(reported.clone()));
+
+        let result = machine.fire_event(Job::Idle, JobEvent::Start, JobContext { slow: true });
+        assert_eq!(result.unwrap(), Job::Running);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec!["guard Idle --Start--> Running"]
+        );
+        #[cfg(feature = "metrics")]
+        assert_eq!(
+            machine.get_metrics().slow_callbacks["guard Idle --Start--> Running"],
+            1
+        );
+
+        let result = machine.fire_event(Job::Idle, JobEvent::Start, JobContext { slow: false });
+        assert!(result.is_ok());
+        assert_eq!(reported.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_nothing_timed_without_threshold() {
+        let clock = Arc::new(CountingClock::default());
+        let machine = job_machine(clock.clone(), None);
+
+        let result = machine.fire_event(Job::Idle, JobEvent::Start, JobContext { slow: false });
+        assert!(result.is_ok());
+        // Only the outcome, for history and metrics, reads the clock
+        let expected = if cfg!(any(feature = "history", feature = "metrics")) {
+            1
+        } else {
+            0
+        };
+        assert_eq!(clock.reads.load(Ordering::SeqCst), expected);
+    }
+}
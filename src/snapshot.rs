@@ -0,0 +1,591 @@
+//! Persisting `StateMachineInstance`s (requires `serde` feature)
+//!
+//! An `InstanceSnapshot` records which machine an instance runs on, the
+//! state it is in and the sequences applied by
+//! `StateMachineInstance::fire_event_sequenced`; history and metrics are not
+//! part of it. Snapshots encode to JSON, or with the `binary-snapshots`
+//! feature to a compact framed form:
+//!
+//! ```text
+//! "RSSM" | version: u8 | postcard payload | CRC-32 of all preceding bytes (LE)
+//! ```
+//!
+//! The version is checked before the checksum, since a later format may
+//! frame its payload differently. Version 1 payloads, written before
+//! snapshots carried sequences, still decode with an empty `SequenceMark`.
+//!
+//! `StateMachineInstance::encoded_snapshot` and `StateMachine::restore_encoded`
+//! pass the JSON form through the machine's `SnapshotCodec`, set with
+//! `StateMachineBuilder::with_snapshot_codec`, so snapshots can be encrypted
+//! before they reach storage. The default codec passes bytes through
+//! unchanged; the `encryption` feature adds `AesGcmCodec`.
+//!
+//! Snapshots only hold the state. Contexts and history records, which may
+//! carry personal data, are encrypted by giving the same codec, e.g. from
+//! `StateMachine::snapshot_codec`, to `FileContextStore`,
+//! `JsonLinesHistorySink` or `JsonLinesFileSink`. Those write each context
+//! or record through the codec, the history sinks as one line of hex each.
+
+use std::fmt::{self, Write};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Context, Event, SequenceMark, State, StateMachine, StateMachineBuilder, StateMachineInstance,
+};
+
+/// Why a snapshot could not be restored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The bytes are truncated, fail their checksum or don't decode
+    Corrupt { reason: String },
+    /// Written by a newer version of the binary format
+    UnsupportedVersion { version: u8 },
+    /// Taken from an instance of another machine
+    MachineMismatch { expected: String, found: String },
+    /// The `SnapshotCodec` could not decode the bytes, e.g. because they
+    /// were encrypted with another key
+    CodecFailure { reason: String },
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::Corrupt { reason } => write!(f, "Corrupt snapshot: {}", reason),
+            RestoreError::UnsupportedVersion { version } => {
+                write!(f, "Unsupported snapshot format version {}", version)
+            }
+            RestoreError::MachineMismatch { expected, found } => write!(
+                f,
+                "Snapshot of machine {} cannot be restored on machine {}",
+                found, expected
+            ),
+            RestoreError::CodecFailure { reason } => {
+                write!(f, "Snapshot could not be decoded: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// Why a `SnapshotCodec` could not decode its input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecError {
+    pub reason: String,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<CodecError> for RestoreError {
+    fn from(error: CodecError) -> Self {
+        RestoreError::CodecFailure {
+            reason: error.reason,
+        }
+    }
+}
+
+/// Transformation applied to encoded snapshots on their way to and from
+/// storage, such as encryption
+pub trait SnapshotCodec: Send + Sync {
+    fn encode(&self, bytes: &[u8]) -> Vec<u8>;
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, CodecError>;
+}
+
+/// Codec leaving bytes unchanged, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PassThroughCodec;
+
+impl SnapshotCodec for PassThroughCodec {
+    fn encode(&self, bytes: &[u8]) -> Vec<u8> {
+        bytes.to_vec()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+// One line of text holding `bytes` passed through `codec`, in hex
+pub(crate) fn encode_line(codec: &dyn SnapshotCodec, bytes: &[u8]) -> String {
+    let mut line = String::new();
+    for byte in codec.encode(bytes) {
+        let _ = write!(line, "{:02x}", byte);
+    }
+    line
+}
+
+// The bytes given to `encode_line` for `line`
+pub(crate) fn decode_line(codec: &dyn SnapshotCodec, line: &str) -> Result<Vec<u8>, CodecError> {
+    let digits = line.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return Err(CodecError {
+            reason: "odd number of hex digits".to_string(),
+        });
+    }
+    let bytes = digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| CodecError {
+            reason: "invalid hex digit".to_string(),
+        })?;
+    codec.decode(&bytes)
+}
+
+/// Persistable state of a `StateMachineInstance`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceSnapshot<S> {
+    pub machine_id: String,
+    pub state: S,
+    #[serde(default)]
+    pub sequence: SequenceMark,
+}
+
+impl<S> InstanceSnapshot<S>
+where
+    S: Serialize + DeserializeOwned,
+{
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, RestoreError> {
+        serde_json::from_str(json).map_err(|error| RestoreError::Corrupt {
+            reason: error.to_string(),
+        })
+    }
+
+    /// The JSON form passed through `codec`
+    pub fn to_encoded(&self, codec: &dyn SnapshotCodec) -> Result<Vec<u8>, serde_json::Error> {
+        Ok(codec.encode(self.to_json()?.as_bytes()))
+    }
+
+    /// Decode bytes written by `to_encoded` with the same codec
+    pub fn from_encoded(bytes: &[u8], codec: &dyn SnapshotCodec) -> Result<Self, RestoreError> {
+        let json = codec.decode(bytes)?;
+        serde_json::from_slice(&json).map_err(|error| RestoreError::Corrupt {
+            reason: error.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod encryption {
+    use super::*;
+    use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+    const NONCE_LEN: usize = 12;
+
+    /// Codec encrypting with AES-256-GCM under a caller-supplied key
+    ///
+    /// Each encoding uses a fresh random nonce, stored in front of the
+    /// ciphertext. Decoding fails on a wrong key or tampered bytes.
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub struct AesGcmCodec {
+        cipher: Aes256Gcm,
+    }
+
+    impl AesGcmCodec {
+        pub fn new(key: &[u8; 32]) -> Self {
+            AesGcmCodec {
+                cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            }
+        }
+    }
+
+    impl SnapshotCodec for AesGcmCodec {
+        fn encode(&self, bytes: &[u8]) -> Vec<u8> {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = self
+                .cipher
+                .encrypt(&nonce, bytes)
+                .expect("snapshot is within the AES-GCM size limit");
+            let mut encoded = nonce.to_vec();
+            encoded.extend(ciphertext);
+            encoded
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+            if bytes.len() < NONCE_LEN {
+                return Err(CodecError {
+                    reason: "missing nonce".to_string(),
+                });
+            }
+            let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+            self.cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| CodecError {
+                    reason: "decryption failed".to_string(),
+                })
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub use encryption::AesGcmCodec;
+
+#[cfg(feature = "binary-snapshots")]
+mod binary {
+    use super::*;
+    use crc::{Crc, CRC_32_ISO_HDLC};
+
+    const MAGIC: &[u8; 4] = b"RSSM";
+    const VERSION: u8 = 2;
+    const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+    // Payload of version 1, without the sequences
+    #[derive(Deserialize)]
+    struct SnapshotV1<S> {
+        machine_id: String,
+        state: S,
+    }
+
+    #[cfg_attr(docsrs, doc(cfg(feature = "binary-snapshots")))]
+    impl<S> InstanceSnapshot<S>
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        /// Encode in the framed binary format
+        pub fn to_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
+            let mut bytes = MAGIC.to_vec();
+            bytes.push(VERSION);
+            let mut bytes = postcard::to_extend(self, bytes)?;
+            let checksum = CRC32.checksum(&bytes);
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+            Ok(bytes)
+        }
+
+        /// Decode bytes written by `to_bytes`
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, RestoreError> {
+            let corrupt = |reason: &str| RestoreError::Corrupt {
+                reason: reason.to_string(),
+            };
+            let header = MAGIC.len() + 1;
+            if bytes.len() < header || &bytes[..MAGIC.len()] != MAGIC {
+                return Err(corrupt("missing header"));
+            }
+            let version = bytes[MAGIC.len()];
+            if version > VERSION {
+                return Err(RestoreError::UnsupportedVersion { version });
+            }
+            if bytes.len() < header + 4 {
+                return Err(corrupt("missing checksum"));
+            }
+            let (framed, trailer) = bytes.split_at(bytes.len() - 4);
+            let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+            if CRC32.checksum(framed) != expected {
+                return Err(corrupt("checksum mismatch"));
+            }
+            let payload = &framed[header..];
+            let decoded = if version == 1 {
+                postcard::from_bytes(payload).map(|legacy: SnapshotV1<S>| InstanceSnapshot {
+                    machine_id: legacy.machine_id,
+                    state: legacy.state,
+                    sequence: SequenceMark::default(),
+                })
+            } else {
+                postcard::from_bytes(payload)
+            };
+            decoded.map_err(|error| RestoreError::Corrupt {
+                reason: error.to_string(),
+            })
+        }
+    }
+}
+
+impl<S, E, C> StateMachineInstance<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn snapshot(&self) -> InstanceSnapshot<S> {
+        let (state, sequence) = self.sequenced_state();
+        InstanceSnapshot {
+            machine_id: self.machine().id().to_string(),
+            state,
+            sequence,
+        }
+    }
+
+    /// The snapshot, encoded with the machine's `SnapshotCodec`
+    pub fn encoded_snapshot(&self) -> Result<Vec<u8>, serde_json::Error>
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        self.snapshot()
+            .to_encoded(self.machine().snapshot_codec.as_ref())
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Encode snapshots with `codec` instead of passing them through
+    pub fn with_snapshot_codec(&mut self, codec: Arc<dyn SnapshotCodec>) -> &mut Self {
+        self.snapshot_codec = codec;
+        self
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// The codec set with `StateMachineBuilder::with_snapshot_codec`, to
+    /// share with the stores of contexts and history
+    pub fn snapshot_codec(&self) -> Arc<dyn SnapshotCodec> {
+        self.snapshot_codec.clone()
+    }
+
+    /// Start an instance in the state recorded by `snapshot`, remembering
+    /// the sequences it applied
+    pub fn restore(
+        self: &Arc<Self>,
+        snapshot: InstanceSnapshot<S>,
+    ) -> Result<StateMachineInstance<S, E, C>, RestoreError> {
+        if snapshot.machine_id != self.id() {
+            return Err(RestoreError::MachineMismatch {
+                expected: self.id().to_string(),
+                found: snapshot.machine_id,
+            });
+        }
+        Ok(self.start_sequenced(snapshot.state, snapshot.sequence))
+    }
+
+    /// Start an instance from bytes written by
+    /// `StateMachineInstance::encoded_snapshot`
+    pub fn restore_encoded(
+        self: &Arc<Self>,
+        bytes: &[u8],
+    ) -> Result<StateMachineInstance<S, E, C>, RestoreError>
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        let snapshot = InstanceSnapshot::from_encoded(bytes, self.snapshot_codec.as_ref())?;
+        self.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+    enum Shipment {
+        Packed,
+        InTransit,
+    }
+
+    impl State for Shipment {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum ShipmentEvent {
+        Dispatch,
+    }
+
+    impl Event for ShipmentEvent {}
+
+    fn shipment_machine(id: &str) -> Arc<StateMachine<Shipment, ShipmentEvent, NoContext>> {
+        let mut builder =
+            StateMachineBuilderFactory::create::<Shipment, ShipmentEvent, NoContext>().id(id);
+        builder
+            .external_transition()
+            .from(Shipment::Packed)
+            .to(Shipment::InTransit)
+            .on(ShipmentEvent::Dispatch)
+            .perform(|_s, _e, _c| {});
+        Arc::new(builder.build())
+    }
+
+    #[test]
+    fn test_json_round_trip_and_restore() {
+        let machine = shipment_machine("shipments");
+        let instance = machine.start(Shipment::Packed);
+        instance
+            .process(ShipmentEvent::Dispatch, NoContext)
+            .unwrap();
+
+        let json = instance.snapshot().to_json().unwrap();
+        let restored = machine
+            .restore(InstanceSnapshot::from_json(&json).unwrap())
+            .unwrap();
+        assert!(restored.is_in(&Shipment::InTransit));
+
+        let other = shipment_machine("returns");
+        assert!(matches!(
+            other.restore(instance.snapshot()),
+            Err(RestoreError::MachineMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_restore_keeps_applied_sequences() {
+        let machine = shipment_machine("shipments");
+        let instance = machine.start(Shipment::Packed);
+        instance
+            .fire_event_sequenced(ShipmentEvent::Dispatch, NoContext, 4)
+            .unwrap();
+
+        let json = instance.snapshot().to_json().unwrap();
+        let restored = machine
+            .restore(InstanceSnapshot::from_json(&json).unwrap())
+            .unwrap();
+        assert_eq!(restored.sequence().last(), Some(4));
+        let redelivered = restored.fire_event_sequenced(ShipmentEvent::Dispatch, NoContext, 4);
+        assert!(matches!(
+            redelivered,
+            Err(crate::TransitionError::OutOfOrder { last: 4, .. })
+        ));
+
+        // Snapshots written before sequences were recorded still restore
+        let legacy = r#"{"machine_id":"shipments","state":"InTransit"}"#;
+        let restored = machine
+            .restore(InstanceSnapshot::from_json(legacy).unwrap())
+            .unwrap();
+        assert_eq!(restored.sequence(), SequenceMark::default());
+    }
+
+    #[test]
+    fn test_pass_through_codec_keeps_json() {
+        let machine = shipment_machine("shipments");
+        let instance = machine.start(Shipment::InTransit);
+        let encoded = instance.encoded_snapshot().unwrap();
+        assert_eq!(encoded, instance.snapshot().to_json().unwrap().into_bytes());
+        assert!(machine
+            .restore_encoded(&encoded)
+            .unwrap()
+            .is_in(&Shipment::InTransit));
+    }
+
+    #[cfg(feature = "encryption")]
+    mod encryption {
+        use super::*;
+
+        fn encrypted_machine(
+            key: [u8; 32],
+        ) -> Arc<StateMachine<Shipment, ShipmentEvent, NoContext>> {
+            let mut builder =
+                StateMachineBuilderFactory::create::<Shipment, ShipmentEvent, NoContext>()
+                    .id("shipments");
+            builder.with_snapshot_codec(Arc::new(AesGcmCodec::new(&key)));
+            Arc::new(builder.build())
+        }
+
+        #[test]
+        fn test_encrypted_round_trip() {
+            let machine = encrypted_machine([7; 32]);
+            let encoded = machine
+                .start(Shipment::InTransit)
+                .encoded_snapshot()
+                .unwrap();
+            assert!(!String::from_utf8_lossy(&encoded).contains("InTransit"));
+            assert!(machine
+                .restore_encoded(&encoded)
+                .unwrap()
+                .is_in(&Shipment::InTransit));
+        }
+
+        #[test]
+        fn test_wrong_key_fails_restore() {
+            let encoded = encrypted_machine([7; 32])
+                .start(Shipment::Packed)
+                .encoded_snapshot()
+                .unwrap();
+            assert!(matches!(
+                encrypted_machine([8; 32]).restore_encoded(&encoded),
+                Err(RestoreError::CodecFailure { .. })
+            ));
+        }
+    }
+
+    #[cfg(feature = "binary-snapshots")]
+    mod binary {
+        use super::*;
+
+        fn snapshot() -> InstanceSnapshot<Shipment> {
+            let mut sequence = SequenceMark::default();
+            sequence.record(9);
+            InstanceSnapshot {
+                machine_id: "shipments".to_string(),
+                state: Shipment::InTransit,
+                sequence,
+            }
+        }
+
+        #[test]
+        fn test_binary_round_trip_is_smaller_than_json() {
+            let bytes = snapshot().to_bytes().unwrap();
+            assert_eq!(&bytes[..4], b"RSSM");
+            assert_eq!(
+                InstanceSnapshot::<Shipment>::from_bytes(&bytes).unwrap(),
+                snapshot()
+            );
+            assert!(bytes.len() < snapshot().to_json().unwrap().len());
+        }
+
+        #[test]
+        fn test_corruption_detected() {
+            let mut bytes = snapshot().to_bytes().unwrap();
+            let last_payload = bytes.len() - 5;
+            bytes[last_payload] ^= 0x01;
+            assert_eq!(
+                InstanceSnapshot::<Shipment>::from_bytes(&bytes),
+                Err(RestoreError::Corrupt {
+                    reason: "checksum mismatch".to_string()
+                })
+            );
+            assert!(matches!(
+                InstanceSnapshot::<Shipment>::from_bytes(&bytes[..3]),
+                Err(RestoreError::Corrupt { .. })
+            ));
+        }
+
+        #[test]
+        fn test_future_version_rejected() {
+            let mut bytes = snapshot().to_bytes().unwrap();
+            bytes[4] += 1;
+            assert_eq!(
+                InstanceSnapshot::<Shipment>::from_bytes(&bytes),
+                Err(RestoreError::UnsupportedVersion { version: 3 })
+            );
+        }
+
+        #[test]
+        fn test_version_1_decodes_without_sequence() {
+            // "RSSM", version 1, postcard of ("shipments", InTransit), CRC
+            let mut bytes = b"RSSM\x01\x09shipments\x01".to_vec();
+            let crc32 = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+            let checksum = crc32.checksum(&bytes);
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+            assert_eq!(
+                InstanceSnapshot::<Shipment>::from_bytes(&bytes).unwrap(),
+                InstanceSnapshot {
+                    sequence: SequenceMark::default(),
+                    ..snapshot()
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Report of everything attached to a single state
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::{Context, Event, State, StateMachine};
+
+/// Timeout configured on a state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateTimeout<S, E> {
+    pub after: Duration,
+    pub target: S,
+    pub event: E,
+}
+
+/// Callbacks, timeouts and transitions attached to one state
+///
+/// Subsystems that are not compiled in report as not configured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateConfigReport<S, E> {
+    pub state: S,
+    pub has_entry_action: bool,
+    pub has_exit_action: bool,
+    /// Names of the requirements for entering the state
+    pub requirements: Vec<String>,
+    pub timeout: Option<StateTimeout<S, E>>,
+    /// Events with at least one transition out of the state
+    pub outgoing_events: Vec<E>,
+    /// Events with an async action registered for the state
+    pub async_action_events: Vec<E>,
+}
+
+impl<S: fmt::Debug, E: fmt::Debug> fmt::Display for StateConfigReport<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "State {:?}", self.state)?;
+        writeln!(f, "  entry action: {}", yes_no(self.has_entry_action))?;
+        writeln!(f, "  exit action: {}", yes_no(self.has_exit_action))?;
+        if !self.requirements.is_empty() {
+            writeln!(f, "  requirements: {}", self.requirements.join(", "))?;
+        }
+        match &self.timeout {
+            Some(timeout) => writeln!(
+                f,
+                "  timeout: {:?} -> {:?} on {:?}",
+                timeout.after, timeout.target, timeout.event
+            )?,
+            None => writeln!(f, "  timeout: none")?,
+        }
+        writeln!(f, "  outgoing events: {:?}", self.outgoing_events)?;
+        if !self.async_action_events.is_empty() {
+            writeln!(f, "  async actions on: {:?}", self.async_action_events)?;
+        }
+        Ok(())
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Whether an entry action is registered for `state`
+    pub fn has_entry_action(&self, state: &S) -> bool {
+        #[cfg(feature = "extended")]
+        {
+            self.state_actions.get(state).is_some_and(|actions| {
+                actions.on_entry.is_some()
+                    || actions.on_entry_mut.is_some()
+                    || actions.on_entry_fallible.is_some()
+            })
+        }
+        #[cfg(not(feature = "extended"))]
+        {
+            let _ = state;
+            false
+        }
+    }
+
+    /// Whether an exit action is registered for `state`
+    pub fn has_exit_action(&self, state: &S) -> bool {
+        #[cfg(feature = "extended")]
+        {
+            self.state_actions
+                .get(state)
+                .is_some_and(|actions| actions.on_exit.is_some() || actions.on_exit_mut.is_some())
+        }
+        #[cfg(not(feature = "extended"))]
+        {
+            let _ = state;
+            false
+        }
+    }
+
+    /// Whether an async action is registered for `event` in `state`
+    pub fn has_async_action(&self, state: &S, event: &E) -> bool {
+        #[cfg(feature = "async")]
+        {
+            self.async_actions
+                .contains_key(&(state.clone(), event.clone()))
+        }
+        #[cfg(not(feature = "async"))]
+        {
+            let _ = (state, event);
+            false
+        }
+    }
+
+    /// Timeout configured on `state`
+    pub fn timeout_for(&self, state: &S) -> Option<StateTimeout<S, E>> {
+        #[cfg(feature = "timeout")]
+        {
+            let after = *self.state_timeouts.get(state)?;
+            let (target, event) = self.timeout_transitions.get(state)?;
+            Some(StateTimeout {
+                after,
+                target: target.clone(),
+                event: event.clone(),
+            })
+        }
+        #[cfg(not(feature = "timeout"))]
+        {
+            let _ = state;
+            None
+        }
+    }
+
+    /// Summarize everything attached to `state`
+    pub fn state_configuration(&self, state: &S) -> StateConfigReport<S, E> {
+        let mut outgoing_events: Vec<E> = self
+            .transitions
+            .keys()
+            .filter(|(from, _)| from == state)
+            .map(|(_, event)| event.clone())
+            .collect();
+        outgoing_events.sort_by_key(|event| format!("{:?}", event));
+
+        #[cfg(feature = "async")]
+        let mut async_action_events: Vec<E> = self
+            .async_actions
+            .keys()
+            .filter(|(from, _)| from == state)
+            .map(|(_, event)| event.clone())
+            .collect();
+        #[cfg(not(feature = "async"))]
+        let mut async_action_events: Vec<E> = Vec::new();
+        async_action_events.sort_by_key(|event| format!("{:?}", event));
+
+        #[cfg(feature = "extended")]
+        let requirements = self
+            .state_requirements
+            .get(state)
+            .map(|requirements| requirements.iter().map(|r| r.name.clone()).collect())
+            .unwrap_or_default();
+        #[cfg(not(feature = "extended"))]
+        let requirements = Vec::new();
+
+        StateConfigReport {
+            state: state.clone(),
+            has_entry_action: self.has_entry_action(state),
+            has_exit_action: self.has_exit_action(state),
+            requirements,
+            timeout: self.timeout_for(state),
+            outgoing_events,
+            async_action_events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Call {
+        Ringing,
+        Connected,
+        Missed,
+    }
+
+    impl State for Call {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum CallEvent {
+        Answer,
+        Timeout,
+        HangUp,
+    }
+
+    impl Event for CallEvent {}
+
+    #[allow(unused_mut)]
+    fn call_machine() -> StateMachine<Call, CallEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Call, CallEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Call::Ringing)
+            .to(Call::Connected)
+            .on(CallEvent::Answer)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Call::Ringing)
+            .to(Call::Missed)
+            .on(CallEvent::Timeout)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Call::Connected)
+            .to(Call::Missed)
+            .on(CallEvent::HangUp)
+            .perform(|_s, _e, _c| {});
+        #[cfg(feature = "timeout")]
+        builder.with_state_timeout(
+            Call::Ringing,
+            Duration::from_secs(30),
+            Call::Missed,
+            CallEvent::Timeout,
+        );
+        let mut machine = builder.build();
+        #[cfg(feature = "extended")]
+        machine.add_entry_action(Call::Ringing, |_s, _c| {});
+        machine
+    }
+
+    #[test]
+    fn test_configured_state_report() {
+        let machine = call_machine();
+        let report = machine.state_configuration(&Call::Ringing);
+
+        assert_eq!(report.has_entry_action, cfg!(feature = "extended"));
+        assert!(!report.has_exit_action);
+        assert_eq!(
+            report.outgoing_events,
+            vec![CallEvent::Answer, CallEvent::Timeout]
+        );
+        #[cfg(feature = "timeout")]
+        assert_eq!(
+            report.timeout,
+            Some(StateTimeout {
+                after: Duration::from_secs(30),
+                target: Call::Missed,
+                event: CallEvent::Timeout,
+            })
+        );
+        assert!(report.to_string().starts_with("State Ringing\n"));
+    }
+
+    #[test]
+    fn test_bare_state_report() {
+        let machine = call_machine();
+        let report = machine.state_configuration(&Call::Missed);
+
+        assert!(!report.has_entry_action && !report.has_exit_action);
+        assert!(report.timeout.is_none());
+        assert!(report.outgoing_events.is_empty());
+        assert_eq!(
+            report.to_string(),
+            "State Missed\n  entry action: no\n  exit action: no\n  timeout: none\n  outgoing events: []\n"
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Transition patterns stamped out once per parameter set
+//!
+//! A `TransitionTemplate` describes external transitions whose states,
+//! events, guards and actions are computed from a parameter value.
+//! `StateMachineBuilder::apply_template` registers the concrete transitions
+//! for one parameter set, tagging each with `"<template>/<instance>"` so
+//! exports can group them.
+
+use std::sync::Arc;
+
+use crate::callbacks::{Action, Guard};
+use crate::definition::CallbackNames;
+use crate::info::InfoAction;
+use crate::{
+    Conditions, Context, Event, State, StateMachineBuilder, Transition, TransitionInfo,
+    TransitionType,
+};
+
+/// Parameter set of a template instance
+pub trait TemplateParams: Send + Sync + 'static {
+    /// Name of this instance, used in the tag of its transitions
+    fn instance_name(&self) -> String;
+}
+
+type Hole<P, T> = Arc<dyn Fn(&P) -> T + Send + Sync>;
+type TemplateCondition<S, E, C, P> = Arc<dyn Fn(&P, &S, &E, &C) -> bool + Send + Sync>;
+type TemplateAction<S, E, C, P> = Arc<dyn Fn(&P, &S, &E, &C) + Send + Sync>;
+type TemplateInfoAction<S, E, C, P> = Arc<dyn Fn(&P, &TransitionInfo<'_, S, E>, &C) + Send + Sync>;
+
+/// One transition of a template, with its parts taken from the parameters
+pub struct TemplateTransition<S, E, C, P> {
+    from: Hole<P, S>,
+    to: Hole<P, S>,
+    event: Hole<P, E>,
+    condition: Option<TemplateCondition<S, E, C, P>>,
+    action: Option<TemplateAction<S, E, C, P>>,
+    info_action: Option<TemplateInfoAction<S, E, C, P>>,
+}
+
+impl<S, E, C, P> TemplateTransition<S, E, C, P> {
+    pub fn new<F, T, V>(from: F, to: T, on: V) -> Self
+    where
+        F: Fn(&P) -> S + Send + Sync + 'static,
+        T: Fn(&P) -> S + Send + Sync + 'static,
+        V: Fn(&P) -> E + Send + Sync + 'static,
+    {
+        TemplateTransition {
+            from: Arc::new(from),
+            to: Arc::new(to),
+            event: Arc::new(on),
+            condition: None,
+            action: None,
+            info_action: None,
+        }
+    }
+
+    pub fn when<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&P, &S, &E, &C) -> bool + Send + Sync + 'static,
+    {
+        self.condition = Some(Arc::new(condition));
+        self
+    }
+
+    pub fn perform<F>(mut self, action: F) -> Self
+    where
+        F: Fn(&P, &S, &E, &C) + Send + Sync + 'static,
+    {
+        self.action = Some(Arc::new(action));
+        self
+    }
+
+    /// Like `perform`, with the transition metadata, whose `tag` names the
+    /// template instance
+    pub fn perform_with_info<F>(mut self, action: F) -> Self
+    where
+        F: Fn(&P, &TransitionInfo<'_, S, E>, &C) + Send + Sync + 'static,
+    {
+        self.info_action = Some(Arc::new(action));
+        self
+    }
+}
+
+/// Named list of template transitions
+pub struct TransitionTemplate<S, E, C, P> {
+    name: String,
+    transitions: Vec<TemplateTransition<S, E, C, P>>,
+}
+
+impl<S, E, C, P> TransitionTemplate<S, E, C, P> {
+    pub fn new(name: impl Into<String>) -> Self {
+        TransitionTemplate {
+            name: name.into(),
+            transitions: Vec::new(),
+        }
+    }
+
+    pub fn transition(mut self, transition: TemplateTransition<S, E, C, P>) -> Self {
+        self.transitions.push(transition);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State + 'static,
+    E: Event + 'static,
+    C: Context + 'static,
+{
+    /// Register the transitions of `template` for one parameter set
+    pub fn apply_template<P>(
+        &mut self,
+        template: &TransitionTemplate<S, E, C, P>,
+        params: P,
+    ) -> &mut Self
+    where
+        P: TemplateParams,
+    {
+        let params = Arc::new(params);
+        let tag = format!("{}/{}", template.name, params.instance_name());
+        for step in &template.transitions {
+            let condition = step.condition.clone().map(|condition| {
+                let params = params.clone();
+                Conditions::of(move |s, e, c| condition(&params, s, e, c))
+            });
+            let action = match (step.action.clone(), step.info_action.clone()) {
+                (Some(action), _) => {
+                    let params = params.clone();
+                    Action::Plain(Arc::new(move |s, e, c| action(&params, s, e, c)))
+                }
+                (None, Some(action)) => {
+                    let params = params.clone();
+                    let action: InfoAction<S, E, C> = Arc::new(move |info, c, _| {
+                        action(&params, info, c);
+                        Ok(())
+                    });
+                    Action::Info(action)
+                }
+                (None, None) => Action::Nothing,
+            };
+            self.add_transition(Transition {
+                from: (step.from)(&params),
+                to: (step.to)(&params),
+                event: (step.event)(&params),
+                guard: Guard::from_parts(condition, None),
+                action,
+                transition_type: TransitionType::External,
+                required_flag: None,
+                approval: None,
+                pure_action: false,
+                irreversible: false,
+                tag: Some(tag.clone()),
+                names: CallbackNames::default(),
+                #[cfg(feature = "guards")]
+                priority: 0,
+            });
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StateMachine, StateMachineBuilderFactory};
+
+    #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+    enum Document {
+        Draft(u8),
+        Pending(u8),
+        Approved(u8),
+        Rejected(u8),
+    }
+
+    impl State for Document {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Review {
+        Submit,
+        Approve,
+        Reject,
+    }
+
+    impl Event for Review {}
+
+    #[derive(Debug, Clone)]
+    struct Expense {
+        amount: u64,
+    }
+
+    impl Context for Expense {}
+
+    // Approval level, allowed to approve up to `limit`
+    struct Level {
+        level: u8,
+        limit: u64,
+    }
+
+    impl TemplateParams for Level {
+        fn instance_name(&self) -> String {
+            format!("level-{}", self.level)
+        }
+    }
+
+    fn approval_machine() -> StateMachine<Document, Review, Expense> {
+        let template = TransitionTemplate::new("approval")
+            .transition(TemplateTransition::new(
+                |p: &Level| Document::Draft(p.level),
+                |p| Document::Pending(p.level),
+                |_| Review::Submit,
+            ))
+            .transition(
+                TemplateTransition::new(
+                    |p: &Level| Document::Pending(p.level),
+                    |p| Document::Approved(p.level),
+                    |_| Review::Approve,
+                )
+                .when(|p, _s, _e, c: &Expense| c.amount <= p.limit),
+            )
+            .transition(TemplateTransition::new(
+                |p: &Level| Document::Pending(p.level),
+                |p| Document::Rejected(p.level),
+                |_| Review::Reject,
+            ));
+
+        let mut builder = StateMachineBuilderFactory::create::<Document, Review, Expense>();
+        builder
+            .apply_template(
+                &template,
+                Level {
+                    level: 1,
+                    limit: 1_000,
+                },
+            )
+            .apply_template(
+                &template,
+                Level {
+                    level: 2,
+                    limit: 10_000,
+                },
+            )
+            .apply_template(
+                &template,
+                Level {
+                    level: 3,
+                    limit: 100_000,
+                },
+            );
+        builder.build()
+    }
+
+    #[test]
+    fn test_template_applied_per_level() {
+        let machine = approval_machine();
+        let transitions: Vec<_> = machine
+            .transitions
+            .values()
+            .flat_map(|t| t.iter())
+            .collect();
+        assert_eq!(transitions.len(), 9);
+        assert!(transitions.iter().all(|t| t
+            .tag
+            .as_deref()
+            .is_some_and(|tag| tag.starts_with("approval/level-"))));
+
+        let expense = Expense { amount: 5_000 };
+        assert_eq!(
+            machine
+                .fire_event(Document::Draft(2), Review::Submit, expense.clone())
+                .unwrap(),
+            Document::Pending(2)
+        );
+        assert!(machine
+            .fire_event(Document::Pending(1), Review::Approve, expense.clone())
+            .is_err());
+        assert_eq!(
+            machine
+                .fire_event(Document::Pending(2), Review::Approve, expense.clone())
+                .unwrap(),
+            Document::Approved(2)
+        );
+        assert_eq!(
+            machine
+                .fire_event(Document::Pending(3), Review::Reject, expense)
+                .unwrap(),
+            Document::Rejected(3)
+        );
+    }
+
+    #[test]
+    fn test_shared_action_sees_template_tag() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let audit = log.clone();
+        let template = TransitionTemplate::new("approval").transition(
+            TemplateTransition::new(
+                |p: &Level| Document::Pending(p.level),
+                |p| Document::Rejected(p.level),
+                |_| Review::Reject,
+            )
+            .perform_with_info(move |_p, info, _c: &Expense| {
+                audit
+                    .lock()
+                    .unwrap()
+                    .push(format!("{:?} {:?}", info.tag, info.to));
+            }),
+        );
+        let mut builder = StateMachineBuilderFactory::create::<Document, Review, Expense>();
+        builder.apply_template(&template, Level { level: 4, limit: 0 });
+        let machine = builder.build();
+        machine
+            .fire_event(Document::Pending(4), Review::Reject, Expense { amount: 1 })
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["Some(\"approval/level-4\") Rejected(4)"]
+        );
+    }
+
+    #[cfg(feature = "visualization")]
+    #[test]
+    fn test_dot_groups_template_instances() {
+        let dot = approval_machine().to_dot();
+        assert!(dot.contains("label=\"approval/level-1\""));
+        assert_eq!(dot.matches("subgraph cluster_").count(), 3);
+    }
+}
@@ -0,0 +1,206 @@
+//! Step-by-step recording of what a single `fire_event` call did
+//!
+//! The recording is made by the same pipeline that serves `fire_event`, so a
+//! trace reflects exactly the callbacks a normal fire would run. Traces are
+//! plain data and can be compared or serialized for snapshot tests.
+//! States and events are named with the interned names of the machine, see
+//! `StateMachine::state_name`. `StateMachine::trace_fire` requires the
+//! `test-util` feature.
+
+use std::sync::Arc;
+
+#[cfg(feature = "test-util")]
+use crate::{Context, Event, State, StateMachine};
+
+/// One internal step of an event firing
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TraceStep {
+    /// Exit action of the source state ran
+    ExitAction { state: Arc<str> },
+    /// A runtime override routed the event to `to`
+    Override { to: Arc<str> },
+    /// Feature flag required by a candidate was looked up
+    FlagCheck { flag: String, enabled: bool },
+    /// Guard of the candidate at `candidate` was evaluated
+    Guard {
+        candidate: usize,
+        to: Arc<str>,
+        passed: bool,
+    },
+    /// A requirement of the target state was not met
+    RequirementFailed {
+        state: Arc<str>,
+        requirement: String,
+    },
+    /// Action of the selected transition ran
+    Action {
+        from: Arc<str>,
+        to: Arc<str>,
+        event: Arc<str>,
+    },
+    /// Fail callback ran because no candidate was taken
+    FailCallback,
+    /// A completion transition moved on from `from` without an event
+    CompletionTransition { from: Arc<str>, to: Arc<str> },
+    /// Entry action of the target state ran
+    EntryAction { state: Arc<str> },
+    /// Transition record was appended to the history
+    HistoryWrite { success: bool },
+    /// Metrics were updated
+    MetricsWrite { success: bool },
+    /// The fire completed in state `to`
+    Completed { to: Arc<str> },
+    /// The fire was rejected with `error`
+    Failed { error: String },
+}
+
+/// Ordered steps taken by one event firing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecutionTrace {
+    pub steps: Vec<TraceStep>,
+}
+
+// Append a step when tracing; `step` is not evaluated otherwise
+pub(crate) fn record(trace: &mut Option<&mut ExecutionTrace>, step: impl FnOnce() -> TraceStep) {
+    if let Some(trace) = trace {
+        trace.steps.push(step());
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Fire an event like `fire_event` and return the steps it took
+    ///
+    /// The transition really happens: actions run and history and metrics
+    /// are written exactly as for `fire_event`.
+    pub fn trace_fire(&self, from: S, event: E, mut context: C) -> ExecutionTrace {
+        let mut trace = ExecutionTrace::default();
+        let _ = self.fire_traced(from, event, &mut context, Some(&mut trace), None, None);
+        trace
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::StateMachineBuilderFactory;
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Door {
+        Closed,
+        Open,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Open,
+        Knock,
+        Kick,
+    }
+
+    impl Event for DoorEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Visitor {
+        has_key: bool,
+    }
+
+    impl Context for Visitor {}
+
+    fn door_machine() -> StateMachine<Door, DoorEvent, Visitor> {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, Visitor>();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Open)
+            .when(|_s, _e, c| c.has_key)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(Door::Closed)
+            .on(DoorEvent::Knock)
+            .perform(|_s, _e, _c| {});
+        builder.set_fail_callback(Arc::new(|_s, _e, _c| {}));
+        builder.build()
+    }
+
+    fn outcome(trace: &ExecutionTrace) -> &TraceStep {
+        trace.steps.last().unwrap()
+    }
+
+    #[test]
+    fn test_trace_paths() {
+        let machine = door_machine();
+        let owner = Visitor { has_key: true };
+        let stranger = Visitor { has_key: false };
+
+        let external = machine.trace_fire(Door::Closed, DoorEvent::Open, owner.clone());
+        assert!(external.steps.contains(&TraceStep::Guard {
+            candidate: 0,
+            to: "Open".into(),
+            passed: true,
+        }));
+        assert!(external.steps.contains(&TraceStep::Action {
+            from: "Closed".into(),
+            to: "Open".into(),
+            event: "Open".into(),
+        }));
+        assert_eq!(
+            outcome(&external),
+            &TraceStep::Completed { to: "Open".into() }
+        );
+
+        let internal = machine.trace_fire(Door::Closed, DoorEvent::Knock, stranger.clone());
+        assert_eq!(
+            outcome(&internal),
+            &TraceStep::Completed {
+                to: "Closed".into()
+            }
+        );
+
+        let rejected = machine.trace_fire(Door::Closed, DoorEvent::Open, stranger.clone());
+        let guard = rejected
+            .steps
+            .iter()
+            .position(|step| matches!(step, TraceStep::Guard { passed: false, .. }))
+            .unwrap();
+        let fail = rejected
+            .steps
+            .iter()
+            .position(|step| step == &TraceStep::FailCallback)
+            .unwrap();
+        assert!(guard < fail);
+        assert!(!rejected
+            .steps
+            .iter()
+            .any(|step| matches!(step, TraceStep::Action { .. })));
+        assert!(matches!(outcome(&rejected), TraceStep::Failed { .. }));
+
+        let unknown = machine.trace_fire(Door::Open, DoorEvent::Kick, stranger);
+        assert!(unknown.steps.contains(&TraceStep::FailCallback));
+        assert!(matches!(outcome(&unknown), TraceStep::Failed { .. }));
+    }
+
+    #[test]
+    fn test_trace_is_stable() {
+        let first = door_machine();
+        let second = door_machine();
+        for context in [Visitor { has_key: true }, Visitor { has_key: false }] {
+            assert_eq!(
+                first.trace_fire(Door::Closed, DoorEvent::Open, context.clone()),
+                second.trace_fire(Door::Closed, DoorEvent::Open, context)
+            );
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Metrics of each transition (requires the `metrics` feature)
+//!
+//! `StateMachineMetrics::transitions` keeps, next to the aggregate counters,
+//! the outcomes and durations of the fires of every source state, event and
+//! target state, keyed by the machine's own types, so guarded transitions
+//! sharing a source and event are told apart. A fire that fails stays in
+//! its source state and is counted under `(from, event, from)`, whether or
+//! not the definition knows the pair.
+
+use std::cmp::Reverse;
+use std::time::Duration;
+
+use crate::{Event, State, StateMachineMetrics};
+
+/// Outcomes and durations of the fires of one `(from, event, to)` transition
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransitionStats {
+    pub successes: u64,
+    pub failures: u64,
+    pub total_duration: Duration,
+    pub min_duration: Option<Duration>,
+    pub max_duration: Option<Duration>,
+}
+
+impl TransitionStats {
+    pub fn attempts(&self) -> u64 {
+        self.successes + self.failures
+    }
+
+    /// Share of the attempts that failed, 0 without attempts
+    pub fn failure_rate(&self) -> f64 {
+        match self.attempts() {
+            0 => 0.0,
+            attempts => self.failures as f64 / attempts as f64,
+        }
+    }
+
+    pub fn average_duration(&self) -> Option<Duration> {
+        match self.attempts() {
+            0 => None,
+            attempts => Some(self.total_duration / attempts as u32),
+        }
+    }
+
+    pub(crate) fn record(&mut self, success: bool, duration: Duration) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+        self.total_duration += duration;
+        self.min_duration = Some(self.min_duration.map_or(duration, |min| min.min(duration)));
+        self.max_duration = Some(self.max_duration.map_or(duration, |max| max.max(duration)));
+    }
+
+    /// Add the fires counted by `other`
+    pub(crate) fn merge(&mut self, other: &TransitionStats) {
+        self.successes += other.successes;
+        self.failures += other.failures;
+        self.total_duration += other.total_duration;
+        self.min_duration = match (self.min_duration, other.min_duration) {
+            (Some(own), Some(other)) => Some(own.min(other)),
+            (own, other) => own.or(other),
+        };
+        self.max_duration = self.max_duration.max(other.max_duration);
+    }
+}
+
+impl<S, E> StateMachineMetrics<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Statistics of the fires of `event` from `from` that ended in `to`,
+    /// `None` if there was none
+    pub fn for_transition(&self, from: &S, event: &E, to: &S) -> Option<&TransitionStats> {
+        self.transitions
+            .get(&(from.clone(), event.clone(), to.clone()))
+    }
+
+    /// Statistics of all fires of `event` from `from`, whatever their
+    /// target, `None` if there was none
+    pub fn for_pair(&self, from: &S, event: &E) -> Option<TransitionStats> {
+        self.transitions
+            .iter()
+            .filter(|((source, fired, _), _)| source == from && fired == event)
+            .map(|(_, stats)| stats)
+            .fold(None, |total: Option<TransitionStats>, stats| {
+                let mut total = total.unwrap_or_default();
+                total.merge(stats);
+                Some(total)
+            })
+    }
+
+    /// The `n` transitions with the most failures, most failing first
+    ///
+    /// Transitions that never failed are left out.
+    pub fn top_failing_transitions(&self, n: usize) -> Vec<(&(S, E, S), &TransitionStats)> {
+        let mut failing: Vec<_> = self
+            .transitions
+            .iter()
+            .filter(|(_, stats)| stats.failures > 0)
+            .collect();
+        failing.sort_by_key(|(_, stats)| Reverse(stats.failures));
+        failing.truncate(n);
+        failing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, StateMachineBuilderFactory};
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Door {
+        Closed,
+        Open,
+        Locked,
+    }
+
+    impl State for Door {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum DoorEvent {
+        Open,
+        Close,
+        Lock,
+    }
+
+    impl Event for DoorEvent {}
+
+    #[derive(Debug, Clone)]
+    struct Key {
+        fits: bool,
+    }
+
+    impl Context for Key {}
+
+    #[test]
+    fn test_stats_by_transition() {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, Key>();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Open)
+            .when(|_s, _e, key| key.fits)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Door::Open)
+            .to(Door::Closed)
+            .on(DoorEvent::Close)
+            .add();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Locked)
+            .on(DoorEvent::Lock)
+            .add();
+        let machine = builder.build();
+
+        for fits in [true, false, false, true, false] {
+            let _ = machine.fire_event(Door::Closed, DoorEvent::Open, Key { fits });
+        }
+        machine
+            .fire_event(Door::Open, DoorEvent::Close, Key { fits: true })
+            .unwrap();
+        assert!(machine
+            .fire_event(Door::Locked, DoorEvent::Open, Key { fits: true })
+            .is_err());
+
+        let metrics = machine.get_metrics();
+        assert_eq!(metrics.total_transitions, 7);
+        let opened = metrics
+            .for_transition(&Door::Closed, &DoorEvent::Open, &Door::Open)
+            .unwrap();
+        assert_eq!((opened.successes, opened.failures), (2, 0));
+        let rejected = metrics
+            .for_transition(&Door::Closed, &DoorEvent::Open, &Door::Closed)
+            .unwrap();
+        assert_eq!((rejected.successes, rejected.failures), (0, 3));
+        let open = metrics.for_pair(&Door::Closed, &DoorEvent::Open).unwrap();
+        assert_eq!(open.attempts(), 5);
+        assert!((open.failure_rate() - 0.6).abs() < 1e-9);
+        assert!(open.min_duration <= open.max_duration);
+        assert!(metrics.for_pair(&Door::Closed, &DoorEvent::Lock).is_none());
+
+        let top = metrics.top_failing_transitions(5);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, &(Door::Closed, DoorEvent::Open, Door::Closed));
+        assert_eq!(top[1].0, &(Door::Locked, DoorEvent::Open, Door::Locked));
+        assert_eq!(metrics.top_failing_transitions(1).len(), 1);
+    }
+
+    #[test]
+    fn test_guarded_targets_counted_apart() {
+        let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, Key>();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Open)
+            .on(DoorEvent::Lock)
+            .when(|_s, _e, key| !key.fits)
+            .add();
+        builder
+            .external_transition()
+            .from(Door::Closed)
+            .to(Door::Locked)
+            .on(DoorEvent::Lock)
+            .when(|_s, _e, key| key.fits)
+            .add();
+        let machine = builder.build();
+
+        for fits in [true, true, false] {
+            machine
+                .fire_event(Door::Closed, DoorEvent::Lock, Key { fits })
+                .unwrap();
+        }
+
+        let metrics = machine.get_metrics();
+        let locked = metrics
+            .for_transition(&Door::Closed, &DoorEvent::Lock, &Door::Locked)
+            .unwrap();
+        assert_eq!(locked.successes, 2);
+        let opened = metrics
+            .for_transition(&Door::Closed, &DoorEvent::Lock, &Door::Open)
+            .unwrap();
+        assert_eq!(opened.successes, 1);
+        assert_eq!(
+            metrics
+                .for_pair(&Door::Closed, &DoorEvent::Lock)
+                .unwrap()
+                .successes,
+            3
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_extremes() {
+        let mut stats = TransitionStats::default();
+        stats.record(true, Duration::from_millis(5));
+        let mut other = TransitionStats::default();
+        other.record(false, Duration::from_millis(2));
+        other.record(true, Duration::from_millis(9));
+        stats.merge(&other);
+        stats.merge(&TransitionStats::default());
+
+        assert_eq!((stats.successes, stats.failures), (2, 1));
+        assert_eq!(stats.total_duration, Duration::from_millis(16));
+        assert_eq!(stats.min_duration, Some(Duration::from_millis(2)));
+        assert_eq!(stats.max_duration, Some(Duration::from_millis(9)));
+        assert_eq!(
+            stats.average_duration(),
+            Some(Duration::from_millis(16) / 3)
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! Which transitions the recorded history never exercised (requires the
+//! `history` feature)
+//!
+//! `StateMachine::unused_transitions` compares the definition with the
+//! successful fires in the history, optionally only those within a recent
+//! window measured with the machine's clock. A fire counts for the
+//! transition matching its source, event and recorded target. Fires that
+//! went on through completion transitions record the state they settled in,
+//! so they only count for transitions ending there.
+//!
+//! Unused transitions are labeled with why: when an initial state is
+//! declared, those leaving a state no transition path reaches from it are
+//! `Unreachable` and can go; the others are merely `NeverFired`.
+
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use crate::{Context, Event, State, StateMachine, Transition};
+
+/// Why a transition shows up as unused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnusedReason {
+    /// Reachable, but not fired in the window
+    NeverFired,
+    /// Leaves a state unreachable from the initial state
+    Unreachable,
+}
+
+/// A transition of the definition that was not fired
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransitionRef<S, E> {
+    /// Source state, `None` for `from_any` transitions
+    pub from: Option<S>,
+    pub event: E,
+    pub to: S,
+    pub reason: UnusedReason,
+}
+
+/// Share of the definition the history exercised
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UsageReport<S, E> {
+    pub total_transitions: usize,
+    pub fired_transitions: usize,
+    /// Percentage of transitions fired, 100 for a machine without any
+    pub fired_percent: f64,
+    pub unused_percent: f64,
+    pub unused: Vec<TransitionRef<S, E>>,
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Transitions without a successful fire in the history, or within the
+    /// last `since` of it
+    ///
+    /// Sorted by source, event and target name.
+    pub fn unused_transitions(&self, since: Option<Duration>) -> Vec<TransitionRef<S, E>> {
+        self.usage_report(since).unused
+    }
+
+    /// `unused_transitions` with the share of the definition they make up
+    pub fn usage_report(&self, since: Option<Duration>) -> UsageReport<S, E> {
+        let now = self.clock.now();
+        let fired: Vec<(S, E, S)> = self.recording.with_history(|records| {
+            records
+                .iter()
+                .filter(|record| record.success)
+                .filter(|record| {
+                    since.is_none_or(|window| now.duration_since(record.timestamp) <= window)
+                })
+                .map(|record| (record.from.clone(), record.event.clone(), record.to.clone()))
+                .collect()
+        });
+
+        let reached = self.reached_states();
+        let mut total_transitions = 0;
+        let mut unused = Vec::new();
+        for transition in self
+            .transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+        {
+            total_transitions += 1;
+            if fired.iter().any(|record| is_fire_of(transition, record)) {
+                continue;
+            }
+            let reason = match &reached {
+                Some(reached) if !reached.contains(&transition.from) => UnusedReason::Unreachable,
+                _ => UnusedReason::NeverFired,
+            };
+            unused.push(TransitionRef {
+                from: Some(transition.from.clone()),
+                event: transition.event.clone(),
+                to: transition.to.clone(),
+                reason,
+            });
+        }
+        for transition in self
+            .wildcard_transitions
+            .values()
+            .flat_map(|candidates| candidates.iter())
+        {
+            total_transitions += 1;
+            let fired_here = fired.iter().any(|(from, event, to)| {
+                *event == transition.event
+                    && *to == transition.to
+                    && !self
+                        .transitions
+                        .contains_key(&(from.clone(), event.clone()))
+            });
+            if !fired_here {
+                unused.push(TransitionRef {
+                    from: None,
+                    event: transition.event.clone(),
+                    to: transition.to.clone(),
+                    reason: UnusedReason::NeverFired,
+                });
+            }
+        }
+        unused.sort_by_cached_key(|t| format!("{:?} {:?} {:?}", t.from, t.event, t.to));
+
+        let fired_transitions = total_transitions - unused.len();
+        let fired_percent = if total_transitions == 0 {
+            100.0
+        } else {
+            fired_transitions as f64 * 100.0 / total_transitions as f64
+        };
+        UsageReport {
+            total_transitions,
+            fired_transitions,
+            fired_percent,
+            unused_percent: 100.0 - fired_percent,
+            unused,
+        }
+    }
+
+    // States reachable from the initial state, if one is declared
+    fn reached_states(&self) -> Option<HashSet<&S>> {
+        let initial = self.initial_state.as_ref()?;
+        let mut reached = HashSet::from([initial]);
+        let mut queue = VecDeque::from([initial]);
+        while let Some(state) = queue.pop_front() {
+            let mut targets: Vec<&S> = self
+                .transitions
+                .values()
+                .flat_map(|candidates| candidates.iter())
+                .filter(|t| t.from == *state)
+                .map(|t| &t.to)
+                .collect();
+            if !self.is_terminal(state) {
+                targets.extend(
+                    self.wildcard_transitions
+                        .values()
+                        .flat_map(|candidates| candidates.iter())
+                        .filter(|t| self.is_event_allowed(state, &t.event))
+                        .map(|t| &t.to),
+                );
+            }
+            if let Some(completions) = self.completion_transitions.get(state) {
+                targets.extend(completions.iter().map(|t| &t.to));
+            }
+            #[cfg(feature = "timeout")]
+            targets.extend(self.timeout_transitions.get(state).map(|(to, _)| to));
+            for target in targets {
+                if reached.insert(target) {
+                    queue.push_back(target);
+                }
+            }
+        }
+        Some(reached)
+    }
+}
+
+fn is_fire_of<S, E, C>(transition: &Transition<S, E, C>, (from, event, to): &(S, E, S)) -> bool
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    transition.from == *from && transition.event == *event && transition.to == *to
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::NoContext;
+    use crate::StateMachineBuilderFactory;
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum Ticket {
+        Open,
+        Assigned,
+        Resolved,
+        Reopened,
+        Escalated,
+        Closed,
+    }
+
+    impl State for Ticket {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum TicketEvent {
+        Assign,
+        Resolve,
+        Reopen,
+        Escalate,
+        Close,
+    }
+
+    impl Event for TicketEvent {}
+
+    fn ticket_machine() -> StateMachine<Ticket, TicketEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Ticket, TicketEvent, NoContext>();
+        builder.initial_state(Ticket::Open);
+        let edges = [
+            (Ticket::Open, TicketEvent::Assign, Ticket::Assigned),
+            (Ticket::Assigned, TicketEvent::Resolve, Ticket::Resolved),
+            (Ticket::Resolved, TicketEvent::Reopen, Ticket::Reopened),
+            (Ticket::Resolved, TicketEvent::Close, Ticket::Closed),
+            (Ticket::Escalated, TicketEvent::Resolve, Ticket::Resolved),
+        ];
+        for (from, event, to) in edges {
+            builder
+                .external_transition()
+                .from(from)
+                .to(to)
+                .on(event)
+                .add();
+        }
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Ticket::Escalated)
+            .on(TicketEvent::Escalate)
+            .add();
+        builder.build()
+    }
+
+    #[test]
+    fn test_reports_untouched_transitions_with_labels() {
+        let machine = ticket_machine();
+        machine
+            .fire_event(Ticket::Open, TicketEvent::Assign, NoContext)
+            .unwrap();
+        machine
+            .fire_event(Ticket::Assigned, TicketEvent::Resolve, NoContext)
+            .unwrap();
+        machine
+            .fire_event(Ticket::Resolved, TicketEvent::Close, NoContext)
+            .unwrap();
+        // Failures don't count as use
+        assert!(machine
+            .fire_event(Ticket::Open, TicketEvent::Reopen, NoContext)
+            .is_err());
+
+        let unused = machine.unused_transitions(None);
+        assert_eq!(
+            unused,
+            vec![
+                TransitionRef {
+                    from: None,
+                    event: TicketEvent::Escalate,
+                    to: Ticket::Escalated,
+                    reason: UnusedReason::NeverFired,
+                },
+                TransitionRef {
+                    from: Some(Ticket::Escalated),
+                    event: TicketEvent::Resolve,
+                    to: Ticket::Resolved,
+                    reason: UnusedReason::NeverFired,
+                },
+                TransitionRef {
+                    from: Some(Ticket::Resolved),
+                    event: TicketEvent::Reopen,
+                    to: Ticket::Reopened,
+                    reason: UnusedReason::NeverFired,
+                },
+            ]
+        );
+
+        let report = machine.usage_report(Some(Duration::from_secs(3600)));
+        assert_eq!(report.total_transitions, 6);
+        assert_eq!(report.fired_transitions, 3);
+        assert_eq!(report.fired_percent, 50.0);
+        assert_eq!(report.unused, unused);
+    }
+
+    #[test]
+    fn test_unreachable_source_labeled() {
+        let mut builder = StateMachineBuilderFactory::create::<Ticket, TicketEvent, NoContext>();
+        builder.initial_state(Ticket::Open);
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Assigned)
+            .on(TicketEvent::Assign)
+            .add();
+        builder
+            .external_transition()
+            .from(Ticket::Escalated)
+            .to(Ticket::Resolved)
+            .on(TicketEvent::Resolve)
+            .add();
+        let machine = builder.build();
+        assert_eq!(
+            machine.unused_transitions(None),
+            vec![
+                TransitionRef {
+                    from: Some(Ticket::Escalated),
+                    event: TicketEvent::Resolve,
+                    to: Ticket::Resolved,
+                    reason: UnusedReason::Unreachable,
+                },
+                TransitionRef {
+                    from: Some(Ticket::Open),
+                    event: TicketEvent::Assign,
+                    to: Ticket::Assigned,
+                    reason: UnusedReason::NeverFired,
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_window_excludes_older_fires() {
+        let clock = std::sync::Arc::new(crate::MockClock::new());
+        let mut builder = StateMachineBuilderFactory::create::<Ticket, TicketEvent, NoContext>();
+        builder.with_clock(clock.clone());
+        builder
+            .external_transition()
+            .from(Ticket::Open)
+            .to(Ticket::Assigned)
+            .on(TicketEvent::Assign)
+            .add();
+        let machine = builder.build();
+        machine
+            .fire_event(Ticket::Open, TicketEvent::Assign, NoContext)
+            .unwrap();
+        clock.advance(Duration::from_secs(120));
+        assert!(machine
+            .unused_transitions(Some(Duration::from_secs(300)))
+            .is_empty());
+        assert_eq!(
+            machine
+                .unused_transitions(Some(Duration::from_secs(60)))
+                .len(),
+            1
+        );
+    }
+}
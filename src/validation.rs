@@ -0,0 +1,903 @@
+//! Checks on a machine definition that don't depend on any context
+//!
+//! `StateMachine::validate` reports findings without changing behavior.
+//! `StateMachineBuilder::try_build` checks the definition before building
+//! and returns every problem it finds:
+//!
+//! - transition builders finalized without a source state, target state or
+//!   event
+//! - names `when_named` and `perform_named` refer to that aren't bound, and
+//!   anonymous closures when opted out of with `require_named_callbacks`
+//! - transitions on events a locked state doesn't allow, and transitions out
+//!   of terminal states
+//! - identical transitions registered twice, and transitions that can never
+//!   be taken because an unguarded candidate of the same `(from, event)`
+//!   pair is evaluated first
+//! - external transitions back to their source state, which should be
+//!   internal ones
+//! - states the declared initial state cannot reach
+//! - states that can't be left but aren't declared terminal
+//! - services declared with `requires_service` but not provided
+//! - name collisions, when opted into with `fail_on_name_collision`
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::{
+    BindingKind, Context, Event, State, StateMachine, StateMachineBuilder, Transition,
+    TransitionType,
+};
+
+/// Whether a name belongs to a state or an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameKind {
+    State,
+    Event,
+}
+
+impl fmt::Display for NameKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameKind::State => write!(f, "state"),
+            NameKind::Event => write!(f, "event"),
+        }
+    }
+}
+
+/// A finding of `StateMachine::validate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /// `count` distinct values render as the same `name`, so they merge into
+    /// one node in exports and one key in metrics
+    NameCollision {
+        kind: NameKind,
+        name: String,
+        count: usize,
+    },
+    /// More than one unguarded candidate for a `(from, event)` pair, so the
+    /// outcome depends on registration order rather than the context
+    Nondeterministic {
+        from: String,
+        event: String,
+        unguarded: usize,
+    },
+    /// A terminal state has a transition out of it, see
+    /// `StateMachine::assert_terminal_integrity`
+    TerminalViolation {
+        state: String,
+        event: Option<String>,
+        to: String,
+    },
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationIssue::NameCollision { kind, name, count } => {
+                write!(f, "{} distinct {} values are named {}", count, kind, name)
+            }
+            ValidationIssue::Nondeterministic {
+                from,
+                event,
+                unguarded,
+            } => write!(
+                f,
+                "{} unguarded transitions from {} on {}",
+                unguarded, from, event
+            ),
+            ValidationIssue::TerminalViolation { state, event, to } => match event {
+                Some(event) => write!(
+                    f,
+                    "terminal state {} has a transition on {} to {}",
+                    state, event, to
+                ),
+                None => write!(
+                    f,
+                    "terminal state {} has a completion transition to {}",
+                    state, to
+                ),
+            },
+        }
+    }
+}
+
+/// Candidate counts for one source state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDeterminism<S> {
+    pub state: S,
+    pub unguarded: usize,
+    pub guarded: usize,
+}
+
+impl<S> StateDeterminism<S> {
+    /// Whether more than one candidate applies regardless of the context
+    pub fn is_ambiguous(&self) -> bool {
+        self.unguarded > 1
+    }
+}
+
+/// Candidates of one event per source state, sorted by state name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeterminismReport<S> {
+    pub states: Vec<StateDeterminism<S>>,
+}
+
+impl<S> DeterminismReport<S> {
+    /// Whether every state has at most one unguarded candidate
+    pub fn is_deterministic(&self) -> bool {
+        !self.states.iter().any(StateDeterminism::is_ambiguous)
+    }
+
+    /// States with more than one unguarded candidate
+    pub fn ambiguous_states(&self) -> impl Iterator<Item = &S> {
+        self.states
+            .iter()
+            .filter(|entry| entry.is_ambiguous())
+            .map(|entry| &entry.state)
+    }
+}
+
+/// Findings of `StateMachine::validate`, sorted by kind and name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reason `StateMachineBuilder::try_build` rejected a definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    NameCollision {
+        kind: NameKind,
+        name: String,
+        count: usize,
+    },
+    /// A transition builder was finalized without its `missing` part; `from`
+    /// and `events` are what it had
+    IncompleteTransition {
+        missing: &'static str,
+        from: Option<String>,
+        events: Vec<String>,
+    },
+    /// A locked state has a transition on an event it doesn't allow
+    EventNotAllowed { state: String, event: String },
+    /// A terminal state has a transition out of it; `event` is `None` for
+    /// completion transitions
+    TransitionFromTerminal {
+        state: String,
+        event: Option<String>,
+    },
+    /// The same unguarded transition was registered more than once
+    DuplicateTransition {
+        from: String,
+        event: String,
+        to: String,
+    },
+    /// The transition to `to` is never taken: the unguarded one to
+    /// `shadowed_by` is evaluated before it
+    ShadowedTransition {
+        from: String,
+        event: String,
+        to: String,
+        shadowed_by: String,
+    },
+    /// An external transition leads back to its source state
+    ExternalSelfLoop { state: String, event: String },
+    /// The declared initial state cannot reach `state`
+    UnreachableState { state: String },
+    /// `state` can't be left but isn't declared terminal
+    DeadEndState { state: String },
+    /// A service declared with `requires_service` was not provided
+    MissingService { service: &'static str },
+    /// A `when_derived` or `perform_derived` callback of the transition
+    /// needs a `value` no `with_derived` derivation produces; `from` is
+    /// `None` for `from_any` transitions
+    MissingDerivation {
+        value: &'static str,
+        from: Option<String>,
+        event: String,
+    },
+    /// `require_named_callbacks` rejected the transition for a closure
+    /// registered without a binding name
+    AnonymousCallback {
+        kind: BindingKind,
+        from: String,
+        event: String,
+    },
+    /// `when_named` or `perform_named` refers to a name not bound with
+    /// `with_bindings`
+    UnboundCallback { kind: BindingKind, name: String },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::IncompleteTransition {
+                missing,
+                from,
+                events,
+            } => {
+                write!(f, "Transition")?;
+                if let Some(from) = from {
+                    write!(f, " from {}", from)?;
+                }
+                if !events.is_empty() {
+                    write!(f, " on {}", events.join(", "))?;
+                }
+                write!(f, " has no {}", missing)
+            }
+            BuildError::NameCollision { kind, name, count } => write!(
+                f,
+                "Name collision: {} distinct {} values are named {}",
+                count, kind, name
+            ),
+            BuildError::EventNotAllowed { state, event } => write!(
+                f,
+                "Event {} is not allowed in locked state {}",
+                event, state
+            ),
+            BuildError::TransitionFromTerminal { state, event } => match event {
+                Some(event) => write!(f, "Terminal state {} has a transition on {}", state, event),
+                None => write!(f, "Terminal state {} has a completion transition", state),
+            },
+            BuildError::DuplicateTransition { from, event, to } => write!(
+                f,
+                "Transition from {} on {} to {} is registered more than once",
+                from, event, to
+            ),
+            BuildError::ShadowedTransition {
+                from,
+                event,
+                to,
+                shadowed_by,
+            } => write!(
+                f,
+                "Transition from {} on {} to {} is shadowed by the unguarded one to {}",
+                from, event, to, shadowed_by
+            ),
+            BuildError::ExternalSelfLoop { state, event } => write!(
+                f,
+                "External transition from {} on {} leads back to it",
+                state, event
+            ),
+            BuildError::UnreachableState { state } => {
+                write!(f, "State {} is unreachable from the initial state", state)
+            }
+            BuildError::DeadEndState { state } => {
+                write!(f, "State {} can't be left but isn't terminal", state)
+            }
+            BuildError::MissingService { service } => {
+                write!(f, "Service {} is required but was not provided", service)
+            }
+            BuildError::MissingDerivation { value, from, event } => write!(
+                f,
+                "Transition from {} on {} needs derived {} but no derivation is registered",
+                from.as_deref().unwrap_or("any state"),
+                event,
+                value
+            ),
+            BuildError::AnonymousCallback { kind, from, event } => write!(
+                f,
+                "Transition from {} on {} has an anonymous {}",
+                from, event, kind
+            ),
+            BuildError::UnboundCallback { kind, name } => {
+                write!(f, "No {} is bound to the name {}", kind, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// An edge between states: source (`None` for wildcards), event (`None` for
+// completion transitions) and target
+type Edge<'a, S, E> = (Option<&'a S>, Option<&'a E>, &'a S);
+
+// Names shared by more than one distinct value, with their counts
+fn collisions<'a, T: fmt::Debug + 'a>(
+    values: impl Iterator<Item = &'a T>,
+) -> BTreeMap<String, usize> {
+    let mut names = BTreeMap::new();
+    for value in values {
+        *names.entry(format!("{:?}", value)).or_insert(0) += 1;
+    }
+    names.retain(|_, count| *count > 1);
+    names
+}
+
+impl<S, E, C> StateMachineBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Make `try_build` fail when distinct states or events share a name
+    pub fn fail_on_name_collision(&mut self, fail: bool) -> &mut Self {
+        self.fail_on_name_collision = fail;
+        self
+    }
+
+    /// Build the state machine, or return every problem with the definition
+    ///
+    /// See the module documentation for what is checked. `build` panics on
+    /// the first problem that leaves the machine unusable and ignores the
+    /// others.
+    pub fn try_build(self) -> Result<StateMachine<S, E, C>, Vec<BuildError>> {
+        let mut errors = self.registration_errors.clone();
+        errors.extend(
+            self.disallowed_transitions()
+                .into_iter()
+                .map(|(state, event)| BuildError::EventNotAllowed {
+                    state: format!("{:?}", state),
+                    event: format!("{:?}", event),
+                }),
+        );
+        errors.extend(
+            self.outgoing_from_terminal()
+                .into_iter()
+                .map(|(state, event)| BuildError::TransitionFromTerminal {
+                    state: format!("{:?}", state),
+                    event: event.map(|event| format!("{:?}", event)),
+                }),
+        );
+        errors.extend(self.candidate_errors());
+        errors.extend(self.state_errors());
+        errors.extend(self.missing_services());
+        errors.extend(self.missing_derivations());
+        if self.fail_on_name_collision {
+            let states = self.transitions.iter().flat_map(|t| [&t.from, &t.to]);
+            let events = self.transitions.iter().map(|t| &t.event);
+            for (kind, names) in [
+                (
+                    NameKind::State,
+                    collisions(states.collect::<HashSet<_>>().into_iter()),
+                ),
+                (
+                    NameKind::Event,
+                    collisions(events.collect::<HashSet<_>>().into_iter()),
+                ),
+            ] {
+                errors.extend(
+                    names
+                        .into_iter()
+                        .map(|(name, count)| BuildError::NameCollision { kind, name, count }),
+                );
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(self.build())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Record a transition builder finalized without its `missing` part
+    pub(crate) fn incomplete_transition(
+        &mut self,
+        missing: &'static str,
+        from: Option<&S>,
+        events: &[E],
+    ) -> &mut Self {
+        self.registration_errors
+            .push(BuildError::IncompleteTransition {
+                missing,
+                from: from.map(|from| format!("{:?}", from)),
+                events: events.iter().map(|event| format!("{:?}", event)).collect(),
+            });
+        self
+    }
+
+    // Duplicate, shadowed and external self-loop transitions, by pair name
+    fn candidate_errors(&self) -> Vec<BuildError> {
+        let mut pairs: HashMap<_, Vec<&Transition<S, E, C>>> = HashMap::new();
+        for transition in &self.transitions {
+            pairs
+                .entry((&transition.from, &transition.event))
+                .or_default()
+                .push(transition);
+        }
+        let mut pairs: Vec<_> = pairs.into_iter().collect();
+        pairs.sort_by_cached_key(|((from, event), _)| format!("{:?}\u{0}{:?}", from, event));
+
+        let mut errors = Vec::new();
+        for ((from, event), mut candidates) in pairs {
+            let name = |value: &dyn fmt::Debug| format!("{:?}", value);
+            #[cfg(feature = "guards")]
+            candidates.sort_by_key(|t| std::cmp::Reverse(t.priority));
+            candidates.dedup_by(|later, earlier| {
+                let duplicate = later.to == earlier.to
+                    && later.transition_type == earlier.transition_type
+                    && !later.is_guarded()
+                    && !earlier.is_guarded();
+                if duplicate {
+                    errors.push(BuildError::DuplicateTransition {
+                        from: name(from),
+                        event: name(event),
+                        to: name(&later.to),
+                    });
+                }
+                duplicate
+            });
+            for transition in &candidates {
+                if transition.transition_type == TransitionType::External && transition.to == *from
+                {
+                    errors.push(BuildError::ExternalSelfLoop {
+                        state: name(from),
+                        event: name(event),
+                    });
+                }
+            }
+            // A candidate without guards or flag always applies, so the
+            // search never gets past it
+            if let Some(position) = candidates
+                .iter()
+                .position(|t| !t.is_guarded() && t.required_flag.is_none())
+            {
+                let shadowed_by = &candidates[position].to;
+                errors.extend(candidates[position + 1..].iter().map(|t| {
+                    BuildError::ShadowedTransition {
+                        from: name(from),
+                        event: name(event),
+                        to: name(&t.to),
+                        shadowed_by: name(shadowed_by),
+                    }
+                }));
+            }
+        }
+        errors
+    }
+
+    // Unreachable and dead-end states, by name
+    fn state_errors(&self) -> Vec<BuildError> {
+        #[allow(unused_mut)]
+        let mut edges: Vec<Edge<S, E>> = self
+            .transitions
+            .iter()
+            .map(|t| (Some(&t.from), Some(&t.event), &t.to))
+            .chain(
+                self.wildcard_transitions
+                    .iter()
+                    .map(|t| (None, Some(&t.event), &t.to)),
+            )
+            .chain(
+                self.completion_transitions
+                    .iter()
+                    .map(|t| (Some(&t.from), None, &t.to)),
+            )
+            .collect();
+        #[cfg(feature = "timeout")]
+        edges.extend(
+            self.timeout_transitions
+                .iter()
+                .map(|(from, (to, event))| (Some(from), Some(event), to)),
+        );
+
+        let mut states: HashSet<&S> = self.terminal_states.iter().collect();
+        states.extend(self.initial_state.as_ref());
+        for (from, _, to) in &edges {
+            states.extend(*from);
+            states.insert(to);
+        }
+        // Whether an edge leads out of `state`
+        let leaves = |state: &S, (from, event, to): &Edge<S, E>| {
+            *to != state
+                && match from {
+                    Some(from) => *from == state,
+                    None => {
+                        !self.terminal_states.contains(state)
+                            && event.is_none_or(|event| {
+                                self.locked_events
+                                    .get(state)
+                                    .is_none_or(|allowed| allowed.contains(event))
+                            })
+                    }
+                }
+        };
+
+        let mut errors = Vec::new();
+        if let Some(initial) = &self.initial_state {
+            let mut reached = HashSet::from([initial]);
+            let mut queue = VecDeque::from([initial]);
+            while let Some(state) = queue.pop_front() {
+                for edge in &edges {
+                    if leaves(state, edge) && reached.insert(edge.2) {
+                        queue.push_back(edge.2);
+                    }
+                }
+            }
+            let mut unreachable: Vec<String> = states
+                .iter()
+                .filter(|state| !reached.contains(*state))
+                .map(|state| format!("{:?}", state))
+                .collect();
+            unreachable.sort();
+            errors.extend(
+                unreachable
+                    .into_iter()
+                    .map(|state| BuildError::UnreachableState { state }),
+            );
+        }
+
+        let mut dead_ends: Vec<String> = states
+            .iter()
+            .filter(|state| {
+                !self.terminal_states.contains(**state)
+                    && !edges.iter().any(|edge| leaves(state, edge))
+            })
+            .map(|state| format!("{:?}", state))
+            .collect();
+        dead_ends.sort();
+        errors.extend(
+            dead_ends
+                .into_iter()
+                .map(|state| BuildError::DeadEndState { state }),
+        );
+        errors
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Check the definition for problems independent of any context
+    pub fn validate(&self) -> ValidationReport {
+        let mut states = HashSet::new();
+        let mut events = HashSet::new();
+        for candidates in self.transitions.values() {
+            for transition in candidates.iter() {
+                states.insert(&transition.from);
+                states.insert(&transition.to);
+                events.insert(&transition.event);
+            }
+        }
+
+        let mut issues = Vec::new();
+        for (kind, names) in [
+            (NameKind::State, collisions(states.into_iter())),
+            (NameKind::Event, collisions(events.into_iter())),
+        ] {
+            issues.extend(
+                names
+                    .into_iter()
+                    .map(|(name, count)| ValidationIssue::NameCollision { kind, name, count }),
+            );
+        }
+
+        let mut pairs: Vec<_> = self.transitions.iter().collect();
+        pairs.sort_by_cached_key(|((from, event), _)| format!("{:?}\u{0}{:?}", from, event));
+        for ((from, event), candidates) in pairs {
+            let unguarded = candidates.iter().filter(|t| !t.is_guarded()).count();
+            if unguarded > 1 {
+                issues.push(ValidationIssue::Nondeterministic {
+                    from: format!("{:?}", from),
+                    event: format!("{:?}", event),
+                    unguarded,
+                });
+            }
+        }
+        issues.extend(self.terminal_violations(&[]).into_iter().map(|v| {
+            ValidationIssue::TerminalViolation {
+                state: format!("{:?}", v.from),
+                event: v.event.map(|event| format!("{:?}", event)),
+                to: format!("{:?}", v.to),
+            }
+        }));
+        ValidationReport { issues }
+    }
+
+    /// Count the guarded and unguarded candidates of `event` per source state
+    pub fn is_deterministic_for(&self, event: &E) -> DeterminismReport<S> {
+        let mut states: Vec<StateDeterminism<S>> = self
+            .transitions
+            .iter()
+            .filter(|((_, e), _)| e == event)
+            .map(|((from, _), candidates)| {
+                let unguarded = candidates.iter().filter(|t| !t.is_guarded()).count();
+                StateDeterminism {
+                    state: from.clone(),
+                    unguarded,
+                    guarded: candidates.len() - unguarded,
+                }
+            })
+            .collect();
+        states.sort_by_cached_key(|entry| format!("{:?}", entry.state));
+        DeterminismReport { states }
+    }
+
+    /// Whether no `(from, event)` pair has more than one unguarded candidate
+    pub fn is_deterministic(&self) -> bool {
+        self.transitions
+            .values()
+            .all(|candidates| candidates.iter().filter(|t| !t.is_guarded()).count() <= 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{NoContext, Order, OrderEvent};
+    use crate::StateMachineBuilderFactory;
+
+    // Wrapper hiding its payload from Debug, as generic wrappers often do
+    #[derive(Clone, Hash, Eq, PartialEq)]
+    struct Step(u32);
+
+    impl fmt::Debug for Step {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Step")
+        }
+    }
+
+    impl State for Step {}
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    enum StepEvent {
+        Next,
+    }
+
+    impl Event for StepEvent {}
+
+    fn step_builder() -> StateMachineBuilder<Step, StepEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Step, StepEvent, NoContext>();
+        builder.terminal_states(vec![Step(2)]);
+        for n in 0..2 {
+            builder
+                .external_transition()
+                .from(Step(n))
+                .to(Step(n + 1))
+                .on(StepEvent::Next)
+                .perform(|_s, _e, _c| {});
+        }
+        builder
+    }
+
+    #[test]
+    fn test_name_collision_reported() {
+        let machine = step_builder().try_build().unwrap();
+        let report = machine.validate();
+        assert_eq!(
+            report.issues,
+            vec![ValidationIssue::NameCollision {
+                kind: NameKind::State,
+                name: "Step".to_string(),
+                count: 3,
+            }]
+        );
+        assert_eq!(
+            report.to_string(),
+            "3 distinct state values are named Step\n"
+        );
+    }
+
+    #[test]
+    fn test_name_collision_fails_try_build_when_enabled() {
+        let mut builder = step_builder();
+        builder.fail_on_name_collision(true);
+        match builder.try_build() {
+            Err(errors) => assert_eq!(
+                errors,
+                vec![BuildError::NameCollision {
+                    kind: NameKind::State,
+                    name: "Step".to_string(),
+                    count: 3,
+                }]
+            ),
+            Ok(_) => panic!("expected the name collision to fail the build"),
+        }
+    }
+
+    fn refund_builder() -> StateMachineBuilder<Order, OrderEvent, NoContext> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .initial_state(Order::Placed)
+            .terminal_states(vec![Order::Cancelled, Order::Refunded]);
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .when(|_s, _e, _c| true)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Refunded)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        builder
+    }
+
+    #[test]
+    fn test_deterministic_machine_passes() {
+        let machine = refund_builder().build();
+        let report = machine.is_deterministic_for(&OrderEvent::Cancel);
+        assert_eq!(
+            report.states,
+            vec![
+                StateDeterminism {
+                    state: Order::Placed,
+                    unguarded: 0,
+                    guarded: 1,
+                },
+                StateDeterminism {
+                    state: Order::Shipped,
+                    unguarded: 1,
+                    guarded: 0,
+                },
+            ]
+        );
+        assert!(report.is_deterministic());
+        assert!(machine.is_deterministic());
+        assert!(machine.validate().is_clean());
+    }
+
+    #[test]
+    fn test_nondeterministic_pair_detected() {
+        let mut builder = refund_builder();
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        let machine = builder.build();
+
+        let report = machine.is_deterministic_for(&OrderEvent::Cancel);
+        assert!(!report.is_deterministic());
+        assert_eq!(
+            report.ambiguous_states().collect::<Vec<_>>(),
+            vec![&Order::Shipped]
+        );
+        assert!(machine
+            .is_deterministic_for(&OrderEvent::Ship)
+            .is_deterministic());
+        assert!(!machine.is_deterministic());
+        assert_eq!(
+            machine.validate().issues,
+            vec![ValidationIssue::Nondeterministic {
+                from: "Shipped".to_string(),
+                event: "Cancel".to_string(),
+                unguarded: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_try_build_collects_definition_problems() {
+        assert!(refund_builder().try_build().is_ok());
+        let mut builder = refund_builder();
+
+        // Registered twice
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        // Never taken: the unguarded refund comes first
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Delivered)
+            .on(OrderEvent::Ship)
+            .when(|_s, _e, _c| false)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .to(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::OnHold)
+            .to(Order::Placed)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+
+        let errors = match builder.try_build() {
+            Err(errors) => errors,
+            Ok(_) => panic!("expected the definition to fail the build"),
+        };
+        let name = |name: &str| name.to_string();
+        assert_eq!(
+            errors,
+            vec![
+                BuildError::DuplicateTransition {
+                    from: name("Placed"),
+                    event: name("Ship"),
+                    to: name("Shipped"),
+                },
+                BuildError::ShadowedTransition {
+                    from: name("Shipped"),
+                    event: name("Cancel"),
+                    to: name("Cancelled"),
+                    shadowed_by: name("Refunded"),
+                },
+                BuildError::ExternalSelfLoop {
+                    state: name("Shipped"),
+                    event: name("Ship"),
+                },
+                BuildError::UnreachableState {
+                    state: name("OnHold"),
+                },
+                BuildError::DeadEndState {
+                    state: name("Delivered"),
+                },
+            ]
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "Transition from Shipped on Cancel to Cancelled is shadowed by the unguarded one to Refunded"
+        );
+    }
+
+    #[test]
+    fn test_incomplete_transition_reported() {
+        let mut builder = refund_builder();
+        builder
+            .external_transition()
+            .from(Order::Shipped)
+            .on(OrderEvent::Ship)
+            .perform(|_s, _e, _c| {});
+        match builder.try_build() {
+            Err(errors) => {
+                assert_eq!(
+                    errors,
+                    vec![BuildError::IncompleteTransition {
+                        missing: "to state",
+                        from: Some("Shipped".to_string()),
+                        events: vec!["Ship".to_string()],
+                    }]
+                );
+                assert_eq!(
+                    errors[0].to_string(),
+                    "Transition from Shipped on Ship has no to state"
+                );
+            }
+            Ok(_) => panic!("expected the incomplete transition to fail the build"),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "event is required")]
+    fn test_build_panics_on_incomplete_transition() {
+        let mut builder = refund_builder();
+        builder
+            .internal_transition()
+            .within(Order::Placed)
+            .perform(|_s, _e, _c| {});
+        builder.build();
+    }
+}
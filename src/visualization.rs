@@ -0,0 +1,925 @@
+//! Diagram and table exports with configurable labels (requires the
+//! `visualization` feature)
+//!
+//! Every export lists transitions sorted by source state and event, keeping
+//! the evaluation order of candidates sharing a pair, so the output of a
+//! definition is stable between runs.
+//!
+//! `write_dot` and `write_plantuml` stream the document to an `io::Write`
+//! line by line, for machines too large to export into one `String`; the
+//! `String` exports are built on them.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::{self, Write};
+
+use crate::derived::DerivedValues;
+use crate::{Context, Event, FlagCache, State, StateMachine, Transition, TransitionType};
+
+/// Human-readable names for states and events in exports
+///
+/// Both methods fall back to the `Debug` representation.
+pub trait LabelProvider<S: Debug, E: Debug> {
+    fn state_label(&self, state: &S) -> String {
+        format!("{:?}", state)
+    }
+
+    fn event_label(&self, event: &E) -> String {
+        format!("{:?}", event)
+    }
+}
+
+/// `LabelProvider` using the `Debug` representation of everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DebugLabels;
+
+impl<S: Debug, E: Debug> LabelProvider<S, E> for DebugLabels {}
+
+/// `LabelProvider` looking labels up in maps, with `Debug` as fallback
+#[derive(Debug, Clone)]
+pub struct MapLabelProvider<S, E> {
+    states: HashMap<S, String>,
+    events: HashMap<E, String>,
+}
+
+impl<S, E> MapLabelProvider<S, E>
+where
+    S: Eq + Hash,
+    E: Eq + Hash,
+{
+    pub fn new(states: HashMap<S, String>, events: HashMap<E, String>) -> Self {
+        MapLabelProvider { states, events }
+    }
+}
+
+impl<S, E> LabelProvider<S, E> for MapLabelProvider<S, E>
+where
+    S: Debug + Eq + Hash,
+    E: Debug + Eq + Hash,
+{
+    fn state_label(&self, state: &S) -> String {
+        match self.states.get(state) {
+            Some(label) => label.clone(),
+            None => format!("{:?}", state),
+        }
+    }
+
+    fn event_label(&self, event: &E) -> String {
+        match self.events.get(event) {
+            Some(label) => label.clone(),
+            None => format!("{:?}", event),
+        }
+    }
+}
+
+/// Options shared by the exports
+pub struct ExportOptions<'a, S, E> {
+    labels: &'a dyn LabelProvider<S, E>,
+    annotate: bool,
+    merge_parallel_edges: bool,
+    legend: bool,
+}
+
+impl<'a, S: Debug, E: Debug> ExportOptions<'a, S, E> {
+    pub fn new() -> Self {
+        ExportOptions {
+            labels: &DebugLabels,
+            annotate: false,
+            merge_parallel_edges: false,
+            legend: false,
+        }
+    }
+
+    /// Use `labels` for state and event names
+    pub fn labels(mut self, labels: &'a dyn LabelProvider<S, E>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Annotate DOT edges with priority, guard and feature flag,
+    /// e.g. `Process [p=30, guarded]`
+    pub fn annotate(mut self, annotate: bool) -> Self {
+        self.annotate = annotate;
+        self
+    }
+
+    /// Draw transitions of the same kind between the same two states as a
+    /// single DOT edge with one label line per transition
+    pub fn merge_parallel_edges(mut self, merge: bool) -> Self {
+        self.merge_parallel_edges = merge;
+        self
+    }
+
+    /// Add a legend explaining the edge styles and annotations to DOT output
+    pub fn legend(mut self, legend: bool) -> Self {
+        self.legend = legend;
+        self
+    }
+}
+
+impl<S: Debug, E: Debug> Default for ExportOptions<'_, S, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Escape a label for use inside a double-quoted DOT string
+fn dot_escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+const DOT_LEGEND: &str = "  subgraph cluster_legend {
+    label=\"Legend\";
+    node [shape=plaintext];
+    legend_external [label=\"solid: external\"];
+    legend_internal [label=\"dashed: internal\"];
+    legend_flagged [label=\"dotted: disabled unless feature flag is on\"];
+    legend_annotations [label=\"[p=N]: priority, guarded: has a guard\"];
+  }
+";
+
+// First transition of a DOT edge and the transitions of each label line
+type DotEdge<'a, S, E, C> = (&'a Transition<S, E, C>, Vec<Vec<&'a Transition<S, E, C>>>);
+
+// Whether two transitions came from one `on_any_of` registration: same
+// endpoints and settings, and the very same guard and action closures
+fn registered_together<S, E, C>(a: &Transition<S, E, C>, b: &Transition<S, E, C>) -> bool
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    #[cfg(feature = "guards")]
+    if a.priority != b.priority {
+        return false;
+    }
+    a.has_action()
+        && a.from == b.from
+        && a.to == b.to
+        && a.transition_type == b.transition_type
+        && a.required_flag == b.required_flag
+        && a.guard.ptr_eq(&b.guard)
+        && a.action.ptr_eq(&b.action)
+}
+
+// DOT line style for a transition
+fn dot_style<S, E, C>(transition: &Transition<S, E, C>) -> Option<&'static str>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    if transition.required_flag.is_some() {
+        Some("dotted")
+    } else if transition.transition_type == TransitionType::Internal {
+        Some("dashed")
+    } else {
+        None
+    }
+}
+
+impl<S, E, C> StateMachine<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    // States of tagged transitions per template instance; a state shared by
+    // instances is placed in the first group it appears in
+    fn template_groups(&self) -> Vec<(&str, Vec<&S>)> {
+        let mut groups: Vec<(&str, Vec<&S>)> = Vec::new();
+        let mut grouped = HashSet::new();
+        for transition in self.sorted_transitions() {
+            let Some(tag) = transition.tag.as_deref() else {
+                continue;
+            };
+            let index = match groups.iter().position(|(group, _)| *group == tag) {
+                Some(index) => index,
+                None => {
+                    groups.push((tag, Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            for state in [&transition.from, &transition.to] {
+                if grouped.insert(state) {
+                    groups[index].1.push(state);
+                }
+            }
+        }
+        groups
+    }
+
+    /// All transitions in export order
+    pub(crate) fn sorted_transitions(&self) -> Vec<&Transition<S, E, C>> {
+        let mut pairs: Vec<_> = self.transitions.iter().collect();
+        pairs.sort_by_cached_key(|((from, event), _)| format!("{:?}\u{0}{:?}", from, event));
+        pairs
+            .into_iter()
+            .flat_map(|(_, candidates)| candidates.iter())
+            .collect()
+    }
+
+    /// `from_any` transitions, sorted by event
+    fn sorted_wildcards(&self) -> Vec<&Transition<S, E, C>> {
+        let mut events: Vec<_> = self.wildcard_transitions.iter().collect();
+        events.sort_by_cached_key(|(event, _)| format!("{:?}", event));
+        events
+            .into_iter()
+            .flat_map(|(_, candidates)| candidates.iter())
+            .collect()
+    }
+
+    // Described states, sorted by name
+    fn sorted_state_descriptions(&self) -> Vec<(&S, &str)> {
+        let mut described: Vec<(&S, &str)> = self
+            .descriptions
+            .states
+            .iter()
+            .map(|(state, text)| (state, text.as_str()))
+            .collect();
+        described.sort_by_cached_key(|(state, _)| format!("{:?}", state));
+        described
+    }
+
+    /// Export to DOT format with the given options
+    pub fn to_dot_with(&self, options: &ExportOptions<'_, S, E>) -> String {
+        let mut dot = Vec::new();
+        self.write_dot(&mut dot, options)
+            .expect("writing to a Vec can't fail");
+        String::from_utf8(dot).expect("labels are strings")
+    }
+
+    /// `to_dot_with`, writing the document to `writer` as it goes
+    pub fn write_dot<W: Write>(
+        &self,
+        mut writer: W,
+        options: &ExportOptions<'_, S, E>,
+    ) -> io::Result<()> {
+        writer.write_all(b"digraph StateMachine {\n")?;
+        writer.write_all(b"  rankdir=LR;\n")?;
+        writer.write_all(b"  node [shape=box];\n\n")?;
+
+        // Entry arrow from a point into the initial state
+        if let Some(initial) = &self.initial_state {
+            writer.write_all(b"  \"__start\" [shape=point];\n")?;
+            writeln!(
+                writer,
+                "  \"__start\" -> \"{}\";",
+                dot_escape(&options.labels.state_label(initial))
+            )?;
+        }
+        for state in self.sorted_terminal_states() {
+            writeln!(
+                writer,
+                "  \"{}\" [shape=doublecircle];",
+                dot_escape(&options.labels.state_label(state))
+            )?;
+        }
+
+        for (state, description) in self.sorted_state_descriptions() {
+            writeln!(
+                writer,
+                "  \"{}\" [tooltip=\"{}\"];",
+                dot_escape(&options.labels.state_label(state)),
+                dot_escape(description)
+            )?;
+        }
+
+        for (index, (tag, states)) in self.template_groups().into_iter().enumerate() {
+            writeln!(
+                writer,
+                "  subgraph cluster_{} {{\n    label=\"{}\";",
+                index,
+                dot_escape(tag)
+            )?;
+            for state in states {
+                writeln!(
+                    writer,
+                    "    \"{}\";",
+                    dot_escape(&options.labels.state_label(state))
+                )?;
+            }
+            writer.write_all(b"  }\n")?;
+        }
+
+        // Edges as (first transition, label lines), in export order.
+        // Transitions registered with `on_any_of` share a label line.
+        // Only edges between the same two states can take a transition in,
+        // so they are looked up by their endpoints.
+        let mut edges: Vec<DotEdge<'_, S, E, C>> = Vec::new();
+        let mut between: HashMap<(&S, &S), Vec<usize>> = HashMap::new();
+        for transition in self.sorted_transitions() {
+            let endpoints = between
+                .entry((&transition.from, &transition.to))
+                .or_default();
+            let sibling = endpoints.iter().find_map(|&index| {
+                let lines = &edges[index].1;
+                let line = lines
+                    .iter()
+                    .position(|line| registered_together(line[0], transition))?;
+                Some((index, line))
+            });
+            if let Some((index, line)) = sibling {
+                edges[index].1[line].push(transition);
+                continue;
+            }
+            let parallel = endpoints
+                .iter()
+                .copied()
+                .find(|&index| dot_style(edges[index].0) == dot_style(transition));
+            match parallel {
+                Some(index) if options.merge_parallel_edges => {
+                    edges[index].1.push(vec![transition]);
+                }
+                _ => {
+                    endpoints.push(edges.len());
+                    edges.push((transition, vec![vec![transition]]));
+                }
+            }
+        }
+
+        for (transition, lines) in edges {
+            write!(
+                writer,
+                "  \"{}\" -> \"{}\" [label=\"",
+                dot_escape(&options.labels.state_label(&transition.from)),
+                dot_escape(&options.labels.state_label(&transition.to)),
+            )?;
+            for (index, line) in lines.iter().enumerate() {
+                if index > 0 {
+                    writer.write_all(b"\\n")?;
+                }
+                writer.write_all(dot_escape(&self.edge_label(line, options)).as_bytes())?;
+            }
+            match dot_style(transition) {
+                Some(style) => writeln!(writer, "\", style={}];", style)?,
+                None => writer.write_all(b"\"];\n")?,
+            }
+        }
+
+        // `from_any` transitions leave a synthetic `*` node
+        let wildcards = self.sorted_wildcards();
+        if !wildcards.is_empty() {
+            writer.write_all(b"  \"*\" [shape=circle];\n")?;
+        }
+        for transition in wildcards {
+            writeln!(
+                writer,
+                "  \"*\" -> \"{}\" [label=\"{}\"];",
+                dot_escape(&options.labels.state_label(&transition.to)),
+                dot_escape(&self.edge_label(&[transition], options))
+            )?;
+        }
+
+        if options.legend {
+            writer.write_all(b"\n")?;
+            writer.write_all(DOT_LEGEND.as_bytes())?;
+        }
+
+        writer.write_all(b"}\n")?;
+        writer.flush()
+    }
+
+    /// Export to DOT format highlighting what `context` allows from `current`
+    ///
+    /// Outgoing transitions of `current` whose feature flag and guards pass
+    /// are green, blocked ones orange; all other transitions are grey.
+    /// Guards are evaluated without running any action and without touching
+    /// history, metrics or slow-callback reporting.
+    pub fn to_dot_for_context(
+        &self,
+        current: &S,
+        context: &C,
+        options: &ExportOptions<'_, S, E>,
+    ) -> String {
+        let mut dot = String::from("digraph StateMachine {\n");
+        dot.push_str("  rankdir=LR;\n");
+        dot.push_str("  node [shape=box];\n\n");
+        dot.push_str(&format!(
+            "  \"{}\" [style=filled, fillcolor=lightblue];\n",
+            dot_escape(&options.labels.state_label(current))
+        ));
+
+        let mut flags = FlagCache::new(self.feature_flags.as_ref());
+        let mut derived = DerivedValues::new(&self.derivations, &self.services);
+        for transition in self.sorted_transitions() {
+            let mut label = self.edge_label(&[transition], options);
+            let color = if &transition.from != current {
+                "grey"
+            } else if let Some(flag) = transition
+                .required_flag
+                .as_ref()
+                .filter(|flag| !flags.is_enabled(flag, context))
+            {
+                label.push_str(&format!(" (flag {} off)", flag));
+                "orange"
+            } else if transition
+                .check_guards(
+                    &self.id,
+                    &transition.from,
+                    &transition.event,
+                    context,
+                    &mut derived,
+                )
+                .is_some_and(|outcome| outcome.is_err())
+            {
+                label.push_str(" (guard failed)");
+                "orange"
+            } else {
+                "green"
+            };
+            dot.push_str(&format!(
+                "  \"{}\" -> \"{}\" [label=\"{}\", color={}];\n",
+                dot_escape(&options.labels.state_label(&transition.from)),
+                dot_escape(&options.labels.state_label(&transition.to)),
+                dot_escape(&label),
+                color
+            ));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    // Event labels of transitions registered together, with the annotations
+    // of the first when requested
+    fn edge_label(
+        &self,
+        transitions: &[&Transition<S, E, C>],
+        options: &ExportOptions<'_, S, E>,
+    ) -> String {
+        let transition = transitions[0];
+        let label = transitions
+            .iter()
+            .map(|t| options.labels.event_label(&t.event))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !options.annotate {
+            return label;
+        }
+
+        let mut notes = Vec::new();
+        #[cfg(feature = "guards")]
+        notes.push(format!("p={}", transition.priority));
+        if transition.is_guarded() {
+            notes.push("guarded".to_string());
+        }
+        if let Some(flag) = &transition.required_flag {
+            notes.push(format!("flag={}", flag));
+        }
+
+        if notes.is_empty() {
+            label
+        } else {
+            format!("{} [{}]", label, notes.join(", "))
+        }
+    }
+
+    /// Export to PlantUML format with the given options
+    ///
+    /// PlantUML state names can't contain spaces, so states are declared
+    /// with their label as an alias of the `Debug` name.
+    pub fn to_plantuml_with(&self, options: &ExportOptions<'_, S, E>) -> String {
+        let mut uml = Vec::new();
+        self.write_plantuml(&mut uml, options)
+            .expect("writing to a Vec can't fail");
+        String::from_utf8(uml).expect("labels are strings")
+    }
+
+    /// `to_plantuml_with`, writing the document to `writer` as it goes
+    pub fn write_plantuml<W: Write>(
+        &self,
+        mut writer: W,
+        options: &ExportOptions<'_, S, E>,
+    ) -> io::Result<()> {
+        writer.write_all(b"@startuml\n")?;
+        let transitions = self.sorted_transitions();
+
+        let mut declared = HashSet::new();
+        for transition in &transitions {
+            for state in [&transition.from, &transition.to] {
+                let name = format!("{:?}", state);
+                let label = options.labels.state_label(state);
+                if label != name && !declared.contains(&name) {
+                    writeln!(writer, "state \"{}\" as {}", label, name)?;
+                    declared.insert(name);
+                }
+            }
+        }
+
+        if let Some(initial) = &self.initial_state {
+            writeln!(writer, "[*] --> {:?}", initial)?;
+        }
+
+        for (state, description) in self.sorted_state_descriptions() {
+            writeln!(writer, "note right of {:?}", state)?;
+            for line in description.lines() {
+                writeln!(writer, "  {}", line)?;
+            }
+            writer.write_all(b"end note\n")?;
+        }
+
+        for transition in transitions {
+            writeln!(
+                writer,
+                "{:?} --> {:?} : {}",
+                transition.from,
+                transition.to,
+                options.labels.event_label(&transition.event)
+            )?;
+        }
+
+        let wildcards = self.sorted_wildcards();
+        if !wildcards.is_empty() {
+            writer.write_all(b"state \"*\" as AnyState\n")?;
+        }
+        for transition in wildcards {
+            writeln!(
+                writer,
+                "AnyState --> {:?} : {}",
+                transition.to,
+                options.labels.event_label(&transition.event)
+            )?;
+        }
+
+        for state in self.sorted_terminal_states() {
+            writeln!(writer, "{:?} --> [*]", state)?;
+        }
+
+        writer.write_all(b"@enduml\n")?;
+        writer.flush()
+    }
+
+    /// Export the transitions as a Markdown table
+    pub fn to_markdown_with(&self, options: &ExportOptions<'_, S, E>) -> String {
+        let mut table = String::from("| From | Event | To |\n|---|---|---|\n");
+        // Footnotes for described events, numbered by first appearance
+        let mut footnotes: Vec<(&E, &str)> = Vec::new();
+        for transition in self.sorted_transitions() {
+            let mut event = options.labels.event_label(&transition.event);
+            if let Some(description) = self.event_description(&transition.event) {
+                let number = match footnotes.iter().position(|(e, _)| *e == &transition.event) {
+                    Some(index) => index + 1,
+                    None => {
+                        footnotes.push((&transition.event, description));
+                        footnotes.len()
+                    }
+                };
+                event.push_str(&format!("[^{}]", number));
+            }
+            table.push_str(&format!(
+                "| {} | {} | {} |\n",
+                options.labels.state_label(&transition.from),
+                event,
+                options.labels.state_label(&transition.to)
+            ));
+        }
+        for transition in self.sorted_wildcards() {
+            table.push_str(&format!(
+                "| * | {} | {} |\n",
+                options.labels.event_label(&transition.event),
+                options.labels.state_label(&transition.to)
+            ));
+        }
+
+        if !footnotes.is_empty() {
+            table.push('\n');
+        }
+        for (number, (_, description)) in footnotes.iter().enumerate() {
+            // Continuation lines are indented to stay in the footnote
+            let text = description.lines().collect::<Vec<_>>().join("\n    ");
+            table.push_str(&format!("[^{}]: {}\n", number + 1, text));
+        }
+        table
+    }
+
+    /// Export the transitions as a Markdown table
+    pub fn to_markdown(&self) -> String {
+        self.to_markdown_with(&ExportOptions::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_machine, NoContext, Order, OrderEvent};
+    use crate::StateMachineBuilderFactory;
+
+    fn german_labels() -> MapLabelProvider<Order, OrderEvent> {
+        MapLabelProvider::new(
+            HashMap::from([
+                (Order::Placed, "Warten auf Zahlung".to_string()),
+                (Order::Paid, "Bezahlt".to_string()),
+            ]),
+            HashMap::from([(OrderEvent::Pay, "Bezahlen".to_string())]),
+        )
+    }
+
+    #[test]
+    fn test_custom_labels_in_exports() {
+        let machine = order_machine();
+        let labels = german_labels();
+        let options = ExportOptions::new().labels(&labels);
+
+        let dot = machine.to_dot_with(&options);
+        assert!(dot.contains("\"Warten auf Zahlung\" -> \"Bezahlt\" [label=\"Bezahlen\"];"));
+
+        let markdown = machine.to_markdown_with(&options);
+        assert!(markdown.contains("| Warten auf Zahlung | Bezahlen | Bezahlt |"));
+
+        let uml = machine.to_plantuml_with(&options);
+        assert!(uml.contains("state \"Bezahlt\" as Paid"));
+    }
+
+    #[test]
+    fn test_unmapped_labels_fall_back_to_debug() {
+        let machine = order_machine();
+        let labels = german_labels();
+        let markdown = machine.to_markdown_with(&ExportOptions::new().labels(&labels));
+        assert!(markdown.contains("| Warten auf Zahlung | Cancel | Cancelled |"));
+
+        assert_eq!(
+            machine.to_markdown(),
+            "| From | Event | To |\n|---|---|---|\n\
+             | Paid | Cancel | Cancelled |\n\
+             | Paid | Ship | Shipped |\n\
+             | Placed | Cancel | Cancelled |\n\
+             | Placed | Pay | Paid |\n"
+        );
+    }
+
+    #[cfg(feature = "guards")]
+    #[derive(Debug, Clone)]
+    struct Amount(u32);
+
+    #[cfg(feature = "guards")]
+    impl Context for Amount {}
+
+    // Priority-ordered guards from the order example
+    #[cfg(feature = "guards")]
+    fn process_machine() -> StateMachine<Order, OrderEvent, Amount> {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, Amount>();
+        for (priority, min) in [(10, 0), (20, 100), (30, 1000)] {
+            builder
+                .external_transition()
+                .from(Order::Placed)
+                .to(Order::Paid)
+                .on(OrderEvent::Pay)
+                .when(move |_s, _e, c| c.0 >= min)
+                .with_priority(priority)
+                .perform(|_s, _e, _c| {});
+        }
+        builder
+            .internal_transition()
+            .within(Order::Placed)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        builder.build()
+    }
+
+    #[cfg(feature = "guards")]
+    #[test]
+    fn test_dot_priority_annotations() {
+        let machine = process_machine();
+        let dot = machine.to_dot_with(&ExportOptions::new().annotate(true).legend(true));
+
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(
+            edges,
+            vec![
+                "  \"Placed\" -> \"Placed\" [label=\"Cancel [p=0]\", style=dashed];",
+                "  \"Placed\" -> \"Paid\" [label=\"Pay [p=30, guarded]\"];",
+                "  \"Placed\" -> \"Paid\" [label=\"Pay [p=20, guarded]\"];",
+                "  \"Placed\" -> \"Paid\" [label=\"Pay [p=10, guarded]\"];",
+            ]
+        );
+        assert!(dot.contains("subgraph cluster_legend"));
+    }
+
+    #[cfg(feature = "guards")]
+    #[test]
+    fn test_dot_merged_parallel_edges() {
+        let machine = process_machine();
+        let dot = machine.to_dot_with(
+            &ExportOptions::new()
+                .annotate(true)
+                .merge_parallel_edges(true),
+        );
+
+        assert_eq!(dot.matches("-> \"Paid\"").count(), 1);
+        assert!(dot.contains(
+            "[label=\"Pay [p=30, guarded]\\nPay [p=20, guarded]\\nPay [p=10, guarded]\"]"
+        ));
+        assert!(!dot.contains("cluster_legend"));
+    }
+
+    #[test]
+    fn test_dot_for_context_colors() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .when(|_s, _e, _c| true)
+            .perform(|_s, _e, _c| panic!("actions must not run"));
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .when(|_s, _e, _c| false)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        let machine = builder.build();
+
+        let dot = machine.to_dot_for_context(&Order::Placed, &NoContext, &ExportOptions::new());
+        assert!(dot.contains("\"Placed\" [style=filled, fillcolor=lightblue];"));
+        assert!(dot.contains("\"Placed\" -> \"Paid\" [label=\"Pay\", color=green];"));
+        assert!(dot.contains(
+            "\"Placed\" -> \"Cancelled\" [label=\"Cancel (guard failed)\", color=orange];"
+        ));
+        assert!(dot.contains("\"Paid\" -> \"Cancelled\" [label=\"Cancel\", color=grey];"));
+
+        #[cfg(feature = "history")]
+        assert!(machine.get_history().is_empty());
+        #[cfg(feature = "metrics")]
+        assert_eq!(machine.get_metrics().total_transitions, 0);
+    }
+
+    #[test]
+    fn test_descriptions_in_exports() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        builder
+            .describe_state(Order::Paid, "Money received.\nShip within \"2 days\".")
+            .describe_event(OrderEvent::Pay, "Customer paid\nby card");
+        let machine = builder.build();
+
+        let dot = machine.to_dot();
+        assert!(dot
+            .contains("  \"Paid\" [tooltip=\"Money received.\\nShip within \\\"2 days\\\".\"];\n"));
+
+        let uml = machine.to_plantuml();
+        assert!(uml.contains(
+            "note right of Paid\n  Money received.\n  Ship within \"2 days\".\nend note\n"
+        ));
+
+        let markdown = machine.to_markdown();
+        assert!(markdown.contains("| Placed | Pay[^1] | Paid |\n"));
+        assert!(markdown.ends_with("\n[^1]: Customer paid\n    by card\n"));
+    }
+
+    #[test]
+    fn test_on_any_of_collapses_into_one_edge() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Cancelled)
+            .on_any_of(vec![OrderEvent::Pay, OrderEvent::Cancel])
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transition()
+            .from(Order::Paid)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        let dot = builder.build().to_dot();
+
+        assert!(dot.contains("  \"Placed\" -> \"Cancelled\" [label=\"Cancel, Pay\"];\n"));
+        // Separately registered transitions keep their own edges
+        assert_eq!(dot.matches("\"Paid\" -> \"Cancelled\"").count(), 2);
+    }
+
+    #[test]
+    fn test_wildcard_edges_leave_star_node() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        let machine = builder.build();
+
+        assert!(machine
+            .to_dot()
+            .contains("  \"*\" -> \"Cancelled\" [label=\"Cancel\"];\n"));
+        assert!(machine
+            .to_plantuml()
+            .contains("AnyState --> Cancelled : Cancel\n"));
+        assert!(machine
+            .to_markdown()
+            .contains("| * | Cancel | Cancelled |\n"));
+    }
+
+    #[test]
+    fn test_initial_and_terminal_states_marked() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .initial_state(Order::Placed)
+            .terminal_states(vec![Order::Cancelled]);
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        let machine = builder.build();
+
+        let dot = machine.to_dot();
+        assert!(dot.contains("  \"__start\" -> \"Placed\";\n"));
+        assert!(dot.contains("  \"Cancelled\" [shape=doublecircle];\n"));
+        let uml = machine.to_plantuml();
+        assert!(uml.starts_with("@startuml\n[*] --> Placed\n"));
+        assert!(uml.ends_with("Cancelled --> [*]\n@enduml\n"));
+    }
+
+    // Keeps what is written, counting the calls
+    #[derive(Default)]
+    struct CountingWriter {
+        bytes: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streamed_exports_match_string_exports() {
+        let mut builder = StateMachineBuilderFactory::create::<Order, OrderEvent, NoContext>();
+        builder
+            .initial_state(Order::Placed)
+            .terminal_states(vec![Order::Cancelled])
+            .describe_state(Order::Paid, "Money received.\nShip within \"2 days\".");
+        builder
+            .external_transition()
+            .from(Order::Placed)
+            .to(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        builder
+            .internal_transition()
+            .within(Order::Paid)
+            .on(OrderEvent::Pay)
+            .perform(|_s, _e, _c| {});
+        builder
+            .external_transitions()
+            .from_any()
+            .to(Order::Cancelled)
+            .on(OrderEvent::Cancel)
+            .perform(|_s, _e, _c| {});
+        let machine = builder.build();
+        let labels = german_labels();
+        let options = ExportOptions::new()
+            .labels(&labels)
+            .annotate(true)
+            .legend(true);
+
+        let mut dot = CountingWriter::default();
+        machine.write_dot(&mut dot, &options).unwrap();
+        assert_eq!(dot.bytes, machine.to_dot_with(&options).into_bytes());
+        assert!(dot.writes > 10);
+
+        let mut uml = CountingWriter::default();
+        machine.write_plantuml(&mut uml, &options).unwrap();
+        assert_eq!(uml.bytes, machine.to_plantuml_with(&options).into_bytes());
+        assert!(uml.writes > 10);
+    }
+}
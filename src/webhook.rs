@@ -0,0 +1,898 @@
+//! HTTP callbacks on selected transitions (requires the `http-bridge`
+//! feature)
+//!
+//! A `WebhookListener` is added to a machine like any other listener. After
+//! each successful fire it looks for the `WebhookRule`s matching the
+//! transition, renders their JSON payload templates and queues the
+//! payloads. Queueing never blocks the fire: the queue is bounded, and
+//! payloads arriving while it is full are dropped and counted. A background
+//! thread posts the payloads in order. Deliveries failing with an I/O error
+//! or a 5xx status are retried with exponential backoff, scheduled so that
+//! the payloads queued behind them don't wait; other failures, and
+//! deliveries out of attempts, go to the dead-letter callback.
+//!
+//! String values of a template may reference these placeholders:
+//!
+//! - `{{machine_id}}`, `{{from}}`, `{{event}}` and `{{to}}`, the latter three
+//!   being the `Debug` names of the states and event
+//! - `{{timestamp}}`, the wall-clock time of the fire in milliseconds since
+//!   the Unix epoch, read from `WebhookListenerBuilder::clock`
+//! - `{{context.<field>}}`, a field returned by the extractor given to
+//!   `WebhookListenerBuilder::context_fields`
+//!
+//! A string consisting of a single placeholder is replaced by its value,
+//! which keeps the JSON type of context fields and the timestamp. Within a
+//! longer string, placeholders are replaced by their text. Unknown
+//! placeholders are left as they are.
+//!
+//! Only plain `http://` URLs are supported, with IPv6 hosts in brackets as
+//! in `http://[::1]:8080/`; put a proxy in front of endpoints that need TLS.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+use crate::{Clock, Context, Event, State, SystemClock, TransitionListener};
+
+type ContextFields<C> = Arc<dyn Fn(&C) -> Map<String, Value> + Send + Sync>;
+type DeadLetterHandler = Arc<dyn Fn(DeadLetter) + Send + Sync>;
+
+/// Transitions to post, and the payload to post for them
+///
+/// Unset parts of the `(from, event, to)` pattern match anything.
+#[derive(Debug, Clone)]
+pub struct WebhookRule<S, E> {
+    from: Option<S>,
+    event: Option<E>,
+    to: Option<S>,
+    url: String,
+    template: Value,
+}
+
+impl<S, E> WebhookRule<S, E>
+where
+    S: State,
+    E: Event,
+{
+    /// Post `template`, rendered, to `url` after every transition
+    pub fn new(url: impl Into<String>, template: Value) -> Self {
+        WebhookRule {
+            from: None,
+            event: None,
+            to: None,
+            url: url.into(),
+            template,
+        }
+    }
+
+    pub fn from(mut self, state: S) -> Self {
+        self.from = Some(state);
+        self
+    }
+
+    pub fn on(mut self, event: E) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub fn to(mut self, state: S) -> Self {
+        self.to = Some(state);
+        self
+    }
+
+    fn matches(&self, from: &S, event: &E, to: &S) -> bool {
+        self.from.as_ref().is_none_or(|state| state == from)
+            && self.event.as_ref().is_none_or(|e| e == event)
+            && self.to.as_ref().is_none_or(|state| state == to)
+    }
+}
+
+/// Retry and timeout settings of a `WebhookListener`
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Attempts per payload, the first one included
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Limit on connecting, and on each read and write of a request
+    pub timeout: Duration,
+    /// Payloads waiting to be posted, and separately payloads waiting for
+    /// a retry, beyond which new ones are dropped
+    pub queue_capacity: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            queue_capacity: 1024,
+        }
+    }
+}
+
+/// Why a delivery attempt failed
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// Not an `http://` URL
+    InvalidUrl(String),
+    Io(io::Error),
+    /// The endpoint answered with a status outside 2xx
+    Status(u16),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::InvalidUrl(url) => write!(f, "Unsupported webhook URL: {}", url),
+            DeliveryError::Io(error) => write!(f, "Webhook request failed: {}", error),
+            DeliveryError::Status(status) => write!(f, "Webhook answered with status {}", status),
+        }
+    }
+}
+
+impl DeliveryError {
+    // Whether a later attempt may succeed
+    fn is_transient(&self) -> bool {
+        match self {
+            DeliveryError::InvalidUrl(_) => false,
+            DeliveryError::Io(_) => true,
+            DeliveryError::Status(status) => *status >= 500,
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeliveryError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DeliveryError {
+    fn from(error: io::Error) -> Self {
+        DeliveryError::Io(error)
+    }
+}
+
+/// A payload given up on, after an attempt failing in a way retrying can't
+/// fix, its last attempt, or with the retry queue full
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub url: String,
+    pub payload: Value,
+    pub attempts: u32,
+    /// Error of the last attempt
+    pub error: DeliveryError,
+}
+
+// A rendered payload waiting for the worker
+struct Delivery {
+    url: String,
+    payload: Value,
+}
+
+// A delivery being attempted or waiting for a retry
+struct Pending {
+    delivery: Delivery,
+    body: String,
+    attempts: u32,
+    // Wait before the next retry
+    backoff: Duration,
+}
+
+#[derive(Default)]
+struct Counters {
+    delivered: AtomicU64,
+    dead_lettered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Builder of a `WebhookListener`
+pub struct WebhookListenerBuilder<S, E, C> {
+    machine_id: String,
+    rules: Vec<WebhookRule<S, E>>,
+    context_fields: Option<ContextFields<C>>,
+    config: WebhookConfig,
+    dead_letter: Option<DeadLetterHandler>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S, E, C> WebhookListenerBuilder<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    pub fn rule(mut self, rule: WebhookRule<S, E>) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Fields of the context available to templates as
+    /// `{{context.<field>}}`
+    pub fn context_fields<F>(mut self, extract: F) -> Self
+    where
+        F: Fn(&C) -> Map<String, Value> + Send + Sync + 'static,
+    {
+        self.context_fields = Some(Arc::new(extract));
+        self
+    }
+
+    pub fn config(mut self, config: WebhookConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Clock `{{timestamp}}` is read from, the system clock by default; pass
+    /// the one given to `StateMachineBuilder::with_clock` for the payloads
+    /// to agree with the history
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Called on the worker thread for every payload given up on
+    pub fn on_dead_letter<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(DeadLetter) + Send + Sync + 'static,
+    {
+        self.dead_letter = Some(Arc::new(handler));
+        self
+    }
+
+    /// Start the delivery worker
+    pub fn build(self) -> WebhookListener<S, E, C> {
+        let (sender, receiver) = mpsc::sync_channel(self.config.queue_capacity);
+        let counters = Arc::new(Counters::default());
+        let worker = Worker {
+            config: self.config,
+            dead_letter: self.dead_letter,
+            counters: counters.clone(),
+            retries: BTreeMap::new(),
+            scheduled: 0,
+        };
+        thread::Builder::new()
+            .name("webhook-bridge".to_string())
+            .spawn(move || worker.run(receiver))
+            .expect("failed to spawn the webhook worker");
+        WebhookListener {
+            machine_id: self.machine_id,
+            rules: self.rules,
+            context_fields: self.context_fields,
+            clock: self.clock,
+            sender,
+            counters,
+        }
+    }
+}
+
+/// Listener posting JSON payloads for matching transitions, see the module
+/// documentation
+///
+/// Dropping it, e.g. with its machine, lets the worker finish the queued
+/// payloads and their retries, then stop.
+pub struct WebhookListener<S, E, C> {
+    machine_id: String,
+    rules: Vec<WebhookRule<S, E>>,
+    context_fields: Option<ContextFields<C>>,
+    clock: Arc<dyn Clock>,
+    sender: SyncSender<Delivery>,
+    counters: Arc<Counters>,
+}
+
+impl<S, E, C> WebhookListener<S, E, C>
+where
+    S: State,
+    E: Event,
+    C: Context,
+{
+    /// Listener for the machine `machine_id`, the value of
+    /// `{{machine_id}}`
+    pub fn builder(machine_id: impl Into<String>) -> WebhookListenerBuilder<S, E, C> {
+        WebhookListenerBuilder {
+            machine_id: machine_id.into(),
+            rules: Vec::new(),
+            context_fields: None,
+            config: WebhookConfig::default(),
+            dead_letter: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Payloads posted successfully so far
+    pub fn delivered(&self) -> u64 {
+        self.counters.delivered.load(Ordering::Relaxed)
+    }
+
+    /// Payloads handed to the dead-letter callback so far
+    pub fn dead_lettered(&self) -> u64 {
+        self.counters.dead_lettered.load(Ordering::Relaxed)
+    }
+
+    /// Payloads dropped so far because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<S, E, C> TransitionListener<S, E, C> for WebhookListener<S, E, C>
+where
+    S: State + Send + Sync,
+    E: Event + Send + Sync,
+    C: Context,
+{
+    fn after_transition(&self, from: &S, to: &S, event: &E, context: &C) {
+        let mut matching = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(from, event, to))
+            .peekable();
+        if matching.peek().is_none() {
+            return;
+        }
+        let timestamp = self
+            .clock
+            .wall_time()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let fields = Fields {
+            machine_id: &self.machine_id,
+            from: format!("{:?}", from),
+            event: format!("{:?}", event),
+            to: format!("{:?}", to),
+            timestamp,
+            context: self
+                .context_fields
+                .as_ref()
+                .map(|extract| extract(context))
+                .unwrap_or_default(),
+        };
+        for rule in matching {
+            let delivery = Delivery {
+                url: rule.url.clone(),
+                payload: fields.render(&rule.template),
+            };
+            // The worker only stops once the listener is dropped
+            if let Err(TrySendError::Full(_)) = self.sender.try_send(delivery) {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Values of the placeholders for one fire
+struct Fields<'a> {
+    machine_id: &'a str,
+    from: String,
+    event: String,
+    to: String,
+    timestamp: u64,
+    context: Map<String, Value>,
+}
+
+impl Fields<'_> {
+    fn render(&self, template: &Value) -> Value {
+        match template {
+            Value::String(text) => self.render_text(text),
+            Value::Array(items) => Value::Array(items.iter().map(|v| self.render(v)).collect()),
+            Value::Object(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), self.render(value)))
+                    .collect(),
+            ),
+            other => other.clone(),
+        }
+    }
+
+    fn render_text(&self, text: &str) -> Value {
+        if let Some(name) = text
+            .strip_prefix("{{")
+            .and_then(|rest| rest.strip_suffix("}}"))
+            .filter(|name| !name.contains("{{"))
+        {
+            if let Some(value) = self.value(name.trim()) {
+                return value;
+            }
+        }
+        let mut rendered = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            let name = rest[start + 2..start + end].trim();
+            rendered.push_str(&rest[..start]);
+            match self.value(name) {
+                Some(Value::String(value)) => rendered.push_str(&value),
+                Some(value) => rendered.push_str(&value.to_string()),
+                None => rendered.push_str(&rest[start..start + end + 2]),
+            }
+            rest = &rest[start + end + 2..];
+        }
+        rendered.push_str(rest);
+        Value::String(rendered)
+    }
+
+    fn value(&self, name: &str) -> Option<Value> {
+        match name {
+            "machine_id" => Some(Value::from(self.machine_id)),
+            "from" => Some(Value::from(self.from.as_str())),
+            "event" => Some(Value::from(self.event.as_str())),
+            "to" => Some(Value::from(self.to.as_str())),
+            "timestamp" => Some(Value::from(self.timestamp)),
+            _ => name
+                .strip_prefix("context.")
+                .and_then(|field| self.context.get(field))
+                .cloned(),
+        }
+    }
+}
+
+struct Worker {
+    config: WebhookConfig,
+    dead_letter: Option<DeadLetterHandler>,
+    counters: Arc<Counters>,
+    // Deliveries waiting for a retry, by when it is due and then by when it
+    // was scheduled
+    retries: BTreeMap<(Instant, u64), Pending>,
+    scheduled: u64,
+}
+
+impl Worker {
+    fn run(mut self, deliveries: Receiver<Delivery>) {
+        let mut open = true;
+        loop {
+            let now = Instant::now();
+            while let Some(retry) = self
+                .retries
+                .first_entry()
+                .filter(|retry| retry.key().0 <= now)
+            {
+                let pending = retry.remove();
+                self.attempt(pending);
+            }
+            let wait = self
+                .retries
+                .keys()
+                .next()
+                .map(|(due, _)| due.saturating_duration_since(Instant::now()));
+            if !open {
+                // Only the retries are left
+                match wait {
+                    Some(wait) => thread::sleep(wait),
+                    None => return,
+                }
+                continue;
+            }
+            let received = match wait {
+                Some(wait) => deliveries.recv_timeout(wait),
+                None => deliveries
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(delivery) => self.attempt(Pending {
+                    body: delivery.payload.to_string(),
+                    delivery,
+                    attempts: 0,
+                    backoff: self.config.initial_backoff,
+                }),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => open = false,
+            }
+        }
+    }
+
+    // Post `pending` once, scheduling a retry when it fails
+    fn attempt(&mut self, mut pending: Pending) {
+        pending.attempts += 1;
+        let error = match post(
+            &pending.delivery.url,
+            pending.body.as_bytes(),
+            self.config.timeout,
+        ) {
+            Ok(()) => {
+                self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(error) => error,
+        };
+        if error.is_transient()
+            && pending.attempts < self.config.max_attempts
+            && self.retries.len() < self.config.queue_capacity
+        {
+            let due = Instant::now() + pending.backoff;
+            pending.backoff = (pending.backoff * 2).min(self.config.max_backoff);
+            self.scheduled += 1;
+            self.retries.insert((due, self.scheduled), pending);
+            return;
+        }
+
+        self.counters.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = &self.dead_letter {
+            let letter = DeadLetter {
+                url: pending.delivery.url,
+                payload: pending.delivery.payload,
+                attempts: pending.attempts,
+                error,
+            };
+            // A panicking handler must not stop the worker
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(letter)));
+        }
+    }
+}
+
+// POST `body` as JSON, succeeding on a 2xx status
+fn post(url: &str, body: &[u8], timeout: Duration) -> Result<(), DeliveryError> {
+    let invalid = || DeliveryError::InvalidUrl(url.to_string());
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = split_authority(authority).ok_or_else(invalid)?;
+
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(DeliveryError::Status(status))
+    }
+}
+
+// Host and port of `host[:port]`, where an IPv6 host is in brackets
+fn split_authority(authority: &str) -> Option<(&str, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 80,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{order_builder, Order, OrderEvent};
+    use serde_json::json;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::SystemTime;
+
+    #[derive(Debug, Clone)]
+    struct Customer {
+        id: u32,
+    }
+
+    impl Context for Customer {}
+
+    // Wall clock stopped at one second past the epoch
+    struct StoppedClock;
+
+    impl Clock for StoppedClock {
+        fn now(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn wall_time(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(1)
+        }
+    }
+
+    // Answer each request with the next status, repeating the last one, and
+    // pass on the bodies received
+    fn serve(statuses: Vec<u16>, delay: Duration) -> (String, Receiver<Value>) {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/orders", server.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for (index, stream) in server.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                thread::sleep(delay);
+                let status = statuses[index.min(statuses.len() - 1)];
+                let _ = write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+                if sender.send(serde_json::from_slice(&body).unwrap()).is_err() {
+                    return;
+                }
+            }
+        });
+        (url, receiver)
+    }
+
+    fn fast_retries(max_attempts: u32) -> WebhookConfig {
+        WebhookConfig {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            ..WebhookConfig::default()
+        }
+    }
+
+    fn machine(
+        listener: WebhookListener<Order, OrderEvent, Customer>,
+    ) -> crate::StateMachine<Order, OrderEvent, Customer> {
+        let mut builder = order_builder();
+        builder.add_listener(Box::new(listener));
+        builder.id("orders").build()
+    }
+
+    #[test]
+    fn test_matching_transition_is_posted() {
+        let (url, received) = serve(vec![200], Duration::ZERO);
+        let listener = WebhookListener::builder("orders")
+            .rule(
+                WebhookRule::new(
+                    url,
+                    json!({
+                        "machine": "{{machine_id}}",
+                        "transition": "{{from}} -{{event}}-> {{to}}",
+                        "customer": "{{context.customer}}",
+                        "at": "{{timestamp}}",
+                    }),
+                )
+                .on(OrderEvent::Ship),
+            )
+            .context_fields(|c: &Customer| {
+                let mut fields = Map::new();
+                fields.insert("customer".to_string(), json!(c.id));
+                fields
+            })
+            .clock(Arc::new(StoppedClock))
+            .build();
+        let machine = machine(listener);
+
+        let customer = Customer { id: 7 };
+        assert!(machine
+            .fire_event(Order::Placed, OrderEvent::Pay, customer.clone())
+            .is_ok());
+        assert!(machine
+            .fire_event(Order::Paid, OrderEvent::Ship, customer)
+            .is_ok());
+
+        let payload = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(payload["machine"], "orders");
+        assert_eq!(payload["transition"], "Paid -Ship-> Shipped");
+        assert_eq!(payload["customer"], 7);
+        assert_eq!(payload["at"], 1000);
+        // Paying matched no rule
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_failed_delivery_is_retried() {
+        let (url, received) = serve(vec![500, 200], Duration::ZERO);
+        let listener = WebhookListener::builder("orders")
+            .rule(WebhookRule::new(url, json!({ "to": "{{to}}" })))
+            .config(fast_retries(3))
+            .build();
+        let counters = listener.counters.clone();
+        let machine = machine(listener);
+
+        assert!(machine
+            .fire_event(Order::Placed, OrderEvent::Pay, Customer { id: 1 })
+            .is_ok());
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "to": "Paid" })
+        );
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "to": "Paid" })
+        );
+        let deadline = Instant::now() + timeout;
+        while counters.delivered.load(Ordering::Relaxed) == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(counters.delivered.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_undeliverable_payload_is_dead_lettered() {
+        let (url, _received) = serve(vec![500], Duration::ZERO);
+        let (letters, dead) = mpsc::channel();
+        let listener = WebhookListener::builder("orders")
+            .rule(WebhookRule::new(url, json!({ "to": "{{to}}" })).from(Order::Placed))
+            .config(fast_retries(2))
+            .on_dead_letter(move |letter| letters.send(letter).unwrap())
+            .build();
+        let machine = machine(listener);
+
+        assert!(machine
+            .fire_event(Order::Placed, OrderEvent::Pay, Customer { id: 1 })
+            .is_ok());
+
+        let letter = dead.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(letter.attempts, 2);
+        assert_eq!(letter.payload, json!({ "to": "Paid" }));
+        assert!(matches!(letter.error, DeliveryError::Status(500)));
+    }
+
+    #[test]
+    fn test_client_error_is_not_retried() {
+        let (url, received) = serve(vec![404, 200], Duration::ZERO);
+        let (letters, dead) = mpsc::channel();
+        let listener = WebhookListener::builder("orders")
+            .rule(WebhookRule::new(url, json!({ "to": "{{to}}" })).from(Order::Placed))
+            .config(fast_retries(3))
+            .on_dead_letter(move |letter| letters.send(letter).unwrap())
+            .build();
+        let machine = machine(listener);
+
+        assert!(machine
+            .fire_event(Order::Placed, OrderEvent::Pay, Customer { id: 1 })
+            .is_ok());
+
+        let letter = dead.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(letter.attempts, 1);
+        assert!(matches!(letter.error, DeliveryError::Status(404)));
+        assert!(received.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_retry_does_not_hold_up_later_payloads() {
+        let (url, received) = serve(vec![503, 200], Duration::ZERO);
+        let listener = WebhookListener::builder("orders")
+            .rule(WebhookRule::new(url, json!({ "event": "{{event}}" })))
+            .config(WebhookConfig {
+                max_attempts: 2,
+                initial_backoff: Duration::from_secs(1),
+                ..WebhookConfig::default()
+            })
+            .build();
+        let machine = machine(listener);
+
+        assert!(machine
+            .fire_event(Order::Placed, OrderEvent::Pay, Customer { id: 1 })
+            .is_ok());
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "event": "Pay" })
+        );
+        assert!(machine
+            .fire_event(Order::Paid, OrderEvent::Ship, Customer { id: 1 })
+            .is_ok());
+
+        // Posted while the first payload waits for its retry
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "event": "Ship" })
+        );
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "event": "Pay" })
+        );
+    }
+
+    #[test]
+    fn test_full_queue_drops_payloads() {
+        let (url, _received) = serve(vec![200], Duration::from_secs(1));
+        let listener = WebhookListener::builder("orders")
+            .rule(WebhookRule::new(url, json!({ "event": "{{event}}" })))
+            .config(WebhookConfig {
+                queue_capacity: 1,
+                ..WebhookConfig::default()
+            })
+            .build();
+        let counters = listener.counters.clone();
+        let machine = machine(listener);
+
+        // One payload being posted and one queued at most
+        for _ in 0..3 {
+            assert!(machine
+                .fire_event(Order::Placed, OrderEvent::Pay, Customer { id: 1 })
+                .is_ok());
+        }
+        assert!(counters.dropped.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn test_authority_split() {
+        assert_eq!(split_authority("example.com"), Some(("example.com", 80)));
+        assert_eq!(
+            split_authority("example.com:8080"),
+            Some(("example.com", 8080))
+        );
+        assert_eq!(split_authority("[::1]"), Some(("::1", 80)));
+        assert_eq!(split_authority("[::1]:8080"), Some(("::1", 8080)));
+        for invalid in ["::1", "[::1", "[::1]8080", ":8080", "example.com:http"] {
+            assert_eq!(split_authority(invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_fire_does_not_wait_for_delivery() {
+        let (url, received) = serve(vec![200], Duration::from_secs(1));
+        let listener = WebhookListener::builder("orders")
+            .rule(WebhookRule::new(url, json!({ "event": "{{event}}" })))
+            .build();
+        let machine = machine(listener);
+
+        let started = Instant::now();
+        assert!(machine
+            .fire_event(Order::Placed, OrderEvent::Pay, Customer { id: 1 })
+            .is_ok());
+        assert!(machine
+            .fire_event(Order::Paid, OrderEvent::Ship, Customer { id: 1 })
+            .is_ok());
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "event": "Pay" })
+        );
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            json!({ "event": "Ship" })
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Interned names reach listeners without allocating
+//!
+//! Lives in its own test binary for the counting allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use rs_statemachine::*;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum Door {
+    Open,
+    Closed,
+}
+
+impl State for Door {}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum DoorEvent {
+    Close,
+    Lock,
+}
+
+impl Event for DoorEvent {}
+
+#[derive(Debug, Clone)]
+struct NoContext;
+
+impl Context for NoContext {}
+
+// Writes each fire into a buffer sized up front, counting what that allocated
+struct AuditLog {
+    lines: Mutex<String>,
+    allocations: Mutex<Vec<usize>>,
+}
+
+impl AuditLog {
+    fn log(&self, line: impl FnOnce(&mut String)) {
+        let mut lines = self.lines.lock().unwrap();
+        let before = allocations();
+        line(&mut lines);
+        let allocated = allocations() - before;
+        drop(lines);
+        self.allocations.lock().unwrap().push(allocated);
+    }
+}
+
+struct NamedListener(Arc<AuditLog>);
+
+impl TransitionListener<Door, DoorEvent, NoContext> for NamedListener {
+    fn after_transition_named(&self, names: &TransitionNames, _context: &NoContext) {
+        self.0.log(|lines| {
+            let _ = writeln!(
+                lines,
+                "{} --{}--> {}",
+                names.from.as_deref().unwrap_or("?"),
+                names.event.as_deref().unwrap_or("?"),
+                names.to.as_deref().unwrap_or("?"),
+            );
+        });
+    }
+
+    fn on_failure_named(
+        &self,
+        names: &TransitionNames,
+        _context: &NoContext,
+        error: &TransitionError<Door, DoorEvent>,
+    ) {
+        self.0.log(|lines| {
+            let _ = writeln!(
+                lines,
+                "{} --{}--> {}",
+                names.from.as_deref().unwrap_or("?"),
+                names.event.as_deref().unwrap_or("?"),
+                error.code(),
+            );
+        });
+    }
+}
+
+#[test]
+fn test_named_hooks_do_not_allocate() {
+    let log = Arc::new(AuditLog {
+        lines: Mutex::new(String::with_capacity(1024)),
+        allocations: Mutex::new(Vec::with_capacity(16)),
+    });
+    let mut builder = StateMachineBuilderFactory::create::<Door, DoorEvent, NoContext>();
+    builder
+        .external_transition()
+        .from(Door::Open)
+        .to(Door::Closed)
+        .on(DoorEvent::Close)
+        .add();
+    builder
+        .internal_transition()
+        .within(Door::Closed)
+        .on(DoorEvent::Lock)
+        .add();
+    builder.add_listener(Box::new(NamedListener(log.clone())));
+    let machine = builder.build();
+
+    machine
+        .fire_event(Door::Open, DoorEvent::Close, NoContext)
+        .unwrap();
+    machine
+        .fire_event(Door::Closed, DoorEvent::Lock, NoContext)
+        .unwrap();
+    assert!(machine
+        .fire_event(Door::Open, DoorEvent::Lock, NoContext)
+        .is_err());
+
+    assert_eq!(
+        *log.lines.lock().unwrap(),
+        "Open --Close--> Closed\nClosed --Lock--> Closed\nOpen --Lock--> no_valid_transition\n"
+    );
+    assert_eq!(*log.allocations.lock().unwrap(), [0, 0, 0]);
+}
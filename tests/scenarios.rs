@@ -0,0 +1,135 @@
+//! End-to-end scenarios, written with the `Scenario` runner
+//!
+//! Run with `cargo test --features test-util --test scenarios`.
+
+use rs_statemachine::*;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum Light {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl State for Light {}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum LightEvent {
+    Timer,
+    Pedestrian,
+}
+
+impl Event for LightEvent {}
+
+#[derive(Debug, Clone)]
+struct Crossing {
+    pedestrian_waiting: bool,
+}
+
+impl Context for Crossing {}
+
+const IDLE: Crossing = Crossing {
+    pedestrian_waiting: false,
+};
+
+fn traffic_light() -> StateMachine<Light, LightEvent, Crossing> {
+    let mut builder = StateMachineBuilderFactory::create::<Light, LightEvent, Crossing>();
+    builder
+        .external_transition()
+        .from(Light::Green)
+        .to(Light::Yellow)
+        .on(LightEvent::Timer)
+        .perform(|_s, _e, _c| {});
+    builder
+        .external_transition()
+        .from(Light::Yellow)
+        .to(Light::Red)
+        .on(LightEvent::Timer)
+        .perform(|_s, _e, _c| {});
+    builder
+        .external_transition()
+        .from(Light::Red)
+        .to(Light::Green)
+        .on(LightEvent::Timer)
+        .perform(|_s, _e, _c| {});
+    builder
+        .external_transition()
+        .from(Light::Green)
+        .to(Light::Yellow)
+        .on(LightEvent::Pedestrian)
+        .when(|_s, _e, c| c.pedestrian_waiting)
+        .perform(|_s, _e, _c| {});
+    #[cfg(feature = "extended")]
+    builder
+        .with_entry_action(Light::Red, |_s, _c| {})
+        .with_exit_action(Light::Green, |_s, _c| {});
+    builder.build()
+}
+
+#[test]
+fn full_cycle() {
+    let scenario = Scenario::new(&traffic_light())
+        .given(Light::Green)
+        .when(LightEvent::Timer, IDLE)
+        .expect_state(Light::Yellow);
+    #[cfg(feature = "extended")]
+    let scenario = scenario.expect_exit(Light::Green);
+    let scenario = scenario
+        .when(LightEvent::Timer, IDLE)
+        .expect_state(Light::Red);
+    #[cfg(feature = "extended")]
+    let scenario = scenario.expect_entry(Light::Red);
+    let scenario = scenario
+        .when(LightEvent::Timer, IDLE)
+        .expect_state(Light::Green);
+    #[cfg(feature = "history")]
+    let scenario = scenario.expect_history_len(3);
+    #[cfg(feature = "metrics")]
+    let scenario = scenario.expect_metric("successful_transitions", 3);
+
+    if let Err(failure) = scenario.run() {
+        panic!("{}", failure);
+    }
+}
+
+#[test]
+fn pedestrian_request_needs_someone_waiting() {
+    let scenario = Scenario::new(&traffic_light())
+        .given(Light::Green)
+        .when(LightEvent::Pedestrian, IDLE)
+        .expect_error("condition_failed")
+        .expect_state(Light::Green)
+        .when(
+            LightEvent::Pedestrian,
+            Crossing {
+                pedestrian_waiting: true,
+            },
+        )
+        .expect_state(Light::Yellow)
+        .when(LightEvent::Pedestrian, IDLE)
+        .expect_error("no_valid_transition");
+    #[cfg(feature = "metrics")]
+    let scenario = scenario.expect_metric("failed_transitions", 2);
+
+    if let Err(failure) = scenario.run() {
+        panic!("{}", failure);
+    }
+}
+
+#[test]
+fn diverging_scenario_reports_step() {
+    let failure = Scenario::new(&traffic_light())
+        .given(Light::Green)
+        .when(LightEvent::Timer, IDLE)
+        .when(LightEvent::Timer, IDLE)
+        .expect_state(Light::Green)
+        .run()
+        .unwrap_err();
+
+    assert_eq!(failure.step, 4);
+    assert_eq!(failure.description, "expect_state(Green)");
+    assert_eq!(
+        failure.to_string(),
+        "step 4 (expect_state(Green)): expected state Green, found Red"
+    );
+}